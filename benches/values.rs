@@ -0,0 +1,113 @@
+//! Counts the allocations of contexts holding short lists, with lists
+//! stored inline by `Value` and with each list in a `Vec` of its own, as
+//! they were before
+//!
+//! Run with `cargo bench --bench values`.
+
+use ironwood::{Environment, StringId, StringInterner, Value};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const CONTEXTS: usize = 10_000;
+
+/// Lengths of the lists in each context: a user's roles, segments and so on
+const LENGTHS: &[usize] = &[0, 1, 2, 3, 4, 6];
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let mut interner = StringInterner::new();
+    let names: Vec<StringId> = (0..LENGTHS.len())
+        .map(|n| interner.intern(&format!("list{n}")))
+        .collect();
+    let tags: Vec<StringId> = (0..8)
+        .map(|n| interner.intern(&format!("tag{n}")))
+        .collect();
+
+    let build_inline = || -> Vec<Environment> {
+        (0..CONTEXTS)
+            .map(|i| {
+                let mut env = Environment::new();
+                for (slot, (&name, &len)) in names.iter().zip(LENGTHS).enumerate() {
+                    let value = if slot % 2 == 0 {
+                        Value::IntegerList((0..len as i64).map(|n| n + i as i64).collect())
+                    } else {
+                        Value::StringList(tags[..len].iter().copied().collect())
+                    };
+                    env.insert(name, value);
+                }
+                env
+            })
+            .collect()
+    };
+    let build_heap = || -> Vec<Vec<(StringId, Vec<i64>)>> {
+        (0..CONTEXTS)
+            .map(|i| {
+                let mut env = Vec::with_capacity(LENGTHS.len());
+                for (&name, &len) in names.iter().zip(LENGTHS) {
+                    env.push((name, (0..len as i64).map(|n| n + i as i64).collect()));
+                }
+                env
+            })
+            .collect()
+    };
+
+    println!(
+        "{:<8} {:>16} {:>16} {:>10}",
+        "", "inline/context", "vec/context", "inline ms"
+    );
+    let (inline, built_inline, ms) = count(build_inline);
+    let (heap, built_heap, _) = count(build_heap);
+    report("build", built_inline, built_heap, ms);
+
+    let (_, cloned_inline, ms) = count(|| inline.clone());
+    let (_, cloned_heap, _) = count(|| heap.clone());
+    report("clone", cloned_inline, cloned_heap, ms);
+    println!("value size: {} bytes", std::mem::size_of::<Value>());
+}
+
+/// Run `f`, returning its result, the allocations it made and its time
+fn count<T>(f: impl FnOnce() -> T) -> (T, usize, f64) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let result = black_box(f());
+    let elapsed = start.elapsed().as_secs_f64() * 1_000.0;
+    (
+        result,
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+        elapsed,
+    )
+}
+
+fn report(name: &str, inline: usize, heap: usize, ms: f64) {
+    println!(
+        "{:<8} {:>16.2} {:>16.2} {:>10.2}",
+        name,
+        inline as f64 / CONTEXTS as f64,
+        heap as f64 / CONTEXTS as f64,
+        ms
+    );
+}
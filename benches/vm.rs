@@ -0,0 +1,91 @@
+//! Compares the tree-walking evaluator against the bytecode VM
+//!
+//! Run with `cargo bench --bench vm`.
+
+use ironwood::compile::compile;
+use ironwood::{parse, Environment, Evaluator, StringInterner, Value};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 200_000;
+
+const RULES: &[(&str, &str)] = &[
+    ("equality", r#"(and (= country "US") (>= age 21))"#),
+    (
+        "targeting",
+        r#"(and (= country "US")
+                (>= age 21)
+                (< age 65)
+                (one-of interests ["sports" "music" "travel" "food" "tech"])
+                (not (in segment ["churned" "fraud" "test"])))"#,
+    ),
+    (
+        "short-circuit",
+        r#"(or (= country "CA") (= country "MX") (= country "US") (> age 200))"#,
+    ),
+    ("geo", "(geo_within_radius lat lng 40.7128 -74.0060 50000)"),
+];
+
+fn main() {
+    let mut interner = StringInterner::new();
+    let mut env = Environment::new();
+    let country = interner.intern("US");
+    env.insert(interner.intern("country"), Value::String(country));
+    env.insert(interner.intern("age"), Value::Integer(34));
+    env.insert(
+        interner.intern("segment"),
+        Value::Symbol(interner.intern("new")),
+    );
+    let interests = vec![interner.intern("food"), interner.intern("film")];
+    env.insert(
+        interner.intern("interests"),
+        Value::StringList(interests.into()),
+    );
+    env.insert(interner.intern("lat"), Value::Float(40.73));
+    env.insert(interner.intern("lng"), Value::Float(-73.99));
+    env.insert(interner.intern("user_id"), Value::Integer(4_001));
+
+    // Large blocklists are tested with a sorted set rather than a scan
+    let blocked: Vec<String> = (0..1_000).map(|n| (n * 7).to_string()).collect();
+    let membership = format!("(not-in user_id [{}])", blocked.join(" "));
+    let rules = RULES
+        .iter()
+        .copied()
+        .chain([("membership", membership.as_str())]);
+
+    println!(
+        "{:<14} {:>12} {:>12} {:>8}",
+        "rule", "ast ns/eval", "vm ns/eval", "speedup"
+    );
+    for (name, source) in rules {
+        let expr = parse(source, &mut interner).expect("benchmark rule parses");
+        let evaluator = Evaluator::new(&interner);
+        let compiled = compile(&expr, &interner);
+        assert_eq!(evaluator.eval(&expr, &env), compiled.eval(&env, &interner));
+
+        let ast = time(|| evaluator.eval(black_box(&expr), black_box(&env)));
+        let vm = time(|| compiled.eval(black_box(&env), &interner));
+        println!(
+            "{:<14} {:>12.1} {:>12.1} {:>7.2}x",
+            name,
+            per_eval(ast),
+            per_eval(vm),
+            ast.as_secs_f64() / vm.as_secs_f64()
+        );
+    }
+}
+
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    for _ in 0..ITERATIONS / 10 {
+        black_box(f());
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed()
+}
+
+fn per_eval(elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+}
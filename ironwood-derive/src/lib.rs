@@ -0,0 +1,93 @@
+//! Derive macros for `ironwood::IntoValue` and `ironwood::FromValue`
+//!
+//! Use them through the `derive` feature of `ironwood`, which re-exports
+//! them next to the traits. Both derive for structs with named fields,
+//! which convert to and from `Value::Map`s keyed by the field names.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, FieldsNamed};
+
+/// Derive `IntoValue`, turning a struct into a map of its fields
+#[proc_macro_derive(IntoValue)]
+pub fn derive_into_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, into_value)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Derive `FromValue`, reading a struct from a map of its fields
+#[proc_macro_derive(FromValue)]
+pub fn derive_from_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, from_value)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(
+    input: &DeriveInput,
+    body: fn(&DeriveInput, &FieldsNamed) -> TokenStream2,
+) -> Result<TokenStream2, Error> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(body(input, fields)),
+            _ => Err(Error::new_spanned(
+                &input.ident,
+                "only structs with named fields convert to values",
+            )),
+        },
+        _ => Err(Error::new_spanned(
+            &input.ident,
+            "only structs with named fields convert to values",
+        )),
+    }
+}
+
+fn into_value(input: &DeriveInput, fields: &FieldsNamed) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let inserts = fields.named.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("named field");
+        let key = ident.unraw().to_string();
+        quote! {
+            let value = ::ironwood::IntoValue::into_value(self.#ident, interner);
+            map.insert(interner.intern(#key), value);
+        }
+    });
+    quote! {
+        impl #impl_generics ::ironwood::IntoValue for #name #ty_generics #where_clause {
+            fn into_value(self, interner: &mut ::ironwood::StringInterner) -> ::ironwood::Value {
+                let mut map = ::ironwood::convert::ValueMap::default();
+                #(#inserts)*
+                ::ironwood::Value::Map(map)
+            }
+        }
+    }
+}
+
+fn from_value(input: &DeriveInput, fields: &FieldsNamed) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let reads = fields.named.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("named field");
+        let key = ident.unraw().to_string();
+        quote! {
+            #ident: ::ironwood::convert::field(map, #key, interner)?,
+        }
+    });
+    quote! {
+        impl #impl_generics ::ironwood::FromValue for #name #ty_generics #where_clause {
+            fn from_value(
+                value: &::ironwood::Value,
+                interner: &::ironwood::StringInterner,
+            ) -> ::core::result::Result<Self, ::ironwood::convert::ConvertError> {
+                let map = ::ironwood::convert::map(value)?;
+                ::core::result::Result::Ok(Self { #(#reads)* })
+            }
+        }
+    }
+}
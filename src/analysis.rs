@@ -0,0 +1,475 @@
+//! Static satisfiability analysis
+//!
+//! `satisfiability` decides, without evaluating anything, whether a rule
+//! can never be true or is always true, and names the subexpressions
+//! responsible. It reasons about simple predicates comparing a variable
+//! with literals, which it reads through `and`, `or` and `not`:
+//!
+//! - `=`, `!=`, `in` and `not-in` restrict a variable to, or exclude it
+//!   from, a set of values, so `(and (= x 1) (= x 2))` and
+//!   `(and (in x [1 2]) (not-in x [1 2]))` can never hold
+//! - `<`, `<=`, `>` and `>=` against numbers restrict it to an interval, so
+//!   `(and (> x 5) (< x 3))` and `(and (= x 1) (> x 1))` can never hold
+//! - any other condition is compared structurally with the others, so
+//!   `(and p (not p))` can never hold
+//!
+//! A rule is always true when its negation can never hold, as with
+//! `(or (< x 10) (>= x 10))`. That assumes every variable is bound to a
+//! value the predicates can compare; a missing or null variable makes such
+//! a rule null instead. Values compare the way `=` and the ruleset index
+//! compare them, so `1` and `1.0` are equal.
+//!
+//! The analysis is sound but incomplete: a rule it reports is certainly
+//! unsatisfiable or always true, but it does not find every such rule.
+
+use crate::compat::{self, FxHashSet};
+use crate::ruleset::IndexKey;
+use crate::{BuiltinFunction, Expr, StringId, StringInterner, Value};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// What `satisfiability` found out about an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Satisfiability<'e> {
+    /// The expression can never be true, because the subexpressions
+    /// listed cannot all hold
+    Unsatisfiable(Vec<&'e Expr>),
+    /// The expression is always true, because at least one of the
+    /// subexpressions listed always holds
+    Tautology(Vec<&'e Expr>),
+    /// Neither could be shown
+    Unknown,
+}
+
+/// Decide whether an expression built with `interner` can never be true
+/// or is always true
+///
+/// The subexpressions returned are references into `expr`, which
+/// `SpannedExpr::span_of` turns into source spans.
+pub fn satisfiability<'e>(expr: &'e Expr, interner: &StringInterner) -> Satisfiability<'e> {
+    if let Some(conflict) = unsatisfiable(expr, false, interner) {
+        Satisfiability::Unsatisfiable(conflict)
+    } else if let Some(cover) = unsatisfiable(expr, true, interner) {
+        Satisfiability::Tautology(cover)
+    } else {
+        Satisfiability::Unknown
+    }
+}
+
+/// Find subexpressions showing that `expr`, or its negation if `negated`,
+/// can never be true
+fn unsatisfiable<'e>(
+    expr: &'e Expr,
+    negated: bool,
+    interner: &StringInterner,
+) -> Option<Vec<&'e Expr>> {
+    let inner = expr.unannotated();
+    if let Expr::Literal(Value::Bool(value)) = inner {
+        return (*value == negated).then(|| vec![expr]);
+    }
+    let Expr::Call { function, args } = inner else {
+        return None;
+    };
+    match (interner.builtin(*function)?, negated) {
+        (BuiltinFunction::Not, _) if args.len() == 1 => unsatisfiable(&args[0], !negated, interner),
+        (BuiltinFunction::And, false) | (BuiltinFunction::Or, true) => {
+            let mut atoms = Vec::with_capacity(args.len());
+            for arg in args {
+                if let Some(conflict) = gather(arg, None, negated, interner, &mut atoms) {
+                    return Some(conflict);
+                }
+            }
+            conflicting(&atoms)
+        }
+        // A disjunction fails only if every disjunct does
+        (BuiltinFunction::Or, false) | (BuiltinFunction::And, true) => {
+            let mut conflict = Vec::new();
+            for arg in args {
+                conflict.extend(unsatisfiable(arg, negated, interner)?);
+            }
+            Some(conflict)
+        }
+        _ => conflicting(&[atom(expr, negated, interner)]),
+    }
+}
+
+/// Collect the atoms of a conjunct, `expr` negated if `negated`, reading
+/// nested conjunctions as one, or find that the conjunct alone cannot hold
+///
+/// `shown` is the `not` the conjunct was found under, which stands for it
+/// in conflicts.
+fn gather<'e>(
+    expr: &'e Expr,
+    shown: Option<&'e Expr>,
+    negated: bool,
+    interner: &StringInterner,
+    atoms: &mut Vec<Atom<'e>>,
+) -> Option<Vec<&'e Expr>> {
+    if let Expr::Call { function, args } = expr.unannotated() {
+        match (interner.builtin(*function), negated) {
+            (Some(BuiltinFunction::Not), _) if args.len() == 1 => {
+                let shown = shown.unwrap_or(expr);
+                return gather(&args[0], Some(shown), !negated, interner, atoms);
+            }
+            (Some(BuiltinFunction::And), false) | (Some(BuiltinFunction::Or), true) => {
+                for arg in args {
+                    if let Some(conflict) = gather(arg, None, negated, interner, atoms) {
+                        return Some(conflict);
+                    }
+                }
+                return None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(conflict) = unsatisfiable(expr, negated, interner) {
+        return Some(conflict);
+    }
+    let mut atom = atom(expr, negated, interner);
+    atom.expr = shown.unwrap_or(expr);
+    atoms.push(atom);
+    None
+}
+
+/// What a variable or condition is constrained
+#[derive(Debug, Clone, PartialEq)]
+enum Subject<'e> {
+    Variable(StringId),
+    /// A condition that is not a simple predicate, by its expression
+    Condition(&'e Expr),
+}
+
+/// Constraint a predicate places on its subject
+#[derive(Debug, Clone)]
+enum Constraint {
+    /// Equal to one of the keys
+    OneOf(FxHashSet<IndexKey>),
+    /// Equal to none of the keys
+    NoneOf(FxHashSet<IndexKey>),
+    /// Above a bound, which is included if the flag is set
+    Above(f64, bool),
+    /// Below a bound, which is included if the flag is set
+    Below(f64, bool),
+    /// A condition that is true, or false
+    Holds(bool),
+}
+
+impl Constraint {
+    fn negate(self) -> Self {
+        match self {
+            Constraint::OneOf(keys) => Constraint::NoneOf(keys),
+            Constraint::NoneOf(keys) => Constraint::OneOf(keys),
+            Constraint::Above(bound, included) => Constraint::Below(bound, !included),
+            Constraint::Below(bound, included) => Constraint::Above(bound, !included),
+            Constraint::Holds(holds) => Constraint::Holds(!holds),
+        }
+    }
+}
+
+/// A predicate, the subject it constrains and how
+struct Atom<'e> {
+    expr: &'e Expr,
+    subject: Subject<'e>,
+    constraint: Constraint,
+}
+
+/// Read `expr`, negated if `negated`, as a constraint on a variable, or on
+/// itself if it is not a simple predicate
+fn atom<'e>(expr: &'e Expr, negated: bool, interner: &StringInterner) -> Atom<'e> {
+    let (subject, constraint) = predicate(expr.unannotated(), interner).unwrap_or_else(|| {
+        (
+            Subject::Condition(expr.unannotated()),
+            Constraint::Holds(true),
+        )
+    });
+    let constraint = if negated {
+        constraint.negate()
+    } else {
+        constraint
+    };
+    Atom {
+        expr,
+        subject,
+        constraint,
+    }
+}
+
+/// Read a simple predicate on a variable
+fn predicate<'e>(expr: &Expr, interner: &StringInterner) -> Option<(Subject<'e>, Constraint)> {
+    let Expr::Call { function, args } = expr else {
+        return None;
+    };
+    let [left, right] = args.as_slice() else {
+        return None;
+    };
+    let function = interner.builtin(*function)?;
+    // Comparisons read with the literal on the left are mirrored
+    let (name, operand, function) = match (left.unannotated(), right.unannotated()) {
+        (Expr::Variable(name), operand) => (*name, operand, function),
+        (operand, Expr::Variable(name)) => (*name, operand, mirror(function)?),
+        _ => return None,
+    };
+    let constraint = match function {
+        BuiltinFunction::Equal => Constraint::OneOf(keys([literal(operand)?], interner)?),
+        BuiltinFunction::NotEqual => Constraint::NoneOf(keys([literal(operand)?], interner)?),
+        BuiltinFunction::In => Constraint::OneOf(keys(list(operand)?, interner)?),
+        BuiltinFunction::NotIn => Constraint::NoneOf(keys(list(operand)?, interner)?),
+        BuiltinFunction::LessThan => Constraint::Below(number(operand)?, false),
+        BuiltinFunction::LessThanOrEqual => Constraint::Below(number(operand)?, true),
+        BuiltinFunction::GreaterThan => Constraint::Above(number(operand)?, false),
+        BuiltinFunction::GreaterThanOrEqual => Constraint::Above(number(operand)?, true),
+        _ => return None,
+    };
+    Some((Subject::Variable(name), constraint))
+}
+
+/// The comparison that holds with its operands swapped
+fn mirror(function: BuiltinFunction) -> Option<BuiltinFunction> {
+    Some(match function {
+        BuiltinFunction::Equal | BuiltinFunction::NotEqual => function,
+        BuiltinFunction::LessThan => BuiltinFunction::GreaterThan,
+        BuiltinFunction::LessThanOrEqual => BuiltinFunction::GreaterThanOrEqual,
+        BuiltinFunction::GreaterThan => BuiltinFunction::LessThan,
+        BuiltinFunction::GreaterThanOrEqual => BuiltinFunction::LessThanOrEqual,
+        _ => return None,
+    })
+}
+
+fn literal(expr: &Expr) -> Option<&Value> {
+    match expr {
+        Expr::Literal(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Items of a literal list
+fn list(expr: &Expr) -> Option<Vec<Value>> {
+    Some(match expr {
+        Expr::Literal(Value::StringList(ids)) => ids.iter().map(|&id| Value::String(id)).collect(),
+        Expr::Literal(Value::IntegerList(ns)) => ns.iter().map(|&n| Value::Integer(n)).collect(),
+        Expr::Literal(Value::List(items)) => items.clone(),
+        Expr::List(items) => items
+            .iter()
+            .map(|item| literal(item).cloned())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    })
+}
+
+/// Keys of values that all have one
+fn keys<V: core::borrow::Borrow<Value>>(
+    values: impl IntoIterator<Item = V>,
+    interner: &StringInterner,
+) -> Option<FxHashSet<IndexKey>> {
+    values
+        .into_iter()
+        .map(|value| IndexKey::of(value.borrow(), interner))
+        .collect()
+}
+
+fn number(expr: &Expr) -> Option<f64> {
+    match literal(expr)? {
+        Value::Integer(n) => Some(*n as f64),
+        Value::Float(x) if !x.is_nan() => Some(*x),
+        _ => None,
+    }
+}
+
+/// Find atoms that cannot all hold, preferring a pair over every atom on
+/// the subject in conflict
+fn conflicting<'e>(atoms: &[Atom<'e>]) -> Option<Vec<&'e Expr>> {
+    let mut subjects: Vec<&Subject> = Vec::new();
+    for atom in atoms {
+        if !subjects.contains(&&atom.subject) {
+            subjects.push(&atom.subject);
+        }
+    }
+    for subject in subjects {
+        let on: Vec<&Atom> = atoms
+            .iter()
+            .filter(|atom| atom.subject == *subject)
+            .collect();
+        if !unsatisfiable_together(on.iter().map(|atom| &atom.constraint)) {
+            continue;
+        }
+        for (i, first) in on.iter().enumerate() {
+            if unsatisfiable_together([&first.constraint]) {
+                return Some(vec![first.expr]);
+            }
+            for second in &on[i + 1..] {
+                if unsatisfiable_together([&first.constraint, &second.constraint]) {
+                    return Some(vec![first.expr, second.expr]);
+                }
+            }
+        }
+        return Some(on.iter().map(|atom| atom.expr).collect());
+    }
+    None
+}
+
+/// Check if no value satisfies every constraint
+fn unsatisfiable_together<'c>(constraints: impl IntoIterator<Item = &'c Constraint>) -> bool {
+    let mut one_of: Option<FxHashSet<IndexKey>> = None;
+    let mut none_of = FxHashSet::default();
+    let mut lower: Option<(f64, bool)> = None;
+    let mut upper: Option<(f64, bool)> = None;
+    let mut holds = None;
+    for constraint in constraints {
+        match constraint {
+            Constraint::OneOf(keys) => {
+                let allowed = match one_of.take() {
+                    Some(allowed) => keys.intersection(&allowed).copied().collect(),
+                    None => keys.clone(),
+                };
+                one_of = Some(allowed);
+            }
+            Constraint::NoneOf(keys) => none_of.extend(keys.iter().copied()),
+            &Constraint::Above(bound, included) => {
+                let tighter = lower.is_none_or(|(lower, lower_included)| {
+                    bound > lower || (bound == lower && lower_included && !included)
+                });
+                if tighter {
+                    lower = Some((bound, included));
+                }
+            }
+            &Constraint::Below(bound, included) => {
+                let tighter = upper.is_none_or(|(upper, upper_included)| {
+                    bound < upper || (bound == upper && upper_included && !included)
+                });
+                if tighter {
+                    upper = Some((bound, included));
+                }
+            }
+            &Constraint::Holds(value) => {
+                if holds.is_some_and(|holds| holds != value) {
+                    return true;
+                }
+                holds = Some(value);
+            }
+        }
+    }
+    let bounded = lower.is_some() || upper.is_some();
+    if let (Some((lower, lower_included)), Some((upper, upper_included))) = (lower, upper) {
+        if lower > upper || (lower == upper && !(lower_included && upper_included)) {
+            return true;
+        }
+        // The interval is a single integer that is excluded
+        if lower == upper
+            && compat::fract(lower) == 0.0
+            && none_of.contains(&IndexKey::Int(lower as i64))
+        {
+            return true;
+        }
+    }
+    let within = |key: &IndexKey| match key {
+        IndexKey::Int(n) => {
+            let n = *n as f64;
+            lower.is_none_or(|(lower, included)| n > lower || (included && n == lower))
+                && upper.is_none_or(|(upper, included)| n < upper || (included && n == upper))
+        }
+        // Only numbers compare with numbers
+        _ => !bounded,
+    };
+    one_of.is_some_and(|allowed| {
+        allowed
+            .iter()
+            .all(|key| none_of.contains(key) || !within(key))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use alloc::string::{String, ToString};
+
+    fn analyze(source: &str) -> (&'static str, Vec<String>) {
+        let mut interner = StringInterner::new();
+        let expr = parse(source, &mut interner).unwrap();
+        let show = |exprs: Vec<&Expr>| {
+            exprs
+                .into_iter()
+                .map(|expr| expr.to_sexpr(&interner))
+                .collect()
+        };
+        match satisfiability(&expr, &interner) {
+            Satisfiability::Unsatisfiable(conflict) => ("unsatisfiable", show(conflict)),
+            Satisfiability::Tautology(cover) => ("tautology", show(cover)),
+            Satisfiability::Unknown => ("unknown", Vec::new()),
+        }
+    }
+
+    #[test]
+    fn finds_contradictions() {
+        let cases: [(&str, &[&str]); 10] = [
+            ("(and (= x 1) (= x 2))", &["(= x 1)", "(= x 2)"]),
+            (
+                "(and (= x 1) (> y 0) (and (= 2 x)))",
+                &["(= x 1)", "(= 2 x)"],
+            ),
+            ("(and (> x 5) (< x 3))", &["(> x 5)", "(< x 3)"]),
+            ("(and (>= x 5) (< x 5))", &["(>= x 5)", "(< x 5)"]),
+            (
+                "(and (= x 1) (not (<= x 1.5)))",
+                &["(= x 1)", "(not (<= x 1.5))"],
+            ),
+            (
+                "(and (in x [1 2]) (!= x 1) (!= x 2))",
+                &["(in x [1 2])", "(!= x 1)", "(!= x 2)"],
+            ),
+            (
+                "(and (>= x 3) (<= x 3) (not-in x [3]))",
+                &["(>= x 3)", "(<= x 3)", "(not-in x [3])"],
+            ),
+            (
+                "(and (exists x) (not (exists x)))",
+                &["(exists x)", "(not (exists x))"],
+            ),
+            (
+                "(or (and p false) (and (= x 1) (= x \"1\")))",
+                &["false", "(= x 1)", "(= x \"1\")"],
+            ),
+            ("(not (or (= x 1) true))", &["true"]),
+        ];
+        for (source, conflict) in cases {
+            assert_eq!(
+                analyze(source),
+                (
+                    "unsatisfiable",
+                    conflict.iter().map(|s| s.to_string()).collect()
+                ),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_tautologies() {
+        let cases: [(&str, &[&str]); 4] = [
+            ("(or (< x 10) (>= x 10))", &["(< x 10)", "(>= x 10)"]),
+            ("(or (= x 1) (!= x 1.0))", &["(= x 1)", "(!= x 1.0)"]),
+            ("(or p (not p))", &["p", "(not p)"]),
+            ("(not (and (= x 1) (= x 2)))", &["(= x 1)", "(= x 2)"]),
+        ];
+        for (source, cover) in cases {
+            assert_eq!(
+                analyze(source),
+                ("tautology", cover.iter().map(|s| s.to_string()).collect()),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_the_rest_unknown() {
+        for source in [
+            "(and (= x 1) (= y 2))",
+            "(and (in x [1 2]) (= x 2.0))",
+            "(and (> x 1) (< x 2))",
+            "(or (> x 1) (< x 1))",
+            "(and (= (+ x 1) 2) (= (+ x 1) 3))",
+        ] {
+            assert_eq!(analyze(source).0, "unknown", "{source}");
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Arena-allocated expressions
+//!
+//! `ExprArena` stores expression nodes in one flat vector and the children
+//! of every call and list in a shared side table, so building or copying a
+//! tree costs a few vector pushes instead of one allocation per node.
+//! Nodes are addressed by `ExprId` and never freed individually; drop or
+//! `clear` the arena to release them. Convert with `ExprArena::alloc_expr`
+//! and `ExprArena::to_expr`.
+
+use crate::{Expr, StringId, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Handle to a node in an `ExprArena`
+///
+/// Only meaningful with the arena that produced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(u32);
+
+impl ExprId {
+    /// Get the index of the node in its arena
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A node stored in an `ExprArena`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArenaExpr {
+    /// Literal value
+    Literal(Value),
+    /// Variable reference
+    Variable(StringId),
+    /// Function call whose arguments are `ExprArena::children`
+    Call { function: StringId, args: Children },
+    /// List literal whose items are `ExprArena::children`
+    List(Children),
+    /// Expression with metadata attached
+    Annotated {
+        metadata: Vec<(StringId, Value)>,
+        expr: ExprId,
+    },
+    /// Local variables bound for the evaluation of `body`
+    Let {
+        bindings: Vec<(StringId, ExprId)>,
+        body: ExprId,
+    },
+}
+
+/// Range of the child table holding a node's children
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Children {
+    start: u32,
+    len: u32,
+}
+
+impl Children {
+    /// Get the number of children
+    pub fn len(self) -> usize {
+        self.len as usize
+    }
+
+    /// Check if there are no children
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Flat storage for expression trees
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExprArena {
+    nodes: Vec<ArenaExpr>,
+    /// Child IDs of every call and list, each node's children contiguous
+    children: Vec<ExprId>,
+}
+
+impl ExprArena {
+    /// Create an empty arena
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an arena with room for `nodes` nodes without reallocating
+    pub fn with_capacity(nodes: usize) -> Self {
+        Self {
+            nodes: Vec::with_capacity(nodes),
+            children: Vec::with_capacity(nodes),
+        }
+    }
+
+    /// Add a literal node
+    pub fn literal(&mut self, value: Value) -> ExprId {
+        self.push(ArenaExpr::Literal(value))
+    }
+
+    /// Add a variable node
+    pub fn variable(&mut self, name: StringId) -> ExprId {
+        self.push(ArenaExpr::Variable(name))
+    }
+
+    /// Add a call node over already allocated arguments
+    pub fn call(&mut self, function: StringId, args: &[ExprId]) -> ExprId {
+        let args = self.push_children(args);
+        self.push(ArenaExpr::Call { function, args })
+    }
+
+    /// Add a list node over already allocated items
+    pub fn list(&mut self, items: &[ExprId]) -> ExprId {
+        let items = self.push_children(items);
+        self.push(ArenaExpr::List(items))
+    }
+
+    /// Add an annotation node over an already allocated expression
+    pub fn annotated(&mut self, metadata: Vec<(StringId, Value)>, expr: ExprId) -> ExprId {
+        self.push(ArenaExpr::Annotated { metadata, expr })
+    }
+
+    /// Add a `let` node over already allocated expressions
+    pub fn let_in(&mut self, bindings: Vec<(StringId, ExprId)>, body: ExprId) -> ExprId {
+        self.push(ArenaExpr::Let { bindings, body })
+    }
+
+    /// Copy an `Expr` tree into the arena, returning the ID of its root
+    pub fn alloc_expr(&mut self, expr: &Expr) -> ExprId {
+        match expr {
+            Expr::Literal(value) => self.literal(value.clone()),
+            Expr::Variable(name) => self.variable(*name),
+            Expr::Call { function, args } => {
+                let args: Vec<ExprId> = args.iter().map(|arg| self.alloc_expr(arg)).collect();
+                self.call(*function, &args)
+            }
+            Expr::List(items) => {
+                let items: Vec<ExprId> = items.iter().map(|item| self.alloc_expr(item)).collect();
+                self.list(&items)
+            }
+            Expr::Annotated { metadata, expr } => {
+                let expr = self.alloc_expr(expr);
+                self.annotated(metadata.clone(), expr)
+            }
+            Expr::Let { bindings, body } => {
+                let bindings = bindings
+                    .iter()
+                    .map(|(name, value)| (*name, self.alloc_expr(value)))
+                    .collect();
+                let body = self.alloc_expr(body);
+                self.let_in(bindings, body)
+            }
+        }
+    }
+
+    /// Rebuild the `Expr` tree rooted at `id`
+    pub fn to_expr(&self, id: ExprId) -> Expr {
+        match self.get(id) {
+            ArenaExpr::Literal(value) => Expr::Literal(value.clone()),
+            ArenaExpr::Variable(name) => Expr::Variable(*name),
+            ArenaExpr::Call { function, args } => Expr::Call {
+                function: *function,
+                args: self.to_exprs(*args),
+            },
+            ArenaExpr::List(items) => Expr::List(self.to_exprs(*items)),
+            ArenaExpr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata: metadata.clone(),
+                expr: Box::new(self.to_expr(*expr)),
+            },
+            ArenaExpr::Let { bindings, body } => Expr::Let {
+                bindings: bindings
+                    .iter()
+                    .map(|&(name, value)| (name, self.to_expr(value)))
+                    .collect(),
+                body: Box::new(self.to_expr(*body)),
+            },
+        }
+    }
+
+    /// Get a node
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` did not come from this arena.
+    pub fn get(&self, id: ExprId) -> &ArenaExpr {
+        &self.nodes[id.index()]
+    }
+
+    /// Get the IDs of a call's arguments or a list's items
+    pub fn children(&self, children: Children) -> &[ExprId] {
+        let start = children.start as usize;
+        &self.children[start..start + children.len()]
+    }
+
+    /// Get the number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Check if the arena has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Remove every node, keeping the allocated storage for reuse
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.children.clear();
+    }
+
+    fn to_exprs(&self, children: Children) -> Vec<Expr> {
+        self.children(children)
+            .iter()
+            .map(|&child| self.to_expr(child))
+            .collect()
+    }
+
+    fn push(&mut self, node: ArenaExpr) -> ExprId {
+        let id = ExprId(self.nodes.len() as u32);
+        self.nodes.push(node);
+        id
+    }
+
+    fn push_children(&mut self, ids: &[ExprId]) -> Children {
+        let start = self.children.len() as u32;
+        self.children.extend_from_slice(ids);
+        Children {
+            start,
+            len: ids.len() as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, StringInterner};
+
+    #[test]
+    fn round_trip() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(or (and (= country "US") (>= age 21)) (in tier ['gold [1 2]]) (and))"#,
+            &mut interner,
+        )
+        .unwrap();
+
+        let mut arena = ExprArena::new();
+        let root = arena.alloc_expr(&expr);
+        assert_eq!(arena.len(), 16);
+        assert_eq!(arena.to_expr(root), expr);
+
+        let ArenaExpr::Call { function, args } = arena.get(root) else {
+            panic!("expected a call");
+        };
+        assert_eq!(interner.resolve(*function), Some("or"));
+        assert_eq!(args.len(), 3);
+        let last = arena.children(*args)[2];
+        assert!(matches!(arena.get(last), ArenaExpr::Call { args, .. } if args.is_empty()));
+    }
+
+    #[test]
+    fn build_directly() {
+        let mut interner = StringInterner::new();
+        let mut arena = ExprArena::with_capacity(4);
+        let age = arena.variable(interner.intern("age"));
+        let limit = arena.literal(Value::Integer(21));
+        // Nodes can be shared by several parents
+        let check = arena.call(interner.intern(">="), &[age, limit]);
+        let both = arena.call(interner.intern("and"), &[check, check]);
+
+        assert_eq!(
+            arena.to_expr(both),
+            parse("(and (>= age 21) (>= age 21))", &mut interner).unwrap()
+        );
+
+        arena.clear();
+        assert!(arena.is_empty());
+    }
+}
@@ -0,0 +1,480 @@
+//! Asynchronous evaluation
+//!
+//! With the `async` feature an `AsyncEvaluator` evaluates expressions whose
+//! variables come from an `AsyncVariableProvider` and whose custom
+//! functions may be `async`, such as lookups in Redis or a feature store.
+//! It is independent of any runtime and only needs its future polled.
+//!
+//! Evaluation runs the ordinary evaluator in rounds. A round uses the
+//! values fetched so far, and reads of anything not yet fetched give null
+//! and are noted in order. The first noted read is one the evaluation
+//! certainly needs, since everything before it saw real values, so the
+//! round fetches it and tries again; a round that notes nothing gives the
+//! result. Short circuits therefore skip awaits as they skip evaluation:
+//! in `(or (= tier "gold") (> (ltv user) 1000))` nothing is awaited for
+//! `ltv` once `tier` is `"gold"`.
+//!
+//! Reads noted after the first were made with a placeholder before them,
+//! so they are guesses. `with_max_concurrency` lets a round await that many
+//! noted reads at once, saving round trips at the cost of sometimes
+//! fetching something a short circuit skips. At the default of 1 nothing
+//! is fetched that evaluation does not read.
+//!
+//! Every variable and distinct call is awaited at most once per
+//! evaluation. Variables in the environment are never fetched.
+
+use crate::compat::FxHashMap;
+use crate::provider::VariableProvider;
+use crate::{
+    Environment, EvalError, EvalOptions, Evaluator, Expr, FunctionRegistry, StringId,
+    StringInterner, Value,
+};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::Poll;
+use std::sync::Mutex;
+
+/// A boxed future that can move between threads
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Source of values for variables missing from the environment, fetched
+/// asynchronously
+pub trait AsyncVariableProvider: Send + Sync {
+    /// Get the value of a variable, `None` if it has none
+    fn get(&self, name: StringId) -> BoxFuture<'_, Option<Value>>;
+}
+
+impl<F, Fut> AsyncVariableProvider for F
+where
+    F: Fn(StringId) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<Value>> + Send + 'static,
+{
+    fn get(&self, name: StringId) -> BoxFuture<'_, Option<Value>> {
+        Box::pin(self(name))
+    }
+}
+
+impl fmt::Debug for dyn AsyncVariableProvider + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AsyncVariableProvider")
+    }
+}
+
+/// An asynchronous host function callable from expressions
+pub type AsyncFunction =
+    Arc<dyn Fn(Vec<Value>) -> BoxFuture<'static, Result<Value, EvalError>> + Send + Sync>;
+
+/// Named asynchronous custom functions
+///
+/// They are called like functions in a `FunctionRegistry`, and take
+/// precedence over synchronous functions of the same name.
+#[derive(Clone, Default)]
+pub struct AsyncFunctionRegistry {
+    functions: FxHashMap<Box<str>, AsyncFunction>,
+}
+
+impl AsyncFunctionRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function, replacing any function with the same name
+    pub fn register<F, Fut>(&mut self, name: &str, function: F)
+    where
+        F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value, EvalError>> + Send + 'static,
+    {
+        let function: AsyncFunction = Arc::new(move |args| Box::pin(function(args)));
+        self.functions.insert(name.into(), function);
+    }
+
+    /// Remove a function, returning whether it was registered
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    /// Check if a function is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Get the number of registered functions
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Check if no functions are registered
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
+impl fmt::Debug for AsyncFunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+/// Evaluates expressions with asynchronous variables and functions
+#[derive(Debug, Clone)]
+pub struct AsyncEvaluator<'a> {
+    interner: &'a StringInterner,
+    options: EvalOptions,
+    functions: Option<&'a FunctionRegistry>,
+    async_functions: Option<&'a AsyncFunctionRegistry>,
+    provider: Option<&'a dyn AsyncVariableProvider>,
+    max_concurrency: usize,
+}
+
+/// Something a round read before it was fetched
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Fetch {
+    Variable(StringId),
+    Call(Arc<str>, Vec<Value>),
+}
+
+/// Result of awaiting a `Fetch`
+enum Outcome {
+    Variable(Option<Value>),
+    Call(Result<Value, EvalError>),
+}
+
+/// What the rounds of one evaluation have fetched and still need
+#[derive(Default)]
+struct Fetched {
+    variables: FxHashMap<StringId, Option<Value>>,
+    calls: FxHashMap<(Arc<str>, Vec<Value>), Result<Value, EvalError>>,
+    /// Reads of things not yet fetched, in the order the round made them
+    pending: Vec<Fetch>,
+}
+
+impl Fetched {
+    fn note(&mut self, fetch: Fetch) {
+        if !self.pending.contains(&fetch) {
+            self.pending.push(fetch);
+        }
+    }
+}
+
+/// Answers a round's variable reads from what has been fetched
+struct RoundProvider(Arc<Mutex<Fetched>>);
+
+impl VariableProvider for RoundProvider {
+    fn get(&self, name: StringId) -> Option<Value> {
+        let mut fetched = self.0.lock().expect("fetch state poisoned");
+        match fetched.variables.get(&name) {
+            Some(value) => value.clone(),
+            None => {
+                fetched.note(Fetch::Variable(name));
+                Some(Value::Null)
+            }
+        }
+    }
+}
+
+impl<'a> AsyncEvaluator<'a> {
+    /// Create an evaluator using the interner the expressions were built with
+    pub fn new(interner: &'a StringInterner) -> Self {
+        Self::with_options(interner, EvalOptions::default())
+    }
+
+    /// Create an evaluator with non-default options
+    pub fn with_options(interner: &'a StringInterner, options: EvalOptions) -> Self {
+        Self {
+            interner,
+            options,
+            functions: None,
+            async_functions: None,
+            provider: None,
+            max_concurrency: 1,
+        }
+    }
+
+    /// Resolve calls to names that are not builtins through `functions`
+    pub fn with_functions(mut self, functions: &'a FunctionRegistry) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
+    /// Resolve calls to names that are not builtins through the
+    /// asynchronous `functions` first
+    pub fn with_async_functions(mut self, functions: &'a AsyncFunctionRegistry) -> Self {
+        self.async_functions = Some(functions);
+        self
+    }
+
+    /// Ask `provider` for variables missing from the environment when an
+    /// expression reads them
+    pub fn with_provider(mut self, provider: &'a dyn AsyncVariableProvider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Await up to `max` variables and calls at once in each round, at
+    /// least 1
+    pub fn with_max_concurrency(mut self, max: usize) -> Self {
+        self.max_concurrency = max.max(1);
+        self
+    }
+
+    /// Get the options this evaluator uses
+    pub fn options(&self) -> &EvalOptions {
+        &self.options
+    }
+
+    /// Evaluate an expression against an environment
+    pub async fn eval(&self, expr: &Expr, env: &Environment) -> Result<Value, EvalError> {
+        let fetched = Arc::new(Mutex::new(Fetched::default()));
+        let functions = self.round_functions(&fetched);
+        let provider = RoundProvider(Arc::clone(&fetched));
+        loop {
+            let mut evaluator =
+                Evaluator::with_options(self.interner, self.options).with_functions(&functions);
+            if self.provider.is_some() {
+                evaluator = evaluator.with_provider(&provider);
+            }
+            let result = evaluator.eval(expr, env);
+
+            let batch: Vec<Fetch> = {
+                let mut fetched = fetched.lock().expect("fetch state poisoned");
+                let take = fetched.pending.len().min(self.max_concurrency);
+                let batch = fetched.pending.drain(..take).collect();
+                fetched.pending.clear();
+                batch
+            };
+            if batch.is_empty() {
+                return result;
+            }
+            let futures = batch.iter().map(|fetch| self.fetch(fetch)).collect();
+            let results = join_all(futures).await;
+            let mut fetched = fetched.lock().expect("fetch state poisoned");
+            for (fetch, outcome) in batch.into_iter().zip(results) {
+                match (fetch, outcome) {
+                    (Fetch::Variable(name), Outcome::Variable(value)) => {
+                        fetched.variables.insert(name, value);
+                    }
+                    (Fetch::Call(name, args), Outcome::Call(result)) => {
+                        fetched.calls.insert((name, args), result);
+                    }
+                    _ => unreachable!("outcomes match their fetches"),
+                }
+            }
+        }
+    }
+
+    /// Await one variable or call
+    fn fetch(&self, fetch: &Fetch) -> BoxFuture<'a, Outcome> {
+        match fetch {
+            Fetch::Variable(name) => {
+                let provider = self.provider.expect("only provided variables are noted");
+                let value = provider.get(*name);
+                Box::pin(async move { Outcome::Variable(value.await) })
+            }
+            Fetch::Call(name, args) => {
+                let functions = self.async_functions.expect("only async calls are noted");
+                let result = functions.functions[&**name](args.clone());
+                Box::pin(async move { Outcome::Call(result.await) })
+            }
+        }
+    }
+
+    /// Build the registry a round calls through, whose async functions
+    /// answer from what has been fetched
+    fn round_functions(&self, fetched: &Arc<Mutex<Fetched>>) -> FunctionRegistry {
+        let mut functions = self.functions.cloned().unwrap_or_default();
+        for name in self.async_functions.iter().flat_map(|f| f.functions.keys()) {
+            let name: Arc<str> = Arc::from(&**name);
+            let fetched = Arc::clone(fetched);
+            functions.register(&name.clone(), move |args| {
+                let mut fetched = fetched.lock().expect("fetch state poisoned");
+                let key = (Arc::clone(&name), args.to_vec());
+                match fetched.calls.get(&key) {
+                    Some(result) => result.clone(),
+                    None => {
+                        fetched.note(Fetch::Call(key.0, key.1));
+                        Ok(Value::Null)
+                    }
+                }
+            });
+        }
+        functions
+    }
+}
+
+/// Await every future concurrently, giving their outputs in order
+async fn join_all<T>(mut futures: Vec<BoxFuture<'_, T>>) -> Vec<T> {
+    let mut outputs: Vec<Option<T>> = futures.iter().map(|_| None).collect();
+    poll_fn(|cx| {
+        let mut done = true;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_some() {
+                continue;
+            }
+            match future.as_mut().poll(cx) {
+                Poll::Ready(value) => *output = Some(value),
+                Poll::Pending => done = false,
+            }
+        }
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs
+        .into_iter()
+        .map(|output| output.expect("every future finished"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Wake, Waker};
+
+    /// Run a future to completion on this thread
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = core::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    /// Pending once before finishing, like a network round trip
+    async fn round_trip() {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+    }
+
+    #[test]
+    fn fetches_what_evaluation_reads() {
+        let mut interner = StringInterner::new();
+        let rule = parse(
+            r#"(or (= tier "gold") (and (> ltv 1000) (> (risk user) 0.5) (< (risk user) 0.9)))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let (tier, ltv, user) = (
+            interner.intern("tier"),
+            interner.intern("ltv"),
+            interner.intern("user"),
+        );
+        let (gold, silver) = (interner.intern("gold"), interner.intern("silver"));
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let provider = move |name: StringId| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async move {
+                round_trip().await;
+                (name == ltv).then_some(Value::Integer(1500))
+            }
+        };
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let mut functions = AsyncFunctionRegistry::new();
+        functions.register("risk", move |args: Vec<Value>| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async move {
+                round_trip().await;
+                match args[..] {
+                    [Value::Integer(id)] => Ok(Value::Float(id as f64 / 100.0)),
+                    _ => Err(EvalError::Custom("risk expects a user ID".into())),
+                }
+            }
+        });
+        let evaluator = AsyncEvaluator::new(&interner)
+            .with_provider(&provider)
+            .with_async_functions(&functions);
+
+        let mut env = Environment::new();
+        env.insert(tier, Value::String(gold));
+        assert_eq!(block_on(evaluator.eval(&rule, &env)), Ok(Value::Bool(true)));
+        assert_eq!(fetches.load(Ordering::Relaxed), 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        env.insert(tier, Value::String(silver));
+        env.insert(user, Value::Integer(70));
+        assert_eq!(block_on(evaluator.eval(&rule, &env)), Ok(Value::Bool(true)));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        env.insert(user, Value::Null);
+        assert_eq!(
+            block_on(evaluator.eval(&rule, &env)),
+            Err(EvalError::Custom("risk expects a user ID".into()))
+        );
+
+        let missing = parse("(> churn 0.5)", &mut interner).unwrap();
+        let evaluator = AsyncEvaluator::new(&interner).with_provider(&provider);
+        assert!(matches!(
+            block_on(evaluator.eval(&missing, &env)),
+            Err(EvalError::UnknownVariable(_))
+        ));
+    }
+
+    #[test]
+    fn futures_are_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let interner = StringInterner::new();
+        let evaluator = AsyncEvaluator::new(&interner);
+        let (expr, env) = (Expr::Literal(Value::Null), Environment::new());
+        assert_send(&evaluator.eval(&expr, &env));
+    }
+
+    #[test]
+    fn limits_concurrency() {
+        let mut interner = StringInterner::new();
+        let rule = parse("(and (> a 1) (> b 1) (> c 1) (> d 1))", &mut interner).unwrap();
+        let env = Environment::new();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (current, peak) = (Arc::clone(&in_flight), Arc::clone(&most));
+        let provider = move |_| {
+            let (current, peak) = (Arc::clone(&current), Arc::clone(&peak));
+            async move {
+                let now = current.fetch_add(1, Ordering::Relaxed) + 1;
+                peak.fetch_max(now, Ordering::Relaxed);
+                round_trip().await;
+                current.fetch_sub(1, Ordering::Relaxed);
+                Some(Value::Integer(2))
+            }
+        };
+
+        for (max, expected) in [(1, 1), (3, 3), (8, 4)] {
+            most.store(0, Ordering::Relaxed);
+            let evaluator = AsyncEvaluator::new(&interner)
+                .with_provider(&provider)
+                .with_max_concurrency(max);
+            assert_eq!(block_on(evaluator.eval(&rule, &env)), Ok(Value::Bool(true)));
+            assert_eq!(most.load(Ordering::Relaxed), expected, "{}", max);
+        }
+    }
+}
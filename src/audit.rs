@@ -0,0 +1,608 @@
+//! Decision audit logs
+//!
+//! Rules that make automated decisions about people often have to explain
+//! each decision afterwards. `RuleSet::matches_audited` and
+//! `Evaluator::eval_audited` evaluate like `matches` and `eval`, and send
+//! an `AuditRecord` for every rule they evaluate to an `AuditSink`: the
+//! rule ID, a hash of the environment, the result, the operands that
+//! decided it, how long it took and when it finished. Records are printed
+//! with the interner as they are made, so they can be kept or sent
+//! anywhere without it.
+//!
+//! Auditing traces every rule with the tree walker, so it is slower than
+//! plain evaluation. Records can go to:
+//!
+//! - any `Fn(&AuditRecord)`
+//! - an `mpsc::Sender` or `SyncSender`, to hand them to another thread
+//! - a `FileSink`, with the `json` feature, which appends them to a file
+//!   as JSON lines
+//! - an `OtlpSink`, with the `json` feature, which batches them into
+//!   OpenTelemetry (OTLP/JSON) log export requests for a collector
+
+use crate::rollout::fnv1a;
+use crate::trace::Trace;
+use crate::{Environment, EvalError, Evaluator, Expr, Interner, RuleId, RuleSet, Value};
+#[cfg(feature = "json")]
+use serde_json::{json, Value as Json};
+use std::fmt::{self, Write as _};
+#[cfg(feature = "json")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "json")]
+use std::io::{self, Write};
+#[cfg(feature = "json")]
+use std::path::Path;
+use std::sync::mpsc::{Sender, SyncSender};
+#[cfg(feature = "json")]
+use std::sync::Mutex;
+#[cfg(feature = "json")]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime};
+
+/// One evaluation of a rule, as recorded for an audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    rule_id: RuleId,
+    context_hash: u64,
+    result: Result<String, String>,
+    trace: Vec<String>,
+    elapsed: Duration,
+    timestamp: SystemTime,
+}
+
+impl AuditRecord {
+    fn new(rule_id: RuleId, context_hash: u64, trace: &Trace, interner: &dyn Interner) -> Self {
+        let result = match trace.result() {
+            Ok(value) => Ok(value.display(interner).to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+        let lines = core::iter::once(trace)
+            .chain(trace.children())
+            .map(|node| step(node, interner))
+            .collect();
+        Self {
+            rule_id,
+            context_hash,
+            result,
+            trace: lines,
+            elapsed: trace.elapsed(),
+            timestamp: now(),
+        }
+    }
+
+    /// Get the ID of the rule evaluated
+    pub fn rule_id(&self) -> RuleId {
+        self.rule_id
+    }
+
+    /// Get the `context_hash` of the environment the rule was evaluated
+    /// against
+    pub fn context_hash(&self) -> u64 {
+        self.context_hash
+    }
+
+    /// Get the printed result, or the error the evaluation failed with
+    pub fn result(&self) -> Result<&str, &str> {
+        self.result.as_deref().map_err(String::as_str)
+    }
+
+    /// Get the rule and then each operand it evaluated, as
+    /// `expr => result`
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    /// Get how long the evaluation took
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Get when the evaluation finished
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Convert the record to a JSON object, as written by `FileSink`
+    ///
+    /// ```json
+    /// {"rule_id": 7, "context_hash": "5d0f1bb2a6c3e481", "result": "true",
+    ///  "trace": ["(> score 700) => true", "score => 712", "700 => 700"],
+    ///  "elapsed_ns": 2140, "timestamp_ns": 1760572800000000000}
+    /// ```
+    ///
+    /// A failed evaluation has an `error` in place of `result`. The hash is
+    /// a hex string because JSON numbers lose precision past 2^53.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Json {
+        let mut json = json!({
+            "rule_id": self.rule_id,
+            "context_hash": format!("{:016x}", self.context_hash),
+            "trace": self.trace,
+            "elapsed_ns": nanos(self.elapsed),
+            "timestamp_ns": unix_nanos(self.timestamp),
+        });
+        match &self.result {
+            Ok(value) => json["result"] = value.as_str().into(),
+            Err(error) => json["error"] = error.as_str().into(),
+        }
+        json
+    }
+}
+
+/// Receives a record of every audited evaluation
+///
+/// Sinks are shared by every evaluation they audit, so they record through
+/// atomics or locks.
+pub trait AuditSink: Send + Sync {
+    /// An audited rule was evaluated
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// Records sent after the receiver is dropped are discarded
+impl AuditSink for Sender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        let _ = self.send(record.clone());
+    }
+}
+
+/// Blocks while the channel is full. Records sent after the receiver is
+/// dropped are discarded
+impl AuditSink for SyncSender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        let _ = self.send(record.clone());
+    }
+}
+
+impl fmt::Debug for dyn AuditSink + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// Hash an environment for an audit record
+///
+/// The hash covers every variable's name and printed value, in name order,
+/// so it is the same for equal environments in any process and with any
+/// interner, and lets a decision be matched to the context it was made in
+/// without logging the context itself.
+pub fn context_hash(env: &Environment, interner: &dyn Interner) -> u64 {
+    let mut entries: Vec<_> = env.iter().collect();
+    entries.sort_by_key(|&(name, _)| (interner.resolve(name), name));
+    let mut text = String::new();
+    for (name, value) in entries {
+        let _ = write!(
+            text,
+            "{}\0{}\0",
+            interner.resolve(name).unwrap_or_default(),
+            value.display(interner)
+        );
+    }
+    fnv1a(text.into_bytes())
+}
+
+impl Evaluator<'_> {
+    /// Evaluate an expression as the rule `rule_id`, sending a record of
+    /// the evaluation to `sink`
+    ///
+    /// The result is the same as `eval`.
+    pub fn eval_audited(
+        &self,
+        rule_id: RuleId,
+        expr: &Expr,
+        env: &Environment,
+        sink: &dyn AuditSink,
+    ) -> Result<Value, EvalError> {
+        let hash = context_hash(env, self.interner());
+        self.audit(rule_id, expr, env, hash, sink)
+    }
+
+    fn audit(
+        &self,
+        rule_id: RuleId,
+        expr: &Expr,
+        env: &Environment,
+        context_hash: u64,
+        sink: &dyn AuditSink,
+    ) -> Result<Value, EvalError> {
+        let (result, trace) = self.eval_with_trace(expr, env);
+        sink.record(&AuditRecord::new(
+            rule_id,
+            context_hash,
+            &trace,
+            self.interner(),
+        ));
+        result
+    }
+}
+
+impl RuleSet {
+    /// Return the IDs of all rules that evaluate to `true`, like `matches`,
+    /// sending a record of every rule evaluated to `sink`
+    ///
+    /// Rules the index rules out are not evaluated and have no record.
+    pub fn matches_audited(&self, env: &Environment, sink: &dyn AuditSink) -> Vec<RuleId> {
+        let evaluator = Evaluator::new(self.interner());
+        let hash = context_hash(env, self.interner());
+        self.candidates(env)
+            .into_iter()
+            .filter_map(|slot| {
+                let (id, expr) = self.rule_in(slot)?;
+                let result = evaluator.audit(id, expr, env, hash, sink);
+                (result == Ok(Value::Bool(true))).then_some(id)
+            })
+            .collect()
+    }
+}
+
+/// Appends audit records to a file or other writer as JSON lines
+///
+/// Each record is written and flushed as one line. A sink cannot fail an
+/// evaluation, so the first write that fails is kept for `take_error`,
+/// and hosts that must not lose records check it.
+#[cfg(feature = "json")]
+#[derive(Debug)]
+pub struct FileSink<W = File> {
+    out: Mutex<W>,
+    error: Mutex<Option<io::Error>>,
+}
+
+#[cfg(feature = "json")]
+impl FileSink {
+    /// Open a file to append records to, creating it if it does not exist
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+#[cfg(feature = "json")]
+impl<W: Write + Send> FileSink<W> {
+    /// Write records to `out`
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Take the first error writing a record hit, if any
+    pub fn take_error(&self) -> Option<io::Error> {
+        lock(&self.error).take()
+    }
+
+    /// Get the writer back
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "json")]
+impl<W: Write + Send> AuditSink for FileSink<W> {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = record.to_json().to_string();
+        line.push('\n');
+        let written = {
+            let mut out = lock(&self.out);
+            out.write_all(line.as_bytes()).and_then(|()| out.flush())
+        };
+        if let Err(error) = written {
+            lock(&self.error).get_or_insert(error);
+        }
+    }
+}
+
+/// Batches audit records into OpenTelemetry log export requests
+///
+/// Records become OTLP log records, at `INFO` or at `ERROR` for failed
+/// evaluations, with the attributes `ironwood.rule_id`,
+/// `ironwood.context_hash`, `ironwood.result` or `ironwood.error`,
+/// `ironwood.trace` and `ironwood.elapsed_ns`. Once a batch is full, it is
+/// passed to `export` as the JSON body of an `ExportLogsServiceRequest`,
+/// which the host sends to a collector's `/v1/logs` with any HTTP client.
+/// Records still pending are exported by `flush` and on drop.
+#[cfg(feature = "json")]
+pub struct OtlpSink<F: Fn(String) + Send + Sync> {
+    export: F,
+    batch: usize,
+    pending: Mutex<Vec<Json>>,
+}
+
+#[cfg(feature = "json")]
+impl<F: Fn(String) + Send + Sync> OtlpSink<F> {
+    /// Records exported together by default
+    pub const DEFAULT_BATCH: usize = 64;
+
+    /// Export batches of records through `export`
+    pub fn new(export: F) -> Self {
+        Self {
+            export,
+            batch: Self::DEFAULT_BATCH,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Export records in batches of `batch`, at least one
+    pub fn with_batch_size(mut self, batch: usize) -> Self {
+        self.batch = batch.max(1);
+        self
+    }
+
+    /// Export any pending records now
+    pub fn flush(&self) {
+        let records = core::mem::take(&mut *lock(&self.pending));
+        self.export(records);
+    }
+
+    fn export(&self, records: Vec<Json>) {
+        if records.is_empty() {
+            return;
+        }
+        let request = json!({
+            "resourceLogs": [{
+                "resource": {},
+                "scopeLogs": [{
+                    "scope": {"name": "ironwood", "version": env!("CARGO_PKG_VERSION")},
+                    "logRecords": records,
+                }],
+            }],
+        });
+        (self.export)(request.to_string());
+    }
+}
+
+#[cfg(feature = "json")]
+impl<F: Fn(String) + Send + Sync> AuditSink for OtlpSink<F> {
+    fn record(&self, record: &AuditRecord) {
+        let full = {
+            let mut pending = lock(&self.pending);
+            pending.push(otlp_record(record));
+            if pending.len() < self.batch {
+                return;
+            }
+            core::mem::take(&mut *pending)
+        };
+        self.export(full);
+    }
+}
+
+#[cfg(feature = "json")]
+impl<F: Fn(String) + Send + Sync> Drop for OtlpSink<F> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(feature = "json")]
+impl<F: Fn(String) + Send + Sync> fmt::Debug for OtlpSink<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtlpSink")
+            .field("batch", &self.batch)
+            .field("pending", &lock(&self.pending).len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Convert a record to an OTLP/JSON log record
+#[cfg(feature = "json")]
+fn otlp_record(record: &AuditRecord) -> Json {
+    // OTLP/JSON writes 64-bit integers as strings
+    let int = |n: u64| json!({"intValue": n.to_string()});
+    let string = |s: &str| json!({"stringValue": s});
+    let attribute = |key: &str, value: Json| json!({"key": key, "value": value});
+    let (severity, text, outcome) = match &record.result {
+        Ok(value) => (9, "INFO", attribute("ironwood.result", string(value))),
+        Err(error) => (17, "ERROR", attribute("ironwood.error", string(error))),
+    };
+    let trace: Vec<Json> = record.trace.iter().map(|line| string(line)).collect();
+    json!({
+        "timeUnixNano": unix_nanos(record.timestamp).to_string(),
+        "severityNumber": severity,
+        "severityText": text,
+        "body": string("ironwood decision"),
+        "attributes": [
+            attribute("ironwood.rule_id", int(record.rule_id)),
+            attribute(
+                "ironwood.context_hash",
+                string(&format!("{:016x}", record.context_hash)),
+            ),
+            outcome,
+            attribute("ironwood.trace", json!({"arrayValue": {"values": trace}})),
+            attribute("ironwood.elapsed_ns", int(nanos(record.elapsed))),
+        ],
+    })
+}
+
+/// A traced node as `expr => result`
+fn step(node: &Trace, interner: &dyn Interner) -> String {
+    match node.result() {
+        Ok(value) => format!(
+            "{} => {}",
+            node.expr().display(interner),
+            value.display(interner)
+        ),
+        Err(error) => format!("{} => error: {}", node.expr().display(interner), error),
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+/// `SystemTime::now` panics on targets without a clock
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn now() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
+
+#[cfg(feature = "json")]
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(feature = "json")]
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, nanos)
+}
+
+/// Lock a mutex, recovering it if a writer panicked
+#[cfg(feature = "json")]
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, StringInterner};
+    use std::sync::mpsc;
+
+    fn rule_set() -> RuleSet {
+        let mut set = RuleSet::new();
+        for (id, source) in [
+            (1, r#"(and (= country "US") (> score 700))"#),
+            (2, r#"(= country "DE")"#),
+            (3, "(> (/ score missing) 1)"),
+        ] {
+            let expr = parse(source, set.interner_mut()).unwrap();
+            set.add_rule(id, expr);
+        }
+        set
+    }
+
+    fn env(set: &mut RuleSet, score: i64) -> Environment {
+        env_with(set.interner_mut(), score)
+    }
+
+    #[test]
+    fn records_rule_sets() {
+        let mut set = rule_set();
+        let env = env(&mut set, 712);
+        let (sender, receiver) = mpsc::channel();
+
+        assert_eq!(set.matches_audited(&env, &sender), set.matches(&env));
+        let records: Vec<AuditRecord> = receiver.try_iter().collect();
+        // The index rules out rule 2
+        let ids: Vec<_> = records.iter().map(AuditRecord::rule_id).collect();
+        assert_eq!(ids, [1, 3]);
+
+        let record = &records[0];
+        assert_eq!(record.result(), Ok("true"));
+        assert_eq!(
+            record.trace(),
+            [
+                r#"(and (= country "US") (> score 700)) => true"#,
+                r#"(= country "US") => true"#,
+                "(> score 700) => true",
+            ]
+        );
+        assert_eq!(record.context_hash(), context_hash(&env, set.interner()));
+        assert!(record.timestamp() > SystemTime::UNIX_EPOCH);
+        assert!(records[1].result().is_err());
+    }
+
+    #[test]
+    fn hashes_contexts() {
+        let mut interner = StringInterner::new();
+        let env = env_with(&mut interner, 712);
+        let hash = context_hash(&env, &interner);
+
+        // Equal contexts hash the same with any interner
+        let mut other = StringInterner::new();
+        other.intern("padding");
+        let copy = env_with(&mut other, 712);
+        assert_eq!(context_hash(&copy, &other), hash);
+
+        let changed = env_with(&mut other, 713);
+        assert_ne!(context_hash(&changed, &other), hash);
+    }
+
+    fn env_with(interner: &mut StringInterner, score: i64) -> Environment {
+        let mut env = Environment::new();
+        env.insert(interner.intern("score"), Value::Integer(score));
+        let us = interner.intern("US");
+        env.insert(interner.intern("country"), Value::String(us));
+        env
+    }
+
+    #[test]
+    fn audits_evaluators() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(>= age 21)", &mut interner).unwrap();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        let records = std::sync::Mutex::new(Vec::new());
+        let sink = |record: &AuditRecord| records.lock().unwrap().push(record.clone());
+        let evaluator = Evaluator::new(&interner);
+
+        let result = evaluator.eval_audited(9, &expr, &env, &sink);
+        assert_eq!(result, evaluator.eval(&expr, &env));
+        let records = records.into_inner().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rule_id(), 9);
+        assert_eq!(
+            records[0].trace(),
+            ["(>= age 21) => true", "age => 30", "21 => 21"]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn writes_json_lines() {
+        let mut set = rule_set();
+        let env = env(&mut set, 640);
+        let sink = FileSink::new(Vec::new());
+        set.matches_audited(&env, &sink);
+        assert!(sink.take_error().is_none());
+
+        let out = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<Json> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["rule_id"], 1);
+        assert_eq!(lines[0]["result"], "false");
+        assert_eq!(lines[0]["trace"][2], "(> score 700) => false");
+        assert!(lines[1]["error"].is_string());
+        assert!(lines[1]["timestamp_ns"].as_u64().unwrap() > 0);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn exports_otlp_batches() {
+        let mut set = rule_set();
+        let env = env(&mut set, 712);
+        let (sender, receiver) = mpsc::channel();
+        let sink = OtlpSink::new(move |body| sender.send(body).unwrap()).with_batch_size(3);
+
+        set.matches_audited(&env, &sink);
+        assert!(receiver.try_recv().is_err());
+        set.matches_audited(&env, &sink);
+        drop(sink);
+
+        let bodies: Vec<Json> = receiver
+            .try_iter()
+            .map(|body| serde_json::from_str(&body).unwrap())
+            .collect();
+        assert_eq!(bodies.len(), 2);
+        let records = &bodies[0]["resourceLogs"][0]["scopeLogs"][0]["logRecords"];
+        assert_eq!(records.as_array().unwrap().len(), 3);
+        assert_eq!(records[0]["severityText"], "INFO");
+        assert_eq!(records[1]["severityText"], "ERROR");
+        assert_eq!(
+            records[0]["attributes"][0],
+            json!({"key": "ironwood.rule_id", "value": {"intValue": "1"}})
+        );
+        let rest = &bodies[1]["resourceLogs"][0]["scopeLogs"][0]["logRecords"];
+        assert_eq!(rest.as_array().unwrap().len(), 1);
+    }
+}
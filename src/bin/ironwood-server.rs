@@ -0,0 +1,504 @@
+//! Evaluation service
+//!
+//! ```text
+//! ironwood-server BUNDLE [--listen 127.0.0.1:8080]
+//! ```
+//!
+//! Serves the rules of a bundle over HTTP so services in other languages
+//! can evaluate them without bindings. The bundle is either written by
+//! `RuleSet::to_bytes` or a rule file, whose rules are numbered from 1 in
+//! file order.
+//!
+//! - `POST /evaluate` with `{"rule_id": 7, "context": {...}}` evaluates a
+//!   rule against a context read like `json::context_from_json`, returning
+//!   `{"rule_id": 7, "result": ..., "trace": {...}}` or an `"error"` in
+//!   place of the result. `"trace": false` leaves the trace out, which is
+//!   much faster.
+//! - `GET /health` reports that the service is up and how many rules it
+//!   has.
+//! - `GET /metrics` reports counters in the Prometheus text format.
+//!
+//! Connections are served by a fixed pool of `WORKERS` threads and closed
+//! after one response. Further connections wait in a bounded queue, reads
+//! and writes time out after `TIMEOUT`, and a request line and headers
+//! longer than `MAX_HEAD` are refused with 431, so slow or oversized
+//! requests cannot exhaust the service. Each request interns its strings
+//! into a child of the bundle's interner, so evaluations run in parallel
+//! and the bundle's interner never grows.
+
+use ironwood::json::value_to_json;
+use ironwood::metrics::MetricsSink;
+use ironwood::{
+    parse_many, Environment, EvalError, Evaluator, RuleId, RuleSet, StringInterner, Trace, Value,
+};
+use serde_json::{json, Value as Json};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use std::{env, fs, thread};
+
+const USAGE: &str = "usage: ironwood-server BUNDLE [--listen ADDR]";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// Largest request body accepted
+const MAX_BODY: usize = 1 << 20;
+
+/// Largest request line and headers accepted, in bytes together
+const MAX_HEAD: u64 = 16 << 10;
+
+/// Number of threads serving connections, and of accepted connections
+/// waiting for one
+const WORKERS: usize = 32;
+
+/// Longest a read or write on a connection may block
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Magic bytes of a bundle written by `RuleSet::to_bytes`
+const BUNDLE_MAGIC: &[u8] = b"IRWR";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("ironwood-server: {}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut bundle = None;
+    let mut listen = DEFAULT_LISTEN;
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--listen" => listen = args.next().ok_or("`--listen` needs an address")?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if bundle.is_none() => bundle = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`\n{}", arg, USAGE)),
+        }
+    }
+    let bundle = bundle.ok_or_else(|| USAGE.to_string())?;
+    let bytes = fs::read(bundle).map_err(|error| format!("{}: {}", bundle, error))?;
+    let rules = load_bundle(&bytes).map_err(|error| format!("{}: {}", bundle, error))?;
+
+    let listener = TcpListener::bind(listen).map_err(|error| format!("{}: {}", listen, error))?;
+    eprintln!("serving {} rule(s) on {}", rules.len(), listen);
+    let service = Arc::new(Service::new(rules));
+    // Accepting blocks while every worker is busy and the queue is full
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(WORKERS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let (service, receiver) = (Arc::clone(&service), Arc::clone(&receiver));
+        thread::spawn(move || loop {
+            let Ok(stream) = receiver.lock().unwrap().recv() else {
+                return;
+            };
+            if let Err(error) = serve(stream, &service) {
+                eprintln!("connection failed: {}", error);
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        if sender.send(stream).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Load a bundle from `RuleSet::to_bytes` or the text of a rule file
+fn load_bundle(bytes: &[u8]) -> Result<RuleSet, String> {
+    if bytes.starts_with(BUNDLE_MAGIC) {
+        return RuleSet::from_bytes(bytes).map_err(|error| error.to_string());
+    }
+    let source = std::str::from_utf8(bytes).map_err(|error| error.to_string())?;
+    let mut interner = StringInterner::new();
+    let exprs = parse_many(source, &mut interner)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| error.to_string())?;
+    let mut rules = RuleSet::with_interner(interner);
+    for (id, expr) in (1..).zip(exprs) {
+        rules.add_rule(id, expr);
+    }
+    Ok(rules)
+}
+
+/// Counters exported by `GET /metrics`
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    evaluations: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl MetricsSink for Counters {
+    fn evaluation(&self, elapsed: Duration, result: &Result<Value, EvalError>) {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The loaded rules and what the service has done with them
+struct Service {
+    rules: RuleSet,
+    count: usize,
+    counters: Counters,
+}
+
+/// Status, content type and body of a response
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Json) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, json!({ "error": message.into() }))
+    }
+}
+
+impl Service {
+    fn new(rules: RuleSet) -> Self {
+        Service {
+            count: rules.len(),
+            rules,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Answer one request
+    fn handle(&self, method: &str, path: &str, body: &[u8]) -> Response {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+        match (method, path) {
+            ("POST", "/evaluate") => match serde_json::from_slice(body) {
+                Ok(request) => self.evaluate(&request),
+                Err(error) => Response::error(400, format!("invalid JSON: {}", error)),
+            },
+            ("GET", "/health") => Response::json(200, json!({"status": "ok", "rules": self.count})),
+            ("GET", "/metrics") => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: self.metrics(),
+            },
+            (_, "/evaluate" | "/health" | "/metrics") => {
+                Response::error(405, format!("{} not allowed", method))
+            }
+            _ => Response::error(404, format!("no route for {}", path)),
+        }
+    }
+
+    fn evaluate(&self, request: &Json) -> Response {
+        let Some(id) = request.get("rule_id").and_then(Json::as_u64) else {
+            return Response::error(400, "`rule_id` must be a non-negative integer");
+        };
+        let context = request.get("context").cloned().unwrap_or_else(|| json!({}));
+        let traced = request.get("trace").and_then(Json::as_bool).unwrap_or(true);
+
+        evaluate_rule(&self.rules, id, &context, traced, &self.counters)
+    }
+
+    fn metrics(&self) -> String {
+        let counters = &self.counters;
+        let read = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            "# TYPE ironwood_requests_total counter\n\
+             ironwood_requests_total {}\n\
+             # TYPE ironwood_evaluations_total counter\n\
+             ironwood_evaluations_total {}\n\
+             # TYPE ironwood_evaluation_errors_total counter\n\
+             ironwood_evaluation_errors_total {}\n\
+             # TYPE ironwood_evaluation_seconds_total counter\n\
+             ironwood_evaluation_seconds_total {}\n\
+             # TYPE ironwood_rules gauge\n\
+             ironwood_rules {}\n",
+            read(&counters.requests),
+            read(&counters.evaluations),
+            read(&counters.errors),
+            read(&counters.nanos) as f64 / 1e9,
+            self.count,
+        )
+    }
+}
+
+/// Evaluate a rule against a JSON context interned into a child of the
+/// rules' interner
+fn evaluate_rule(
+    rules: &RuleSet,
+    id: RuleId,
+    context: &Json,
+    traced: bool,
+    counters: &Counters,
+) -> Response {
+    let Some(expr) = rules.rule(id) else {
+        return Response::error(404, format!("no rule {}", id));
+    };
+    let mut interner = rules.interner().child();
+    let env = match Environment::from_json(context, &mut interner) {
+        Ok(env) => env,
+        Err(error) => return Response::error(400, error.to_string()),
+    };
+    let interner = &interner;
+    let evaluator = Evaluator::new(interner).with_metrics(counters);
+    let mut body = json!({ "rule_id": id });
+    let result = if traced {
+        let (result, trace) = evaluator.eval_with_trace(expr, &env);
+        body["trace"] = trace_to_json(&trace, interner);
+        result
+    } else {
+        evaluator.eval(expr, &env)
+    };
+    match result_to_json(&result, interner) {
+        Ok(value) => body["result"] = value,
+        Err(message) => body["error"] = message.into(),
+    }
+    Response::json(200, body)
+}
+
+fn result_to_json(
+    result: &Result<Value, EvalError>,
+    interner: &StringInterner,
+) -> Result<Json, String> {
+    match result {
+        Ok(value) => value_to_json(value, interner).map_err(|error| error.to_string()),
+        Err(error) => Err(describe_eval(error, interner)),
+    }
+}
+
+fn trace_to_json(trace: &Trace, interner: &StringInterner) -> Json {
+    let mut node = json!({
+        "expr": trace.expr().to_sexpr(interner),
+        "elapsed_us": trace.elapsed().as_micros() as u64,
+    });
+    match result_to_json(trace.result(), interner) {
+        Ok(value) => node["result"] = value,
+        Err(message) => node["error"] = message.into(),
+    }
+    let children: Vec<Json> = trace
+        .children()
+        .iter()
+        .map(|child| trace_to_json(child, interner))
+        .collect();
+    if !children.is_empty() {
+        node["children"] = children.into();
+    }
+    node
+}
+
+/// Describe an evaluation error with interned names resolved
+fn describe_eval(error: &EvalError, interner: &StringInterner) -> String {
+    let name = |id| interner.resolve(id).unwrap_or("?");
+    match error {
+        EvalError::UnknownVariable(id) => format!("unknown variable `{}`", name(*id)),
+        EvalError::UnknownFunction(id) => format!("unknown function `{}`", name(*id)),
+        EvalError::Nondeterministic(id) => format!("function `{}` is not deterministic", name(*id)),
+        other => other.to_string(),
+    }
+}
+
+/// Read one request and write its response
+fn serve(stream: TcpStream, service: &Service) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader) {
+        Ok((method, path, body)) => service.handle(&method, &path, &body),
+        Err(response) => response,
+    };
+    write_response(&mut &stream, &response)
+}
+
+/// Read the method, path and body of an HTTP/1.1 request, or the error
+/// response to send instead
+fn read_request(reader: &mut impl BufRead) -> Result<(String, String, Vec<u8>), Response> {
+    let bad = |message: String| Response::error(400, message);
+    let mut head = reader.take(MAX_HEAD);
+    let mut line = String::new();
+    read_head_line(&mut head, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad("malformed request line".into()));
+    };
+    let (method, path) = (
+        method.to_string(),
+        target.split('?').next().unwrap_or("/").to_string(),
+    );
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        read_head_line(&mut head, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| bad("invalid Content-Length".into()))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(bad(format!("body larger than {} bytes", MAX_BODY)));
+    }
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|error| bad(error.to_string()))?;
+    Ok((method, path, body))
+}
+
+/// Read one line of the request line and headers, which fails with 431
+/// once they exceed `MAX_HEAD`
+fn read_head_line(head: &mut io::Take<impl BufRead>, line: &mut String) -> Result<(), Response> {
+    head.read_line(line)
+        .map_err(|error| Response::error(400, error.to_string()))?;
+    if !line.ends_with('\n') && head.limit() == 0 {
+        return Err(Response::error(
+            431,
+            format!("request line and headers larger than {} bytes", MAX_HEAD),
+        ));
+    }
+    Ok(())
+}
+
+fn write_response(out: &mut impl Write, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Error",
+    };
+    write!(
+        out,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> Service {
+        let source = r#"(and (>= user.age 21) (= country "US"))
+                        (> missing 1)"#;
+        Service::new(load_bundle(source.as_bytes()).unwrap())
+    }
+
+    fn post(service: &Service, body: Json) -> (u16, Json) {
+        let response = service.handle("POST", "/evaluate", body.to_string().as_bytes());
+        (
+            response.status,
+            serde_json::from_str(&response.body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn evaluates_rules() {
+        let service = service();
+        let context = json!({"user": {"age": 30}, "country": "US"});
+        let (status, body) = post(&service, json!({"rule_id": 1, "context": context}));
+        assert_eq!(status, 200);
+        assert_eq!(body["result"], json!(true));
+        assert_eq!(
+            body["trace"]["expr"],
+            json!(r#"(and (>= user.age 21) (= country "US"))"#)
+        );
+        assert_eq!(body["trace"]["children"].as_array().unwrap().len(), 2);
+
+        let request = json!({"rule_id": 2, "context": {}, "trace": false});
+        let (status, body) = post(&service, request);
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!({"rule_id": 2, "error": "unknown variable `missing`"})
+        );
+
+        // Strings of a request never reach the bundle's interner
+        post(
+            &service,
+            json!({"rule_id": 1, "context": {"fresh": "text"}}),
+        );
+        assert_eq!(service.rules.interner().get_id("fresh"), None);
+
+        assert_eq!(post(&service, json!({"rule_id": 9})).0, 404);
+        assert_eq!(post(&service, json!({"context": {}})).0, 400);
+        assert_eq!(service.handle("POST", "/evaluate", b"{").status, 400);
+        assert_eq!(service.handle("GET", "/evaluate", b"").status, 405);
+
+        let health = service.handle("GET", "/health", b"");
+        assert_eq!(health.body, json!({"status": "ok", "rules": 2}).to_string());
+        let metrics = service.handle("GET", "/metrics", b"").body;
+        assert!(
+            metrics.contains("ironwood_evaluations_total 3\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("ironwood_evaluation_errors_total 2\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn reads_requests() {
+        let input = b"POST /evaluate?x=1 HTTP/1.1\r\nHost: a\r\ncontent-length: 2\r\n\r\n{}";
+        let (method, path, body) = read_request(&mut &input[..]).unwrap();
+        assert_eq!(
+            (method.as_str(), path.as_str(), &body[..]),
+            ("POST", "/evaluate", &b"{}"[..])
+        );
+        assert_eq!(read_request(&mut &b"\r\n"[..]).unwrap_err().status, 400);
+
+        // Request lines and headers are bounded however they are split
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD as usize));
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(4000));
+        for input in [long_line, many_headers] {
+            let error = read_request(&mut input.as_bytes()).unwrap_err();
+            assert_eq!(error.status, 431);
+        }
+
+        let mut out = Vec::new();
+        write_response(&mut out, &Response::error(404, "gone")).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(text.ends_with("\r\n\r\n{\"error\":\"gone\"}"));
+    }
+}
@@ -0,0 +1,413 @@
+//! Command-line interface
+//!
+//! ```text
+//! ironwood [repl] [--context ctx.json]
+//! ironwood eval rule.sexp [--context ctx.json] [--explain]
+//! ironwood check rules/ [--schema schema.json]
+//! ```
+//!
+//! `repl` evaluates expressions as they are typed, `eval` evaluates every
+//! rule of a file and prints one result per line, and `check` parses every
+//! `.sexp` file under the given paths and typechecks its rules against a
+//! schema, exiting with status 1 if any rule is invalid so rule
+//! repositories can be validated in CI. Contexts and schemas are read with
+//! `json::context_from_json` and `json::schema_from_json`.
+
+use ironwood::json::{context_from_json, schema_from_json};
+use ironwood::{
+    parse_many, typecheck, Context, EvalError, Evaluator, Expr, IronwoodError, Schema,
+    StringInterner, TypeError,
+};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::{env, fs};
+
+const USAGE: &str = "usage:
+  ironwood [repl] [--context ctx.json]
+  ironwood eval rule.sexp [--context ctx.json] [--explain]
+  ironwood check PATH... [--schema schema.json]";
+
+/// Extension of rule files found by `check` when walking directories
+const RULE_EXTENSION: &str = "sexp";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => repl(&[]),
+        Some("repl") => repl(&args[1..]),
+        Some("eval") => eval(&args[1..]),
+        Some("check") => check(&args[1..]),
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            Ok(true)
+        }
+        Some(arg) if arg.starts_with("--") => repl(&args),
+        Some(command) => Err(format!("unknown command `{}`\n{}", command, USAGE)),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("ironwood: {}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Positional arguments and options of a command
+struct Args<'a> {
+    paths: Vec<&'a str>,
+    context: Option<&'a str>,
+    schema: Option<&'a str>,
+    explain: bool,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> Result<Self, String> {
+        let mut parsed = Args {
+            paths: Vec::new(),
+            context: None,
+            schema: None,
+            explain: false,
+        };
+        let mut args = args.iter().map(String::as_str);
+        while let Some(arg) = args.next() {
+            match arg {
+                "--context" | "--schema" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("`{}` needs a file", arg))?;
+                    match arg {
+                        "--context" => parsed.context = Some(value),
+                        _ => parsed.schema = Some(value),
+                    }
+                }
+                "--explain" => parsed.explain = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+                _ => parsed.paths.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Evaluate expressions read from stdin, one result per expression
+///
+/// Input is read until it parses, so an expression may span lines.
+/// `:context FILE` replaces the attributes, `:explain EXPR` prints a trace
+/// and `:quit` exits.
+fn repl(args: &[String]) -> Result<bool, String> {
+    let args = Args::parse(args)?;
+    let mut context = Context::new();
+    if let Some(path) = args.context {
+        load_context(path, &mut context)?;
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { ". " });
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let Some(line) = lines.next() else {
+            return Ok(true);
+        };
+        input.push_str(&line.map_err(|e| e.to_string())?);
+        input.push('\n');
+
+        let command = input.trim();
+        if command.is_empty() {
+            input.clear();
+            continue;
+        }
+        if command == ":quit" {
+            return Ok(true);
+        }
+        if let Some(path) = command.strip_prefix(":context ") {
+            context.clear();
+            if let Err(message) = load_context(path.trim(), &mut context) {
+                eprintln!("{}", message);
+            }
+            input.clear();
+            continue;
+        }
+        let (source, explain) = match command.strip_prefix(":explain ") {
+            Some(source) => (source, true),
+            None => (command, false),
+        };
+        let expr = match context.parse(source) {
+            Ok(expr) => expr,
+            Err(error) if incomplete(&error) => continue,
+            Err(error) => {
+                eprintln!("{}", error);
+                input.clear();
+                continue;
+            }
+        };
+        if explain {
+            print!("{}", explain_rule(&expr, &context));
+        } else {
+            match context.eval(&expr) {
+                Ok(value) => println!("{}", value.display(context.interner())),
+                Err(error) => eprintln!("error: {}", describe_eval(&error, context.interner())),
+            }
+        }
+        input.clear();
+    }
+}
+
+/// Check if a parse failed only because the input ended early
+fn incomplete(error: &IronwoodError) -> bool {
+    matches!(
+        error,
+        IronwoodError::Parse { message, .. }
+            if message == "unclosed delimiter" || message == "unterminated string"
+    )
+}
+
+/// Evaluate every rule of a file, printing one result per line
+fn eval(args: &[String]) -> Result<bool, String> {
+    let args = Args::parse(args)?;
+    let [path] = args.paths[..] else {
+        return Err(format!("`eval` takes one rule file\n{}", USAGE));
+    };
+    let source = read(Path::new(path))?;
+    let mut interner = StringInterner::new();
+    let rules = parse_many(&source, &mut interner)
+        .collect::<Result<Vec<Expr>, _>>()
+        .map_err(|error| format!("{}: {}", path, error))?;
+
+    let mut context = Context::with_interner(interner);
+    if let Some(path) = args.context {
+        load_context(path, &mut context)?;
+    }
+    let mut ok = true;
+    for expr in &rules {
+        if args.explain {
+            print!("{}", explain_rule(expr, &context));
+            continue;
+        }
+        match context.eval(expr) {
+            Ok(value) => println!("{}", value.display(context.interner())),
+            Err(error) => {
+                println!("error: {}", describe_eval(&error, context.interner()));
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+/// Parse and typecheck every rule file under the given paths
+fn check(args: &[String]) -> Result<bool, String> {
+    let args = Args::parse(args)?;
+    if args.paths.is_empty() {
+        return Err(format!("`check` takes at least one path\n{}", USAGE));
+    }
+    let mut interner = StringInterner::new();
+    let schema = match args.schema {
+        Some(path) => {
+            let json = read_json(Path::new(path))?;
+            let schema = schema_from_json(&json, &mut interner)
+                .map_err(|error| format!("{}: {}", path, error))?;
+            Some(schema)
+        }
+        None => None,
+    };
+
+    let mut files = Vec::new();
+    for path in &args.paths {
+        collect_rule_files(Path::new(path), true, &mut files)?;
+    }
+    let mut rules = 0;
+    let mut failures = 0;
+    for file in &files {
+        let source = read(file)?;
+        for problem in check_source(&source, schema.as_ref(), &mut interner, &mut rules) {
+            println!("{}: {}", file.display(), problem);
+            failures += 1;
+        }
+    }
+    println!(
+        "checked {} rule(s) in {} file(s), {} problem(s)",
+        rules,
+        files.len(),
+        failures
+    );
+    Ok(failures == 0)
+}
+
+/// Parse the rules of one file and typecheck them against `schema`,
+/// counting them in `rules` and returning a message per problem
+fn check_source(
+    source: &str,
+    schema: Option<&Schema>,
+    interner: &mut StringInterner,
+    rules: &mut usize,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let parsed: Vec<_> = parse_many(source, interner).collect();
+    for (index, rule) in parsed.into_iter().enumerate() {
+        *rules += 1;
+        let expr = match rule {
+            Ok(expr) => expr,
+            Err(error) => {
+                problems.push(error.to_string());
+                continue;
+            }
+        };
+        if let Some(schema) = schema {
+            if let Err(error) = typecheck(&expr, schema, interner) {
+                problems.push(format!(
+                    "rule {}: {}",
+                    index + 1,
+                    describe_type(&error, interner)
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Add `path` if it is a file, or the rule files under it if it is a
+/// directory, in name order. Files named explicitly are kept whatever
+/// their extension
+fn collect_rule_files(path: &Path, explicit: bool, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        if explicit || path.extension().is_some_and(|ext| ext == RULE_EXTENSION) {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|error| format!("{}: {}", path.display(), error))?;
+    entries.sort();
+    for entry in entries {
+        collect_rule_files(&entry, false, files)?;
+    }
+    Ok(())
+}
+
+fn explain_rule(expr: &Expr, context: &Context) -> String {
+    let evaluator = Evaluator::new(context.interner());
+    let (_, trace) = evaluator.eval_with_trace(expr, context.environment());
+    trace.render(context.interner())
+}
+
+fn load_context(path: &str, context: &mut Context) -> Result<(), String> {
+    let json = read_json(Path::new(path))?;
+    context_from_json(&json, context).map_err(|error| format!("{}: {}", path, error))
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, String> {
+    let text = read(path)?;
+    serde_json::from_str(&text).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+/// Describe an evaluation error with interned names resolved
+fn describe_eval(error: &EvalError, interner: &StringInterner) -> String {
+    match error {
+        EvalError::UnknownVariable(id) => {
+            format!(
+                "unknown variable `{}`",
+                interner.resolve(*id).unwrap_or("?")
+            )
+        }
+        EvalError::UnknownFunction(id) => {
+            format!(
+                "unknown function `{}`",
+                interner.resolve(*id).unwrap_or("?")
+            )
+        }
+        EvalError::Nondeterministic(id) => {
+            format!(
+                "function `{}` is not deterministic",
+                interner.resolve(*id).unwrap_or("?")
+            )
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Describe a type error with interned names resolved
+fn describe_type(error: &TypeError, interner: &StringInterner) -> String {
+    let name = |id| interner.resolve(id).unwrap_or("?");
+    match error {
+        TypeError::UndeclaredVariable {
+            name: id,
+            suggestion,
+        } => match suggestion {
+            Some(suggestion) => format!(
+                "undeclared variable `{}`, did you mean `{}`?",
+                name(*id),
+                name(*suggestion)
+            ),
+            None => format!("undeclared variable `{}`", name(*id)),
+        },
+        TypeError::UnknownFunction {
+            name: id,
+            suggestion,
+        } => match suggestion {
+            Some(builtin) => format!(
+                "unknown function `{}`, did you mean `{}`?",
+                name(*id),
+                builtin.as_str()
+            ),
+            None => format!("unknown function `{}`", name(*id)),
+        },
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn checks_rule_files() {
+        let mut interner = StringInterner::new();
+        let json = json!({"user": {"age": "integer"}, "country": "string"});
+        let schema = schema_from_json(&json, &mut interner).unwrap();
+
+        let source = r#"
+            ; adults in the US
+            (and (>= user.age 21) (= country "US"))
+            (= contry "US")
+            (> country 3)
+        "#;
+        let mut rules = 0;
+        let problems = check_source(source, Some(&schema), &mut interner, &mut rules);
+        assert_eq!(rules, 3);
+        assert_eq!(
+            problems,
+            vec![
+                "rule 2: undeclared variable `contry`, did you mean `country`?".to_string(),
+                "rule 3: `>` cannot compare String with Integer".to_string(),
+            ]
+        );
+
+        let problems = check_source("(= a 1) (= b", None, &mut interner, &mut rules);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("parse error at 1:9"));
+    }
+
+    #[test]
+    fn detects_incomplete_input() {
+        let mut interner = StringInterner::new();
+        for (source, expected) in [("(and a", true), ("\"abc", true), ("(and a))", false)] {
+            let error = ironwood::parse(source, &mut interner).unwrap_err();
+            assert_eq!(incomplete(&error), expected, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,896 @@
+//! Versioned binary encoding of expressions and interners
+//!
+//! `Expr::to_bytes` writes an expression together with the strings it
+//! uses, so `Expr::from_bytes` can rebuild it into any interner. The
+//! encoding is byte-order independent:
+//!
+//! - a header of the magic bytes `IRWD` and a format version byte
+//! - a string table: a count, then each string as a length and UTF-8 bytes
+//! - the expression in prefix order, one tag byte per node. Strings are
+//!   referenced by their index in the table
+//!
+//! Readers accept every format version up to `VERSION`. When a version
+//! renames a builtin, expressions written by an earlier one have their
+//! calls to the old name rewritten as they are decoded, so stored rules
+//! keep their meaning across upgrades of the crate. `migrate` rewrites a
+//! stored expression in the current version once and for all.
+//!
+//! Counts, lengths, indices and integers are LEB128 varints, with integers
+//! zigzag encoded first. Floats are their IEEE 754 bits in little-endian
+//! order, and decimals their mantissa as 16 little-endian bytes followed
+//! by a scale byte.
+//!
+//! `StringInterner::to_bytes` snapshots a whole interner as the magic bytes
+//! `IRWI`, a version byte and a table of every ID handed out, in ID order,
+//! and `StringInterner::from_bytes` restores it with the same IDs. Each
+//! entry is a varint that is 0 for a released ID and otherwise the length
+//! of the string plus one, followed by its UTF-8 bytes. Version 1
+//! snapshots, a plain string table, are still read.
+//!
+//! `RuleSet::to_bytes` bundles a rule set for workers to load at startup
+//! without parsing: the magic bytes `IRWR`, a version byte, the length and
+//! bytes of its interner snapshot, then a count and each rule as its ID
+//! followed by its expression. Expressions in a bundle reference strings by
+//! their ID in the interner rather than through a string table. Compiled
+//! forms are not stored, and `RuleSet::from_bytes` compiles each rule as it
+//! is added back. Custom functions are closures and cannot be bundled, so
+//! workers register them again.
+
+use crate::compat::FxHashMap;
+use crate::{BloomSet, Decimal, Expr, RuleSet, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAGIC: &[u8; 4] = b"IRWD";
+const INTERNER_MAGIC: &[u8; 4] = b"IRWI";
+const RULES_MAGIC: &[u8; 4] = b"IRWR";
+/// Version of the expression format `Expr::to_bytes` writes
+pub const VERSION: u8 = 1;
+/// Interner snapshots moved to version 2 to record released IDs
+const INTERNER_VERSION: u8 = 2;
+
+/// Builtin renamed by a format version, as the version and the old and
+/// new names
+type Rename = (u8, &'static str, &'static str);
+
+/// Builtins renamed by each format version so far, oldest first
+const RENAMES: &[Rename] = &[];
+
+// Value tags, also used for `Expr::Literal`
+const SYMBOL: u8 = 0x00;
+const STRING: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const FLOAT: u8 = 0x03;
+const STRING_LIST: u8 = 0x04;
+const INTEGER_LIST: u8 = 0x05;
+const FALSE: u8 = 0x06;
+const TRUE: u8 = 0x07;
+const VALUE_LIST: u8 = 0x08;
+const NULL: u8 = 0x09;
+const TEXT: u8 = 0x0a;
+const MAP: u8 = 0x0b;
+const DECIMAL: u8 = 0x0c;
+const QUOTED: u8 = 0x0d;
+const INT_RANGE: u8 = 0x0e;
+const BLOOM_SET: u8 = 0x0f;
+
+// Expression tags
+const VARIABLE: u8 = 0x10;
+const CALL: u8 = 0x11;
+const EXPR_LIST: u8 = 0x12;
+const ANNOTATED: u8 = 0x13;
+const LET: u8 = 0x14;
+
+/// Error decoding bytes produced by `Expr::to_bytes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Input does not start with the expected magic bytes
+    BadMagic,
+    /// Input was written by an unsupported format version
+    UnsupportedVersion(u8),
+    /// Input ends in the middle of a value
+    UnexpectedEnd,
+    /// Unknown node tag at a byte offset
+    InvalidTag { tag: u8, offset: usize },
+    /// String reference past the end of the string table
+    InvalidStringIndex(u64),
+    /// String is not valid UTF-8
+    InvalidUtf8,
+    /// Varint does not fit in 64 bits
+    Overflow,
+    /// Bytes remain after the expression
+    TrailingBytes,
+    /// Interner snapshot lists the same string twice
+    DuplicateString,
+    /// Input was written by another format version than the one given
+    VersionMismatch { expected: u8, found: u8 },
+    /// Decimal has more digits after the point than `decimal::MAX_SCALE`
+    InvalidDecimal,
+    /// Bloom set has no bits or a hash count out of range
+    InvalidBloomSet,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => f.write_str("not an encoded expression"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encoding version {}", version)
+            }
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of input"),
+            DecodeError::InvalidTag { tag, offset } => {
+                write!(f, "invalid tag {:#04x} at byte {}", tag, offset)
+            }
+            DecodeError::InvalidStringIndex(index) => {
+                write!(f, "string index {} out of range", index)
+            }
+            DecodeError::InvalidUtf8 => f.write_str("string is not valid UTF-8"),
+            DecodeError::Overflow => f.write_str("varint overflows 64 bits"),
+            DecodeError::TrailingBytes => f.write_str("trailing bytes after expression"),
+            DecodeError::DuplicateString => f.write_str("duplicate string in interner snapshot"),
+            DecodeError::VersionMismatch { expected, found } => {
+                write!(f, "expected encoding version {}, found {}", expected, found)
+            }
+            DecodeError::InvalidDecimal => f.write_str("decimal scale out of range"),
+            DecodeError::InvalidBloomSet => f.write_str("bloom set has no bits or bad hash count"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl Expr {
+    /// Encode this expression and the strings it uses
+    ///
+    /// Returns `None` if the expression contains IDs missing from
+    /// `interner`.
+    pub fn to_bytes(&self, interner: &StringInterner) -> Option<Vec<u8>> {
+        let mut encoder = Encoder {
+            interner,
+            strings: Vec::new(),
+            slots: FxHashMap::default(),
+            body: Vec::new(),
+        };
+        encoder.expr(self)?;
+
+        let mut out = Vec::with_capacity(5 + encoder.body.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, encoder.strings.len() as u64);
+        for s in &encoder.strings {
+            write_varint(&mut out, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        out.extend_from_slice(&encoder.body);
+        Some(out)
+    }
+
+    /// Decode an expression written by `to_bytes` in any format version,
+    /// interning its strings into `interner`
+    pub fn from_bytes(bytes: &[u8], interner: &mut StringInterner) -> Result<Expr, DecodeError> {
+        let (mut decoder, version) = Decoder::with_version(bytes, MAGIC, VERSION)?;
+        let count = decoder.len()?;
+        decoder.strings.reserve(count.min(bytes.len()));
+        for _ in 0..count {
+            let s = decoder.str()?;
+            decoder.strings.push(interner.intern(s));
+        }
+
+        let expr = decoder.expr()?;
+        if decoder.pos != bytes.len() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(upgrade(expr, version, RENAMES, interner))
+    }
+}
+
+/// Rewrite an expression written by `to_bytes` in format version
+/// `from_version` in the current version
+///
+/// Fails if `bytes` were written by another version than `from_version`.
+pub fn migrate(bytes: &[u8], from_version: u8) -> Result<Vec<u8>, DecodeError> {
+    let (_, found) = Decoder::with_version(bytes, MAGIC, VERSION)?;
+    if found != from_version {
+        return Err(DecodeError::VersionMismatch {
+            expected: from_version,
+            found,
+        });
+    }
+    let mut interner = StringInterner::new();
+    let expr = Expr::from_bytes(bytes, &mut interner)?;
+    Ok(expr
+        .to_bytes(&interner)
+        .expect("decoded strings are interned"))
+}
+
+/// Apply the renames of every format version after `version`
+fn upgrade(mut expr: Expr, version: u8, renames: &[Rename], interner: &mut StringInterner) -> Expr {
+    for &(since, old, new) in renames {
+        let Some(old) = interner.get_id(old).filter(|_| version < since) else {
+            continue;
+        };
+        let new = interner.intern(new);
+        expr = expr.map(|node| match node {
+            Expr::Call { function, args } if function == old => Expr::Call {
+                function: new,
+                args,
+            },
+            node => node,
+        });
+    }
+    expr
+}
+
+impl StringInterner {
+    /// Snapshot every interned string, preserving IDs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(INTERNER_MAGIC);
+        out.push(INTERNER_VERSION);
+        write_varint(&mut out, self.id_bound() as u64);
+        for raw in 0..self.id_bound() {
+            match self.resolve(StringId::new(raw)) {
+                Some(s) => {
+                    write_varint(&mut out, s.len() as u64 + 1);
+                    out.extend_from_slice(s.as_bytes());
+                }
+                None => write_varint(&mut out, 0),
+            }
+        }
+        out
+    }
+
+    /// Restore an interner written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<StringInterner, DecodeError> {
+        let (mut decoder, version) =
+            Decoder::with_version(bytes, INTERNER_MAGIC, INTERNER_VERSION)?;
+        let mut interner = StringInterner::new();
+        for _ in 0..decoder.len()? {
+            let s = match version {
+                1 => decoder.str()?,
+                _ => match decoder.len()?.checked_sub(1) {
+                    Some(len) => decoder.str_of_len(len)?,
+                    None => {
+                        interner.skip_id();
+                        continue;
+                    }
+                },
+            };
+            if interner.contains(s) {
+                return Err(DecodeError::DuplicateString);
+            }
+            interner.intern(s);
+        }
+        if decoder.pos != bytes.len() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        interner.detect_builtins();
+        Ok(interner)
+    }
+}
+
+impl RuleSet {
+    /// Bundle the interner and every rule, in insertion order
+    ///
+    /// Returns `None` if a rule contains IDs missing from the interner.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let interner = self.interner();
+        let mut encoder = Encoder {
+            interner,
+            strings: Vec::new(),
+            // Every ID is its own index, as the interner is bundled whole
+            slots: (0..interner.id_bound())
+                .map(|raw| (StringId::new(raw), u64::from(raw)))
+                .collect(),
+            body: Vec::new(),
+        };
+        let mut count = 0;
+        for (id, expr) in self.rules() {
+            write_varint(&mut encoder.body, id);
+            encoder.expr(expr)?;
+            count += 1;
+        }
+
+        let snapshot = interner.to_bytes();
+        let mut out = Vec::with_capacity(15 + snapshot.len() + encoder.body.len());
+        out.extend_from_slice(RULES_MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, snapshot.len() as u64);
+        out.extend_from_slice(&snapshot);
+        write_varint(&mut out, count);
+        out.extend_from_slice(&encoder.body);
+        Some(out)
+    }
+
+    /// Restore a rule set written by `to_bytes`, with the same string IDs
+    /// and rules in the same order
+    pub fn from_bytes(bytes: &[u8]) -> Result<RuleSet, DecodeError> {
+        let (mut decoder, version) = Decoder::with_version(bytes, RULES_MAGIC, VERSION)?;
+        let len = decoder.len()?;
+        let mut interner = StringInterner::from_bytes(decoder.take(len)?)?;
+        decoder.strings = (0..interner.id_bound()).map(StringId::new).collect();
+        let count = decoder.len()?;
+        let mut rules = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            let id = decoder.varint()?;
+            rules.push((id, decoder.expr()?));
+        }
+        if decoder.pos != bytes.len() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let rules: Vec<_> = rules
+            .into_iter()
+            .map(|(id, expr)| (id, upgrade(expr, version, RENAMES, &mut interner)))
+            .collect();
+        let mut rule_set = RuleSet::with_interner(interner);
+        for (id, expr) in rules {
+            rule_set.add_rule(id, expr);
+        }
+        Ok(rule_set)
+    }
+}
+
+struct Encoder<'i> {
+    interner: &'i StringInterner,
+    /// String table in order of first use
+    strings: Vec<&'i str>,
+    /// Table index of each interned ID seen so far
+    slots: FxHashMap<StringId, u64>,
+    body: Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn expr(&mut self, expr: &Expr) -> Option<()> {
+        match expr {
+            Expr::Literal(value) => self.value(value)?,
+            Expr::Variable(id) => {
+                self.body.push(VARIABLE);
+                self.string(*id)?;
+            }
+            Expr::Call { function, args } => {
+                self.body.push(CALL);
+                self.string(*function)?;
+                write_varint(&mut self.body, args.len() as u64);
+                for arg in args {
+                    self.expr(arg)?;
+                }
+            }
+            Expr::List(items) => {
+                self.body.push(EXPR_LIST);
+                write_varint(&mut self.body, items.len() as u64);
+                for item in items {
+                    self.expr(item)?;
+                }
+            }
+            Expr::Annotated { metadata, expr } => {
+                self.body.push(ANNOTATED);
+                write_varint(&mut self.body, metadata.len() as u64);
+                for (key, value) in metadata {
+                    self.string(*key)?;
+                    self.value(value)?;
+                }
+                self.expr(expr)?;
+            }
+            Expr::Let { bindings, body } => {
+                self.body.push(LET);
+                write_varint(&mut self.body, bindings.len() as u64);
+                for (name, value) in bindings {
+                    self.string(*name)?;
+                    self.expr(value)?;
+                }
+                self.expr(body)?;
+            }
+        }
+        Some(())
+    }
+
+    fn value(&mut self, value: &Value) -> Option<()> {
+        match value {
+            Value::Symbol(id) => {
+                self.body.push(SYMBOL);
+                self.string(*id)?;
+            }
+            Value::String(id) => {
+                self.body.push(STRING);
+                self.string(*id)?;
+            }
+            Value::Integer(n) => {
+                self.body.push(INTEGER);
+                write_varint(&mut self.body, zigzag(*n));
+            }
+            Value::Float(x) => {
+                self.body.push(FLOAT);
+                self.body.extend_from_slice(&x.to_bits().to_le_bytes());
+            }
+            Value::StringList(ids) => {
+                self.body.push(STRING_LIST);
+                write_varint(&mut self.body, ids.len() as u64);
+                for id in ids {
+                    self.string(*id)?;
+                }
+            }
+            Value::IntegerList(ns) => {
+                self.body.push(INTEGER_LIST);
+                write_varint(&mut self.body, ns.len() as u64);
+                for n in ns {
+                    write_varint(&mut self.body, zigzag(*n));
+                }
+            }
+            Value::Bool(b) => self.body.push(if *b { TRUE } else { FALSE }),
+            Value::List(items) => {
+                self.body.push(VALUE_LIST);
+                write_varint(&mut self.body, items.len() as u64);
+                for item in items {
+                    self.value(item)?;
+                }
+            }
+            Value::Null => self.body.push(NULL),
+            Value::Text(text) => {
+                self.body.push(TEXT);
+                write_varint(&mut self.body, text.len() as u64);
+                self.body.extend_from_slice(text.as_bytes());
+            }
+            Value::Decimal(d) => {
+                self.body.push(DECIMAL);
+                self.body.extend_from_slice(&d.mantissa().to_le_bytes());
+                self.body.push(d.scale());
+            }
+            Value::Map(map) => {
+                // Sorted by key text so equal maps encode identically
+                let mut entries = map
+                    .iter()
+                    .map(|(&key, value)| Some((self.interner.resolve(key)?, key, value)))
+                    .collect::<Option<Vec<_>>>()?;
+                entries.sort_unstable_by_key(|&(text, _, _)| text);
+                self.body.push(MAP);
+                write_varint(&mut self.body, entries.len() as u64);
+                for (_, key, value) in entries {
+                    self.string(key)?;
+                    self.value(value)?;
+                }
+            }
+            Value::Quoted(expr) => {
+                self.body.push(QUOTED);
+                self.expr(expr)?;
+            }
+            Value::IntRange(start, end) => {
+                self.body.push(INT_RANGE);
+                write_varint(&mut self.body, zigzag(*start));
+                write_varint(&mut self.body, zigzag(*end));
+            }
+            Value::BloomSet(set) => {
+                self.body.push(BLOOM_SET);
+                write_varint(&mut self.body, u64::from(set.hashes()));
+                write_varint(&mut self.body, set.len());
+                write_varint(&mut self.body, set.words().len() as u64);
+                for word in set.words() {
+                    self.body.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Write the table index of an interned string, adding it if needed
+    fn string(&mut self, id: StringId) -> Option<()> {
+        let slot = match self.slots.get(&id) {
+            Some(&slot) => slot,
+            None => {
+                let slot = self.strings.len() as u64;
+                self.strings.push(self.interner.resolve(id)?);
+                self.slots.insert(id, slot);
+                slot
+            }
+        };
+        write_varint(&mut self.body, slot);
+        Some(())
+    }
+}
+
+struct Decoder<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+    /// Interned IDs of the string table
+    strings: Vec<StringId>,
+}
+
+impl<'b> Decoder<'b> {
+    /// Start decoding after checking the header, accepting any version
+    /// from 1 to `latest` and returning the one found
+    fn with_version(
+        bytes: &'b [u8],
+        magic: &[u8; 4],
+        latest: u8,
+    ) -> Result<(Self, u8), DecodeError> {
+        if bytes.get(..magic.len()) != Some(magic.as_slice()) {
+            return Err(DecodeError::BadMagic);
+        }
+        let mut decoder = Decoder {
+            bytes,
+            pos: magic.len(),
+            strings: Vec::new(),
+        };
+        let version = decoder.byte()?;
+        if !(1..=latest).contains(&version) {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok((decoder, version))
+    }
+
+    fn expr(&mut self) -> Result<Expr, DecodeError> {
+        let offset = self.pos;
+        Ok(match self.byte()? {
+            VARIABLE => Expr::Variable(self.string()?),
+            CALL => {
+                let function = self.string()?;
+                let args = self.items(Self::expr)?;
+                Expr::Call { function, args }
+            }
+            EXPR_LIST => Expr::List(self.items(Self::expr)?),
+            ANNOTATED => Expr::Annotated {
+                metadata: self.items(|d| Ok((d.string()?, d.value()?)))?,
+                expr: Box::new(self.expr()?),
+            },
+            LET => Expr::Let {
+                bindings: self.items(|d| Ok((d.string()?, d.expr()?)))?,
+                body: Box::new(self.expr()?),
+            },
+            _ => {
+                self.pos = offset;
+                Expr::Literal(self.value()?)
+            }
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        let offset = self.pos;
+        Ok(match self.byte()? {
+            SYMBOL => Value::Symbol(self.string()?),
+            STRING => Value::String(self.string()?),
+            INTEGER => Value::Integer(unzigzag(self.varint()?)),
+            FLOAT => {
+                let bits = self.take(8)?.try_into().expect("eight bytes");
+                Value::Float(f64::from_bits(u64::from_le_bytes(bits)))
+            }
+            STRING_LIST => Value::StringList(self.items(Self::string)?.into()),
+            INTEGER_LIST => Value::IntegerList(self.items(|d| Ok(unzigzag(d.varint()?)))?.into()),
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            VALUE_LIST => Value::List(self.items(Self::value)?),
+            NULL => Value::Null,
+            TEXT => Value::Text(self.str()?.into()),
+            MAP => Value::Map(
+                self.items(|d| Ok((d.string()?, d.value()?)))?
+                    .into_iter()
+                    .collect(),
+            ),
+            DECIMAL => {
+                let bytes = self.take(16)?.try_into().expect("sixteen bytes");
+                let scale = self.byte()?;
+                let decimal = Decimal::new(i128::from_le_bytes(bytes), scale);
+                Value::Decimal(decimal.ok_or(DecodeError::InvalidDecimal)?)
+            }
+            QUOTED => Value::Quoted(Box::new(self.expr()?)),
+            INT_RANGE => Value::IntRange(unzigzag(self.varint()?), unzigzag(self.varint()?)),
+            BLOOM_SET => {
+                let hashes = u32::try_from(self.varint()?).map_err(|_| DecodeError::Overflow)?;
+                let len = self.varint()?;
+                let words = self.items(|d| {
+                    let bytes = d.take(8)?.try_into().expect("eight bytes");
+                    Ok(u64::from_le_bytes(bytes))
+                })?;
+                let set = BloomSet::from_parts(words, hashes, len);
+                Value::BloomSet(set.ok_or(DecodeError::InvalidBloomSet)?)
+            }
+            tag => return Err(DecodeError::InvalidTag { tag, offset }),
+        })
+    }
+
+    /// Read a count followed by that many items
+    fn items<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<Vec<T>, DecodeError> {
+        let count = self.len()?;
+        // Every item takes at least one byte, which bounds the allocation
+        let mut items = Vec::with_capacity(count.min(self.bytes.len() - self.pos));
+        for _ in 0..count {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn string(&mut self) -> Result<StringId, DecodeError> {
+        let index = self.varint()?;
+        self.strings
+            .get(index as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidStringIndex(index))
+    }
+
+    fn str(&mut self) -> Result<&'b str, DecodeError> {
+        let len = self.len()?;
+        self.str_of_len(len)
+    }
+
+    fn str_of_len(&mut self, len: usize) -> Result<&'b str, DecodeError> {
+        core::str::from_utf8(self.take(len)?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.varint()?).map_err(|_| DecodeError::Overflow)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut result = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::Overflow);
+            }
+            result |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(DecodeError::Overflow)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    (n >> 1) as i64 ^ -((n & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use alloc::vec;
+
+    #[test]
+    fn round_trip_into_fresh_interner() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(or (and (= country "US") (>= age -21) (< total -12.50d) (in 'tier ["gold" 'silver 3.5 null]))
+                   (matches-regex email "@example\\.com$")
+                   (let ((total (* price qty)) (over (> total 100))) (and over vip))
+                   (meta (id 7) (owner "growth") (not (one-of tags country))))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let mut audience = BloomSet::new(1_000, 0.01);
+        audience.insert_str("user-1");
+        audience.insert_int(42);
+        let literal = Expr::List(vec![
+            Expr::Literal(Value::StringList(vec![interner.intern("gold")].into())),
+            Expr::Literal(Value::IntegerList(vec![i64::MIN, 0, i64::MAX].into())),
+            Expr::Literal(Value::List(vec![
+                Value::Bool(true),
+                Value::Text("é".into()),
+            ])),
+            Expr::Literal(Value::Float(f64::NEG_INFINITY)),
+            Expr::Literal(Value::Map(
+                [("x-tenant", Value::Integer(7)), ("accept", Value::Null)]
+                    .into_iter()
+                    .map(|(key, value)| (interner.intern(key), value))
+                    .collect(),
+            )),
+            Expr::Literal(Value::Quoted(Box::new(Expr::Variable(
+                interner.intern("quoted"),
+            )))),
+            Expr::Literal(Value::IntRange(-5, i64::MAX)),
+            Expr::Literal(Value::BloomSet(audience)),
+        ]);
+
+        for expr in [expr, literal] {
+            let bytes = expr.to_bytes(&interner).unwrap();
+            let mut fresh = StringInterner::new();
+            fresh.intern("unrelated");
+            let decoded = Expr::from_bytes(&bytes, &mut fresh).unwrap();
+            assert_eq!(decoded.to_sexpr(&fresh), expr.to_sexpr(&interner));
+            assert_eq!(decoded.to_bytes(&fresh), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn stable_layout() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(> age -2)", &mut interner).unwrap();
+        let bytes = expr.to_bytes(&interner).unwrap();
+        let expected = [
+            b'I', b'R', b'W', b'D', VERSION, // header
+            2, 1, b'>', 3, b'a', b'g', b'e', // string table
+            CALL, 0, 2, VARIABLE, 1, INTEGER, 3, // (> age -2)
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let mut interner = StringInterner::new();
+        let expr = parse(r#"(= name "x")"#, &mut interner).unwrap();
+        let bytes = expr.to_bytes(&interner).unwrap();
+
+        let mut cases = vec![
+            (b"nope".to_vec(), DecodeError::BadMagic),
+            (
+                bytes[..bytes.len() - 1].to_vec(),
+                DecodeError::UnexpectedEnd,
+            ),
+            (
+                [bytes.as_slice(), &[0]].concat(),
+                DecodeError::TrailingBytes,
+            ),
+        ];
+        let mut version = bytes.clone();
+        version[4] = 9;
+        cases.push((version, DecodeError::UnsupportedVersion(9)));
+        let mut tag = bytes.clone();
+        let last_tag = bytes.len() - 2;
+        tag[last_tag] = 0x7f;
+        cases.push((
+            tag,
+            DecodeError::InvalidTag {
+                tag: 0x7f,
+                offset: last_tag,
+            },
+        ));
+
+        let mut decimal = Expr::Literal(Value::Decimal("0.5".parse().unwrap()))
+            .to_bytes(&interner)
+            .unwrap();
+        *decimal.last_mut().unwrap() = 19;
+        cases.push((decimal, DecodeError::InvalidDecimal));
+
+        for (input, error) in cases {
+            assert_eq!(Expr::from_bytes(&input, &mut interner), Err(error));
+        }
+        assert_eq!(
+            Expr::Variable(StringId::new(0)).to_bytes(&StringInterner::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn interner_snapshot() {
+        let mut interner = StringInterner::new();
+        let expr = parse(r#"(in country ["US" "CA" "é"])"#, &mut interner).unwrap();
+        let bytes = interner.to_bytes();
+
+        let restored = StringInterner::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.len(), interner.len());
+        assert_eq!(expr.to_sexpr(&restored), expr.to_sexpr(&interner));
+        assert_eq!(restored.to_bytes(), bytes);
+
+        let duplicate = [b"IRWI".as_slice(), &[2, 2, 2, b'a', 2, b'a']].concat();
+        assert_eq!(
+            StringInterner::from_bytes(&duplicate).unwrap_err(),
+            DecodeError::DuplicateString
+        );
+
+        // Released IDs stay released, and version 1 tables still load
+        let mut released = StringInterner::new();
+        let a = released.intern("a");
+        let b = released.intern("b");
+        released.retain(|id, _| id != a);
+        let restored = StringInterner::from_bytes(&released.to_bytes()).unwrap();
+        assert_eq!(restored.resolve(a), None);
+        assert_eq!(restored.get_id("b"), Some(b));
+        let v1 = [b"IRWI".as_slice(), &[1, 2, 1, b'a', 1, b'b']].concat();
+        assert_eq!(
+            StringInterner::from_bytes(&v1).unwrap().get_id("b"),
+            Some(b)
+        );
+        assert_eq!(
+            StringInterner::from_bytes(&expr.to_bytes(&interner).unwrap()).unwrap_err(),
+            DecodeError::BadMagic
+        );
+    }
+
+    #[test]
+    fn rule_set_bundle() {
+        let mut rules = RuleSet::new();
+        for (id, source) in [
+            (3, r#"(= country "US")"#),
+            (1, r#"(and (> age 18) (in tier ["gold" "silver"]))"#),
+            (2, "(let ((x (+ age 1))) (> x 30))"),
+        ] {
+            let expr = parse(source, rules.interner_mut()).unwrap();
+            rules.add_rule(id, expr);
+        }
+        rules.remove_rule(2);
+        let bytes = rules.to_bytes().unwrap();
+        assert_eq!(&bytes[..5], b"IRWR\x01");
+
+        let restored = RuleSet::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.rule(1), rules.rule(1));
+        assert_eq!(restored.interner().len(), rules.interner().len());
+        assert_eq!(restored.to_bytes().unwrap(), bytes);
+
+        let interner = restored.interner();
+        let mut env = crate::Environment::new();
+        env.insert(
+            interner.get_id("country").unwrap(),
+            Value::String(interner.get_id("US").unwrap()),
+        );
+        env.insert(interner.get_id("age").unwrap(), Value::Integer(40));
+        env.insert(
+            interner.get_id("tier").unwrap(),
+            Value::String(interner.get_id("gold").unwrap()),
+        );
+        assert_eq!(restored.matches(&env), [3, 1]);
+
+        assert_eq!(
+            RuleSet::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+        assert_eq!(
+            RuleSet::from_bytes(&rules.interner().to_bytes()).unwrap_err(),
+            DecodeError::BadMagic
+        );
+    }
+
+    #[test]
+    fn migrates_old_versions() {
+        let mut interner = StringInterner::new();
+        let expr = parse(r#"(and (one-of tier ["gold"]) (> age 18))"#, &mut interner).unwrap();
+        let bytes = expr.to_bytes(&interner).unwrap();
+        assert_eq!(migrate(&bytes, VERSION).unwrap(), bytes);
+        assert_eq!(
+            migrate(&bytes, 0).unwrap_err(),
+            DecodeError::VersionMismatch {
+                expected: 0,
+                found: VERSION
+            }
+        );
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            migrate(&newer, VERSION + 1).unwrap_err(),
+            DecodeError::UnsupportedVersion(VERSION + 1)
+        );
+
+        // A builtin renamed by version 2, then again by version 3
+        let renames = [(2, "one-of", "any-of"), (3, "any-of", "in")];
+        let decoded = Expr::from_bytes(&bytes, &mut interner).unwrap();
+        let upgraded = upgrade(decoded.clone(), 1, &renames, &mut interner);
+        let expected = parse(r#"(and (in tier ["gold"]) (> age 18))"#, &mut interner).unwrap();
+        assert_eq!(upgraded, expected);
+        assert_eq!(
+            upgrade(decoded.clone(), 3, &renames, &mut interner),
+            decoded
+        );
+    }
+}
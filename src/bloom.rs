@@ -0,0 +1,276 @@
+//! Bloom filters for membership in huge sets
+//!
+//! An `in` list of millions of user IDs is too large to ship inside a rule
+//! or to scan. A `BloomSet` holds such a set in a fixed number of bits:
+//! hosts build it offline from the IDs for an expected size and false
+//! positive rate, store it with `Expr::to_bytes` or serde, and attach it to
+//! an environment or an expression as `Value::BloomSet`. Then
+//! `(in-set user_id audience)` answers with a constant number of bit
+//! lookups. Members are always found, and other values are found with
+//! about the chosen rate.
+//!
+//! Items are hashed with FNV-1a, which is the same on every platform and
+//! release, so a set built by one process works in any other. Strings and
+//! integers are distinct, so `"7"` is not a member of a set holding `7`,
+//! and floats and decimals with no fractional part are looked up as the
+//! integer they equal, like `=`. Under an interner made by
+//! `StringInterner::with_case_folding`, strings are looked up in their
+//! folded, lowercase form, so build such sets from lowercase strings.
+
+use crate::compat::portable;
+use crate::rollout::fnv1a;
+use crate::{Interner, Value};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Most hash functions a set may use
+pub const MAX_HASHES: u32 = 32;
+
+/// Lowest false positive rate `BloomSet::new` sizes a set for
+const MIN_RATE: f64 = 1e-9;
+
+/// Floats at least this large may equal several integers once rounded
+const EXACT_FLOAT_LIMIT: f64 = (1u64 << f64::MANTISSA_DIGITS) as f64;
+
+/// A probabilistic set of strings and integers
+///
+/// Clones share their bits until one is modified, so sets are cheap to
+/// place in environments and expressions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BloomSet {
+    bits: Arc<Vec<u64>>,
+    hashes: u32,
+    len: u64,
+}
+
+/// Item as hashed, keeping strings and integers apart
+enum Key<'a> {
+    Str(&'a str),
+    Int(i64),
+}
+
+impl BloomSet {
+    /// Create an empty set sized to hold `expected` items with a false
+    /// positive rate of about `false_positive_rate`. The rate is clamped to
+    /// between 1e-9 and 0.5
+    pub fn new(expected: usize, false_positive_rate: f64) -> Self {
+        let rate = if false_positive_rate >= MIN_RATE {
+            false_positive_rate.min(0.5)
+        } else {
+            MIN_RATE
+        };
+        let n = expected.max(1) as f64;
+        let ln2 = core::f64::consts::LN_2;
+        let bits = portable::ceil(-n * portable::ln(rate) / (ln2 * ln2));
+        let words = portable::ceil(bits / 64.0).max(1.0) as usize;
+        let hashes = (words as f64 * 64.0 / n * ln2 + 0.5) as u32;
+        Self {
+            bits: Arc::new(vec![0; words]),
+            hashes: hashes.clamp(1, MAX_HASHES),
+            len: 0,
+        }
+    }
+
+    /// Rebuild a set from the parts returned by `words`, `hashes` and
+    /// `len`, `None` if there are no words or the hash count is not in
+    /// `1..=MAX_HASHES`
+    pub fn from_parts(words: Vec<u64>, hashes: u32, len: u64) -> Option<Self> {
+        if words.is_empty() || !(1..=MAX_HASHES).contains(&hashes) {
+            return None;
+        }
+        Some(Self {
+            bits: Arc::new(words),
+            hashes,
+            len,
+        })
+    }
+
+    /// Get the bits of the set, 64 to a word
+    pub fn words(&self) -> &[u64] {
+        &self.bits
+    }
+
+    /// Get the number of bits set for each item
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Get the number of items inserted, counting repeats
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Check if nothing has been inserted
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert a string
+    pub fn insert_str(&mut self, item: &str) {
+        self.insert(Key::Str(item));
+    }
+
+    /// Insert an integer
+    pub fn insert_int(&mut self, item: i64) {
+        self.insert(Key::Int(item));
+    }
+
+    /// Check if a string may have been inserted
+    pub fn contains_str(&self, item: &str) -> bool {
+        self.test(Key::Str(item))
+    }
+
+    /// Check if an integer may have been inserted
+    pub fn contains_int(&self, item: i64) -> bool {
+        self.test(Key::Int(item))
+    }
+
+    /// Check if a value may have been inserted, comparing like `=`. Values
+    /// other than strings and numbers are never members
+    pub fn contains(&self, value: &Value, interner: &dyn Interner) -> bool {
+        let text = match value {
+            Value::Symbol(id) | Value::String(id) => match interner.resolve(*id) {
+                Some(text) => text,
+                None => return false,
+            },
+            Value::Text(text) => text,
+            Value::Integer(n) => return self.test(Key::Int(*n)),
+            Value::Float(x) if is_exact_integer(*x) => return self.test(Key::Int(*x as i64)),
+            Value::Decimal(d) => return d.to_i64().is_some_and(|n| self.test(Key::Int(n))),
+            _ => return false,
+        };
+        self.test(Key::Str(&interner.case_folding().fold(text)))
+    }
+
+    /// Estimate the false positive rate from the items inserted so far
+    pub fn false_positive_rate(&self) -> f64 {
+        let bits = (self.bits.len() * 64) as f64;
+        let k = f64::from(self.hashes);
+        let unset = portable::exp(-k * self.len as f64 / bits);
+        portable::exp(k * portable::ln(1.0 - unset))
+    }
+
+    fn insert(&mut self, key: Key) {
+        let bits = Arc::make_mut(&mut self.bits);
+        for bit in positions(&key, self.hashes, bits.len()) {
+            bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn test(&self, key: Key) -> bool {
+        positions(&key, self.hashes, self.bits.len())
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Check if a float is a whole number that converts to `i64` exactly
+fn is_exact_integer(x: f64) -> bool {
+    x.abs() < EXACT_FLOAT_LIMIT && x == (x as i64) as f64
+}
+
+/// Bit positions of an item, by double hashing one FNV-1a hash
+fn positions(key: &Key, hashes: u32, words: usize) -> impl Iterator<Item = usize> {
+    let first = match key {
+        Key::Str(text) => fnv1a([b's'].into_iter().chain(text.bytes())),
+        Key::Int(n) => fnv1a([b'i'].into_iter().chain(n.to_le_bytes())),
+    };
+    // The SplitMix64 finalizer, made odd so every step moves
+    let mut second = first ^ (first >> 30);
+    second = second.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    second ^= second >> 27;
+    second = second.wrapping_mul(0x94d0_49bb_1331_11eb);
+    second = (second ^ (second >> 31)) | 1;
+    let bits = words as u64 * 64;
+    (0..u64::from(hashes))
+        .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Parts {
+    words: Vec<u64>,
+    hashes: u32,
+    len: u64,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for BloomSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Parts {
+            words: self.words().to_vec(),
+            hashes: self.hashes,
+            len: self.len,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BloomSet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let parts = Parts::deserialize(deserializer)?;
+        BloomSet::from_parts(parts.words, parts.hashes, parts.len)
+            .ok_or_else(|| serde::de::Error::custom("invalid bloom set"))
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for BloomSet {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let items: Vec<i64> = u.arbitrary()?;
+        let mut set = BloomSet::new(items.len(), 0.01);
+        for item in items {
+            set.insert_int(item);
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decimal, StringInterner};
+
+    #[test]
+    fn members_are_found() {
+        let mut set = BloomSet::new(10_000, 0.01);
+        assert!(set.is_empty());
+        for n in 0..10_000 {
+            set.insert_int(n * 2);
+        }
+        set.insert_str("user-42");
+        assert_eq!(set.len(), 10_001);
+        assert!((0..10_000).all(|n| set.contains_int(n * 2)));
+        assert!(set.contains_str("user-42"));
+        assert!(!set.contains_str("0"));
+
+        let false_positives = (0..10_000).filter(|n| set.contains_int(n * 2 + 1)).count();
+        assert!(false_positives < 200, "{}", false_positives);
+        assert!(set.false_positive_rate() < 0.02);
+
+        let mut interner = StringInterner::new();
+        let user = interner.intern("user-42");
+        assert!(set.contains(&Value::String(user), &interner));
+        assert!(set.contains(&Value::Text("user-42".into()), &interner));
+        assert!(set.contains(&Value::Float(8.0), &interner));
+        assert!(set.contains(&Value::Decimal(Decimal::from(8)), &interner));
+        assert!(!set.contains(&Value::Bool(true), &interner));
+    }
+
+    #[test]
+    fn parts_roundtrip() {
+        let mut set = BloomSet::new(100, 0.001);
+        set.insert_str("a");
+        let copy = set.clone();
+        set.insert_str("b");
+        assert!(!copy.contains_str("b"));
+
+        let rebuilt = BloomSet::from_parts(set.words().to_vec(), set.hashes(), set.len());
+        assert_eq!(rebuilt, Some(set));
+        assert_eq!(BloomSet::from_parts(Vec::new(), 3, 0), None);
+        assert_eq!(BloomSet::from_parts(vec![0], 0, 0), None);
+        assert_eq!(BloomSet::from_parts(vec![0], MAX_HASHES + 1, 0), None);
+    }
+}
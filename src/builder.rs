@@ -0,0 +1,420 @@
+//! Fluent construction of expressions
+//!
+//! `ExprBuilder` interns names and strings as it goes, so rules can be
+//! built in code without touching `StringId`s:
+//!
+//! ```
+//! use ironwood::{ExprBuilder, StringInterner};
+//!
+//! let mut interner = StringInterner::new();
+//! let b = ExprBuilder::new(&mut interner);
+//! let rule = b.and([
+//!     b.eq(b.var("country"), b.str("US")),
+//!     b.gt(b.var("age"), 21),
+//! ]);
+//! assert_eq!(
+//!     rule.to_sexpr(&*b.interner()),
+//!     r#"(and (= country "US") (> age 21))"#
+//! );
+//! ```
+//!
+//! Methods named after builtins always produce calls with a valid number of
+//! arguments. `builtin` and `call` take an argument list of any length and
+//! check it.
+
+use crate::eval::{check_arity, EvalError};
+use crate::{BuiltinFunction, Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Ref, RefCell};
+
+/// Builds expressions, interning strings into a borrowed interner
+///
+/// The interner is held in a `RefCell` so calls can be nested, as in
+/// `b.not(b.var("banned"))`.
+#[derive(Debug)]
+pub struct ExprBuilder<'a> {
+    interner: RefCell<&'a mut StringInterner>,
+}
+
+impl<'a> ExprBuilder<'a> {
+    /// Create a builder that interns into `interner`
+    pub fn new(interner: &'a mut StringInterner) -> Self {
+        Self {
+            interner: RefCell::new(interner),
+        }
+    }
+
+    /// Borrow the interner, e.g. to print a built expression
+    ///
+    /// Building while the returned guard is alive panics.
+    pub fn interner(&self) -> Ref<'_, StringInterner> {
+        Ref::map(self.interner.borrow(), |interner| &**interner)
+    }
+
+    /// A variable reference
+    pub fn var(&self, name: &str) -> Expr {
+        Expr::Variable(self.intern(name))
+    }
+
+    /// A string literal
+    pub fn str(&self, text: &str) -> Expr {
+        Expr::Literal(Value::String(self.intern(text)))
+    }
+
+    /// A symbol literal
+    pub fn sym(&self, name: &str) -> Expr {
+        Expr::Literal(Value::Symbol(self.intern(name)))
+    }
+
+    /// The null literal
+    pub fn null(&self) -> Expr {
+        Expr::Literal(Value::Null)
+    }
+
+    /// A list whose items are evaluated
+    pub fn list<E: Into<Expr>>(&self, items: impl IntoIterator<Item = E>) -> Expr {
+        Expr::List(items.into_iter().map(Into::into).collect())
+    }
+
+    /// `(let ((name value) ...) body)`, binding each local in turn
+    pub fn let_in<'n, E: Into<Expr>>(
+        &self,
+        bindings: impl IntoIterator<Item = (&'n str, E)>,
+        body: impl Into<Expr>,
+    ) -> Expr {
+        Expr::Let {
+            bindings: bindings
+                .into_iter()
+                .map(|(name, value)| (self.intern(name), value.into()))
+                .collect(),
+            body: Box::new(body.into()),
+        }
+    }
+
+    /// A call to a builtin, checking the number of arguments
+    pub fn builtin<E: Into<Expr>>(
+        &self,
+        function: BuiltinFunction,
+        args: impl IntoIterator<Item = E>,
+    ) -> Result<Expr, EvalError> {
+        let args: Vec<Expr> = args.into_iter().map(Into::into).collect();
+        check_arity(function, args.len())?;
+        Ok(self.make(function, args))
+    }
+
+    /// A call by name
+    ///
+    /// Builtin names are checked like `builtin`. Any other name is a call
+    /// to a custom function, which is resolved when the expression is
+    /// evaluated or compiled.
+    pub fn call<E: Into<Expr>>(
+        &self,
+        name: &str,
+        args: impl IntoIterator<Item = E>,
+    ) -> Result<Expr, EvalError> {
+        if let Some(function) = BuiltinFunction::from_str(name) {
+            return self.builtin(function, args);
+        }
+        Ok(Expr::Call {
+            function: self.intern(name),
+            args: args.into_iter().map(Into::into).collect(),
+        })
+    }
+
+    /// `(and ...)`
+    pub fn and(&self, operands: impl IntoIterator<Item = Expr>) -> Expr {
+        self.make(BuiltinFunction::And, operands.into_iter().collect())
+    }
+
+    /// `(or ...)`
+    pub fn or(&self, operands: impl IntoIterator<Item = Expr>) -> Expr {
+        self.make(BuiltinFunction::Or, operands.into_iter().collect())
+    }
+
+    /// `(not operand)`
+    pub fn not(&self, operand: impl Into<Expr>) -> Expr {
+        self.make(BuiltinFunction::Not, vec![operand.into()])
+    }
+
+    /// `(= left right)`
+    pub fn eq(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::Equal, left, right)
+    }
+
+    /// `(!= left right)`
+    pub fn ne(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::NotEqual, left, right)
+    }
+
+    /// `(< left right)`
+    pub fn lt(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::LessThan, left, right)
+    }
+
+    /// `(<= left right)`
+    pub fn le(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::LessThanOrEqual, left, right)
+    }
+
+    /// `(> left right)`
+    pub fn gt(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::GreaterThan, left, right)
+    }
+
+    /// `(>= left right)`
+    pub fn ge(&self, left: impl Into<Expr>, right: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::GreaterThanOrEqual, left, right)
+    }
+
+    /// `(in value list)`
+    pub fn is_in(&self, value: impl Into<Expr>, list: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::In, value, list)
+    }
+
+    /// `(not-in value list)`
+    pub fn not_in(&self, value: impl Into<Expr>, list: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::NotIn, value, list)
+    }
+
+    /// `(exists value)`
+    pub fn exists(&self, value: impl Into<Expr>) -> Expr {
+        self.make(BuiltinFunction::Exists, vec![value.into()])
+    }
+
+    /// `(is-null value)`
+    pub fn is_null(&self, value: impl Into<Expr>) -> Expr {
+        self.make(BuiltinFunction::IsNull, vec![value.into()])
+    }
+
+    /// `(starts-with text prefix)`
+    pub fn starts_with(&self, text: impl Into<Expr>, prefix: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::StartsWith, text, prefix)
+    }
+
+    /// `(ends-with text suffix)`
+    pub fn ends_with(&self, text: impl Into<Expr>, suffix: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::EndsWith, text, suffix)
+    }
+
+    /// `(contains text needle)`
+    pub fn contains(&self, text: impl Into<Expr>, needle: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::Contains, text, needle)
+    }
+
+    /// `(matches-regex text "pattern")`
+    pub fn matches_regex(&self, text: impl Into<Expr>, pattern: &str) -> Expr {
+        let pattern = self.str(pattern);
+        self.binary(BuiltinFunction::MatchesRegex, text, pattern)
+    }
+
+    /// `(get map key)`
+    pub fn get(&self, map: impl Into<Expr>, key: &str) -> Expr {
+        let key = self.str(key);
+        self.binary(BuiltinFunction::Get, map, key)
+    }
+
+    /// `(has-key map key)`
+    pub fn has_key(&self, map: impl Into<Expr>, key: &str) -> Expr {
+        let key = self.str(key);
+        self.binary(BuiltinFunction::HasKey, map, key)
+    }
+
+    /// `(semver-matches version "range")`
+    pub fn semver_matches(&self, version: impl Into<Expr>, range: &str) -> Expr {
+        let range = self.str(range);
+        self.binary(BuiltinFunction::SemverMatches, version, range)
+    }
+
+    /// `(ip-in-cidr ip "block")`
+    pub fn ip_in_cidr(&self, ip: impl Into<Expr>, block: &str) -> Expr {
+        let block = self.str(block);
+        self.binary(BuiltinFunction::IpInCidr, ip, block)
+    }
+
+    /// `(percent-of key "salt" percent)`
+    pub fn percent_of(&self, key: impl Into<Expr>, salt: &str, percent: impl Into<Expr>) -> Expr {
+        let salt = self.str(salt);
+        self.make(
+            BuiltinFunction::PercentOf,
+            vec![key.into(), salt, percent.into()],
+        )
+    }
+
+    /// `(experiment-bucket key "salt" variants weights)`
+    pub fn experiment_bucket(
+        &self,
+        key: impl Into<Expr>,
+        salt: &str,
+        variants: impl Into<Expr>,
+        weights: impl Into<Expr>,
+    ) -> Expr {
+        let salt = self.str(salt);
+        self.make(
+            BuiltinFunction::ExperimentBucket,
+            vec![key.into(), salt, variants.into(), weights.into()],
+        )
+    }
+
+    /// `(quote expr)`
+    pub fn quote(&self, expr: impl Into<Expr>) -> Expr {
+        self.make(BuiltinFunction::Quote, vec![expr.into()])
+    }
+
+    /// `(eval quoted)`
+    pub fn eval(&self, quoted: impl Into<Expr>) -> Expr {
+        self.make(BuiltinFunction::Eval, vec![quoted.into()])
+    }
+
+    /// `(int-range start end)`
+    pub fn int_range(&self, start: impl Into<Expr>, end: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::IntRange, start, end)
+    }
+
+    /// `(in-range value range)`
+    pub fn in_range(&self, value: impl Into<Expr>, range: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::InRange, value, range)
+    }
+
+    /// `(ranges-overlap a b)`
+    pub fn ranges_overlap(&self, a: impl Into<Expr>, b: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::RangesOverlap, a, b)
+    }
+
+    /// `(union list1 list2 ...)`
+    pub fn union(&self, lists: impl IntoIterator<Item = Expr>) -> Expr {
+        self.make(BuiltinFunction::Union, lists.into_iter().collect())
+    }
+
+    /// `(intersection list1 list2 ...)`
+    pub fn intersection(&self, lists: impl IntoIterator<Item = Expr>) -> Expr {
+        self.make(BuiltinFunction::Intersection, lists.into_iter().collect())
+    }
+
+    /// `(difference a b)`
+    pub fn difference(&self, a: impl Into<Expr>, b: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::Difference, a, b)
+    }
+
+    /// `(subset? a b)`
+    pub fn is_subset(&self, a: impl Into<Expr>, b: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::IsSubset, a, b)
+    }
+
+    /// `(disjoint? a b)`
+    pub fn is_disjoint(&self, a: impl Into<Expr>, b: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::IsDisjoint, a, b)
+    }
+
+    /// `(in-set value set)`
+    pub fn in_set(&self, value: impl Into<Expr>, set: impl Into<Expr>) -> Expr {
+        self.binary(BuiltinFunction::InSet, value, set)
+    }
+
+    fn binary(
+        &self,
+        function: BuiltinFunction,
+        left: impl Into<Expr>,
+        right: impl Into<Expr>,
+    ) -> Expr {
+        self.make(function, vec![left.into(), right.into()])
+    }
+
+    fn make(&self, function: BuiltinFunction, args: Vec<Expr>) -> Expr {
+        Expr::Call {
+            function: self.intern(function.as_str()),
+            args,
+        }
+    }
+
+    fn intern(&self, s: &str) -> StringId {
+        self.interner.borrow_mut().intern(s)
+    }
+}
+
+impl From<Value> for Expr {
+    fn from(value: Value) -> Self {
+        Expr::Literal(value)
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(n: i64) -> Self {
+        Expr::Literal(Value::Integer(n))
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(x: f64) -> Self {
+        Expr::Literal(Value::Float(x))
+    }
+}
+
+impl From<bool> for Expr {
+    fn from(b: bool) -> Self {
+        Expr::Literal(Value::Bool(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Arity;
+    use crate::parse;
+
+    #[test]
+    fn builds_parsed_equivalent() {
+        let mut interner = StringInterner::new();
+        let built = {
+            let b = ExprBuilder::new(&mut interner);
+            b.or([
+                b.and([
+                    b.eq(b.var("country"), b.str("US")),
+                    b.ge(b.var("age"), 21),
+                    b.not(b.is_in(b.var("tier"), b.list([b.sym("banned"), b.null()]))),
+                ]),
+                b.matches_regex(b.var("email"), "@example\\.com$"),
+                b.call("risk-score", [b.var("age")]).unwrap(),
+                b.gt(b.var("score"), 0.5),
+                b.let_in([("limit", 100)], b.lt(b.var("score"), b.var("limit"))),
+            ])
+        };
+        let parsed = parse(
+            r#"(or (and (= country "US") (>= age 21) (not (in tier ['banned null])))
+                   (matches-regex email "@example\\.com$")
+                   (risk-score age)
+                   (> score 0.5)
+                   (let ((limit 100)) (< score limit)))"#,
+            &mut interner,
+        )
+        .unwrap();
+        assert_eq!(built, parsed);
+    }
+
+    #[test]
+    fn checks_arity() {
+        let mut interner = StringInterner::new();
+        let b = ExprBuilder::new(&mut interner);
+        assert_eq!(
+            b.call("substring", [b.var("name"), Expr::from(1)]),
+            Err(EvalError::ArityMismatch {
+                function: BuiltinFunction::Substring,
+                expected: Arity::Exact(3),
+                found: 2,
+            })
+        );
+        let bbox = b.builtin(
+            BuiltinFunction::GeoWithinBbox,
+            [
+                b.var("lat"),
+                b.var("lng"),
+                40.0.into(),
+                (-75.0).into(),
+                41.0.into(),
+                (-73.0).into(),
+            ],
+        );
+        assert!(bbox.is_ok());
+    }
+}
@@ -0,0 +1,411 @@
+//! Caching decisions for repeated contexts
+//!
+//! Services often evaluate the same rules against the same context many
+//! times, such as on retries or for a user who makes several requests in a
+//! row. A `DecisionCache` keeps the most recently used results of a
+//! `RuleSetHandle`'s rules, keyed by rule ID and the
+//! `Environment::fingerprint` of the variables the rule reads, and serves
+//! repeats without evaluating. Each result is kept with the values of
+//! those variables, and a hit is only served when they equal the
+//! environment's, since fingerprints can collide.
+//!
+//! Results are only valid for the rules they were computed with, so the
+//! cache empties itself the first time it is used after the handle loads a
+//! new rule set, or with a different handle than before. A cache used with
+//! several handles in turn is correct but keeps little.
+//!
+//! Rules must be deterministic for cached results to be correct. Every
+//! builtin is, but custom functions and providers are not consulted:
+//! evaluations go through `CompiledExpr::eval` without them.
+
+use crate::compat::{FxHashMap, Mutex};
+use crate::{Environment, EvalError, RuleId, RuleSetHandle, StringId, Value};
+use alloc::vec::Vec;
+
+/// Index of no entry in the recency list
+const NONE: usize = usize::MAX;
+
+/// Rule ID and fingerprint of the environment it was evaluated against
+type Key = (RuleId, u128);
+
+/// Least recently used cache of rule results
+///
+/// A cache hit moves the entry to the front of the recency list, so every
+/// lookup takes the one lock exclusively.
+#[derive(Debug)]
+pub struct DecisionCache {
+    capacity: usize,
+    state: Mutex<Lru>,
+}
+
+/// Entries linked from most to least recently used
+#[derive(Debug)]
+struct Lru {
+    /// Handle the entries were computed with, `None` before the first
+    handle: Option<usize>,
+    /// Generation of the rule set the entries were computed with
+    generation: u64,
+    slots: FxHashMap<Key, usize>,
+    entries: Vec<Entry>,
+    newest: usize,
+    oldest: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: Key,
+    /// Values of the variables the rule reads, `None` for unbound ones
+    bindings: Vec<Option<Value>>,
+    result: Result<Value, EvalError>,
+    newer: usize,
+    older: usize,
+}
+
+impl DecisionCache {
+    /// Create a cache holding at most `capacity` results
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(Lru {
+                handle: None,
+                generation: 0,
+                slots: FxHashMap::default(),
+                entries: Vec::new(),
+                newest: NONE,
+                oldest: NONE,
+            }),
+        }
+    }
+
+    /// Get the most results the cache holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the number of results cached
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Check if no results are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every cached result
+    pub fn clear(&self) {
+        self.state.lock().unwrap().clear();
+    }
+
+    /// Evaluate a rule of the active rule set, or get its cached result
+    /// for an environment with the same values of the variables the rule
+    /// reads. `None` if there is no such rule
+    pub fn eval(
+        &self,
+        handle: &RuleSetHandle,
+        id: RuleId,
+        env: &Environment,
+    ) -> Option<Result<Value, EvalError>> {
+        let (rules, generation) = handle.load_with_generation();
+        let compiled = rules.compiled(id)?;
+        let variables = compiled.variables();
+        let key = (id, env.fingerprint(variables, rules.interner()));
+        let version = (handle.id(), generation);
+        Some(self.get_or_eval(version, key, env, variables, || {
+            compiled.eval(env, rules.interner())
+        }))
+    }
+
+    /// Return the IDs of the active rules that evaluate to `true`, like
+    /// `RuleSet::matches`, using cached results where there are any
+    pub fn matches(&self, handle: &RuleSetHandle, env: &Environment) -> Vec<RuleId> {
+        let (rules, generation) = handle.load_with_generation();
+        let version = (handle.id(), generation);
+        rules
+            .candidates(env)
+            .into_iter()
+            .filter_map(|slot| {
+                let (id, compiled) = rules.compiled_in(slot)?;
+                let variables = compiled.variables();
+                let key = (id, env.fingerprint(variables, rules.interner()));
+                let result = self.get_or_eval(version, key, env, variables, || {
+                    compiled.eval(env, rules.interner())
+                });
+                (result == Ok(Value::Bool(true))).then_some(id)
+            })
+            .collect()
+    }
+
+    /// Get the cached result for `key`, or evaluate and cache it. Rules
+    /// are evaluated without holding the lock, and results of a rule set
+    /// older than the cached ones are not cached. `version` is the handle's
+    /// ID and the generation of the rule set, and `variables` are the ones
+    /// the rule reads from `env`
+    fn get_or_eval(
+        &self,
+        version: (usize, u64),
+        key: Key,
+        env: &Environment,
+        variables: &[StringId],
+        eval: impl FnOnce() -> Result<Value, EvalError>,
+    ) -> Result<Value, EvalError> {
+        {
+            let mut lru = self.state.lock().unwrap();
+            if lru.sync(version) {
+                if let Some(result) = lru.get(&key, env, variables) {
+                    return result;
+                }
+            }
+        }
+        let result = eval();
+        let mut lru = self.state.lock().unwrap();
+        if lru.sync(version) {
+            let bindings = variables.iter().map(|&name| env.get(name).cloned());
+            lru.insert(key, bindings.collect(), result.clone(), self.capacity);
+        }
+        result
+    }
+}
+
+impl Lru {
+    /// Empty the cache if `version` is of another handle or a newer
+    /// generation than its entries, and check if its entries are now of
+    /// `version`
+    fn sync(&mut self, (handle, generation): (usize, u64)) -> bool {
+        if self.handle != Some(handle) || generation > self.generation {
+            self.clear();
+            self.handle = Some(handle);
+            self.generation = generation;
+        }
+        generation == self.generation
+    }
+
+    /// Get the result cached for `key` if it was computed with the same
+    /// values of `variables` as `env` has
+    fn get(
+        &mut self,
+        key: &Key,
+        env: &Environment,
+        variables: &[StringId],
+    ) -> Option<Result<Value, EvalError>> {
+        let slot = *self.slots.get(key)?;
+        let bindings = &self.entries[slot].bindings;
+        if bindings.len() != variables.len()
+            || !bindings
+                .iter()
+                .zip(variables)
+                .all(|(bound, &name)| bound.as_ref() == env.get(name))
+        {
+            return None;
+        }
+        self.touch(slot);
+        Some(self.entries[slot].result.clone())
+    }
+
+    fn insert(
+        &mut self,
+        key: Key,
+        bindings: Vec<Option<Value>>,
+        result: Result<Value, EvalError>,
+        capacity: usize,
+    ) {
+        if let Some(&slot) = self.slots.get(&key) {
+            let entry = &mut self.entries[slot];
+            entry.bindings = bindings;
+            entry.result = result;
+            self.touch(slot);
+            return;
+        }
+        let slot = if self.entries.len() < capacity {
+            self.entries.push(Entry {
+                key,
+                bindings,
+                result,
+                newer: NONE,
+                older: NONE,
+            });
+            self.entries.len() - 1
+        } else if self.oldest != NONE {
+            // Reuse the least recently used entry
+            let slot = self.oldest;
+            self.unlink(slot);
+            let entry = &mut self.entries[slot];
+            self.slots.remove(&entry.key);
+            entry.key = key;
+            entry.bindings = bindings;
+            entry.result = result;
+            slot
+        } else {
+            return;
+        };
+        self.slots.insert(key, slot);
+        self.link_newest(slot);
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.entries.clear();
+        self.newest = NONE;
+        self.oldest = NONE;
+    }
+
+    /// Mark an entry as the most recently used
+    fn touch(&mut self, slot: usize) {
+        if self.newest != slot {
+            self.unlink(slot);
+            self.link_newest(slot);
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let Entry { newer, older, .. } = self.entries[slot];
+        match newer {
+            NONE => self.newest = older,
+            newer => self.entries[newer].older = older,
+        }
+        match older {
+            NONE => self.oldest = newer,
+            older => self.entries[older].newer = newer,
+        }
+    }
+
+    fn link_newest(&mut self, slot: usize) {
+        let entry = &mut self.entries[slot];
+        entry.newer = NONE;
+        entry.older = self.newest;
+        match self.newest {
+            NONE => self.oldest = slot,
+            newest => self.entries[newest].newer = slot,
+        }
+        self.newest = slot;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::tests::rule_set;
+    use crate::StringInterner;
+
+    #[test]
+    fn caches_results() {
+        let handle = RuleSetHandle::new(rule_set(
+            StringInterner::new(),
+            &[(1, "(> age 18)"), (2, "(< age 65)"), (3, "(= tier 'gold)")],
+        ));
+        let rules = handle.load();
+        let (age, tier) = (
+            rules.interner().get_id("age").unwrap(),
+            rules.interner().get_id("tier").unwrap(),
+        );
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(30));
+        let cache = DecisionCache::new(8);
+
+        assert_eq!(cache.eval(&handle, 1, &env), Some(Ok(Value::Bool(true))));
+        assert_eq!(cache.len(), 1);
+        // Variables rule 1 does not read leave its key unchanged
+        env.insert(tier, Value::Integer(1));
+        assert_eq!(cache.eval(&handle, 1, &env), Some(Ok(Value::Bool(true))));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.eval(&handle, 9, &env), None);
+
+        // The index rules out rule 3, which is not evaluated or cached
+        assert_eq!(cache.matches(&handle, &env), rules.matches(&env));
+        assert_eq!(cache.len(), 2);
+        env.insert(age, Value::Integer(70));
+        assert_eq!(cache.matches(&handle, &env), [1]);
+        assert_eq!(cache.len(), 4);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let handle = RuleSetHandle::new(rule_set(StringInterner::new(), &[(1, "(> age 18)")]));
+        let age = handle.load().interner().get_id("age").unwrap();
+        let env = |n| -> Environment { [(age, Value::Integer(n))].into_iter().collect() };
+        let cache = DecisionCache::new(2);
+        let key = |n| {
+            let rules = handle.load();
+            let variables = rules.compiled(1).unwrap().variables();
+            (1, env(n).fingerprint(variables, rules.interner()))
+        };
+
+        cache.eval(&handle, 1, &env(1));
+        cache.eval(&handle, 1, &env(2));
+        cache.eval(&handle, 1, &env(1));
+        cache.eval(&handle, 1, &env(3));
+        let lru = cache.state.lock().unwrap();
+        assert_eq!(lru.entries.len(), 2);
+        assert!(lru.slots.contains_key(&key(1)));
+        assert!(!lru.slots.contains_key(&key(2)));
+        assert!(lru.slots.contains_key(&key(3)));
+        drop(lru);
+
+        let empty = DecisionCache::new(0);
+        assert_eq!(
+            empty.eval(&handle, 1, &env(1)),
+            Some(Ok(Value::Bool(false)))
+        );
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn checks_bindings_on_a_hit() {
+        let handle = RuleSetHandle::new(rule_set(StringInterner::new(), &[(1, "(> age 18)")]));
+        let rules = handle.load();
+        let age = rules.interner().get_id("age").unwrap();
+        let variables = rules.compiled(1).unwrap().variables();
+        let adult: Environment = [(age, Value::Integer(30))].into_iter().collect();
+        let child: Environment = [(age, Value::Integer(10))].into_iter().collect();
+        let cache = DecisionCache::new(8);
+        assert_eq!(cache.eval(&handle, 1, &adult), Some(Ok(Value::Bool(true))));
+
+        // An environment whose fingerprint collides with a cached one is
+        // evaluated instead of served the other environment's result
+        let version = (handle.id(), handle.generation());
+        let key = (1, adult.fingerprint(variables, rules.interner()));
+        let eval = || rules.compiled(1).unwrap().eval(&child, rules.interner());
+        assert_eq!(
+            cache.get_or_eval(version, key, &child, variables, eval),
+            Ok(Value::Bool(false))
+        );
+        // The colliding result replaced the entry, so the first environment
+        // is evaluated again too
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.eval(&handle, 1, &adult), Some(Ok(Value::Bool(true))));
+    }
+
+    #[test]
+    fn invalidates_on_reload() {
+        let handle = RuleSetHandle::new(rule_set(StringInterner::new(), &[(1, "(> age 18)")]));
+        let held = handle.load();
+        let age = held.interner().get_id("age").unwrap();
+        let env: Environment = [(age, Value::Integer(30))].into_iter().collect();
+        let cache = DecisionCache::new(8);
+        assert_eq!(cache.eval(&handle, 1, &env), Some(Ok(Value::Bool(true))));
+
+        let next = rule_set(held.interner().clone(), &[(1, "(> age 40)")]);
+        handle.replace(next).unwrap();
+        assert_eq!(cache.eval(&handle, 1, &env), Some(Ok(Value::Bool(false))));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn invalidates_on_another_handle() {
+        let first = RuleSetHandle::new(rule_set(StringInterner::new(), &[(1, "(> age 18)")]));
+        let interner = first.load().interner().clone();
+        let second = RuleSetHandle::new(rule_set(interner, &[(1, "(> age 40)")]));
+        let age = first.load().interner().get_id("age").unwrap();
+        let env: Environment = [(age, Value::Integer(30))].into_iter().collect();
+        let cache = DecisionCache::new(8);
+
+        // Both handles are at generation 0, but results do not carry over
+        assert_eq!(cache.eval(&first, 1, &env), Some(Ok(Value::Bool(true))));
+        assert_eq!(cache.eval(&second, 1, &env), Some(Ok(Value::Bool(false))));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.matches(&first, &env), [1]);
+    }
+}
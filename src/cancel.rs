@@ -0,0 +1,42 @@
+//! Cooperative cancellation
+//!
+//! Custom functions may block, so evaluation can be stopped from outside
+//! through a `CancelToken` set with `Evaluator::with_cancel_token`, or
+//! bounded in time with `Evaluator::eval_with_deadline`. Both are checked
+//! before every call and after every custom function returns, and fail the
+//! evaluation with `EvalError::Cancelled`. A custom function that is
+//! already running is not interrupted.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Shared flag that cancels every evaluation watching it
+///
+/// Clones share the same flag, so one clone can be handed to an evaluator
+/// and another kept to cancel it from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel evaluations watching this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Check if the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Clear the cancellation so the token can be reused
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,341 @@
+//! Canonical forms of expressions
+//!
+//! `Expr::canonicalize` rewrites an expression so that rules written
+//! differently but meaning the same come out identical, and
+//! `Expr::equivalent` compares two expressions by their canonical forms:
+//!
+//! - negated builtins become `not` of their positive form, so
+//!   `(not-in x l)` is `(not (in x l))`, `(none-of a b)` is
+//!   `(not (one-of a b))` and `(!= a b)` is `(not (= a b))`, and double
+//!   negation is removed. Under `MissingVariable::False` a null result
+//!   becomes false before it can be negated, so these two rewrites are
+//!   skipped there
+//! - `>` and `>=` become `<` and `<=` with their operands swapped
+//! - operands of `and`/`or` are flattened, sorted and deduplicated, and
+//!   the two operands of `=` are sorted
+//! - list literals of literals become values, and lists tested for
+//!   membership are sorted and deduplicated
+//! - metadata attached with `meta` is dropped
+//!
+//! Operands are ordered by their S-expression text, so the order does not
+//! depend on the order strings were interned. Like `simplify`, the rewrites
+//! assume boolean operators are given boolean operands, and reordering
+//! `and`/`or` can change which operand a short-circuit skips, so a rule
+//! that fails to evaluate may be equivalent to one that succeeds.
+
+use crate::eval::make_list;
+use crate::{BuiltinFunction, EvalOptions, Expr, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+
+impl Expr {
+    /// Rewrite this expression, built with `interner`, into its canonical
+    /// form for evaluation with `options`
+    ///
+    /// `interner` is mutable because the canonical form may call builtins,
+    /// such as `not`, that the original does not.
+    pub fn canonicalize(&self, interner: &mut StringInterner, options: &EvalOptions) -> Expr {
+        canonical(self.clone(), interner, options)
+    }
+
+    /// Check if two expressions built with `interner` have the same
+    /// canonical form for evaluation with `options`
+    pub fn equivalent(
+        &self,
+        other: &Expr,
+        interner: &mut StringInterner,
+        options: &EvalOptions,
+    ) -> bool {
+        self == other
+            || self.canonicalize(interner, options) == other.canonicalize(interner, options)
+    }
+}
+
+fn canonical(expr: Expr, interner: &mut StringInterner, options: &EvalOptions) -> Expr {
+    match expr {
+        Expr::List(items) => {
+            let items: Vec<Expr> = items
+                .into_iter()
+                .map(|item| canonical(item, interner, options))
+                .collect();
+            let values: Option<Vec<&Value>> = items
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(value) => Some(value),
+                    _ => None,
+                })
+                .collect();
+            match values {
+                Some(values) => Expr::Literal(make_list(&values)),
+                None => Expr::List(items),
+            }
+        }
+        Expr::Call { function, args } => {
+            let args: Vec<Expr> = args
+                .into_iter()
+                .map(|arg| canonical(arg, interner, options))
+                .collect();
+            match interner.builtin(function) {
+                // Leave bad arity for the evaluator to report
+                Some(builtin) if builtin.arity().accepts(args.len()) => {
+                    canonical_call(builtin, args, interner, options)
+                }
+                _ => Expr::Call { function, args },
+            }
+        }
+        // Metadata does not change what a rule means
+        Expr::Annotated { expr, .. } => canonical(*expr, interner, options),
+        Expr::Let { bindings, body } => Expr::Let {
+            bindings: bindings
+                .into_iter()
+                .map(|(name, value)| (name, canonical(value, interner, options)))
+                .collect(),
+            body: Box::new(canonical(*body, interner, options)),
+        },
+        leaf => leaf,
+    }
+}
+
+/// Rewrite a builtin call whose arguments are already canonical
+fn canonical_call(
+    function: BuiltinFunction,
+    mut args: Vec<Expr>,
+    interner: &mut StringInterner,
+    options: &EvalOptions,
+) -> Expr {
+    // Negating keeps null under the other policies, but not false
+    let negations = !options.null_is_false();
+    match function {
+        BuiltinFunction::NotIn | BuiltinFunction::NoneOf | BuiltinFunction::NotEqual
+            if negations =>
+        {
+            let positive = match function {
+                BuiltinFunction::NotIn => BuiltinFunction::In,
+                BuiltinFunction::NoneOf => BuiltinFunction::OneOf,
+                _ => BuiltinFunction::Equal,
+            };
+            let positive = canonical_call(positive, args, interner, options);
+            negate(positive, interner)
+        }
+        BuiltinFunction::Not if negations => {
+            let operand = args.pop().expect("not has one argument");
+            negate(operand, interner)
+        }
+        BuiltinFunction::GreaterThan | BuiltinFunction::GreaterThanOrEqual => {
+            args.swap(0, 1);
+            let flipped = match function {
+                BuiltinFunction::GreaterThan => BuiltinFunction::LessThan,
+                _ => BuiltinFunction::LessThanOrEqual,
+            };
+            call(flipped, args, interner)
+        }
+        BuiltinFunction::And | BuiltinFunction::Or => {
+            let mut operands = Vec::with_capacity(args.len());
+            for arg in args {
+                match arg {
+                    // Operands are already canonical, so nested calls are flat
+                    Expr::Call {
+                        function: inner,
+                        args,
+                    } if interner.builtin(inner) == Some(function) => operands.extend(args),
+                    other => operands.push(other),
+                }
+            }
+            sort_operands(&mut operands, interner);
+            operands.dedup();
+            call(function, operands, interner)
+        }
+        BuiltinFunction::Equal | BuiltinFunction::NotEqual => {
+            sort_operands(&mut args, interner);
+            call(function, args, interner)
+        }
+        BuiltinFunction::In | BuiltinFunction::NotIn => {
+            let list = args.pop().expect("in has two arguments");
+            args.push(sort_set(list, interner));
+            call(function, args, interner)
+        }
+        BuiltinFunction::OneOf | BuiltinFunction::AllOf | BuiltinFunction::NoneOf => {
+            let args = args
+                .into_iter()
+                .map(|arg| sort_set(arg, interner))
+                .collect();
+            call(function, args, interner)
+        }
+        _ => call(function, args, interner),
+    }
+}
+
+/// Wrap an expression in `not`, cancelling a `not` it already has
+fn negate(expr: Expr, interner: &mut StringInterner) -> Expr {
+    match expr {
+        Expr::Call { function, mut args }
+            if interner.builtin(function) == Some(BuiltinFunction::Not) =>
+        {
+            args.pop().expect("not has one argument")
+        }
+        other => call(BuiltinFunction::Not, vec![other], interner),
+    }
+}
+
+fn call(function: BuiltinFunction, args: Vec<Expr>, interner: &mut StringInterner) -> Expr {
+    Expr::Call {
+        function: interner.intern(function.as_str()),
+        args,
+    }
+}
+
+fn sort_operands(operands: &mut [Expr], interner: &StringInterner) {
+    operands.sort_by_cached_key(|operand| operand.to_sexpr(interner));
+}
+
+/// Sort and deduplicate a list whose order does not matter
+fn sort_set(list: Expr, interner: &StringInterner) -> Expr {
+    match list {
+        Expr::Literal(Value::StringList(mut ids)) => {
+            ids.sort_by_cached_key(|&id| interner.resolve(id).unwrap_or_default());
+            ids.dedup();
+            Expr::Literal(Value::StringList(ids))
+        }
+        Expr::Literal(Value::IntegerList(mut ns)) => {
+            ns.sort_unstable();
+            ns.dedup();
+            Expr::Literal(Value::IntegerList(ns))
+        }
+        Expr::Literal(Value::List(mut items)) => {
+            items.sort_by_cached_key(|item| item.display(interner).to_string());
+            items.dedup();
+            Expr::Literal(Value::List(items))
+        }
+        Expr::List(mut items) => {
+            sort_operands(&mut items, interner);
+            items.dedup();
+            Expr::List(items)
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Environment, Evaluator, MissingVariable};
+
+    #[test]
+    fn equivalent_rules() {
+        let mut interner = StringInterner::new();
+        let pairs = [
+            ("(and a b)", "(and b a)", true),
+            ("(and a (and b c))", "(and (and c b) a a)", true),
+            ("(or a b)", "(and a b)", false),
+            ("(= x 1)", "(= 1 x)", true),
+            ("(> x 1)", "(< 1 x)", true),
+            ("(>= x 1)", "(<= x 1)", false),
+            ("(not-in x [3 1 2])", "(not (in x [1 2 3 3]))", true),
+            (
+                "(none-of tags [\"b\" \"a\"])",
+                "(not (one-of tags [\"a\" \"b\"]))",
+                true,
+            ),
+            ("(!= x y)", "(not (= y x))", true),
+            ("(not (not (in x [y z])))", "(in x [z y])", true),
+            ("(in x [y z])", "(in x [y])", false),
+            (
+                "(geo_within_polygon lat lng [[0 1] [1 0] [0 0]])",
+                "(geo_within_polygon lat lng [[0 0] [1 0] [0 1]])",
+                false,
+            ),
+            ("(frob b a)", "(frob a b)", false),
+        ];
+
+        for (left, right, expected) in pairs {
+            let left_expr = parse(left, &mut interner).unwrap();
+            let right_expr = parse(right, &mut interner).unwrap();
+            assert_eq!(
+                left_expr.equivalent(&right_expr, &mut interner, &EvalOptions::default()),
+                expected,
+                "{} and {}",
+                left,
+                right
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_negations_when_null_is_false() {
+        let mut interner = StringInterner::new();
+        let options = EvalOptions {
+            missing: MissingVariable::False,
+            ..EvalOptions::default()
+        };
+        let pairs = [
+            ("(not-in x [3 1 2])", "(not (in x [1 2 3]))", false),
+            ("(not-in x [3 1 2])", "(not-in x [1 2 3 3])", true),
+            ("(!= x y)", "(not (= y x))", false),
+            ("(!= x y)", "(!= y x)", true),
+            (
+                "(none-of tags [\"b\" \"a\"])",
+                "(none-of tags [\"a\" \"b\"])",
+                true,
+            ),
+            ("(not (not (in x [y z])))", "(in x [z y])", false),
+            ("(> x 1)", "(< 1 x)", true),
+        ];
+
+        for (left, right, expected) in pairs {
+            let left_expr = parse(left, &mut interner).unwrap();
+            let right_expr = parse(right, &mut interner).unwrap();
+            assert_eq!(
+                left_expr.equivalent(&right_expr, &mut interner, &options),
+                expected,
+                "{} and {}",
+                left,
+                right
+            );
+        }
+    }
+
+    #[test]
+    fn preserves_results() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(40));
+        env.insert(interner.intern("vip"), Value::Bool(false));
+        let tags = vec![interner.intern("news"), interner.intern("tech")];
+        env.insert(interner.intern("tags"), Value::StringList(tags.into()));
+
+        let sources = [
+            "(and (>= age 18) (or vip (> 50 age)))",
+            r#"(or (not-in age [40 30]) (none-of tags ["sports" "news"]))"#,
+            r#"(and (!= age 41) (all-of tags ["tech" "news"]) (not (not vip)))"#,
+            "(or (> missing 1) (not (= age 40)))",
+            "(not-in missing [1 2])",
+            "(!= missing 1)",
+            "(none-of missing [1 2])",
+            "(not (not (in missing [1 2])))",
+        ];
+        for missing in [
+            MissingVariable::Error,
+            MissingVariable::Null,
+            MissingVariable::False,
+        ] {
+            let options = EvalOptions {
+                missing,
+                ..EvalOptions::default()
+            };
+            for source in sources {
+                let expr = parse(source, &mut interner).unwrap();
+                let canonical = expr.canonicalize(&mut interner, &options);
+                let evaluator = Evaluator::with_options(&interner, options);
+                assert_eq!(
+                    evaluator.eval(&canonical, &env),
+                    evaluator.eval(&expr, &env),
+                    "{} under {:?}",
+                    source,
+                    missing
+                );
+            }
+        }
+    }
+}
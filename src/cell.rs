@@ -0,0 +1,156 @@
+//! Geo cells for `geo-cell-in` and `geohash`
+//!
+//! A cell is a geohash string such as `"u09tvw"` or an H3 cell index as an
+//! integer. `(geo-cell-in cell cells)` is true when `cell` is one of
+//! `cells` or lies inside one of them, so a list of coarse cells covers
+//! every finer cell within: a geohash lies inside each of its prefixes and
+//! an H3 cell inside its parent at each coarser resolution. Strings and
+//! integers never match each other, an integer that is not an H3 cell
+//! index only matches itself, and any other value matches nothing.
+//!
+//! `(geohash lat lng precision)` computes the geohash of a point with
+//! `precision` characters, from 1 to 12, so rules can test points against
+//! geohash cells without precomputing them. Computing H3 cells needs the
+//! H3 library and is left to the caller.
+
+use crate::{Interner, Value};
+use alloc::string::String;
+
+/// Longest geohash `geohash` computes, finer than a centimeter
+pub(crate) const MAX_PRECISION: i64 = 12;
+
+/// Geohash digits, in order of value
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Bits of an H3 index holding the resolution
+const H3_RESOLUTION_SHIFT: u32 = 52;
+
+/// Finest H3 resolution, which has 15 digits
+const H3_MAX_RESOLUTION: u64 = 15;
+
+/// Iterate the cells `cell` lies in, starting with `cell` itself and
+/// ending with the coarsest
+pub(crate) fn ancestors<'a>(
+    cell: &'a Value,
+    interner: &'a dyn Interner,
+) -> impl Iterator<Item = Value> + 'a {
+    let geohash = match cell {
+        Value::String(id) => interner.resolve(*id),
+        Value::Text(text) => Some(&**text),
+        _ => None,
+    };
+    let prefixes = geohash.into_iter().flat_map(|text| {
+        text.char_indices()
+            .rev()
+            .map(move |(i, c)| Value::Text(text[..i + c.len_utf8()].into()))
+    });
+    let index = match cell {
+        Value::Integer(n) => Some(*n as u64),
+        _ => None,
+    };
+    let parents = index.into_iter().flat_map(|index| {
+        let resolution = h3_resolution(index);
+        // An integer that is not a cell index is its own only ancestor
+        let own = resolution.is_none().then_some(Value::Integer(index as i64));
+        let parents = resolution.into_iter().flat_map(move |finest| {
+            (0..=finest)
+                .rev()
+                .map(move |resolution| Value::Integer(h3_parent(index, resolution) as i64))
+        });
+        own.into_iter().chain(parents)
+    });
+    prefixes.chain(parents)
+}
+
+/// Resolution of an H3 cell index, `None` if `index` is not one
+fn h3_resolution(index: u64) -> Option<u64> {
+    // Cell indexes have mode 1 and the reserved high bit clear
+    if index >> 63 != 0 || (index >> 59) & 0xf != 1 {
+        return None;
+    }
+    Some((index >> H3_RESOLUTION_SHIFT) & 0xf)
+}
+
+/// Parent of an H3 cell at a resolution no finer than its own, which
+/// leaves the digits below that resolution unused
+fn h3_parent(index: u64, resolution: u64) -> u64 {
+    let mut parent = index & !(0xf << H3_RESOLUTION_SHIFT) | resolution << H3_RESOLUTION_SHIFT;
+    for digit in resolution + 1..=H3_MAX_RESOLUTION {
+        parent |= 7 << ((H3_MAX_RESOLUTION - digit) * 3);
+    }
+    parent
+}
+
+/// Geohash of a point given in degrees, `precision` characters long
+pub(crate) fn geohash(lat: f64, lng: f64, precision: usize) -> String {
+    let (mut lat_range, mut lng_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    // Bits alternate between longitude and latitude, longitude first
+    let mut even = true;
+    let mut digit = 0;
+    let mut bits = 0;
+    while hash.len() < precision {
+        let (range, value) = if even {
+            (&mut lng_range, lng)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        digit <<= 1;
+        if value >= mid {
+            digit |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[digit] as char);
+            digit = 0;
+            bits = 0;
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StringInterner;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn encodes_geohashes() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(48.8584, 2.2945, 6), "u09tun");
+        assert_eq!(geohash(-90.0, -180.0, 3), "000");
+        assert_eq!(geohash(90.0, 180.0, 3), "zzz");
+    }
+
+    #[test]
+    fn lists_ancestors() {
+        let mut interner = StringInterner::new();
+        let cell = Value::String(interner.intern("u09t"));
+        let prefixes: Vec<_> = ancestors(&cell, &interner).collect();
+        let expected: Vec<_> = ["u09t", "u09", "u0", "u"]
+            .into_iter()
+            .map(|prefix| Value::Text(prefix.into()))
+            .collect();
+        assert_eq!(prefixes, expected);
+
+        // A resolution 9 cell and its parents, down to the base cell
+        let cell = 0x8928308280fffff;
+        assert_eq!(h3_resolution(cell), Some(9));
+        let parents: Vec<_> = ancestors(&Value::Integer(cell as i64), &interner).collect();
+        assert_eq!(parents.len(), 10);
+        assert_eq!(parents[0], Value::Integer(cell as i64));
+        assert_eq!(parents[1], Value::Integer(0x8828308281fffff));
+        assert_eq!(parents[9], Value::Integer(0x8029fffffffffff));
+
+        // Not a cell index, so only itself
+        let parents: Vec<_> = ancestors(&Value::Integer(-42), &interner).collect();
+        assert_eq!(parents, [Value::Integer(-42)]);
+        assert_eq!(ancestors(&Value::Float(1.0), &interner).count(), 0);
+    }
+}
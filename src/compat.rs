@@ -0,0 +1,146 @@
+//! Stand-ins for the parts of `std` the crate uses
+//!
+//! With the `std` feature these are the `std` items themselves. Without it
+//! the crate is `no_std` and uses `alloc` plus small dependencies instead:
+//! hash maps come from `hashbrown`, locks from `spin` and float functions
+//! from `libm`. `Instant` is uninhabited, so deadlines cannot be set and
+//! metrics timings are zero.
+
+#[cfg(feature = "std")]
+pub(crate) use with_std::*;
+#[cfg(not(feature = "std"))]
+pub(crate) use without_std::*;
+
+/// Float functions computed in software by `libm`, which give the same
+/// results on every platform
+pub(crate) mod portable {
+    pub(crate) use libm::{asin, ceil, cos, exp, log as ln, sin, sqrt};
+}
+
+#[cfg(feature = "std")]
+mod with_std {
+    use core::time::Duration;
+    pub(crate) use rustc_hash::{FxHashMap, FxHashSet};
+    pub(crate) use std::collections::HashSet;
+    pub(crate) use std::sync::{Mutex, RwLock};
+    pub(crate) use std::time::Instant;
+
+    /// Check if `deadline` has passed
+    pub(crate) fn deadline_passed(deadline: Instant) -> bool {
+        Instant::now() >= deadline
+    }
+
+    /// Read the clock to time something
+    pub(crate) fn now() -> Option<Instant> {
+        Some(Instant::now())
+    }
+
+    /// Time passed since `start`
+    pub(crate) fn elapsed(start: Option<Instant>) -> Duration {
+        start.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+
+    pub(crate) fn fract(x: f64) -> f64 {
+        x.fract()
+    }
+
+    pub(crate) fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    pub(crate) fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+
+    pub(crate) fn asin(x: f64) -> f64 {
+        x.asin()
+    }
+
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod without_std {
+    use core::convert::Infallible;
+    use core::fmt;
+    use core::time::Duration;
+
+    pub(crate) use libm::{asin, cos, sin, sqrt};
+
+    pub(crate) type FxHashMap<K, V> = hashbrown::HashMap<K, V, rustc_hash::FxBuildHasher>;
+    pub(crate) type FxHashSet<T> = hashbrown::HashSet<T, rustc_hash::FxBuildHasher>;
+    pub(crate) type HashSet<T> = FxHashSet<T>;
+
+    /// Point in time, which cannot exist without `std`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub(crate) enum Instant {}
+
+    /// Check if `deadline` has passed
+    pub(crate) fn deadline_passed(deadline: Instant) -> bool {
+        match deadline {}
+    }
+
+    /// Read the clock to time something, which there is none of
+    pub(crate) fn now() -> Option<Instant> {
+        None
+    }
+
+    /// Time passed since `start`, always zero
+    pub(crate) fn elapsed(_start: Option<Instant>) -> Duration {
+        Duration::ZERO
+    }
+
+    pub(crate) fn fract(x: f64) -> f64 {
+        x - libm::trunc(x)
+    }
+
+    /// Spinning mutual exclusion lock with the interface of `std::sync::Mutex`
+    ///
+    /// Locking never fails since a spin lock cannot be poisoned.
+    #[derive(Default)]
+    pub(crate) struct Mutex<T>(spin::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Self(spin::Mutex::new(value))
+        }
+
+        pub(crate) fn lock(&self) -> Result<spin::MutexGuard<'_, T>, Infallible> {
+            Ok(self.0.lock())
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("Mutex").field(&*self.0.lock()).finish()
+        }
+    }
+
+    /// Spinning reader-writer lock with the interface of `std::sync::RwLock`
+    ///
+    /// Locking never fails since a spin lock cannot be poisoned.
+    #[derive(Default)]
+    pub(crate) struct RwLock<T>(spin::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Self(spin::RwLock::new(value))
+        }
+
+        pub(crate) fn read(&self) -> Result<spin::RwLockReadGuard<'_, T>, Infallible> {
+            Ok(self.0.read())
+        }
+
+        pub(crate) fn write(&self) -> Result<spin::RwLockWriteGuard<'_, T>, Infallible> {
+            Ok(self.0.write())
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("RwLock").field(&*self.0.read()).finish()
+        }
+    }
+}
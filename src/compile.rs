@@ -0,0 +1,1492 @@
+//! Bytecode compiler and stack VM
+//!
+//! Lowers an `Expr` into a flat instruction sequence so hot rules can be
+//! evaluated without walking the tree. Function names are resolved once at
+//! compile time, literal lists and builtin calls whose arguments are all
+//! constants are evaluated once and stored as constants, and literal
+//! `matches-regex` patterns are compiled once per distinct pattern. Long
+//! literal lists of integers or strings tested with `in` or `one-of` are
+//! sorted or hashed once so each test is a lookup rather than a scan.
+//! Builtin calls that appear more than once, as in generated rules that
+//! repeat an audience check, are evaluated once per evaluation and their
+//! value reused. Locals bound by `let`, and the element that `any`, `all`,
+//! `filter` and `map` read as `_`, live in slots of their own, apart from
+//! the environment's variables.
+//! Evaluation results and errors match `Evaluator::eval`.
+
+use crate::compat::FxHashMap;
+use crate::eval::{self, check_arity, ElementFold, EvalError, EvalOptions, JunctionStep};
+use crate::expr::ELEMENT;
+use crate::function::{FunctionRegistry, Resolved};
+use crate::limits::Limit;
+use crate::member::MemberSet;
+use crate::metrics::{self, Cache, MetricsSink};
+use crate::net::Cidr;
+use crate::pattern::{compile_regex, literal_pattern, Pattern};
+use crate::provider::VariableProvider;
+use crate::semver::Operand;
+use crate::telemetry;
+use crate::{BuiltinFunction, EnvSlots, Environment, Expr, Interner, StringId, Value};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// A single VM instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Push a constant from the constant table
+    Const(u32),
+    /// Push the value bound to a variable slot
+    Load(u32),
+    /// Push the value bound to a variable slot, or null if it is unbound
+    LoadOrNull(u32),
+    /// Pop a value and bind it to a local slot of a `let`
+    Store(u32),
+    /// Push the value bound to a local slot
+    LoadLocal(u32),
+    /// Pop `argc` arguments and push the result of applying a builtin
+    Call(BuiltinFunction, u32),
+    /// Pop `n` values and push a list containing them
+    MakeList(u32),
+    /// Pop a value and push whether it matches a pattern from the regex
+    /// table
+    MatchRegex(u32),
+    /// Pop a value and push how it compares with a version or range from
+    /// the version table under a `semver-*` builtin
+    MatchVersion {
+        function: BuiltinFunction,
+        index: u32,
+    },
+    /// Pop a value and push whether it is an address in a block from the
+    /// CIDR table
+    MatchCidr(u32),
+    /// Pop a value and push the result of `in`, `not-in`, `one-of`,
+    /// `none-of` or `geo-cell-in` against a list from the member set table
+    Member {
+        function: BuiltinFunction,
+        index: u32,
+    },
+    /// Pop `argc` arguments and push the result of calling a function from
+    /// the custom function table
+    CallCustom { index: u32, argc: u32 },
+    /// Pop operand number `operand`, counting from zero, of `and`/`or` and
+    /// fold it into the running result below it, jumping to `target` once
+    /// the result is decided unless evaluation is strict
+    Junction {
+        function: BuiltinFunction,
+        operand: u32,
+        target: u32,
+    },
+    /// Pop the test of an `if` clause and jump to `target` unless it is
+    /// true
+    Branch(u32),
+    /// Jump to `target`
+    Jump(u32),
+    /// Pop a list and push the result of `any`, `all`, `filter` or `map`
+    /// over it, running the code that follows, up to `end`, once per
+    /// element with the element in a local slot
+    Iterate {
+        function: BuiltinFunction,
+        slot: u32,
+        end: u32,
+    },
+    /// Push the cached value of a repeated subexpression and jump to
+    /// `target` if it has already been evaluated
+    Recall { slot: u32, target: u32 },
+    /// Cache the value on top of the stack as the value of a repeated
+    /// subexpression
+    Remember(u32),
+    /// Fail with an error from the error table
+    Fail(u32),
+}
+
+/// An expression compiled to bytecode
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledExpr {
+    code: Vec<Instruction>,
+    constants: Vec<Value>,
+    /// Name of the variable in each slot
+    variables: Vec<StringId>,
+    regexes: Vec<Pattern>,
+    versions: Vec<Operand>,
+    cidrs: Vec<Cidr>,
+    members: Vec<MemberSet>,
+    functions: Vec<Resolved>,
+    errors: Vec<EvalError>,
+    /// Number of locals bound by `let`s
+    locals: usize,
+    /// Number of repeated subexpressions cached during evaluation
+    memos: usize,
+    max_stack: usize,
+    /// Nesting of the source expression, checked against `EvalLimits`
+    depth: usize,
+}
+
+/// Compile an expression built with `interner`
+pub fn compile(expr: &Expr, interner: &dyn Interner) -> CompiledExpr {
+    compile_with(expr, interner, &FunctionRegistry::new())
+}
+
+/// Compile an expression that may call custom functions from `functions`
+///
+/// Custom functions are resolved now, so later changes to the registry do
+/// not affect the compiled expression.
+pub fn compile_with(
+    expr: &Expr,
+    interner: &dyn Interner,
+    functions: &FunctionRegistry,
+) -> CompiledExpr {
+    let span = telemetry::compile();
+    let mut compiler = Compiler {
+        interner,
+        functions,
+        compiled: CompiledExpr {
+            code: Vec::new(),
+            constants: Vec::new(),
+            variables: Vec::new(),
+            regexes: Vec::new(),
+            versions: Vec::new(),
+            cidrs: Vec::new(),
+            members: Vec::new(),
+            functions: Vec::new(),
+            errors: Vec::new(),
+            locals: 0,
+            memos: 0,
+            max_stack: 0,
+            depth: depth(expr),
+        },
+        variable_slots: FxHashMap::default(),
+        regex_slots: FxHashMap::default(),
+        version_slots: FxHashMap::default(),
+        cidr_slots: FxHashMap::default(),
+        function_slots: FxHashMap::default(),
+        memo_slots: repeated_calls(expr, interner),
+        scope: Vec::new(),
+        depth: 0,
+    };
+    compiler.compile(expr);
+    telemetry::compiled(&span, compiler.compiled.code.len());
+    compiler.compiled
+}
+
+impl CompiledExpr {
+    /// Get the instruction sequence
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// Get the constant table referenced by `Instruction::Const`
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// Evaluate against an environment with default options
+    pub fn eval(&self, env: &Environment, interner: &dyn Interner) -> Result<Value, EvalError> {
+        self.eval_with(env, interner, &EvalOptions::default())
+    }
+
+    /// Evaluate against an environment
+    ///
+    /// `interner` must be the one the expression was compiled with, or one
+    /// derived from it, and is used to resolve the text of strings.
+    pub fn eval_with(
+        &self,
+        env: &Environment,
+        interner: &dyn Interner,
+        options: &EvalOptions,
+    ) -> Result<Value, EvalError> {
+        self.run(
+            |slot| env.get(self.variables[slot]),
+            interner,
+            options,
+            None,
+            None,
+        )
+    }
+
+    /// Evaluate against an environment, asking `provider` for variables
+    /// missing from it when the expression reads them
+    ///
+    /// `interner` is used as in `eval_with`.
+    pub fn eval_with_provider(
+        &self,
+        env: &Environment,
+        interner: &dyn Interner,
+        options: &EvalOptions,
+        provider: &dyn VariableProvider,
+    ) -> Result<Value, EvalError> {
+        self.run(
+            |slot| env.get(self.variables[slot]),
+            interner,
+            options,
+            None,
+            Some(provider),
+        )
+    }
+
+    /// Get the variables the expression reads, in slot order
+    pub fn variables(&self) -> &[StringId] {
+        &self.variables
+    }
+
+    /// Get the slot of a variable, if the expression reads it
+    pub fn slot(&self, name: StringId) -> Option<usize> {
+        self.variables.iter().position(|&variable| variable == name)
+    }
+
+    /// Create unbound slots for every variable the expression reads
+    pub fn slots(&self) -> EnvSlots {
+        EnvSlots::new(self.variables.len())
+    }
+
+    /// Copy the variables the expression reads from an environment into
+    /// slots
+    pub fn bind(&self, env: &Environment) -> EnvSlots {
+        let mut slots = self.slots();
+        for (slot, &name) in self.variables.iter().enumerate() {
+            if let Some(value) = env.get(name) {
+                slots.set(slot, value.clone());
+            }
+        }
+        slots
+    }
+
+    /// Evaluate against variable slots, indexing them directly instead of
+    /// looking variables up by name
+    ///
+    /// Slots missing from `slots` are unbound. `interner` is used as in
+    /// `eval_with`.
+    pub fn eval_slots(
+        &self,
+        slots: &EnvSlots,
+        interner: &dyn Interner,
+        options: &EvalOptions,
+    ) -> Result<Value, EvalError> {
+        self.run(|slot| slots.get(slot), interner, options, None, None)
+    }
+
+    /// Evaluate with variables from `lookup`, or failing that `provider`,
+    /// reporting to `metrics`
+    pub(crate) fn run<'a>(
+        &'a self,
+        lookup: impl Fn(usize) -> Option<&'a Value>,
+        interner: &dyn Interner,
+        options: &EvalOptions,
+        metrics: Option<&dyn MetricsSink>,
+        provider: Option<&dyn VariableProvider>,
+    ) -> Result<Value, EvalError> {
+        let span = telemetry::eval();
+        let result = self.execute(lookup, interner, options, metrics, provider);
+        span.result(&result);
+        result
+    }
+
+    fn execute<'a>(
+        &'a self,
+        lookup: impl Fn(usize) -> Option<&'a Value>,
+        interner: &dyn Interner,
+        options: &EvalOptions,
+        metrics: Option<&dyn MetricsSink>,
+        provider: Option<&dyn VariableProvider>,
+    ) -> Result<Value, EvalError> {
+        if self.depth > options.limits.max_depth {
+            return Err(EvalError::LimitExceeded(Limit::Depth));
+        }
+        let mut machine = Machine {
+            compiled: self,
+            lookup,
+            interner,
+            options,
+            metrics,
+            provider,
+            memo: vec![None; self.memos],
+            locals: vec![Value::Null; self.locals],
+            // Constants and variables are borrowed, only computed values are owned
+            stack: Vec::with_capacity(self.max_stack),
+            steps: 0,
+        };
+        machine.exec(0, self.code.len())?;
+        Ok(machine
+            .stack
+            .pop()
+            .expect("compiled expression leaves a result")
+            .into_owned())
+    }
+}
+
+/// State of one evaluation of a compiled expression
+struct Machine<'a, 'o, L> {
+    compiled: &'a CompiledExpr,
+    lookup: L,
+    interner: &'o dyn Interner,
+    options: &'o EvalOptions,
+    metrics: Option<&'o dyn MetricsSink>,
+    provider: Option<&'o dyn VariableProvider>,
+    memo: Vec<Option<Value>>,
+    locals: Vec<Value>,
+    stack: Vec<Cow<'a, Value>>,
+    steps: usize,
+}
+
+impl<'a, L: Fn(usize) -> Option<&'a Value>> Machine<'a, '_, L> {
+    /// Get a variable from the lookup, or failing that the provider
+    fn load(&self, slot: u32) -> Option<Cow<'a, Value>> {
+        match (self.lookup)(slot as usize) {
+            Some(value) => Some(Cow::Borrowed(value)),
+            None => {
+                let name = self.compiled.variables[slot as usize];
+                self.provider?.get(name).map(Cow::Owned)
+            }
+        }
+    }
+
+    /// Run the instructions from `start` up to `end`
+    fn exec(&mut self, start: usize, end: usize) -> Result<(), EvalError> {
+        let compiled = self.compiled;
+        let (interner, options) = (self.interner, self.options);
+        let null_is_false = options.null_is_false();
+        let mut pc = start;
+        while pc < end {
+            let instruction = compiled.code[pc];
+            pc += 1;
+            self.steps += 1;
+            if self.steps > options.limits.max_steps {
+                return Err(EvalError::LimitExceeded(Limit::Steps));
+            }
+            match instruction {
+                Instruction::Const(index) => {
+                    self.stack
+                        .push(Cow::Borrowed(&compiled.constants[index as usize]));
+                }
+                Instruction::Load(slot) => {
+                    let value = match self.load(slot) {
+                        Some(value) => value,
+                        None => Cow::Borrowed(options.unbound(compiled.variables[slot as usize])?),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::LoadOrNull(slot) => {
+                    let value = self.load(slot);
+                    self.stack.push(value.unwrap_or(Cow::Owned(Value::Null)));
+                }
+                Instruction::Store(slot) => {
+                    let value = self.stack.pop().expect("value on self.stack");
+                    self.locals[slot as usize] = value.into_owned();
+                }
+                Instruction::LoadLocal(slot) => {
+                    self.stack
+                        .push(Cow::Owned(self.locals[slot as usize].clone()));
+                }
+                Instruction::Call(function, argc) => {
+                    let base = self.stack.len() - argc as usize;
+                    let args = &self.stack[base..];
+                    let mut result = metrics::timed(self.metrics, function, || {
+                        eval::apply(function, args, interner, options)
+                    })?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    options.check_length(&result)?;
+                    self.stack.truncate(base);
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::MakeList(n) => {
+                    let base = self.stack.len() - n as usize;
+                    let list = eval::make_list(&self.stack[base..]);
+                    self.stack.truncate(base);
+                    self.stack.push(Cow::Owned(list));
+                }
+                Instruction::MatchRegex(index) => {
+                    let value = self.stack.pop().expect("operand on self.stack");
+                    let regex = &compiled.regexes[index as usize].0;
+                    let mut result =
+                        metrics::timed(self.metrics, BuiltinFunction::MatchesRegex, || {
+                            eval::match_regex(&value, regex, interner)
+                        })?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::MatchVersion { function, index } => {
+                    let value = self.stack.pop().expect("operand on self.stack");
+                    let operand = &compiled.versions[index as usize];
+                    let mut result = metrics::timed(self.metrics, function, || {
+                        eval::match_version(function, &value, operand, interner)
+                    })?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::MatchCidr(index) => {
+                    let value = self.stack.pop().expect("operand on self.stack");
+                    let cidr = &compiled.cidrs[index as usize];
+                    let mut result =
+                        metrics::timed(self.metrics, BuiltinFunction::IpInCidr, || {
+                            eval::match_cidr(&value, cidr, interner)
+                        })?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::Member { function, index } => {
+                    let value = self.stack.pop().expect("operand on self.stack");
+                    let set = &compiled.members[index as usize];
+                    let mut result = metrics::timed(self.metrics, function, || {
+                        eval::match_member(function, &value, set, interner)
+                    })?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::CallCustom { index, argc } => {
+                    let custom = &compiled.functions[index as usize];
+                    if options.deterministic && !custom.deterministic {
+                        return Err(EvalError::Nondeterministic(custom.name));
+                    }
+                    let base = self.stack.len() - argc as usize;
+                    let args: Vec<Value> = self.stack.drain(base..).map(Cow::into_owned).collect();
+                    let mut result = (custom.function)(&args)?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    options.check_length(&result)?;
+                    self.stack.push(Cow::Owned(result));
+                }
+                Instruction::Junction {
+                    function,
+                    operand: position,
+                    target,
+                } => {
+                    let operand = self.stack.last().expect("operand on self.stack");
+                    let step = eval::junction_step(function, operand, null_is_false)?;
+                    self.stack.pop();
+                    let result = self.stack.last_mut().expect("result on self.stack");
+                    match step {
+                        JunctionStep::Undecided => {}
+                        JunctionStep::Null if options.strict => {
+                            // Keep a result decided by an earlier operand
+                            if **result != eval::junction_decided(function) {
+                                *result = Cow::Owned(Value::Null);
+                            }
+                        }
+                        JunctionStep::Null => *result = Cow::Owned(Value::Null),
+                        JunctionStep::Decided => {
+                            *result = Cow::Owned(eval::junction_decided(function));
+                            if !options.strict {
+                                if let Some(metrics) = self.metrics {
+                                    metrics.short_circuit(function, position as usize + 1);
+                                }
+                                pc = target as usize;
+                            }
+                        }
+                    }
+                }
+                Instruction::Branch(target) => {
+                    let test = self.stack.pop().expect("test on self.stack");
+                    if !eval::branch(&test)? {
+                        pc = target as usize;
+                    }
+                }
+                Instruction::Jump(target) => pc = target as usize,
+                Instruction::Iterate {
+                    function,
+                    slot,
+                    end: body_end,
+                } => {
+                    let list = self.stack.pop().expect("list on stack");
+                    let mut result = self.iterate(function, &list, slot, pc, body_end as usize)?;
+                    if null_is_false && result.is_null() {
+                        result = Value::Bool(false);
+                    }
+                    self.stack.push(Cow::Owned(result));
+                    pc = body_end as usize;
+                }
+                Instruction::Recall { slot, target } => {
+                    let value = &self.memo[slot as usize];
+                    if let Some(metrics) = self.metrics {
+                        metrics.cache(Cache::Memo, value.is_some());
+                    }
+                    if let Some(value) = value {
+                        self.stack.push(Cow::Owned(value.clone()));
+                        pc = target as usize;
+                    }
+                }
+                Instruction::Remember(slot) => {
+                    let value = self.stack.last().expect("value on self.stack");
+                    self.memo[slot as usize] = Some(value.as_ref().clone());
+                }
+                Instruction::Fail(index) => return Err(compiled.errors[index as usize].clone()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the second argument of `any`/`all`/`filter`/`map` in
+    /// `start..end` once per element of `list`, with the element in local
+    /// `slot`
+    fn iterate(
+        &mut self,
+        function: BuiltinFunction,
+        list: &Value,
+        slot: u32,
+        start: usize,
+        end: usize,
+    ) -> Result<Value, EvalError> {
+        if list.is_null() {
+            return Ok(Value::Null);
+        }
+        let mut fold = ElementFold::new(function);
+        for (i, item) in eval::list_items(function, list)?.enumerate() {
+            self.locals[slot as usize] = item.clone();
+            self.exec(start, end)?;
+            let value = self.stack.pop().expect("element result on stack");
+            if fold.step(item, &value, self.options)? {
+                if let Some(metrics) = self.metrics {
+                    metrics.short_circuit(function, i + 1);
+                }
+                break;
+            }
+        }
+        Ok(fold.finish())
+    }
+}
+
+struct Compiler<'i, 'e> {
+    interner: &'i dyn Interner,
+    functions: &'i FunctionRegistry,
+    compiled: CompiledExpr,
+    /// Slot of each variable read
+    variable_slots: FxHashMap<StringId, u32>,
+    /// Regex table index of each literal pattern
+    regex_slots: FxHashMap<StringId, u32>,
+    /// Version table index of each literal operand, keyed like
+    /// `SemverCache`
+    version_slots: FxHashMap<(StringId, bool), u32>,
+    /// CIDR table index of each literal block
+    cidr_slots: FxHashMap<StringId, u32>,
+    /// Custom function table index of each called function name
+    function_slots: FxHashMap<StringId, u32>,
+    /// Cache slot of each repeated subexpression, assigned when it is
+    /// first compiled
+    memo_slots: FxHashMap<&'e Expr, Option<u32>>,
+    /// Locals of the enclosing `let`s and their slots, innermost last
+    scope: Vec<(StringId, u32)>,
+    /// Current stack depth at the instruction being emitted
+    depth: usize,
+}
+
+impl<'i, 'e> Compiler<'i, 'e> {
+    fn compile(&mut self, expr: &'e Expr) {
+        // A repeated subexpression that reads a local may differ per `let`
+        if self.memo_slots.contains_key(expr)
+            && !reads_any(expr, self.scope.iter().map(|&(name, _)| name))
+        {
+            self.compile_memoized(expr);
+        } else {
+            self.compile_uncached(expr);
+        }
+    }
+
+    /// Compile a repeated subexpression so its value is computed by the
+    /// first occurrence that is reached and recalled by the rest
+    fn compile_memoized(&mut self, expr: &'e Expr) {
+        let recall = self.compiled.code.len();
+        self.emit(Instruction::Recall { slot: 0, target: 0 });
+        self.compile_uncached(expr);
+        if let [Instruction::Const(_)] = self.compiled.code[recall + 1..] {
+            // Folded to a constant, which needs no cache
+            self.compiled.code.remove(recall);
+            return;
+        }
+        let memos = &mut self.compiled.memos;
+        let slot = *self
+            .memo_slots
+            .get_mut(expr)
+            .expect("repeated subexpression")
+            .get_or_insert_with(|| {
+                *memos += 1;
+                *memos as u32 - 1
+            });
+        self.emit(Instruction::Remember(slot));
+        let target = self.compiled.code.len() as u32;
+        self.compiled.code[recall] = Instruction::Recall { slot, target };
+    }
+
+    fn compile_uncached(&mut self, expr: &'e Expr) {
+        match expr {
+            Expr::Literal(value) => self.push_const(value.clone()),
+            Expr::Annotated { expr, .. } => self.compile(expr),
+            Expr::Variable(name) => {
+                match self.local_slot(*name) {
+                    Some(slot) => self.emit(Instruction::LoadLocal(slot)),
+                    None => {
+                        let slot = self.variable_slot(*name);
+                        self.emit(Instruction::Load(slot));
+                    }
+                }
+                self.grow(1);
+            }
+            Expr::Let { bindings, body } => {
+                let scope = self.scope.len();
+                for (name, value) in bindings {
+                    self.compile(value);
+                    let slot = self.compiled.locals as u32;
+                    self.compiled.locals += 1;
+                    self.emit(Instruction::Store(slot));
+                    self.depth -= 1;
+                    self.scope.push((*name, slot));
+                }
+                self.compile(body);
+                self.scope.truncate(scope);
+            }
+            Expr::List(items) => {
+                if let Some(list) = constant_list(items) {
+                    self.push_const(list);
+                    return;
+                }
+                for item in items {
+                    self.compile(item);
+                }
+                self.emit(Instruction::MakeList(items.len() as u32));
+                self.depth -= items.len();
+                self.grow(1);
+            }
+            Expr::Call { function, args } => {
+                let Some(builtin) = self.interner.builtin(*function) else {
+                    self.compile_custom_call(*function, args);
+                    return;
+                };
+                if let Err(error) = check_arity(builtin, args.len()) {
+                    self.fail(error);
+                    return;
+                }
+                match builtin {
+                    BuiltinFunction::And | BuiltinFunction::Or => {
+                        self.compile_junction(builtin, args)
+                    }
+                    BuiltinFunction::If => self.compile_if(args),
+                    BuiltinFunction::Quote => {
+                        self.push_const(Value::Quoted(Box::new(args[0].clone())))
+                    }
+                    // Compiled expressions only read the variables they were
+                    // compiled with, so they cannot evaluate quoted ones
+                    BuiltinFunction::Eval => self.fail(EvalError::EvalNotAllowed),
+                    BuiltinFunction::Any
+                    | BuiltinFunction::All
+                    | BuiltinFunction::Filter
+                    | BuiltinFunction::Map => self.compile_iteration(builtin, args),
+                    BuiltinFunction::Exists | BuiltinFunction::IsNull => {
+                        match &args[0] {
+                            Expr::Variable(name) => {
+                                match self.local_slot(*name) {
+                                    Some(slot) => self.emit(Instruction::LoadLocal(slot)),
+                                    None => {
+                                        let slot = self.variable_slot(*name);
+                                        self.emit(Instruction::LoadOrNull(slot));
+                                    }
+                                }
+                                self.grow(1);
+                            }
+                            arg => self.compile(arg),
+                        }
+                        self.emit(Instruction::Call(builtin, 1));
+                    }
+                    BuiltinFunction::MatchesRegex => match literal_pattern(&args[1]) {
+                        Some(pattern) => self.compile_regex_match(&args[0], pattern),
+                        None => self.compile_call(builtin, args),
+                    },
+                    BuiltinFunction::SemverEq
+                    | BuiltinFunction::SemverGt
+                    | BuiltinFunction::SemverLt
+                    | BuiltinFunction::SemverMatches => match literal_pattern(&args[1]) {
+                        Some(operand) => self.compile_version_match(builtin, &args[0], operand),
+                        None => self.compile_call(builtin, args),
+                    },
+                    BuiltinFunction::IpInCidr => match literal_pattern(&args[1]) {
+                        Some(block) => self.compile_cidr_match(&args[0], block),
+                        None => self.compile_call(builtin, args),
+                    },
+                    BuiltinFunction::In
+                    | BuiltinFunction::NotIn
+                    | BuiltinFunction::OneOf
+                    | BuiltinFunction::NoneOf
+                    | BuiltinFunction::GeoCellIn
+                    | BuiltinFunction::Intersection
+                    | BuiltinFunction::Difference
+                    | BuiltinFunction::IsSubset
+                    | BuiltinFunction::IsDisjoint => self.compile_member_call(builtin, args),
+                    _ => self.compile_call(builtin, args),
+                }
+            }
+        }
+    }
+
+    fn compile_call(&mut self, builtin: BuiltinFunction, args: &'e [Expr]) {
+        let start = self.compiled.code.len();
+        for arg in args {
+            self.compile(arg);
+        }
+        if !self.fold_call(builtin, start, args.len()) {
+            self.emit(Instruction::Call(builtin, args.len() as u32));
+            self.depth -= args.len();
+            self.grow(1);
+        }
+    }
+
+    /// Get the slot of the innermost local bound to `name`
+    fn local_slot(&self, name: StringId) -> Option<u32> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|&&(local, _)| local == name)
+            .map(|&(_, slot)| slot)
+    }
+
+    fn variable_slot(&mut self, name: StringId) -> u32 {
+        let variables = &mut self.compiled.variables;
+        *self.variable_slots.entry(name).or_insert_with(|| {
+            variables.push(name);
+            variables.len() as u32 - 1
+        })
+    }
+
+    /// Compile a call to a name that is not a builtin
+    fn compile_custom_call(&mut self, name: StringId, args: &'e [Expr]) {
+        let index = match self.function_slots.get(&name) {
+            Some(&index) => index,
+            None => {
+                let text = self.interner.resolve(name).unwrap_or_default();
+                let Some(custom) = self.functions.get(text) else {
+                    self.fail(EvalError::UnknownFunction(name));
+                    return;
+                };
+                let index = self.compiled.functions.len() as u32;
+                self.compiled.functions.push(Resolved {
+                    name,
+                    function: Arc::clone(custom),
+                    deterministic: self.functions.is_deterministic(text),
+                });
+                self.function_slots.insert(name, index);
+                index
+            }
+        };
+        for arg in args {
+            self.compile(arg);
+        }
+        self.emit(Instruction::CallCustom {
+            index,
+            argc: args.len() as u32,
+        });
+        self.depth -= args.len();
+        self.grow(1);
+    }
+
+    /// Compile `matches-regex` with a literal pattern, compiling the pattern
+    /// now. An invalid pattern fails once the matched value is evaluated
+    fn compile_regex_match(&mut self, value: &'e Expr, pattern: StringId) {
+        self.compile(value);
+        if let Some(&index) = self.regex_slots.get(&pattern) {
+            self.emit(Instruction::MatchRegex(index));
+            return;
+        }
+        let source = self.interner.resolve(pattern).unwrap_or_default();
+        match compile_regex(source) {
+            Ok(regex) => {
+                let index = self.compiled.regexes.len() as u32;
+                self.compiled.regexes.push(Pattern(regex));
+                self.regex_slots.insert(pattern, index);
+                self.emit(Instruction::MatchRegex(index));
+            }
+            Err(error) => {
+                self.depth -= 1;
+                self.fail(error);
+            }
+        }
+    }
+
+    /// Compile a `semver-*` call with a literal operand, parsing it now. An
+    /// invalid operand fails once the compared value is evaluated
+    fn compile_version_match(
+        &mut self,
+        function: BuiltinFunction,
+        value: &'e Expr,
+        operand: StringId,
+    ) {
+        self.compile(value);
+        let key = (operand, function == BuiltinFunction::SemverMatches);
+        if let Some(&index) = self.version_slots.get(&key) {
+            self.emit(Instruction::MatchVersion { function, index });
+            return;
+        }
+        let source = self.interner.resolve(operand).unwrap_or_default();
+        match Operand::parse(function, source) {
+            Ok(parsed) => {
+                let index = self.compiled.versions.len() as u32;
+                self.compiled.versions.push(parsed);
+                self.version_slots.insert(key, index);
+                self.emit(Instruction::MatchVersion { function, index });
+            }
+            Err(error) => {
+                self.depth -= 1;
+                self.fail(error);
+            }
+        }
+    }
+
+    /// Compile `ip-in-cidr` with a literal block, parsing it now. An
+    /// invalid block fails once the tested value is evaluated
+    fn compile_cidr_match(&mut self, value: &'e Expr, block: StringId) {
+        self.compile(value);
+        if let Some(&index) = self.cidr_slots.get(&block) {
+            self.emit(Instruction::MatchCidr(index));
+            return;
+        }
+        let source = self.interner.resolve(block).unwrap_or_default();
+        match Cidr::parse(source) {
+            Ok(cidr) => {
+                let index = self.compiled.cidrs.len() as u32;
+                self.compiled.cidrs.push(cidr);
+                self.cidr_slots.insert(block, index);
+                self.emit(Instruction::MatchCidr(index));
+            }
+            Err(error) => {
+                self.depth -= 1;
+                self.fail(error);
+            }
+        }
+    }
+
+    /// Compile a membership test, replacing a scan of a long literal list
+    /// with a lookup in a `MemberSet`
+    fn compile_member_call(&mut self, function: BuiltinFunction, args: &'e [Expr]) {
+        self.compile_call(function, args);
+        // A list that compiled to a constant is the last push before the
+        // call; anything else, including a folded call, is left as is
+        let [.., Instruction::Const(list), Instruction::Call(_, 2)] = self.compiled.code[..] else {
+            return;
+        };
+        let Some(set) = MemberSet::new(&self.compiled.constants[list as usize]) else {
+            return;
+        };
+        let len = self.compiled.code.len();
+        self.compiled.code.truncate(len - 2);
+        self.compiled.constants.truncate(list as usize);
+        let index = self.compiled.members.len() as u32;
+        self.compiled.members.push(set);
+        self.emit(Instruction::Member { function, index });
+    }
+
+    /// Compile `and`/`or` as a running result folded with each operand
+    fn compile_junction(&mut self, function: BuiltinFunction, args: &'e [Expr]) {
+        self.push_const(eval::junction_identity(function));
+
+        let mut jumps = Vec::with_capacity(args.len());
+        for (operand, arg) in args.iter().enumerate() {
+            self.compile(arg);
+            jumps.push(self.compiled.code.len());
+            self.emit(Instruction::Junction {
+                function,
+                operand: operand as u32,
+                target: 0,
+            });
+            self.depth -= 1;
+        }
+
+        let end = self.compiled.code.len() as u32;
+        for jump in jumps {
+            if let Instruction::Junction { target, .. } = &mut self.compiled.code[jump] {
+                *target = end;
+            }
+        }
+    }
+
+    /// Compile `if` as a chain of tests, each jumping over its value unless
+    /// it is true and each value jumping to the end
+    fn compile_if(&mut self, args: &'e [Expr]) {
+        let mut jumps = Vec::new();
+        let mut otherwise = None;
+        for clause in args.chunks(2) {
+            let [test, value] = clause else {
+                otherwise = Some(&clause[0]);
+                break;
+            };
+            let start = self.compiled.code.len();
+            self.compile(test);
+            if let [Instruction::Const(index)] = self.compiled.code[start..] {
+                // A constant test is decided now, unless it is a type error
+                if let Ok(taken) = eval::branch(&self.compiled.constants[index as usize]) {
+                    self.compiled.code.truncate(start);
+                    self.compiled.constants.truncate(index as usize);
+                    self.depth -= 1;
+                    if taken {
+                        otherwise = Some(value);
+                        break;
+                    }
+                    continue;
+                }
+            }
+            let branch = self.compiled.code.len();
+            self.emit(Instruction::Branch(0));
+            self.depth -= 1;
+            self.compile(value);
+            // Only the value of the clause taken stays on the stack
+            self.depth -= 1;
+            jumps.push(self.compiled.code.len());
+            self.emit(Instruction::Jump(0));
+            self.compiled.code[branch] = Instruction::Branch(self.compiled.code.len() as u32);
+        }
+        match otherwise {
+            Some(otherwise) => self.compile(otherwise),
+            None => self.push_const(Value::Null),
+        }
+
+        let end = self.compiled.code.len() as u32;
+        for jump in jumps {
+            self.compiled.code[jump] = Instruction::Jump(end);
+        }
+    }
+
+    /// Compile `any`/`all`/`filter`/`map` as a loop running the second
+    /// argument once per element, which it reads from a local slot as `_`
+    fn compile_iteration(&mut self, function: BuiltinFunction, args: &'e [Expr]) {
+        self.compile(&args[0]);
+        let slot = self.compiled.locals as u32;
+        self.compiled.locals += 1;
+        let iterate = self.compiled.code.len();
+        self.emit(Instruction::Iterate {
+            function,
+            slot,
+            end: 0,
+        });
+        let scope = self.scope.len();
+        if let Some(element) = self.interner.get_id(ELEMENT) {
+            self.scope.push((element, slot));
+        }
+        self.compile(&args[1]);
+        self.scope.truncate(scope);
+        // Each result for an element is consumed, and the list is replaced
+        // by the result
+        self.depth -= 1;
+        let end = self.compiled.code.len() as u32;
+        self.compiled.code[iterate] = Instruction::Iterate {
+            function,
+            slot,
+            end,
+        };
+    }
+
+    /// Evaluate a call at compile time if every argument compiled to a
+    /// single constant. Calls that fail are left for runtime so the error
+    /// is only reported if the call is actually reached
+    fn fold_call(&mut self, function: BuiltinFunction, start: usize, argc: usize) -> bool {
+        let code = &self.compiled.code[start..];
+        if code.len() != argc || !code.iter().all(|i| matches!(i, Instruction::Const(_))) {
+            return false;
+        }
+        let base = self.compiled.constants.len() - argc;
+        // Null results depend on the missing-variable policy, computed
+        // strings on the length limit, overflow on the overflow policy and
+        // distances on the distance formula, so all are left for runtime
+        if !eval::foldable(function) {
+            return false;
+        }
+        let Ok(value) = eval::apply(
+            function,
+            &self.compiled.constants[base..],
+            self.interner,
+            &EvalOptions::default(),
+        ) else {
+            return false;
+        };
+        if value.is_null() || value.is_text() {
+            return false;
+        }
+        self.compiled.code.truncate(start);
+        self.compiled.constants.truncate(base);
+        self.depth -= argc;
+        self.push_const(value);
+        true
+    }
+
+    fn push_const(&mut self, value: Value) {
+        let index = self.compiled.constants.len() as u32;
+        self.compiled.constants.push(value);
+        self.emit(Instruction::Const(index));
+        self.grow(1);
+    }
+
+    fn fail(&mut self, error: EvalError) {
+        let index = self.compiled.errors.len() as u32;
+        self.compiled.errors.push(error);
+        self.emit(Instruction::Fail(index));
+        // Keep depth accounting consistent for enclosing instructions
+        self.grow(1);
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.compiled.code.push(instruction);
+    }
+
+    fn grow(&mut self, n: usize) {
+        self.depth += n;
+        self.compiled.max_stack = self.compiled.max_stack.max(self.depth);
+    }
+}
+
+/// Nesting of an expression, counting the root as 1
+pub(crate) fn depth(expr: &Expr) -> usize {
+    match expr {
+        Expr::Call { args: children, .. } | Expr::List(children) => {
+            1 + children.iter().map(depth).max().unwrap_or(0)
+        }
+        Expr::Annotated { expr, .. } => depth(expr),
+        Expr::Let { bindings, body } => {
+            1 + bindings
+                .iter()
+                .map(|(_, value)| depth(value))
+                .chain([depth(body)])
+                .max()
+                .unwrap_or(0)
+        }
+        _ => 1,
+    }
+}
+
+/// Check if `expr` reads a variable named in `names`, not counting locals
+/// it binds itself
+fn reads_any(expr: &Expr, mut names: impl Iterator<Item = StringId>) -> bool {
+    let mut variables = None;
+    names.any(|name| {
+        variables
+            .get_or_insert_with(|| expr.variables())
+            .contains(&name)
+    })
+}
+
+/// Find call subtrees that appear more than once, so each can be evaluated
+/// once per evaluation
+///
+/// Only calls made entirely of builtins are cached, since custom functions
+/// may count or log their calls, and calls that read a local are not, since
+/// the same text may read different values under different `let`s.
+/// Occurrences inside a repeated subtree are not counted again, as the
+/// enclosing subtree is already evaluated once.
+fn repeated_calls<'e>(expr: &'e Expr, interner: &dyn Interner) -> FxHashMap<&'e Expr, Option<u32>> {
+    fn visit<'e>(
+        expr: &'e Expr,
+        interner: &dyn Interner,
+        counts: &mut FxHashMap<&'e Expr, u32>,
+        bound: &mut Vec<StringId>,
+    ) -> bool {
+        match expr {
+            Expr::Call { function, args } => {
+                if let Some(count) = counts.get_mut(expr) {
+                    if !reads_any(expr, bound.iter().copied()) {
+                        *count += 1;
+                        return true;
+                    }
+                }
+                let mut builtin = interner.builtin(*function).is_some();
+                for arg in args {
+                    builtin &= visit(arg, interner, counts, bound);
+                }
+                if builtin {
+                    counts.insert(expr, 1);
+                }
+                builtin
+            }
+            Expr::List(items) => {
+                let mut builtin = true;
+                for item in items {
+                    builtin &= visit(item, interner, counts, bound);
+                }
+                builtin
+            }
+            Expr::Annotated { expr, .. } => visit(expr, interner, counts, bound),
+            Expr::Let { bindings, body } => {
+                let scope = bound.len();
+                for (name, value) in bindings {
+                    visit(value, interner, counts, bound);
+                    bound.push(*name);
+                }
+                visit(body, interner, counts, bound);
+                bound.truncate(scope);
+                false
+            }
+            Expr::Variable(name) => !bound.contains(name),
+            Expr::Literal(_) => true,
+        }
+    }
+
+    let mut counts = FxHashMap::default();
+    visit(expr, interner, &mut counts, &mut Vec::new());
+    counts
+        .into_iter()
+        .filter(|&(_, count)| count > 1)
+        .map(|(expr, _)| (expr, None))
+        .collect()
+}
+
+/// Build a list literal made only of literals at compile time
+fn constant_list(items: &[Expr]) -> Option<Value> {
+    let values: Vec<&Value> = items
+        .iter()
+        .map(|item| match item {
+            Expr::Literal(value) => Some(value),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    Some(eval::make_list(&values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::MissingVariable;
+    use crate::{parse, Evaluator, StringInterner};
+    use alloc::format;
+    use alloc::string::{String, ToString};
+
+    fn env(interner: &mut StringInterner) -> Environment {
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        env.insert(
+            interner.intern("status"),
+            Value::Symbol(interner.intern("active")),
+        );
+        let tags = vec![interner.intern("sports"), interner.intern("news")];
+        env.insert(interner.intern("tags"), Value::StringList(tags.into()));
+        env
+    }
+
+    #[test]
+    fn matches_tree_walker() {
+        let mut interner = StringInterner::new();
+        let env = env(&mut interner);
+        let sources = [
+            r#"(and (= status "active") (>= age 18) (one-of tags ["news" "tech"]))"#,
+            r#"(or (< age 18) (not (in status ["active" "trial"])))"#,
+            "(and)",
+            "(or (> age 100) (= 1 1.0))",
+            "(in age [age 31])",
+            "(and (> age 1) missing)",
+            "(or true missing)",
+            "(and true 1)",
+            "(frobnicate age)",
+            "(geo_within_radius 0 0 0 0.001 200)",
+            "(geo_within_polygon age 0 [[0 -1] [50 -1] [50 1] [0 1]])",
+            "(geo_within_bbox age 0 0 -1 missing 1)",
+            "(and (exists age) (is-null missing) (not (exists nope)))",
+            "(or (> missing 1) (and true missing))",
+            "(not (in missing [1 2]))",
+            r#"(and (starts-with status "act") (= (uppercase status) "ACTIVE"))"#,
+            r#"(in (concat "spo" "rts") tags)"#,
+            "(string-length missing)",
+            r#"(or (matches-regex status "^act") (matches-regex missing "x"))"#,
+            r#"(matches-regex status (concat "v" "e$"))"#,
+            r#"(and (matches-regex age "[") true)"#,
+            r#"(matches-regex missing "[")"#,
+            r#"(or (semver-gt status "1.0.0") (semver-matches missing "^1"))"#,
+            r#"(and (semver-lt missing "x") true)"#,
+            r#"(or (ip-in-cidr "10.1.2.3" "10.0.0.0/8") (ip-in-cidr missing "::/0"))"#,
+            r#"(ip-in-cidr status "10.0.0.0/99")"#,
+            r#"(or (and (> age 100) (= (uppercase status) "X")) (= (uppercase status) "ACTIVE"))"#,
+            r#"(and (in (concat status "!") tags) (not (in (concat status "!") tags)))"#,
+            "(or (> (string-length missing) 1) (> (string-length missing) 1))",
+        ];
+
+        for source in sources {
+            let expr = parse(source, &mut interner).unwrap();
+            let compiled = compile(&expr, &interner);
+            let slots = compiled.bind(&env);
+            for missing in [
+                MissingVariable::Error,
+                MissingVariable::Null,
+                MissingVariable::False,
+            ] {
+                for strict in [false, true] {
+                    let options = EvalOptions {
+                        missing,
+                        strict,
+                        ..EvalOptions::default()
+                    };
+                    let expected = Evaluator::with_options(&interner, options).eval(&expr, &env);
+                    assert_eq!(
+                        compiled.eval_with(&env, &interner, &options),
+                        expected,
+                        "{}",
+                        source
+                    );
+                    assert_eq!(
+                        compiled.eval_slots(&slots, &interner, &options),
+                        expected,
+                        "{}",
+                        source
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn literal_lists_are_constants() {
+        let mut interner = StringInterner::new();
+        let expr = parse(r#"(in x ["a" "b" "c"])"#, &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+
+        assert_eq!(
+            compiled.instructions(),
+            &[
+                Instruction::Load(0),
+                Instruction::Const(0),
+                Instruction::Call(BuiltinFunction::In, 2),
+            ]
+        );
+        assert!(compiled.constants()[0].is_string_list());
+    }
+
+    #[test]
+    fn variables_have_slots() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(and (> age 18) (< age 65) (exists tier))", &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+        let (age, tier) = (interner.intern("age"), interner.intern("tier"));
+        assert_eq!(compiled.variables(), &[age, tier]);
+        assert_eq!(compiled.slot(tier), Some(1));
+        assert_eq!(compiled.slot(interner.intern("other")), None);
+
+        let options = EvalOptions::default();
+        let mut slots = compiled.slots();
+        assert_eq!(
+            compiled.eval_slots(&slots, &interner, &options),
+            Err(EvalError::UnknownVariable(age))
+        );
+        slots.set(0, Value::Integer(30));
+        assert_eq!(
+            compiled.eval_slots(&slots, &interner, &options),
+            Ok(Value::Bool(false))
+        );
+        slots.set(1, Value::Symbol(interner.intern("gold")));
+        assert_eq!(
+            compiled.eval_slots(&slots, &interner, &options),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn constant_calls_are_folded() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(and (> 3 2) (in 2 [1 2]) flag)", &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+
+        // The first constant is the identity `and` starts from
+        assert_eq!(compiled.constants(), vec![Value::Bool(true); 3]);
+        assert_eq!(compiled.instructions()[1], Instruction::Const(1));
+
+        // Failing calls stay in the code so they only error when reached
+        let expr = parse("(or true (< \"a\" 1))", &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+        assert_eq!(
+            compiled.eval(&Environment::new(), &interner),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn regex_patterns_are_compiled_once() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(or (matches-regex a "^x+$") (matches-regex b "^x+$") (matches-regex b "y"))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let compiled = compile(&expr, &interner);
+        assert_eq!(compiled.regexes.len(), 2);
+
+        let mut env = Environment::new();
+        env.insert(
+            interner.get_id("a").unwrap(),
+            Value::Symbol(interner.intern("z")),
+        );
+        env.insert(interner.get_id("b").unwrap(), Value::Text("xxx".into()));
+        assert_eq!(compiled.eval(&env, &interner), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn long_lists_are_member_sets() {
+        let mut interner = StringInterner::new();
+        let env = env(&mut interner);
+        let numbers: Vec<String> = (0..40).map(|n| (n * 37 % 101).to_string()).collect();
+        let numbers = numbers.join(" ");
+        let words: Vec<String> = (0..40).map(|n| format!(r#""w{}""#, n)).collect();
+        let words = format!(r#"{} "news""#, words.join(" "));
+        let sources = [
+            format!("(in age [{}])", numbers),
+            format!("(not-in age [{}])", numbers),
+            format!("(in 30.0 [{} age])", numbers),
+            format!("(in missing [{}])", numbers),
+            format!("(one-of tags [{}])", words),
+            format!("(none-of tags [{}])", words),
+            format!("(in status [{}])", words),
+            format!("(one-of [age 74] [{}])", numbers),
+        ];
+
+        for source in &sources {
+            let expr = parse(source, &mut interner).unwrap();
+            let compiled = compile(&expr, &interner);
+            for missing in [MissingVariable::Error, MissingVariable::False] {
+                let options = EvalOptions {
+                    missing,
+                    ..EvalOptions::default()
+                };
+                let expected = Evaluator::with_options(&interner, options).eval(&expr, &env);
+                assert_eq!(
+                    compiled.eval_with(&env, &interner, &options),
+                    expected,
+                    "{}",
+                    source
+                );
+            }
+        }
+
+        let expr = parse(&sources[0], &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+        assert_eq!(
+            compiled.instructions(),
+            &[
+                Instruction::Load(0),
+                Instruction::Member {
+                    function: BuiltinFunction::In,
+                    index: 0,
+                },
+            ]
+        );
+        assert!(compiled.constants().is_empty());
+    }
+
+    #[test]
+    fn decimal_members() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let x = interner.intern("x");
+        let long: Vec<String> = (1..=17).map(|n| n.to_string()).collect();
+        let long = long.join(" ");
+        let sources = [
+            "(in x [1 2 3])".to_string(),
+            "(not-in x [1 2 3])".to_string(),
+            "(in x [1 2 3.5d])".to_string(),
+            format!("(in x [{}])", long),
+            format!("(not-in x [{}])", long),
+            format!("(one-of [x] [{}])", long),
+            format!("(none-of [x] [{}])", long),
+        ];
+
+        for (decimal, member) in [("3", true), ("3.00", true), ("3.5", false)] {
+            env.insert(x, Value::Decimal(decimal.parse().unwrap()));
+            for source in &sources {
+                let expr = parse(source, &mut interner).unwrap();
+                let expected = Evaluator::new(&interner).eval(&expr, &env);
+                assert_eq!(
+                    compile(&expr, &interner).eval(&env, &interner),
+                    expected,
+                    "{} with x = {}",
+                    source,
+                    decimal
+                );
+            }
+            let expr = parse(&sources[0], &mut interner).unwrap();
+            assert_eq!(
+                Evaluator::new(&interner).eval(&expr, &env),
+                Ok(Value::Bool(member))
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_calls_are_evaluated_once() {
+        let mut interner = StringInterner::new();
+        let audience = r#"(and (= (lowercase country) "us") (one-of tags ["news" "tech"]))"#;
+        let source = format!(
+            "(or (and {0} (> age 65)) (and {0} (< age 18)) (and (> 2 1) (> 2 1)))",
+            audience
+        );
+        let expr = parse(&source, &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+
+        // Only the outermost repeat is cached, and the folded constant
+        // comparison needs no cache
+        assert_eq!(compiled.memos, 1);
+        let remembered = compiled
+            .instructions()
+            .iter()
+            .filter(|i| matches!(i, Instruction::Remember(0)))
+            .count();
+        assert_eq!(remembered, 2);
+
+        let mut env = env(&mut interner);
+        env.insert(
+            interner.intern("country"),
+            Value::String(interner.intern("US")),
+        );
+        for age in [10, 30, 70] {
+            env.insert(interner.intern("age"), Value::Integer(age));
+            assert_eq!(
+                compiled.eval(&env, &interner),
+                Evaluator::new(&interner).eval(&expr, &env)
+            );
+        }
+    }
+
+    #[test]
+    fn custom_functions_are_resolved_at_compile_time() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("double", |args: &[Value]| match args {
+            [Value::Integer(n)] => Ok(Value::Integer(n * 2)),
+            _ => Ok(Value::Null),
+        });
+        let mut interner = StringInterner::new();
+        let expr = parse("(= (double (double age)) 120)", &mut interner).unwrap();
+        let compiled = compile_with(&expr, &interner, &registry);
+        assert_eq!(compiled.functions.len(), 1);
+
+        // Replacing the function afterwards does not change the compiled code
+        registry.register("double", |_| Ok(Value::Integer(0)));
+        let env = env(&mut interner);
+        assert_eq!(compiled.eval(&env, &interner), Ok(Value::Bool(true)));
+
+        let missing = compile(&expr, &interner);
+        assert_eq!(
+            missing.eval(&env, &interner),
+            Err(EvalError::UnknownFunction(
+                interner.get_id("double").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn short_circuit_jumps() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(or a b)", &mut interner).unwrap();
+        let compiled = compile(&expr, &interner);
+
+        assert_eq!(
+            compiled.instructions()[2],
+            Instruction::Junction {
+                function: BuiltinFunction::Or,
+                operand: 0,
+                target: 5,
+            }
+        );
+
+        let mut env = Environment::new();
+        env.insert(interner.get_id("a").unwrap(), Value::Bool(true));
+        assert_eq!(compiled.eval(&env, &interner), Ok(Value::Bool(true)));
+    }
+}
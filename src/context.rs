@@ -1,14 +1,63 @@
+//! Evaluation contexts with structured attributes
+//!
+//! A `Context` owns an interner together with the attributes of one event,
+//! so rules can be parsed and evaluated against it directly. Attributes may
+//! be nested: `user.device.os` names the `os` attribute of the `device`
+//! attribute of `user`, and rules refer to it with the same dotted path as
+//! a plain variable:
+//!
+//! ```
+//! use ironwood::{Context, Value};
+//!
+//! let mut context = Context::new();
+//! context
+//!     .scope("user")
+//!     .set("age", Value::Integer(30))
+//!     .scope("device")
+//!     .set_str("os", "ios");
+//! let rule = context.parse(r#"(and (>= user.age 21) (= user.device.os "ios"))"#).unwrap();
+//! assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+//! ```
+//!
+//! Attributes are stored flattened under their full dotted path, so
+//! looking one up costs the same as any other variable and compiled rules
+//! need no changes. Only leaves are bound: `user.device` on its own is an
+//! unknown variable.
+
+use crate::convert::IntoValue;
 use crate::intern::{StringId, StringInterner};
+use crate::{parse, Environment, EvalError, Evaluator, Expr, IronwoodError, Value};
+use alloc::format;
+use alloc::string::{String, ToString};
+
+/// Separator between the segments of an attribute path
+pub const PATH_SEPARATOR: char = '.';
 
+/// Interner and attributes to parse and evaluate rules against
 #[derive(Debug)]
 pub struct Context {
     interner: StringInterner,
+    attributes: Environment,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
+    /// Create an empty context, its interner holding the builtin names at
+    /// their fixed IDs
     pub fn new() -> Self {
+        Self::with_interner(StringInterner::with_builtins())
+    }
+
+    /// Create a context that interns into an existing interner
+    pub fn with_interner(interner: StringInterner) -> Self {
         Self {
-            interner: StringInterner::new(),
+            interner,
+            attributes: Environment::new(),
         }
     }
 
@@ -19,10 +68,185 @@ impl Context {
     pub fn resolve(&self, id: StringId) -> Option<&str> {
         self.interner.resolve(id)
     }
+
+    /// Get the interner
+    pub fn interner(&self) -> &StringInterner {
+        &self.interner
+    }
+
+    /// Get the attributes as an environment keyed by dotted path
+    pub fn environment(&self) -> &Environment {
+        &self.attributes
+    }
+
+    /// Set the attribute at `path`, returning the previous value if any
+    pub fn set(&mut self, path: &str, value: Value) -> Option<Value> {
+        let id = self.interner.intern(path);
+        self.attributes.insert(id, value)
+    }
+
+    /// Set the attribute at `path` to an interned string
+    pub fn set_str(&mut self, path: &str, value: &str) -> Option<Value> {
+        let value = Value::String(self.interner.intern(value));
+        self.set(path, value)
+    }
+
+    /// Borrow the interner and attributes together, to fill in both at once
+    #[cfg(feature = "json")]
+    pub(crate) fn parts_mut(&mut self) -> (&mut StringInterner, &mut Environment) {
+        (&mut self.interner, &mut self.attributes)
+    }
+
+    /// Convert a native value and set it at `path`
+    ///
+    /// Maps, such as those of structs deriving `IntoValue`, are spread into
+    /// one attribute per entry under `path`, so a profile set at `user`
+    /// gives `user.age` and `user.country`. An empty `path` sets the
+    /// entries at the top level.
+    pub fn set_value(&mut self, path: &str, value: impl IntoValue) {
+        let value = value.into_value(&mut self.interner);
+        self.spread(path, value);
+    }
+
+    fn spread(&mut self, path: &str, value: Value) {
+        let Value::Map(entries) = value else {
+            self.set(path, value);
+            return;
+        };
+        for (key, value) in entries {
+            let key = self.interner.resolve(key).unwrap_or_default();
+            let name = if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}{}{}", path, PATH_SEPARATOR, key)
+            };
+            self.spread(&name, value);
+        }
+    }
+
+    /// Get the attribute at `path`
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        self.attributes.get(self.interner.get_id(path)?)
+    }
+
+    /// Remove the attribute at `path`
+    pub fn remove(&mut self, path: &str) -> Option<Value> {
+        self.attributes.remove(self.interner.get_id(path)?)
+    }
+
+    /// Get a handle that sets attributes nested under `prefix`
+    pub fn scope(&mut self, prefix: &str) -> Scope<'_> {
+        Scope {
+            context: self,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Remove every attribute, keeping the interner so parsed rules stay
+    /// valid for the next event
+    pub fn clear(&mut self) {
+        self.attributes = Environment::new();
+    }
+
+    /// Parse a rule, interning its names into this context
+    pub fn parse(&mut self, source: &str) -> Result<Expr, IronwoodError> {
+        parse(source, &mut self.interner)
+    }
+
+    /// Evaluate a rule against the attributes
+    pub fn eval(&self, expr: &Expr) -> Result<Value, EvalError> {
+        Evaluator::new(&self.interner).eval(expr, &self.attributes)
+    }
 }
 
-impl Default for Context {
-    fn default() -> Self {
-        Self::new()
+/// Handle for setting the attributes under one path prefix
+///
+/// Returned by `Context::scope`. Scopes nest, so a whole object can be
+/// filled in without spelling out every dotted path.
+#[derive(Debug)]
+pub struct Scope<'c> {
+    context: &'c mut Context,
+    prefix: String,
+}
+
+impl Scope<'_> {
+    /// Set the attribute `name` under this scope
+    pub fn set(&mut self, name: &str, value: Value) -> &mut Self {
+        let path = self.path(name);
+        self.context.set(&path, value);
+        self
+    }
+
+    /// Set the attribute `name` under this scope to an interned string
+    pub fn set_str(&mut self, name: &str, value: &str) -> &mut Self {
+        let path = self.path(name);
+        self.context.set_str(&path, value);
+        self
     }
-}
\ No newline at end of file
+
+    /// Get a handle for the attributes nested under `name`
+    pub fn scope(&mut self, name: &str) -> Scope<'_> {
+        let prefix = self.path(name);
+        Scope {
+            context: self.context,
+            prefix,
+        }
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}{}{}", self.prefix, PATH_SEPARATOR, name)
+    }
+}
+
+/// Split a dotted attribute path into its segments
+pub fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split(PATH_SEPARATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn nested_attributes() {
+        let mut context = Context::new();
+        context.set("country", Value::Bool(true));
+        {
+            let mut user = context.scope("user");
+            user.set("age", Value::Integer(30));
+            user.scope("device")
+                .set_str("os", "ios")
+                .set("version", Value::Integer(17));
+        }
+        assert_eq!(
+            context.get("user.device.version"),
+            Some(&Value::Integer(17))
+        );
+        assert_eq!(context.get("user.device"), None);
+
+        let rule = context
+            .parse(r#"(and (= user.device.os "ios") (>= user.device.version 16))"#)
+            .unwrap();
+        assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+        assert_eq!(
+            compile(&rule, context.interner()).eval(context.environment(), context.interner()),
+            Ok(Value::Bool(true))
+        );
+
+        let device = context.parse("user.device").unwrap();
+        assert!(matches!(
+            context.eval(&device),
+            Err(EvalError::UnknownVariable(_))
+        ));
+
+        context.clear();
+        assert!(context.environment().is_empty());
+        assert!(context.eval(&rule).is_err());
+        assert_eq!(
+            path_segments("user.device.os").collect::<Vec<_>>(),
+            ["user", "device", "os"]
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! Conversions between values and native Rust types
+//!
+//! Numbers and booleans convert with `From` and `TryFrom`. Types holding
+//! strings need an interner, so the `IntoValue` and `FromValue` traits
+//! take one: strings become interned `Value::String`s and structs become
+//! `Value::Map`s keyed by their field names. With the `derive` feature,
+//! `#[derive(IntoValue, FromValue)]` implements them for structs with named
+//! fields, and `Context::set_value` spreads a struct into dotted
+//! attributes:
+//!
+//! ```
+//! # #[cfg(feature = "derive")] {
+//! use ironwood::{Context, IntoValue, Value};
+//!
+//! #[derive(IntoValue)]
+//! struct UserProfile {
+//!     age: i64,
+//!     country: String,
+//!     tags: Vec<String>,
+//! }
+//!
+//! let profile = UserProfile {
+//!     age: 30,
+//!     country: "CA".to_string(),
+//!     tags: vec!["news".to_string()],
+//! };
+//! let mut context = Context::new();
+//! context.set_value("user", profile);
+//! let rule = context.parse(r#"(and (>= user.age 21) (= user.country "CA"))"#).unwrap();
+//! assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+//! # }
+//! ```
+//!
+//! Lists convert through the same rules as list literals, so a `Vec<String>`
+//! becomes a `StringList` and a `Vec<i64>` an `IntegerList`. `None` becomes
+//! `Null`, and a missing struct field reads as `Null`, so `Option` fields
+//! may be left out.
+
+use crate::compat::FxHashMap;
+use crate::eval::make_list;
+use crate::{BloomSet, Decimal, StringId, StringInterner, Value, ValueType};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+/// Entries of a `Value::Map`
+pub type ValueMap = FxHashMap<StringId, Value>;
+
+/// Convert a native value into a `Value`, interning its strings
+pub trait IntoValue {
+    /// Convert into a value whose strings are interned in `interner`
+    fn into_value(self, interner: &mut StringInterner) -> Value;
+}
+
+/// Read a native value from a `Value`
+pub trait FromValue: Sized {
+    /// Read from a value whose strings are interned in `interner`
+    fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError>;
+}
+
+/// Error converting a value to a native type
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConvertError {
+    /// Value has a type the target cannot be read from
+    TypeMismatch {
+        expected: ValueType,
+        found: ValueType,
+    },
+    /// Integer does not fit the target type
+    OutOfRange(i64),
+    /// String ID is not in the interner
+    UnknownString(StringId),
+    /// Struct field is missing from a map
+    MissingField(&'static str),
+    /// Struct field could not be read
+    Field {
+        name: &'static str,
+        error: Box<ConvertError>,
+    },
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::TypeMismatch { expected, found } => {
+                write!(f, "expected {:?}, found {:?}", expected, found)
+            }
+            ConvertError::OutOfRange(n) => write!(f, "{} is out of range", n),
+            ConvertError::UnknownString(id) => write!(f, "unknown string #{}", id.raw()),
+            ConvertError::MissingField(name) => write!(f, "missing field `{}`", name),
+            ConvertError::Field { name, error } => write!(f, "field `{}`: {}", name, error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConvertError {}
+
+/// Borrow the entries of a map value, as derived `FromValue` impls do
+pub fn map(value: &Value) -> Result<&ValueMap, ConvertError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        other => Err(mismatch(ValueType::Map, other)),
+    }
+}
+
+/// Read the struct field `name` from the entries of a map value, as
+/// derived `FromValue` impls do
+///
+/// A missing field reads as `Null`, so it is only an error if the field
+/// type cannot be read from `Null`.
+pub fn field<T: FromValue>(
+    map: &ValueMap,
+    name: &'static str,
+    interner: &StringInterner,
+) -> Result<T, ConvertError> {
+    let Some(value) = interner.get_id(name).and_then(|key| map.get(&key)) else {
+        return T::from_value(&Value::Null, interner).map_err(|_| ConvertError::MissingField(name));
+    };
+    T::from_value(value, interner).map_err(|error| ConvertError::Field {
+        name,
+        error: Box::new(error),
+    })
+}
+
+fn mismatch(expected: ValueType, found: &Value) -> ConvertError {
+    ConvertError::TypeMismatch {
+        expected,
+        found: found.value_type(),
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Integer(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+impl From<Decimal> for Value {
+    fn from(d: Decimal) -> Self {
+        Value::Decimal(d)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = ConvertError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        value
+            .as_integer()
+            .ok_or_else(|| mismatch(ValueType::Integer, &value))
+    }
+}
+
+/// Integers are read as floats too, as the numeric builtins compare them
+impl TryFrom<Value> for f64 {
+    type Error = ConvertError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        f64::from_value(&value, &StringInterner::new())
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConvertError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        value
+            .as_bool()
+            .ok_or_else(|| mismatch(ValueType::Bool, &value))
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        value
+            .as_bool()
+            .ok_or_else(|| mismatch(ValueType::Bool, value))
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::Integer(self)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        value
+            .as_integer()
+            .ok_or_else(|| mismatch(ValueType::Integer, value))
+    }
+}
+
+/// Implement the traits for integer types narrower than `i64`
+macro_rules! narrow_integers {
+    ($($ty:ty),*) => {$(
+        impl IntoValue for $ty {
+            fn into_value(self, _: &mut StringInterner) -> Value {
+                Value::Integer(i64::from(self))
+            }
+        }
+
+        impl FromValue for $ty {
+            fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError> {
+                let n = i64::from_value(value, interner)?;
+                <$ty>::try_from(n).map_err(|_| ConvertError::OutOfRange(n))
+            }
+        }
+    )*};
+}
+
+narrow_integers!(i8, i16, i32, u8, u16, u32);
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::Float(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        match value {
+            Value::Float(x) => Ok(*x),
+            Value::Integer(n) => Ok(*n as f64),
+            other => Err(mismatch(ValueType::Float, other)),
+        }
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::Float(f64::from(self))
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError> {
+        f64::from_value(value, interner).map(|x| x as f32)
+    }
+}
+
+impl IntoValue for Decimal {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::Decimal(self)
+    }
+}
+
+impl FromValue for Decimal {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        match value {
+            Value::Decimal(d) => Ok(*d),
+            Value::Integer(n) => Ok(Decimal::from(*n)),
+            other => Err(mismatch(ValueType::Decimal, other)),
+        }
+    }
+}
+
+impl IntoValue for RangeInclusive<i64> {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::IntRange(*self.start(), *self.end())
+    }
+}
+
+impl FromValue for RangeInclusive<i64> {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        let (start, end) = value
+            .as_int_range()
+            .ok_or_else(|| mismatch(ValueType::IntRange, value))?;
+        Ok(start..=end)
+    }
+}
+
+impl IntoValue for BloomSet {
+    fn into_value(self, _: &mut StringInterner) -> Value {
+        Value::BloomSet(self)
+    }
+}
+
+impl FromValue for BloomSet {
+    fn from_value(value: &Value, _: &StringInterner) -> Result<Self, ConvertError> {
+        value
+            .as_bloom_set()
+            .cloned()
+            .ok_or_else(|| mismatch(ValueType::BloomSet, value))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, interner: &mut StringInterner) -> Value {
+        Value::String(interner.intern(self))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, interner: &mut StringInterner) -> Value {
+        self.as_str().into_value(interner)
+    }
+}
+
+/// Strings are read from symbols and computed text as well
+impl FromValue for String {
+    fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError> {
+        match value {
+            Value::String(id) | Value::Symbol(id) => interner
+                .resolve(*id)
+                .map(String::from)
+                .ok_or(ConvertError::UnknownString(*id)),
+            Value::Text(text) => Ok(String::from(&**text)),
+            other => Err(mismatch(ValueType::String, other)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, interner: &mut StringInterner) -> Value {
+        self.map_or(Value::Null, |value| value.into_value(interner))
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value, interner).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, interner: &mut StringInterner) -> Value {
+        let values: Vec<Value> = self
+            .into_iter()
+            .map(|item| item.into_value(interner))
+            .collect();
+        make_list(&values)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value, interner: &StringInterner) -> Result<Self, ConvertError> {
+        match value {
+            Value::StringList(ids) => ids
+                .iter()
+                .map(|&id| T::from_value(&Value::String(id), interner))
+                .collect(),
+            Value::IntegerList(ns) => ns
+                .iter()
+                .map(|&n| T::from_value(&Value::Integer(n), interner))
+                .collect(),
+            Value::List(items) => items
+                .iter()
+                .map(|item| T::from_value(item, interner))
+                .collect(),
+            other => Err(mismatch(ValueType::List, other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use alloc::vec;
+
+    #[test]
+    fn native_values() {
+        assert_eq!(Value::from(7), Value::Integer(7));
+        assert_eq!(Value::from(0.5), Value::Float(0.5));
+        assert_eq!(Value::from(true), Value::Bool(true));
+        assert_eq!(i64::try_from(Value::Integer(7)), Ok(7));
+        assert_eq!(f64::try_from(Value::Integer(2)), Ok(2.0));
+        assert_eq!(bool::try_from(Value::Bool(false)), Ok(false));
+        assert_eq!(
+            i64::try_from(Value::Bool(true)),
+            Err(ConvertError::TypeMismatch {
+                expected: ValueType::Integer,
+                found: ValueType::Bool,
+            })
+        );
+
+        let mut interner = StringInterner::new();
+        let tags = vec!["news", "tech"].into_value(&mut interner);
+        assert!(tags.is_string_list());
+        assert_eq!(
+            Vec::<String>::from_value(&tags, &interner).unwrap(),
+            ["news", "tech"]
+        );
+        let scores = vec![Some(1), None].into_value(&mut interner);
+        assert_eq!(scores, Value::List(vec![Value::Integer(1), Value::Null]));
+        assert_eq!(
+            Vec::<Option<u8>>::from_value(&scores, &interner),
+            Ok(vec![Some(1), None])
+        );
+        assert_eq!(
+            u8::from_value(&Value::Integer(300), &interner),
+            Err(ConvertError::OutOfRange(300))
+        );
+    }
+
+    #[test]
+    fn struct_fields() {
+        let mut context = Context::new();
+        let mut profile = ValueMap::default();
+        profile.insert(context.intern("age"), Value::Integer(30));
+        profile.insert(context.intern("nickname"), Value::Null);
+        let country = Value::String(context.intern("CA"));
+        profile.insert(context.intern("country"), country);
+        let profile = Value::Map(profile);
+
+        context.set_value("user", profile.clone());
+        let rule = context
+            .parse(r#"(and (>= user.age 21) (= user.country "CA"))"#)
+            .unwrap();
+        assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+
+        let map = map(&profile).unwrap();
+        let interner = context.interner();
+        assert_eq!(field::<i64>(map, "age", interner), Ok(30));
+        assert_eq!(field::<Option<String>>(map, "nickname", interner), Ok(None));
+        assert_eq!(field::<Option<String>>(map, "email", interner), Ok(None));
+        assert_eq!(
+            field::<i64>(map, "email", interner),
+            Err(ConvertError::MissingField("email"))
+        );
+        assert!(matches!(
+            field::<bool>(map, "age", interner),
+            Err(ConvertError::Field { name: "age", .. })
+        ));
+    }
+
+    #[cfg(feature = "derive")]
+    mod derived {
+        use super::*;
+        use crate::{FromValue, IntoValue};
+
+        #[derive(Debug, PartialEq, IntoValue, FromValue)]
+        struct UserProfile {
+            age: i64,
+            country: String,
+            tags: Vec<String>,
+            r#type: Option<String>,
+            score: f64,
+        }
+
+        #[test]
+        fn derived_conversions() {
+            let profile = UserProfile {
+                age: 30,
+                country: String::from("CA"),
+                tags: vec![String::from("news")],
+                r#type: None,
+                score: 0.5,
+            };
+            let mut context = Context::new();
+            let mut interner = StringInterner::new();
+            let value = profile.into_value(&mut interner);
+            assert_eq!(
+                UserProfile::from_value(&value, &interner),
+                Ok(UserProfile {
+                    age: 30,
+                    country: String::from("CA"),
+                    tags: vec![String::from("news")],
+                    r#type: None,
+                    score: 0.5,
+                })
+            );
+            assert!(interner.contains("type"));
+
+            context.set_value(
+                "",
+                UserProfile {
+                    age: 17,
+                    country: String::from("US"),
+                    tags: Vec::new(),
+                    r#type: Some(String::from("guest")),
+                    score: 1.0,
+                },
+            );
+            let rule = context
+                .parse(r#"(or (>= age 21) (= type "guest"))"#)
+                .unwrap();
+            assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+        }
+    }
+}
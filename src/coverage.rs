@@ -0,0 +1,264 @@
+//! Coverage of a rule set over enumerated dimensions
+//!
+//! `RuleSet::coverage` takes variables whose values a `Schema` enumerates,
+//! such as `country` or `device`, and works out for every combination of
+//! their values which rules match. Each rule is partially evaluated with
+//! the combination bound, so no environments are simulated: a rule that
+//! reduces to `true` matches the combination, one that reduces to
+//! anything else or to a residual that `satisfiability` shows can never
+//! hold does not, and any other residual may match depending on the
+//! variables left unbound.
+//!
+//! From the combinations, `Coverage::gaps` finds those no rule can match
+//! and `Coverage::overlaps` the pairs of rules that can match the same
+//! combination, to spot audience gaps and overlap between campaigns. The
+//! work grows with the product of the domain sizes, so it is capped at
+//! `MAX_CELLS` combinations.
+
+use crate::analysis::{satisfiability, Satisfiability};
+use crate::compat::FxHashSet;
+use crate::{Expr, PartialEnv, RuleId, RuleSet, Schema, StringId, StringInterner, Value};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest number of combinations `RuleSet::coverage` enumerates
+pub const MAX_CELLS: usize = 1 << 16;
+
+/// Reasons coverage cannot be computed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoverageError {
+    /// Dimension has no domain in the schema
+    NoDomain(StringId),
+    /// Dimensions have more than `MAX_CELLS` combinations
+    TooManyCells,
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageError::NoDomain(name) => {
+                write!(f, "no domain declared for #{}", name.raw())
+            }
+            CoverageError::TooManyCells => {
+                write!(f, "dimensions have more than {} combinations", MAX_CELLS)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CoverageError {}
+
+/// One combination of dimension values and the rules matching it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageCell {
+    values: Vec<Value>,
+    matched: Vec<RuleId>,
+    possible: Vec<RuleId>,
+}
+
+impl CoverageCell {
+    /// Get the value of each dimension, in the order they were given
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// Get the rules that match whatever the other variables are, in
+    /// insertion order
+    pub fn matched(&self) -> &[RuleId] {
+        &self.matched
+    }
+
+    /// Get the rules that match depending on the other variables, in
+    /// insertion order
+    pub fn possible(&self) -> &[RuleId] {
+        &self.possible
+    }
+
+    /// Check if no rule can match
+    pub fn is_gap(&self) -> bool {
+        self.matched.is_empty() && self.possible.is_empty()
+    }
+
+    /// Iterate over the rules that can match, those that match whatever
+    /// the other variables are first
+    pub fn rules(&self) -> impl Iterator<Item = RuleId> + '_ {
+        self.matched.iter().chain(&self.possible).copied()
+    }
+}
+
+/// Rules matching each combination of dimension values, returned by
+/// `RuleSet::coverage`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    dimensions: Vec<StringId>,
+    cells: Vec<CoverageCell>,
+}
+
+impl Coverage {
+    /// Get the dimensions, in the order they were given
+    pub fn dimensions(&self) -> &[StringId] {
+        &self.dimensions
+    }
+
+    /// Get every combination, varying the last dimension fastest
+    pub fn cells(&self) -> &[CoverageCell] {
+        &self.cells
+    }
+
+    /// Iterate over the combinations no rule can match
+    pub fn gaps(&self) -> impl Iterator<Item = &CoverageCell> {
+        self.cells.iter().filter(|cell| cell.is_gap())
+    }
+
+    /// Get the pairs of rules that can match the same combination, in the
+    /// order they are first found
+    pub fn overlaps(&self) -> Vec<(RuleId, RuleId)> {
+        let mut seen = FxHashSet::default();
+        let mut pairs = Vec::new();
+        for cell in &self.cells {
+            let rules: Vec<RuleId> = cell.rules().collect();
+            for (i, &first) in rules.iter().enumerate() {
+                for &second in &rules[i + 1..] {
+                    let pair = (first.min(second), first.max(second));
+                    if seen.insert(pair) {
+                        pairs.push((first, second));
+                    }
+                }
+            }
+        }
+        pairs
+    }
+}
+
+impl RuleSet {
+    /// Work out which rules match each combination of values of
+    /// `dimensions`, whose domains `schema` enumerates
+    pub fn coverage(
+        &self,
+        schema: &Schema,
+        dimensions: &[StringId],
+    ) -> Result<Coverage, CoverageError> {
+        let domains = dimensions
+            .iter()
+            .map(|&name| schema.domain(name).ok_or(CoverageError::NoDomain(name)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let size = domains
+            .iter()
+            .try_fold(1usize, |size, domain| size.checked_mul(domain.len()))
+            .filter(|&size| size <= MAX_CELLS)
+            .ok_or(CoverageError::TooManyCells)?;
+
+        let interner = self.interner();
+        let mut cells = Vec::with_capacity(size);
+        let mut position = vec![0; domains.len()];
+        for _ in 0..size {
+            let values: Vec<Value> = position
+                .iter()
+                .zip(&domains)
+                .map(|(&i, domain)| domain[i].clone())
+                .collect();
+            let mut env = PartialEnv::new(interner);
+            for (&name, value) in dimensions.iter().zip(&values) {
+                env.bind(name, value.clone());
+            }
+            let mut cell = CoverageCell {
+                values,
+                matched: Vec::new(),
+                possible: Vec::new(),
+            };
+            for (id, expr) in self.rules() {
+                match decide(&expr.partial_eval(&env), interner) {
+                    Some(true) => cell.matched.push(id),
+                    Some(false) => {}
+                    None => cell.possible.push(id),
+                }
+            }
+            cells.push(cell);
+
+            // Advance to the next combination, the last dimension fastest
+            for (i, domain) in domains.iter().enumerate().rev() {
+                position[i] += 1;
+                if position[i] < domain.len() {
+                    break;
+                }
+                position[i] = 0;
+            }
+        }
+        Ok(Coverage {
+            dimensions: dimensions.to_vec(),
+            cells,
+        })
+    }
+}
+
+/// Whether a partially evaluated rule matches, `None` if that depends on
+/// the variables left unbound
+fn decide(residual: &Expr, interner: &StringInterner) -> Option<bool> {
+    match residual.unannotated() {
+        Expr::Literal(value) => Some(*value == Value::Bool(true)),
+        residual => match satisfiability(residual, interner) {
+            Satisfiability::Unsatisfiable(_) => Some(false),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn finds_gaps_and_overlaps() {
+        let mut rules = RuleSet::new();
+        for (id, source) in [
+            (1, r#"(= country "US")"#),
+            (2, r#"(in country ["US" "CA"])"#),
+            (3, r#"(and (= country "DE") (> age 18))"#),
+            (4, r#"(and (= device "tv") (> age 18) (< age 10))"#),
+        ] {
+            let expr = parse(source, rules.interner_mut()).unwrap();
+            rules.add_rule(id, expr);
+        }
+        let interner = rules.interner_mut();
+        let (country, device) = (interner.intern("country"), interner.intern("device"));
+        let countries = ["US", "CA", "DE", "FR"].map(|name| Value::String(interner.intern(name)));
+        let devices = ["tv", "phone"].map(|name| Value::String(interner.intern(name)));
+        let mut schema = Schema::new();
+        schema.declare_domain(country, countries.to_vec());
+        schema.declare_domain(device, devices.to_vec());
+
+        let coverage = rules.coverage(&schema, &[country]).unwrap();
+        let cells = coverage.cells();
+        assert_eq!(cells.len(), 4);
+        assert_eq!(
+            (cells[0].matched(), cells[0].possible()),
+            (&[1, 2][..], &[][..])
+        );
+        assert_eq!(cells[1].matched(), [2]);
+        // Rule 4 depends on `device`, but can never hold
+        assert_eq!(
+            (cells[2].matched(), cells[2].possible()),
+            (&[][..], &[3][..])
+        );
+        let gaps: Vec<_> = coverage.gaps().map(CoverageCell::values).collect();
+        assert_eq!(gaps, [&countries[3..]]);
+        assert_eq!(coverage.overlaps(), [(1, 2)]);
+
+        let coverage = rules.coverage(&schema, &[country, device]).unwrap();
+        assert_eq!(coverage.cells().len(), 8);
+        assert_eq!(
+            coverage.cells()[1].values(),
+            [countries[0].clone(), devices[1].clone()]
+        );
+        assert_eq!(coverage.gaps().count(), 2);
+
+        let age = rules.interner_mut().intern("age");
+        assert_eq!(
+            rules.coverage(&schema, &[country, age]),
+            Err(CoverageError::NoDomain(age))
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Exact decimal numbers
+//!
+//! A `Decimal` is an `i128` mantissa scaled by a power of ten, so amounts
+//! such as prices add up exactly where floats would not: `0.1d` plus
+//! `0.2d` is `0.3d`. Rules write decimal literals with a `d` suffix, as in
+//! `(>= (* price 3) 59.97d)`, and `(decimal x)` converts a string, integer
+//! or float, such as a price from a JSON payload, by its shortest decimal
+//! text.
+//!
+//! The scale is at most `MAX_SCALE` digits after the point. Decimals keep
+//! the scale they were written with, so `1.50d` prints as `1.50d`, but
+//! compare and hash by value, so `1.50d` equals `1.5d`.
+
+use alloc::string::{String, ToString};
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::str::FromStr;
+
+/// Most digits a decimal may have after the point
+pub const MAX_SCALE: u8 = 18;
+
+/// Fixed-point decimal number
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u8,
+}
+
+impl Decimal {
+    /// Create the decimal `mantissa / 10^scale`, or `None` if `scale` is
+    /// more than `MAX_SCALE`
+    pub fn new(mantissa: i128, scale: u8) -> Option<Self> {
+        (scale <= MAX_SCALE).then_some(Self { mantissa, scale })
+    }
+
+    /// Get the digits of the decimal as an integer
+    pub fn mantissa(self) -> i128 {
+        self.mantissa
+    }
+
+    /// Get the number of digits after the point
+    pub fn scale(self) -> u8 {
+        self.scale
+    }
+
+    /// Convert to the nearest float
+    pub fn to_f64(self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    /// Convert to an integer if the decimal has no fractional part and fits
+    pub fn to_i64(self) -> Option<i64> {
+        let normalized = self.normalized();
+        if normalized.scale > 0 {
+            return None;
+        }
+        i64::try_from(normalized.mantissa).ok()
+    }
+
+    /// Convert a float by its shortest decimal text, `None` if it is not
+    /// finite or needs more than `MAX_SCALE` digits after the point
+    pub fn from_f64(x: f64) -> Option<Self> {
+        if !x.is_finite() {
+            return None;
+        }
+        x.to_string().parse().ok()
+    }
+
+    /// Add two decimals, `None` on overflow
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let scale = self.scale.max(other.scale);
+        let mantissa = self.rescaled(scale)?.checked_add(other.rescaled(scale)?)?;
+        Some(Self { mantissa, scale })
+    }
+
+    /// Subtract `other`, `None` on overflow
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.checked_add(Self {
+            mantissa: other.mantissa.checked_neg()?,
+            scale: other.scale,
+        })
+    }
+
+    /// Multiply two decimals, `None` on overflow or if the product needs
+    /// more than `MAX_SCALE` digits after the point
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.normalized(), other.normalized());
+        let product = Self {
+            mantissa: a.mantissa.checked_mul(b.mantissa)?,
+            scale: a.scale + b.scale,
+        };
+        Self::new(product.mantissa, product.scale)
+    }
+
+    /// Mantissa of this decimal at a scale at least its own
+    fn rescaled(self, scale: u8) -> Option<i128> {
+        self.mantissa
+            .checked_mul(10i128.checked_pow(u32::from(scale - self.scale))?)
+    }
+
+    /// The same value without trailing zeros after the point
+    fn normalized(self) -> Self {
+        let mut decimal = self;
+        while decimal.scale > 0 && decimal.mantissa % 10 == 0 {
+            decimal.mantissa /= 10;
+            decimal.scale -= 1;
+        }
+        decimal
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(n: i64) -> Self {
+        Self {
+            mantissa: i128::from(n),
+            scale: 0,
+        }
+    }
+}
+
+/// Error parsing a `Decimal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDecimalError;
+
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid decimal")
+    }
+}
+
+impl From<Decimal> for String {
+    fn from(decimal: Decimal) -> Self {
+        decimal.to_string()
+    }
+}
+
+impl TryFrom<String> for Decimal {
+    type Error = ParseDecimalError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses text such as `-12.50`, without a `d` suffix or exponent
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(ParseDecimalError);
+        }
+        let scale = u8::try_from(fraction.len())
+            .ok()
+            .filter(|&scale| scale <= MAX_SCALE)
+            .ok_or(ParseDecimalError)?;
+        let mut mantissa: i128 = 0;
+        for c in whole.chars().chain(fraction.chars()) {
+            let digit = c.to_digit(10).ok_or(ParseDecimalError)?;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add(i128::from(digit)))
+                .ok_or(ParseDecimalError)?;
+        }
+        Ok(Self {
+            mantissa: if negative { -mantissa } else { mantissa },
+            scale,
+        })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = usize::from(self.scale);
+        if self.mantissa < 0 {
+            write!(f, "-")?;
+        }
+        if scale == 0 {
+            return write!(f, "{}", digits);
+        }
+        if digits.len() > scale {
+            let (whole, fraction) = digits.split_at(digits.len() - scale);
+            write!(f, "{}.{}", whole, fraction)
+        } else {
+            write!(f, "0.{:0>width$}", digits, width = scale)
+        }
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Whole parts first, then fractions, which are below 10^MAX_SCALE
+        // and so cannot overflow when brought to a common scale
+        let split = |d: &Decimal| {
+            let unit = 10i128.pow(u32::from(d.scale));
+            (d.mantissa.div_euclid(unit), d.mantissa.rem_euclid(unit))
+        };
+        let ((a_whole, a_fraction), (b_whole, b_fraction)) = (split(self), split(other));
+        let scale = u32::from(self.scale.max(other.scale));
+        a_whole.cmp(&b_whole).then_with(|| {
+            let a = a_fraction * 10i128.pow(scale - u32::from(self.scale));
+            let b = b_fraction * 10i128.pow(scale - u32::from(other.scale));
+            a.cmp(&b)
+        })
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let normalized = self.normalized();
+        normalized.mantissa.hash(state);
+        normalized.scale.hash(state);
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Decimal {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            mantissa: i128::from(i64::arbitrary(u)?),
+            scale: u.int_in_range(0..=MAX_SCALE)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn exact_arithmetic() {
+        assert_eq!(d("0.1").checked_add(d("0.2")), Some(d("0.3")));
+        assert_eq!(d("19.99").checked_mul(d("3")), Some(d("59.97")));
+        assert_eq!(d("1").checked_sub(d("1.005")), Some(d("-0.005")));
+        assert_eq!(d("1.50"), d("1.5"));
+        assert!(d("-0.5") < d("0.25"));
+        assert!(d("-1.5") < d("-1.25"));
+        assert!(d("2") > d("1.999999999999999999"));
+        assert_eq!(Decimal::from(3).checked_mul(d("0.10")), Some(d("0.3")));
+        assert_eq!(Decimal::from_f64(0.1), Some(d("0.1")));
+        assert_eq!(Decimal::from_f64(f64::NAN), None);
+        assert_eq!(d("0.3").to_f64(), 0.3);
+        assert_eq!(d("-4.00").to_i64(), Some(-4));
+        assert_eq!(d("4.01").to_i64(), None);
+
+        let max = Decimal::new(i128::MAX, 0).unwrap();
+        assert_eq!(max.checked_add(d("1")), None);
+        assert_eq!(d("0.000000001").checked_mul(d("0.0000000001")), None);
+        assert_eq!(Decimal::new(1, MAX_SCALE + 1), None);
+    }
+
+    #[test]
+    fn text() {
+        for s in ["12.50", "-0.05", "0", "-7", "0.000000000000000001"] {
+            assert_eq!(format!("{}", d(s)), s);
+        }
+        assert_eq!(format!("{}", d("+.5")), "0.5");
+        assert_eq!(format!("{}", d("3.")), "3");
+        for bad in ["", ".", "-", "1.2.3", "1e5", "abc", "0.0000000000000000001"] {
+            assert_eq!(bad.parse::<Decimal>(), Err(ParseDecimalError), "{}", bad);
+        }
+    }
+}
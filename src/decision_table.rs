@@ -0,0 +1,438 @@
+//! Decision tables
+//!
+//! Much business logic is authored as a spreadsheet rather than as
+//! S-expressions: one column per input, one row per rule, and an outcome
+//! per row. A `DecisionTable` holds such a table and compiles it into an
+//! `if` expression that evaluates like any other rule.
+//!
+//! ```
+//! use ironwood::decision_table::{Cell, DecisionTable};
+//! use ironwood::{parse, Environment, Evaluator, StringInterner, Value};
+//!
+//! let mut interner = StringInterner::new();
+//! let tier = parse("tier", &mut interner).unwrap();
+//! let spend = parse("spend", &mut interner).unwrap();
+//! let mut table = DecisionTable::new(vec![tier, spend]);
+//! for (cells, discount) in [([r#""gold""#, ">= 100"], 20), ([r#""gold""#, "-"], 10)] {
+//!     let cells = cells
+//!         .iter()
+//!         .map(|text| Cell::parse(text, &mut interner))
+//!         .collect::<Result<Vec<_>, _>>()
+//!         .unwrap();
+//!     table.row(cells, Value::Integer(discount).into()).unwrap();
+//! }
+//! table.set_default(Value::Integer(0).into());
+//! let expr = table.compile(&mut interner);
+//!
+//! let mut env = Environment::new();
+//! env.insert(interner.intern("tier"), Value::String(interner.intern("gold")));
+//! env.insert(interner.intern("spend"), Value::Integer(40));
+//! assert_eq!(Evaluator::new(&interner).eval(&expr, &env), Ok(Value::Integer(10)));
+//! ```
+//!
+//! Rows are tried in order and the first whose every cell holds gives the
+//! outcome, the default when none does. A cell holds for any input (`-`),
+//! when the input equals a literal, when it is in a list of literals, or
+//! when a condition reading the input as `_` is true. Compilation drops
+//! rows that follow one matching everything, and tests a cell shared by a
+//! run of adjacent rows once for the whole run when the run ends in a row
+//! matching everything else, so the run's remaining cells are decided
+//! without falling through to later rows.
+
+use crate::expr::ELEMENT;
+use crate::optimize::simplify;
+use crate::{parse, BuiltinFunction, Expr, IronwoodError, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Reasons a row cannot be added to a decision table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecisionTableError {
+    /// Row has a different number of cells than the table has inputs
+    RowWidth { expected: usize, found: usize },
+}
+
+impl fmt::Display for DecisionTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecisionTableError::RowWidth { expected, found } => write!(
+                f,
+                "row has {} cell(s), but the table has {} input(s)",
+                found, expected
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecisionTableError {}
+
+/// Condition a row places on one input
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    /// Holds for any input
+    Any,
+    /// Holds when the input equals the value
+    Equals(Value),
+    /// Holds when the input is one of the values
+    OneOf(Vec<Value>),
+    /// Holds when the expression, reading the input as `_`, is true
+    Condition(Expr),
+}
+
+impl Cell {
+    /// Parse a cell as written in a spreadsheet
+    ///
+    /// `-` or a blank cell holds for any input. A cell starting with a
+    /// comparison operator, such as `>= 18` or `!= "US"`, compares the
+    /// input with the rest of the cell. Otherwise the cell is parsed as an
+    /// expression: a literal is compared for equality, a list of literals
+    /// for membership, and any other expression is a condition reading the
+    /// input as `_`.
+    pub fn parse(text: &str, interner: &mut StringInterner) -> Result<Cell, IronwoodError> {
+        let text = text.trim();
+        if text.is_empty() || text == "-" {
+            return Ok(Cell::Any);
+        }
+        for operator in [">=", "<=", "!=", ">", "<", "="] {
+            if let Some(operand) = text.strip_prefix(operator) {
+                let operand = parse(operand, interner)?;
+                let function = interner.intern(operator);
+                let element = interner.intern(ELEMENT);
+                return Ok(match (operator, operand) {
+                    ("=", Expr::Literal(value)) => Cell::Equals(value),
+                    (_, operand) => Cell::Condition(Expr::Call {
+                        function,
+                        args: alloc::vec![Expr::Variable(element), operand],
+                    }),
+                });
+            }
+        }
+        Ok(match parse(text, interner)? {
+            Expr::Literal(value) => Cell::Equals(value),
+            Expr::List(items) if items.iter().all(|item| matches!(item, Expr::Literal(_))) => {
+                Cell::OneOf(
+                    items
+                        .into_iter()
+                        .filter_map(|item| match item {
+                            Expr::Literal(value) => Some(value),
+                            _ => None,
+                        })
+                        .collect(),
+                )
+            }
+            condition => Cell::Condition(condition),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    cells: Vec<Cell>,
+    outcome: Expr,
+}
+
+/// Rows of conditions on a fixed set of inputs, each giving an outcome
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecisionTable {
+    inputs: Vec<Expr>,
+    rows: Vec<Row>,
+    default: Expr,
+}
+
+impl DecisionTable {
+    /// Create a table with no rows over the input expressions, one per
+    /// column, whose default outcome is null
+    pub fn new(inputs: Vec<Expr>) -> Self {
+        Self {
+            inputs,
+            rows: Vec::new(),
+            default: Expr::Literal(Value::Null),
+        }
+    }
+
+    /// Get the input expressions, one per column
+    pub fn inputs(&self) -> &[Expr] {
+        &self.inputs
+    }
+
+    /// Get the number of rows
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Check if the table has no rows
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Add a row giving `outcome` when every cell holds, after the rows
+    /// already added
+    pub fn row(&mut self, cells: Vec<Cell>, outcome: Expr) -> Result<(), DecisionTableError> {
+        if cells.len() != self.inputs.len() {
+            return Err(DecisionTableError::RowWidth {
+                expected: self.inputs.len(),
+                found: cells.len(),
+            });
+        }
+        self.rows.push(Row { cells, outcome });
+        Ok(())
+    }
+
+    /// Set the outcome when no row matches
+    pub fn set_default(&mut self, outcome: Expr) {
+        self.default = outcome;
+    }
+
+    /// Compile the table into an expression giving the outcome of the
+    /// first matching row
+    pub fn compile(&self, interner: &mut StringInterner) -> Expr {
+        let names = Names {
+            and: interner.intern(BuiltinFunction::And.as_str()),
+            equal: interner.intern(BuiltinFunction::Equal.as_str()),
+            is_in: interner.intern(BuiltinFunction::In.as_str()),
+            if_: interner.intern(BuiltinFunction::If.as_str()),
+            element: interner.intern(ELEMENT),
+        };
+        let rows: Vec<&Row> = self.rows.iter().collect();
+        let (mut clauses, exhaustive) = self.chain(&rows, 0, &names, interner);
+        if !exhaustive {
+            clauses.push(self.default.clone());
+        }
+        simplify(names.chain(clauses), interner)
+    }
+
+    /// Build the `if` clauses trying `rows` in order, given that the cells
+    /// before column `start` hold or are `-`, and whether the last row
+    /// matches everything, leaving a final outcome and no fall through
+    fn chain(
+        &self,
+        rows: &[&Row],
+        start: usize,
+        names: &Names,
+        interner: &StringInterner,
+    ) -> (Vec<Expr>, bool) {
+        let mut clauses = Vec::new();
+        let mut i = 0;
+        while i < rows.len() {
+            let row = rows[i];
+            // First column from `start` whose cell constrains the input
+            let Some(column) = (start..self.inputs.len()).find(|&c| row.cells[c] != Cell::Any)
+            else {
+                clauses.push(row.outcome.clone());
+                return (clauses, true);
+            };
+            let cell = &row.cells[column];
+            let run = rows[i..]
+                .iter()
+                .take_while(|other| {
+                    other.cells[start..column].iter().all(|c| *c == Cell::Any)
+                        && other.cells[column] == *cell
+                })
+                .count();
+            if run > 1 {
+                let (inner, exhaustive) =
+                    self.chain(&rows[i..i + run], column + 1, names, interner);
+                if exhaustive {
+                    clauses.push(self.test(column, cell, names, interner));
+                    clauses.push(names.chain(inner));
+                    i += run;
+                    continue;
+                }
+            }
+            let tests = (start..self.inputs.len())
+                .filter(|&c| row.cells[c] != Cell::Any)
+                .map(|c| self.test(c, &row.cells[c], names, interner))
+                .collect();
+            clauses.push(Expr::Call {
+                function: names.and,
+                args: tests,
+            });
+            clauses.push(row.outcome.clone());
+            i += 1;
+        }
+        (clauses, false)
+    }
+
+    /// Build the test of a cell that is not `-` on the input of `column`
+    fn test(&self, column: usize, cell: &Cell, names: &Names, interner: &StringInterner) -> Expr {
+        let input = &self.inputs[column];
+        match cell {
+            Cell::Any => Expr::Literal(Value::Bool(true)),
+            Cell::Equals(value) => Expr::Call {
+                function: names.equal,
+                args: alloc::vec![input.clone(), Expr::Literal(value.clone())],
+            },
+            Cell::OneOf(values) => Expr::Call {
+                function: names.is_in,
+                args: alloc::vec![
+                    input.clone(),
+                    Expr::List(values.iter().cloned().map(Expr::Literal).collect()),
+                ],
+            },
+            Cell::Condition(condition) => {
+                substitute(condition.clone(), names.element, input, interner)
+            }
+        }
+    }
+}
+
+/// IDs of the names compiled tables use
+struct Names {
+    and: StringId,
+    equal: StringId,
+    is_in: StringId,
+    if_: StringId,
+    element: StringId,
+}
+
+impl Names {
+    /// Build an `if` from its clauses, or the outcome itself when there
+    /// is only one
+    fn chain(&self, mut clauses: Vec<Expr>) -> Expr {
+        if clauses.len() == 1 {
+            return clauses.pop().expect("one clause");
+        }
+        Expr::Call {
+            function: self.if_,
+            args: clauses,
+        }
+    }
+}
+
+/// Replace `_` in a cell condition with the column's input, except where
+/// `any`/`all`/`filter`/`map` or a `let` binds `_` again
+fn substitute(expr: Expr, element: StringId, input: &Expr, interner: &StringInterner) -> Expr {
+    match expr {
+        Expr::Variable(name) if name == element => input.clone(),
+        Expr::Call { function, args } => {
+            let binds = interner
+                .builtin(function)
+                .is_some_and(BuiltinFunction::binds_element);
+            let args = args
+                .into_iter()
+                .enumerate()
+                .map(|(i, arg)| match binds && i == 1 {
+                    true => arg,
+                    false => substitute(arg, element, input, interner),
+                })
+                .collect();
+            Expr::Call { function, args }
+        }
+        Expr::List(items) => Expr::List(
+            items
+                .into_iter()
+                .map(|item| substitute(item, element, input, interner))
+                .collect(),
+        ),
+        Expr::Annotated { metadata, expr } => Expr::Annotated {
+            metadata,
+            expr: Box::new(substitute(*expr, element, input, interner)),
+        },
+        Expr::Let { bindings, body } => {
+            let mut shadowed = false;
+            let bindings = bindings
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match shadowed {
+                        true => value,
+                        false => substitute(value, element, input, interner),
+                    };
+                    shadowed |= name == element;
+                    (name, value)
+                })
+                .collect();
+            let body = match shadowed {
+                true => *body,
+                false => substitute(*body, element, input, interner),
+            };
+            Expr::Let {
+                bindings,
+                body: Box::new(body),
+            }
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, Evaluator};
+
+    #[test]
+    fn compiles_first_match() {
+        let mut interner = StringInterner::new();
+        let inputs = ["country", "age"]
+            .iter()
+            .map(|name| parse(name, &mut interner).unwrap())
+            .collect();
+        let mut table = DecisionTable::new(inputs);
+        let rows: [([&str; 2], i64); 6] = [
+            ([r#""US""#, "< 18"], 1),
+            ([r#""US""#, "-"], 2),
+            ([r#"["DE" "FR"]"#, "(> (+ _ 1) 65)"], 3),
+            (["-", "= 30"], 4),
+            (["-", "-"], 5),
+            ([r#""UK""#, "-"], 6),
+        ];
+        for (cells, outcome) in rows {
+            let cells = cells
+                .iter()
+                .map(|text| Cell::parse(text, &mut interner).unwrap())
+                .collect();
+            table.row(cells, Value::Integer(outcome).into()).unwrap();
+        }
+        let expr = table.compile(&mut interner);
+        // `"US"` is tested once for both its rows, and the row after the
+        // catch-all is dropped
+        assert_eq!(
+            expr.to_sexpr(&interner),
+            r#"(if (= country "US") (if (< age 18) 1 2) (and (in country ["DE" "FR"]) (> (+ age 1) 65)) 3 (= age 30) 4 5)"#
+        );
+
+        let evaluator = Evaluator::new(&interner);
+        let (country, age) = (
+            interner.get_id("country").unwrap(),
+            interner.get_id("age").unwrap(),
+        );
+        for (name, years, expected) in [
+            ("US", 12, 1),
+            ("US", 40, 2),
+            ("FR", 70, 3),
+            ("FR", 30, 4),
+            ("UK", 40, 5),
+        ] {
+            let mut env = Environment::new();
+            env.insert(country, Value::String(interner.get_id(name).unwrap()));
+            env.insert(age, Value::Integer(years));
+            assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Integer(expected)));
+        }
+
+        assert_eq!(
+            table.row(alloc::vec![Cell::Any], Value::Null.into()),
+            Err(DecisionTableError::RowWidth {
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn falls_through_to_default() {
+        let mut interner = StringInterner::new();
+        let tier = parse("tier", &mut interner).unwrap();
+        let mut table = DecisionTable::new(alloc::vec![tier]);
+        let gold = Cell::parse(r#""gold""#, &mut interner).unwrap();
+        table
+            .row(alloc::vec![gold], Value::Integer(1).into())
+            .unwrap();
+        table.set_default(Value::Integer(0).into());
+        let expr = table.compile(&mut interner);
+        assert_eq!(expr.to_sexpr(&interner), r#"(if (= tier "gold") 1 0)"#);
+
+        let empty = DecisionTable::new(Vec::new()).compile(&mut interner);
+        assert_eq!(empty, Expr::Literal(Value::Null));
+    }
+}
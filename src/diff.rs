@@ -0,0 +1,286 @@
+//! Structural diffs between expressions
+//!
+//! `expr::diff` compares two versions of a rule node by node, for audit
+//! logs and change review. Calls to the same function, lists, `let`s with
+//! the same names and annotations with the same metadata are compared child
+//! by child; any other difference replaces the whole node. Children are
+//! aligned on the longest run of unchanged ones, so an operand inserted
+//! into an `and` shows as added rather than as every later operand
+//! changing. Unaligned children left between two aligned ones are paired
+//! up in order and compared, and the rest are added or removed.
+//!
+//! Nodes are located by a path of child indices from the root, counting
+//! `let` bindings before the body and skipping through annotations.
+//! Removed and changed nodes are located in the old expression and added
+//! nodes in the new one.
+
+use crate::{Expr, StringInterner};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// One difference between two expressions
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Node only in the new expression
+    Added { path: Vec<usize>, expr: Expr },
+    /// Node only in the old expression
+    Removed { path: Vec<usize>, expr: Expr },
+    /// Node replaced by another
+    Changed {
+        path: Vec<usize>,
+        old: Expr,
+        new: Expr,
+    },
+}
+
+impl Change {
+    /// Get the path of child indices locating the node
+    pub fn path(&self) -> &[usize] {
+        match self {
+            Change::Added { path, .. } | Change::Removed { path, .. } => path,
+            Change::Changed { path, .. } => path,
+        }
+    }
+}
+
+/// Differences between two expressions, returned by `expr::diff`
+///
+/// Displays as one line per change, `+` for added nodes, `-` for removed
+/// ones and `~` for changed ones, each with its path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprDiff {
+    changes: Vec<Change>,
+    /// Changes rendered through the interner the expressions were built
+    /// with
+    text: String,
+}
+
+impl ExprDiff {
+    /// Get the changes, in order of position
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Check if the expressions are the same
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for ExprDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Compare two expressions built with `interner`
+pub fn diff(old: &Expr, new: &Expr, interner: &StringInterner) -> ExprDiff {
+    let mut changes = Vec::new();
+    compare(old, new, &mut Vec::new(), &mut changes);
+    let mut text = String::new();
+    for change in &changes {
+        let (sign, path) = match change {
+            Change::Added { path, .. } => ('+', path),
+            Change::Removed { path, .. } => ('-', path),
+            Change::Changed { path, .. } => ('~', path),
+        };
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push(sign);
+        text.push(' ');
+        if path.is_empty() {
+            text.push_str("root");
+        }
+        for (i, index) in path.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "." };
+            let _ = write!(text, "{separator}{index}");
+        }
+        let _ = match change {
+            Change::Added { expr, .. } | Change::Removed { expr, .. } => {
+                write!(text, ": {}", expr.display(interner))
+            }
+            Change::Changed { old, new, .. } => write!(
+                text,
+                ": {} -> {}",
+                old.display(interner),
+                new.display(interner)
+            ),
+        };
+    }
+    ExprDiff { changes, text }
+}
+
+fn compare(old: &Expr, new: &Expr, path: &mut Vec<usize>, changes: &mut Vec<Change>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (
+            Expr::Call { function, args },
+            Expr::Call {
+                function: new_function,
+                args: new_args,
+            },
+        ) if function == new_function => children(args, new_args, path, changes),
+        (Expr::List(items), Expr::List(new_items)) => children(items, new_items, path, changes),
+        (
+            Expr::Annotated { metadata, expr },
+            Expr::Annotated {
+                metadata: new_metadata,
+                expr: new_expr,
+            },
+        ) if metadata == new_metadata => compare(expr, new_expr, path, changes),
+        (
+            Expr::Let { bindings, body },
+            Expr::Let {
+                bindings: new_bindings,
+                body: new_body,
+            },
+        ) if bindings.len() == new_bindings.len()
+            && bindings
+                .iter()
+                .zip(new_bindings)
+                .all(|((name, _), (new_name, _))| name == new_name) =>
+        {
+            for (i, ((_, value), (_, new_value))) in bindings.iter().zip(new_bindings).enumerate() {
+                path.push(i);
+                compare(value, new_value, path, changes);
+                path.pop();
+            }
+            path.push(bindings.len());
+            compare(body, new_body, path, changes);
+            path.pop();
+        }
+        _ => changes.push(Change::Changed {
+            path: path.clone(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+    }
+}
+
+/// Compare children aligned on their longest common subsequence
+fn children(old: &[Expr], new: &[Expr], path: &mut Vec<usize>, changes: &mut Vec<Change>) {
+    // Length of the longest common subsequence of each pair of suffixes
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let (mut gap_old, mut gap_new) = (0, 0);
+    loop {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            gap(old, gap_old..i, new, gap_new..j, path, changes);
+            i += 1;
+            j += 1;
+            (gap_old, gap_new) = (i, j);
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            i += 1;
+        } else if j < new.len() {
+            j += 1;
+        } else {
+            break;
+        }
+    }
+    gap(
+        old,
+        gap_old..old.len(),
+        new,
+        gap_new..new.len(),
+        path,
+        changes,
+    );
+}
+
+/// Compare children between two aligned ones
+fn gap(
+    old: &[Expr],
+    old_range: core::ops::Range<usize>,
+    new: &[Expr],
+    new_range: core::ops::Range<usize>,
+    path: &mut Vec<usize>,
+    changes: &mut Vec<Change>,
+) {
+    let paired = old_range.len().min(new_range.len());
+    for (i, j) in old_range.clone().zip(new_range.clone()) {
+        path.push(i);
+        compare(&old[i], &new[j], path, changes);
+        path.pop();
+    }
+    for i in old_range.skip(paired) {
+        path.push(i);
+        changes.push(Change::Removed {
+            path: path.clone(),
+            expr: old[i].clone(),
+        });
+        path.pop();
+    }
+    for j in new_range.skip(paired) {
+        path.push(j);
+        changes.push(Change::Added {
+            path: path.clone(),
+            expr: new[j].clone(),
+        });
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use alloc::string::ToString;
+
+    fn diff_of(old: &str, new: &str) -> String {
+        let mut interner = StringInterner::new();
+        let old = parse(old, &mut interner).unwrap();
+        let new = parse(new, &mut interner).unwrap();
+        diff(&old, &new, &interner).to_string()
+    }
+
+    #[test]
+    fn describes_changes() {
+        let cases = [
+            ("(and (> age 18) tier)", "(and (> age 18) tier)", ""),
+            ("(> age 18)", "(> age 21)", "~ 1: 18 -> 21"),
+            ("(and a b)", "(and a c b)", "+ 1: c"),
+            ("(or a b c)", "(or a c)", "- 1: b"),
+            ("(and a b)", "(or a b)", "~ root: (and a b) -> (or a b)"),
+            (
+                r#"(and (> age 18) (= country "US") (exists x))"#,
+                r#"(and (> age 21) (= country "US") (= tier "gold") y)"#,
+                "~ 0.1: 18 -> 21\n~ 2: (exists x) -> (= tier \"gold\")\n+ 3: y",
+            ),
+            (
+                "(let ((x (+ a 1))) (> x 2))",
+                "(let ((x (+ a 2))) (> x 2))",
+                "~ 0.1: 1 -> 2",
+            ),
+        ];
+        for (old, new, expected) in cases {
+            assert_eq!(diff_of(old, new), expected, "{old} -> {new}");
+        }
+    }
+
+    #[test]
+    fn locates_changes() {
+        let mut interner = StringInterner::new();
+        let old = parse("(and a (in x [1 2 3]))", &mut interner).unwrap();
+        let new = parse("(and a (in x [1 3]) b)", &mut interner).unwrap();
+        let diff = diff(&old, &new, &interner);
+        let paths: Vec<_> = diff.changes().iter().map(Change::path).collect();
+        assert!(!diff.is_empty());
+        // The `2` removed from the list, then `b`
+        assert_eq!(paths, [&[1, 1, 1][..], &[2][..]]);
+    }
+}
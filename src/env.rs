@@ -0,0 +1,328 @@
+//! Variable environments for expression evaluation
+
+use crate::compat::FxHashMap;
+use crate::{Interner, StringId, Value};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Mapping from interned variable names to their values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    vars: FxHashMap<StringId, Value>,
+}
+
+impl Environment {
+    /// Create an empty environment
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a variable, returning the previous value if any
+    pub fn insert(&mut self, name: StringId, value: Value) -> Option<Value> {
+        self.vars.insert(name, value)
+    }
+
+    /// Look up the value bound to a variable
+    pub fn get(&self, name: StringId) -> Option<&Value> {
+        self.vars.get(&name)
+    }
+
+    /// Remove a variable binding
+    pub fn remove(&mut self, name: StringId) -> Option<Value> {
+        self.vars.remove(&name)
+    }
+
+    /// Check if a variable is bound
+    pub fn contains(&self, name: StringId) -> bool {
+        self.vars.contains_key(&name)
+    }
+
+    /// Get the number of bound variables
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// Check if the environment is empty
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Iterate over all bindings in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (StringId, &Value)> {
+        self.vars.iter().map(|(&id, value)| (id, value))
+    }
+
+    /// Hash the bindings of `variables` into a 128-bit fingerprint
+    ///
+    /// Only the listed variables are hashed, such as the `variables` of a
+    /// compiled rule, so environments that differ only in variables a rule
+    /// never reads have the same fingerprint for it. Names and strings are
+    /// hashed by their text and variables in name order, so the
+    /// fingerprint is the same whatever order `variables` is in, whichever
+    /// interner built the environment and in any process. Unbound
+    /// variables hash differently from variables bound to null.
+    ///
+    /// The hash is FNV-1a, which is fast but not collision resistant:
+    /// different bindings can be crafted to share a fingerprint, so compare
+    /// the bindings themselves before trusting a match.
+    pub fn fingerprint(&self, variables: &[StringId], interner: &dyn Interner) -> u128 {
+        let mut names = variables.to_vec();
+        names.sort_unstable_by_key(|&name| (interner.resolve(name), name));
+        names.dedup();
+        let mut hasher = Fingerprint::new(interner);
+        for name in names {
+            hasher.id(name);
+            match self.get(name) {
+                Some(value) => hasher.value(value),
+                None => hasher.tag(u8::MAX),
+            }
+        }
+        hasher.hash
+    }
+}
+
+/// FNV-1a over the structure of values, for `Environment::fingerprint`
+struct Fingerprint<'a> {
+    hash: u128,
+    interner: &'a dyn Interner,
+}
+
+impl<'a> Fingerprint<'a> {
+    const OFFSET: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    /// Ends every string, and is never part of UTF-8 text
+    const END: u8 = 0xff;
+
+    fn new(interner: &'a dyn Interner) -> Self {
+        Self {
+            hash: Self::OFFSET,
+            interner,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = (self.hash ^ u128::from(byte)).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.bytes(&[tag]);
+    }
+
+    fn int(&mut self, n: u64) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    fn text(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+        self.tag(Self::END);
+    }
+
+    fn id(&mut self, id: StringId) {
+        self.text(self.interner.resolve(id).unwrap_or_default());
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Symbol(id) => {
+                self.tag(0);
+                self.id(*id);
+            }
+            Value::String(id) => {
+                self.tag(1);
+                self.id(*id);
+            }
+            Value::Integer(n) => {
+                self.tag(2);
+                self.int(*n as u64);
+            }
+            Value::Float(x) => {
+                self.tag(3);
+                self.int(x.to_bits());
+            }
+            Value::StringList(ids) => {
+                self.tag(4);
+                self.int(ids.len() as u64);
+                ids.iter().for_each(|&id| self.id(id));
+            }
+            Value::IntegerList(ns) => {
+                self.tag(5);
+                self.int(ns.len() as u64);
+                ns.iter().for_each(|&n| self.int(n as u64));
+            }
+            Value::Bool(b) => {
+                self.tag(6);
+                self.tag(u8::from(*b));
+            }
+            Value::List(values) => {
+                self.tag(7);
+                self.int(values.len() as u64);
+                values.iter().for_each(|value| self.value(value));
+            }
+            Value::Null => self.tag(8),
+            Value::Text(text) => {
+                self.tag(9);
+                self.text(text);
+            }
+            Value::Map(map) => {
+                self.tag(10);
+                self.int(map.len() as u64);
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_unstable_by_key(|&(&key, _)| (self.interner.resolve(key), key));
+                for (&key, value) in entries {
+                    self.id(key);
+                    self.value(value);
+                }
+            }
+            Value::Decimal(d) => {
+                self.tag(11);
+                self.bytes(&d.mantissa().to_le_bytes());
+                self.tag(d.scale());
+            }
+            Value::Quoted(expr) => {
+                self.tag(12);
+                let _ = write!(self, "{}", expr.display(self.interner));
+                self.tag(Self::END);
+            }
+            Value::IntRange(lo, hi) => {
+                self.tag(13);
+                self.int(*lo as u64);
+                self.int(*hi as u64);
+            }
+            Value::BloomSet(set) => {
+                self.tag(14);
+                self.int(u64::from(set.hashes()));
+                self.int(set.len());
+                self.int(set.words().len() as u64);
+                set.words().iter().for_each(|&word| self.int(word));
+            }
+        }
+    }
+}
+
+impl Write for Fingerprint<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl FromIterator<(StringId, Value)> for Environment {
+    fn from_iter<I: IntoIterator<Item = (StringId, Value)>>(iter: I) -> Self {
+        Self {
+            vars: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<(StringId, Value)> for Environment {
+    fn extend<I: IntoIterator<Item = (StringId, Value)>>(&mut self, iter: I) {
+        self.vars.extend(iter);
+    }
+}
+
+/// Variable values indexed by the dense slots of a `CompiledExpr`
+///
+/// Filling slots once and evaluating against them skips the name lookup
+/// `Environment` does for every variable read. Slot numbers come from
+/// `CompiledExpr::slot`, and are only meaningful for that expression.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvSlots {
+    values: Vec<Option<Value>>,
+}
+
+impl EnvSlots {
+    /// Create `len` unbound slots
+    pub fn new(len: usize) -> Self {
+        Self {
+            values: vec![None; len],
+        }
+    }
+
+    /// Bind a slot, growing the slots if needed, and return the previous
+    /// value if any
+    pub fn set(&mut self, slot: usize, value: Value) -> Option<Value> {
+        if slot >= self.values.len() {
+            self.values.resize(slot + 1, None);
+        }
+        self.values[slot].replace(value)
+    }
+
+    /// Look up the value bound to a slot
+    pub fn get(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot)?.as_ref()
+    }
+
+    /// Unbind a slot, returning its value if any
+    pub fn remove(&mut self, slot: usize) -> Option<Value> {
+        self.values.get_mut(slot)?.take()
+    }
+
+    /// Unbind every slot, keeping the allocation for reuse
+    pub fn clear(&mut self) {
+        self.values.fill(None);
+    }
+
+    /// Get the number of slots, bound or not
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Check if there are no slots
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StringInterner;
+    use alloc::vec;
+
+    #[test]
+    fn fingerprints_read_variables() {
+        let mut interner = StringInterner::new();
+        let (age, name, tags) = (
+            interner.intern("age"),
+            interner.intern("name"),
+            interner.intern("tags"),
+        );
+        let ann = interner.intern("ann");
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(30));
+        env.insert(name, Value::String(ann));
+        env.insert(tags, Value::StringList(vec![ann].into()));
+        let print = env.fingerprint(&[age, name], &interner);
+
+        // Order, repeats and variables not listed make no difference
+        assert_eq!(env.fingerprint(&[name, age, name], &interner), print);
+        env.insert(tags, Value::Null);
+        assert_eq!(env.fingerprint(&[age, name], &interner), print);
+
+        env.insert(age, Value::Integer(31));
+        assert_ne!(env.fingerprint(&[age, name], &interner), print);
+        env.insert(age, Value::Float(30.0));
+        assert_ne!(env.fingerprint(&[age, name], &interner), print);
+
+        let unbound = Environment::new().fingerprint(&[age], &interner);
+        let null: Environment = [(age, Value::Null)].into_iter().collect();
+        assert_ne!(null.fingerprint(&[age], &interner), unbound);
+
+        // Other interners give the same fingerprint for the same bindings
+        let mut other = StringInterner::new();
+        other.intern("padding");
+        let ann = other.intern("ann");
+        let copy: Environment = [
+            (other.intern("name"), Value::String(ann)),
+            (other.intern("age"), Value::Integer(30)),
+        ]
+        .into_iter()
+        .collect();
+        let names = [other.intern("age"), other.intern("name")];
+        assert_eq!(copy.fingerprint(&names, &other), print);
+        assert_eq!(copy.fingerprint(&names, &other.freeze()), print);
+    }
+}
@@ -0,0 +1,321 @@
+//! Error types carrying source locations
+//!
+//! Every `IronwoodError` carries a `Span` pointing into the rule source so
+//! callers can show users exactly where a rule failed.
+
+use crate::eval::EvalError;
+use crate::expr::Arity;
+use crate::limits::Limit;
+use crate::{BuiltinFunction, StringInterner, ValueType};
+use alloc::string::{String, ToString};
+use core::fmt;
+
+/// Region of source text, as a byte range plus the 1-based line and column
+/// of its first character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset one past the last character
+    pub end: usize,
+    /// Line of `start`, starting at 1
+    pub line: usize,
+    /// Column of `start` in characters, starting at 1
+    pub col: usize,
+}
+
+impl Span {
+    /// Build the span for byte range `start..end` of `source`
+    pub fn from_source(source: &str, start: usize, end: usize) -> Self {
+        let before = &source[..start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let col = before[line_start..].chars().count() + 1;
+        Self {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
+    /// Length of the span in bytes
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Check if the span covers no text
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Get the source text covered by this span
+    pub fn snippet<'s>(&self, source: &'s str) -> &'s str {
+        source.get(self.start..self.end).unwrap_or("")
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Errors reported while parsing or evaluating rules
+#[derive(Debug, Clone, PartialEq)]
+pub enum IronwoodError {
+    /// Source text is not a well-formed expression
+    Parse { message: String, span: Span },
+    /// Value has a type the operation cannot accept
+    Type {
+        function: BuiltinFunction,
+        expected: &'static str,
+        found: ValueType,
+        span: Span,
+    },
+    /// Variable is not bound in the environment
+    UnknownVariable { name: String, span: Span },
+    /// Function name does not match any builtin or registered function
+    UnknownFunction {
+        name: String,
+        /// Builtin with the closest name
+        suggestion: Option<BuiltinFunction>,
+        span: Span,
+    },
+    /// Builtin called with the wrong number of arguments
+    Arity {
+        function: BuiltinFunction,
+        expected: Arity,
+        found: usize,
+        span: Span,
+    },
+    /// Pattern given to `matches-regex` is not a valid regular expression
+    InvalidRegex { message: String, span: Span },
+    /// Version or range given to a `semver-*` builtin does not parse
+    InvalidVersion { message: String, span: Span },
+    /// Address or CIDR block given to an `ip-*` builtin does not parse
+    InvalidAddress { message: String, span: Span },
+    /// Text or float given to `decimal` is not a decimal
+    InvalidDecimal { message: String, span: Span },
+    /// Result of an arithmetic builtin does not fit its type
+    Overflow {
+        function: BuiltinFunction,
+        span: Span,
+    },
+    /// Error reported by a user-defined function
+    Custom { message: String, span: Span },
+    /// Rule went past one of its `EvalLimits`
+    LimitExceeded { limit: Limit, span: Span },
+    /// Evaluation was cancelled or ran past its deadline
+    Cancelled { span: Span },
+    /// Deterministic evaluation reached a custom function not registered
+    /// as deterministic
+    Nondeterministic { name: String, span: Span },
+    /// Scoring expression evaluated to something other than a number
+    NotNumeric { found: ValueType, span: Span },
+    /// `eval` was called where it is not allowed
+    EvalNotAllowed { span: Span },
+}
+
+impl IronwoodError {
+    /// Get the source location of this error
+    pub fn span(&self) -> Span {
+        match self {
+            IronwoodError::Parse { span, .. }
+            | IronwoodError::Type { span, .. }
+            | IronwoodError::UnknownVariable { span, .. }
+            | IronwoodError::UnknownFunction { span, .. }
+            | IronwoodError::Arity { span, .. }
+            | IronwoodError::InvalidRegex { span, .. }
+            | IronwoodError::InvalidVersion { span, .. }
+            | IronwoodError::InvalidAddress { span, .. }
+            | IronwoodError::InvalidDecimal { span, .. }
+            | IronwoodError::Overflow { span, .. }
+            | IronwoodError::Custom { span, .. }
+            | IronwoodError::LimitExceeded { span, .. }
+            | IronwoodError::Cancelled { span }
+            | IronwoodError::Nondeterministic { span, .. }
+            | IronwoodError::NotNumeric { span, .. }
+            | IronwoodError::EvalNotAllowed { span } => *span,
+        }
+    }
+
+    /// Attach a source location to an evaluation error, resolving interned
+    /// names for display
+    pub fn from_eval(error: EvalError, span: Span, interner: &StringInterner) -> Self {
+        let name = |id| interner.resolve(id).unwrap_or("<unknown>").to_string();
+        match error {
+            EvalError::UnknownVariable(id) => IronwoodError::UnknownVariable {
+                name: name(id),
+                span,
+            },
+            EvalError::UnknownFunction(id) => {
+                let name = name(id);
+                IronwoodError::UnknownFunction {
+                    suggestion: BuiltinFunction::suggest(&name),
+                    name,
+                    span,
+                }
+            }
+            EvalError::ArityMismatch {
+                function,
+                expected,
+                found,
+            } => IronwoodError::Arity {
+                function,
+                expected,
+                found,
+                span,
+            },
+            EvalError::TypeMismatch {
+                function,
+                expected,
+                found,
+            } => IronwoodError::Type {
+                function,
+                expected,
+                found,
+                span,
+            },
+            EvalError::InvalidRegex(message) => IronwoodError::InvalidRegex { message, span },
+            EvalError::InvalidVersion(message) => IronwoodError::InvalidVersion { message, span },
+            EvalError::InvalidAddress(message) => IronwoodError::InvalidAddress { message, span },
+            EvalError::InvalidDecimal(message) => IronwoodError::InvalidDecimal { message, span },
+            EvalError::Overflow(function) => IronwoodError::Overflow { function, span },
+            EvalError::Custom(message) => IronwoodError::Custom { message, span },
+            EvalError::LimitExceeded(limit) => IronwoodError::LimitExceeded { limit, span },
+            EvalError::Cancelled => IronwoodError::Cancelled { span },
+            EvalError::Nondeterministic(id) => IronwoodError::Nondeterministic {
+                name: name(id),
+                span,
+            },
+            EvalError::NotNumeric(found) => IronwoodError::NotNumeric { found, span },
+            EvalError::EvalNotAllowed => IronwoodError::EvalNotAllowed { span },
+        }
+    }
+}
+
+impl fmt::Display for IronwoodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IronwoodError::Parse { message, span } => {
+                write!(f, "parse error at {}: {}", span, message)
+            }
+            IronwoodError::Type {
+                function,
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "type error at {}: `{}` expects {}, found {:?}",
+                span,
+                function.as_str(),
+                expected,
+                found
+            ),
+            IronwoodError::UnknownVariable { name, span } => {
+                write!(f, "unknown variable `{}` at {}", name, span)
+            }
+            IronwoodError::UnknownFunction {
+                name,
+                suggestion,
+                span,
+            } => {
+                write!(f, "unknown function `{}` at {}", name, span)?;
+                match suggestion {
+                    Some(builtin) => write!(f, ", did you mean `{}`?", builtin.as_str()),
+                    None => Ok(()),
+                }
+            }
+            IronwoodError::Arity {
+                function,
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "arity mismatch at {}: `{}` expects {} argument(s), found {}",
+                span,
+                function.as_str(),
+                expected,
+                found
+            ),
+            IronwoodError::InvalidRegex { message, span } => {
+                write!(f, "invalid regex at {}: {}", span, message)
+            }
+            IronwoodError::InvalidVersion { message, span } => {
+                write!(f, "invalid version at {}: {}", span, message)
+            }
+            IronwoodError::InvalidAddress { message, span } => {
+                write!(f, "invalid address at {}: {}", span, message)
+            }
+            IronwoodError::InvalidDecimal { message, span } => {
+                write!(f, "invalid decimal at {}: {}", span, message)
+            }
+            IronwoodError::Overflow { function, span } => {
+                write!(f, "result of `{}` overflows at {}", function.as_str(), span)
+            }
+            IronwoodError::Custom { message, span } => {
+                write!(f, "error at {}: {}", span, message)
+            }
+            IronwoodError::LimitExceeded { limit, span } => {
+                write!(f, "{} exceeded at {}", limit, span)
+            }
+            IronwoodError::Cancelled { span } => write!(f, "evaluation cancelled at {}", span),
+            IronwoodError::Nondeterministic { name, span } => {
+                write!(f, "function `{}` is not deterministic at {}", name, span)
+            }
+            IronwoodError::NotNumeric { found, span } => {
+                write!(f, "expected a numeric score at {}, found {:?}", span, found)
+            }
+            IronwoodError::EvalNotAllowed { span } => {
+                write!(f, "`eval` is not allowed at {}", span)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IronwoodError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn span_line_and_column() {
+        let source = "(and\n  (= x 1)\n  (> y 2))";
+        let start = source.find("(>").unwrap();
+        let span = Span::from_source(source, start, start + 7);
+
+        assert_eq!(span.line, 3);
+        assert_eq!(span.col, 3);
+        assert_eq!(span.snippet(source), "(> y 2)");
+        assert_eq!(span.to_string(), "3:3");
+    }
+
+    #[test]
+    fn from_eval_resolves_names() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let span = Span::from_source("age", 0, 3);
+
+        let error = IronwoodError::from_eval(EvalError::UnknownVariable(age), span, &interner);
+        assert_eq!(
+            error,
+            IronwoodError::UnknownVariable {
+                name: "age".to_string(),
+                span,
+            }
+        );
+        assert_eq!(error.to_string(), "unknown variable `age` at 1:1");
+
+        let one_of = interner.intern("one_of");
+        let error = IronwoodError::from_eval(EvalError::UnknownFunction(one_of), span, &interner);
+        assert_eq!(
+            error.to_string(),
+            "unknown function `one_of` at 1:1, did you mean `one-of`?"
+        );
+    }
+}
@@ -0,0 +1,4161 @@
+//! Tree-walking evaluator for S-expressions
+//!
+//! Evaluates an `Expr` against an `Environment` of variable bindings.
+//! Boolean and comparison builtins produce `Value::Bool`.
+//!
+//! # Null
+//!
+//! `Value::Null` marks an absent value. Builtins given a null argument
+//! produce null, except `exists` and `is-null`, which test for it, and
+//! `and`/`or`/`not`, which use three-valued logic: `(and false null)` is
+//! false and `(or true null)` is true, but `(and true null)` is null.
+//! Missing variables are errors unless `EvalOptions::missing` says
+//! otherwise.
+//!
+//! # Evaluation order
+//!
+//! Arguments are evaluated left to right. `and` stops at the first false
+//! operand and `or` at the first true one; the remaining operands are never
+//! evaluated, so missing variables, custom functions and errors in them
+//! are not reached. `(and (exists tier) (= tier "gold"))` is safe
+//! for any environment. A null operand does not stop either, since a later
+//! operand may still decide the result. `if` evaluates only the branch it
+//! takes. Every other call, including `not`, evaluates all of its
+//! arguments before it runs.
+//!
+//! With `EvalOptions::strict`, `and` and `or` evaluate every operand and
+//! return the first error, which surfaces mistakes in operands that a
+//! particular environment happens to skip. Results without errors are the
+//! same in both modes.
+//!
+//! # Conditionals
+//!
+//! `(if test then else)` is `then` when `test` is true and `else`
+//! otherwise, including when `test` is null, so rules can return a value
+//! such as a bid modifier or a tier label rather than a match. More tests
+//! chain as `(if test1 value1 test2 value2 ... else)`, taking the value of
+//! the first true test; without a final `else` the result is null when no
+//! test is true. A test that is not a boolean or null is a type mismatch.
+//! The parser also reads `(cond (test1 value1) ... (else value))` as the
+//! equivalent `if`.
+//!
+//! # Lists
+//!
+//! `(count xs)`, `(sum xs)`, `(avg xs)`, `(min-of xs)` and `(max-of xs)`
+//! aggregate a list value. `sum` adds like `+`, `avg` is a float and
+//! `min-of`/`max-of` order elements like `<`. A null element makes the
+//! result null, as does an empty list for all but `count` and `sum`.
+//!
+//! `(any xs predicate)` and `(all xs predicate)` evaluate `predicate` once
+//! per element with the element bound to the local `_`, as in
+//! `(any tags (starts-with _ "sports-"))`. They stop at the first element
+//! that decides the result and treat null results like `or` and `and`.
+//!
+//! `(filter xs predicate)` keeps the elements whose predicate is true,
+//! dropping those where it is false or null, and `(map xs value)` collects
+//! `value` for each element. Both build their result like a list literal.
+//! All four also take a named element, as in
+//! `(filter (p purchases) (> (get p "amount") 100))`; see the parser.
+//!
+//! # Strings
+//!
+//! String builtins read the text of strings and symbols through the
+//! interner. A string result is `Value::String` when its text is already
+//! interned and `Value::Text` otherwise, and `=` and membership compare the
+//! two by text.
+//!
+//! `=` compares text exactly, or with the case folding of an interner made
+//! by `StringInterner::with_case_folding`. `(equal-fold a b)` instead
+//! ignores case and Unicode composition: both strings are put in
+//! normalization form C and lowercased before comparing, so `"IPHONE"`
+//! equals `"iPhone"` and a precomposed `"é"` equals `"e"` followed by a
+//! combining accent.
+//!
+//! # Ordering
+//!
+//! `<`, `<=`, `>` and `>=` order numbers by value, integers and floats
+//! alike, and strings lexicographically by the bytes of their text, so
+//! `(and (>= name "a") (< name "n"))` selects names from a to m. Lists
+//! are ordered element by element, with a list that is a prefix of another
+//! ordering first. Operands of different kinds, such as a number and a
+//! string, are a type mismatch, and comparisons involving NaN are false.
+//!
+//! # Arithmetic
+//!
+//! `(+ a b ...)`, `(- a b)` and `(* a b ...)` return an integer when every
+//! operand is an integer, a decimal when the operands mix integers and
+//! decimals, and a float otherwise. Integer and decimal results are exact.
+//! Decimals and floats do not mix: convert with `(decimal x)` first, which
+//! reads a float or numeric string such as `"19.99"` as the decimal it
+//! prints as. `=` and the ordering builtins compare decimals exactly with
+//! integers and other decimals. The `decimal` module describes decimal
+//! literals such as `19.99d`.
+//!
+//! Integer results that do not fit in an `i64` follow
+//! `EvalOptions::overflow`: an `EvalError::Overflow` by default, so
+//! untrusted rules cannot produce silently wrong numbers, or a wrapped or
+//! saturated result if asked. Decimal overflow is always an error.
+//!
+//! # Geo
+//!
+//! Coordinates are numbers in degrees, always given latitude first. A point
+//! is passed as two arguments, `lat lng`, except to `geo-distance`, which
+//! takes `[lat lng]` pairs, and a polygon as a list of such pairs, e.g.
+//! `[[40.70 -74.02] [40.88 -73.93] [40.80 -73.91]]`. Distances are
+//! great-circle distances unless `EvalOptions::distance` selects the
+//! cheaper planar approximation.
+//!
+//! - `(geo_within_radius lat lng center_lat center_lng meters)` compares
+//!   the distance to the center
+//! - `(geo-distance from to)` is the distance in meters between two points,
+//!   as a float, e.g. `(< (geo-distance user store) 5000)`
+//! - `(geo-cell-in cell cells)` tests a geohash or H3 cell against a list
+//!   of cells that may contain it, and `(geohash lat lng precision)`
+//!   computes a geohash. Details are in the `cell` module
+//! - `(geo_within_polygon lat lng polygon)` treats edges as straight lines
+//!   in latitude/longitude, which is accurate for city-sized areas. The
+//!   polygon closes implicitly
+//! - `(geo_within_bbox lat lng south west north east)` includes the edges.
+//!   A box with `west > east` crosses the antimeridian
+//!
+//! # Versions
+//!
+//! `semver-eq`, `semver-gt` and `semver-lt` compare a version string with
+//! another, and `(semver-matches version range)` tests it against a range
+//! such as `">=2.3.0, <3"`. The syntax is described in the `semver`
+//! module.
+//!
+//! # Networks
+//!
+//! `(ip-in-cidr ip "10.0.0.0/8")` tests an IPv4 or IPv6 address against a
+//! CIDR block and `(ip-in-range ip first last)` against an inclusive range
+//! of addresses. Details are in the `net` module.
+//!
+//! # Rollouts
+//!
+//! `(percent-of key salt percent)` is true for a stable `percent` of keys,
+//! which may be strings or integers. The bucketing hash is specified in
+//! the `rollout` module so other services can reproduce it.
+//!
+//! `(experiment-bucket key salt variants weights)` assigns each key one of
+//! a list of variants, in proportion to a list of weights with one
+//! non-negative number per variant, e.g.
+//! `(experiment-bucket user.id "checkout" ["control" "treatment"] [50 50])`.
+//! The `rollout` module shows how to keep experiments in a layer mutually
+//! exclusive.
+//!
+//! # Determinism
+//!
+//! Builtins depend only on their arguments: there is no clock or random
+//! source, and `percent-of` always takes its salt. Float arithmetic is
+//! IEEE 754 everywhere, and a NaN result is always the same NaN. The
+//! functions behind geo distances come from the platform, though, and may
+//! differ in the last bits between x86, ARM and WASM. With
+//! `EvalOptions::deterministic` set they are computed in software instead,
+//! and custom functions not registered as deterministic are refused, so
+//! audit replays reproduce production results bit for bit. A deadline or
+//! cancellation can still stop an evaluation, but never changes the result
+//! of one that finishes.
+
+use crate::cancel::CancelToken;
+use crate::cell;
+use crate::compat::{self, FxHashSet, Instant};
+use crate::compile::{compile, compile_with, CompiledExpr};
+use crate::expr::{Arity, ELEMENT};
+use crate::function::FunctionRegistry;
+use crate::limits::{EvalLimits, Limit};
+use crate::member::MemberSet;
+use crate::metrics::{self, Cache, MetricsSink};
+use crate::net::{self, Cidr, CidrCache};
+use crate::pattern::{compile_regex, literal_pattern, Regex, RegexCache};
+use crate::provider::VariableProvider;
+use crate::rollout;
+use crate::score;
+use crate::semver::{Operand, SemverCache, Version};
+use crate::telemetry;
+use crate::{BuiltinFunction, Decimal, Environment, Expr, Interner, StringId, Value, ValueType};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use core::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+/// Mean Earth radius in meters, used by geo functions
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// Errors that can occur while evaluating an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// Variable is not bound in the environment
+    UnknownVariable(StringId),
+    /// Function name does not match any builtin
+    UnknownFunction(StringId),
+    /// Builtin called with the wrong number of arguments
+    ArityMismatch {
+        function: BuiltinFunction,
+        expected: Arity,
+        found: usize,
+    },
+    /// Argument has a type the builtin cannot operate on
+    TypeMismatch {
+        function: BuiltinFunction,
+        expected: &'static str,
+        found: ValueType,
+    },
+    /// Pattern given to `matches-regex` is not a valid regular expression
+    InvalidRegex(String),
+    /// Version or range given to a `semver-*` builtin does not parse
+    InvalidVersion(String),
+    /// Address or CIDR block given to an `ip-*` builtin does not parse
+    InvalidAddress(String),
+    /// Text or float given to `decimal` is not a decimal
+    InvalidDecimal(String),
+    /// Result of an arithmetic builtin does not fit its type
+    Overflow(BuiltinFunction),
+    /// Error reported by a user-defined function
+    Custom(String),
+    /// Evaluation went past one of `EvalOptions::limits`
+    LimitExceeded(Limit),
+    /// Custom function is not registered as deterministic, but
+    /// `EvalOptions::deterministic` is set
+    Nondeterministic(StringId),
+    /// Cancel token was tripped or the deadline passed
+    Cancelled,
+    /// Scoring expression evaluated to something other than a number
+    NotNumeric(ValueType),
+    /// `eval` was called without `EvalOptions::allow_eval`, or in a
+    /// compiled expression
+    EvalNotAllowed,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UnknownVariable(id) => write!(f, "unknown variable #{}", id.raw()),
+            EvalError::UnknownFunction(id) => write!(f, "unknown function #{}", id.raw()),
+            EvalError::ArityMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` expects {} argument(s), found {}",
+                function.as_str(),
+                expected,
+                found
+            ),
+            EvalError::TypeMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` expects {}, found {:?}",
+                function.as_str(),
+                expected,
+                found
+            ),
+            EvalError::InvalidRegex(message) => write!(f, "invalid regex: {}", message),
+            EvalError::InvalidVersion(message) => write!(f, "invalid version: {}", message),
+            EvalError::InvalidAddress(message) => write!(f, "invalid address: {}", message),
+            EvalError::InvalidDecimal(message) => write!(f, "invalid decimal: {}", message),
+            EvalError::Overflow(function) => {
+                write!(f, "result of `{}` overflows", function.as_str())
+            }
+            EvalError::Custom(message) => f.write_str(message),
+            EvalError::LimitExceeded(limit) => write!(f, "{} exceeded", limit),
+            EvalError::Nondeterministic(id) => {
+                write!(f, "function #{} is not deterministic", id.raw())
+            }
+            EvalError::Cancelled => f.write_str("evaluation cancelled"),
+            EvalError::NotNumeric(found) => {
+                write!(f, "expected a numeric score, found {:?}", found)
+            }
+            EvalError::EvalNotAllowed => f.write_str("`eval` is not allowed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EvalError {}
+
+/// How variables missing from the environment are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MissingVariable {
+    /// Fail with `EvalError::UnknownVariable`
+    #[default]
+    Error,
+    /// Evaluate to `Value::Null`, which propagates through builtins
+    Null,
+    /// Evaluate to `Value::Null`, but any builtin call that would produce
+    /// null produces false instead
+    False,
+}
+
+/// What integer arithmetic does when a result does not fit in an `i64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum IntegerOverflow {
+    /// Fail with `EvalError::Overflow`
+    #[default]
+    Error,
+    /// Wrap around in two's complement
+    Wrap,
+    /// Clamp to `i64::MIN` or `i64::MAX`
+    Saturate,
+}
+
+/// Formula for distances between points on the Earth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DistanceFormula {
+    /// Great-circle distance on a sphere, within about 0.5% of the true
+    /// distance anywhere
+    #[default]
+    Haversine,
+    /// Straight-line distance on an equirectangular projection, which is
+    /// cheaper and close to great-circle distance over city-sized
+    /// distances away from the poles
+    Planar,
+}
+
+/// Options controlling evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct EvalOptions {
+    /// Policy for variables missing from the environment
+    pub missing: MissingVariable,
+    /// Evaluate every operand of `and`/`or` instead of stopping once the
+    /// result is decided
+    pub strict: bool,
+    /// Bounds on depth, steps and string length
+    pub limits: EvalLimits,
+    /// Policy for integer arithmetic that overflows
+    pub overflow: IntegerOverflow,
+    /// Formula for `geo-distance` and `geo_within_radius`
+    pub distance: DistanceFormula,
+    /// Give bit-for-bit the same results on every platform: float
+    /// functions are computed in software instead of by the platform, and
+    /// custom functions not registered as deterministic fail with
+    /// `EvalError::Nondeterministic`
+    pub deterministic: bool,
+    /// Allow `eval` to evaluate quoted expressions, which may come from
+    /// data rather than from the rule's author. Nested evaluations count
+    /// towards the same depth and step limits as the rule calling them
+    pub allow_eval: bool,
+}
+
+impl EvalOptions {
+    /// Apply the missing-variable policy to a variable with no value
+    pub(crate) fn unbound(&self, name: StringId) -> Result<&'static Value, EvalError> {
+        match self.missing {
+            MissingVariable::Error => Err(EvalError::UnknownVariable(name)),
+            _ => Ok(&NULL),
+        }
+    }
+
+    /// Check if null results of builtin calls become false
+    pub(crate) fn null_is_false(&self) -> bool {
+        self.missing == MissingVariable::False
+    }
+
+    /// Apply the missing-variable policy and string length limit to a
+    /// call's result
+    pub(crate) fn finish(&self, result: Value) -> Result<Value, EvalError> {
+        if result.is_null() && self.null_is_false() {
+            return Ok(Value::Bool(false));
+        }
+        self.check_length(&result)?;
+        Ok(result)
+    }
+
+    /// Check a computed string against `limits.max_string_len`
+    #[inline]
+    pub(crate) fn check_length(&self, value: &Value) -> Result<(), EvalError> {
+        match value {
+            Value::Text(text) if text.len() > self.limits.max_string_len => {
+                Err(EvalError::LimitExceeded(Limit::StringLength))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+static NULL: Value = Value::Null;
+
+/// Evaluates expressions, resolving function names through an interner
+#[derive(Debug, Clone)]
+pub struct Evaluator<'a> {
+    interner: &'a dyn Interner,
+    options: EvalOptions,
+    /// Literal `matches-regex` patterns compiled so far
+    regexes: RegexCache,
+    /// Literal `semver-*` versions and ranges parsed so far
+    versions: SemverCache,
+    /// Literal `ip-in-cidr` blocks parsed so far
+    cidrs: CidrCache,
+    /// Functions called by names that are not builtins
+    functions: Option<&'a FunctionRegistry>,
+    /// Checked at call boundaries to stop evaluation early
+    cancel: Option<CancelToken>,
+    /// Receives counters and timings, if any are wanted
+    metrics: Option<&'a dyn MetricsSink>,
+    /// Asked for variables missing from the environment
+    provider: Option<&'a dyn VariableProvider>,
+}
+
+impl<'a> Evaluator<'a> {
+    /// Create an evaluator using the interner the expressions were built with
+    pub fn new(interner: &'a dyn Interner) -> Self {
+        Self::with_options(interner, EvalOptions::default())
+    }
+
+    /// Create an evaluator with non-default options
+    pub fn with_options(interner: &'a dyn Interner, options: EvalOptions) -> Self {
+        Self {
+            interner,
+            options,
+            regexes: RegexCache::default(),
+            versions: SemverCache::default(),
+            cidrs: CidrCache::default(),
+            functions: None,
+            cancel: None,
+            metrics: None,
+            provider: None,
+        }
+    }
+
+    /// Resolve calls to names that are not builtins through `functions`
+    pub fn with_functions(mut self, functions: &'a FunctionRegistry) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
+    /// Stop evaluations with `EvalError::Cancelled` once `token` is
+    /// cancelled
+    pub fn with_cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Report evaluations, builtin calls, cache lookups and short circuits
+    /// to `metrics`
+    pub fn with_metrics(mut self, metrics: &'a dyn MetricsSink) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Ask `provider` for variables missing from the environment when an
+    /// expression reads them
+    pub fn with_provider(mut self, provider: &'a dyn VariableProvider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Get the options this evaluator uses
+    pub fn options(&self) -> &EvalOptions {
+        &self.options
+    }
+
+    /// Get the interner this evaluator resolves names through
+    pub(crate) fn interner(&self) -> &'a dyn Interner {
+        self.interner
+    }
+
+    /// Evaluate an expression against an environment
+    pub fn eval(&self, expr: &Expr, env: &Environment) -> Result<Value, EvalError> {
+        self.eval_observed(expr, env, &mut NoObserver)
+    }
+
+    /// Evaluate an expression whose result is a number, such as a weighted
+    /// score, as an `f64`
+    ///
+    /// Fails with `EvalError::NotNumeric` if the result is not an integer,
+    /// float or decimal.
+    pub fn eval_score(&self, expr: &Expr, env: &Environment) -> Result<f64, EvalError> {
+        score::to_score(self.eval(expr, env)?)
+    }
+
+    /// Evaluate an expression, failing with `EvalError::Cancelled` if it
+    /// is still running at `deadline`
+    ///
+    /// The clock is read at call boundaries, so a custom function that
+    /// blocks past the deadline fails the evaluation once it returns.
+    #[cfg(feature = "std")]
+    pub fn eval_with_deadline(
+        &self,
+        expr: &Expr,
+        env: &Environment,
+        deadline: Instant,
+    ) -> Result<Value, EvalError> {
+        self.eval_until(expr, env, &mut NoObserver, Some(deadline))
+    }
+
+    /// Evaluate an expression, reporting every evaluated subexpression to
+    /// `observer`
+    pub(crate) fn eval_observed<'e, O: Observer<'e>>(
+        &self,
+        expr: &'e Expr,
+        env: &Environment,
+        observer: &mut O,
+    ) -> Result<Value, EvalError> {
+        self.eval_until(expr, env, observer, None)
+    }
+
+    fn eval_until<'e, O: Observer<'e>>(
+        &self,
+        expr: &'e Expr,
+        env: &Environment,
+        observer: &mut O,
+        deadline: Option<Instant>,
+    ) -> Result<Value, EvalError> {
+        let mut walk = Walk {
+            observer,
+            depth: 0,
+            steps: 0,
+            evals: 0,
+            deadline,
+            locals: Vec::new(),
+        };
+        let span = telemetry::eval();
+        let result = match self.metrics {
+            None => self.walk(expr, env, &mut walk),
+            Some(metrics) => {
+                let start = compat::now();
+                let result = self.walk(expr, env, &mut walk);
+                metrics.evaluation(compat::elapsed(start), &result);
+                result
+            }
+        };
+        span.result(&result);
+        result
+    }
+
+    /// Fail if the cancel token is tripped or the deadline has passed
+    fn check_cancelled<O>(&self, walk: &Walk<'_, O>) -> Result<(), EvalError> {
+        let cancelled = self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+            || walk.deadline.is_some_and(compat::deadline_passed);
+        if cancelled {
+            Err(EvalError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn walk<'e, O: Observer<'e>>(
+        &self,
+        expr: &'e Expr,
+        env: &Environment,
+        walk: &mut Walk<'_, O>,
+    ) -> Result<Value, EvalError> {
+        if let Some(value) = walk.observer.recall(expr) {
+            return Ok(value);
+        }
+        walk.observer.enter();
+        walk.depth += 1;
+        walk.steps += 1;
+        let limits = &self.options.limits;
+        let result = if walk.depth > limits.max_depth {
+            Err(EvalError::LimitExceeded(Limit::Depth))
+        } else if walk.steps > limits.max_steps {
+            Err(EvalError::LimitExceeded(Limit::Steps))
+        } else {
+            self.eval_node(expr, env, walk)
+        };
+        walk.depth -= 1;
+        walk.observer.exit(expr, &result);
+        result
+    }
+
+    fn eval_node<'e, O: Observer<'e>>(
+        &self,
+        expr: &'e Expr,
+        env: &Environment,
+        walk: &mut Walk<'_, O>,
+    ) -> Result<Value, EvalError> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Variable(name) => match walk.local(*name) {
+                Some(value) => Ok(value.clone()),
+                None => match self.variable(env, *name) {
+                    Some(value) => Ok(value),
+                    None => self.options.unbound(*name).cloned(),
+                },
+            },
+            Expr::List(items) => {
+                let values = items
+                    .iter()
+                    .map(|item| self.walk(item, env, walk))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(make_list(&values))
+            }
+            Expr::Call { function, args } => {
+                self.check_cancelled(walk)?;
+                if let Some(builtin) = self.interner.builtin(*function) {
+                    check_arity(builtin, args.len())?;
+                    return self.eval_builtin(builtin, args, env, walk);
+                }
+                let name = self.interner.resolve(*function);
+                let (name, functions) = name
+                    .zip(self.functions)
+                    .ok_or(EvalError::UnknownFunction(*function))?;
+                let custom = functions
+                    .get(name)
+                    .ok_or(EvalError::UnknownFunction(*function))?;
+                if self.options.deterministic && !functions.is_deterministic(name) {
+                    return Err(EvalError::Nondeterministic(*function));
+                }
+                let values = args
+                    .iter()
+                    .map(|arg| self.walk(arg, env, walk))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let result = custom(&values)?;
+                self.check_cancelled(walk)?;
+                self.options.finish(result)
+            }
+            // Metadata does not count as a level of nesting
+            Expr::Annotated { expr, .. } => self.eval_node(expr, env, walk),
+            Expr::Let { bindings, body } => {
+                let scope = walk.locals.len();
+                let result = self.eval_let(bindings, body, env, walk);
+                walk.locals.truncate(scope);
+                result
+            }
+        }
+    }
+
+    /// Bind each local in turn, then evaluate the body with them in scope
+    fn eval_let<'e, O: Observer<'e>>(
+        &self,
+        bindings: &'e [(StringId, Expr)],
+        body: &'e Expr,
+        env: &Environment,
+        walk: &mut Walk<'_, O>,
+    ) -> Result<Value, EvalError> {
+        for (name, value) in bindings {
+            let value = self.walk(value, env, walk)?;
+            walk.locals.push((*name, value));
+        }
+        self.walk(body, env, walk)
+    }
+
+    /// Evaluate one expression against many environments
+    ///
+    /// The expression is compiled once, resolving function names and folding
+    /// constant subexpressions, and the compiled form is run per environment.
+    /// The cancel token is checked between environments.
+    pub fn eval_batch(&self, expr: &Expr, envs: &[Environment]) -> Vec<Result<Value, EvalError>> {
+        let compiled = self.compile_batch(expr);
+        envs.iter()
+            .map(|env| self.eval_compiled(&compiled, env))
+            .collect()
+    }
+
+    /// Compile an expression for batch evaluation with this evaluator's
+    /// functions
+    pub(crate) fn compile_batch(&self, expr: &Expr) -> CompiledExpr {
+        match self.functions {
+            Some(functions) => compile_with(expr, self.interner, functions),
+            None => compile(expr, self.interner),
+        }
+    }
+
+    /// Run a batch-compiled expression against one environment of a batch
+    pub(crate) fn eval_compiled(
+        &self,
+        compiled: &CompiledExpr,
+        env: &Environment,
+    ) -> Result<Value, EvalError> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(EvalError::Cancelled);
+        }
+        let lookup = |slot| env.get(compiled.variables()[slot]);
+        let Some(metrics) = self.metrics else {
+            return compiled.run(lookup, self.interner, &self.options, None, self.provider);
+        };
+        let start = compat::now();
+        let result = compiled.run(
+            lookup,
+            self.interner,
+            &self.options,
+            Some(metrics),
+            self.provider,
+        );
+        metrics.evaluation(compat::elapsed(start), &result);
+        result
+    }
+
+    /// Get a variable from the environment, or failing that the provider
+    fn variable(&self, env: &Environment, name: StringId) -> Option<Value> {
+        match env.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.provider?.get(name),
+        }
+    }
+
+    fn eval_builtin<'e, O: Observer<'e>>(
+        &self,
+        function: BuiltinFunction,
+        args: &'e [Expr],
+        env: &Environment,
+        walk: &mut Walk<'_, O>,
+    ) -> Result<Value, EvalError> {
+        match function {
+            BuiltinFunction::Quote => Ok(Value::Quoted(Box::new(args[0].clone()))),
+            BuiltinFunction::Eval => {
+                if !self.options.allow_eval {
+                    return Err(EvalError::EvalNotAllowed);
+                }
+                let expr = match self.walk(&args[0], env, walk)? {
+                    Value::Quoted(expr) => expr,
+                    Value::Null => return Ok(Value::Null),
+                    other => return Err(type_mismatch(function, "quoted expression", &other)),
+                };
+                if walk.evals >= self.options.limits.max_eval_depth {
+                    return Err(EvalError::LimitExceeded(Limit::EvalDepth));
+                }
+                // The quoted expression sees the environment but not the
+                // caller's locals, and observers do not see inside it
+                let mut inner = Walk {
+                    observer: &mut NoObserver,
+                    depth: walk.depth,
+                    steps: walk.steps,
+                    evals: walk.evals + 1,
+                    deadline: walk.deadline,
+                    locals: Vec::new(),
+                };
+                let result = self.walk(&expr, env, &mut inner);
+                walk.steps = inner.steps;
+                result
+            }
+            BuiltinFunction::And | BuiltinFunction::Or => {
+                let mut result = junction_identity(function);
+                let mut decided = false;
+                for (i, arg) in args.iter().enumerate() {
+                    let operand = self.walk(arg, env, walk)?;
+                    match junction_step(function, &operand, self.options.null_is_false())? {
+                        JunctionStep::Undecided => {}
+                        JunctionStep::Null => result = Value::Null,
+                        JunctionStep::Decided if self.options.strict => decided = true,
+                        JunctionStep::Decided => {
+                            if let Some(metrics) = self.metrics {
+                                metrics.short_circuit(function, i + 1);
+                            }
+                            return Ok(junction_decided(function));
+                        }
+                    }
+                }
+                if decided {
+                    Ok(junction_decided(function))
+                } else {
+                    Ok(result)
+                }
+            }
+            BuiltinFunction::Any
+            | BuiltinFunction::All
+            | BuiltinFunction::Filter
+            | BuiltinFunction::Map => {
+                let list = self.walk(&args[0], env, walk)?;
+                if list.is_null() {
+                    return self.options.finish(Value::Null);
+                }
+                let element = self.interner.get_id(ELEMENT);
+                let mut fold = ElementFold::new(function);
+                for (i, item) in list_items(function, &list)?.enumerate() {
+                    // The second argument reads the element as the local `_`
+                    let scope = walk.locals.len();
+                    if let Some(element) = element {
+                        walk.locals.push((element, item.clone()));
+                    }
+                    let value = self.walk(&args[1], env, walk);
+                    walk.locals.truncate(scope);
+                    if fold.step(item, &value?, &self.options)? {
+                        if let Some(metrics) = self.metrics {
+                            metrics.short_circuit(function, i + 1);
+                        }
+                        break;
+                    }
+                }
+                Ok(fold.finish())
+            }
+            BuiltinFunction::If => {
+                for clause in args.chunks(2) {
+                    match clause {
+                        [test, value] => {
+                            let test = self.walk(test, env, walk)?;
+                            if branch(&test)? {
+                                return self.walk(value, env, walk);
+                            }
+                        }
+                        [otherwise] => return self.walk(otherwise, env, walk),
+                        _ => unreachable!("chunks of at most two"),
+                    }
+                }
+                Ok(Value::Null)
+            }
+            BuiltinFunction::Exists | BuiltinFunction::IsNull => {
+                // A missing variable is null here regardless of the policy
+                let value = match &args[0] {
+                    arg @ Expr::Variable(name) => {
+                        walk.observer.enter();
+                        let value = match walk.local(*name) {
+                            Some(value) => Some(value.clone()),
+                            None => self.variable(env, *name),
+                        };
+                        let value = Ok(value.unwrap_or(Value::Null));
+                        walk.observer.exit(arg, &value);
+                        value?
+                    }
+                    arg => self.walk(arg, env, walk)?,
+                };
+                metrics::timed(self.metrics, function, || {
+                    apply(function, &[value], self.interner, &self.options)
+                })
+            }
+            BuiltinFunction::MatchesRegex if literal_pattern(&args[1]).is_some() => {
+                let value = self.walk(&args[0], env, walk)?;
+                self.walk(&args[1], env, walk)?;
+                let id = literal_pattern(&args[1]).expect("literal pattern");
+                let pattern = self.interner.resolve(id).unwrap_or_default();
+                if let Some(metrics) = self.metrics {
+                    metrics.cache(Cache::Regex, self.regexes.contains(id));
+                }
+                let result = metrics::timed(self.metrics, function, || {
+                    let regex = self.regexes.get_or_compile(id, pattern)?;
+                    match_regex(&value, &regex, self.interner)
+                });
+                self.options.finish(result?)
+            }
+            BuiltinFunction::SemverEq
+            | BuiltinFunction::SemverGt
+            | BuiltinFunction::SemverLt
+            | BuiltinFunction::SemverMatches
+                if literal_pattern(&args[1]).is_some() =>
+            {
+                let value = self.walk(&args[0], env, walk)?;
+                self.walk(&args[1], env, walk)?;
+                let id = literal_pattern(&args[1]).expect("literal operand");
+                let text = self.interner.resolve(id).unwrap_or_default();
+                if let Some(metrics) = self.metrics {
+                    metrics.cache(Cache::Version, self.versions.contains(function, id));
+                }
+                let result = metrics::timed(self.metrics, function, || {
+                    let operand = self.versions.get_or_parse(function, id, text)?;
+                    match_version(function, &value, &operand, self.interner)
+                });
+                self.options.finish(result?)
+            }
+            BuiltinFunction::IpInCidr if literal_pattern(&args[1]).is_some() => {
+                let value = self.walk(&args[0], env, walk)?;
+                self.walk(&args[1], env, walk)?;
+                let id = literal_pattern(&args[1]).expect("literal block");
+                let text = self.interner.resolve(id).unwrap_or_default();
+                if let Some(metrics) = self.metrics {
+                    metrics.cache(Cache::Cidr, self.cidrs.contains(id));
+                }
+                let result = metrics::timed(self.metrics, function, || {
+                    let cidr = self.cidrs.get_or_parse(id, text)?;
+                    match_cidr(&value, &cidr, self.interner)
+                });
+                self.options.finish(result?)
+            }
+            _ => {
+                let values = args
+                    .iter()
+                    .map(|arg| self.walk(arg, env, walk))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let result = metrics::timed(self.metrics, function, || {
+                    apply(function, &values, self.interner, &self.options)
+                });
+                self.options.finish(result?)
+            }
+        }
+    }
+}
+
+/// Receives each subexpression as the tree walker evaluates it
+///
+/// `enter` is called before a subexpression is evaluated and `exit` with
+/// its result afterwards, so calls nest like the evaluation itself. A
+/// subexpression `recall` has a value for is not evaluated, entered or
+/// exited at all.
+pub(crate) trait Observer<'e> {
+    fn recall(&mut self, _expr: &'e Expr) -> Option<Value> {
+        None
+    }
+    fn enter(&mut self);
+    fn exit(&mut self, expr: &'e Expr, result: &Result<Value, EvalError>);
+}
+
+/// State threaded through one tree-walking evaluation
+struct Walk<'w, O> {
+    observer: &'w mut O,
+    /// Nesting of the subexpression being evaluated
+    depth: usize,
+    /// Subexpressions evaluated so far
+    steps: usize,
+    /// Nesting of `eval` calls
+    evals: usize,
+    deadline: Option<Instant>,
+    /// Locals bound by the enclosing `let`s, innermost last
+    locals: Vec<(StringId, Value)>,
+}
+
+impl<O> Walk<'_, O> {
+    /// Get the innermost local bound to `name`
+    fn local(&self, name: StringId) -> Option<&Value> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|(local, _)| *local == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Observer used by plain evaluation
+struct NoObserver;
+
+impl Observer<'_> for NoObserver {
+    #[inline(always)]
+    fn enter(&mut self) {}
+
+    #[inline(always)]
+    fn exit(&mut self, _expr: &Expr, _result: &Result<Value, EvalError>) {}
+}
+
+/// Result of `and`/`or`, or `all`/`any`, with no operands
+pub(crate) fn junction_identity(function: BuiltinFunction) -> Value {
+    Value::Bool(matches!(
+        function,
+        BuiltinFunction::And | BuiltinFunction::All
+    ))
+}
+
+/// Result of `and`/`or`, or `all`/`any`, once an operand decides it
+pub(crate) fn junction_decided(function: BuiltinFunction) -> Value {
+    Value::Bool(matches!(
+        function,
+        BuiltinFunction::Or | BuiltinFunction::Any
+    ))
+}
+
+/// Effect of one operand on the running result of `and`/`or`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JunctionStep {
+    /// The result is unchanged
+    Undecided,
+    /// The result becomes null unless it is later decided
+    Null,
+    /// The result is `true` for `or` and `false` for `and`, and remaining
+    /// operands must not be evaluated
+    Decided,
+}
+
+/// Classify one operand of `and`/`or`, or one predicate result of
+/// `all`/`any`
+///
+/// `and` and `all` are decided by a false operand and `or` and `any` by a
+/// true one. A null operand counts as false when `null_is_false` is set.
+#[inline]
+pub(crate) fn junction_step(
+    function: BuiltinFunction,
+    operand: &Value,
+    null_is_false: bool,
+) -> Result<JunctionStep, EvalError> {
+    let decisive = matches!(function, BuiltinFunction::Or | BuiltinFunction::Any);
+    match operand {
+        Value::Bool(b) if *b == decisive => Ok(JunctionStep::Decided),
+        Value::Bool(_) => Ok(JunctionStep::Undecided),
+        Value::Null if null_is_false => {
+            if decisive {
+                Ok(JunctionStep::Undecided)
+            } else {
+                Ok(JunctionStep::Decided)
+            }
+        }
+        Value::Null => Ok(JunctionStep::Null),
+        other => Err(type_mismatch(function, "boolean", other)),
+    }
+}
+
+/// Check if the test of an `if` clause takes its value, which a null test
+/// does not
+#[inline]
+pub(crate) fn branch(test: &Value) -> Result<bool, EvalError> {
+    match test {
+        Value::Bool(b) => Ok(*b),
+        Value::Null => Ok(false),
+        other => Err(type_mismatch(BuiltinFunction::If, "boolean", other)),
+    }
+}
+
+/// Running result of `any`, `all`, `filter` or `map`, fed the value of
+/// the second argument for each element in turn
+pub(crate) struct ElementFold {
+    function: BuiltinFunction,
+    result: Value,
+    decided: bool,
+    items: Vec<Value>,
+}
+
+impl ElementFold {
+    pub(crate) fn new(function: BuiltinFunction) -> Self {
+        Self {
+            function,
+            result: junction_identity(function),
+            decided: false,
+            items: Vec::new(),
+        }
+    }
+
+    /// Feed the value computed for `item`, returning true once the result
+    /// is decided and remaining elements must not be evaluated
+    pub(crate) fn step(
+        &mut self,
+        item: Value,
+        value: &Value,
+        options: &EvalOptions,
+    ) -> Result<bool, EvalError> {
+        match self.function {
+            BuiltinFunction::Filter => match value {
+                Value::Bool(true) => self.items.push(item),
+                Value::Bool(false) | Value::Null => {}
+                other => return Err(type_mismatch(self.function, "boolean", other)),
+            },
+            BuiltinFunction::Map => self.items.push(value.clone()),
+            function => match junction_step(function, value, options.null_is_false())? {
+                JunctionStep::Undecided => {}
+                JunctionStep::Null => self.result = Value::Null,
+                JunctionStep::Decided => {
+                    self.decided = true;
+                    return Ok(!options.strict);
+                }
+            },
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn finish(self) -> Value {
+        match self.function {
+            BuiltinFunction::Filter | BuiltinFunction::Map => make_list(&self.items),
+            function if self.decided => junction_decided(function),
+            _ => self.result,
+        }
+    }
+}
+
+/// Check if calls to a builtin with constant arguments may be evaluated
+/// ahead of time with the default options, which distances may not
+pub(crate) fn foldable(function: BuiltinFunction) -> bool {
+    !matches!(
+        function,
+        BuiltinFunction::GeoDistance | BuiltinFunction::GeoWithinRadius
+    )
+}
+
+/// Check that a builtin accepts `found` arguments
+pub(crate) fn check_arity(function: BuiltinFunction, found: usize) -> Result<(), EvalError> {
+    let expected = function.arity();
+    if expected.accepts(found) {
+        Ok(())
+    } else {
+        Err(EvalError::ArityMismatch {
+            function,
+            expected,
+            found,
+        })
+    }
+}
+
+/// Apply a builtin to already evaluated arguments
+///
+/// `interner` resolves the text of string arguments. String results that
+/// are already interned are returned as `Value::String`, others as
+/// `Value::Text`.
+pub(crate) fn apply<V: Borrow<Value>>(
+    function: BuiltinFunction,
+    args: &[V],
+    interner: &dyn Interner,
+    options: &EvalOptions,
+) -> Result<Value, EvalError> {
+    if propagates_null(function) && args.iter().any(|arg| arg.borrow().is_null()) {
+        return Ok(Value::Null);
+    }
+
+    match function {
+        // Arguments are already values here, so only a literal was quoted
+        BuiltinFunction::Quote => {
+            let [value] = expect_args(function, args)?;
+            Ok(Value::Quoted(Box::new(Expr::Literal(value.clone()))))
+        }
+        BuiltinFunction::Eval => Err(EvalError::EvalNotAllowed),
+        BuiltinFunction::And | BuiltinFunction::Or => {
+            let mut result = junction_identity(function);
+            let mut decided = false;
+            for arg in args {
+                // Operands after the deciding one are still type checked
+                match junction_step(function, arg.borrow(), false)? {
+                    JunctionStep::Undecided => {}
+                    JunctionStep::Null => result = Value::Null,
+                    JunctionStep::Decided => decided = true,
+                }
+            }
+            if decided {
+                Ok(junction_decided(function))
+            } else {
+                Ok(result)
+            }
+        }
+        BuiltinFunction::Not => {
+            let [value] = expect_args(function, args)?;
+            Ok(match value {
+                Value::Null => Value::Null,
+                other => Value::Bool(!truthy(function, other)?),
+            })
+        }
+        BuiltinFunction::If => {
+            for clause in args.chunks(2) {
+                match clause {
+                    [test, value] if branch(test.borrow())? => return Ok(value.borrow().clone()),
+                    [otherwise] => return Ok(otherwise.borrow().clone()),
+                    _ => {}
+                }
+            }
+            Ok(Value::Null)
+        }
+        BuiltinFunction::Exists => {
+            let [value] = expect_args(function, args)?;
+            Ok(Value::Bool(!value.is_null()))
+        }
+        BuiltinFunction::IsNull => {
+            let [value] = expect_args(function, args)?;
+            Ok(Value::Bool(value.is_null()))
+        }
+        BuiltinFunction::Equal => {
+            let [a, b] = expect_args(function, args)?;
+            Ok(Value::Bool(values_equal(a, b, interner)))
+        }
+        BuiltinFunction::NotEqual => {
+            let [a, b] = expect_args(function, args)?;
+            Ok(Value::Bool(!values_equal(a, b, interner)))
+        }
+        BuiltinFunction::LessThan
+        | BuiltinFunction::LessThanOrEqual
+        | BuiltinFunction::GreaterThan
+        | BuiltinFunction::GreaterThanOrEqual => {
+            let [a, b] = expect_args(function, args)?;
+            let ordering = compare_values(function, a, b, interner)?;
+            Ok(Value::Bool(match function {
+                BuiltinFunction::LessThan => ordering == Some(Ordering::Less),
+                BuiltinFunction::LessThanOrEqual => {
+                    matches!(ordering, Some(Ordering::Less | Ordering::Equal))
+                }
+                BuiltinFunction::GreaterThan => ordering == Some(Ordering::Greater),
+                _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            }))
+        }
+        BuiltinFunction::In => {
+            let [item, list] = expect_args(function, args)?;
+            Ok(Value::Bool(contains(function, list, item, interner)?))
+        }
+        BuiltinFunction::NotIn => {
+            let [item, list] = expect_args(function, args)?;
+            Ok(Value::Bool(!contains(function, list, item, interner)?))
+        }
+        BuiltinFunction::OneOf => {
+            let [items, list] = expect_args(function, args)?;
+            Ok(Value::Bool(any_contained(function, items, list, interner)?))
+        }
+        BuiltinFunction::AllOf => {
+            let [items, required] = expect_args(function, args)?;
+            for value in elements(required) {
+                if !contains(function, items, &value, interner)? {
+                    return Ok(Value::Bool(false));
+                }
+            }
+            Ok(Value::Bool(true))
+        }
+        BuiltinFunction::NoneOf => {
+            let [items, list] = expect_args(function, args)?;
+            Ok(Value::Bool(!any_contained(
+                function, items, list, interner,
+            )?))
+        }
+        BuiltinFunction::GeoWithinRadius => {
+            let [lat, lng, center_lat, center_lng, radius] = expect_args(function, args)?;
+            let distance = options.distance.distance(
+                (number(function, lat)?, number(function, lng)?),
+                (number(function, center_lat)?, number(function, center_lng)?),
+                Trig::of(options),
+            );
+            Ok(Value::Bool(distance <= number(function, radius)?))
+        }
+        BuiltinFunction::GeoCellIn => {
+            let [cell, cells] = expect_args(function, args)?;
+            if list_len(cells).is_none() {
+                return Err(type_mismatch(function, "list", cells));
+            }
+            for ancestor in cell::ancestors(cell, interner) {
+                if contains(function, cells, &ancestor, interner)? {
+                    return Ok(Value::Bool(true));
+                }
+            }
+            Ok(Value::Bool(false))
+        }
+        BuiltinFunction::Geohash => {
+            let [lat, lng, precision] = expect_args(function, args)?;
+            let (lat, lng) = (number(function, lat)?, number(function, lng)?);
+            if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+                return Err(EvalError::TypeMismatch {
+                    function,
+                    expected: "coordinates in degrees",
+                    found: ValueType::Float,
+                });
+            }
+            let precision = match precision {
+                Value::Integer(n) if (1..=cell::MAX_PRECISION).contains(n) => *n as usize,
+                other => return Err(type_mismatch(function, "precision from 1 to 12", other)),
+            };
+            let hash = cell::geohash(lat, lng, precision);
+            Ok(string_value(Cow::Owned(hash), interner))
+        }
+        BuiltinFunction::GeoDistance => {
+            const EXPECTED: &str = "[lat lng] pair";
+            let [from, to] = expect_args(function, args)?;
+            let from = point(function, from, EXPECTED)?;
+            let to = point(function, to, EXPECTED)?;
+            Ok(Value::Float(options.distance.distance(
+                from,
+                to,
+                Trig::of(options),
+            )))
+        }
+        BuiltinFunction::GeoWithinPolygon => {
+            let [lat, lng, polygon] = expect_args(function, args)?;
+            let point = (number(function, lat)?, number(function, lng)?);
+            Ok(Value::Bool(point_in_polygon(
+                point,
+                &polygon_vertices(function, polygon)?,
+            )))
+        }
+        BuiltinFunction::GeoWithinBbox => {
+            let [lat, lng, south, west, north, east] = expect_args(function, args)?;
+            let (lat, lng) = (number(function, lat)?, number(function, lng)?);
+            let (west, east) = (number(function, west)?, number(function, east)?);
+            let within_lat = number(function, south)? <= lat && lat <= number(function, north)?;
+            let within_lng = if west <= east {
+                west <= lng && lng <= east
+            } else {
+                lng >= west || lng <= east
+            };
+            Ok(Value::Bool(within_lat && within_lng))
+        }
+        BuiltinFunction::StartsWith => {
+            let [value, prefix] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let prefix = text(function, prefix, interner)?;
+            Ok(Value::Bool(
+                interner.case_folding().starts_with(value, prefix),
+            ))
+        }
+        BuiltinFunction::EndsWith => {
+            let [value, suffix] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let suffix = text(function, suffix, interner)?;
+            Ok(Value::Bool(
+                interner.case_folding().ends_with(value, suffix),
+            ))
+        }
+        BuiltinFunction::Contains => {
+            let [value, needle] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let needle = text(function, needle, interner)?;
+            Ok(Value::Bool(interner.case_folding().contains(value, needle)))
+        }
+        BuiltinFunction::Add | BuiltinFunction::Subtract | BuiltinFunction::Multiply => {
+            let mut args = args.iter().map(Borrow::borrow);
+            let first = args.next().expect("arithmetic has at least two arguments");
+            args.try_fold(first.clone(), |result, arg| {
+                arithmetic(function, &result, arg, options.overflow)
+            })
+        }
+        BuiltinFunction::Decimal => {
+            let [value] = expect_args(function, args)?;
+            Ok(Value::Decimal(to_decimal(function, value, interner)?))
+        }
+        BuiltinFunction::Count => {
+            let [list] = expect_args(function, args)?;
+            Ok(Value::Integer(list_items(function, list)?.count() as i64))
+        }
+        BuiltinFunction::Sum => {
+            let [list] = expect_args(function, args)?;
+            let mut sum = Value::Integer(0);
+            for item in list_items(function, list)? {
+                if item.is_null() {
+                    return Ok(Value::Null);
+                }
+                sum = arithmetic(function, &sum, &item, options.overflow)?;
+            }
+            Ok(sum)
+        }
+        BuiltinFunction::Avg => {
+            let [list] = expect_args(function, args)?;
+            let (mut total, mut count) = (0.0, 0);
+            for item in list_items(function, list)? {
+                if item.is_null() {
+                    return Ok(Value::Null);
+                }
+                total += number(function, &item)?;
+                count += 1;
+            }
+            Ok(match count {
+                0 => Value::Null,
+                _ => float(total / count as f64),
+            })
+        }
+        BuiltinFunction::MinOf | BuiltinFunction::MaxOf => {
+            let [list] = expect_args(function, args)?;
+            let wanted = match function {
+                BuiltinFunction::MinOf => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            let mut best: Option<Value> = None;
+            for item in list_items(function, list)? {
+                if item.is_null() {
+                    return Ok(Value::Null);
+                }
+                best = match best {
+                    Some(current)
+                        if compare_values(function, &item, &current, interner)? != Some(wanted) =>
+                    {
+                        Some(current)
+                    }
+                    _ => Some(item),
+                };
+            }
+            Ok(best.unwrap_or(Value::Null))
+        }
+        BuiltinFunction::Any
+        | BuiltinFunction::All
+        | BuiltinFunction::Filter
+        | BuiltinFunction::Map => {
+            // The second argument was evaluated once, so it does not read
+            // the element and has the same value for each
+            let [list, value] = expect_args(function, args)?;
+            if list.is_null() {
+                return Ok(Value::Null);
+            }
+            let mut fold = ElementFold::new(function);
+            for item in list_items(function, list)? {
+                if fold.step(item, value, options)? {
+                    break;
+                }
+            }
+            Ok(fold.finish())
+        }
+        BuiltinFunction::EqualFold => {
+            let [a, b] = expect_args(function, args)?;
+            let a = text(function, a, interner)?;
+            let b = text(function, b, interner)?;
+            Ok(Value::Bool(a == b || folded(a).eq(folded(b))))
+        }
+        BuiltinFunction::Lowercase => {
+            let [value] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let lower = if value.chars().flat_map(char::to_lowercase).eq(value.chars()) {
+                Cow::Borrowed(value)
+            } else {
+                Cow::Owned(value.to_lowercase())
+            };
+            Ok(string_value(lower, interner))
+        }
+        BuiltinFunction::Uppercase => {
+            let [value] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let upper = if value.chars().flat_map(char::to_uppercase).eq(value.chars()) {
+                Cow::Borrowed(value)
+            } else {
+                Cow::Owned(value.to_uppercase())
+            };
+            Ok(string_value(upper, interner))
+        }
+        BuiltinFunction::Trim => {
+            let [value] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            Ok(string_value(Cow::Borrowed(value.trim()), interner))
+        }
+        BuiltinFunction::Concat => {
+            let parts = args
+                .iter()
+                .map(|arg| text(function, arg.borrow(), interner))
+                .collect::<Result<Vec<_>, _>>()?;
+            let joined = match parts.as_slice() {
+                [part] => Cow::Borrowed(*part),
+                parts => Cow::Owned(parts.concat()),
+            };
+            Ok(string_value(joined, interner))
+        }
+        BuiltinFunction::StringLength => {
+            let [value] = expect_args(function, args)?;
+            let length = text(function, value, interner)?.chars().count();
+            Ok(Value::Integer(length as i64))
+        }
+        BuiltinFunction::Substring => {
+            let [value, start, end] = expect_args(function, args)?;
+            let value = text(function, value, interner)?;
+            let start = char_offset(value, index(function, start)?);
+            let end = char_offset(value, index(function, end)?).max(start);
+            Ok(string_value(Cow::Borrowed(&value[start..end]), interner))
+        }
+        BuiltinFunction::MatchesRegex => {
+            let [value, pattern] = expect_args(function, args)?;
+            let regex = compile_regex(text(function, pattern, interner)?)?;
+            match_regex(value, &regex, interner)
+        }
+        BuiltinFunction::Get => {
+            let [map, key] = expect_args(function, args)?;
+            let entry = map_entry(function, map, key, interner)?;
+            Ok(entry.cloned().unwrap_or(Value::Null))
+        }
+        BuiltinFunction::HasKey => {
+            let [map, key] = expect_args(function, args)?;
+            Ok(Value::Bool(
+                map_entry(function, map, key, interner)?.is_some(),
+            ))
+        }
+        BuiltinFunction::SemverEq
+        | BuiltinFunction::SemverGt
+        | BuiltinFunction::SemverLt
+        | BuiltinFunction::SemverMatches => {
+            let [value, operand] = expect_args(function, args)?;
+            let operand = Operand::parse(function, text(function, operand, interner)?)?;
+            match_version(function, value, &operand, interner)
+        }
+        BuiltinFunction::IpInCidr => {
+            let [value, block] = expect_args(function, args)?;
+            let cidr = Cidr::parse(text(function, block, interner)?)?;
+            match_cidr(value, &cidr, interner)
+        }
+        BuiltinFunction::IpInRange => {
+            let [value, first, last] = expect_args(function, args)?;
+            let ip = |value| text(function, value, interner).and_then(net::parse_ip);
+            Ok(Value::Bool(net::in_range(
+                ip(value)?,
+                ip(first)?,
+                ip(last)?,
+            )))
+        }
+        BuiltinFunction::PercentOf => {
+            let [key, salt, percent] = expect_args(function, args)?;
+            let bucket = bucket(function, key, salt, interner)?;
+            Ok(Value::Bool(rollout::in_rollout(
+                bucket,
+                number(function, percent)?,
+            )))
+        }
+        BuiltinFunction::ExperimentBucket => {
+            const EXPECTED: &str = "one non-negative weight per variant";
+            let [key, salt, variants, weight_list] = expect_args(function, args)?;
+            let bucket = bucket(function, key, salt, interner)?;
+            if list_len(variants).is_none() {
+                return Err(type_mismatch(function, "list", variants));
+            }
+            let weights = match list_len(weight_list) {
+                Some(len) if Some(len) == list_len(variants) => elements(weight_list)
+                    .map(|weight| match number(function, &weight) {
+                        Ok(weight) if weight >= 0.0 && weight.is_finite() => Ok(weight),
+                        _ => Err(type_mismatch(function, EXPECTED, &weight)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => return Err(type_mismatch(function, EXPECTED, weight_list)),
+            };
+            let index = rollout::allocate(bucket, &weights)
+                .ok_or_else(|| type_mismatch(function, "weights summing above 0", weight_list))?;
+            Ok(elements(variants)
+                .nth(index)
+                .expect("one variant per weight"))
+        }
+        BuiltinFunction::IntRange => {
+            let [start, end] = expect_args(function, args)?;
+            match (start, end) {
+                (Value::Integer(start), Value::Integer(end)) if start <= end => {
+                    Ok(Value::IntRange(*start, *end))
+                }
+                (Value::Integer(_), Value::Integer(_)) => {
+                    Err(type_mismatch(function, "end no less than start", end))
+                }
+                (Value::Integer(_), other) | (other, _) => {
+                    Err(type_mismatch(function, "integer", other))
+                }
+            }
+        }
+        BuiltinFunction::InRange => {
+            let [value, range] = expect_args(function, args)?;
+            let (start, end) = int_range(function, range)?;
+            Ok(Value::Bool(match value {
+                Value::Integer(n) => (start..=end).contains(n),
+                Value::Decimal(d) => (Decimal::from(start)..=Decimal::from(end)).contains(d),
+                other => {
+                    let x = number(function, other)?;
+                    start as f64 <= x && x <= end as f64
+                }
+            }))
+        }
+        BuiltinFunction::RangesOverlap => {
+            let [a, b] = expect_args(function, args)?;
+            let (a_start, a_end) = int_range(function, a)?;
+            let (b_start, b_end) = int_range(function, b)?;
+            Ok(Value::Bool(a_start <= b_end && b_start <= a_end))
+        }
+        BuiltinFunction::Union => {
+            let mut seen = FxHashSet::default();
+            let mut items = Vec::new();
+            for list in args {
+                for item in list_items(function, list.borrow())? {
+                    if seen.insert(item.clone()) {
+                        items.push(item);
+                    }
+                }
+            }
+            Ok(make_list(&items))
+        }
+        BuiltinFunction::Intersection | BuiltinFunction::Difference => {
+            let (first, rest) = args.split_first().expect("at least two lists");
+            let others = rest
+                .iter()
+                .map(|list| Members::new(function, list.borrow()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let keep = function == BuiltinFunction::Intersection;
+            retain_members(function, first.borrow(), keep, |item| {
+                others.iter().all(|other| other.contains(item, interner))
+            })
+        }
+        BuiltinFunction::IsSubset | BuiltinFunction::IsDisjoint => {
+            let [a, b] = expect_args(function, args)?;
+            let b = Members::new(function, b)?;
+            let expected = function == BuiltinFunction::IsSubset;
+            all_members(function, a, expected, |item| b.contains(item, interner))
+        }
+        BuiltinFunction::InSet => {
+            let [value, set] = expect_args(function, args)?;
+            let set = set
+                .as_bloom_set()
+                .ok_or_else(|| type_mismatch(function, "bloom set", set))?;
+            Ok(Value::Bool(set.contains(value, interner)))
+        }
+    }
+}
+
+/// Get the rollout bucket of a key under a salt. Strings are folded first,
+/// so every spelling an interner treats as the same lands in one bucket
+fn bucket(
+    function: BuiltinFunction,
+    key: &Value,
+    salt: &Value,
+    interner: &dyn Interner,
+) -> Result<u64, EvalError> {
+    let folding = interner.case_folding();
+    let salt = folding.fold(text(function, salt, interner)?);
+    Ok(match key {
+        Value::Integer(n) => rollout::bucket(&n.to_string(), &salt),
+        key => rollout::bucket(&folding.fold(text(function, key, interner)?), &salt),
+    })
+}
+
+/// The second list of a set builtin, hashed if long enough to be worth it
+struct Members<'v> {
+    list: &'v Value,
+    set: Option<MemberSet>,
+}
+
+impl<'v> Members<'v> {
+    fn new(function: BuiltinFunction, list: &'v Value) -> Result<Self, EvalError> {
+        if list_len(list).is_none() {
+            return Err(type_mismatch(function, "list", list));
+        }
+        Ok(Members {
+            list,
+            set: MemberSet::new(list),
+        })
+    }
+
+    fn contains(&self, item: &Value, interner: &dyn Interner) -> bool {
+        match &self.set {
+            Some(set) => set.contains(item, interner),
+            None => contains(BuiltinFunction::In, self.list, item, interner).unwrap_or(false),
+        }
+    }
+}
+
+/// Keep the distinct elements of a list whose membership is `keep`, in order
+fn retain_members(
+    function: BuiltinFunction,
+    list: &Value,
+    keep: bool,
+    member: impl Fn(&Value) -> bool,
+) -> Result<Value, EvalError> {
+    let mut seen = FxHashSet::default();
+    let mut items = Vec::new();
+    for item in list_items(function, list)? {
+        if member(&item) == keep && seen.insert(item.clone()) {
+            items.push(item);
+        }
+    }
+    Ok(make_list(&items))
+}
+
+/// Check that the membership of every element of a list is `expected`
+fn all_members(
+    function: BuiltinFunction,
+    list: &Value,
+    expected: bool,
+    member: impl Fn(&Value) -> bool,
+) -> Result<Value, EvalError> {
+    let mut items = list_items(function, list)?;
+    Ok(Value::Bool(items.all(|item| member(&item) == expected)))
+}
+
+/// Get the bounds of an `int-range` argument
+fn int_range(function: BuiltinFunction, value: &Value) -> Result<(i64, i64), EvalError> {
+    value
+        .as_int_range()
+        .ok_or_else(|| type_mismatch(function, "range", value))
+}
+
+/// Test a value against a compiled `matches-regex` pattern
+pub(crate) fn match_regex(
+    value: &Value,
+    regex: &Regex,
+    interner: &dyn Interner,
+) -> Result<Value, EvalError> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let text = text(BuiltinFunction::MatchesRegex, value, interner)?;
+    Ok(Value::Bool(regex.is_match(text)))
+}
+
+/// Compare a value with the parsed second argument of a `semver-*` builtin
+pub(crate) fn match_version(
+    function: BuiltinFunction,
+    value: &Value,
+    operand: &Operand,
+    interner: &dyn Interner,
+) -> Result<Value, EvalError> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let version = Version::parse(text(function, value, interner)?)?;
+    Ok(Value::Bool(operand.test(function, &version)))
+}
+
+/// Test a value against a parsed `ip-in-cidr` block
+pub(crate) fn match_cidr(
+    value: &Value,
+    cidr: &Cidr,
+    interner: &dyn Interner,
+) -> Result<Value, EvalError> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let ip = net::parse_ip(text(BuiltinFunction::IpInCidr, value, interner)?)?;
+    Ok(Value::Bool(cidr.contains(ip)))
+}
+
+/// Apply `in`, `not-in`, `one-of`, `none-of`, `geo-cell-in` or a
+/// two-list set builtin to a value and a literal list prepared as a
+/// `MemberSet`
+pub(crate) fn match_member(
+    function: BuiltinFunction,
+    value: &Value,
+    set: &MemberSet,
+    interner: &dyn Interner,
+) -> Result<Value, EvalError> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let member = |item: &Value| set.contains(item, interner);
+    let found = match function {
+        BuiltinFunction::In | BuiltinFunction::NotIn => set.contains(value, interner),
+        BuiltinFunction::GeoCellIn => {
+            cell::ancestors(value, interner).any(|ancestor| set.contains(&ancestor, interner))
+        }
+        BuiltinFunction::Intersection => return retain_members(function, value, true, member),
+        BuiltinFunction::Difference => return retain_members(function, value, false, member),
+        BuiltinFunction::IsSubset => return all_members(function, value, true, member),
+        BuiltinFunction::IsDisjoint => return all_members(function, value, false, member),
+        _ => elements(value).any(|item| set.contains(&item, interner)),
+    };
+    let negated = matches!(function, BuiltinFunction::NotIn | BuiltinFunction::NoneOf);
+    Ok(Value::Bool(found != negated))
+}
+
+/// Check if a null argument makes a builtin's result null
+fn propagates_null(function: BuiltinFunction) -> bool {
+    !matches!(
+        function,
+        BuiltinFunction::And
+            | BuiltinFunction::Or
+            | BuiltinFunction::Not
+            | BuiltinFunction::Exists
+            | BuiltinFunction::IsNull
+            | BuiltinFunction::If
+            | BuiltinFunction::Quote
+            | BuiltinFunction::Any
+            | BuiltinFunction::All
+            | BuiltinFunction::Filter
+            | BuiltinFunction::Map
+    )
+}
+
+/// Build the value of a list literal from its evaluated elements
+///
+/// Lists of only strings or symbols become `StringList` and lists of only
+/// integers become `IntegerList`. Any other combination, including nested
+/// lists, becomes a general `List`. The empty list is an empty `StringList`.
+pub(crate) fn make_list<V: Borrow<Value>>(items: &[V]) -> Value {
+    let items = || items.iter().map(Borrow::borrow);
+    if items().all(|v| matches!(v, Value::String(_) | Value::Symbol(_))) {
+        Value::StringList(items().filter_map(text_id).collect())
+    } else if items().all(Value::is_integer) {
+        Value::IntegerList(items().filter_map(Value::as_integer).collect())
+    } else {
+        Value::List(items().cloned().collect())
+    }
+}
+
+fn text_id(value: &Value) -> Option<StringId> {
+    match value {
+        Value::String(id) | Value::Symbol(id) => Some(*id),
+        _ => None,
+    }
+}
+
+/// Borrow exactly `N` arguments
+fn expect_args<const N: usize, V: Borrow<Value>>(
+    function: BuiltinFunction,
+    args: &[V],
+) -> Result<[&Value; N], EvalError> {
+    let args: &[V; N] = args.try_into().map_err(|_| EvalError::ArityMismatch {
+        function,
+        expected: Arity::Exact(N),
+        found: args.len(),
+    })?;
+    Ok(args.each_ref().map(Borrow::borrow))
+}
+
+fn truthy(function: BuiltinFunction, value: &Value) -> Result<bool, EvalError> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        other => Err(type_mismatch(function, "boolean", other)),
+    }
+}
+
+fn number(function: BuiltinFunction, value: &Value) -> Result<f64, EvalError> {
+    match value {
+        Value::Integer(n) => Ok(*n as f64),
+        Value::Float(f) => Ok(*f),
+        Value::Decimal(d) => Ok(d.to_f64()),
+        other => Err(type_mismatch(function, "number", other)),
+    }
+}
+
+/// Get the exact value of an integer or decimal
+fn exact(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::Integer(n) => Some(Decimal::from(*n)),
+        Value::Decimal(d) => Some(*d),
+        _ => None,
+    }
+}
+
+/// Apply `+`, `-` or `*` to two numbers
+///
+/// Integers stay integers and decimals with integers stay exact; any other
+/// mix of numbers is computed in floating point, except that decimals and
+/// floats do not mix, since the result could not be exact. Integer results
+/// that overflow follow `overflow`, and decimal results that overflow are
+/// always errors.
+fn arithmetic(
+    function: BuiltinFunction,
+    a: &Value,
+    b: &Value,
+    overflow: IntegerOverflow,
+) -> Result<Value, EvalError> {
+    let error = || EvalError::Overflow(function);
+    match (a, b) {
+        (&Value::Integer(x), &Value::Integer(y)) => {
+            let checked = match function {
+                BuiltinFunction::Add | BuiltinFunction::Sum => x.checked_add(y),
+                BuiltinFunction::Subtract => x.checked_sub(y),
+                _ => x.checked_mul(y),
+            };
+            let result = match (checked, overflow) {
+                (Some(n), _) => n,
+                (None, IntegerOverflow::Error) => return Err(error()),
+                (None, IntegerOverflow::Wrap) => match function {
+                    BuiltinFunction::Add | BuiltinFunction::Sum => x.wrapping_add(y),
+                    BuiltinFunction::Subtract => x.wrapping_sub(y),
+                    _ => x.wrapping_mul(y),
+                },
+                (None, IntegerOverflow::Saturate) => match function {
+                    BuiltinFunction::Add | BuiltinFunction::Sum => x.saturating_add(y),
+                    BuiltinFunction::Subtract => x.saturating_sub(y),
+                    _ => x.saturating_mul(y),
+                },
+            };
+            Ok(Value::Integer(result))
+        }
+        (Value::Decimal(_), Value::Float(_)) => {
+            Err(type_mismatch(function, "integer or decimal", b))
+        }
+        (Value::Float(_), Value::Decimal(_)) => Err(type_mismatch(function, "float", b)),
+        _ => match (exact(a), exact(b)) {
+            (Some(x), Some(y)) => {
+                let result = match function {
+                    BuiltinFunction::Add | BuiltinFunction::Sum => x.checked_add(y),
+                    BuiltinFunction::Subtract => x.checked_sub(y),
+                    _ => x.checked_mul(y),
+                };
+                result.map(Value::Decimal).ok_or_else(error)
+            }
+            _ => {
+                let (x, y) = (number(function, a)?, number(function, b)?);
+                Ok(float(match function {
+                    BuiltinFunction::Add | BuiltinFunction::Sum => x + y,
+                    BuiltinFunction::Subtract => x - y,
+                    _ => x * y,
+                }))
+            }
+        },
+    }
+}
+
+/// Wrap a computed float, replacing NaN with the one canonical NaN, since
+/// platforms set the sign and payload of NaN results differently
+fn float(x: f64) -> Value {
+    Value::Float(if x.is_nan() { f64::NAN } else { x })
+}
+
+/// Convert a number or numeric text to a decimal for `decimal`
+fn to_decimal(
+    function: BuiltinFunction,
+    value: &Value,
+    interner: &dyn Interner,
+) -> Result<Decimal, EvalError> {
+    match value {
+        Value::Float(x) => {
+            Decimal::from_f64(*x).ok_or_else(|| EvalError::InvalidDecimal(x.to_string()))
+        }
+        Value::String(_) | Value::Symbol(_) | Value::Text(_) => {
+            let text = text(function, value, interner)?;
+            text.parse()
+                .map_err(|_| EvalError::InvalidDecimal(text.to_string()))
+        }
+        other => exact(other).ok_or_else(|| type_mismatch(function, "number or string", other)),
+    }
+}
+
+/// Borrow the text of a string, symbol or computed text value
+fn text<'v>(
+    function: BuiltinFunction,
+    value: &'v Value,
+    interner: &'v dyn Interner,
+) -> Result<&'v str, EvalError> {
+    match value {
+        Value::String(id) | Value::Symbol(id) => interner
+            .resolve(*id)
+            .ok_or_else(|| type_mismatch(function, "string", value)),
+        Value::Text(s) => Ok(s),
+        other => Err(type_mismatch(function, "string", other)),
+    }
+}
+
+/// Characters of `s` in normalization form C, lowercased
+fn folded(s: &str) -> impl Iterator<Item = char> + '_ {
+    s.nfc().flat_map(char::to_lowercase)
+}
+
+/// Build a string result, reusing the interned ID if the text has one so
+/// only text new to the interner is copied
+///
+/// A case-folding interner finds an ID for every casing of its strings,
+/// so the ID is only reused if it resolves to exactly this text.
+fn string_value(text: Cow<'_, str>, interner: &dyn Interner) -> Value {
+    match interner.get_id(&text) {
+        Some(id) if interner.resolve(id) == Some(&*text) => Value::String(id),
+        _ => Value::Text(text.into_owned().into_boxed_str()),
+    }
+}
+
+/// Look up the entry of a map under a string, symbol or text key
+///
+/// Text that is not in the interner cannot be a key of any map.
+fn map_entry<'v>(
+    function: BuiltinFunction,
+    map: &'v Value,
+    key: &Value,
+    interner: &dyn Interner,
+) -> Result<Option<&'v Value>, EvalError> {
+    let Value::Map(map) = map else {
+        return Err(type_mismatch(function, "map", map));
+    };
+    let key = match key {
+        Value::String(id) | Value::Symbol(id) => Some(*id),
+        Value::Text(s) => interner.get_id(s),
+        other => return Err(type_mismatch(function, "string", other)),
+    };
+    Ok(key.and_then(|key| map.get(&key)))
+}
+
+/// Get a character index argument
+fn index(function: BuiltinFunction, value: &Value) -> Result<usize, EvalError> {
+    match value {
+        Value::Integer(n) if *n >= 0 => Ok(*n as usize),
+        other => Err(type_mismatch(function, "non-negative integer", other)),
+    }
+}
+
+/// Byte offset of the character at index `chars`, clamped to the end of `s`
+fn char_offset(s: &str, chars: usize) -> usize {
+    s.char_indices()
+        .nth(chars)
+        .map_or(s.len(), |(offset, _)| offset)
+}
+
+fn type_mismatch(function: BuiltinFunction, expected: &'static str, found: &Value) -> EvalError {
+    EvalError::TypeMismatch {
+        function,
+        expected,
+        found: found.value_type(),
+    }
+}
+
+/// Equality used by `=`: numbers compare across integer, float and
+/// decimal, exactly unless a float is involved,
+/// symbols, strings and computed text compare equal when their text is
+/// the same under the interner's case folding, and lists compare
+/// element-wise regardless of their representation
+fn values_equal(a: &Value, b: &Value, interner: &dyn Interner) -> bool {
+    match (a, b) {
+        (Value::Integer(x), Value::Float(y)) | (Value::Float(y), Value::Integer(x)) => {
+            *x as f64 == *y
+        }
+        (Value::Float(x), Value::Float(y)) => x == y,
+        (Value::Decimal(d), Value::Float(y)) | (Value::Float(y), Value::Decimal(d)) => {
+            d.to_f64() == *y
+        }
+        (Value::Decimal(_), Value::Integer(_) | Value::Decimal(_))
+        | (Value::Integer(_), Value::Decimal(_)) => exact(a) == exact(b),
+        (Value::Symbol(x) | Value::String(x), Value::Symbol(y) | Value::String(y)) => x == y,
+        (Value::Symbol(id) | Value::String(id), Value::Text(s))
+        | (Value::Text(s), Value::Symbol(id) | Value::String(id)) => interner
+            .resolve(*id)
+            .is_some_and(|text| interner.case_folding().eq(text, s)),
+        (Value::Text(x), Value::Text(y)) => interner.case_folding().eq(x, y),
+        _ => match (list_len(a), list_len(b)) {
+            (Some(n), Some(m)) => {
+                n == m
+                    && elements(a)
+                        .zip(elements(b))
+                        .all(|(x, y)| values_equal(&x, &y, interner))
+            }
+            _ => a == b,
+        },
+    }
+}
+
+/// Get the number of elements of a list value
+fn list_len(value: &Value) -> Option<usize> {
+    match value {
+        Value::StringList(ids) => Some(ids.len()),
+        Value::IntegerList(ns) => Some(ns.len()),
+        Value::List(items) => Some(items.len()),
+        _ => None,
+    }
+}
+
+/// Order two numbers, strings or lists, `None` if NaN is involved
+fn compare_values(
+    function: BuiltinFunction,
+    a: &Value,
+    b: &Value,
+    interner: &dyn Interner,
+) -> Result<Option<Ordering>, EvalError> {
+    match a {
+        Value::Integer(_) | Value::Decimal(_) => {
+            if let (Some(x), Some(y)) = (exact(a), exact(b)) {
+                return Ok(Some(x.cmp(&y)));
+            }
+            Ok(number(function, a)?.partial_cmp(&number(function, b)?))
+        }
+        Value::Float(x) => Ok(x.partial_cmp(&number(function, b)?)),
+        Value::String(_) | Value::Symbol(_) | Value::Text(_) => Ok(Some(
+            text(function, a, interner)?.cmp(text(function, b, interner)?),
+        )),
+        _ if list_len(a).is_some() => {
+            if list_len(b).is_none() {
+                return Err(type_mismatch(function, "list", b));
+            }
+            let (mut xs, mut ys) = (elements(a), elements(b));
+            loop {
+                match (xs.next(), ys.next()) {
+                    (Some(x), Some(y)) => match compare_values(function, &x, &y, interner)? {
+                        Some(Ordering::Equal) => {}
+                        ordering => return Ok(ordering),
+                    },
+                    (x, y) => return Ok(Some(x.is_some().cmp(&y.is_some()))),
+                }
+            }
+        }
+        other => Err(type_mismatch(function, "number, string or list", other)),
+    }
+}
+
+/// Iterate the elements of a list value, treating scalars as a single-element list
+fn elements(value: &Value) -> impl Iterator<Item = Value> + '_ {
+    let (strings, integers, values, scalar): (&[StringId], &[i64], &[Value], _) = match value {
+        Value::StringList(ids) => (ids, &[], &[], None),
+        Value::IntegerList(ns) => (&[], ns, &[], None),
+        Value::List(items) => (&[], &[], items, None),
+        other => (&[], &[], &[], Some(other)),
+    };
+    strings
+        .iter()
+        .map(|&id| Value::String(id))
+        .chain(integers.iter().map(|&n| Value::Integer(n)))
+        .chain(values.iter().cloned())
+        .chain(scalar.cloned())
+}
+
+/// Iterate the elements of a list argument of an aggregation builtin
+pub(crate) fn list_items(
+    function: BuiltinFunction,
+    list: &Value,
+) -> Result<impl Iterator<Item = Value> + '_, EvalError> {
+    match list_len(list) {
+        Some(_) => Ok(elements(list)),
+        None => Err(type_mismatch(function, "list", list)),
+    }
+}
+
+/// Check whether `list` contains `item`
+fn contains(
+    function: BuiltinFunction,
+    list: &Value,
+    item: &Value,
+    interner: &dyn Interner,
+) -> Result<bool, EvalError> {
+    match list {
+        Value::StringList(ids) => Ok(match item {
+            Value::Symbol(id) | Value::String(id) => ids.contains(id),
+            Value::Text(s) => interner.get_id(s).is_some_and(|id| ids.contains(&id)),
+            _ => false,
+        }),
+        Value::IntegerList(ns) => Ok(match item {
+            Value::Integer(n) => ns.contains(n),
+            Value::Float(f) => ns.iter().any(|&n| n as f64 == *f),
+            Value::Decimal(_) => ns.iter().any(|&n| exact(&Value::Integer(n)) == exact(item)),
+            _ => false,
+        }),
+        Value::List(items) => Ok(items
+            .iter()
+            .any(|value| values_equal(value, item, interner))),
+        other => Err(type_mismatch(function, "list", other)),
+    }
+}
+
+fn any_contained(
+    function: BuiltinFunction,
+    items: &Value,
+    list: &Value,
+    interner: &dyn Interner,
+) -> Result<bool, EvalError> {
+    for value in elements(items) {
+        if contains(function, list, &value, interner)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Read a polygon given as a list of at least three `[lat lng]` pairs
+fn polygon_vertices(
+    function: BuiltinFunction,
+    polygon: &Value,
+) -> Result<Vec<(f64, f64)>, EvalError> {
+    const EXPECTED: &str = "list of at least 3 [lat lng] pairs";
+    if list_len(polygon).is_none_or(|n| n < 3) {
+        return Err(type_mismatch(function, EXPECTED, polygon));
+    }
+    elements(polygon)
+        .map(|pair| point(function, &pair, EXPECTED))
+        .collect()
+}
+
+/// Read a `[lat lng]` pair, reporting `expected` if it is not one
+fn point(
+    function: BuiltinFunction,
+    pair: &Value,
+    expected: &'static str,
+) -> Result<(f64, f64), EvalError> {
+    if list_len(pair) != Some(2) {
+        return Err(type_mismatch(function, expected, pair));
+    }
+    let mut coordinates = elements(pair);
+    let lat = number(function, &coordinates.next().expect("two coordinates"))?;
+    let lng = number(function, &coordinates.next().expect("two coordinates"))?;
+    Ok((lat, lng))
+}
+
+/// Check whether a point lies inside a polygon by counting how many edges
+/// a ray from the point towards increasing longitude crosses
+fn point_in_polygon((lat, lng): (f64, f64), vertices: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    let mut previous = vertices[vertices.len() - 1];
+    for &vertex in vertices {
+        let ((lat1, lng1), (lat2, lng2)) = (previous, vertex);
+        if (lat1 > lat) != (lat2 > lat) {
+            let crossing = lng1 + (lat - lat1) / (lat2 - lat1) * (lng2 - lng1);
+            if lng < crossing {
+                inside = !inside;
+            }
+        }
+        previous = vertex;
+    }
+    inside
+}
+
+impl DistanceFormula {
+    /// Distance in meters between two `(lat, lng)` points given in degrees
+    pub fn meters(self, from: (f64, f64), to: (f64, f64)) -> f64 {
+        self.distance(from, to, &Trig::NATIVE)
+    }
+
+    /// Distance in meters computed with `trig`
+    fn distance(self, (lat1, lng1): (f64, f64), (lat2, lng2): (f64, f64), trig: &Trig) -> f64 {
+        match self {
+            DistanceFormula::Haversine => haversine_distance(lat1, lng1, lat2, lng2, trig),
+            DistanceFormula::Planar => planar_distance(lat1, lng1, lat2, lng2, trig),
+        }
+    }
+}
+
+/// Float functions used by the distance formulas
+struct Trig {
+    sin: fn(f64) -> f64,
+    cos: fn(f64) -> f64,
+    asin: fn(f64) -> f64,
+    sqrt: fn(f64) -> f64,
+}
+
+impl Trig {
+    /// The platform's own implementations, which may differ in the last
+    /// bits between platforms
+    const NATIVE: Trig = Trig {
+        sin: compat::sin,
+        cos: compat::cos,
+        asin: compat::asin,
+        sqrt: compat::sqrt,
+    };
+
+    /// Software implementations with the same results everywhere
+    const PORTABLE: Trig = Trig {
+        sin: compat::portable::sin,
+        cos: compat::portable::cos,
+        asin: compat::portable::asin,
+        sqrt: compat::portable::sqrt,
+    };
+
+    /// Get the implementations `options` call for
+    fn of(options: &EvalOptions) -> &'static Trig {
+        if options.deterministic {
+            &Trig::PORTABLE
+        } else {
+            &Trig::NATIVE
+        }
+    }
+}
+
+/// Distance in meters between two points given in degrees, treating
+/// longitude as shrinking with the cosine of the mean latitude
+fn planar_distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64, trig: &Trig) -> f64 {
+    // The shorter way around, across the antimeridian if need be
+    let mut d_lng = (lng2 - lng1).abs() % 360.0;
+    if d_lng > 180.0 {
+        d_lng = 360.0 - d_lng;
+    }
+    let x = d_lng.to_radians() * (trig.cos)(((lat1 + lat2) / 2.0).to_radians());
+    let y = (lat2 - lat1).to_radians();
+    EARTH_RADIUS_METERS * (trig.sqrt)(x * x + y * y)
+}
+
+/// Great-circle distance in meters between two points given in degrees
+fn haversine_distance(lat1: f64, lng1: f64, lat2: f64, lng2: f64, trig: &Trig) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lng = (lng2 - lng1).to_radians();
+    let sin_lat = (trig.sin)(d_lat / 2.0);
+    let sin_lng = (trig.sin)(d_lng / 2.0);
+    let a = sin_lat * sin_lat
+        + (trig.cos)(lat1.to_radians()) * (trig.cos)(lat2.to_radians()) * sin_lng * sin_lng;
+    // Rounding can push antipodal points just past 1
+    2.0 * EARTH_RADIUS_METERS * (trig.asin)((trig.sqrt)(a.min(1.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CaseFolding, StringInterner};
+    use alloc::sync::Arc;
+    use alloc::{format, vec};
+    use core::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    fn call(interner: &mut StringInterner, name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call {
+            function: interner.intern(name),
+            args,
+        }
+    }
+
+    #[test]
+    fn comparisons_and_booleans() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(25));
+
+        let adult = call(
+            &mut interner,
+            ">=",
+            vec![Expr::Variable(age), Expr::Literal(Value::Integer(18))],
+        );
+        let senior = call(
+            &mut interner,
+            ">",
+            vec![Expr::Variable(age), Expr::Literal(Value::Float(64.5))],
+        );
+        let not_senior = call(&mut interner, "not", vec![senior]);
+        let expr = call(&mut interner, "and", vec![adult, not_senior]);
+
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn ordered_comparisons() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("name"), Value::Text("kim".into()));
+        let cases = [
+            (r#"(and (>= name "a") (< name "n"))"#, true),
+            (r#"(< "Zed" "apple")"#, true),
+            (r#"(<= 'b "b")"#, true),
+            ("(< [1 2] [1 3])", true),
+            ("(< [1 2] [1 2 0])", true),
+            (r#"(> ["b"] ["a" "z"])"#, true),
+            ("(>= [1.5] [2])", false),
+            ("(< 1 1.5)", true),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(Value::Bool(expected)),
+                "{}",
+                source
+            );
+        }
+
+        let errors = [
+            (r#"(< name 1)"#, "string"),
+            (r#"(> [1] "a")"#, "list"),
+            ("(< [1] [\"a\"])", "number"),
+            ("(<= true false)", "number, string or list"),
+        ];
+        for (source, expected) in errors {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert!(
+                matches!(
+                    evaluator.eval(&expr, &env),
+                    Err(EvalError::TypeMismatch { expected: e, .. }) if e == expected
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_arithmetic() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("price"), Value::Float(19.99));
+        env.insert(interner.intern("total"), Value::Text("59.97".into()));
+        let cases = [
+            ("(= (+ 0.1d 0.2d) 0.3d)", Value::Bool(true)),
+            ("(= (+ 0.1 0.2) 0.3)", Value::Bool(false)),
+            (
+                "(* (decimal price) 3)",
+                Value::Decimal("59.97".parse().unwrap()),
+            ),
+            (
+                "(= (* (decimal price) 3) (decimal total))",
+                Value::Bool(true),
+            ),
+            ("(- 10 0.01d)", Value::Decimal("9.99".parse().unwrap())),
+            ("(+ 1 2 3)", Value::Integer(6)),
+            ("(* 2 1.5)", Value::Float(3.0)),
+            ("(> 1.000000000000000001d 1)", Value::Bool(true)),
+            ("(= 2.50d 2.5)", Value::Bool(true)),
+            ("(< 2 2.01d)", Value::Bool(true)),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(evaluator.eval(&expr, &env), Ok(expected), "{}", source);
+        }
+
+        let eval = |source: &str, interner: &mut StringInterner| {
+            let expr = crate::parse(source, interner).unwrap();
+            Evaluator::new(interner).eval(&expr, &env)
+        };
+        assert_eq!(
+            eval("(+ 9223372036854775807 1)", &mut interner),
+            Err(EvalError::Overflow(BuiltinFunction::Add))
+        );
+        assert_eq!(
+            eval(r#"(decimal "12,50")"#, &mut interner),
+            Err(EvalError::InvalidDecimal("12,50".into()))
+        );
+        assert!(matches!(
+            eval("(+ 1.5d price)", &mut interner),
+            Err(EvalError::TypeMismatch {
+                found: ValueType::Float,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("big"), Value::Integer(i64::MAX));
+        let cases = [
+            ("(+ big 1)", i64::MIN, i64::MAX),
+            ("(- (- 0 big) 2)", i64::MAX, i64::MIN),
+            ("(* big -2)", 2, i64::MIN),
+            ("(+ big -1)", i64::MAX - 1, i64::MAX - 1),
+        ];
+        for (source, wrapped, saturated) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let Expr::Call { function, .. } = expr else {
+                unreachable!()
+            };
+            let function = interner.builtin(function).unwrap();
+            for (overflow, expected) in [
+                (IntegerOverflow::Wrap, Ok(Value::Integer(wrapped))),
+                (IntegerOverflow::Saturate, Ok(Value::Integer(saturated))),
+                (
+                    IntegerOverflow::Error,
+                    if wrapped == saturated {
+                        Ok(Value::Integer(wrapped))
+                    } else {
+                        Err(EvalError::Overflow(function))
+                    },
+                ),
+            ] {
+                let options = EvalOptions {
+                    overflow,
+                    ..EvalOptions::default()
+                };
+                let evaluator = Evaluator::with_options(&interner, options);
+                assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+                assert_eq!(
+                    compile(&expr, &interner).eval_with(&env, &interner, &options),
+                    expected,
+                    "{}",
+                    source
+                );
+            }
+        }
+
+        // Constant overflow is not folded away at compile time
+        let expr = crate::parse("(* 9223372036854775807 2)", &mut interner).unwrap();
+        let options = EvalOptions {
+            overflow: IntegerOverflow::Saturate,
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            compile(&expr, &interner).eval_with(&env, &interner, &options),
+            Ok(Value::Integer(i64::MAX))
+        );
+    }
+
+    #[test]
+    fn let_bindings() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("x"), Value::Integer(1));
+        env.insert(interner.intern("price"), Value::Integer(30));
+        let cases = [
+            (
+                "(let ((total (* price 4))) (> total 100))",
+                Value::Bool(true),
+            ),
+            // Later bindings see earlier ones, and locals shadow variables
+            ("(let ((x (+ x 1)) (y (* x 10))) y)", Value::Integer(20)),
+            ("(let ((x 5)) (let ((x (+ x 1))) x))", Value::Integer(6)),
+            ("(let () price)", Value::Integer(30)),
+            (
+                "(let ((missing null)) (exists missing))",
+                Value::Bool(false),
+            ),
+            ("(let ((price null)) (exists price))", Value::Bool(false)),
+            // Equal subexpressions in different scopes are not shared
+            (
+                "(and (= (+ x 1) 2) (let ((x 5)) (= (+ x 1) 6)) (= (+ x 1) 2))",
+                Value::Bool(true),
+            ),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            assert_eq!(
+                compile(&expr, &interner).eval(&env, &interner),
+                Ok(expected),
+                "{}",
+                source
+            );
+        }
+
+        // A local is only in scope in the bindings after it and the body
+        let expr = crate::parse("(and (let ((y 1)) (= y 1)) (exists y))", &mut interner).unwrap();
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(false)));
+        assert_eq!(
+            compile(&expr, &interner).eval(&env, &interner),
+            Ok(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("score"), Value::Integer(72));
+        env.insert(interner.intern("vip"), Value::Null);
+        let silver = Value::String(interner.intern("silver"));
+        let cases = [
+            (r#"(if (> score 50) 1.5 1.0)"#, Value::Float(1.5)),
+            (r#"(if vip 2 1)"#, Value::Integer(1)),
+            (
+                r#"(cond ((> score 90) "gold") ((> score 50) "silver") (else "bronze"))"#,
+                silver,
+            ),
+            ("(if (> score 90) 1)", Value::Null),
+            // Untaken branches are not evaluated
+            ("(if (> score 50) score missing)", Value::Integer(72)),
+            ("(if false (+ 9223372036854775807 1) 0)", Value::Integer(0)),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            assert_eq!(
+                compile(&expr, &interner).eval(&env, &interner),
+                Ok(expected),
+                "{}",
+                source
+            );
+        }
+
+        let expr = crate::parse("(if score 1 2)", &mut interner).unwrap();
+        let expected = Err(EvalError::TypeMismatch {
+            function: BuiltinFunction::If,
+            expected: "boolean",
+            found: ValueType::Integer,
+        });
+        assert_eq!(Evaluator::new(&interner).eval(&expr, &env), expected);
+        assert_eq!(compile(&expr, &interner).eval(&env, &interner), expected);
+    }
+
+    #[test]
+    fn aggregation() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("tags"),
+            Value::StringList(vec![interner.intern("sports-nba"), interner.intern("news")].into()),
+        );
+        env.insert(
+            interner.intern("bids"),
+            Value::IntegerList(vec![3, 9, 4].into()),
+        );
+        env.insert(
+            interner.intern("prices"),
+            Value::List(vec![Value::Float(1.5), Value::Integer(2)]),
+        );
+        env.insert(interner.intern("none"), Value::IntegerList(vec![].into()));
+        env.insert(interner.intern("limit"), Value::Integer(5));
+        let nba = Value::String(interner.intern("sports-nba"));
+        let cases = [
+            ("(count tags)", Value::Integer(2)),
+            ("(sum bids)", Value::Integer(16)),
+            ("(sum prices)", Value::Float(3.5)),
+            ("(sum none)", Value::Integer(0)),
+            ("(avg bids)", Value::Float(16.0 / 3.0)),
+            ("(avg none)", Value::Null),
+            ("(min-of bids)", Value::Integer(3)),
+            ("(max-of tags)", nba),
+            ("(max-of [1 null])", Value::Null),
+            (r#"(any tags (starts-with _ "sports-"))"#, Value::Bool(true)),
+            (
+                r#"(all tags (starts-with _ "sports-"))"#,
+                Value::Bool(false),
+            ),
+            ("(all bids (< _ 10))", Value::Bool(true)),
+            ("(any none (> _ 0))", Value::Bool(false)),
+            ("(all none (> _ 0))", Value::Bool(true)),
+            // The predicate reads attributes and nests, shadowing `_`
+            ("(any bids (> _ limit))", Value::Bool(true)),
+            ("(any [[1 2] [3]] (all _ (> _ 2)))", Value::Bool(true)),
+            ("(all [1 null] (> _ 0))", Value::Null),
+            ("(any (get null \"x\") _)", Value::Null),
+            // Elements after the deciding one are not evaluated
+            ("(any [1 \"a\"] (= (+ _ 0) 1))", Value::Bool(true)),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            assert_eq!(
+                compile(&expr, &interner).eval(&env, &interner),
+                Ok(expected),
+                "{}",
+                source
+            );
+        }
+
+        for (source, function, found) in [
+            ("(count limit)", BuiltinFunction::Count, ValueType::Integer),
+            ("(any bids _)", BuiltinFunction::Any, ValueType::Integer),
+            ("(sum tags)", BuiltinFunction::Sum, ValueType::String),
+        ] {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            let result = evaluator.eval(&expr, &env);
+            assert!(
+                matches!(
+                    &result,
+                    Err(EvalError::TypeMismatch { function: f, found: t, .. })
+                        if *f == function && *t == found
+                ),
+                "{}: {:?}",
+                source,
+                result
+            );
+            assert_eq!(compile(&expr, &interner).eval(&env, &interner), result);
+        }
+    }
+
+    #[test]
+    fn filter_and_map() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let amount = interner.intern("amount");
+        let purchases = [120, 40, 300]
+            .into_iter()
+            .map(|n| Value::Map([(amount, Value::Integer(n))].into_iter().collect()))
+            .collect();
+        env.insert(interner.intern("purchases"), Value::List(purchases));
+        env.insert(
+            interner.intern("bids"),
+            Value::IntegerList(vec![3, 9, 4].into()),
+        );
+        env.insert(interner.intern("limit"), Value::Integer(5));
+        let cases = [
+            (
+                r#"(map (filter (p purchases) (> (get p "amount") 100)) (get _ "amount"))"#,
+                Value::IntegerList(vec![120, 300].into()),
+            ),
+            (
+                r#"(count (filter (p purchases) (> (get p "amount") limit)))"#,
+                Value::Integer(3),
+            ),
+            (
+                "(filter bids (> _ limit))",
+                Value::IntegerList(vec![9].into()),
+            ),
+            (
+                "(map (b bids) (* b 2))",
+                Value::IntegerList(vec![6, 18, 8].into()),
+            ),
+            (
+                "(map (b bids) (> b 3))",
+                Value::List(vec![
+                    Value::Bool(false),
+                    Value::Bool(true),
+                    Value::Bool(true),
+                ]),
+            ),
+            (
+                "(filter [1 null 2] (= _ 1))",
+                Value::IntegerList(vec![1].into()),
+            ),
+            (
+                "(map [1 null] _)",
+                Value::List(vec![Value::Integer(1), Value::Null]),
+            ),
+            ("(filter (get null \"x\") true)", Value::Null),
+            // A named element reads like a `let` local, and `any`/`all` take one too
+            (
+                "(any (b bids) (all (c bids) (<= c (* b 3))))",
+                Value::Bool(true),
+            ),
+            (
+                "(map (_ bids) (+ _ 1))",
+                Value::IntegerList(vec![4, 10, 5].into()),
+            ),
+            // Arguments that do not read the element are the same for each
+            ("(map bids limit)", Value::IntegerList(vec![5, 5, 5].into())),
+            ("(filter bids false)", Value::StringList(vec![].into())),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            assert_eq!(
+                compile(&expr, &interner).eval(&env, &interner),
+                Ok(expected),
+                "{}",
+                source
+            );
+        }
+
+        let expr = crate::parse("(filter bids _)", &mut interner).unwrap();
+        let result = Evaluator::new(&interner).eval(&expr, &env);
+        assert!(
+            matches!(
+                &result,
+                Err(EvalError::TypeMismatch {
+                    function: BuiltinFunction::Filter,
+                    found: ValueType::Integer,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(compile(&expr, &interner).eval(&env, &interner), result);
+    }
+
+    #[test]
+    fn membership() {
+        let mut interner = StringInterner::new();
+        let tags = interner.intern("tags");
+        let urgent = interner.intern("urgent");
+        let spam = interner.intern("spam");
+        let mut env = Environment::new();
+        env.insert(tags, Value::StringList(vec![urgent].into()));
+
+        let list = Expr::List(vec![
+            Expr::Literal(Value::String(urgent)),
+            Expr::Literal(Value::String(spam)),
+        ]);
+        let one_of = call(
+            &mut interner,
+            "one-of",
+            vec![Expr::Variable(tags), list.clone()],
+        );
+        let all_of = call(&mut interner, "all-of", vec![Expr::Variable(tags), list]);
+        let in_list = call(
+            &mut interner,
+            "in",
+            vec![
+                Expr::Literal(Value::Integer(3)),
+                Expr::Literal(Value::IntegerList(vec![1, 2, 3].into())),
+            ],
+        );
+
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(evaluator.eval(&one_of, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&all_of, &env), Ok(Value::Bool(false)));
+        assert_eq!(evaluator.eval(&in_list, &env), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn heterogeneous_lists() {
+        let mut interner = StringInterner::new();
+        let a = interner.intern("a");
+        let list = Expr::List(vec![
+            Expr::Literal(Value::Integer(1)),
+            Expr::Literal(Value::String(a)),
+            Expr::List(vec![Expr::Literal(Value::Integer(2))]),
+        ]);
+        let has_nested = call(
+            &mut interner,
+            "in",
+            vec![
+                Expr::Literal(Value::IntegerList(vec![2].into())),
+                list.clone(),
+            ],
+        );
+        let has_float = call(
+            &mut interner,
+            "in",
+            vec![Expr::Literal(Value::Float(1.0)), list.clone()],
+        );
+        let same = call(
+            &mut interner,
+            "=",
+            vec![
+                Expr::List(vec![Expr::Literal(Value::Symbol(a))]),
+                Expr::Literal(Value::List(vec![Value::String(a)])),
+            ],
+        );
+
+        let evaluator = Evaluator::new(&interner);
+        let env = Environment::new();
+        assert_eq!(
+            evaluator.eval(&list, &env),
+            Ok(Value::List(vec![
+                Value::Integer(1),
+                Value::String(a),
+                Value::IntegerList(vec![2].into()),
+            ]))
+        );
+        assert_eq!(evaluator.eval(&has_nested, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&has_float, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&same, &env), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn null_semantics() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let flag = interner.intern("flag");
+        let adult = call(
+            &mut interner,
+            ">=",
+            vec![Expr::Variable(age), Expr::Literal(Value::Integer(18))],
+        );
+        let not_adult = call(&mut interner, "not", vec![adult.clone()]);
+        let exists = call(&mut interner, "exists", vec![Expr::Variable(age)]);
+        let is_null = call(&mut interner, "is-null", vec![Expr::Variable(flag)]);
+        let or_true = call(
+            &mut interner,
+            "or",
+            vec![adult.clone(), Expr::Literal(Value::Bool(true))],
+        );
+        let and_true = call(
+            &mut interner,
+            "and",
+            vec![Expr::Literal(Value::Bool(true)), Expr::Variable(flag)],
+        );
+
+        let mut env = Environment::new();
+        env.insert(flag, Value::Null);
+        let strict = Evaluator::new(&interner);
+        let nulls = Evaluator::with_options(
+            &interner,
+            EvalOptions {
+                missing: MissingVariable::Null,
+                ..EvalOptions::default()
+            },
+        );
+        let falses = Evaluator::with_options(
+            &interner,
+            EvalOptions {
+                missing: MissingVariable::False,
+                ..EvalOptions::default()
+            },
+        );
+
+        assert_eq!(
+            strict.eval(&adult, &env),
+            Err(EvalError::UnknownVariable(age))
+        );
+        assert_eq!(strict.eval(&exists, &env), Ok(Value::Bool(false)));
+        assert_eq!(strict.eval(&is_null, &env), Ok(Value::Bool(true)));
+        assert_eq!(nulls.eval(&adult, &env), Ok(Value::Null));
+        assert_eq!(nulls.eval(&not_adult, &env), Ok(Value::Null));
+        assert_eq!(nulls.eval(&or_true, &env), Ok(Value::Bool(true)));
+        assert_eq!(nulls.eval(&and_true, &env), Ok(Value::Null));
+        assert_eq!(falses.eval(&adult, &env), Ok(Value::Bool(false)));
+        assert_eq!(falses.eval(&not_adult, &env), Ok(Value::Bool(true)));
+        assert_eq!(falses.eval(&and_true, &env), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn strings() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let name = interner.intern("  Ada Lovelace ");
+        env.insert(interner.intern("name"), Value::String(name));
+        env.insert(
+            interner.intern("code"),
+            Value::Symbol(interner.intern("ab-12")),
+        );
+        let ada = interner.intern("ada");
+
+        let cases = [
+            (r#"(starts-with (trim name) "Ada")"#, Value::Bool(true)),
+            (r#"(ends-with code "12")"#, Value::Bool(true)),
+            (r#"(contains name "Love")"#, Value::Bool(true)),
+            (r#"(contains name "love")"#, Value::Bool(false)),
+            ("(string-length name)", Value::Integer(15)),
+            ("(string-length \"héllo\")", Value::Integer(5)),
+            (r#"(equal-fold "IPHONE" "iPhone")"#, Value::Bool(true)),
+            (
+                "(equal-fold \"Caf\u{e9}\" \"CAFE\u{301}\")",
+                Value::Bool(true),
+            ),
+            (r#"(equal-fold "cafe" "café")"#, Value::Bool(false)),
+            ("(lowercase (substring name 2 5))", Value::String(ada)),
+            ("(uppercase code)", Value::Text("AB-12".into())),
+            ("(substring \"héllo\" 1 3)", Value::Text("él".into())),
+            ("(substring code 2 99)", Value::Text("-12".into())),
+            ("(substring code 4 1)", Value::Text("".into())),
+            (
+                r#"(concat code "/" (uppercase "x"))"#,
+                Value::Text("ab-12/X".into()),
+            ),
+            (r#"(= (concat "a" "da") "ada")"#, Value::Bool(true)),
+            (r#"(in (uppercase "us") ["US" "CA"])"#, Value::Bool(true)),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(evaluator.eval(&expr, &env), Ok(expected), "{}", source);
+        }
+
+        // Results already in the interner reuse their ID
+        let expr = crate::parse("(trim name)", &mut interner).unwrap();
+        interner.intern("Ada Lovelace");
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(
+            evaluator.eval(&expr, &env),
+            Ok(Value::String(interner.get_id("Ada Lovelace").unwrap()))
+        );
+
+        let expr = crate::parse("(substring name -1 2)", &mut interner).unwrap();
+        let evaluator = Evaluator::new(&interner);
+        assert!(matches!(
+            evaluator.eval(&expr, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::Substring,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn case_folded_strings() {
+        let mut interner = StringInterner::with_case_folding(CaseFolding::Ascii);
+        let mut env = Environment::new();
+        let us = interner.intern("us");
+        env.insert(interner.intern("country"), Value::String(us));
+
+        let cases = [
+            (r#"(uppercase "us")"#, Value::Text("US".into())),
+            (r#"(lowercase "US")"#, Value::String(us)),
+            (r#"(concat "U" "s")"#, Value::Text("Us".into())),
+            (r#"(trim " US ")"#, Value::Text("US".into())),
+            (r#"(substring "xUSx" 1 3)"#, Value::Text("US".into())),
+            (r#"(starts-with (uppercase "us") "U")"#, Value::Bool(true)),
+            (r#"(starts-with country "U")"#, Value::Bool(true)),
+            (r#"(ends-with "Lovelace" "LACE")"#, Value::Bool(true)),
+            (r#"(contains "Ada Lovelace" "LOVE")"#, Value::Bool(true)),
+            (r#"(contains "Ada" "")"#, Value::Bool(true)),
+            (r#"(contains "Ada" "ADAM")"#, Value::Bool(false)),
+            (r#"(= (uppercase country) "us")"#, Value::Bool(true)),
+            (
+                r#"(= (uppercase country) (concat "U" "s"))"#,
+                Value::Bool(true),
+            ),
+            (r#"(in (uppercase country) ["US" "CA"])"#, Value::Bool(true)),
+            #[cfg(feature = "std")]
+            (r#"(matches-regex "Ada" "^ada$")"#, Value::Bool(false)),
+            #[cfg(feature = "std")]
+            (r#"(matches-regex "Ada" "(?i)^ada$")"#, Value::Bool(true)),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            let compiled = crate::compile(&expr, &interner);
+            assert_eq!(compiled.eval(&env, &interner), Ok(expected), "{}", source);
+        }
+
+        let mut unicode = StringInterner::with_case_folding(CaseFolding::Unicode);
+        let expr = crate::parse(r#"(starts-with "ÉTÉ" (lowercase "ÉT"))"#, &mut unicode).unwrap();
+        let evaluator = Evaluator::new(&unicode);
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(true)));
+
+        // Rollouts and bloom sets see one spelling whichever came first
+        let mut audience = crate::BloomSet::new(100, 0.001);
+        audience.insert_str("user-1");
+        for first in ["User-1", "user-1"] {
+            let mut interner = StringInterner::with_case_folding(CaseFolding::Ascii);
+            interner.intern(first);
+            let mut env = Environment::new();
+            env.insert(
+                interner.intern("audience"),
+                Value::BloomSet(audience.clone()),
+            );
+            for (source, expected) in [
+                (r#"(percent-of "USER-1" "Experiment-42" 9.95)"#, true),
+                (r#"(percent-of "user-1" "experiment-42" 9.9)"#, false),
+                (
+                    r#"(percent-of (concat "User" "-1") "experiment-42" 9.95)"#,
+                    true,
+                ),
+                (r#"(in-set "User-1" audience)"#, true),
+                (r#"(in-set (uppercase "user-1") audience)"#, true),
+            ] {
+                let expr = crate::parse(source, &mut interner).unwrap();
+                let evaluator = Evaluator::new(&interner);
+                assert_eq!(
+                    evaluator.eval(&expr, &env),
+                    Ok(Value::Bool(expected)),
+                    "{} after {}",
+                    source,
+                    first
+                );
+                let compiled = crate::compile(&expr, &interner);
+                assert_eq!(
+                    compiled.eval(&env, &interner),
+                    Ok(Value::Bool(expected)),
+                    "{} after {}",
+                    source,
+                    first
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn regex() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let email = interner.intern("ada@example.com");
+        env.insert(interner.intern("email"), Value::String(email));
+
+        let matches =
+            crate::parse(r#"(matches-regex email "@example\\.com$")"#, &mut interner).unwrap();
+        let computed =
+            crate::parse(r#"(matches-regex email (concat "^" "ada"))"#, &mut interner).unwrap();
+        let invalid = crate::parse(r#"(matches-regex email "(")"#, &mut interner).unwrap();
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(evaluator.eval(&matches, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&matches, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&computed, &env), Ok(Value::Bool(true)));
+        assert!(matches!(
+            evaluator.eval(&invalid, &env),
+            Err(EvalError::InvalidRegex(_))
+        ));
+    }
+
+    #[test]
+    fn geo_within_radius() {
+        let mut interner = StringInterner::new();
+        // Eiffel Tower to Louvre is roughly 3.2km
+        let point = vec![
+            Expr::Literal(Value::Float(48.8584)),
+            Expr::Literal(Value::Float(2.2945)),
+            Expr::Literal(Value::Float(48.8606)),
+            Expr::Literal(Value::Float(2.3376)),
+        ];
+        let mut near = point.clone();
+        near.push(Expr::Literal(Value::Integer(5_000)));
+        let mut far = point;
+        far.push(Expr::Literal(Value::Integer(1_000)));
+        let near = call(&mut interner, "geo_within_radius", near);
+        let far = call(&mut interner, "geo_within_radius", far);
+
+        let evaluator = Evaluator::new(&interner);
+        let env = Environment::new();
+        assert_eq!(evaluator.eval(&near, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&far, &env), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn geo_distance() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        for (name, lat, lng) in [("user", 48.8584, 2.2945), ("store", 48.8606, 2.3376)] {
+            let point = Value::List(vec![Value::Float(lat), Value::Float(lng)]);
+            env.insert(interner.intern(name), point);
+        }
+        let expr = crate::parse("(geo-distance user store)", &mut interner).unwrap();
+        let rule = crate::parse("(< (geo-distance user store) 5000)", &mut interner).unwrap();
+
+        for distance in [DistanceFormula::Haversine, DistanceFormula::Planar] {
+            let options = EvalOptions {
+                distance,
+                ..EvalOptions::default()
+            };
+            let evaluator = Evaluator::with_options(&interner, options);
+            let meters = evaluator.eval(&expr, &env).unwrap().as_float().unwrap();
+            assert!(
+                (meters - 3_170.0).abs() < 10.0,
+                "{:?}: {}",
+                distance,
+                meters
+            );
+            assert_eq!(evaluator.eval(&rule, &env), Ok(Value::Bool(true)));
+        }
+
+        // The formulas differ over long distances, so constant calls are
+        // not folded with the default one
+        let expr = crate::parse("(geo-distance [0 0] [60 179])", &mut interner).unwrap();
+        let options = EvalOptions {
+            distance: DistanceFormula::Planar,
+            ..EvalOptions::default()
+        };
+        let planar = Evaluator::with_options(&interner, options).eval(&expr, &env);
+        let haversine = Evaluator::new(&interner).eval(&expr, &env);
+        assert_ne!(planar, haversine);
+        let compiled = compile(&expr, &interner);
+        assert_eq!(compiled.eval_with(&env, &interner, &options), planar);
+        let simplified = crate::optimize::simplify(expr, &interner);
+        assert!(simplified.is_call(), "{:?}", simplified);
+
+        let expr = crate::parse("(geo-distance user 48.8)", &mut interner).unwrap();
+        assert!(matches!(
+            Evaluator::new(&interner).eval(&expr, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::GeoDistance,
+                expected: "[lat lng] pair",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn geo_cells() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("cell"),
+            Value::String(interner.intern("u09tunq")),
+        );
+        env.insert(interner.intern("h3"), Value::Integer(0x8928308280fffff));
+        // Long enough to be compiled to a member set
+        let mut parents: Vec<i64> = (0..20).collect();
+        parents.push(0x8828308281fffff);
+        let parents = format!("{:?}", parents).replace(',', "");
+        let cases = [
+            (r#"(geo-cell-in cell ["u0d" "u09t"])"#, true),
+            (r#"(geo-cell-in cell ["u0d" "u09tunq"])"#, true),
+            (r#"(geo-cell-in cell ["u0d" "u09tunqx"])"#, false),
+            ("(geo-cell-in cell [])", false),
+            (&*format!("(geo-cell-in h3 {})", parents), true),
+            ("(geo-cell-in h3 [613196570331971583])", true),
+            ("(geo-cell-in h3 [613196570334068735])", false),
+            ("(geo-cell-in h3 [617700169958293503])", true),
+            ("(geo-cell-in 42 [42])", true),
+            (
+                r#"(geo-cell-in (geohash 48.8584 2.2945 9) ["u09tun"])"#,
+                true,
+            ),
+            (r#"(= (geohash 48.8584 2.2945 6) "u09tun")"#, true),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let expected = Ok(Value::Bool(expected));
+            assert_eq!(
+                Evaluator::new(&interner).eval(&expr, &env),
+                expected,
+                "{}",
+                source
+            );
+            assert_eq!(
+                compile(&expr, &interner).eval(&env, &interner),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        for (source, found) in [
+            ("(geohash 91 0 5)", ValueType::Float),
+            ("(geohash 0 0 13)", ValueType::Integer),
+            ("(geo-cell-in cell \"u09\")", ValueType::String),
+        ] {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let result = Evaluator::new(&interner).eval(&expr, &env);
+            assert!(
+                matches!(&result, Err(EvalError::TypeMismatch { found: f, .. }) if *f == found),
+                "{}: {:?}",
+                source,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn geo_polygon_and_bbox() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        // Roughly Manhattan, as a quadrilateral
+        let manhattan = crate::parse(
+            "[[40.700 -74.020] [40.880 -73.930] [40.800 -73.910] [40.710 -73.970]]",
+            &mut interner,
+        )
+        .unwrap();
+        let polygon = Evaluator::new(&interner).eval(&manhattan, &env).unwrap();
+        env.insert(interner.intern("manhattan"), polygon);
+
+        let cases = [
+            // Times Square
+            ("(geo_within_polygon 40.758 -73.985 manhattan)", true),
+            // Brooklyn
+            ("(geo_within_polygon 40.678 -73.944 manhattan)", false),
+            ("(geo_within_polygon 1 1 [[0 0] [0 2] [2 2] [2 0]])", true),
+            (
+                "(geo_within_bbox 40.758 -73.985 40.70 -74.02 40.88 -73.91)",
+                true,
+            ),
+            (
+                "(geo_within_bbox 40.758 -73.985 40.70 -73.95 40.88 -73.91)",
+                false,
+            ),
+            // A box spanning the antimeridian around Fiji
+            ("(geo_within_bbox -17.7 -179.9 -21 177 -12 -178)", true),
+            ("(geo_within_bbox -17.7 170 -21 177 -12 -178)", false),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            assert_eq!(
+                evaluator.eval(&expr, &env),
+                Ok(Value::Bool(expected)),
+                "{}",
+                source
+            );
+        }
+
+        let expr = crate::parse("(geo_within_polygon 1 1 [[0 0] [0 2]])", &mut interner).unwrap();
+        assert!(matches!(
+            Evaluator::new(&interner).eval(&expr, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::GeoWithinPolygon,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut registry = FunctionRegistry::new();
+        let counter = Arc::clone(&calls);
+        registry.register("expensive", move |_| {
+            counter.fetch_add(1, AtomicOrdering::Relaxed);
+            Ok(Value::Bool(true))
+        });
+
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        let sources = [
+            ("(and (< age 18) (expensive) missing)", Value::Bool(false)),
+            ("(or (> age 18) (expensive) missing)", Value::Bool(true)),
+            (
+                "(and (> age 18) false (expensive) (+ 1 2))",
+                Value::Bool(false),
+            ),
+        ]
+        .map(|(source, expected)| (crate::parse(source, &mut interner).unwrap(), expected));
+        let or_null = crate::parse("(or (expensive) null false)", &mut interner).unwrap();
+
+        let lazy = Evaluator::new(&interner).with_functions(&registry);
+        let strict = Evaluator::with_options(
+            &interner,
+            EvalOptions {
+                strict: true,
+                ..EvalOptions::default()
+            },
+        )
+        .with_functions(&registry);
+        for (expr, expected) in sources {
+            let source = expr.to_sexpr(&interner);
+            let before = calls.load(AtomicOrdering::Relaxed);
+            assert_eq!(lazy.eval(&expr, &env), Ok(expected), "{}", source);
+            assert_eq!(calls.load(AtomicOrdering::Relaxed), before, "{}", source);
+            assert!(strict.eval(&expr, &env).is_err(), "{}", source);
+            assert_eq!(
+                calls.load(AtomicOrdering::Relaxed),
+                before + 1,
+                "{}",
+                source
+            );
+        }
+
+        // Without errors, strict evaluation only differs in the work it does
+        assert_eq!(strict.eval(&or_null, &env), Ok(Value::Bool(true)));
+        assert_eq!(calls.load(AtomicOrdering::Relaxed), 4);
+    }
+
+    #[test]
+    fn custom_functions() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("risk-score", |args: &[Value]| match args {
+            [Value::Integer(age), Value::Bool(vip)] => {
+                Ok(Value::Integer(if *vip { 0 } else { 100 - age }))
+            }
+            _ => Err(EvalError::Custom("risk-score expects age and vip".into())),
+        });
+        // Builtins take precedence over registered functions
+        registry.register("not", |_| Ok(Value::Bool(true)));
+
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        env.insert(interner.intern("vip"), Value::Bool(false));
+        let high = crate::parse("(> (risk-score age vip) 50)", &mut interner).unwrap();
+        let bad = crate::parse("(risk-score vip)", &mut interner).unwrap();
+        let not = crate::parse("(not true)", &mut interner).unwrap();
+
+        let evaluator = Evaluator::new(&interner).with_functions(&registry);
+        assert_eq!(evaluator.eval(&high, &env), Ok(Value::Bool(true)));
+        assert_eq!(
+            evaluator.eval(&bad, &env),
+            Err(EvalError::Custom("risk-score expects age and vip".into()))
+        );
+        assert_eq!(evaluator.eval(&not, &env), Ok(Value::Bool(false)));
+        assert_eq!(
+            evaluator.eval_batch(&high, core::slice::from_ref(&env)),
+            vec![Ok(Value::Bool(true))]
+        );
+
+        let unregistered = Evaluator::new(&interner);
+        assert_eq!(
+            unregistered.eval(&high, &env),
+            Err(EvalError::UnknownFunction(
+                interner.get_id("risk-score").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut interner = StringInterner::new();
+        let mut registry = FunctionRegistry::new();
+        registry.register("coin", |_| Ok(Value::Bool(true)));
+        registry.register_deterministic("double", |args| match args {
+            [Value::Integer(n)] => Ok(Value::Integer(n * 2)),
+            _ => Ok(Value::Null),
+        });
+        let options = EvalOptions {
+            deterministic: true,
+            ..EvalOptions::default()
+        };
+        let env = Environment::new();
+
+        let cases = [
+            ("(= (double 21) 42)", Ok(Value::Bool(true))),
+            (
+                "(and (coin) true)",
+                Err(EvalError::Nondeterministic(interner.intern("coin"))),
+            ),
+            // Checked where the call is reached, like unknown functions
+            ("(or true (coin))", Ok(Value::Bool(true))),
+        ];
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::with_options(&interner, options).with_functions(&registry);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            let compiled = compile_with(&expr, &interner, &registry);
+            assert_eq!(
+                compiled.eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        // Portable float functions agree closely with the platform's
+        let expr = crate::parse(
+            "(geo-distance [48.8584 2.2945] [-33.8568 151.2153])",
+            &mut interner,
+        )
+        .unwrap();
+        let portable = Evaluator::with_options(&interner, options).eval(&expr, &env);
+        let native = Evaluator::new(&interner).eval(&expr, &env);
+        let (portable, native) = (
+            portable.unwrap().as_float().unwrap(),
+            native.unwrap().as_float().unwrap(),
+        );
+        assert!((portable - native).abs() < 1e-6, "{} {}", portable, native);
+
+        // NaN results are canonical whatever produced them
+        let mut env = Environment::new();
+        env.insert(interner.intern("inf"), Value::Float(f64::INFINITY));
+        let expr = crate::parse("(- inf inf)", &mut interner).unwrap();
+        match Evaluator::new(&interner).eval(&expr, &env) {
+            Ok(Value::Float(nan)) => assert_eq!(nan.to_bits(), f64::NAN.to_bits()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let mut interner = StringInterner::new();
+        let env = Environment::new();
+        let limits = EvalLimits {
+            max_depth: 4,
+            max_steps: 5,
+            max_string_len: 5,
+            max_eval_depth: 1,
+        };
+        let options = EvalOptions {
+            limits,
+            ..EvalOptions::default()
+        };
+        let cases = [
+            ("(not (not (not (not true))))", Limit::Depth),
+            ("(and true true true true true)", Limit::Steps),
+            (r#"(concat "abc" "def")"#, Limit::StringLength),
+        ]
+        .map(|(source, limit)| (crate::parse(source, &mut interner).unwrap(), limit));
+
+        let evaluator = Evaluator::with_options(&interner, options);
+        for (expr, limit) in cases {
+            let source = expr.to_sexpr(&interner);
+            let expected = Err(EvalError::LimitExceeded(limit));
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            let compiled = compile(&expr, &interner);
+            assert_eq!(
+                compiled.eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+            assert!(Evaluator::new(&interner).eval(&expr, &env).is_ok());
+        }
+    }
+
+    #[test]
+    fn maps() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let headers = [("x-tenant", "acme"), ("accept", "text/html")]
+            .into_iter()
+            .map(|(key, value)| {
+                let value = Value::String(interner.intern(value));
+                (interner.intern(key), value)
+            })
+            .collect();
+        env.insert(interner.intern("headers"), Value::Map(headers));
+        env.insert(
+            interner.intern("tags"),
+            Value::StringList(Vec::new().into()),
+        );
+        let cases = [
+            (
+                r#"(= (get headers "x-tenant") "acme")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(get headers 'accept)"#,
+                Ok(Value::String(interner.intern("text/html"))),
+            ),
+            (r#"(get headers "cookie")"#, Ok(Value::Null)),
+            (
+                r#"(has-key headers (concat "x-" "tenant"))"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(has-key headers (concat "x-" "unseen"))"#,
+                Ok(Value::Bool(false)),
+            ),
+            (r#"(has-key missing "x-tenant")"#, Ok(Value::Null)),
+            (
+                r#"(get tags "x-tenant")"#,
+                Err(EvalError::TypeMismatch {
+                    function: BuiltinFunction::Get,
+                    expected: "map",
+                    found: ValueType::StringList,
+                }),
+            ),
+            (
+                "(has-key headers 1)",
+                Err(EvalError::TypeMismatch {
+                    function: BuiltinFunction::HasKey,
+                    expected: "string",
+                    found: ValueType::Integer,
+                }),
+            ),
+        ]
+        .map(|(source, expected)| (crate::parse(source, &mut interner).unwrap(), expected));
+
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        let evaluator = Evaluator::with_options(&interner, options);
+        for (expr, expected) in cases {
+            let source = expr.to_sexpr(&interner);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn semver() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("app_version"),
+            Value::String(interner.intern("2.10.1")),
+        );
+        env.insert(interner.intern("build"), Value::Integer(7));
+        let invalid = |message: &str| Err(EvalError::InvalidVersion(message.to_string()));
+        let cases = [
+            (r#"(semver-gt app_version "2.9.0")"#, Ok(Value::Bool(true))),
+            (
+                r#"(semver-lt app_version "2.10.1-rc.1")"#,
+                Ok(Value::Bool(false)),
+            ),
+            (
+                r#"(semver-eq app_version "2.10.1+ios")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(semver-matches app_version ">=2.3.0, <3")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(semver-matches app_version "~2.9")"#,
+                Ok(Value::Bool(false)),
+            ),
+            (
+                r#"(semver-matches "3.0.0" (concat "^" "3"))"#,
+                Ok(Value::Bool(true)),
+            ),
+            (r#"(semver-gt missing "1.0.0")"#, Ok(Value::Null)),
+            (
+                r#"(semver-gt app_version "2.x")"#,
+                invalid("`2.x` is not a semantic version"),
+            ),
+            (
+                r#"(semver-matches "1.0" ">=1")"#,
+                invalid("`1.0` is not a semantic version"),
+            ),
+            (
+                r#"(semver-matches app_version ">=2.3.0; <3")"#,
+                invalid("`>=2.3.0; <3` is not a version range"),
+            ),
+            (
+                r#"(semver-eq build "7.0.0")"#,
+                Err(EvalError::TypeMismatch {
+                    function: BuiltinFunction::SemverEq,
+                    expected: "string",
+                    found: ValueType::Integer,
+                }),
+            ),
+        ]
+        .map(|(source, expected)| (crate::parse(source, &mut interner).unwrap(), expected));
+
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        let evaluator = Evaluator::with_options(&interner, options);
+        for (expr, expected) in cases {
+            let source = expr.to_sexpr(&interner);
+            // Twice, so the second run uses the cached operand
+            for _ in 0..2 {
+                assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            }
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn ip_addresses() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("client_ip"),
+            Value::String(interner.intern("192.168.1.42")),
+        );
+        env.insert(
+            interner.intern("peer"),
+            Value::String(interner.intern("2001:db8::7")),
+        );
+        let cases = [
+            (
+                r#"(ip-in-cidr client_ip "192.168.0.0/16")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(ip-in-cidr client_ip "10.0.0.0/8")"#,
+                Ok(Value::Bool(false)),
+            ),
+            (
+                r#"(ip-in-cidr peer "2001:db8::/32")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(ip-in-cidr peer (concat "2001:db8::" "/32"))"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(ip-in-range client_ip "192.168.1.10" "192.168.1.99")"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(ip-in-range peer "192.168.1.10" "192.168.1.99")"#,
+                Ok(Value::Bool(false)),
+            ),
+            (r#"(ip-in-cidr missing "10.0.0.0/8")"#, Ok(Value::Null)),
+            (
+                r#"(ip-in-cidr client_ip "10.0.0.0/40")"#,
+                Err(EvalError::InvalidAddress(
+                    "`10.0.0.0/40` is not a CIDR block".to_string(),
+                )),
+            ),
+            (
+                r#"(ip-in-range "localhost" "10.0.0.1" "10.0.0.9")"#,
+                Err(EvalError::InvalidAddress(
+                    "`localhost` is not an IP address".to_string(),
+                )),
+            ),
+        ]
+        .map(|(source, expected)| (crate::parse(source, &mut interner).unwrap(), expected));
+
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        let evaluator = Evaluator::with_options(&interner, options);
+        for (expr, expected) in cases {
+            let source = expr.to_sexpr(&interner);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn percent_of() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("user_id"),
+            Value::String(interner.intern("user-1")),
+        );
+        env.insert(interner.intern("account"), Value::Integer(42));
+        let cases = [
+            (
+                r#"(percent-of user_id "experiment-42" 10)"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(percent-of user_id "experiment-42" 9.9)"#,
+                Ok(Value::Bool(false)),
+            ),
+            (
+                r#"(percent-of user_id "experiment-42" 0)"#,
+                Ok(Value::Bool(false)),
+            ),
+            (
+                r#"(percent-of user_id "checkout-v2" 9)"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(= (percent-of account 'exp 41.17) (percent-of "42" "exp" 41.17))"#,
+                Ok(Value::Bool(true)),
+            ),
+            (
+                r#"(percent-of missing "experiment-42" 100)"#,
+                Ok(Value::Null),
+            ),
+            (
+                r#"(percent-of user_id "experiment-42" "all")"#,
+                Err(EvalError::TypeMismatch {
+                    function: BuiltinFunction::PercentOf,
+                    expected: "number",
+                    found: ValueType::String,
+                }),
+            ),
+        ]
+        .map(|(source, expected)| (crate::parse(source, &mut interner).unwrap(), expected));
+
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        let evaluator = Evaluator::with_options(&interner, options);
+        for (expr, expected) in cases {
+            let source = expr.to_sexpr(&interner);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn experiment_bucket() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("user_id"),
+            Value::String(interner.intern("user-1")),
+        );
+        env.insert(interner.intern("account"), Value::Integer(42));
+        let [a, b] = ["a", "b"].map(|name| Value::String(interner.intern(name)));
+        // `user-1` lands in bucket 994 under `experiment-42`
+        let cases = [
+            (
+                r#"(experiment-bucket user_id "experiment-42" ["a" "b"] [10 90])"#,
+                a,
+            ),
+            (
+                r#"(experiment-bucket user_id "experiment-42" ["a" "b"] [9.9 90.1])"#,
+                b.clone(),
+            ),
+            (
+                r#"(experiment-bucket user_id "experiment-42" ["a" "b" "c"] [0 1 0])"#,
+                b,
+            ),
+            (
+                r#"(= (experiment-bucket account "exp" [1 2 3] [1 1 1])
+                      (experiment-bucket "42" "exp" [1 2 3] [1 1 1]))"#,
+                Value::Bool(true),
+            ),
+            (
+                r#"(experiment-bucket missing "exp" ["a"] [1])"#,
+                Value::Null,
+            ),
+        ];
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::with_options(&interner, options);
+            let expected = Ok(expected);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        for source in [
+            r#"(experiment-bucket user_id "exp" ["a" "b"] [1])"#,
+            r#"(experiment-bucket user_id "exp" ["a" "b"] [1 -1])"#,
+            r#"(experiment-bucket user_id "exp" ["a" "b"] [0 0])"#,
+            r#"(experiment-bucket user_id "exp" "a" [1])"#,
+        ] {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let result = Evaluator::new(&interner).eval(&expr, &env);
+            assert!(
+                matches!(
+                    result,
+                    Err(EvalError::TypeMismatch {
+                        function: BuiltinFunction::ExperimentBucket,
+                        ..
+                    })
+                ),
+                "{}: {:?}",
+                source,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn quote_and_eval() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let rule = interner.intern("rule");
+        let quoted = crate::parse("(quote (> age 18))", &mut interner).unwrap();
+        let meta =
+            crate::parse("(let ((age 5)) (and (eval rule) (> age 1)))", &mut interner).unwrap();
+        let looping = crate::parse("(eval (quote (eval rule)))", &mut interner).unwrap();
+        let looping_rule = crate::parse("(quote (eval rule))", &mut interner).unwrap();
+        let adult = crate::parse("(> age 18)", &mut interner).unwrap();
+
+        let plain = Evaluator::new(&interner);
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(30));
+        assert_eq!(
+            plain.eval(&quoted, &env),
+            Ok(Value::Quoted(Box::new(adult.clone())))
+        );
+        env.insert(rule, Value::Quoted(Box::new(adult)));
+        assert_eq!(plain.eval(&meta, &env), Err(EvalError::EvalNotAllowed));
+        assert_eq!(
+            compile(&meta, &interner).eval(&env, &interner),
+            Err(EvalError::EvalNotAllowed)
+        );
+
+        let options = EvalOptions {
+            allow_eval: true,
+            ..EvalOptions::default()
+        };
+        // The quoted rule reads `age` from the environment, not the local
+        let sandboxed = Evaluator::with_options(&interner, options);
+        assert_eq!(sandboxed.eval(&meta, &env), Ok(Value::Bool(true)));
+        env.insert(age, Value::Integer(12));
+        assert_eq!(sandboxed.eval(&meta, &env), Ok(Value::Bool(false)));
+
+        // A rule that evaluates itself stops at the nesting limit
+        env.insert(rule, sandboxed.eval(&looping_rule, &env).unwrap());
+        assert_eq!(
+            sandboxed.eval(&looping, &env),
+            Err(EvalError::LimitExceeded(Limit::EvalDepth))
+        );
+        let limited = Evaluator::with_options(
+            &interner,
+            EvalOptions {
+                limits: EvalLimits {
+                    max_steps: 50,
+                    ..EvalLimits::UNLIMITED
+                },
+                ..options
+            },
+        );
+        assert_eq!(
+            limited.eval(&looping, &env),
+            Err(EvalError::LimitExceeded(Limit::Steps))
+        );
+
+        env.insert(rule, Value::Integer(1));
+        assert!(matches!(
+            sandboxed.eval(&looping, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::Eval,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn int_ranges() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(21));
+        env.insert(interner.intern("band"), Value::IntRange(18, 24));
+        let cases = [
+            ("(int-range 18 24)", Value::IntRange(18, 24)),
+            ("(in-range age band)", Value::Bool(true)),
+            ("(in-range 24 band)", Value::Bool(true)),
+            ("(in-range 25 band)", Value::Bool(false)),
+            ("(in-range 17.5 band)", Value::Bool(false)),
+            ("(in-range 24.0d band)", Value::Bool(true)),
+            ("(ranges-overlap band (int-range 24 30))", Value::Bool(true)),
+            ("(ranges-overlap band (int-range 0 17))", Value::Bool(false)),
+            ("(= band (int-range 18 24))", Value::Bool(true)),
+            ("(in-range missing band)", Value::Null),
+        ];
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::with_options(&interner, options);
+            let expected = Ok(expected);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        for (source, function) in [
+            ("(int-range 24 18)", BuiltinFunction::IntRange),
+            ("(int-range 1 2.5)", BuiltinFunction::IntRange),
+            ("(in-range age [18 24])", BuiltinFunction::InRange),
+            ("(ranges-overlap band 3)", BuiltinFunction::RangesOverlap),
+        ] {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let result = Evaluator::new(&interner).eval(&expr, &env);
+            assert!(
+                matches!(result, Err(EvalError::TypeMismatch { function: f, .. }) if f == function),
+                "{}: {:?}",
+                source,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn set_operations() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        let segments = crate::parse(r#"["sports" "news" "music"]"#, &mut interner).unwrap();
+        let segments = Evaluator::new(&interner).eval(&segments, &env).unwrap();
+        env.insert(interner.intern("segments"), segments);
+        env.insert(
+            interner.intern("ids"),
+            Value::IntegerList(vec![3, 18, 40, 3].into()),
+        );
+        let long: String = (1..=20).map(|n| format!(" {}", n)).collect();
+        let cases = [
+            (
+                r#"(union segments ["news" "travel"] [])"#,
+                r#"["sports" "news" "music" "travel"]"#,
+            ),
+            (
+                r#"(intersection segments ["news" "music"] ["music"])"#,
+                r#"["music"]"#,
+            ),
+            (r#"(difference segments ["news"])"#, r#"["sports" "music"]"#),
+            (r#"(subset? ["news"] segments)"#, "true"),
+            (r#"(subset? segments ["news"])"#, "false"),
+            (r#"(disjoint? segments ["travel"])"#, "true"),
+            ("(union ids [1 2.5])", "[3 18 40 1 2.5]"),
+            (&format!("(intersection ids [{}])", long), "[3 18]"),
+            (&format!("(difference ids [{}])", long), "[40]"),
+            (&format!("(subset? ids [{}])", long), "false"),
+            (&format!("(subset? [2 3.0] [{}])", long), "true"),
+            (&format!("(disjoint? [21 22] [{}])", long), "true"),
+            ("(union missing ids)", "null"),
+        ];
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let expected = crate::parse(expected, &mut interner).unwrap();
+            let evaluator = Evaluator::with_options(&interner, options);
+            let expected = evaluator.eval(&expected, &env);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        for source in [
+            "(union ids 3)",
+            r#"(difference "news" segments)"#,
+            &format!("(subset? 3 [{}])", long),
+        ] {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            assert!(
+                matches!(
+                    compile(&expr, &interner).eval(&env, &interner),
+                    Err(EvalError::TypeMismatch { .. })
+                ),
+                "{}",
+                source
+            );
+            assert_eq!(
+                Evaluator::new(&interner).eval(&expr, &env),
+                compile(&expr, &interner).eval(&env, &interner),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn bloom_sets() {
+        let mut interner = StringInterner::new();
+        let mut audience = crate::BloomSet::new(1_000, 0.001);
+        for n in 0..1_000 {
+            audience.insert_int(n * 10);
+        }
+        audience.insert_str("vip");
+        let mut env = Environment::new();
+        env.insert(
+            interner.intern("audience"),
+            Value::BloomSet(audience.clone()),
+        );
+        env.insert(interner.intern("user_id"), Value::Integer(420));
+        let cases = [
+            ("(in-set user_id audience)", Value::Bool(true)),
+            ("(in-set 420.0 audience)", Value::Bool(true)),
+            ("(in-set \"vip\" audience)", Value::Bool(true)),
+            ("(in-set \"420\" audience)", Value::Bool(false)),
+            ("(in-set [420] audience)", Value::Bool(false)),
+            ("(in-set missing audience)", Value::Null),
+        ];
+        let options = EvalOptions {
+            missing: MissingVariable::Null,
+            ..EvalOptions::default()
+        };
+        for (source, expected) in cases {
+            let expr = crate::parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::with_options(&interner, options);
+            let expected = Ok(expected);
+            assert_eq!(evaluator.eval(&expr, &env), expected, "{}", source);
+            assert_eq!(
+                compile(&expr, &interner).eval_with(&env, &interner, &options),
+                expected,
+                "{}",
+                source
+            );
+        }
+
+        // A set attached to the expression instead of the environment
+        let builder = crate::ExprBuilder::new(&mut interner);
+        let expr = builder.in_set(builder.var("user_id"), Value::BloomSet(audience));
+        assert_eq!(
+            Evaluator::new(&interner).eval(&expr, &env),
+            Ok(Value::Bool(true))
+        );
+
+        let expr = crate::parse("(in-set user_id [420])", &mut interner).unwrap();
+        assert!(matches!(
+            Evaluator::new(&interner).eval(&expr, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::InSet,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn provided_variables() {
+        let mut interner = StringInterner::new();
+        let (tier, ltv) = (interner.intern("tier"), interner.intern("ltv"));
+        let [rule, exists] = [
+            r#"(or (= tier "gold") (> ltv 1000))"#,
+            "(and (exists ltv) (not (exists churn)))",
+        ]
+        .map(|source| crate::parse(source, &mut interner).unwrap());
+        let gold = interner.intern("gold");
+        let silver = interner.intern("silver");
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let provider = move |name: StringId| {
+            counter.fetch_add(1, AtomicOrdering::Relaxed);
+            (name == ltv).then_some(Value::Integer(1500))
+        };
+
+        let evaluator = Evaluator::new(&interner).with_provider(&provider);
+        let compiled = compile(&rule, &interner);
+        let options = EvalOptions::default();
+        let mut env = Environment::new();
+        env.insert(tier, Value::String(gold));
+        assert_eq!(evaluator.eval(&rule, &env), Ok(Value::Bool(true)));
+        assert_eq!(
+            compiled.eval_with_provider(&env, &interner, &options, &provider),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(fetches.load(AtomicOrdering::Relaxed), 0);
+
+        env.insert(tier, Value::String(silver));
+        assert_eq!(evaluator.eval(&rule, &env), Ok(Value::Bool(true)));
+        assert_eq!(
+            compiled.eval_with_provider(&env, &interner, &options, &provider),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            evaluator.eval_batch(&rule, core::slice::from_ref(&env)),
+            vec![Ok(Value::Bool(true))]
+        );
+        assert_eq!(fetches.load(AtomicOrdering::Relaxed), 3);
+
+        // The environment takes precedence, and unprovided variables are
+        // missing as usual
+        env.insert(ltv, Value::Integer(10));
+        assert_eq!(evaluator.eval(&rule, &env), Ok(Value::Bool(false)));
+        env.remove(ltv);
+        assert_eq!(evaluator.eval(&exists, &env), Ok(Value::Bool(true)));
+        assert_eq!(
+            compile(&exists, &interner).eval_with_provider(&env, &interner, &options, &provider),
+            Ok(Value::Bool(true))
+        );
+        let churn = crate::parse("(> churn 0.5)", &mut interner).unwrap();
+        let evaluator = Evaluator::new(&interner).with_provider(&provider);
+        assert!(matches!(
+            evaluator.eval(&churn, &env),
+            Err(EvalError::UnknownVariable(_))
+        ));
+    }
+
+    #[test]
+    fn cancellation() {
+        let token = CancelToken::new();
+        let mut registry = FunctionRegistry::new();
+        let tripwire = token.clone();
+        registry.register("trip", move |_| {
+            tripwire.cancel();
+            Ok(Value::Bool(true))
+        });
+
+        let mut interner = StringInterner::new();
+        let env = Environment::new();
+        let [tripped, plain] = ["(and (trip) (not false))", "(not false)"]
+            .map(|source| crate::parse(source, &mut interner).unwrap());
+
+        let evaluator = Evaluator::new(&interner)
+            .with_functions(&registry)
+            .with_cancel_token(token.clone());
+        assert_eq!(evaluator.eval(&plain, &env), Ok(Value::Bool(true)));
+        assert_eq!(evaluator.eval(&tripped, &env), Err(EvalError::Cancelled));
+        assert!(token.is_cancelled());
+        assert_eq!(evaluator.eval(&plain, &env), Err(EvalError::Cancelled));
+        assert_eq!(
+            evaluator.eval_batch(&plain, &[Environment::new()]),
+            vec![Err(EvalError::Cancelled)]
+        );
+        token.reset();
+        assert_eq!(evaluator.eval(&plain, &env), Ok(Value::Bool(true)));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn deadlines() {
+        use core::time::Duration;
+
+        let mut registry = FunctionRegistry::new();
+        registry.register("slow", |_| {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(Value::Bool(true))
+        });
+
+        let mut interner = StringInterner::new();
+        let env = Environment::new();
+        let [slow, plain] =
+            ["(slow)", "(not false)"].map(|source| crate::parse(source, &mut interner).unwrap());
+        let evaluator = Evaluator::new(&interner).with_functions(&registry);
+
+        let past = Instant::now();
+        assert_eq!(
+            evaluator.eval_with_deadline(&plain, &env, past),
+            Err(EvalError::Cancelled)
+        );
+        let soon = Instant::now() + Duration::from_millis(5);
+        assert_eq!(
+            evaluator.eval_with_deadline(&slow, &env, soon),
+            Err(EvalError::Cancelled)
+        );
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(
+            evaluator.eval_with_deadline(&slow, &env, later),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn batch() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let expr = call(
+            &mut interner,
+            ">=",
+            vec![Expr::Variable(age), Expr::Literal(Value::Integer(18))],
+        );
+        let envs: Vec<Environment> = [Some(30), Some(12), None]
+            .into_iter()
+            .map(|n| n.map(|n| (age, Value::Integer(n))).into_iter().collect())
+            .collect();
+
+        let evaluator = Evaluator::new(&interner);
+        let results = evaluator.eval_batch(&expr, &envs);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Value::Bool(true)),
+                Ok(Value::Bool(false)),
+                Err(EvalError::UnknownVariable(age)),
+            ]
+        );
+        for (env, result) in envs.iter().zip(&results) {
+            assert_eq!(&evaluator.eval(&expr, env), result);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let mut interner = StringInterner::new();
+        let missing = interner.intern("missing");
+        let unknown = call(&mut interner, "frobnicate", vec![]);
+        let arity = call(&mut interner, "=", vec![Expr::Variable(missing)]);
+        let not_bool = call(&mut interner, "and", vec![Expr::Literal(Value::Integer(1))]);
+
+        let evaluator = Evaluator::new(&interner);
+        let env = Environment::new();
+        assert_eq!(
+            evaluator.eval(&Expr::Variable(missing), &env),
+            Err(EvalError::UnknownVariable(missing))
+        );
+        assert!(matches!(
+            evaluator.eval(&unknown, &env),
+            Err(EvalError::UnknownFunction(_))
+        ));
+        assert_eq!(
+            evaluator.eval(&arity, &env),
+            Err(EvalError::ArityMismatch {
+                function: BuiltinFunction::Equal,
+                expected: Arity::Exact(2),
+                found: 1,
+            })
+        );
+        assert_eq!(
+            evaluator.eval(&not_bool, &env),
+            Err(EvalError::TypeMismatch {
+                function: BuiltinFunction::And,
+                expected: "boolean",
+                found: ValueType::Integer,
+            })
+        );
+    }
+}
@@ -3,12 +3,22 @@
 //! This module defines the AST for parsed S-expressions and how they map
 //! to the runtime evaluation system.
 
+use crate::suggest;
 use crate::{StringId, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub use crate::diff::{diff, Change, ExprDiff};
+#[cfg(feature = "json")]
+pub use crate::json::{from_json, to_json};
 
 /// Represents a parsed S-expression
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Expr {
-    /// Literal value (string, integer, float)
+    /// Literal value
     Literal(Value),
     
     /// Variable reference
@@ -24,6 +34,27 @@ pub enum Expr {
     
     /// List literal
     List(Vec<Expr>),
+
+    /// Expression with key/value metadata attached, which evaluates exactly
+    /// like `expr`
+    Annotated {
+        /// Metadata keys (interned) and values, in source order
+        metadata: Vec<(StringId, Value)>,
+        /// Annotated expression
+        expr: Box<Expr>,
+    },
+
+    /// Local variables bound for the evaluation of `body`
+    ///
+    /// Bindings are evaluated in order and each may read the ones before
+    /// it. A local shadows any variable of the same name in the
+    /// environment.
+    Let {
+        /// Local names (interned) and the expressions bound to them
+        bindings: Vec<(StringId, Expr)>,
+        /// Expression evaluated with the locals bound
+        body: Box<Expr>,
+    },
 }
 
 impl Expr {
@@ -46,9 +77,66 @@ impl Expr {
     pub fn is_list(&self) -> bool {
         matches!(self, Expr::List(_))
     }
+
+    /// Check if this expression binds local variables
+    pub fn is_let(&self) -> bool {
+        matches!(self, Expr::Let { .. })
+    }
+
+    /// Attach metadata to this expression, after any it already has
+    pub fn annotate(self, metadata: Vec<(StringId, Value)>) -> Expr {
+        match self {
+            Expr::Annotated {
+                metadata: mut existing,
+                expr,
+            } => {
+                existing.extend(metadata);
+                Expr::Annotated {
+                    metadata: existing,
+                    expr,
+                }
+            }
+            expr => Expr::Annotated {
+                metadata,
+                expr: Box::new(expr),
+            },
+        }
+    }
+
+    /// Get the metadata attached to this expression, empty if it has none
+    pub fn metadata(&self) -> &[(StringId, Value)] {
+        match self {
+            Expr::Annotated { metadata, .. } => metadata,
+            _ => &[],
+        }
+    }
+
+    /// Get the value of a metadata key, the last one if it repeats
+    pub fn metadata_value(&self, key: StringId) -> Option<&Value> {
+        self.metadata()
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Get the expression without its metadata
+    pub fn unannotated(&self) -> &Expr {
+        match self {
+            Expr::Annotated { expr, .. } => expr.unannotated(),
+            expr => expr,
+        }
+    }
 }
 
+/// Variable naming the element the second argument of `any`, `all`,
+/// `filter` or `map` is evaluated on
+pub const ELEMENT: &str = "_";
+
 /// Built-in functions supported by the expression engine
+///
+/// New builtins are added at the end, so the IDs of existing ones in
+/// `StringInterner::with_builtins` stay the same.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BuiltinFunction {
     // Boolean operators
@@ -73,9 +161,186 @@ pub enum BuiltinFunction {
     
     // Geo functions
     GeoWithinRadius,
+    GeoWithinPolygon,
+    GeoWithinBbox,
+
+    // Null checks
+    Exists,
+    IsNull,
+
+    // String functions
+    StartsWith,
+    EndsWith,
+    Contains,
+    Lowercase,
+    Uppercase,
+    Trim,
+    Concat,
+    StringLength,
+    Substring,
+    MatchesRegex,
+
+    // Map functions
+    Get,
+    HasKey,
+
+    // Rollout functions
+    PercentOf,
+
+    // Version functions
+    SemverEq,
+    SemverGt,
+    SemverLt,
+    SemverMatches,
+
+    // Network functions
+    IpInCidr,
+    IpInRange,
+
+    // String comparison
+    EqualFold,
+
+    // Arithmetic
+    Add,
+    Subtract,
+    Multiply,
+    Decimal,
+
+    // Conditionals
+    If,
+
+    // Aggregation over lists
+    Count,
+    Sum,
+    Avg,
+    MinOf,
+    MaxOf,
+    Any,
+    All,
+    Filter,
+    Map,
+
+    // Geo
+    GeoDistance,
+    GeoCellIn,
+    Geohash,
+
+    // Experiments
+    ExperimentBucket,
+
+    // Metaprogramming
+    Quote,
+    Eval,
+
+    // Ranges
+    IntRange,
+    InRange,
+    RangesOverlap,
+
+    // Sets
+    Union,
+    Intersection,
+    Difference,
+    IsSubset,
+    IsDisjoint,
+
+    // Bloom sets
+    InSet,
 }
 
 impl BuiltinFunction {
+    /// Every builtin, in declaration order, which is also the order of
+    /// their IDs in `StringInterner::with_builtins`
+    pub const ALL: &'static [BuiltinFunction] = &[
+        BuiltinFunction::And,
+        BuiltinFunction::Or,
+        BuiltinFunction::Not,
+        BuiltinFunction::Equal,
+        BuiltinFunction::NotEqual,
+        BuiltinFunction::LessThan,
+        BuiltinFunction::LessThanOrEqual,
+        BuiltinFunction::GreaterThan,
+        BuiltinFunction::GreaterThanOrEqual,
+        BuiltinFunction::In,
+        BuiltinFunction::NotIn,
+        BuiltinFunction::OneOf,
+        BuiltinFunction::AllOf,
+        BuiltinFunction::NoneOf,
+        BuiltinFunction::GeoWithinRadius,
+        BuiltinFunction::GeoWithinPolygon,
+        BuiltinFunction::GeoWithinBbox,
+        BuiltinFunction::Exists,
+        BuiltinFunction::IsNull,
+        BuiltinFunction::StartsWith,
+        BuiltinFunction::EndsWith,
+        BuiltinFunction::Contains,
+        BuiltinFunction::Lowercase,
+        BuiltinFunction::Uppercase,
+        BuiltinFunction::Trim,
+        BuiltinFunction::Concat,
+        BuiltinFunction::StringLength,
+        BuiltinFunction::Substring,
+        BuiltinFunction::MatchesRegex,
+        BuiltinFunction::Get,
+        BuiltinFunction::HasKey,
+        BuiltinFunction::PercentOf,
+        BuiltinFunction::SemverEq,
+        BuiltinFunction::SemverGt,
+        BuiltinFunction::SemverLt,
+        BuiltinFunction::SemverMatches,
+        BuiltinFunction::IpInCidr,
+        BuiltinFunction::IpInRange,
+        BuiltinFunction::EqualFold,
+        BuiltinFunction::Add,
+        BuiltinFunction::Subtract,
+        BuiltinFunction::Multiply,
+        BuiltinFunction::Decimal,
+        BuiltinFunction::If,
+        BuiltinFunction::Count,
+        BuiltinFunction::Sum,
+        BuiltinFunction::Avg,
+        BuiltinFunction::MinOf,
+        BuiltinFunction::MaxOf,
+        BuiltinFunction::Any,
+        BuiltinFunction::All,
+        BuiltinFunction::Filter,
+        BuiltinFunction::Map,
+        BuiltinFunction::GeoDistance,
+        BuiltinFunction::GeoCellIn,
+        BuiltinFunction::Geohash,
+        BuiltinFunction::ExperimentBucket,
+        BuiltinFunction::Quote,
+        BuiltinFunction::Eval,
+        BuiltinFunction::IntRange,
+        BuiltinFunction::InRange,
+        BuiltinFunction::RangesOverlap,
+        BuiltinFunction::Union,
+        BuiltinFunction::Intersection,
+        BuiltinFunction::Difference,
+        BuiltinFunction::IsSubset,
+        BuiltinFunction::IsDisjoint,
+        BuiltinFunction::InSet,
+    ];
+
+    /// Get the ID of this builtin's name in an interner created with
+    /// `StringInterner::with_builtins`, its index in `ALL`
+    pub fn id(self) -> StringId {
+        StringId::new(self as u32)
+    }
+
+    /// Get the builtin whose name has `id` in an interner created with
+    /// `StringInterner::with_builtins`
+    pub fn from_id(id: StringId) -> Option<Self> {
+        Self::ALL.get(id.raw() as usize).copied()
+    }
+
+    /// Get the builtin whose name is closest to a misspelled `name`, if
+    /// any is close enough to suggest
+    pub fn suggest(name: &str) -> Option<Self> {
+        let closest = suggest::closest(name, Self::ALL.iter().map(|builtin| builtin.as_str()))?;
+        Self::from_str(closest)
+    }
+
     /// Get the string representation of this function
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -94,10 +359,113 @@ impl BuiltinFunction {
             BuiltinFunction::AllOf => "all-of",
             BuiltinFunction::NoneOf => "none-of",
             BuiltinFunction::GeoWithinRadius => "geo_within_radius",
+            BuiltinFunction::GeoWithinPolygon => "geo_within_polygon",
+            BuiltinFunction::GeoWithinBbox => "geo_within_bbox",
+            BuiltinFunction::Exists => "exists",
+            BuiltinFunction::IsNull => "is-null",
+            BuiltinFunction::StartsWith => "starts-with",
+            BuiltinFunction::EndsWith => "ends-with",
+            BuiltinFunction::Contains => "contains",
+            BuiltinFunction::Lowercase => "lowercase",
+            BuiltinFunction::Uppercase => "uppercase",
+            BuiltinFunction::Trim => "trim",
+            BuiltinFunction::Concat => "concat",
+            BuiltinFunction::StringLength => "string-length",
+            BuiltinFunction::Substring => "substring",
+            BuiltinFunction::MatchesRegex => "matches-regex",
+            BuiltinFunction::Get => "get",
+            BuiltinFunction::HasKey => "has-key",
+            BuiltinFunction::PercentOf => "percent-of",
+            BuiltinFunction::SemverEq => "semver-eq",
+            BuiltinFunction::SemverGt => "semver-gt",
+            BuiltinFunction::SemverLt => "semver-lt",
+            BuiltinFunction::SemverMatches => "semver-matches",
+            BuiltinFunction::IpInCidr => "ip-in-cidr",
+            BuiltinFunction::IpInRange => "ip-in-range",
+            BuiltinFunction::EqualFold => "equal-fold",
+            BuiltinFunction::Add => "+",
+            BuiltinFunction::Subtract => "-",
+            BuiltinFunction::Multiply => "*",
+            BuiltinFunction::Decimal => "decimal",
+            BuiltinFunction::If => "if",
+            BuiltinFunction::Count => "count",
+            BuiltinFunction::Sum => "sum",
+            BuiltinFunction::Avg => "avg",
+            BuiltinFunction::MinOf => "min-of",
+            BuiltinFunction::MaxOf => "max-of",
+            BuiltinFunction::Any => "any",
+            BuiltinFunction::All => "all",
+            BuiltinFunction::Filter => "filter",
+            BuiltinFunction::Map => "map",
+            BuiltinFunction::GeoDistance => "geo-distance",
+            BuiltinFunction::GeoCellIn => "geo-cell-in",
+            BuiltinFunction::Geohash => "geohash",
+            BuiltinFunction::ExperimentBucket => "experiment-bucket",
+            BuiltinFunction::Quote => "quote",
+            BuiltinFunction::Eval => "eval",
+            BuiltinFunction::IntRange => "int-range",
+            BuiltinFunction::InRange => "in-range",
+            BuiltinFunction::RangesOverlap => "ranges-overlap",
+            BuiltinFunction::Union => "union",
+            BuiltinFunction::Intersection => "intersection",
+            BuiltinFunction::Difference => "difference",
+            BuiltinFunction::IsSubset => "subset?",
+            BuiltinFunction::IsDisjoint => "disjoint?",
+            BuiltinFunction::InSet => "in-set",
         }
     }
+
+    /// Check if the second argument is evaluated once per element of the
+    /// list that is the first, reading the element as `_`
+    pub fn binds_element(self) -> bool {
+        matches!(
+            self,
+            BuiltinFunction::Any
+                | BuiltinFunction::All
+                | BuiltinFunction::Filter
+                | BuiltinFunction::Map
+        )
+    }
     
+    /// Get the number of arguments this function accepts
+    pub fn arity(&self) -> Arity {
+        match self {
+            BuiltinFunction::And | BuiltinFunction::Or => Arity::AtLeast(0),
+            BuiltinFunction::Not
+            | BuiltinFunction::Exists
+            | BuiltinFunction::IsNull
+            | BuiltinFunction::Lowercase
+            | BuiltinFunction::Uppercase
+            | BuiltinFunction::Trim
+            | BuiltinFunction::StringLength
+            | BuiltinFunction::Decimal
+            | BuiltinFunction::Count
+            | BuiltinFunction::Sum
+            | BuiltinFunction::Avg
+            | BuiltinFunction::MinOf
+            | BuiltinFunction::MaxOf => Arity::Exact(1),
+            BuiltinFunction::Concat => Arity::AtLeast(1),
+            BuiltinFunction::Add | BuiltinFunction::Multiply | BuiltinFunction::If => {
+                Arity::AtLeast(2)
+            }
+            BuiltinFunction::Substring
+            | BuiltinFunction::PercentOf
+            | BuiltinFunction::IpInRange => Arity::Exact(3),
+            BuiltinFunction::GeoWithinRadius => Arity::Exact(5),
+            BuiltinFunction::GeoWithinPolygon | BuiltinFunction::Geohash => Arity::Exact(3),
+            BuiltinFunction::GeoWithinBbox => Arity::Exact(6),
+            BuiltinFunction::ExperimentBucket => Arity::Exact(4),
+            BuiltinFunction::Quote | BuiltinFunction::Eval => Arity::Exact(1),
+            BuiltinFunction::IntRange
+            | BuiltinFunction::InRange
+            | BuiltinFunction::RangesOverlap => Arity::Exact(2),
+            BuiltinFunction::Union | BuiltinFunction::Intersection => Arity::AtLeast(2),
+            _ => Arity::Exact(2),
+        }
+    }
+
     /// Try to parse a function from its string representation
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             "and" => Some(BuiltinFunction::And),
@@ -115,11 +483,92 @@ impl BuiltinFunction {
             "all-of" => Some(BuiltinFunction::AllOf),
             "none-of" => Some(BuiltinFunction::NoneOf),
             "geo_within_radius" => Some(BuiltinFunction::GeoWithinRadius),
+            "geo_within_polygon" => Some(BuiltinFunction::GeoWithinPolygon),
+            "geo_within_bbox" => Some(BuiltinFunction::GeoWithinBbox),
+            "exists" => Some(BuiltinFunction::Exists),
+            "is-null" => Some(BuiltinFunction::IsNull),
+            "starts-with" => Some(BuiltinFunction::StartsWith),
+            "ends-with" => Some(BuiltinFunction::EndsWith),
+            "contains" => Some(BuiltinFunction::Contains),
+            "lowercase" => Some(BuiltinFunction::Lowercase),
+            "uppercase" => Some(BuiltinFunction::Uppercase),
+            "trim" => Some(BuiltinFunction::Trim),
+            "concat" => Some(BuiltinFunction::Concat),
+            "string-length" => Some(BuiltinFunction::StringLength),
+            "substring" => Some(BuiltinFunction::Substring),
+            "matches-regex" => Some(BuiltinFunction::MatchesRegex),
+            "get" => Some(BuiltinFunction::Get),
+            "has-key" => Some(BuiltinFunction::HasKey),
+            "percent-of" => Some(BuiltinFunction::PercentOf),
+            "semver-eq" => Some(BuiltinFunction::SemverEq),
+            "semver-gt" => Some(BuiltinFunction::SemverGt),
+            "semver-lt" => Some(BuiltinFunction::SemverLt),
+            "semver-matches" => Some(BuiltinFunction::SemverMatches),
+            "ip-in-cidr" => Some(BuiltinFunction::IpInCidr),
+            "ip-in-range" => Some(BuiltinFunction::IpInRange),
+            "equal-fold" => Some(BuiltinFunction::EqualFold),
+            "+" => Some(BuiltinFunction::Add),
+            "-" => Some(BuiltinFunction::Subtract),
+            "*" => Some(BuiltinFunction::Multiply),
+            "decimal" => Some(BuiltinFunction::Decimal),
+            "if" => Some(BuiltinFunction::If),
+            "count" => Some(BuiltinFunction::Count),
+            "sum" => Some(BuiltinFunction::Sum),
+            "avg" => Some(BuiltinFunction::Avg),
+            "min-of" => Some(BuiltinFunction::MinOf),
+            "max-of" => Some(BuiltinFunction::MaxOf),
+            "any" => Some(BuiltinFunction::Any),
+            "all" => Some(BuiltinFunction::All),
+            "filter" => Some(BuiltinFunction::Filter),
+            "map" => Some(BuiltinFunction::Map),
+            "geo-distance" => Some(BuiltinFunction::GeoDistance),
+            "geo-cell-in" => Some(BuiltinFunction::GeoCellIn),
+            "geohash" => Some(BuiltinFunction::Geohash),
+            "experiment-bucket" => Some(BuiltinFunction::ExperimentBucket),
+            "quote" => Some(BuiltinFunction::Quote),
+            "eval" => Some(BuiltinFunction::Eval),
+            "int-range" => Some(BuiltinFunction::IntRange),
+            "in-range" => Some(BuiltinFunction::InRange),
+            "ranges-overlap" => Some(BuiltinFunction::RangesOverlap),
+            "union" => Some(BuiltinFunction::Union),
+            "intersection" => Some(BuiltinFunction::Intersection),
+            "difference" => Some(BuiltinFunction::Difference),
+            "subset?" => Some(BuiltinFunction::IsSubset),
+            "disjoint?" => Some(BuiltinFunction::IsDisjoint),
+            "in-set" => Some(BuiltinFunction::InSet),
             _ => None,
         }
     }
 }
 
+/// Number of arguments accepted by a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arity {
+    /// Exactly this many arguments
+    Exact(usize),
+    /// This many arguments or more
+    AtLeast(usize),
+}
+
+impl Arity {
+    /// Check if a call with `count` arguments is accepted
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -133,5 +582,25 @@ mod tests {
         
         assert_eq!(BuiltinFunction::And.as_str(), "and");
         assert_eq!(BuiltinFunction::Equal.as_str(), "=");
+
+        assert!(BuiltinFunction::And.arity().accepts(0));
+        assert!(BuiltinFunction::Not.arity().accepts(1));
+        assert!(!BuiltinFunction::In.arity().accepts(3));
+
+        for builtin in BuiltinFunction::ALL {
+            assert_eq!(BuiltinFunction::from_str(builtin.as_str()), Some(*builtin));
+            assert_eq!(BuiltinFunction::from_id(builtin.id()), Some(*builtin));
+        }
+        let past_end = StringId::new(BuiltinFunction::ALL.len() as u32);
+        assert_eq!(BuiltinFunction::from_id(past_end), None);
+        assert_eq!(
+            BuiltinFunction::suggest("one_of"),
+            Some(BuiltinFunction::OneOf)
+        );
+        assert_eq!(
+            BuiltinFunction::suggest("startswith"),
+            Some(BuiltinFunction::StartsWith)
+        );
+        assert_eq!(BuiltinFunction::suggest("frob"), None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+//! Feature flags
+//!
+//! A `Flag` serves one of several variants, chosen by targeting rules
+//! tried in order: the first rule whose condition holds for a `Context`
+//! serves its variant, and when none does the flag serves its default. A
+//! rule can also roll its variant out to a percentage of keys, such as
+//! `user.id`, bucketed as `percent-of` does with the flag's key as the
+//! salt, so raising the percentage only ever adds keys. A key outside the
+//! rollout, or missing from the context, moves on to the next rule.
+//!
+//! ```
+//! use ironwood::{Context, Flag, Flags, Value};
+//!
+//! let mut context = Context::new();
+//! let staff = context.parse(r#"(= user.team "staff")"#).unwrap();
+//! let everyone = context.parse("true").unwrap();
+//! let mut flags = Flags::new();
+//! flags.define(
+//!     Flag::new("new-checkout", Value::Bool(false))
+//!         .target(staff, Value::Bool(true))
+//!         .rollout(everyone, 10.0, "user.id", Value::Bool(true)),
+//! );
+//!
+//! context.set_str("user.team", "staff");
+//! let decision = flags.evaluate("new-checkout", &context).unwrap();
+//! assert_eq!(decision.variant(), &Value::Bool(true));
+//! assert_eq!(decision.reason().to_string(), "rule 0 matched");
+//! ```
+//!
+//! Conditions are parsed with the interner of the contexts the flag is
+//! evaluated against. A condition that fails to evaluate serves the
+//! default, with the error as the reason.
+
+use crate::compat::FxHashMap;
+use crate::rollout::{bucket, in_rollout};
+use crate::{Context, EvalError, Evaluator, Expr, Value};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Reasons a flag cannot be evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlagError {
+    /// No flag is defined with the key
+    UnknownFlag(String),
+}
+
+impl fmt::Display for FlagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlagError::UnknownFlag(key) => write!(f, "unknown flag `{}`", key),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FlagError {}
+
+/// Flag serving variants by targeting rules, built with `Flag::new`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flag {
+    key: String,
+    default: Value,
+    rules: Vec<Targeting>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Targeting {
+    condition: Expr,
+    variant: Value,
+    /// Percentage of keys served and the path of the key attribute
+    rollout: Option<(f64, String)>,
+}
+
+impl Flag {
+    /// Create a flag serving `default` to every context
+    pub fn new(key: &str, default: Value) -> Self {
+        Self {
+            key: key.to_string(),
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Add a rule serving `variant` when `condition` holds
+    pub fn target(mut self, condition: Expr, variant: Value) -> Self {
+        self.rules.push(Targeting {
+            condition,
+            variant,
+            rollout: None,
+        });
+        self
+    }
+
+    /// Add a rule serving `variant` to `percent` of the keys at the path
+    /// `key` for which `condition` holds
+    pub fn rollout(mut self, condition: Expr, percent: f64, key: &str, variant: Value) -> Self {
+        self.rules.push(Targeting {
+            condition,
+            variant,
+            rollout: Some((percent, key.to_string())),
+        });
+        self
+    }
+
+    /// Get the key of the flag
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Get the variant served when no rule matches
+    pub fn default(&self) -> &Value {
+        &self.default
+    }
+
+    /// Choose the variant to serve `context`
+    pub fn evaluate(&self, context: &Context) -> FlagDecision {
+        let evaluator = Evaluator::new(context.interner());
+        for (rule, targeting) in self.rules.iter().enumerate() {
+            match evaluator.eval(&targeting.condition, context.environment()) {
+                Ok(Value::Bool(true)) => {}
+                Ok(_) => continue,
+                Err(error) => {
+                    return FlagDecision {
+                        variant: self.default.clone(),
+                        reason: FlagReason::Error { rule, error },
+                    }
+                }
+            }
+            let reason = match &targeting.rollout {
+                None => FlagReason::Matched { rule },
+                Some((percent, path)) => {
+                    let Some(key) = rollout_key(context, path) else {
+                        continue;
+                    };
+                    let bucket = bucket(&key, &self.key);
+                    if !in_rollout(bucket, *percent) {
+                        continue;
+                    }
+                    FlagReason::Rollout {
+                        rule,
+                        percent: *percent,
+                        bucket,
+                    }
+                }
+            };
+            return FlagDecision {
+                variant: targeting.variant.clone(),
+                reason,
+            };
+        }
+        FlagDecision {
+            variant: self.default.clone(),
+            reason: FlagReason::Default,
+        }
+    }
+}
+
+/// Get the text a rollout buckets the attribute at `path` by, with integers
+/// in decimal as `percent-of` writes them
+fn rollout_key(context: &Context, path: &str) -> Option<String> {
+    match context.get(path)? {
+        Value::String(id) | Value::Symbol(id) => context.resolve(*id).map(str::to_string),
+        Value::Text(text) => Some(text.to_string()),
+        Value::Integer(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Why a flag served its variant
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlagReason {
+    /// Condition of the rule at this index held
+    Matched { rule: usize },
+    /// Condition of the rule at this index held and the key's bucket is in
+    /// its rollout
+    Rollout {
+        rule: usize,
+        percent: f64,
+        bucket: u64,
+    },
+    /// No rule matched, so the default was served
+    Default,
+    /// Condition of the rule at this index failed, so the default was
+    /// served
+    Error { rule: usize, error: EvalError },
+}
+
+impl fmt::Display for FlagReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlagReason::Matched { rule } => write!(f, "rule {} matched", rule),
+            FlagReason::Rollout {
+                rule,
+                percent,
+                bucket,
+            } => write!(
+                f,
+                "rule {} matched and bucket {} is in its {}% rollout",
+                rule, bucket, percent
+            ),
+            FlagReason::Default => f.write_str("no rule matched"),
+            FlagReason::Error { rule, error } => write!(f, "rule {} failed: {}", rule, error),
+        }
+    }
+}
+
+/// Variant a flag served and why, returned by `Flag::evaluate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagDecision {
+    variant: Value,
+    reason: FlagReason,
+}
+
+impl FlagDecision {
+    /// Get the variant served
+    pub fn variant(&self) -> &Value {
+        &self.variant
+    }
+
+    /// Get why the variant was served
+    pub fn reason(&self) -> &FlagReason {
+        &self.reason
+    }
+}
+
+/// Flags by key
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    flags: FxHashMap<String, Flag>,
+}
+
+impl Flags {
+    /// Create an empty set of flags
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a flag, replacing and returning any flag with the same key
+    pub fn define(&mut self, flag: Flag) -> Option<Flag> {
+        self.flags.insert(flag.key.clone(), flag)
+    }
+
+    /// Remove a flag, returning it if it was defined
+    pub fn remove(&mut self, key: &str) -> Option<Flag> {
+        self.flags.remove(key)
+    }
+
+    /// Get a flag by key
+    pub fn get(&self, key: &str) -> Option<&Flag> {
+        self.flags.get(key)
+    }
+
+    /// Choose the variant of the flag `key` to serve `context`
+    pub fn evaluate(&self, key: &str, context: &Context) -> Result<FlagDecision, FlagError> {
+        let flag = self
+            .get(key)
+            .ok_or_else(|| FlagError::UnknownFlag(key.to_string()))?;
+        Ok(flag.evaluate(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_variants() {
+        let mut context = Context::new();
+        let variant = |context: &mut Context, name: &str| Value::String(context.intern(name));
+        let (control, treatment, beta) = (
+            variant(&mut context, "control"),
+            variant(&mut context, "treatment"),
+            variant(&mut context, "beta"),
+        );
+        let flag = Flag::new("checkout-v2", control.clone())
+            .target(
+                context.parse(r#"(= user.country "DE")"#).unwrap(),
+                beta.clone(),
+            )
+            .target(context.parse("(> user.age 200)").unwrap(), beta.clone())
+            .rollout(
+                context.parse("(>= user.age 18)").unwrap(),
+                10.0,
+                "user.id",
+                treatment.clone(),
+            );
+        let mut flags = Flags::new();
+        flags.define(flag);
+
+        context.set_str("user.country", "DE");
+        let decision = flags.evaluate("checkout-v2", &context).unwrap();
+        assert_eq!(decision.variant(), &beta);
+        assert_eq!(decision.reason(), &FlagReason::Matched { rule: 0 });
+
+        // `user-1` lands in bucket 899 under this flag's key
+        context.set_str("user.country", "US");
+        context.set("user.age", Value::Integer(30));
+        context.set_str("user.id", "user-1");
+        let decision = flags.evaluate("checkout-v2", &context).unwrap();
+        assert_eq!(decision.variant(), &treatment);
+        assert_eq!(
+            decision.reason().to_string(),
+            "rule 2 matched and bucket 899 is in its 10% rollout"
+        );
+
+        for (age, id) in [(30, "user-2"), (12, "user-1")] {
+            context.set("user.age", Value::Integer(age));
+            context.set_str("user.id", id);
+            let decision = flags.evaluate("checkout-v2", &context).unwrap();
+            assert_eq!(decision.variant(), &control);
+            assert_eq!(decision.reason(), &FlagReason::Default);
+        }
+
+        context.remove("user.age");
+        let decision = flags.evaluate("checkout-v2", &context).unwrap();
+        assert_eq!(decision.variant(), &control);
+        assert!(matches!(
+            decision.reason(),
+            FlagReason::Error { rule: 1, .. }
+        ));
+
+        assert_eq!(
+            flags.evaluate("missing", &context),
+            Err(FlagError::UnknownFlag("missing".to_string()))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Read-only interner for the serving path
+//!
+//! Once rules are loaded, `StringInterner::freeze` turns the interner into
+//! a `FrozenInterner`: every string packed into one buffer and a minimal
+//! perfect hash table over them, built with hash-and-displace. Strings are
+//! split into buckets by hash, and each bucket gets a pilot value chosen so
+//! that its strings land on table slots no other string uses. A lookup
+//! hashes once, reads the bucket's pilot and compares against the single
+//! candidate in its slot, so it takes no locks and never probes.
+//!
+//! The frozen interner cannot intern new strings, but it is `Send + Sync`
+//! and can be shared by reference across any number of threads. It
+//! implements `Interner`, so evaluators and compiled rules read through it
+//! the same as through the `StringInterner` it was frozen from.
+
+use crate::intern::{CaseFolding, Interner};
+use crate::{BuiltinFunction, StringId, StringInterner};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Average number of strings per pilot bucket
+const BUCKET_SIZE: usize = 4;
+
+/// Pilots tried for one bucket before building again with another seed
+const MAX_PILOT: u32 = 1 << 16;
+
+/// Slot of unused table entries, an ID that never resolves
+const EMPTY: StringId = StringId::new(u32::MAX);
+
+/// Immutable string interner backed by a perfect hash table
+///
+/// IDs are those of the `StringInterner` it was frozen from, including
+/// builtin names interned at their fixed IDs by
+/// `StringInterner::with_builtins`.
+#[derive(Debug, Clone)]
+pub struct FrozenInterner {
+    /// Text of every string, in ID order
+    text: Box<str>,
+    /// Byte range of each ID's string in `text`, `None` for released IDs
+    spans: Box<[Option<(usize, usize)>]>,
+    /// Table from each string to its ID
+    table: PerfectTable,
+    /// Number of live strings
+    len: usize,
+    /// Whether builtin names hold the IDs given by `BuiltinFunction::id`
+    builtins: bool,
+}
+
+/// Perfect hash table from strings to their IDs
+#[derive(Debug, Clone)]
+struct PerfectTable {
+    /// Seed the table was built with
+    seed: u64,
+    /// How strings are folded before hashing and compared
+    folding: CaseFolding,
+    /// Displacement of each bucket into `ids`
+    pilots: Box<[u32]>,
+    /// The one ID that may be stored at each slot
+    ids: Box<[StringId]>,
+}
+
+impl StringInterner {
+    /// Freeze the interner into a lock-free, read-only one with the same IDs
+    pub fn freeze(self) -> FrozenInterner {
+        let mut text = String::new();
+        let mut spans = Vec::with_capacity(self.id_bound() as usize);
+        let mut keys = Vec::with_capacity(self.len());
+        for raw in 0..self.id_bound() {
+            let id = StringId::new(raw);
+            spans.push(self.resolve(id).map(|s| {
+                keys.push((s, id));
+                let start = text.len();
+                text.push_str(s);
+                (start, s.len())
+            }));
+        }
+
+        let table = (0..)
+            .find_map(|seed| PerfectTable::build(&keys, seed, self.case_folding()))
+            .expect("some seed separates distinct strings");
+        let mut frozen = FrozenInterner {
+            text: text.into_boxed_str(),
+            spans: spans.into_boxed_slice(),
+            table,
+            len: keys.len(),
+            builtins: false,
+        };
+        frozen.builtins = BuiltinFunction::ALL
+            .iter()
+            .all(|builtin| frozen.resolve(builtin.id()) == Some(builtin.as_str()));
+        frozen
+    }
+}
+
+impl FrozenInterner {
+    /// Get the string for an interned ID
+    pub fn resolve(&self, id: StringId) -> Option<&str> {
+        let (start, len) = (*self.spans.get(id.raw() as usize)?)?;
+        Some(&self.text[start..start + len])
+    }
+
+    /// Get the ID for a string if it exists
+    pub fn get_id(&self, s: &str) -> Option<StringId> {
+        let id = self.table.candidate(s);
+        self.resolve(id)
+            .is_some_and(|text| self.table.folding.eq(text, s))
+            .then_some(id)
+    }
+
+    /// Check if a string is interned
+    pub fn contains(&self, s: &str) -> bool {
+        self.get_id(s).is_some()
+    }
+
+    /// Get the builtin function named by `id`, if any
+    pub fn builtin(&self, id: StringId) -> Option<BuiltinFunction> {
+        if self.builtins {
+            BuiltinFunction::from_id(id)
+        } else {
+            self.resolve(id).and_then(BuiltinFunction::from_str)
+        }
+    }
+
+    /// Get how the interner compares strings
+    pub fn case_folding(&self) -> CaseFolding {
+        self.table.folding
+    }
+
+    /// Get the number of interned strings
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the interner is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Interner for FrozenInterner {
+    fn resolve(&self, id: StringId) -> Option<&str> {
+        FrozenInterner::resolve(self, id)
+    }
+
+    fn get_id(&self, s: &str) -> Option<StringId> {
+        FrozenInterner::get_id(self, s)
+    }
+
+    fn builtin(&self, id: StringId) -> Option<BuiltinFunction> {
+        FrozenInterner::builtin(self, id)
+    }
+
+    fn case_folding(&self) -> CaseFolding {
+        FrozenInterner::case_folding(self)
+    }
+}
+
+impl PerfectTable {
+    /// Build a table for `keys` with `seed`, or `None` if some bucket found
+    /// no pilot
+    fn build(keys: &[(&str, StringId)], seed: u64, folding: CaseFolding) -> Option<Self> {
+        let size = keys.len().max(1);
+        let bucket_count = keys.len().div_ceil(BUCKET_SIZE).max(1);
+        let mut buckets = vec![Vec::new(); bucket_count];
+        for &(s, id) in keys {
+            let hash = folding.hash_seeded(seed, s);
+            buckets[bucket(hash, bucket_count)].push((hash, id));
+        }
+        // Place the largest buckets first, while the table is emptiest
+        let mut order: Vec<usize> = (0..bucket_count).collect();
+        order.sort_unstable_by_key(|&b| core::cmp::Reverse(buckets[b].len()));
+
+        let mut pilots = vec![0; bucket_count];
+        let mut ids = vec![EMPTY; size];
+        let mut slots = Vec::with_capacity(BUCKET_SIZE);
+        for b in order {
+            let entries = &buckets[b];
+            if entries.is_empty() {
+                break;
+            }
+            let pilot = (0..MAX_PILOT).find(|&pilot| {
+                slots.clear();
+                entries.iter().all(|&(hash, _)| {
+                    let at = slot(hash, pilot, size);
+                    let free = ids[at] == EMPTY && !slots.contains(&at);
+                    slots.push(at);
+                    free
+                })
+            })?;
+            pilots[b] = pilot;
+            for (&(_, id), &at) in entries.iter().zip(&slots) {
+                ids[at] = id;
+            }
+        }
+        Some(Self {
+            seed,
+            folding,
+            pilots: pilots.into_boxed_slice(),
+            ids: ids.into_boxed_slice(),
+        })
+    }
+
+    /// Get the only ID that `s` can have
+    fn candidate(&self, s: &str) -> StringId {
+        let hash = self.folding.hash_seeded(self.seed, s);
+        let pilot = self.pilots[bucket(hash, self.pilots.len())];
+        self.ids[slot(hash, pilot, self.ids.len())]
+    }
+}
+
+fn bucket(hash: u64, count: usize) -> usize {
+    (hash >> 32) as usize % count
+}
+
+fn slot(hash: u64, pilot: u32, size: usize) -> usize {
+    let mixed = (hash ^ u64::from(pilot).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+        .wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    (mixed >> 32) as usize % size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile, Environment, Evaluator, Value};
+    use alloc::format;
+
+    #[test]
+    fn frozen_lookups() {
+        let mut interner = StringInterner::with_builtins();
+        let names: Vec<String> = (0..5000).map(|i| format!("attribute-{}", i)).collect();
+        let ids: Vec<StringId> = names.iter().map(|name| interner.intern(name)).collect();
+        let long = interner.intern(&"x".repeat(100));
+        let gone = interner.intern("request-1");
+        interner.retain(|id, _| id != gone);
+
+        let frozen = interner.freeze();
+        assert_eq!(frozen.len(), BuiltinFunction::ALL.len() + names.len() + 1);
+        for (name, &id) in names.iter().zip(&ids) {
+            assert_eq!(frozen.get_id(name), Some(id));
+            assert_eq!(frozen.resolve(id), Some(name.as_str()));
+        }
+        assert_eq!(frozen.get_id(&"x".repeat(100)), Some(long));
+        assert_eq!(frozen.resolve(gone), None);
+        assert!(!frozen.contains("request-1"));
+        assert!(!frozen.contains("attribute-5000"));
+        assert_eq!(
+            frozen.builtin(BuiltinFunction::In.id()),
+            Some(BuiltinFunction::In)
+        );
+        assert_eq!(frozen.builtin(ids[0]), None);
+
+        let empty = StringInterner::new().freeze();
+        assert!(empty.is_empty());
+        assert_eq!(empty.get_id(""), None);
+        assert_eq!(empty.resolve(StringId::new(0)), None);
+
+        fn shared<T: Send + Sync>(_: &T) {}
+        shared(&frozen);
+    }
+
+    #[test]
+    fn serves_compiled_rules() {
+        let mut interner = StringInterner::with_builtins();
+        let source = r#"(and (in country ["US" "CA"]) (starts-with (lowercase plan) "pro"))"#;
+        let expr = crate::parse(source, &mut interner).unwrap();
+        interner.intern("pro-annual");
+        let frozen = interner.freeze();
+
+        let mut env = Environment::new();
+        let plan = frozen.get_id("pro-annual").unwrap();
+        env.insert(
+            frozen.get_id("country").unwrap(),
+            Value::String(frozen.get_id("CA").unwrap()),
+        );
+        env.insert(frozen.get_id("plan").unwrap(), Value::String(plan));
+
+        let compiled = compile(&expr, &frozen);
+        assert_eq!(compiled.eval(&env, &frozen), Ok(Value::Bool(true)));
+        let evaluator = Evaluator::new(&frozen);
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(true)));
+        env.insert(frozen.get_id("country").unwrap(), Value::String(plan));
+        assert_eq!(compiled.eval(&env, &frozen), Ok(Value::Bool(false)));
+    }
+}
@@ -0,0 +1,141 @@
+//! User-defined functions
+//!
+//! Builtins are a closed set. Host applications register additional
+//! functions by name in a `FunctionRegistry` and hand it to an `Evaluator`
+//! or `compile_with`. A call whose name is not a builtin is looked up in
+//! the registry; builtins always take precedence.
+//!
+//! Custom functions receive evaluated arguments, including nulls, and
+//! check their own arity and types.
+//!
+//! Evaluation with `EvalOptions::deterministic` set only calls functions
+//! registered with `register_deterministic`, since the engine cannot tell
+//! whether a host function reads the clock, a random source or other
+//! state outside its arguments.
+
+use crate::compat::FxHashMap;
+use crate::eval::EvalError;
+use crate::{StringId, Value};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+
+/// A host function callable from expressions
+pub type CustomFunction = Arc<dyn Fn(&[Value]) -> Result<Value, EvalError> + Send + Sync>;
+
+/// Named custom functions
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    /// Each function, and whether its result depends only on its arguments
+    functions: FxHashMap<Box<str>, (CustomFunction, bool)>,
+}
+
+impl FunctionRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function, replacing any function with the same name
+    pub fn register<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, EvalError> + Send + Sync + 'static,
+    {
+        self.functions
+            .insert(name.into(), (Arc::new(function), false));
+    }
+
+    /// Register a function whose result depends only on its arguments, so
+    /// deterministic evaluation may call it, replacing any function with
+    /// the same name
+    pub fn register_deterministic<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, EvalError> + Send + Sync + 'static,
+    {
+        self.functions
+            .insert(name.into(), (Arc::new(function), true));
+    }
+
+    /// Remove a function, returning whether it was registered
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    /// Look up a function by name
+    pub fn get(&self, name: &str) -> Option<&CustomFunction> {
+        self.functions.get(name).map(|(function, _)| function)
+    }
+
+    /// Check if a function is registered with `register_deterministic`
+    pub fn is_deterministic(&self, name: &str) -> bool {
+        self.functions
+            .get(name)
+            .is_some_and(|&(_, deterministic)| deterministic)
+    }
+
+    /// Check if a function is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Get the number of registered functions
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Check if no functions are registered
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+/// A custom function resolved at compile time
+#[derive(Clone)]
+pub(crate) struct Resolved {
+    pub(crate) name: StringId,
+    pub(crate) function: CustomFunction,
+    pub(crate) deterministic: bool,
+}
+
+impl PartialEq for Resolved {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.function, &other.function)
+    }
+}
+
+impl fmt::Debug for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Resolved").field(&self.name).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_replace() {
+        let mut registry = FunctionRegistry::new();
+        registry.register("answer", |_| Ok(Value::Integer(41)));
+        registry.register("answer", |_| Ok(Value::Integer(42)));
+
+        assert_eq!(registry.len(), 1);
+        assert!(registry.contains("answer"));
+        let answer = registry.get("answer").unwrap();
+        assert_eq!(answer(&[]), Ok(Value::Integer(42)));
+
+        assert!(!registry.is_deterministic("answer"));
+        registry.register_deterministic("answer", |_| Ok(Value::Integer(42)));
+        assert!(registry.is_deterministic("answer"));
+
+        assert!(registry.unregister("answer"));
+        assert!(registry.is_empty());
+        assert!(!registry.is_deterministic("answer"));
+    }
+}
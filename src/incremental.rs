@@ -0,0 +1,288 @@
+//! Incremental re-evaluation
+//!
+//! For streams where one variable changes at a time, `IncrementalEvaluator`
+//! keeps the result of every subexpression from the last evaluation. When a
+//! variable changes, only the subexpressions reading it are evaluated
+//! again; the tree walker takes every other one from the cache, so an
+//! `and` of ten predicates re-runs one predicate and the `and` itself when
+//! `age` goes from 20 to 21.
+//!
+//! Subexpressions reading a `let` local or the element `_` depend on more
+//! than the environment and are never cached. Errors are not cached
+//! either, since cancellation and limits can make them transient. Custom
+//! functions are assumed to return the same value for the same arguments,
+//! so a nondeterministic one is only called again when its arguments
+//! change. Subexpressions calling `eval` can read any variable through
+//! the quoted expression, so they are never cached.
+
+use crate::compat::{FxHashMap, FxHashSet};
+use crate::eval::{EvalError, Observer};
+use crate::expr::ELEMENT;
+use crate::{BuiltinFunction, Environment, Evaluator, Expr, StringId, Value};
+use alloc::vec::Vec;
+
+/// Evaluates one expression, re-evaluating only what a changed variable
+/// affects
+#[derive(Debug, Clone)]
+pub struct IncrementalEvaluator<'a> {
+    evaluator: Evaluator<'a>,
+    expr: &'a Expr,
+    env: Environment,
+    cache: Cache,
+    /// Addresses of the cached subexpressions reading each variable
+    dependents: FxHashMap<StringId, Vec<usize>>,
+    result: Result<Value, EvalError>,
+}
+
+impl<'a> IncrementalEvaluator<'a> {
+    /// Evaluate `expr` against `env`, keeping the result of every
+    /// subexpression for later updates
+    pub fn new(evaluator: Evaluator<'a>, expr: &'a Expr, env: Environment) -> Self {
+        let mut incremental = Self {
+            evaluator,
+            expr,
+            env,
+            cache: Cache::default(),
+            dependents: FxHashMap::default(),
+            result: Ok(Value::Null),
+        };
+        let element = incremental.evaluator.interner().get_id(ELEMENT);
+        incremental.analyze(expr, element, &mut Vec::new());
+        incremental.result =
+            incremental
+                .evaluator
+                .eval_observed(expr, &incremental.env, &mut incremental.cache);
+        incremental
+    }
+
+    /// Get the result of the expression against the current environment
+    pub fn result(&self) -> &Result<Value, EvalError> {
+        &self.result
+    }
+
+    /// Get the current environment
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    /// Get how many subexpressions the last evaluation evaluated rather
+    /// than took from the cache
+    pub fn evaluated(&self) -> usize {
+        self.cache.evaluated
+    }
+
+    /// Set `name` to `value` and re-evaluate, returning whether the result
+    /// changed
+    pub fn update(&mut self, name: StringId, value: Value) -> bool {
+        if self.env.get(name) == Some(&value) {
+            return false;
+        }
+        self.env.insert(name, value);
+        self.refresh(name)
+    }
+
+    /// Remove `name` from the environment and re-evaluate, returning
+    /// whether the result changed
+    pub fn remove(&mut self, name: StringId) -> bool {
+        if self.env.remove(name).is_none() {
+            return false;
+        }
+        self.refresh(name)
+    }
+
+    /// Drop the cached subexpressions reading `name` and evaluate again
+    fn refresh(&mut self, name: StringId) -> bool {
+        for address in self.dependents.get(&name).into_iter().flatten() {
+            self.cache.results.remove(address);
+        }
+        self.cache.evaluated = 0;
+        let result = self
+            .evaluator
+            .eval_observed(self.expr, &self.env, &mut self.cache);
+        let changed = result != self.result;
+        self.result = result;
+        changed
+    }
+
+    /// Mark the subexpressions of `expr` that can be cached and record the
+    /// variables each depends on, returning what `expr` reads without
+    /// binding it
+    fn analyze(
+        &mut self,
+        expr: &'a Expr,
+        element: Option<StringId>,
+        bound: &mut Vec<StringId>,
+    ) -> Reads {
+        let mut reads = Reads::default();
+        match expr {
+            Expr::Literal(_) => {}
+            Expr::Variable(name) => {
+                reads.free.insert(*name);
+            }
+            Expr::List(items) => {
+                for item in items {
+                    reads.extend(self.analyze(item, element, bound));
+                }
+            }
+            Expr::Call { function, args } => {
+                let builtin = self.evaluator.interner().builtin(*function);
+                let binds = builtin.is_some_and(|builtin| builtin.binds_element());
+                reads.dynamic = builtin == Some(BuiltinFunction::Eval);
+                for (i, arg) in args.iter().enumerate() {
+                    // The second argument reads the element as the local `_`
+                    match element.filter(|_| binds && i == 1) {
+                        Some(element) => {
+                            bound.push(element);
+                            let mut inner = self.analyze(arg, Some(element), bound);
+                            bound.pop();
+                            inner.free.remove(&element);
+                            reads.extend(inner);
+                        }
+                        None => reads.extend(self.analyze(arg, element, bound)),
+                    }
+                }
+            }
+            Expr::Annotated { expr, .. } => reads = self.analyze(expr, element, bound),
+            Expr::Let { bindings, body } => {
+                let scope = bound.len();
+                for (name, value) in bindings {
+                    let mut inner = self.analyze(value, element, bound);
+                    inner.free.retain(|name| !bound[scope..].contains(name));
+                    reads.extend(inner);
+                    bound.push(*name);
+                }
+                let mut inner = self.analyze(body, element, bound);
+                inner.free.retain(|name| !bound[scope..].contains(name));
+                reads.extend(inner);
+                bound.truncate(scope);
+            }
+        }
+
+        if !reads.dynamic && reads.free.iter().all(|name| !bound.contains(name)) {
+            let address = address(expr);
+            self.cache.cacheable.insert(address);
+            for name in &reads.free {
+                self.dependents.entry(*name).or_default().push(address);
+            }
+        }
+        reads
+    }
+}
+
+/// Variables a subexpression reads without binding them
+#[derive(Debug, Default)]
+struct Reads {
+    free: FxHashSet<StringId>,
+    /// Whether it calls `eval`, whose quoted expression can read any
+    /// variable
+    dynamic: bool,
+}
+
+impl Reads {
+    fn extend(&mut self, other: Reads) {
+        self.free.extend(other.free);
+        self.dynamic |= other.dynamic;
+    }
+}
+
+/// Results of the cacheable subexpressions, keyed by address
+#[derive(Debug, Clone, Default)]
+struct Cache {
+    results: FxHashMap<usize, Value>,
+    cacheable: FxHashSet<usize>,
+    evaluated: usize,
+}
+
+impl<'e> Observer<'e> for Cache {
+    fn recall(&mut self, expr: &'e Expr) -> Option<Value> {
+        self.results.get(&address(expr)).cloned()
+    }
+
+    fn enter(&mut self) {}
+
+    fn exit(&mut self, expr: &'e Expr, result: &Result<Value, EvalError>) {
+        self.evaluated += 1;
+        let address = address(expr);
+        if let (Ok(value), true) = (result, self.cacheable.contains(&address)) {
+            self.results.insert(address, value.clone());
+        }
+    }
+}
+
+fn address(expr: &Expr) -> usize {
+    expr as *const Expr as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, StringInterner};
+
+    #[test]
+    fn reevaluates_only_dependents() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (> age 18) (= country "US") (in tier ["gold" "silver"]))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let (age, country, tier) = (
+            interner.intern("age"),
+            interner.intern("country"),
+            interner.intern("tier"),
+        );
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(20));
+        env.insert(country, Value::String(interner.intern("US")));
+        env.insert(tier, Value::String(interner.intern("gold")));
+
+        let mut incremental = IncrementalEvaluator::new(Evaluator::new(&interner), &expr, env);
+        assert_eq!(incremental.result(), &Ok(Value::Bool(true)));
+
+        // The `and`, the comparison and `age` itself
+        assert!(!incremental.update(age, Value::Integer(21)));
+        assert_eq!(incremental.evaluated(), 3);
+        assert!(incremental.update(age, Value::Integer(17)));
+        assert_eq!(incremental.result(), &Ok(Value::Bool(false)));
+        assert!(!incremental.update(age, Value::Integer(17)));
+
+        assert!(incremental.update(age, Value::Integer(30)));
+        assert!(incremental.remove(tier));
+        assert_eq!(incremental.result(), &Err(EvalError::UnknownVariable(tier)));
+    }
+
+    #[test]
+    fn locals_are_not_cached() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            "(and (any xs (> _ limit)) (let ((y (+ x 1))) (> y 2)))",
+            &mut interner,
+        )
+        .unwrap();
+        let (xs, limit, x) = (
+            interner.intern("xs"),
+            interner.intern("limit"),
+            interner.intern("x"),
+        );
+        let mut env = Environment::new();
+        env.insert(xs, Value::IntegerList(alloc::vec![1, 5].into()));
+        env.insert(limit, Value::Integer(3));
+        env.insert(x, Value::Integer(5));
+
+        let evaluator = Evaluator::new(&interner);
+        let mut incremental = IncrementalEvaluator::new(evaluator.clone(), &expr, env);
+        assert_eq!(incremental.result(), &Ok(Value::Bool(true)));
+        // Each update flips the result
+        for (name, value, expected) in [
+            (x, 1, false),
+            (x, 2, true),
+            (limit, 5, false),
+            (limit, 0, true),
+        ] {
+            assert!(incremental.update(name, Value::Integer(value)));
+            let plain = evaluator.eval(&expr, incremental.env());
+            assert_eq!(incremental.result(), &plain);
+            assert_eq!(plain, Ok(Value::Bool(expected)));
+        }
+    }
+}
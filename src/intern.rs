@@ -1,82 +1,865 @@
 //! String interning system for efficient storage and comparison
 //! See https://en.wikipedia.org/wiki/String_interning
+//!
+//! Interned strings live until they are released. Services that intern
+//! per-request strings next to long-lived rules can drop them with
+//! `StringInterner::retain`, or take a `StringInterner::mark` once the
+//! rules are loaded and `release_since` it after each request. Released
+//! IDs resolve to nothing; IDs of strings that are kept stay valid. When
+//! the interner is shared, such as by the workers of a service, each
+//! request can instead intern into its own `StringInterner::child`, which
+//! shares the rules' strings and is dropped with the request. An
+//! interner that is done growing can be frozen into a lock-free
+//! `FrozenInterner` with `StringInterner::freeze`.
+//!
+//! `StringInterner::with_builtins` interns the name of every
+//! `BuiltinFunction` first, so builtin `i` of `BuiltinFunction::ALL` has ID
+//! `i`: `and` is 0, `or` is 1, `not` is 2 and so on. Evaluators look up
+//! builtins with `StringInterner::builtin`, which then matches on the ID
+//! instead of resolving and comparing the name.
+//!
+//! An interner made with `StringInterner::with_case_folding` ignores case:
+//! interning `"us"` after `"US"` returns the ID of `"US"`, which still
+//! resolves to `"US"`, the first form seen. Rules and contexts interned
+//! through it then compare symbols and strings such as country codes
+//! without regard to case, and so do `=`, membership, `starts-with`,
+//! `ends-with` and `contains` on computed text. `matches-regex` follows its
+//! pattern, which can ask for case-insensitive matching with `(?i)`.
 
-use rustc_hash::FxHashMap;
-use std::collections::HashMap;
+use crate::compat::RwLock;
+use crate::{BuiltinFunction, Expr, Value};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{BuildHasher, Hash, Hasher};
+use hashbrown::HashTable;
+use rustc_hash::FxBuildHasher;
+
+/// Number of lock shards in `ConcurrentStringInterner`, must be a power of two
+const SHARD_COUNT: usize = 16;
+
+/// One lock shard of the concurrent interner's forward table, holding the
+/// IDs of the strings whose hash selects it
+type Shard = RwLock<HashTable<StringId>>;
 
 /// String interning pool that provides efficient storage and lookup of strings
-#[derive(Debug, Default)]
+///
+/// Strings of up to `INLINE_CAPACITY` bytes are stored inline in their
+/// slot and longer ones in a single growing arena, so interning a string
+/// costs no allocation of its own. The forward table holds only IDs and
+/// compares candidates against the stored text, so each string is stored
+/// once.
+///
+/// Clones share their strings until one of them is modified, which copies
+/// them, so cloning an interner is cheap.
+#[derive(Debug, Clone, Default)]
 pub struct StringInterner {
-    /// Map from string to interned ID
-    string_to_id: FxHashMap<String, StringId>,
-    /// Map from ID to string (for reverse lookup)
-    id_to_string: HashMap<StringId, String>,
-    /// Next available ID
-    next_id: u32,
+    /// Interner this is a child of, whose strings are read-only here
+    parent: Option<Arc<StringInterner>>,
+    /// Strings interned by this interner itself
+    strings: Arc<Strings>,
+    /// Whether builtin names hold the IDs given by `BuiltinFunction::id`
+    builtins: bool,
+    /// How strings are compared when interning and looking them up
+    folding: CaseFolding,
+}
+
+/// Strings of one interner, whose IDs start at `base`
+#[derive(Debug, Clone, Default)]
+struct Strings {
+    /// ID of the first slot, past every ID of the parent in a child
+    base: u32,
+    /// IDs of the live strings, hashed by their text
+    table: HashTable<StringId>,
+    /// Storage of each ID handed out, indexed by ID less `base`
+    slots: Vec<Slot>,
+    /// Text of the strings too long to store inline, in ID order
+    arena: String,
 }
 
 /// Interned string identifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct StringId(u32);
 
+/// How an interner compares strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CaseFolding {
+    /// Strings are the same only if they are equal
+    #[default]
+    Exact,
+    /// ASCII letters match regardless of case
+    Ascii,
+    /// All letters match regardless of case, comparing the lowercase forms
+    /// given by `char::to_lowercase`
+    Unicode,
+}
+
+impl CaseFolding {
+    /// Hash the folded form of `s`
+    pub(crate) fn hash(self, s: &str) -> u64 {
+        let mut state = FxBuildHasher.build_hasher();
+        self.write(s, &mut state);
+        state.finish()
+    }
+
+    /// Hash the folded form of `s` after `seed`
+    pub(crate) fn hash_seeded(self, seed: u64, s: &str) -> u64 {
+        let mut state = FxBuildHasher.build_hasher();
+        seed.hash(&mut state);
+        self.write(s, &mut state);
+        state.finish()
+    }
+
+    /// Get the folded form of `s`, which is `s` itself without folding
+    pub(crate) fn fold(self, s: &str) -> Cow<'_, str> {
+        match self {
+            CaseFolding::Exact => Cow::Borrowed(s),
+            CaseFolding::Ascii if !s.bytes().any(|b| b.is_ascii_uppercase()) => Cow::Borrowed(s),
+            CaseFolding::Ascii => Cow::Owned(s.to_ascii_lowercase()),
+            CaseFolding::Unicode => Cow::Owned(lowercase(s)),
+        }
+    }
+
+    /// Check if two strings are the same once folded
+    pub(crate) fn eq(self, a: &str, b: &str) -> bool {
+        match self {
+            CaseFolding::Exact => a == b,
+            CaseFolding::Ascii => a.eq_ignore_ascii_case(b),
+            CaseFolding::Unicode => a
+                .chars()
+                .flat_map(char::to_lowercase)
+                .eq(b.chars().flat_map(char::to_lowercase)),
+        }
+    }
+
+    /// Check if `s` starts with `prefix` once both are folded
+    pub(crate) fn starts_with(self, s: &str, prefix: &str) -> bool {
+        match self {
+            CaseFolding::Exact => s.starts_with(prefix),
+            CaseFolding::Ascii => s
+                .as_bytes()
+                .get(..prefix.len())
+                .is_some_and(|head| head.eq_ignore_ascii_case(prefix.as_bytes())),
+            CaseFolding::Unicode => lowercase(s).starts_with(&lowercase(prefix)),
+        }
+    }
+
+    /// Check if `s` ends with `suffix` once both are folded
+    pub(crate) fn ends_with(self, s: &str, suffix: &str) -> bool {
+        match self {
+            CaseFolding::Exact => s.ends_with(suffix),
+            CaseFolding::Ascii => s
+                .len()
+                .checked_sub(suffix.len())
+                .is_some_and(|start| s.as_bytes()[start..].eq_ignore_ascii_case(suffix.as_bytes())),
+            CaseFolding::Unicode => lowercase(s).ends_with(&lowercase(suffix)),
+        }
+    }
+
+    /// Check if `s` contains `needle` once both are folded
+    pub(crate) fn contains(self, s: &str, needle: &str) -> bool {
+        match self {
+            CaseFolding::Exact => s.contains(needle),
+            CaseFolding::Ascii => {
+                needle.is_empty()
+                    || s.as_bytes()
+                        .windows(needle.len())
+                        .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+            }
+            CaseFolding::Unicode => lowercase(s).contains(&lowercase(needle)),
+        }
+    }
+
+    fn write(self, s: &str, state: &mut impl Hasher) {
+        match self {
+            CaseFolding::Exact => s.hash(state),
+            CaseFolding::Ascii => {
+                s.bytes()
+                    .for_each(|byte| state.write_u8(byte.to_ascii_lowercase()));
+                state.write_u8(0xff);
+            }
+            CaseFolding::Unicode => {
+                s.chars()
+                    .flat_map(char::to_lowercase)
+                    .for_each(|c| state.write_u32(c.into()));
+                state.write_u8(0xff);
+            }
+        }
+    }
+}
+
+/// Lowercase `s` the way `CaseFolding::Unicode` compares it
+fn lowercase(s: &str) -> String {
+    s.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// Longest string, in bytes, stored inline in its slot
+pub const INLINE_CAPACITY: usize = 22;
+
+/// Storage of one interned string
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    /// Short string stored in place
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_CAPACITY],
+    },
+    /// Long string stored at `start..start + len` in the arena
+    Arena { start: usize, len: usize },
+    /// ID whose string was released
+    Released,
+}
+
 impl StringInterner {
     /// Create a new string interner
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Create an empty string interner with room for `capacity` strings
+    /// before it reallocates
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            strings: Arc::new(Strings {
+                table: HashTable::with_capacity(capacity),
+                slots: Vec::with_capacity(capacity),
+                ..Strings::default()
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// Create a string interner that compares strings with `folding`
+    ///
+    /// Strings the same once folded get one ID, which resolves to the
+    /// first of them interned.
+    pub fn with_case_folding(folding: CaseFolding) -> Self {
+        Self {
+            folding,
+            ..Self::default()
+        }
+    }
+
+    /// Get how the interner compares strings
+    pub fn case_folding(&self) -> CaseFolding {
+        self.folding
+    }
+
+    /// Create a child of this interner for short-lived strings, such as
+    /// those of one request
+    ///
+    /// The child resolves every ID of this interner and interns the
+    /// strings this interner has to the same IDs. Other strings are
+    /// interned into the child alone, with IDs past this interner's, and
+    /// are dropped with it, so per-request strings never grow a shared
+    /// interner. No strings are copied: the child shares this interner's
+    /// read-only, and they are only copied if this interner is modified
+    /// while the child is alive.
+    pub fn child(&self) -> Self {
+        Self {
+            parent: Some(Arc::new(self.clone())),
+            strings: Arc::new(Strings {
+                base: self.id_bound(),
+                ..Strings::default()
+            }),
+            builtins: self.builtins,
+            folding: self.folding,
+        }
+    }
+
+    /// Reserve room for at least `additional` more strings
+    pub fn reserve(&mut self, additional: usize) {
+        let Strings {
+            base,
+            table,
+            slots,
+            arena,
+        } = Arc::make_mut(&mut self.strings);
+        let folding = self.folding;
+        table.reserve(additional, |&id| {
+            folding.hash(text(*base, slots, arena, id).unwrap_or(""))
+        });
+        slots.reserve(additional);
+    }
+
+    /// Intern every string of `strings`, returning their IDs in order
+    ///
+    /// Room for all of them, including the arena text of long strings, is
+    /// reserved up front, so loading a large vocabulary grows the interner
+    /// once instead of rehashing and copying as it fills.
+    pub fn intern_many<S: AsRef<str>>(&mut self, strings: &[S]) -> Vec<StringId> {
+        self.reserve(strings.len());
+        let long: usize = strings
+            .iter()
+            .map(|s| s.as_ref().len())
+            .filter(|&len| len > INLINE_CAPACITY)
+            .sum();
+        Arc::make_mut(&mut self.strings).arena.reserve(long);
+        strings.iter().map(|s| self.intern(s.as_ref())).collect()
+    }
+
+    /// Create a string interner holding every builtin name at its
+    /// `BuiltinFunction::id`
+    pub fn with_builtins() -> Self {
+        let mut interner = Self::new();
+        for builtin in BuiltinFunction::ALL {
+            interner.intern(builtin.as_str());
+        }
+        interner.builtins = true;
+        interner
+    }
+
+    /// Get the builtin function named by `id`, if any
+    ///
+    /// With the builtins at their fixed IDs this is a range check; otherwise
+    /// the name is resolved and looked up.
+    pub fn builtin(&self, id: StringId) -> Option<BuiltinFunction> {
+        if self.builtins {
+            BuiltinFunction::from_id(id)
+        } else {
+            self.resolve(id).and_then(BuiltinFunction::from_str)
+        }
+    }
+
+    /// Record whether the builtin names hold their fixed IDs, as after
+    /// `with_builtins`
+    pub(crate) fn detect_builtins(&mut self) {
+        self.builtins = BuiltinFunction::ALL
+            .iter()
+            .all(|builtin| self.resolve(builtin.id()) == Some(builtin.as_str()));
+    }
+
     /// Intern a string and return its ID
     pub fn intern(&mut self, s: &str) -> StringId {
-        if let Some(&id) = self.string_to_id.get(s) {
+        let hash = self.folding.hash(s);
+        if let Some(id) = self.find(hash, s) {
             return id;
         }
+        let folding = self.folding;
 
-        let id = StringId(self.next_id);
-        self.next_id += 1;
-        
-        let owned = s.to_string();
-        self.string_to_id.insert(owned.clone(), id);
-        self.id_to_string.insert(id, owned);
-        
+        let Strings {
+            base,
+            table,
+            slots,
+            arena,
+        } = Arc::make_mut(&mut self.strings);
+        let id = StringId::new(*base + slots.len() as u32);
+        let slot = if s.len() <= INLINE_CAPACITY {
+            let mut bytes = [0; INLINE_CAPACITY];
+            bytes[..s.len()].copy_from_slice(s.as_bytes());
+            Slot::Inline {
+                len: s.len() as u8,
+                bytes,
+            }
+        } else {
+            let start = arena.len();
+            arena.push_str(s);
+            Slot::Arena {
+                start,
+                len: s.len(),
+            }
+        };
+        slots.push(slot);
+        table.insert_unique(hash, id, |&id| {
+            folding.hash(text(*base, slots, arena, id).unwrap_or(""))
+        });
         id
     }
 
     /// Get the string for an interned ID
     pub fn resolve(&self, id: StringId) -> Option<&str> {
-        self.id_to_string.get(&id).map(|s| s.as_str())
+        let strings = &*self.strings;
+        if id.raw() < strings.base {
+            return self.parent.as_ref()?.resolve(id);
+        }
+        text(strings.base, &strings.slots, &strings.arena, id)
     }
 
     /// Get the ID for a string if it exists
     pub fn get_id(&self, s: &str) -> Option<StringId> {
-        self.string_to_id.get(s).copied()
+        self.find(self.folding.hash(s), s)
+    }
+
+    /// Find a string's ID, in the parent first
+    fn find(&self, hash: u64, s: &str) -> Option<StringId> {
+        if let Some(id) = self.parent.as_ref().and_then(|parent| parent.find(hash, s)) {
+            return Some(id);
+        }
+        self.strings
+            .table
+            .find(hash, |&id| {
+                self.resolve(id)
+                    .is_some_and(|text| self.folding.eq(text, s))
+            })
+            .copied()
     }
 
     /// Check if a string is interned
     pub fn contains(&self, s: &str) -> bool {
-        self.string_to_id.contains_key(s)
+        self.get_id(s).is_some()
     }
 
-    /// Get the number of interned strings
+    /// Iterate over the live strings and their IDs, in ID order, which is
+    /// the order they were interned in
+    pub fn iter(&self) -> impl Iterator<Item = (StringId, &str)> {
+        (0..self.id_bound()).filter_map(|raw| {
+            let id = StringId::new(raw);
+            Some((id, self.resolve(id)?))
+        })
+    }
+
+    /// Get the string of every ID handed out, indexed by `StringId::raw`
+    ///
+    /// The table is dense, so arrays of per-string data can be built
+    /// alongside it and indexed by ID. Released IDs hold empty strings;
+    /// `resolve` tells them apart from an interned empty string.
+    pub fn export(&self) -> Vec<&str> {
+        (0..self.id_bound())
+            .map(|raw| self.resolve(StringId::new(raw)).unwrap_or(""))
+            .collect()
+    }
+
+    /// Get the number of interned strings, including a parent's
     pub fn len(&self) -> usize {
-        self.string_to_id.len()
+        self.parent.as_ref().map_or(0, |parent| parent.len()) + self.strings.table.len()
     }
 
     /// Check if the interner is empty
     pub fn is_empty(&self) -> bool {
-        self.string_to_id.is_empty()
+        self.len() == 0
+    }
+
+    /// Intern every string of `other`, returning where each of its IDs
+    /// ended up in this interner
+    ///
+    /// Strings this interner already has keep their IDs; new ones are
+    /// appended in `other`'s ID order.
+    pub fn merge(&mut self, other: &StringInterner) -> IdRemapTable {
+        let ids = (0..other.id_bound())
+            .map(|raw| Some(self.intern(other.resolve(StringId::new(raw))?)))
+            .collect();
+        IdRemapTable { ids }
+    }
+
+    /// Get the ID the next new string will get, one past every ID handed
+    /// out so far
+    pub(crate) fn id_bound(&self) -> u32 {
+        self.strings.base + self.strings.slots.len() as u32
+    }
+
+    /// Hand out the next ID as already released
+    pub(crate) fn skip_id(&mut self) {
+        Arc::make_mut(&mut self.strings).slots.push(Slot::Released);
+    }
+
+    /// Keep only the strings for which `keep` returns `true`
+    ///
+    /// IDs of kept strings are unchanged, and released IDs are never handed
+    /// out again, so an ID that outlives its string resolves to `None`
+    /// instead of to another string. The arena is compacted, so the space
+    /// of released long strings is reclaimed. A child only releases its
+    /// own strings, never its parent's.
+    pub fn retain(&mut self, mut keep: impl FnMut(StringId, &str) -> bool) {
+        let Strings {
+            base,
+            table,
+            slots,
+            arena: old,
+        } = Arc::make_mut(&mut self.strings);
+        let mut arena = String::new();
+        for (index, slot) in slots.iter_mut().enumerate() {
+            let id = StringId::new(*base + index as u32);
+            let Some(s) = text_of(slot, old) else {
+                continue;
+            };
+            if !keep(id, s) {
+                *slot = Slot::Released;
+            } else if let Slot::Arena { start, len } = slot {
+                let (from, to) = (*start, arena.len());
+                arena.push_str(&old[from..from + *len]);
+                *start = to;
+            }
+        }
+        *old = arena;
+        let base = *base;
+        table.retain(|id| !matches!(slots[(id.raw() - base) as usize], Slot::Released));
+        if self.builtins {
+            self.detect_builtins();
+        }
+    }
+
+    /// Mark the current end of the interner, to later release every
+    /// string interned after it with `release_since`
+    pub fn mark(&self) -> InternMark {
+        InternMark(self.id_bound())
+    }
+
+    /// Release every string interned after `mark`, such as the strings of
+    /// one request, and hand their IDs out again
+    ///
+    /// Strings interned before the mark, including those a later `intern`
+    /// call returned again, keep their IDs. IDs of released strings must not
+    /// be used afterwards, since they may come to name other strings. A
+    /// child never releases its parent's strings.
+    pub fn release_since(&mut self, mark: InternMark) {
+        let bound = mark.0.max(self.strings.base);
+        if bound >= self.id_bound() {
+            return;
+        }
+        let Strings {
+            base,
+            table,
+            slots,
+            arena,
+        } = Arc::make_mut(&mut self.strings);
+        let kept = (bound - *base) as usize;
+        // Long strings are appended in ID order, so theirs are the tail
+        let first_long = slots[kept..].iter().find_map(|slot| match slot {
+            Slot::Arena { start, .. } => Some(*start),
+            _ => None,
+        });
+        if let Some(start) = first_long {
+            arena.truncate(start);
+        }
+        slots.truncate(kept);
+        table.retain(|id| id.raw() < bound);
+        self.builtins &= bound as usize >= BuiltinFunction::ALL.len();
+    }
+}
+
+/// Get the text of `id` from the slots and arena of strings whose IDs
+/// start at `base`
+fn text<'a>(base: u32, slots: &'a [Slot], arena: &'a str, id: StringId) -> Option<&'a str> {
+    let index = id.raw().checked_sub(base)?;
+    text_of(slots.get(index as usize)?, arena)
+}
+
+fn text_of<'a>(slot: &'a Slot, arena: &'a str) -> Option<&'a str> {
+    match slot {
+        // Inline bytes were copied from a `str`, so they are valid UTF-8
+        Slot::Inline { len, bytes } => core::str::from_utf8(&bytes[..*len as usize]).ok(),
+        Slot::Arena { start, len } => Some(&arena[*start..*start + *len]),
+        Slot::Released => None,
+    }
+}
+
+/// Read-only view of an interner, through which evaluation resolves names
+/// and strings
+///
+/// Implemented by `StringInterner` and `FrozenInterner`, so rules built
+/// with an interner can be served from it after `StringInterner::freeze`.
+pub trait Interner: Send + Sync {
+    /// Get the string for an interned ID
+    fn resolve(&self, id: StringId) -> Option<&str>;
+
+    /// Get the ID for a string if it exists
+    fn get_id(&self, s: &str) -> Option<StringId>;
+
+    /// Get the builtin function named by `id`, if any
+    fn builtin(&self, id: StringId) -> Option<BuiltinFunction>;
+
+    /// Get how the interner compares strings
+    fn case_folding(&self) -> CaseFolding;
+}
+
+impl fmt::Debug for dyn Interner + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Interner")
+    }
+}
+
+impl Interner for StringInterner {
+    fn resolve(&self, id: StringId) -> Option<&str> {
+        StringInterner::resolve(self, id)
+    }
+
+    fn get_id(&self, s: &str) -> Option<StringId> {
+        StringInterner::get_id(self, s)
+    }
+
+    fn builtin(&self, id: StringId) -> Option<BuiltinFunction> {
+        StringInterner::builtin(self, id)
+    }
+
+    fn case_folding(&self) -> CaseFolding {
+        StringInterner::case_folding(self)
+    }
+}
+
+/// Position in a `StringInterner`'s history, returned by
+/// `StringInterner::mark`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InternMark(u32);
+
+/// Maps the IDs of one interner onto another, as returned by
+/// `StringInterner::merge`
+///
+/// Use it to rebase expressions and values built against the merged
+/// interner onto the one it was merged into.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IdRemapTable {
+    /// New ID of each source ID, indexed by the source ID, `None` for
+    /// released IDs
+    ids: Vec<Option<StringId>>,
+}
+
+impl IdRemapTable {
+    /// Get the new ID of a source ID
+    pub fn get(&self, id: StringId) -> Option<StringId> {
+        self.ids.get(id.raw() as usize).copied().flatten()
+    }
+
+    /// Get the number of mapped IDs
+    pub fn len(&self) -> usize {
+        self.ids.iter().flatten().count()
+    }
+
+    /// Check if no IDs are mapped
+    pub fn is_empty(&self) -> bool {
+        self.ids.iter().all(Option::is_none)
+    }
+
+    /// Check if every ID maps to itself, so nothing needs rebasing
+    pub fn is_identity(&self) -> bool {
+        self.ids
+            .iter()
+            .enumerate()
+            .all(|(i, id)| id.is_none_or(|id| id.raw() as usize == i))
+    }
+
+    /// Rebase an expression, returning `None` if it contains an ID outside
+    /// the table
+    pub fn remap_expr(&self, expr: &Expr) -> Option<Expr> {
+        Some(match expr {
+            Expr::Literal(value) => Expr::Literal(self.remap_value(value)?),
+            Expr::Variable(id) => Expr::Variable(self.get(*id)?),
+            Expr::Call { function, args } => Expr::Call {
+                function: self.get(*function)?,
+                args: args
+                    .iter()
+                    .map(|arg| self.remap_expr(arg))
+                    .collect::<Option<_>>()?,
+            },
+            Expr::List(items) => Expr::List(
+                items
+                    .iter()
+                    .map(|item| self.remap_expr(item))
+                    .collect::<Option<_>>()?,
+            ),
+            Expr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata: metadata
+                    .iter()
+                    .map(|(key, value)| Some((self.get(*key)?, self.remap_value(value)?)))
+                    .collect::<Option<_>>()?,
+                expr: Box::new(self.remap_expr(expr)?),
+            },
+            Expr::Let { bindings, body } => Expr::Let {
+                bindings: bindings
+                    .iter()
+                    .map(|(name, value)| Some((self.get(*name)?, self.remap_expr(value)?)))
+                    .collect::<Option<_>>()?,
+                body: Box::new(self.remap_expr(body)?),
+            },
+        })
+    }
+
+    /// Rebase a value, returning `None` if it contains an ID outside the
+    /// table
+    pub fn remap_value(&self, value: &Value) -> Option<Value> {
+        Some(match value {
+            Value::Symbol(id) => Value::Symbol(self.get(*id)?),
+            Value::String(id) => Value::String(self.get(*id)?),
+            Value::StringList(ids) => {
+                Value::StringList(ids.iter().map(|id| self.get(*id)).collect::<Option<_>>()?)
+            }
+            Value::List(items) => Value::List(
+                items
+                    .iter()
+                    .map(|item| self.remap_value(item))
+                    .collect::<Option<_>>()?,
+            ),
+            Value::Map(map) => Value::Map(
+                map.iter()
+                    .map(|(key, value)| Some((self.get(*key)?, self.remap_value(value)?)))
+                    .collect::<Option<_>>()?,
+            ),
+            Value::Quoted(expr) => Value::Quoted(Box::new(self.remap_expr(expr)?)),
+            other => other.clone(),
+        })
     }
 }
 
 impl StringId {
+    /// Create an ID from its raw value
+    pub(crate) const fn new(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    /// Create an ID from a raw value returned by `raw`
+    ///
+    /// Any value is a valid ID, but one not handed out by an interner
+    /// resolves to nothing, or to whatever string that interner gives it.
+    pub const fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
     /// Get the raw ID value
     pub fn raw(self) -> u32 {
         self.0
     }
 }
 
+/// Thread-safe string interner that can be shared across evaluator threads
+///
+/// The forward table is split into independently locked shards so threads
+/// interning different strings rarely contend. Like `StringInterner`'s, it
+/// holds only IDs and compares candidates against the stored strings, so
+/// each string is stored once. IDs are dense and assigned in insertion
+/// order, like `StringInterner`.
+#[derive(Debug)]
+pub struct ConcurrentStringInterner {
+    /// Sharded tables of interned IDs, hashed by their strings. A shard is
+    /// always locked before `strings`
+    shards: Box<[Shard]>,
+    /// Append-only storage indexed by ID, `None` for IDs released before
+    /// conversion from a `StringInterner`
+    strings: RwLock<Vec<Option<Arc<str>>>>,
+    /// How strings are compared when interning and looking them up
+    folding: CaseFolding,
+}
+
+impl ConcurrentStringInterner {
+    /// Create a new concurrent string interner
+    pub fn new() -> Self {
+        Self::with_case_folding(CaseFolding::Exact)
+    }
+
+    /// Create a concurrent string interner that compares strings with
+    /// `folding`, like `StringInterner::with_case_folding`
+    pub fn with_case_folding(folding: CaseFolding) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| RwLock::default()).collect(),
+            strings: RwLock::default(),
+            folding,
+        }
+    }
+
+    /// Intern a string and return its ID
+    pub fn intern(&self, s: &str) -> StringId {
+        let hash = self.folding.hash(s);
+        let shard = self.shard(hash);
+        if let Some(id) = self.find(&shard.read().unwrap(), hash, s) {
+            return id;
+        }
+
+        let mut table = shard.write().unwrap();
+        // Another thread may have interned it between the two locks
+        if let Some(id) = self.find(&table, hash, s) {
+            return id;
+        }
+
+        let id = {
+            let mut strings = self.strings.write().unwrap();
+            let id = StringId::new(strings.len() as u32);
+            strings.push(Some(Arc::from(s)));
+            id
+        };
+        let strings = self.strings.read().unwrap();
+        table.insert_unique(hash, id, |&id| {
+            self.folding
+                .hash(strings[id.raw() as usize].as_deref().unwrap_or(""))
+        });
+        id
+    }
+
+    /// Get the string for an interned ID
+    pub fn resolve(&self, id: StringId) -> Option<Arc<str>> {
+        self.strings
+            .read()
+            .unwrap()
+            .get(id.raw() as usize)
+            .cloned()
+            .flatten()
+    }
+
+    /// Get the ID for a string if it exists
+    pub fn get_id(&self, s: &str) -> Option<StringId> {
+        let hash = self.folding.hash(s);
+        self.find(&self.shard(hash).read().unwrap(), hash, s)
+    }
+
+    /// Check if a string is interned
+    pub fn contains(&self, s: &str) -> bool {
+        self.get_id(s).is_some()
+    }
+
+    /// Get the number of interned strings
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    /// Check if the interner is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Select the shard of a hash by its middle bits, since the table
+    /// inside uses the low bits for buckets and the high bits for tags
+    fn shard(&self, hash: u64) -> &Shard {
+        &self.shards[(hash >> 32) as usize & (SHARD_COUNT - 1)]
+    }
+
+    /// Find `s` in a locked shard
+    fn find(&self, table: &HashTable<StringId>, hash: u64, s: &str) -> Option<StringId> {
+        let strings = self.strings.read().unwrap();
+        table
+            .find(hash, |&id| {
+                strings[id.raw() as usize]
+                    .as_deref()
+                    .is_some_and(|text| self.folding.eq(text, s))
+            })
+            .copied()
+    }
+}
+
+impl Default for ConcurrentStringInterner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts an interner into a concurrent one, preserving all IDs
+impl From<StringInterner> for ConcurrentStringInterner {
+    fn from(interner: StringInterner) -> Self {
+        let concurrent = Self::with_case_folding(interner.folding);
+        for raw in 0..interner.id_bound() {
+            match interner.resolve(StringId::new(raw)) {
+                Some(s) => {
+                    concurrent.intern(s);
+                }
+                // Keep the IDs after a released one in place
+                None => concurrent.strings.write().unwrap().push(None),
+            }
+        }
+        concurrent
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
 
     #[test]
     fn basic_interning() {
@@ -94,6 +877,26 @@ mod tests {
         assert_eq!(interner.len(), 2);
     }
 
+    #[test]
+    fn bulk_interning() {
+        let mut interner = StringInterner::with_capacity(4);
+        let a = interner.intern("a");
+        let long = "a string too long to be stored inline";
+        let ids = interner.intern_many(&["b", "a", long, "b"]);
+        assert_eq!(ids[1], a);
+        assert_eq!(ids[0], ids[3]);
+        assert_eq!(interner.len(), 3);
+        assert_eq!(interner.resolve(ids[2]), Some(long));
+
+        let owned: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        let ids = interner.intern_many(&owned);
+        assert!(ids
+            .iter()
+            .zip(&owned)
+            .all(|(&id, s)| interner.resolve(id) == Some(s)));
+        assert_eq!(interner.len(), 103);
+    }
+
     #[test]
     fn lookup_operations() {
         let mut interner = StringInterner::new();
@@ -105,4 +908,344 @@ mod tests {
         assert!(interner.contains("test"));
         assert!(!interner.contains("nonexistent"));
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn concurrent_interning() {
+        let interner = ConcurrentStringInterner::new();
+        let words = ["alpha", "beta", "gamma", "delta"];
+
+        let ids: Vec<Vec<StringId>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| words.iter().map(|w| interner.intern(w)).collect()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert!(ids.iter().all(|thread_ids| thread_ids == &ids[0]));
+        assert_eq!(interner.len(), words.len());
+        for (word, id) in words.iter().zip(&ids[0]) {
+            assert_eq!(interner.resolve(*id).as_deref(), Some(*word));
+            assert_eq!(interner.get_id(word), Some(*id));
+        }
+        assert!(id_raws_dense(&ids[0]));
+
+        // Enough strings to grow every shard's table
+        let names: Vec<String> = (0..2000).map(|i| format!("name-{}", i)).collect();
+        std::thread::scope(|scope| {
+            for chunk in names.chunks(500) {
+                let interner = &interner;
+                scope.spawn(move || {
+                    for name in chunk {
+                        interner.intern(name);
+                    }
+                });
+            }
+        });
+        assert_eq!(interner.len(), words.len() + names.len());
+        for name in &names {
+            let id = interner.get_id(name).unwrap();
+            assert_eq!(interner.resolve(id).as_deref(), Some(name.as_str()));
+        }
+    }
+
+    #[test]
+    fn concurrent_from_interner_preserves_ids() {
+        let mut interner = StringInterner::new();
+        let a = interner.intern("a");
+        let b = interner.intern("b");
+
+        let concurrent = ConcurrentStringInterner::from(interner);
+        assert_eq!(concurrent.get_id("a"), Some(a));
+        assert_eq!(concurrent.get_id("b"), Some(b));
+        assert_eq!(concurrent.intern("c").raw(), 2);
+    }
+
+    #[test]
+    fn merge_remaps_ids() {
+        let mut ours = StringInterner::new();
+        let country = ours.intern("country");
+        ours.intern("age");
+
+        let mut theirs = StringInterner::new();
+        let tier = theirs.intern("tier");
+        let their_country = theirs.intern("country");
+        let header = theirs.intern("x-tier");
+        let expr =
+            crate::parse(r#"(and (= country "US") (in tier ['gold]))"#, &mut theirs).unwrap();
+
+        let table = ours.merge(&theirs);
+        assert_eq!(table.len(), theirs.len());
+        assert_eq!(table.get(their_country), Some(country));
+        assert_eq!(ours.resolve(table.get(tier).unwrap()), Some("tier"));
+        assert!(!table.is_identity());
+
+        let rebased = table.remap_expr(&expr).unwrap();
+        assert_eq!(rebased.to_sexpr(&ours), expr.to_sexpr(&theirs));
+        assert_eq!(table.remap_expr(&Expr::Variable(StringId::new(99))), None);
+
+        // Map keys and the values under them are rebased too
+        let headers = Value::Map(
+            [
+                (header, Value::Symbol(tier)),
+                (their_country, Value::StringList(vec![their_country].into())),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let rebased = table.remap_value(&headers).unwrap();
+        let Value::Map(map) = &rebased else {
+            panic!("expected a map, got {:?}", rebased);
+        };
+        assert_eq!(
+            map.get(&ours.get_id("x-tier").unwrap()),
+            Some(&Value::Symbol(table.get(tier).unwrap()))
+        );
+        assert_eq!(
+            map.get(&country),
+            Some(&Value::StringList(vec![country].into()))
+        );
+        let unmapped = Value::Map([(StringId::new(99), Value::Null)].into_iter().collect());
+        assert_eq!(table.remap_value(&unmapped), None);
+
+        // Quoted expressions are rebased like any other expression
+        let quoted = Value::Quoted(Box::new(expr.clone()));
+        let Some(Value::Quoted(rebased)) = table.remap_value(&quoted) else {
+            panic!("expected a quoted expression");
+        };
+        assert_eq!(rebased.to_sexpr(&ours), expr.to_sexpr(&theirs));
+
+        // Merging an interner into a copy of itself changes nothing
+        let mut copy = StringInterner::new();
+        assert!(copy.merge(&ours).is_identity());
+        assert!(copy.merge(&ours).is_identity());
+    }
+
+    #[test]
+    fn release_strings() {
+        let mut interner = StringInterner::new();
+        let country = interner.intern("country");
+        let age = interner.intern("age");
+        let mark = interner.mark();
+
+        let request = interner.intern("request-1");
+        assert_eq!(interner.intern("country"), country);
+        interner.release_since(mark);
+        assert_eq!(interner.len(), 2);
+        assert_eq!(interner.resolve(request), None);
+        assert_eq!(interner.resolve(country), Some("country"));
+        // Released IDs are handed out again
+        assert_eq!(interner.intern("request-2"), request);
+
+        interner.retain(|id, _| id != age);
+        assert_eq!(interner.resolve(age), None);
+        assert!(!interner.contains("age"));
+        assert_ne!(interner.intern("age"), age);
+
+        let mut merged = StringInterner::new();
+        let table = merged.merge(&interner);
+        assert_eq!(table.get(age), None);
+        assert_eq!(table.len(), interner.len());
+
+        let concurrent = ConcurrentStringInterner::from(interner);
+        assert_eq!(concurrent.resolve(age), None);
+        assert_eq!(concurrent.get_id("age").unwrap().raw(), 3);
+        assert_eq!(concurrent.len(), 3);
+    }
+
+    #[test]
+    fn case_folded_interning() {
+        let mut ascii = StringInterner::with_case_folding(CaseFolding::Ascii);
+        let us = ascii.intern("US");
+        assert_eq!(ascii.intern("us"), us);
+        assert_eq!(ascii.get_id("uS"), Some(us));
+        assert_eq!(ascii.resolve(us), Some("US"));
+        assert_ne!(ascii.intern("ÉTÉ"), ascii.intern("été"));
+        assert_eq!(ascii.len(), 3);
+
+        let mut unicode = StringInterner::with_case_folding(CaseFolding::Unicode);
+        let summer = unicode.intern("ÉTÉ");
+        assert_eq!(unicode.intern("été"), summer);
+        assert_eq!(unicode.resolve(summer), Some("ÉTÉ"));
+        assert_ne!(unicode.intern("straße"), unicode.intern("STRASSE"));
+
+        let mut child = ascii.child();
+        assert_eq!(child.intern("us"), us);
+        let de = child.intern("de");
+        assert_eq!(child.get_id("DE"), Some(de));
+        assert_eq!(ascii.clone().freeze().get_id("Us"), Some(us));
+        let concurrent = ConcurrentStringInterner::from(ascii);
+        assert_eq!(concurrent.get_id("us"), Some(us));
+        assert_eq!(concurrent.intern("FR"), concurrent.intern("fr"));
+
+        // Rules and contexts interned through the same interner compare
+        // without regard to case
+        let mut interner = StringInterner::with_case_folding(CaseFolding::Ascii);
+        let expr = crate::parse(r#"(in country ["US" "CA"])"#, &mut interner).unwrap();
+        let mut env = crate::Environment::new();
+        let country = interner.intern("Country");
+        env.insert(country, Value::String(interner.intern("ca")));
+        let evaluator = crate::Evaluator::new(&interner);
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn dense_export() {
+        let mut interner = StringInterner::new();
+        let ids = interner.intern_many(&["a", "b", "", "c"]);
+        interner.retain(|id, _| id != ids[1]);
+        let mut child = interner.child();
+        let d = child.intern("d");
+
+        let live: Vec<_> = child.iter().collect();
+        assert_eq!(live, [(ids[0], "a"), (ids[2], ""), (ids[3], "c"), (d, "d")]);
+        let table = child.export();
+        assert_eq!(table, ["a", "", "", "c", "d"]);
+        for (raw, s) in table.iter().enumerate() {
+            let id = StringId::from_raw(raw as u32);
+            assert_eq!(id.raw() as usize, raw);
+            assert_eq!(child.resolve(id).unwrap_or(""), *s);
+        }
+        assert_eq!(child.resolve(StringId::from_raw(5)), None);
+    }
+
+    #[test]
+    fn child_interners() {
+        let mut parent = StringInterner::with_builtins();
+        let country = parent.intern("country");
+        let shared = Arc::new(parent.clone());
+
+        let mut child = shared.child();
+        assert_eq!(child.intern("country"), country);
+        assert_eq!(
+            child.builtin(BuiltinFunction::Or.id()),
+            Some(BuiltinFunction::Or)
+        );
+        let long = "a request string too long to be stored inline";
+        let us = child.intern("US");
+        let text = child.intern(long);
+        assert_eq!(us.raw(), parent.id_bound());
+        assert_eq!(
+            (child.resolve(us), child.resolve(text)),
+            (Some("US"), Some(long))
+        );
+        assert_eq!(child.resolve(country), Some("country"));
+        assert_eq!(child.get_id("US"), Some(us));
+        assert_eq!(child.len(), parent.len() + 2);
+        // The parent never sees the child's strings
+        assert_eq!(shared.get_id("US"), None);
+        assert_eq!(shared.len(), parent.len());
+
+        // Children of children resolve every ancestor's strings
+        let mut grandchild = child.child();
+        let tier = grandchild.intern("tier");
+        assert_eq!(grandchild.intern("US"), us);
+        assert_eq!(grandchild.resolve(country), Some("country"));
+        assert_eq!(tier.raw(), us.raw() + 2);
+
+        // A child releases only its own strings
+        child.release_since(InternMark(0));
+        assert_eq!(child.len(), parent.len());
+        assert_eq!(child.resolve(country), Some("country"));
+        assert_eq!(child.resolve(us), None);
+        assert_eq!(child.intern("US"), us);
+        child.retain(|_, _| false);
+        assert_eq!(child.resolve(country), Some("country"));
+        assert_eq!(child.resolve(us), None);
+
+        // Modifying the parent copies its strings instead of changing the
+        // child's view of them
+        let child = parent.child();
+        parent.intern("age");
+        assert_eq!(child.get_id("age"), None);
+        assert_eq!(child.clone().freeze().get_id("US"), None);
+        assert_eq!(child.freeze().resolve(country), Some("country"));
+    }
+
+    #[test]
+    fn inline_and_arena_storage() {
+        let mut interner = StringInterner::new();
+        let short = "é".repeat(INLINE_CAPACITY / 2);
+        let long = "a".repeat(INLINE_CAPACITY + 1);
+        let other = "b".repeat(100);
+        let ids = [&short, &long, "", &other].map(|s| interner.intern(s));
+        assert!(matches!(interner.strings.slots[0], Slot::Inline { .. }));
+        assert!(matches!(interner.strings.slots[1], Slot::Arena { .. }));
+        for (s, id) in [&short, &long, "", &other].iter().zip(ids) {
+            assert_eq!(interner.resolve(id), Some(*s));
+            assert_eq!(interner.intern(s), id);
+        }
+
+        // Released long strings give their arena space back
+        interner.retain(|id, _| id != ids[1]);
+        assert_eq!(interner.strings.arena, other);
+        assert_eq!(interner.resolve(ids[3]), Some(other.as_str()));
+        let mark = interner.mark();
+        interner.intern(&"c".repeat(50));
+        interner.release_since(mark);
+        assert_eq!(interner.strings.arena, other);
+        assert_eq!(interner.get_id(&other), Some(ids[3]));
+    }
+
+    #[test]
+    fn strings_are_stored_once() {
+        let mut interner = StringInterner::new();
+        let long: Vec<String> = (0..100)
+            .map(|i| format!("{}-{}", "x".repeat(INLINE_CAPACITY), i))
+            .collect();
+        for s in long.iter().chain(&long) {
+            interner.intern(s);
+        }
+        // The arena holds the only copy of each long string
+        let total: usize = long.iter().map(String::len).sum();
+        assert_eq!(interner.strings.arena.len(), total);
+        let arena = interner.strings.arena.as_bytes().as_ptr_range();
+        for s in &long {
+            let resolved = interner.resolve(interner.get_id(s).unwrap()).unwrap();
+            assert!(arena.contains(&resolved.as_ptr()));
+        }
+
+        // The concurrent interner's shards hold no reference to the strings
+        let concurrent = ConcurrentStringInterner::new();
+        let id = concurrent.intern("country");
+        let resolved = concurrent.resolve(id).unwrap();
+        assert_eq!(Arc::strong_count(&resolved), 2);
+    }
+
+    #[test]
+    fn builtins_at_fixed_ids() {
+        let mut interner = StringInterner::with_builtins();
+        assert_eq!(interner.len(), BuiltinFunction::ALL.len());
+        for builtin in BuiltinFunction::ALL {
+            assert_eq!(interner.get_id(builtin.as_str()), Some(builtin.id()));
+            assert_eq!(interner.builtin(builtin.id()), Some(*builtin));
+        }
+        assert_eq!(interner.get_id("and").unwrap().raw(), 0);
+        let age = interner.intern("age");
+        assert_eq!(age.raw() as usize, BuiltinFunction::ALL.len());
+        assert_eq!(interner.builtin(age), None);
+
+        // Without the fixed IDs builtins are found by name
+        let mut plain = StringInterner::new();
+        let age = plain.intern("age");
+        let or = plain.intern("or");
+        assert_eq!(plain.builtin(age), None);
+        assert_eq!(plain.builtin(or), Some(BuiltinFunction::Or));
+
+        // Releasing a builtin name falls back to resolving names
+        interner.retain(|id, _| id != BuiltinFunction::Not.id());
+        assert_eq!(interner.builtin(BuiltinFunction::Not.id()), None);
+        assert_eq!(
+            interner.builtin(BuiltinFunction::Or.id()),
+            Some(BuiltinFunction::Or)
+        );
+        assert_ne!(interner.intern("not"), BuiltinFunction::Not.id());
+    }
+
+    #[cfg(feature = "std")]
+    fn id_raws_dense(ids: &[StringId]) -> bool {
+        let mut raws: Vec<u32> = ids.iter().map(|id| id.raw()).collect();
+        raws.sort_unstable();
+        raws.iter().enumerate().all(|(i, &raw)| raw as usize == i)
+    }
 }
\ No newline at end of file
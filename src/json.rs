@@ -0,0 +1,738 @@
+//! JSON rule format
+//!
+//! Maps between `Expr` and the JSON DSL many services already store rules
+//! in:
+//!
+//! ```json
+//! {"op": "and", "args": [
+//!     {"op": "=", "args": [{"var": "country"}, "US"]},
+//!     {"op": ">=", "args": [{"var": "age"}, 21]},
+//!     {"op": "in", "args": [{"sym": "tier"}, ["gold", "silver"]]}
+//! ]}
+//! ```
+//!
+//! - `{"op": name, "args": [...]}` is a call; `args` may be omitted when
+//!   empty
+//! - `{"var": name}` is a variable and `{"sym": name}` a symbol literal
+//! - `{"dec": "12.50"}` is an exact decimal literal
+//! - strings, numbers, booleans and `null` are literals, and arrays are
+//!   lists. Numbers that fit in an `i64` are integers, others are floats
+//! - `{"meta": {key: literal, ...}, "expr": expr}` is an annotated
+//!   expression. JSON objects are unordered, so entries come back sorted
+//!   by key
+//! - `{"let": [[name, expr], ...], "in": expr}` binds locals, in order,
+//!   for the expression under `in`
+//!
+//! Function names are not checked here, so rules may call custom
+//! functions.
+//!
+//! `Environment::from_json` builds an environment from a JSON object of
+//! attributes and `context_from_json` fills a `Context` from one, and
+//! `value_to_json` converts evaluation results back. `schema_from_json`
+//! reads a `Schema` from an object mapping attributes to type names such as
+//! `"integer"` or `"string-list"`.
+
+use crate::compat::FxHashMap;
+use crate::context::PATH_SEPARATOR;
+use crate::eval::make_list;
+use crate::{
+    BuiltinFunction, Context, Environment, Expr, Schema, StringId, StringInterner, Value, ValueType,
+};
+use serde_json::{Map, Number, Value as Json};
+use std::fmt;
+
+/// Error converting between JSON and `Expr`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    /// Location of the offending node, e.g. `$.args[1].var`
+    pub path: String,
+    /// What is wrong with it
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Convert a JSON rule to an expression, interning strings into `interner`
+pub fn from_json(json: &Json, interner: &mut StringInterner) -> Result<Expr, JsonError> {
+    let mut path = String::from("$");
+    expr_from_json(json, interner, &mut path)
+}
+
+/// Convert an expression to a JSON rule
+///
+/// Fails if the expression contains IDs missing from `interner`, or floats
+/// that are not finite or maps, which the format cannot represent.
+pub fn to_json(expr: &Expr, interner: &StringInterner) -> Result<Json, JsonError> {
+    let mut path = String::from("$");
+    expr_to_json(expr, interner, &mut path)
+}
+
+/// Set the attributes of a JSON object in `context`
+///
+/// Nested objects are nested attributes, so `{"user": {"age": 30}}` sets
+/// `user.age`. Other JSON values convert as literals do in rules, except
+/// that strings are plain strings and objects inside arrays become maps.
+pub fn context_from_json(json: &Json, context: &mut Context) -> Result<(), JsonError> {
+    let Json::Object(attributes) = json else {
+        return Err(error("$", "context must be an object"));
+    };
+    let mut path = String::from("$");
+    let (interner, environment) = context.parts_mut();
+    attributes_from_json(attributes, "", environment, interner, &mut path)
+}
+
+impl Environment {
+    /// Build an environment from a JSON object of attributes, interning
+    /// names and strings into `interner`
+    ///
+    /// Attributes convert as in `context_from_json`: nested objects are
+    /// flattened to dotted paths, so `{"user": {"age": 30}}` binds
+    /// `user.age`, numbers are integers when they fit in an `i64` and
+    /// floats otherwise, and arrays are lists.
+    pub fn from_json(json: &Json, interner: &mut StringInterner) -> Result<Self, JsonError> {
+        let Json::Object(attributes) = json else {
+            return Err(error("$", "context must be an object"));
+        };
+        let mut environment = Environment::new();
+        let mut path = String::from("$");
+        attributes_from_json(attributes, "", &mut environment, interner, &mut path)?;
+        Ok(environment)
+    }
+}
+
+/// Convert a value, such as an evaluation result, to JSON
+///
+/// Symbols become `{"sym": name}` as in rules. Fails for the values
+/// `to_json` cannot represent.
+pub fn value_to_json(value: &Value, interner: &StringInterner) -> Result<Json, JsonError> {
+    let mut path = String::from("$");
+    literal_to_json(value, interner, &mut path)
+}
+
+/// Read a schema from a JSON object of attribute types, interning names
+/// into `interner`
+///
+/// Nested objects declare nested attributes, as in `context_from_json`.
+/// Type names are the `ValueType` names in kebab case, so
+/// `{"user": {"age": "integer", "tags": "string-list"}}` declares
+/// `user.age` and `user.tags`.
+pub fn schema_from_json(json: &Json, interner: &mut StringInterner) -> Result<Schema, JsonError> {
+    let Json::Object(types) = json else {
+        return Err(error("$", "schema must be an object"));
+    };
+    let mut schema = Schema::new();
+    let mut path = String::from("$");
+    types_from_json(types, "", &mut schema, interner, &mut path)?;
+    Ok(schema)
+}
+
+fn types_from_json(
+    types: &Map<String, Json>,
+    prefix: &str,
+    schema: &mut Schema,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<(), JsonError> {
+    for (key, json) in types {
+        let len = path.len();
+        path.push_str(&format!(".{}", key));
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}{}{}", prefix, PATH_SEPARATOR, key)
+        };
+        match json {
+            Json::Object(nested) => types_from_json(nested, &name, schema, interner, path)?,
+            Json::String(ty) => {
+                let Some(ty) = value_type(ty) else {
+                    return Err(error(path, format!("unknown type `{}`", ty)));
+                };
+                schema.declare(interner.intern(&name), ty);
+            }
+            _ => return Err(error(path, "type must be a string")),
+        }
+        path.truncate(len);
+    }
+    Ok(())
+}
+
+fn value_type(name: &str) -> Option<ValueType> {
+    Some(match name {
+        "symbol" => ValueType::Symbol,
+        "string" => ValueType::String,
+        "integer" => ValueType::Integer,
+        "float" => ValueType::Float,
+        "string-list" => ValueType::StringList,
+        "integer-list" => ValueType::IntegerList,
+        "bool" => ValueType::Bool,
+        "list" => ValueType::List,
+        "null" => ValueType::Null,
+        "text" => ValueType::Text,
+        "map" => ValueType::Map,
+        "quoted" => ValueType::Quoted,
+        "int-range" => ValueType::IntRange,
+        "bloom-set" => ValueType::BloomSet,
+        _ => return None,
+    })
+}
+
+fn attributes_from_json(
+    attributes: &Map<String, Json>,
+    prefix: &str,
+    environment: &mut Environment,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<(), JsonError> {
+    for (key, json) in attributes {
+        let len = path.len();
+        path.push_str(&format!(".{}", key));
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}{}{}", prefix, PATH_SEPARATOR, key)
+        };
+        match json {
+            Json::Object(nested) => {
+                attributes_from_json(nested, &name, environment, interner, path)?
+            }
+            _ => {
+                let value = attribute_from_json(json, interner, path)?;
+                environment.insert(interner.intern(&name), value);
+            }
+        }
+        path.truncate(len);
+    }
+    Ok(())
+}
+
+fn attribute_from_json(
+    json: &Json,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Value, JsonError> {
+    Ok(match json {
+        Json::Null => Value::Null,
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Number(n) => match n.as_i64() {
+            Some(n) => Value::Integer(n),
+            None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Json::String(s) => Value::String(interner.intern(s)),
+        Json::Array(items) => {
+            let mut values = Vec::with_capacity(items.len());
+            for (i, item) in items.iter().enumerate() {
+                let len = path.len();
+                path.push_str(&format!("[{}]", i));
+                values.push(attribute_from_json(item, interner, path)?);
+                path.truncate(len);
+            }
+            make_list(&values.iter().collect::<Vec<_>>())
+        }
+        Json::Object(entries) => {
+            let mut map = FxHashMap::default();
+            for (key, item) in entries {
+                let len = path.len();
+                path.push_str(&format!(".{}", key));
+                let value = attribute_from_json(item, interner, path)?;
+                map.insert(interner.intern(key), value);
+                path.truncate(len);
+            }
+            Value::Map(map)
+        }
+    })
+}
+
+fn expr_from_json(
+    json: &Json,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Expr, JsonError> {
+    Ok(match json {
+        Json::Null => Expr::Literal(Value::Null),
+        Json::Bool(b) => Expr::Literal(Value::Bool(*b)),
+        Json::Number(n) => Expr::Literal(match n.as_i64() {
+            Some(n) => Value::Integer(n),
+            None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        }),
+        Json::String(s) => Expr::Literal(Value::String(interner.intern(s))),
+        Json::Array(items) => Expr::List(list_from_json(items, interner, path)?),
+        Json::Object(object) => object_from_json(object, interner, path)?,
+    })
+}
+
+fn object_from_json(
+    object: &Map<String, Json>,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Expr, JsonError> {
+    let allowed: &[&str] = if object.contains_key("op") {
+        &["op", "args"]
+    } else if object.contains_key("meta") {
+        &["meta", "expr"]
+    } else if object.contains_key("let") {
+        &["let", "in"]
+    } else {
+        &["var", "sym", "dec"]
+    };
+    if let Some(key) = object.keys().find(|key| !allowed.contains(&key.as_str())) {
+        return Err(error(path, format!("unexpected key `{}`", key)));
+    }
+
+    if let Some(op) = object.get("op") {
+        let function = name(op, "op", interner, path)?;
+        let args = match object.get("args") {
+            None => Vec::new(),
+            Some(Json::Array(args)) => {
+                let len = path.len();
+                path.push_str(".args");
+                let args = list_from_json(args, interner, path)?;
+                path.truncate(len);
+                args
+            }
+            Some(_) => return Err(error(&format!("{}.args", path), "`args` must be an array")),
+        };
+        return Ok(Expr::Call { function, args });
+    }
+    if let Some(meta) = object.get("meta") {
+        return annotated_from_json(meta, object.get("expr"), interner, path);
+    }
+    if let Some(bindings) = object.get("let") {
+        return let_from_json(bindings, object.get("in"), interner, path);
+    }
+    match (object.get("var"), object.get("sym"), object.get("dec")) {
+        (Some(var), None, None) => Ok(Expr::Variable(name(var, "var", interner, path)?)),
+        (None, Some(sym), None) => Ok(Expr::Literal(Value::Symbol(name(
+            sym, "sym", interner, path,
+        )?))),
+        (None, None, Some(dec)) => dec
+            .as_str()
+            .and_then(|text| text.parse().ok())
+            .map(|decimal| Expr::Literal(Value::Decimal(decimal)))
+            .ok_or_else(|| error(path, "`dec` must be a decimal string")),
+        _ => Err(error(
+            path,
+            "expected an object with one of `op`, `meta`, `let`, `var`, `sym` or `dec`",
+        )),
+    }
+}
+
+fn annotated_from_json(
+    meta: &Json,
+    expr: Option<&Json>,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Expr, JsonError> {
+    let len = path.len();
+    path.push_str(".meta");
+    let Json::Object(entries) = meta else {
+        return Err(error(path, "`meta` must be an object"));
+    };
+    let mut metadata = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let len = path.len();
+        path.push_str(&format!(".{}", key));
+        let value = match expr_from_json(value, interner, path)? {
+            Expr::Literal(value) => Some(value),
+            Expr::List(items) => items
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(value) => Some(value),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|values| make_list(&values)),
+            _ => None,
+        };
+        let Some(value) = value else {
+            return Err(error(path, "metadata must be a literal"));
+        };
+        path.truncate(len);
+        metadata.push((interner.intern(key), value));
+    }
+    path.truncate(len);
+
+    let Some(expr) = expr else {
+        return Err(error(path, "annotated expression has no `expr`"));
+    };
+    path.push_str(".expr");
+    let expr = expr_from_json(expr, interner, path)?;
+    path.truncate(len);
+    Ok(Expr::Annotated {
+        metadata,
+        expr: Box::new(expr),
+    })
+}
+
+fn let_from_json(
+    bindings: &Json,
+    body: Option<&Json>,
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Expr, JsonError> {
+    let len = path.len();
+    path.push_str(".let");
+    let Json::Array(entries) = bindings else {
+        return Err(error(path, "`let` must be an array of bindings"));
+    };
+    let mut locals = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let len = path.len();
+        path.push_str(&format!("[{}]", i));
+        let Some([Json::String(name), value]) = entry.as_array().map(Vec::as_slice) else {
+            return Err(error(path, "binding must be a `[name, expr]` pair"));
+        };
+        if name.is_empty() || name.contains(PATH_SEPARATOR) {
+            return Err(error(path, "local name must be a plain, non-empty name"));
+        }
+        path.push_str("[1]");
+        let value = expr_from_json(value, interner, path)?;
+        path.truncate(len);
+        locals.push((interner.intern(name), value));
+    }
+    path.truncate(len);
+
+    let Some(body) = body else {
+        return Err(error(path, "`let` has no `in` expression"));
+    };
+    path.push_str(".in");
+    let body = expr_from_json(body, interner, path)?;
+    path.truncate(len);
+    Ok(Expr::Let {
+        bindings: locals,
+        body: Box::new(body),
+    })
+}
+
+fn list_from_json(
+    items: &[Json],
+    interner: &mut StringInterner,
+    path: &mut String,
+) -> Result<Vec<Expr>, JsonError> {
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let len = path.len();
+            path.push_str(&format!("[{}]", i));
+            let expr = expr_from_json(item, interner, path);
+            path.truncate(len);
+            expr
+        })
+        .collect()
+}
+
+/// Intern the string under `key`
+fn name(
+    json: &Json,
+    key: &str,
+    interner: &mut StringInterner,
+    path: &str,
+) -> Result<StringId, JsonError> {
+    match json {
+        Json::String(s) => Ok(interner.intern(s)),
+        _ => Err(error(
+            &format!("{}.{}", path, key),
+            format!("`{}` must be a string", key),
+        )),
+    }
+}
+
+fn expr_to_json(
+    expr: &Expr,
+    interner: &StringInterner,
+    path: &mut String,
+) -> Result<Json, JsonError> {
+    Ok(match expr {
+        Expr::Literal(value) => literal_to_json(value, interner, path)?,
+        Expr::Variable(id) => tagged("var", resolve(*id, interner, path)?),
+        Expr::Call { function, args } => {
+            let mut object = Map::new();
+            object.insert("op".into(), resolve(*function, interner, path)?.into());
+            let len = path.len();
+            path.push_str(".args");
+            let args = items_to_json(args, path, |arg, path| expr_to_json(arg, interner, path))?;
+            path.truncate(len);
+            object.insert("args".into(), args);
+            Json::Object(object)
+        }
+        Expr::List(items) => {
+            items_to_json(items, path, |item, path| expr_to_json(item, interner, path))?
+        }
+        Expr::Annotated { metadata, expr } => {
+            let len = path.len();
+            path.push_str(".meta");
+            let mut meta = Map::new();
+            for (key, value) in metadata {
+                let key = resolve(*key, interner, path)?;
+                let len = path.len();
+                path.push_str(&format!(".{}", key));
+                meta.insert(key.into(), literal_to_json(value, interner, path)?);
+                path.truncate(len);
+            }
+            path.truncate(len);
+            path.push_str(".expr");
+            let expr = expr_to_json(expr, interner, path)?;
+            path.truncate(len);
+
+            let mut object = Map::new();
+            object.insert("meta".into(), Json::Object(meta));
+            object.insert("expr".into(), expr);
+            Json::Object(object)
+        }
+        Expr::Let { bindings, body } => {
+            let len = path.len();
+            path.push_str(".let");
+            let bindings = items_to_json(bindings, path, |(name, value), path| {
+                let name = resolve(*name, interner, path)?;
+                let len = path.len();
+                path.push_str("[1]");
+                let value = expr_to_json(value, interner, path)?;
+                path.truncate(len);
+                Ok(Json::Array(vec![name.into(), value]))
+            })?;
+            path.truncate(len);
+            path.push_str(".in");
+            let body = expr_to_json(body, interner, path)?;
+            path.truncate(len);
+
+            let mut object = Map::new();
+            object.insert("let".into(), bindings);
+            object.insert("in".into(), body);
+            Json::Object(object)
+        }
+    })
+}
+
+fn literal_to_json(
+    value: &Value,
+    interner: &StringInterner,
+    path: &mut String,
+) -> Result<Json, JsonError> {
+    Ok(match value {
+        Value::Symbol(id) => tagged("sym", resolve(*id, interner, path)?),
+        Value::String(id) => resolve(*id, interner, path)?.into(),
+        Value::Text(text) => Json::String(text.to_string()),
+        Value::Integer(n) => Json::from(*n),
+        Value::Float(x) => Number::from_f64(*x)
+            .map(Json::Number)
+            .ok_or_else(|| error(path, format!("float {} has no JSON form", x)))?,
+        Value::Decimal(decimal) => tagged("dec", &decimal.to_string()),
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Null => Json::Null,
+        Value::StringList(ids) => items_to_json(ids, path, |id, path| {
+            Ok(resolve(*id, interner, path)?.into())
+        })?,
+        Value::IntegerList(ns) => Json::from(ns.as_slice()),
+        Value::List(items) => items_to_json(items, path, |item, path| {
+            literal_to_json(item, interner, path)
+        })?,
+        Value::Map(_) => return Err(error(path, "maps have no JSON form")),
+        Value::BloomSet(_) => return Err(error(path, "bloom sets have no JSON form")),
+        // Written as the call that produces it
+        Value::Quoted(expr) => {
+            let mut object = Map::new();
+            object.insert("op".into(), BuiltinFunction::Quote.as_str().into());
+            let len = path.len();
+            path.push_str(".args[0]");
+            let arg = expr_to_json(expr, interner, path)?;
+            path.truncate(len);
+            object.insert("args".into(), Json::Array(vec![arg]));
+            Json::Object(object)
+        }
+        Value::IntRange(start, end) => {
+            let mut object = Map::new();
+            object.insert("op".into(), BuiltinFunction::IntRange.as_str().into());
+            object.insert("args".into(), Json::from(vec![*start, *end]));
+            Json::Object(object)
+        }
+    })
+}
+
+fn items_to_json<T>(
+    items: &[T],
+    path: &mut String,
+    mut convert: impl FnMut(&T, &mut String) -> Result<Json, JsonError>,
+) -> Result<Json, JsonError> {
+    let mut array = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let len = path.len();
+        path.push_str(&format!("[{}]", i));
+        array.push(convert(item, path)?);
+        path.truncate(len);
+    }
+    Ok(Json::Array(array))
+}
+
+fn tagged(key: &str, name: &str) -> Json {
+    let mut object = Map::new();
+    object.insert(key.into(), name.into());
+    Json::Object(object)
+}
+
+fn resolve<'i>(
+    id: StringId,
+    interner: &'i StringInterner,
+    path: &str,
+) -> Result<&'i str, JsonError> {
+    interner
+        .resolve(id)
+        .ok_or_else(|| error(path, format!("unknown string #{}", id.raw())))
+}
+
+fn error(path: &str, message: impl Into<String>) -> JsonError {
+    JsonError {
+        path: path.to_string(),
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::json;
+
+    #[test]
+    fn round_trip() {
+        let mut interner = StringInterner::new();
+        let json = json!({"op": "and", "args": [
+            {"op": "=", "args": [{"var": "country"}, "US"]},
+            {"op": ">=", "args": [{"var": "age"}, 21.5]},
+            {"op": "in", "args": [{"sym": "tier"}, ["gold", null, 3]]},
+            {"op": "is-null", "args": [{"var": "banned"}]},
+            {"op": "<", "args": [{"var": "total"}, {"dec": "12.50"}]}
+        ]});
+        let expr = from_json(&json, &mut interner).unwrap();
+        let parsed = parse(
+            r#"(and (= country "US") (>= age 21.5) (in 'tier ["gold" null 3]) (is-null banned) (< total 12.50d))"#,
+            &mut interner,
+        )
+        .unwrap();
+        assert_eq!(expr, parsed);
+        assert_eq!(to_json(&expr, &interner).unwrap(), json);
+
+        let json = json!({"let": [["n", {"op": "string-length", "args": [{"var": "name"}]}]],
+                          "in": {"op": ">", "args": [{"var": "n"}, 3]}});
+        let expr = from_json(&json, &mut interner).unwrap();
+        assert_eq!(
+            expr,
+            parse("(let ((n (string-length name))) (> n 3))", &mut interner).unwrap()
+        );
+        assert_eq!(to_json(&expr, &interner).unwrap(), json);
+
+        let list = Expr::Literal(Value::StringList(vec![interner.intern("a")].into()));
+        assert_eq!(to_json(&list, &interner).unwrap(), json!(["a"]));
+        assert_eq!(
+            from_json(&json!({"op": "and"}), &mut interner).unwrap(),
+            parse("(and)", &mut interner).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors_have_paths() {
+        let mut interner = StringInterner::new();
+        let cases = [
+            (json!({"op": "not", "args": [{"var": 1}]}), "$.args[0].var"),
+            (
+                json!({"op": "or", "args": [true, {"value": 1}]}),
+                "$.args[1]",
+            ),
+            (json!([{"op": "=", "args": 2}]), "$[0].args"),
+            (json!({"var": "x", "sym": "y"}), "$"),
+            (json!({"dec": 1.5}), "$"),
+            (
+                json!({"let": [["x", {"var": 1}]], "in": true}),
+                "$.let[0][1].var",
+            ),
+            (json!({"let": [["a.b", 1]], "in": true}), "$.let[0]"),
+            (json!({"let": []}), "$"),
+        ];
+        for (json, path) in cases {
+            assert_eq!(
+                from_json(&json, &mut interner).unwrap_err().path,
+                path,
+                "{}",
+                json
+            );
+        }
+
+        let nan = Expr::List(vec![Expr::Literal(Value::Float(f64::NAN))]);
+        assert_eq!(to_json(&nan, &interner).unwrap_err().path, "$[0]");
+    }
+
+    #[test]
+    fn context_attributes() {
+        let mut context = Context::new();
+        let json = json!({
+            "user": {"age": 30, "device": {"os": "ios"}},
+            "tags": ["a", "b"],
+            "items": [{"sku": 7}],
+            "banned": null
+        });
+        context_from_json(&json, &mut context).unwrap();
+        assert_eq!(context.get("user.age"), Some(&Value::Integer(30)));
+        assert!(context.get("user").is_none());
+        assert_eq!(context.get("banned"), Some(&Value::Null));
+        assert!(matches!(context.get("items"), Some(Value::List(items)) if items[0].is_map()));
+
+        let rule = context
+            .parse(r#"(and (= user.device.os "ios") (in "b" tags) (is-null banned))"#)
+            .unwrap();
+        assert_eq!(context.eval(&rule), Ok(Value::Bool(true)));
+
+        let tags = context.get("tags").unwrap().clone();
+        assert_eq!(
+            value_to_json(&tags, context.interner()).unwrap(),
+            json!(["a", "b"])
+        );
+        assert_eq!(
+            context_from_json(&json!([1]), &mut context)
+                .unwrap_err()
+                .path,
+            "$"
+        );
+    }
+
+    #[test]
+    fn environment_from_json() {
+        let mut interner = StringInterner::new();
+        let json = json!({
+            "user": {"age": 30, "score": 0.5, "device": {"os": "ios"}},
+            "tags": ["a", "b"],
+            "ids": [1, 2]
+        });
+        let env = Environment::from_json(&json, &mut interner).unwrap();
+        assert_eq!(env.len(), 5);
+        let get = |name: &str| env.get(interner.get_id(name).unwrap());
+        assert_eq!(get("user.age"), Some(&Value::Integer(30)));
+        assert_eq!(get("user.score"), Some(&Value::Float(0.5)));
+        let ios = interner.get_id("ios").unwrap();
+        assert_eq!(get("user.device.os"), Some(&Value::String(ios)));
+        assert!(get("tags").unwrap().is_string_list());
+        assert_eq!(get("ids"), Some(&Value::IntegerList(vec![1, 2].into())));
+        assert!(interner.get_id("user").is_none());
+
+        let err = Environment::from_json(&json!("event"), &mut interner).unwrap_err();
+        assert_eq!(err.path, "$");
+    }
+
+    #[test]
+    fn schema_types() {
+        let mut interner = StringInterner::new();
+        let json = json!({"user": {"age": "integer", "tags": "string-list"}, "vip": "bool"});
+        let schema = schema_from_json(&json, &mut interner).unwrap();
+        assert_eq!(schema.len(), 3);
+        let age = interner.get_id("user.age").unwrap();
+        assert_eq!(schema.get(age), Some(ValueType::Integer));
+
+        let bad = json!({"user": {"age": "int"}});
+        let error = schema_from_json(&bad, &mut interner).unwrap_err();
+        assert_eq!(error.path, "$.user.age");
+    }
+}
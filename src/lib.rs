@@ -1,9 +1,135 @@
 //! Ironwood - Efficient S-expression evaluation engine
+//!
+//! The `std` feature is on by default. Without it the crate is `no_std`
+//! and needs only `alloc`; `matches-regex` then fails with
+//! `EvalError::InvalidRegex`, and deadlines and tracing, which need a
+//! clock, are unavailable.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// Lets derived impls name `::ironwood` inside this crate's own tests
+#[cfg(feature = "derive")]
+extern crate self as ironwood;
 
 pub mod intern;
+pub mod frozen;
 pub mod value;
+pub mod decimal;
+pub mod convert;
 pub mod expr;
+pub mod env;
+pub mod context;
+pub mod eval;
+pub mod limits;
+pub mod cancel;
+pub mod error;
+pub mod function;
+pub mod provider;
+pub mod parser;
+pub mod compile;
+pub mod ruleset;
+pub mod reload;
+pub mod cache;
+pub mod score;
+pub mod library;
+pub mod template;
+pub mod decision_table;
+pub mod matcher;
+pub mod schema;
+pub mod optimize;
+pub mod canonical;
+pub mod lint;
+pub mod analysis;
+pub mod coverage;
+pub mod incremental;
+pub(crate) mod pattern;
+pub mod rollout;
+pub mod flags;
+pub(crate) mod semver;
+pub(crate) mod net;
+pub(crate) mod cell;
+pub(crate) mod diff;
+pub(crate) mod member;
+pub mod bloom;
+pub mod list;
+pub(crate) mod suggest;
+pub(crate) mod compat;
+pub mod print;
+pub mod visit;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod audit;
+pub mod builder;
+pub mod binary;
+pub mod arena;
+#[cfg(feature = "serde")]
+pub mod serialize;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "rayon")]
+pub mod parallel;
+#[cfg(feature = "async")]
+pub mod async_eval;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod testing;
+pub mod metrics;
+pub(crate) mod telemetry;
 
-pub use intern::{StringInterner, StringId};
+pub use intern::{
+    CaseFolding, ConcurrentStringInterner, IdRemapTable, InternMark, Interner, StringInterner,
+    StringId,
+};
+pub use frozen::FrozenInterner;
 pub use value::{Value, ValueType};
-pub use expr::{Expr, BuiltinFunction};
\ No newline at end of file
+pub use decimal::Decimal;
+pub use bloom::BloomSet;
+pub use list::SmallList;
+pub use convert::{ConvertError, FromValue, IntoValue};
+#[cfg(feature = "derive")]
+pub use ironwood_derive::{FromValue, IntoValue};
+pub use expr::{Expr, BuiltinFunction};
+pub use env::{EnvSlots, Environment};
+pub use context::{Context, Scope};
+pub use eval::{
+    DistanceFormula, EvalError, EvalOptions, Evaluator, IntegerOverflow, MissingVariable,
+};
+pub use error::{IronwoodError, Span};
+pub use limits::{EvalLimits, Limit};
+pub use cancel::CancelToken;
+pub use metrics::{MetricsSink, NoMetrics};
+pub use function::{CustomFunction, FunctionRegistry};
+pub use provider::VariableProvider;
+#[cfg(feature = "async")]
+pub use async_eval::{AsyncEvaluator, AsyncFunctionRegistry, AsyncVariableProvider};
+pub use parser::{parse, parse_many, parse_spanned, parse_with_limits, SpannedExpr};
+pub use compile::{compile, compile_with, CompiledExpr};
+pub use ruleset::{RuleId, RuleSet};
+pub use reload::{ReloadError, RuleSetHandle};
+pub use cache::DecisionCache;
+pub use score::ScoreSet;
+pub use library::{DependencyGraph, LibraryError, RuleLibrary};
+pub use template::{Template, TemplateError};
+pub use decision_table::{DecisionTable, DecisionTableError};
+pub use matcher::Matcher;
+pub use schema::{typecheck, Schema, TypeError};
+pub use optimize::{Conjunction, PartialEnv, Predicate};
+pub use lint::{complexity, lint, ComplexityScore, Lint, LintWarning};
+pub use analysis::{satisfiability, Satisfiability};
+pub use coverage::{Coverage, CoverageCell, CoverageError};
+pub use flags::{Flag, FlagDecision, FlagError, FlagReason, Flags};
+pub use incremental::IncrementalEvaluator;
+pub use visit::{ExprFolder, ExprVisitor};
+#[cfg(feature = "std")]
+pub use trace::Trace;
+#[cfg(feature = "std")]
+pub use audit::{AuditRecord, AuditSink};
+pub use builder::ExprBuilder;
+pub use binary::DecodeError;
+pub use arena::{ArenaExpr, ExprArena, ExprId};
+#[cfg(feature = "serde")]
+pub use serialize::{SerializableExpr, SerializableValue};
+#[cfg(feature = "json")]
+pub use json::JsonError;
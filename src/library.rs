@@ -0,0 +1,646 @@
+//! Named rules that reference each other
+//!
+//! A `RuleLibrary` holds predicates defined once by name, such as
+//! `(rule "is-adult" (>= age 18))`, which other rules use as
+//! `(and (ref "is-adult") (= country "US"))`. `RuleLibrary::inline`
+//! replaces every reference with the expression it names, recursively, so
+//! the result evaluates and compiles like any other rule. A predicate
+//! referenced more than once in a rule becomes a repeated subexpression,
+//! which a compiled rule evaluates once per evaluation.
+//!
+//! Definitions may reference rules that are defined later, so a library
+//! can be loaded in any order. A definition that would make a rule
+//! reference itself, directly or through others, is rejected when it is
+//! defined, and a reference to a rule that was never defined is reported
+//! when it is inlined.
+//!
+//! A referenced rule reads the attributes of the environment, never the
+//! locals of a `let` it is referenced under, so inlining one under a `let`
+//! that binds a name it reads is an error rather than a silent change of
+//! meaning.
+//!
+//! `RuleLibrary::dependency_graph` lists the rules and variables each rule
+//! references directly, and exports them in the DOT language for Graphviz
+//! to review how the rules of a large library build on each other.
+
+use crate::compat::{FxHashMap, FxHashSet};
+use crate::{Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Name of the definition form
+pub const RULE: &str = "rule";
+
+/// Name of the function that references a defined rule
+pub const REF: &str = "ref";
+
+/// Reasons a rule cannot be defined or inlined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LibraryError {
+    /// Form is not `(rule "name" expr)`
+    InvalidDefinition,
+    /// `ref` is not called with a single string
+    InvalidReference,
+    /// Referenced rule is not defined
+    UnknownRule(StringId),
+    /// Definitions reference each other in a cycle, listed from the rule
+    /// being defined back to itself
+    Cycle(Vec<StringId>),
+    /// Referenced rule reads a variable that a `let` around the reference
+    /// binds as a local
+    Shadowed { rule: StringId, local: StringId },
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibraryError::InvalidDefinition => f.write_str("expected `(rule \"name\" expr)`"),
+            LibraryError::InvalidReference => f.write_str("expected `(ref \"name\")`"),
+            LibraryError::UnknownRule(name) => write!(f, "unknown rule #{}", name.raw()),
+            LibraryError::Cycle(path) => {
+                f.write_str("rules reference each other in a cycle: ")?;
+                for (i, name) in path.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" -> ")?;
+                    }
+                    write!(f, "#{}", name.raw())?;
+                }
+                Ok(())
+            }
+            LibraryError::Shadowed { rule, local } => write!(
+                f,
+                "rule #{} reads #{}, which is bound by a `let` around the reference",
+                rule.raw(),
+                local.raw()
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LibraryError {}
+
+/// Named rules built with one interner, referenced as `(ref "name")`
+#[derive(Debug, Clone, Default)]
+pub struct RuleLibrary {
+    rules: FxHashMap<StringId, Expr>,
+}
+
+impl RuleLibrary {
+    /// Create an empty library
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a rule, replacing and returning any previous definition of
+    /// the same name
+    ///
+    /// `name` is the ID of the name's text, as a string literal naming the
+    /// rule interns it. Fails if `expr` misuses `ref` or the definition
+    /// would close a cycle, leaving the library unchanged.
+    pub fn define(
+        &mut self,
+        name: StringId,
+        expr: Expr,
+        interner: &StringInterner,
+    ) -> Result<Option<Expr>, LibraryError> {
+        if let Some(reference) = interner.get_id(REF) {
+            let mut path = vec![name];
+            let mut visited = FxHashSet::default();
+            if self.reaches(&expr, name, reference, &mut path, &mut visited)? {
+                return Err(LibraryError::Cycle(path));
+            }
+        }
+        Ok(self.rules.insert(name, expr))
+    }
+
+    /// Define a rule from a parsed `(rule "name" expr)` form, returning its
+    /// name
+    pub fn define_rule(
+        &mut self,
+        form: Expr,
+        interner: &StringInterner,
+    ) -> Result<StringId, LibraryError> {
+        let rule = interner.get_id(RULE);
+        let (name, expr) = match form {
+            Expr::Call { function, args } if Some(function) == rule => {
+                match <[Expr; 2]>::try_from(args) {
+                    Ok([Expr::Literal(Value::String(name)), expr]) => (name, expr),
+                    _ => return Err(LibraryError::InvalidDefinition),
+                }
+            }
+            _ => return Err(LibraryError::InvalidDefinition),
+        };
+        self.define(name, expr, interner)?;
+        Ok(name)
+    }
+
+    /// Remove a rule, returning its definition if it existed
+    ///
+    /// Rules that reference it fail to inline until it is defined again.
+    pub fn remove(&mut self, name: StringId) -> Option<Expr> {
+        self.rules.remove(&name)
+    }
+
+    /// Get the definition of a rule, with its references not inlined
+    pub fn get(&self, name: StringId) -> Option<&Expr> {
+        self.rules.get(&name)
+    }
+
+    /// Get the number of rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Check if no rules are defined
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Iterate over the names of all rules in arbitrary order
+    pub fn names(&self) -> impl Iterator<Item = StringId> + '_ {
+        self.rules.keys().copied()
+    }
+
+    /// Replace every `(ref "name")` in an expression built with `interner`
+    /// with the definition it names, recursively
+    pub fn inline(&self, expr: &Expr, interner: &StringInterner) -> Result<Expr, LibraryError> {
+        let Some(reference) = interner.get_id(REF) else {
+            return Ok(expr.clone());
+        };
+        let mut inliner = Inliner {
+            library: self,
+            reference,
+            expanded: FxHashMap::default(),
+            locals: Vec::new(),
+        };
+        inliner.inline(expr.clone())
+    }
+
+    /// Get the rules and variables each rule references directly, with
+    /// names resolved through `interner`
+    pub fn dependency_graph(&self, interner: &StringInterner) -> DependencyGraph {
+        let text = |name: &StringId| interner.resolve(*name).unwrap_or_default();
+        let reference = interner.get_id(REF);
+        let mut rules: Vec<Dependencies> = self
+            .rules
+            .iter()
+            .map(|(&name, definition)| {
+                // Definitions were checked for malformed references
+                let mut references = reference
+                    .and_then(|reference| self::references(definition, reference).ok())
+                    .unwrap_or_default();
+                let mut seen = FxHashSet::default();
+                references.retain(|name| seen.insert(*name));
+                let mut variables: Vec<StringId> = definition.variables().into_iter().collect();
+                variables.sort_by_key(text);
+                Dependencies {
+                    name,
+                    references,
+                    variables,
+                }
+            })
+            .collect();
+        rules.sort_by_key(|rule| text(&rule.name));
+        let index = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| (rule.name, i))
+            .collect();
+        DependencyGraph { rules, index }
+    }
+
+    /// Check if `expr` references `target` through the library, leaving the
+    /// references followed to reach it on `path`
+    fn reaches(
+        &self,
+        expr: &Expr,
+        target: StringId,
+        reference: StringId,
+        path: &mut Vec<StringId>,
+        visited: &mut FxHashSet<StringId>,
+    ) -> Result<bool, LibraryError> {
+        for name in references(expr, reference)? {
+            path.push(name);
+            if name == target {
+                return Ok(true);
+            }
+            if visited.insert(name) {
+                if let Some(definition) = self.rules.get(&name) {
+                    if self.reaches(definition, target, reference, path, visited)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            path.pop();
+        }
+        Ok(false)
+    }
+}
+
+/// Rules and variables each rule of a library references, returned by
+/// `RuleLibrary::dependency_graph`
+#[derive(Debug, Clone)]
+pub struct DependencyGraph {
+    /// Rules in order of name
+    rules: Vec<Dependencies>,
+    /// Position of each rule in `rules`
+    index: FxHashMap<StringId, usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Dependencies {
+    name: StringId,
+    /// Rules referenced, in order of first reference
+    references: Vec<StringId>,
+    /// Variables read, in order of name
+    variables: Vec<StringId>,
+}
+
+impl DependencyGraph {
+    /// Iterate over the rules, in order of name
+    pub fn rules(&self) -> impl Iterator<Item = StringId> + '_ {
+        self.rules.iter().map(|rule| rule.name)
+    }
+
+    /// Get the rules `name` references directly, in order of first
+    /// reference, including any that are not defined
+    pub fn references(&self, name: StringId) -> &[StringId] {
+        self.get(name).map_or(&[], |rule| &rule.references)
+    }
+
+    /// Get the variables `name` reads directly, in order of name
+    pub fn variables(&self, name: StringId) -> &[StringId] {
+        self.get(name).map_or(&[], |rule| &rule.variables)
+    }
+
+    /// Get the rules that reference `name` directly, in order of name
+    pub fn dependents(&self, name: StringId) -> Vec<StringId> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.references.contains(&name))
+            .map(|rule| rule.name)
+            .collect()
+    }
+
+    /// Render the graph in the DOT language, with names resolved through
+    /// `interner`
+    ///
+    /// Rules are boxes and variables ellipses. Rules referenced but not
+    /// defined are dashed boxes.
+    pub fn to_dot(&self, interner: &StringInterner) -> String {
+        let mut dot = String::from("digraph rules {\n");
+        let mut variables = FxHashSet::default();
+        let mut missing = FxHashSet::default();
+        for rule in &self.rules {
+            node(&mut dot, "rule", rule.name, "shape=box", interner);
+            variables.extend(rule.variables.iter().copied());
+            missing.extend(
+                rule.references
+                    .iter()
+                    .filter(|&name| !self.index.contains_key(name)),
+            );
+        }
+        let text = |name: &StringId| interner.resolve(*name).unwrap_or_default();
+        let mut missing: Vec<StringId> = missing.into_iter().collect();
+        missing.sort_by_key(text);
+        for name in missing {
+            node(&mut dot, "rule", name, "shape=box, style=dashed", interner);
+        }
+        let mut variables: Vec<StringId> = variables.into_iter().collect();
+        variables.sort_by_key(text);
+        for name in variables {
+            node(&mut dot, "var", name, "shape=ellipse", interner);
+        }
+        for rule in &self.rules {
+            for &target in &rule.references {
+                let _ = writeln!(
+                    dot,
+                    "  \"rule:{}\" -> \"rule:{}\";",
+                    escape(text(&rule.name)),
+                    escape(text(&target))
+                );
+            }
+            for &target in &rule.variables {
+                let _ = writeln!(
+                    dot,
+                    "  \"rule:{}\" -> \"var:{}\";",
+                    escape(text(&rule.name)),
+                    escape(text(&target))
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn get(&self, name: StringId) -> Option<&Dependencies> {
+        self.index.get(&name).map(|&i| &self.rules[i])
+    }
+}
+
+/// Write a DOT node statement, with an ID prefixed by `kind` so rules and
+/// variables of the same name stay apart
+fn node(dot: &mut String, kind: &str, name: StringId, attributes: &str, interner: &StringInterner) {
+    let name = escape(interner.resolve(name).unwrap_or_default());
+    let _ = writeln!(dot, "  \"{kind}:{name}\" [label=\"{name}\", {attributes}];");
+}
+
+/// Escape a name for a quoted DOT ID
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// State of one `RuleLibrary::inline`
+struct Inliner<'l> {
+    library: &'l RuleLibrary,
+    /// ID of `ref`
+    reference: StringId,
+    /// Rules already inlined, with their own references inlined
+    expanded: FxHashMap<StringId, Expr>,
+    /// Locals of the enclosing `let`s
+    locals: Vec<StringId>,
+}
+
+impl Inliner<'_> {
+    fn inline(&mut self, expr: Expr) -> Result<Expr, LibraryError> {
+        if let Some(name) = reference(&expr, self.reference) {
+            return self.rule(name?);
+        }
+        Ok(match expr {
+            Expr::Call { function, args } => Expr::Call {
+                function,
+                args: args
+                    .into_iter()
+                    .map(|arg| self.inline(arg))
+                    .collect::<Result<_, _>>()?,
+            },
+            Expr::List(items) => Expr::List(
+                items
+                    .into_iter()
+                    .map(|item| self.inline(item))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata,
+                expr: Box::new(self.inline(*expr)?),
+            },
+            Expr::Let { bindings, body } => {
+                let scope = self.locals.len();
+                let mut inlined = Vec::with_capacity(bindings.len());
+                for (name, value) in bindings {
+                    inlined.push((name, self.inline(value)?));
+                    self.locals.push(name);
+                }
+                let body = self.inline(*body);
+                self.locals.truncate(scope);
+                Expr::Let {
+                    bindings: inlined,
+                    body: Box::new(body?),
+                }
+            }
+            leaf => leaf,
+        })
+    }
+
+    /// Get the inlined definition of a referenced rule
+    fn rule(&mut self, name: StringId) -> Result<Expr, LibraryError> {
+        let expanded = match self.expanded.get(&name) {
+            Some(expanded) => expanded.clone(),
+            None => {
+                let definition = self
+                    .library
+                    .rules
+                    .get(&name)
+                    .ok_or(LibraryError::UnknownRule(name))?;
+                // A definition is inlined outside any `let`
+                let locals = core::mem::take(&mut self.locals);
+                let expanded = self.inline(definition.clone());
+                self.locals = locals;
+                let expanded = expanded?;
+                self.expanded.insert(name, expanded.clone());
+                expanded
+            }
+        };
+        if !self.locals.is_empty() {
+            let variables = expanded.variables();
+            if let Some(&local) = self
+                .locals
+                .iter()
+                .find(|&&local| variables.contains(&local))
+            {
+                return Err(LibraryError::Shadowed { rule: name, local });
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// Get the rule a `(ref "name")` call names, or an error if `expr` is a
+/// malformed call to `ref`
+fn reference(expr: &Expr, reference: StringId) -> Option<Result<StringId, LibraryError>> {
+    match expr {
+        Expr::Call { function, args } if *function == reference => Some(match args.as_slice() {
+            [Expr::Literal(Value::String(name))] => Ok(*name),
+            _ => Err(LibraryError::InvalidReference),
+        }),
+        _ => None,
+    }
+}
+
+/// Get the rules an expression references, in walk order
+fn references(expr: &Expr, reference: StringId) -> Result<Vec<StringId>, LibraryError> {
+    let mut names = Vec::new();
+    let mut error = None;
+    expr.walk(|node| match self::reference(node, reference) {
+        Some(Ok(name)) => names.push(name),
+        Some(Err(e)) => {
+            error.get_or_insert(e);
+        }
+        None => {}
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(names),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile, parse, parse_many, Environment};
+
+    fn library(source: &str, interner: &mut StringInterner) -> RuleLibrary {
+        let forms: Vec<Expr> = parse_many(source, interner)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut library = RuleLibrary::new();
+        for form in forms {
+            library.define_rule(form, interner).unwrap();
+        }
+        library
+    }
+
+    #[test]
+    fn inlines_references() {
+        let mut interner = StringInterner::new();
+        // Defined before the rule it references
+        let library = library(
+            r#"(rule "eligible" (and (ref "is-adult") (in country ["US" "CA"])))
+               (rule "is-adult" (>= age 18))"#,
+            &mut interner,
+        );
+        assert_eq!(library.len(), 2);
+
+        let expr = parse(
+            r#"(or (ref "eligible") (and (ref "is-adult") vip))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let expected = parse(
+            r#"(or (and (>= age 18) (in country ["US" "CA"])) (and (>= age 18) vip))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let inlined = library.inline(&expr, &interner).unwrap();
+        assert_eq!(inlined, expected);
+
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        env.insert(
+            interner.intern("country"),
+            Value::String(interner.intern("CA")),
+        );
+        env.insert(interner.intern("vip"), Value::Bool(false));
+        assert_eq!(
+            compile(&inlined, &interner).eval(&env, &interner),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut interner = StringInterner::new();
+        let mut library = library(
+            r#"(rule "a" (and x (ref "b")))
+               (rule "b" (or y (ref "c")))"#,
+            &mut interner,
+        );
+        let [a, b, c] = ["a", "b", "c"].map(|name| interner.get_id(name).unwrap());
+
+        let closing = parse(r#"(not (ref "a"))"#, &mut interner).unwrap();
+        assert_eq!(
+            library.define(c, closing.clone(), &interner),
+            Err(LibraryError::Cycle(vec![c, a, b, c]))
+        );
+        assert!(library.get(c).is_none());
+        let itself = parse(r#"(and z (ref "a"))"#, &mut interner).unwrap();
+        assert_eq!(
+            library.define(a, itself, &interner),
+            Err(LibraryError::Cycle(vec![a, a]))
+        );
+
+        // Redefining `a` without the reference makes room for `c`
+        let plain = parse("x", &mut interner).unwrap();
+        assert!(library.define(a, plain, &interner).unwrap().is_some());
+        assert_eq!(library.define(c, closing, &interner), Ok(None));
+    }
+
+    #[test]
+    fn errors() {
+        let mut interner = StringInterner::new();
+        let mut library = library(r#"(rule "is-adult" (>= age 18))"#, &mut interner);
+
+        let cases = [
+            (r#"(ref "missing")"#, "missing"),
+            (r#"(and (ref "is-adult") (ref "missing"))"#, "missing"),
+        ];
+        for (source, name) in cases {
+            let expr = parse(source, &mut interner).unwrap();
+            assert_eq!(
+                library.inline(&expr, &interner),
+                Err(LibraryError::UnknownRule(interner.get_id(name).unwrap())),
+                "{}",
+                source
+            );
+        }
+
+        for source in [r#"(ref is-adult)"#, r#"(ref "a" "b")"#] {
+            let expr = parse(source, &mut interner).unwrap();
+            assert_eq!(
+                library.inline(&expr, &interner),
+                Err(LibraryError::InvalidReference),
+                "{}",
+                source
+            );
+        }
+        for source in [r#"(rule is-adult true)"#, r#"(rule "x")"#, "(> age 18)"] {
+            let form = parse(source, &mut interner).unwrap();
+            assert_eq!(
+                library.define_rule(form, &interner),
+                Err(LibraryError::InvalidDefinition),
+                "{}",
+                source
+            );
+        }
+
+        let expr = parse(r#"(let ((age 5)) (ref "is-adult"))"#, &mut interner).unwrap();
+        assert_eq!(
+            library.inline(&expr, &interner),
+            Err(LibraryError::Shadowed {
+                rule: interner.get_id("is-adult").unwrap(),
+                local: interner.get_id("age").unwrap(),
+            })
+        );
+        let expr = parse(r#"(let ((n 5)) (and (ref "is-adult") n))"#, &mut interner).unwrap();
+        assert!(library.inline(&expr, &interner).is_ok());
+    }
+
+    #[test]
+    fn dependency_graph() {
+        let mut interner = StringInterner::new();
+        let library = library(
+            r#"(rule "eligible" (and (ref "is-adult") (ref "is-adult") (in country ["US" "CA"])))
+               (rule "is-adult" (>= age 18))
+               (rule "vip" (or (ref "eligible") (ref "staff") (> spend age)))"#,
+            &mut interner,
+        );
+        let [eligible, adult, vip, staff, age, country, spend] = [
+            "eligible", "is-adult", "vip", "staff", "age", "country", "spend",
+        ]
+        .map(|name| interner.get_id(name).unwrap());
+
+        let graph = library.dependency_graph(&interner);
+        assert_eq!(graph.rules().collect::<Vec<_>>(), [eligible, adult, vip]);
+        assert_eq!(graph.references(eligible), [adult]);
+        assert_eq!(graph.references(vip), [eligible, staff]);
+        assert_eq!(graph.variables(vip), [age, spend]);
+        assert_eq!(graph.variables(eligible), [country]);
+        assert_eq!(graph.dependents(adult), [eligible]);
+        assert!(graph.references(staff).is_empty());
+
+        let dot = graph.to_dot(&interner);
+        for line in [
+            "digraph rules {",
+            r#"  "rule:is-adult" [label="is-adult", shape=box];"#,
+            r#"  "rule:staff" [label="staff", shape=box, style=dashed];"#,
+            r#"  "var:age" [label="age", shape=ellipse];"#,
+            r#"  "rule:eligible" -> "rule:is-adult";"#,
+            r#"  "rule:vip" -> "var:spend";"#,
+            "}",
+        ] {
+            assert!(dot.lines().any(|l| l == line), "{line} in {dot}");
+        }
+        assert_eq!(dot.matches(" -> ").count(), 7);
+    }
+}
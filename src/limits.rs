@@ -0,0 +1,79 @@
+//! Resource limits for untrusted rules
+//!
+//! `EvalLimits` bounds how deep, how long-running and how memory-hungry a
+//! rule may be. The parser enforces `max_depth` and `max_string_len` on
+//! rule source, and evaluation enforces all three through
+//! `EvalOptions::limits`, failing with `LimitExceeded` instead of
+//! overflowing the stack or running without end.
+//!
+//! The tree walker counts every evaluated subexpression as a step and
+//! checks depth as it descends. Compiled expressions count every executed
+//! instruction as a step and check the depth of the whole expression
+//! before running, since constant folding and jumps make the two counts
+//! differ.
+//!
+//! `eval` runs its quoted expression under the same depth and step counts
+//! as the rule calling it, and `max_eval_depth` bounds how many `eval`s
+//! may be nested, since a quoted expression may evaluate itself.
+
+use core::fmt;
+
+/// Bounds on parsing and evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EvalLimits {
+    /// Deepest nesting of calls and lists, counting the root as 1
+    pub max_depth: usize,
+    /// Most steps a single evaluation may take
+    pub max_steps: usize,
+    /// Longest string literal or computed string, in bytes
+    pub max_string_len: usize,
+    /// Deepest nesting of `eval` calls
+    pub max_eval_depth: usize,
+}
+
+impl EvalLimits {
+    /// Limits that never trigger
+    pub const UNLIMITED: EvalLimits = EvalLimits {
+        max_depth: usize::MAX,
+        max_steps: usize::MAX,
+        max_string_len: usize::MAX,
+        max_eval_depth: usize::MAX,
+    };
+}
+
+/// Generous limits that only stop pathological rules: depth 256, one
+/// million steps, 1 MiB strings and 16 nested `eval`s
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 256,
+            max_steps: 1_000_000,
+            max_string_len: 1 << 20,
+            max_eval_depth: 16,
+        }
+    }
+}
+
+/// Which limit was exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// `EvalLimits::max_depth`
+    Depth,
+    /// `EvalLimits::max_steps`
+    Steps,
+    /// `EvalLimits::max_string_len`
+    StringLength,
+    /// `EvalLimits::max_eval_depth`
+    EvalDepth,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Depth => "maximum depth",
+            Limit::Steps => "maximum steps",
+            Limit::StringLength => "maximum string length",
+            Limit::EvalDepth => "maximum eval depth",
+        })
+    }
+}
@@ -0,0 +1,416 @@
+//! Rule linting and complexity scoring
+//!
+//! `lint` looks for rules that evaluate but are likely mistakes, so a rule
+//! editor can warn authors before saving:
+//!
+//! - an `if` test, or an operand of `and`/`or`, that is always true or
+//!   always false, which makes branches or operands dead
+//! - an operand of `and`/`or` repeating an earlier one
+//! - an `and` whose conditions can never all hold, as found by
+//!   `satisfiability`
+//! - literal lists longer than `LARGE_LIST`
+//! - nesting deeper than `DEEP_NESTING`
+//!
+//! Each warning points at the node it is about, which
+//! `SpannedExpr::span_of` turns into a source span. Constant tests are
+//! found with `simplify`, so they share its assumption that boolean
+//! operators are given boolean operands.
+//!
+//! `complexity` measures a rule without evaluating it, to rank rules by
+//! cost or reject ones past a budget.
+
+use crate::analysis::{satisfiability, Satisfiability};
+use crate::compat::FxHashSet;
+use crate::compile::depth;
+use crate::optimize::simplify;
+use crate::{BuiltinFunction, Expr, StringId, StringInterner, Value};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Literal lists longer than this are reported by `lint`
+pub const LARGE_LIST: usize = 1000;
+
+/// Rules nested deeper than this are reported by `lint`
+pub const DEEP_NESTING: usize = 16;
+
+/// Likely mistake found by `lint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// An `if` test or an `and`/`or` operand is always `value`
+    ConstantTest { value: bool },
+    /// An `and`/`or` operand repeats an earlier operand
+    DuplicatePredicate,
+    /// An `and` can never be true
+    Contradiction,
+    /// A literal list has `len` items
+    LargeList { len: usize },
+    /// The rule is nested `depth` levels deep
+    DeepNesting { depth: usize },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::ConstantTest { value } => write!(f, "condition is always {value}"),
+            Lint::DuplicatePredicate => write!(f, "duplicated condition"),
+            Lint::Contradiction => write!(f, "conditions can never all hold"),
+            Lint::LargeList { len } => {
+                write!(f, "list of {len} items, more than {LARGE_LIST}")
+            }
+            Lint::DeepNesting { depth } => {
+                write!(f, "nested {depth} levels deep, more than {DEEP_NESTING}")
+            }
+        }
+    }
+}
+
+/// A `Lint` and the node it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintWarning<'e> {
+    expr: &'e Expr,
+    lint: Lint,
+}
+
+impl<'e> LintWarning<'e> {
+    /// Get the node the warning is about, a reference into the linted
+    /// expression
+    pub fn expr(&self) -> &'e Expr {
+        self.expr
+    }
+
+    /// Get what was found
+    pub fn lint(&self) -> Lint {
+        self.lint
+    }
+}
+
+impl fmt::Display for LintWarning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.lint.fmt(f)
+    }
+}
+
+/// Find likely mistakes in an expression built with `interner`, outermost
+/// first
+pub fn lint<'e>(expr: &'e Expr, interner: &StringInterner) -> Vec<LintWarning<'e>> {
+    let mut linter = Linter {
+        interner,
+        warnings: Vec::new(),
+        nesting: depth(expr),
+        nesting_reported: false,
+    };
+    linter.check(expr, 1, None);
+    linter.warnings
+}
+
+struct Linter<'i, 'e> {
+    interner: &'i StringInterner,
+    warnings: Vec<LintWarning<'e>>,
+    /// Nesting of the whole expression
+    nesting: usize,
+    nesting_reported: bool,
+}
+
+impl<'e> Linter<'_, 'e> {
+    fn warn(&mut self, expr: &'e Expr, lint: Lint) {
+        self.warnings.push(LintWarning { expr, lint });
+    }
+
+    /// Check `expr` and its children, where `parent` is the builtin called
+    /// by the enclosing call
+    fn check(&mut self, expr: &'e Expr, level: usize, parent: Option<BuiltinFunction>) {
+        if level > DEEP_NESTING && !self.nesting_reported {
+            self.nesting_reported = true;
+            let depth = self.nesting;
+            self.warn(expr, Lint::DeepNesting { depth });
+        }
+        match expr {
+            Expr::Literal(value) => {
+                if let Some(len) = list_len(value).filter(|&len| len > LARGE_LIST) {
+                    self.warn(expr, Lint::LargeList { len });
+                }
+            }
+            Expr::Variable(_) => {}
+            Expr::List(items) => {
+                if items.len() > LARGE_LIST {
+                    self.warn(expr, Lint::LargeList { len: items.len() });
+                }
+                for item in items {
+                    self.check(item, level + 1, None);
+                }
+            }
+            Expr::Call { function, args } => {
+                let builtin = self.interner.builtin(*function);
+                match builtin {
+                    Some(BuiltinFunction::If) => self.check_if(args),
+                    // Nested calls were checked as part of the outermost
+                    Some(function @ (BuiltinFunction::And | BuiltinFunction::Or))
+                        if parent != builtin =>
+                    {
+                        self.check_junction(expr, function, args)
+                    }
+                    _ => {}
+                }
+                for arg in args {
+                    self.check(arg, level + 1, builtin);
+                }
+            }
+            Expr::Annotated { expr, .. } => self.check(expr, level, parent),
+            Expr::Let { bindings, body } => {
+                for (_, value) in bindings {
+                    self.check(value, level + 1, None);
+                }
+                self.check(body, level + 1, None);
+            }
+        }
+    }
+
+    fn check_if(&mut self, args: &'e [Expr]) {
+        for clause in args.chunks(2) {
+            if let [test, _] = clause {
+                self.check_constant(test);
+            }
+        }
+    }
+
+    fn check_junction(&mut self, expr: &'e Expr, function: BuiltinFunction, args: &'e [Expr]) {
+        let mut operands = Vec::new();
+        flatten(args, function, self.interner, &mut operands);
+        for (i, operand) in operands.iter().enumerate() {
+            self.check_constant(operand);
+            let operand = operand.unannotated();
+            if operands[..i]
+                .iter()
+                .any(|earlier| earlier.unannotated() == operand)
+            {
+                self.warn(operand, Lint::DuplicatePredicate);
+            }
+        }
+        if function == BuiltinFunction::And {
+            // A single false condition is reported as a constant test
+            if let Satisfiability::Unsatisfiable(conflict) = satisfiability(expr, self.interner) {
+                if conflict.len() > 1 {
+                    self.warn(expr, Lint::Contradiction);
+                }
+            }
+        }
+    }
+
+    /// Warn if `test` is a computed boolean that is always the same
+    fn check_constant(&mut self, test: &'e Expr) {
+        if test.unannotated().is_literal() {
+            return;
+        }
+        if let Expr::Literal(Value::Bool(value)) = simplify(test.clone(), self.interner) {
+            self.warn(test, Lint::ConstantTest { value });
+        }
+    }
+}
+
+/// Collect the operands of nested calls to `function`, which `and` and
+/// `or` treat as one call
+fn flatten<'e>(
+    args: &'e [Expr],
+    function: BuiltinFunction,
+    interner: &StringInterner,
+    operands: &mut Vec<&'e Expr>,
+) {
+    for arg in args {
+        match arg.unannotated() {
+            Expr::Call {
+                function: name,
+                args,
+            } if interner.builtin(*name) == Some(function) => {
+                flatten(args, function, interner, operands)
+            }
+            _ => operands.push(arg),
+        }
+    }
+}
+
+/// Number of items of a literal list
+fn list_len(value: &Value) -> Option<usize> {
+    match value {
+        Value::StringList(items) => Some(items.len()),
+        Value::IntegerList(items) => Some(items.len()),
+        Value::List(items) => Some(items.len()),
+        _ => None,
+    }
+}
+
+/// Static measures of how costly an expression is to evaluate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ComplexityScore {
+    /// Number of nodes
+    pub nodes: usize,
+    /// Nesting, counting calls, lists and `let`s
+    pub depth: usize,
+    /// Number of function calls
+    pub calls: usize,
+    /// Number of points where evaluation can take different paths: each
+    /// `if` test and each `and`/`or` operand after the first
+    pub decisions: usize,
+    /// Number of distinct variables read
+    pub variables: usize,
+    /// Number of items in literal lists
+    pub list_items: usize,
+}
+
+impl ComplexityScore {
+    /// Combine the measures into one number to rank rules by
+    ///
+    /// Every node counts once and every decision twice more, since each
+    /// doubles the paths a reader has to follow. Literal lists count by
+    /// their length divided by 16, since membership tests against them
+    /// are hashed.
+    pub fn total(&self) -> usize {
+        self.nodes + 2 * self.decisions + self.list_items / 16
+    }
+}
+
+/// Measure an expression built with `interner`
+pub fn complexity(expr: &Expr, interner: &StringInterner) -> ComplexityScore {
+    let mut score = ComplexityScore {
+        depth: depth(expr),
+        ..ComplexityScore::default()
+    };
+    let mut variables = FxHashSet::default();
+    measure(expr, interner, &mut score, &mut variables);
+    score.variables = variables.len();
+    score
+}
+
+fn measure(
+    expr: &Expr,
+    interner: &StringInterner,
+    score: &mut ComplexityScore,
+    variables: &mut FxHashSet<StringId>,
+) {
+    score.nodes += 1;
+    match expr {
+        Expr::Literal(value) => score.list_items += list_len(value).unwrap_or(0),
+        Expr::Variable(name) => {
+            variables.insert(*name);
+        }
+        Expr::List(items) => {
+            score.list_items += items.len();
+            for item in items {
+                measure(item, interner, score, variables);
+            }
+        }
+        Expr::Call { function, args } => {
+            score.calls += 1;
+            score.decisions += match interner.builtin(*function) {
+                Some(BuiltinFunction::If) => args.len() / 2,
+                Some(BuiltinFunction::And | BuiltinFunction::Or) => args.len().saturating_sub(1),
+                _ => 0,
+            };
+            for arg in args {
+                measure(arg, interner, score, variables);
+            }
+        }
+        Expr::Annotated { expr, .. } => {
+            score.nodes -= 1;
+            measure(expr, interner, score, variables);
+        }
+        Expr::Let { bindings, body } => {
+            for (_, value) in bindings {
+                measure(value, interner, score, variables);
+            }
+            measure(body, interner, score, variables);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, parse_spanned, Span};
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn lints(source: &str) -> Vec<Lint> {
+        let mut interner = StringInterner::new();
+        let expr = parse(source, &mut interner).unwrap();
+        lint(&expr, &interner)
+            .iter()
+            .map(LintWarning::lint)
+            .collect()
+    }
+
+    #[test]
+    fn finds_mistakes() {
+        let cases = [
+            ("(and (> age 18) (= country \"US\"))", vec![]),
+            (
+                "(if (> 2 1) \"a\" \"b\")",
+                vec![Lint::ConstantTest { value: true }],
+            ),
+            (
+                "(or (= x 1) (and y false))",
+                vec![Lint::ConstantTest { value: false }],
+            ),
+            (
+                "(and (> age 18) (or a b) (> age 18))",
+                vec![Lint::DuplicatePredicate],
+            ),
+            ("(and (= x 1) (and y (= x 2)))", vec![Lint::Contradiction]),
+            ("(and (in x [1 2]) (= x 2.0))", vec![]),
+            ("(and (in x [1 2]) (in x [3 4]))", vec![Lint::Contradiction]),
+            ("(and (= x 1) (= y 2))", vec![]),
+            ("(and p (not p))", vec![Lint::Contradiction]),
+            ("(and z (and (= x 1) (= x 2)))", vec![Lint::Contradiction]),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(lints(source), expected, "{source}");
+        }
+
+        let items: Vec<_> = (0..=LARGE_LIST).map(|n| format!("{n}")).collect();
+        let source = format!("(in x [{}])", items.join(" "));
+        assert_eq!(
+            lints(&source),
+            [Lint::LargeList {
+                len: LARGE_LIST + 1
+            }]
+        );
+
+        let source = format!("{}x{}", "(not ".repeat(20), ")".repeat(20));
+        assert_eq!(lints(&source), [Lint::DeepNesting { depth: 21 }]);
+    }
+
+    #[test]
+    fn warnings_locate_nodes() {
+        let mut interner = StringInterner::new();
+        let source = "(and (> age 18) (> age 18))";
+        let spanned = parse_spanned(source, &mut interner).unwrap();
+        let warnings = lint(spanned.expr(), &interner);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "duplicated condition");
+        let span = spanned.span_of(warnings[0].expr()).unwrap();
+        assert_eq!(span, Span::from_source(source, 16, 26));
+    }
+
+    #[test]
+    fn scores_complexity() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            "(and (> age 18) (in country [\"US\" \"CA\"]) (> age 21))",
+            &mut interner,
+        )
+        .unwrap();
+        let score = complexity(&expr, &interner);
+        assert_eq!(
+            score,
+            ComplexityScore {
+                nodes: 12,
+                depth: 4,
+                calls: 4,
+                decisions: 2,
+                variables: 2,
+                list_items: 2,
+            }
+        );
+        assert_eq!(score.total(), 16);
+    }
+}
@@ -0,0 +1,397 @@
+//! Lists of integers and strings that store short lists inline
+//!
+//! Most lists in contexts are short, such as a user's few roles or
+//! segments, yet each used to take an allocation of its own, and another
+//! every time a context or result holding it was cloned.
+//! `Value::IntegerList` and `Value::StringList` hold a `SmallList`, which
+//! keeps up to `INLINE_LEN` elements in place and only allocates for longer
+//! lists. This makes `Value` no larger, since a decimal already takes the
+//! room of four integers.
+//!
+//! A `SmallList` dereferences to a slice, and converts from and into a
+//! `Vec`, so lists are read and built much as before. Run
+//! `cargo bench --bench values` to count the allocations saved.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut};
+
+/// Most elements a list stores inline
+pub const INLINE_LEN: usize = 4;
+
+/// List of `Copy` elements that stores up to `INLINE_LEN` of them inline
+#[derive(Clone)]
+pub struct SmallList<T>(Repr<T>);
+
+#[derive(Clone)]
+enum Repr<T> {
+    /// The first `len` items, where `len` is at least 1. The rest are
+    /// copies of the first, so no default element is needed
+    Inline { len: u8, items: [T; INLINE_LEN] },
+    /// Lists too long to store inline, and the empty list, which a `Vec`
+    /// holds without allocating
+    Heap(Vec<T>),
+}
+
+impl<T: Copy> SmallList<T> {
+    /// Create an empty list
+    pub const fn new() -> Self {
+        Self(Repr::Heap(Vec::new()))
+    }
+
+    /// Check if the elements are stored inline rather than allocated
+    pub fn is_inline(&self) -> bool {
+        match &self.0 {
+            Repr::Inline { .. } => true,
+            Repr::Heap(items) => items.capacity() == 0,
+        }
+    }
+
+    /// Get the elements as a slice
+    pub fn as_slice(&self) -> &[T] {
+        match &self.0 {
+            Repr::Inline { len, items } => &items[..usize::from(*len)],
+            Repr::Heap(items) => items,
+        }
+    }
+
+    /// Get the elements as a mutable slice
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        match &mut self.0 {
+            Repr::Inline { len, items } => &mut items[..usize::from(*len)],
+            Repr::Heap(items) => items,
+        }
+    }
+
+    /// Append an element, moving the list to the heap once it no longer
+    /// fits inline
+    pub fn push(&mut self, item: T) {
+        match &mut self.0 {
+            Repr::Inline { len, items } if usize::from(*len) < INLINE_LEN => {
+                items[usize::from(*len)] = item;
+                *len += 1;
+            }
+            Repr::Inline { items, .. } => {
+                let mut spilled = Vec::with_capacity(INLINE_LEN * 2);
+                spilled.extend_from_slice(items);
+                spilled.push(item);
+                self.0 = Repr::Heap(spilled);
+            }
+            Repr::Heap(items) if items.capacity() == 0 => {
+                self.0 = Repr::Inline {
+                    len: 1,
+                    items: [item; INLINE_LEN],
+                };
+            }
+            Repr::Heap(items) => items.push(item),
+        }
+    }
+
+    /// Shorten the list to its first `len` elements
+    pub fn truncate(&mut self, len: usize) {
+        match &mut self.0 {
+            Repr::Inline { .. } if len == 0 => self.0 = Repr::Heap(Vec::new()),
+            Repr::Inline { len: inline, .. } => {
+                if len < usize::from(*inline) {
+                    *inline = len as u8;
+                }
+            }
+            Repr::Heap(items) => items.truncate(len),
+        }
+    }
+
+    /// Convert the list into a `Vec`
+    pub fn into_vec(self) -> Vec<T> {
+        match self.0 {
+            Repr::Inline { len, items } => items[..usize::from(len)].to_vec(),
+            Repr::Heap(items) => items,
+        }
+    }
+}
+
+impl<T: Copy + PartialEq> SmallList<T> {
+    /// Remove consecutive repeated elements
+    pub fn dedup(&mut self) {
+        let mut kept = 0;
+        let items = self.as_mut_slice();
+        for i in 0..items.len() {
+            if kept == 0 || items[i] != items[kept - 1] {
+                items[kept] = items[i];
+                kept += 1;
+            }
+        }
+        self.truncate(kept);
+    }
+}
+
+impl<T: Copy> Default for SmallList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> Deref for SmallList<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Copy> DerefMut for SmallList<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SmallList<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq for SmallList<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + Eq> Eq for SmallList<T> {}
+
+impl<T: Copy + PartialEq> PartialEq<Vec<T>> for SmallList<T> {
+    fn eq(&self, other: &Vec<T>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq<[T]> for SmallList<T> {
+    fn eq(&self, other: &[T]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl<T: Copy + PartialEq, const N: usize> PartialEq<[T; N]> for SmallList<T> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+/// Hashes like the slice of its elements, and so like a `Vec` of them
+impl<T: Copy + Hash> Hash for SmallList<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state);
+    }
+}
+
+impl<T: Copy> From<Vec<T>> for SmallList<T> {
+    fn from(items: Vec<T>) -> Self {
+        match items.as_slice() {
+            [first, ..] if items.len() <= INLINE_LEN => Self::from_slice(*first, &items),
+            _ => Self(Repr::Heap(items)),
+        }
+    }
+}
+
+impl<T: Copy> From<&[T]> for SmallList<T> {
+    fn from(items: &[T]) -> Self {
+        match items {
+            [] => Self::new(),
+            [first, ..] if items.len() <= INLINE_LEN => Self::from_slice(*first, items),
+            _ => Self(Repr::Heap(items.to_vec())),
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> From<[T; N]> for SmallList<T> {
+    fn from(items: [T; N]) -> Self {
+        Self::from(&items[..])
+    }
+}
+
+impl<T: Copy> From<SmallList<T>> for Vec<T> {
+    fn from(list: SmallList<T>) -> Self {
+        list.into_vec()
+    }
+}
+
+impl<T: Copy> SmallList<T> {
+    /// Store a short, non-empty slice inline
+    fn from_slice(first: T, items: &[T]) -> Self {
+        let mut inline = [first; INLINE_LEN];
+        inline[..items.len()].copy_from_slice(items);
+        Self(Repr::Inline {
+            len: items.len() as u8,
+            items: inline,
+        })
+    }
+}
+
+impl<T: Copy> FromIterator<T> for SmallList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut list = Self::new();
+        list.extend(iter);
+        list
+    }
+}
+
+impl<T: Copy> Extend<T> for SmallList<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        if let Repr::Heap(items) = &mut self.0 {
+            if items.capacity() > 0 || iter.size_hint().0 > INLINE_LEN {
+                items.extend(iter);
+                return;
+            }
+        }
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for &'a SmallList<T> {
+    type Item = &'a T;
+    type IntoIter = core::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.as_slice().iter()
+    }
+}
+
+impl<T: Copy> IntoIterator for SmallList<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            list: self,
+            next: 0,
+        }
+    }
+}
+
+/// Iterator over the elements of a `SmallList` it owns
+#[derive(Debug, Clone)]
+pub struct IntoIter<T: Copy> {
+    list: SmallList<T>,
+    next: usize,
+}
+
+impl<T: Copy> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let item = *self.list.get(self.next)?;
+        self.next += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.list.len() - self.next;
+        (left, Some(left))
+    }
+}
+
+impl<T: Copy> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.next == self.list.len() {
+            return None;
+        }
+        let last = self.list.len() - 1;
+        let item = self.list[last];
+        self.list.truncate(last);
+        Some(item)
+    }
+}
+
+impl<T: Copy> ExactSizeIterator for IntoIter<T> {}
+
+#[cfg(feature = "serde")]
+impl<T: Copy + serde::Serialize> serde::Serialize for SmallList<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_slice().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Copy + serde::Deserialize<'de>> serde::Deserialize<'de> for SmallList<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, T: Copy + arbitrary::Arbitrary<'a>> arbitrary::Arbitrary<'a> for SmallList<T> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Vec::arbitrary(u).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn short_lists_are_inline() {
+        let mut list = SmallList::new();
+        assert!(list.is_inline() && list.is_empty());
+        for n in 1..=4 {
+            list.push(n);
+            assert!(list.is_inline());
+        }
+        assert_eq!(list, [1, 2, 3, 4]);
+        list.push(5);
+        assert!(!list.is_inline());
+        assert_eq!(list, vec![1, 2, 3, 4, 5]);
+
+        let short = SmallList::from(vec![3, 1, 2]);
+        assert!(short.is_inline());
+        let mut sorted = short.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted.as_slice(), [1, 2, 3]);
+        assert_eq!(short.into_vec(), [3, 1, 2]);
+        let collected: SmallList<i64> = (0..4).collect();
+        assert!(collected.is_inline());
+        let long: SmallList<i64> = (0..100).collect();
+        assert_eq!(long.len(), 100);
+    }
+
+    #[test]
+    fn edits_and_iteration() {
+        let mut list = SmallList::from([1, 1, 2, 2, 2, 3]);
+        list.dedup();
+        assert_eq!(list, [1, 2, 3]);
+        // Like a `Vec`, a list keeps its allocation when shortened
+        assert!(!list.is_inline());
+        let mut list = SmallList::from([1, 1, 2]);
+        list.dedup();
+        assert_eq!(list, [1, 2]);
+        list.truncate(0);
+        assert!(list.is_empty() && list.is_inline());
+
+        let list = SmallList::from([1, 2, 3]);
+        assert_eq!(
+            list.clone().into_iter().rev().collect::<Vec<_>>(),
+            [3, 2, 1]
+        );
+        assert_eq!((&list).into_iter().sum::<i32>(), 6);
+        let mut iter = list.into_iter();
+        assert_eq!(
+            (iter.next(), iter.next_back(), iter.len()),
+            (Some(1), Some(3), 1)
+        );
+        assert_eq!((iter.next(), iter.next_back()), (Some(2), None));
+
+        // Hashes like the `Vec` it replaces
+        let hash = |value: &dyn Fn(&mut rustc_hash::FxHasher)| {
+            let mut state = rustc_hash::FxHasher::default();
+            value(&mut state);
+            state.finish()
+        };
+        let items = vec![7, 8];
+        let list = SmallList::from(items.clone());
+        assert_eq!(hash(&|s| list.hash(s)), hash(&|s| items.hash(s)));
+    }
+}
@@ -0,0 +1,366 @@
+//! Inverted-index rule matching
+//!
+//! A `Matcher` holds rules like a `RuleSet`, but indexes every conjunction
+//! of a rule's disjunctive normal form (see `optimize::to_dnf`) instead of
+//! a single predicate, so rules that are an `or` of targeting conditions
+//! are indexed too. Each equality predicate of a conjunction,
+//! `(= var literal)` or `(in var [literals])`, puts the conjunction in the
+//! posting list of its variable and each literal. Matching walks the
+//! posting lists of the environment's bindings and counts the hits of each
+//! conjunction; one whose every equality predicate was hit may hold, and
+//! only the rules of such conjunctions are evaluated. Conjunctions without
+//! an equality predicate are always candidates.
+//!
+//! Candidate rules are evaluated whole, so `matches` returns what
+//! evaluating every rule would.
+
+use crate::compat::{FxHashMap, FxHashSet};
+use crate::compile::{compile, CompiledExpr};
+use crate::optimize::to_dnf;
+use crate::ruleset::IndexKey;
+use crate::{Environment, Expr, RuleId, StringId, StringInterner, Value};
+use alloc::vec::Vec;
+
+/// One conjunction of a rule's disjunctive normal form
+#[derive(Debug, Clone)]
+struct Clause {
+    /// Slot of the rule the clause belongs to
+    rule: usize,
+    /// Number of equality predicates that must all be hit
+    required: u32,
+    /// Posting lists holding the clause, for removal
+    postings: Vec<(StringId, IndexKey)>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    id: RuleId,
+    expr: Expr,
+    compiled: CompiledExpr,
+    clauses: Vec<usize>,
+}
+
+/// A collection of rules indexed by the equality predicates of their
+/// conjunctions
+#[derive(Debug, Default)]
+pub struct Matcher {
+    interner: StringInterner,
+    /// Rule slots, `None` for removed rules
+    rules: Vec<Option<Rule>>,
+    slots: FxHashMap<RuleId, usize>,
+    /// Clause slots, `None` for clauses of removed rules
+    clauses: Vec<Option<Clause>>,
+    /// Variable -> literal -> clauses with that equality predicate
+    postings: FxHashMap<StringId, FxHashMap<IndexKey, Vec<usize>>>,
+    /// Clauses with no equality predicate
+    unindexed: Vec<usize>,
+}
+
+impl Matcher {
+    /// Create an empty matcher with its own interner
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty matcher sharing an existing interner
+    pub fn with_interner(interner: StringInterner) -> Self {
+        Self {
+            interner,
+            ..Self::default()
+        }
+    }
+
+    /// Get the interner rules are built with
+    pub fn interner(&self) -> &StringInterner {
+        &self.interner
+    }
+
+    /// Get the interner mutably, e.g. to parse new rules
+    pub fn interner_mut(&mut self) -> &mut StringInterner {
+        &mut self.interner
+    }
+
+    /// Add a rule built with this matcher's interner, replacing any rule
+    /// with the same ID
+    pub fn add_rule(&mut self, id: RuleId, expr: Expr) {
+        self.remove_rule(id);
+
+        let slot = self.rules.len();
+        let mut clauses = Vec::new();
+        for conjunction in to_dnf(&expr, &self.interner) {
+            let index = self.clauses.len();
+            let mut clause = Clause {
+                rule: slot,
+                required: 0,
+                postings: Vec::new(),
+            };
+            for predicate in &conjunction.predicates {
+                let Some((name, values)) = predicate.equality(&self.interner) else {
+                    continue;
+                };
+                let keys: Option<FxHashSet<IndexKey>> = values
+                    .iter()
+                    .map(|value| IndexKey::of(value, &self.interner))
+                    .collect();
+                let Some(keys) = keys else {
+                    continue;
+                };
+                // An empty list is never hit, so the clause never matches,
+                // just as `in` an empty list never holds
+                clause.required += 1;
+                let by_key = self.postings.entry(name).or_default();
+                for key in keys {
+                    by_key.entry(key).or_default().push(index);
+                    clause.postings.push((name, key));
+                }
+            }
+            if clause.required == 0 {
+                self.unindexed.push(index);
+            }
+            self.clauses.push(Some(clause));
+            clauses.push(index);
+        }
+
+        let compiled = compile(&expr, &self.interner);
+        self.rules.push(Some(Rule {
+            id,
+            expr,
+            compiled,
+            clauses,
+        }));
+        self.slots.insert(id, slot);
+    }
+
+    /// Remove a rule, returning its expression if it existed
+    pub fn remove_rule(&mut self, id: RuleId) -> Option<Expr> {
+        let slot = self.slots.remove(&id)?;
+        let rule = self.rules[slot].take()?;
+        for &index in &rule.clauses {
+            let Some(clause) = self.clauses[index].take() else {
+                continue;
+            };
+            if clause.required == 0 {
+                self.unindexed.retain(|&c| c != index);
+            }
+            for (name, key) in clause.postings {
+                if let Some(clauses) = self
+                    .postings
+                    .get_mut(&name)
+                    .and_then(|by_key| by_key.get_mut(&key))
+                {
+                    clauses.retain(|&c| c != index);
+                }
+            }
+        }
+        Some(rule.expr)
+    }
+
+    /// Get the expression of a rule
+    pub fn rule(&self, id: RuleId) -> Option<&Expr> {
+        let slot = *self.slots.get(&id)?;
+        self.rules[slot].as_ref().map(|rule| &rule.expr)
+    }
+
+    /// Get the number of rules
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Check if the matcher is empty
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Return the IDs of all rules that evaluate to `true`, in insertion
+    /// order. Rules that fail to evaluate do not match
+    pub fn matches(&self, env: &Environment) -> Vec<RuleId> {
+        self.candidates(env)
+            .into_iter()
+            .filter_map(|slot| {
+                let rule = self.rules[slot].as_ref()?;
+                let matched = rule.compiled.eval(env, &self.interner) == Ok(Value::Bool(true));
+                matched.then_some(rule.id)
+            })
+            .collect()
+    }
+
+    /// Slots of the rules that may match `env`, in insertion order
+    fn candidates(&self, env: &Environment) -> Vec<usize> {
+        let mut hits: FxHashMap<usize, u32> = FxHashMap::default();
+        for (name, value) in env.iter() {
+            let Some(by_key) = self.postings.get(&name) else {
+                continue;
+            };
+            match IndexKey::of(value, &self.interner) {
+                Some(key) => {
+                    for &index in by_key.get(&key).into_iter().flatten() {
+                        *hits.entry(index).or_default() += 1;
+                    }
+                }
+                None => {
+                    // A clause listing several integers that round to the
+                    // same float is still hit once
+                    let indices: FxHashSet<usize> = by_key
+                        .iter()
+                        .filter(|(key, _)| key.rounds_to(value))
+                        .flat_map(|(_, indices)| indices.iter().copied())
+                        .collect();
+                    for index in indices {
+                        *hits.entry(index).or_default() += 1;
+                    }
+                }
+            }
+        }
+
+        let mut candidates: Vec<usize> = self
+            .unindexed
+            .iter()
+            .chain(hits.iter().filter_map(|(index, &count)| {
+                let required = self.clauses[*index].as_ref()?.required;
+                (count == required).then_some(index)
+            }))
+            .filter_map(|&index| Some(self.clauses[index].as_ref()?.rule))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use alloc::vec;
+
+    const RULES: &[(RuleId, &str)] = &[
+        (1, r#"(and (= country "US") (>= age 21))"#),
+        (
+            2,
+            r#"(or (in country ["CA" "MX"]) (and (= tier "gold") (= country "US")))"#,
+        ),
+        (3, "(>= age 65)"),
+        (4, r#"(not (or (!= country "US") (< age 30)))"#),
+        (5, r#"(and (= tier "gold") (= tier "silver"))"#),
+        (6, r#"(or (= age 30.0) (in tier []))"#),
+        (7, r#"(and (= tier "gold") (in tier ["gold" "gold"]))"#),
+    ];
+
+    fn matcher() -> Matcher {
+        let mut matcher = Matcher::new();
+        for (id, source) in RULES {
+            let expr = parse(source, matcher.interner_mut()).unwrap();
+            matcher.add_rule(*id, expr);
+        }
+        matcher
+    }
+
+    fn env(matcher: &mut Matcher, country: &str, tier: &str, age: i64) -> Environment {
+        let interner = matcher.interner_mut();
+        [
+            (
+                interner.intern("country"),
+                Value::String(interner.intern(country)),
+            ),
+            (
+                interner.intern("tier"),
+                Value::Symbol(interner.intern(tier)),
+            ),
+            (interner.intern("age"), Value::Integer(age)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn matches_every_rule_evaluated() {
+        let mut matcher = matcher();
+        for (country, tier, age) in [
+            ("US", "gold", 30),
+            ("US", "silver", 70),
+            ("CA", "gold", 18),
+            ("DE", "bronze", 40),
+        ] {
+            let env = env(&mut matcher, country, tier, age);
+            let expected: Vec<RuleId> = RULES
+                .iter()
+                .filter(|(id, _)| {
+                    let expr = matcher.rule(*id).unwrap();
+                    compile(expr, matcher.interner()).eval(&env, matcher.interner())
+                        == Ok(Value::Bool(true))
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            assert_eq!(
+                matcher.matches(&env),
+                expected,
+                "{} {} {}",
+                country,
+                tier,
+                age
+            );
+        }
+    }
+
+    #[test]
+    fn large_floats_match_rounded_integers() {
+        let mut matcher = Matcher::new();
+        for (id, source) in [
+            (1, "(= x 9007199254740993)"),
+            (
+                2,
+                "(and (in x [9007199254740992 9007199254740993]) (= y 1))",
+            ),
+            (3, "(= x 9007199254740992.0)"),
+        ] {
+            let expr = parse(source, matcher.interner_mut()).unwrap();
+            matcher.add_rule(id, expr);
+        }
+        let interner = matcher.interner_mut();
+        let env: Environment = [
+            (interner.intern("x"), Value::Float(9007199254740992.0)),
+            (interner.intern("y"), Value::Integer(1)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(matcher.matches(&env), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn evaluates_only_candidates() {
+        let mut matcher = matcher();
+        let env = env(&mut matcher, "DE", "bronze", 40);
+        // Rule 4 is a negation and rule 3 has no equality predicate
+        let candidates: Vec<RuleId> = matcher
+            .candidates(&env)
+            .into_iter()
+            .map(|slot| matcher.rules[slot].as_ref().unwrap().id)
+            .collect();
+        assert_eq!(candidates, vec![3, 4]);
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut matcher = matcher();
+        let env = env(&mut matcher, "MX", "gold", 20);
+        assert_eq!(matcher.matches(&env), vec![2, 7]);
+
+        let expr = parse(r#"(= country "MX")"#, matcher.interner_mut()).unwrap();
+        matcher.add_rule(1, expr);
+        assert_eq!(matcher.len(), RULES.len());
+        assert_eq!(matcher.matches(&env), vec![2, 7, 1]);
+
+        assert!(matcher.remove_rule(2).is_some());
+        assert!(matcher.remove_rule(2).is_none());
+        assert_eq!(matcher.matches(&env), vec![7, 1]);
+        assert!(matcher
+            .postings
+            .values()
+            .flat_map(|by_key| by_key.values())
+            .all(|clauses| {
+                clauses
+                    .iter()
+                    .all(|&index| matcher.clauses[index].is_some())
+            }));
+    }
+}
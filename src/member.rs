@@ -0,0 +1,117 @@
+//! Membership sets for large literal lists
+//!
+//! Compiled expressions test `in`, `not-in`, `one-of`, `none-of`,
+//! `geo-cell-in` and the two-list set builtins against a literal list of
+//! at least `MIN_LEN` integers or strings with a `MemberSet` built at
+//! compile time instead of scanning the list on every evaluation. Integers
+//! are sorted and deduplicated for binary search, and strings go in a hash
+//! set of their interned IDs.
+//! Shorter lists, and lists mixing types, are scanned as before since a
+//! scan of a few elements is as fast as a lookup.
+
+use crate::compat::{self, FxHashSet};
+use crate::{Interner, StringId, Value};
+use alloc::vec::Vec;
+
+/// Shortest list worth building a set for
+pub(crate) const MIN_LEN: usize = 16;
+
+/// Floats at least this large may equal several integers once rounded
+pub(crate) const EXACT_FLOAT_LIMIT: f64 = (1u64 << f64::MANTISSA_DIGITS) as f64;
+
+/// A literal list prepared for fast membership tests
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MemberSet {
+    /// Sorted, deduplicated integers
+    Integers(Vec<i64>),
+    /// Interned strings
+    Strings(FxHashSet<StringId>),
+}
+
+impl MemberSet {
+    /// Build a set for a literal list, if the list is long enough and of a
+    /// single type
+    pub(crate) fn new(list: &Value) -> Option<Self> {
+        match list {
+            Value::IntegerList(ns) if ns.len() >= MIN_LEN => {
+                let mut ns = ns.to_vec();
+                ns.sort_unstable();
+                ns.dedup();
+                Some(MemberSet::Integers(ns))
+            }
+            Value::StringList(ids) if ids.len() >= MIN_LEN => {
+                Some(MemberSet::Strings(ids.iter().copied().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check if `item` is in the set, comparing like `=`
+    pub(crate) fn contains(&self, item: &Value, interner: &dyn Interner) -> bool {
+        match (self, item) {
+            (MemberSet::Integers(ns), Value::Integer(n)) => ns.binary_search(n).is_ok(),
+            (MemberSet::Integers(ns), Value::Float(f)) => {
+                if compat::fract(*f) == 0.0 && f.abs() < EXACT_FLOAT_LIMIT {
+                    ns.binary_search(&(*f as i64)).is_ok()
+                } else {
+                    ns.iter().any(|&n| n as f64 == *f)
+                }
+            }
+            (MemberSet::Integers(ns), Value::Decimal(d)) => {
+                d.to_i64().is_some_and(|n| ns.binary_search(&n).is_ok())
+            }
+            (MemberSet::Strings(ids), Value::Symbol(id) | Value::String(id)) => ids.contains(id),
+            (MemberSet::Strings(ids), Value::Text(s)) => {
+                interner.get_id(s).is_some_and(|id| ids.contains(&id))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StringInterner;
+    use alloc::{format, vec};
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut interner = StringInterner::new();
+        let ns: Vec<i64> = (0..100).map(|n| (n * 7919) % 1000 - 500).collect();
+        let integers = MemberSet::new(&Value::IntegerList(ns.clone().into())).unwrap();
+        for n in -600..600 {
+            assert_eq!(
+                integers.contains(&Value::Integer(n), &interner),
+                ns.contains(&n),
+                "{}",
+                n
+            );
+        }
+        assert!(integers.contains(&Value::Float(ns[3] as f64), &interner));
+        assert!(!integers.contains(&Value::Float(0.5), &interner));
+        assert!(!integers.contains(&Value::Float(f64::NAN), &interner));
+        let decimal = |s: &str| Value::Decimal(s.parse().unwrap());
+        assert!(integers.contains(&decimal(&format!("{}.00", ns[3])), &interner));
+        assert!(!integers.contains(&decimal("0.5"), &interner));
+        assert!(!integers.contains(&Value::String(interner.intern("1")), &interner));
+
+        let ids: Vec<StringId> = (0..20)
+            .map(|n| interner.intern(&format!("tag{}", n)))
+            .collect();
+        let strings = MemberSet::new(&Value::StringList(ids.clone().into())).unwrap();
+        assert!(strings.contains(&Value::Symbol(ids[4]), &interner));
+        assert!(strings.contains(&Value::Text("tag19".into()), &interner));
+        assert!(!strings.contains(&Value::Text("tag20".into()), &interner));
+        assert!(!strings.contains(&Value::Integer(4), &interner));
+
+        assert_eq!(
+            MemberSet::new(&Value::IntegerList(vec![1, 2, 3].into())),
+            None
+        );
+        assert_eq!(
+            MemberSet::new(&Value::List(vec![Value::Integer(1); MIN_LEN])),
+            None
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Evaluation metrics
+//!
+//! An evaluator given a `MetricsSink` with `Evaluator::with_metrics`
+//! reports to it as it runs, so counters and latencies can be exported to
+//! a metrics system such as Prometheus without wrapping the engine:
+//!
+//! - every evaluation, with its duration and result
+//! - every builtin call, with the time spent in the builtin itself once
+//!   its arguments are evaluated; `and`, `or`, `if` and the list
+//!   iterations `any`, `all`, `filter` and `map` only direct evaluation and
+//!   are not timed
+//! - every lookup of a literal pattern, version or block in the
+//!   evaluator's caches, and of a repeated subexpression in a batch
+//! - every `and`/`or`/`any`/`all` that stops early, with how many operands
+//!   or elements it evaluated
+//!
+//! Every method does nothing by default, so a sink implements only what it
+//! records. Without a sink the evaluator reports nothing and never reads
+//! the clock. Without the `std` feature there is no clock and every
+//! duration is zero.
+
+use crate::compat;
+use crate::{BuiltinFunction, EvalError, Value};
+use core::fmt;
+use core::time::Duration;
+
+/// Receives metrics from evaluations
+///
+/// Sinks are shared by every evaluation of an evaluator, including those
+/// of a parallel batch, so they record through atomics or locks.
+pub trait MetricsSink: Send + Sync {
+    /// An evaluation finished after `elapsed`
+    fn evaluation(&self, elapsed: Duration, result: &Result<Value, EvalError>) {
+        let _ = (elapsed, result);
+    }
+
+    /// A builtin ran for `elapsed`
+    fn builtin(&self, function: BuiltinFunction, elapsed: Duration) {
+        let _ = (function, elapsed);
+    }
+
+    /// A value was looked up in `cache`, and found if `hit`
+    fn cache(&self, cache: Cache, hit: bool) {
+        let _ = (cache, hit);
+    }
+
+    /// `and`, `or`, `any` or `all` was decided after evaluating `evaluated`
+    /// operands or elements, skipping the rest
+    fn short_circuit(&self, function: BuiltinFunction, evaluated: usize) {
+        let _ = (function, evaluated);
+    }
+}
+
+impl fmt::Debug for dyn MetricsSink + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsSink")
+    }
+}
+
+/// Cache a lookup reported to `MetricsSink::cache` went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cache {
+    /// Literal `matches-regex` patterns compiled by an evaluator
+    Regex,
+    /// Literal `semver-*` versions and ranges parsed by an evaluator
+    Version,
+    /// Literal `ip-in-cidr` blocks parsed by an evaluator
+    Cidr,
+    /// Values of repeated subexpressions within one evaluation of a
+    /// compiled expression
+    Memo,
+}
+
+/// Sink that records nothing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMetrics;
+
+impl MetricsSink for NoMetrics {}
+
+/// Run `builtin`, reporting how long it took as a call to `function`
+#[inline]
+pub(crate) fn timed<T>(
+    metrics: Option<&dyn MetricsSink>,
+    function: BuiltinFunction,
+    builtin: impl FnOnce() -> T,
+) -> T {
+    match metrics {
+        None => builtin(),
+        Some(metrics) => {
+            let start = compat::now();
+            let result = builtin();
+            metrics.builtin(function, compat::elapsed(start));
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Environment, Evaluator, StringInterner};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counts {
+        evaluations: AtomicUsize,
+        builtins: AtomicUsize,
+        hits: AtomicUsize,
+        misses: AtomicUsize,
+        short_circuits: AtomicUsize,
+        evaluated: AtomicUsize,
+    }
+
+    impl MetricsSink for Counts {
+        fn evaluation(&self, _elapsed: Duration, _result: &Result<Value, EvalError>) {
+            self.evaluations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn builtin(&self, _function: BuiltinFunction, _elapsed: Duration) {
+            self.builtins.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn cache(&self, _cache: Cache, hit: bool) {
+            let count = if hit { &self.hits } else { &self.misses };
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn short_circuit(&self, _function: BuiltinFunction, evaluated: usize) {
+            self.short_circuits.fetch_add(1, Ordering::Relaxed);
+            self.evaluated.fetch_add(evaluated, Ordering::Relaxed);
+        }
+    }
+
+    impl Counts {
+        fn get(count: &AtomicUsize) -> usize {
+            count.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn reports_tree_walking() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (> 2 1) (= (+ 1 1) 3) (ip-in-cidr "10.0.0.1" "10.0.0.0/8"))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let counts = Counts::default();
+        let evaluator = Evaluator::new(&interner).with_metrics(&counts);
+        let env = Environment::new();
+
+        assert_eq!(evaluator.eval(&expr, &env), Ok(Value::Bool(false)));
+        assert_eq!(Counts::get(&counts.evaluations), 1);
+        // `>`, `+` and `=`, and never the skipped `ip-in-cidr`
+        assert_eq!(Counts::get(&counts.builtins), 3);
+        assert_eq!(Counts::get(&counts.short_circuits), 1);
+        assert_eq!(Counts::get(&counts.evaluated), 2);
+
+        let expr = parse(
+            r#"(or (ip-in-cidr "10.0.0.1" "10.0.0.0/8") (ip-in-cidr "10.0.0.2" "10.0.0.0/8"))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let evaluator = Evaluator::new(&interner).with_metrics(&counts);
+        evaluator.eval(&expr, &env).unwrap();
+        evaluator.eval(&expr, &env).unwrap();
+        assert_eq!(Counts::get(&counts.misses), 1);
+        assert_eq!(Counts::get(&counts.hits), 1);
+    }
+
+    #[test]
+    fn reports_batches() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(any xs (> (+ _ x) 5))", &mut interner).unwrap();
+        let (xs, x) = (interner.intern("xs"), interner.intern("x"));
+        let counts = Counts::default();
+        let evaluator = Evaluator::new(&interner).with_metrics(&counts);
+        let envs: alloc::vec::Vec<_> = [-10, 5]
+            .into_iter()
+            .map(|n| {
+                let mut env = Environment::new();
+                env.insert(xs, Value::IntegerList(alloc::vec![1, 2, 3].into()));
+                env.insert(x, Value::Integer(n));
+                env
+            })
+            .collect();
+
+        let results = evaluator.eval_batch(&expr, &envs);
+        assert_eq!(results, [Ok(Value::Bool(false)), Ok(Value::Bool(true))]);
+        assert_eq!(Counts::get(&counts.evaluations), 2);
+        // The second environment is decided by the first element
+        assert_eq!(Counts::get(&counts.short_circuits), 1);
+        assert_eq!(Counts::get(&counts.evaluated), 1);
+        // `+` and `>` for three elements, then for one
+        assert_eq!(Counts::get(&counts.builtins), 8);
+    }
+}
@@ -0,0 +1,194 @@
+//! IP addresses for `ip-in-cidr` and `ip-in-range`
+//!
+//! Addresses are IPv4 or IPv6 strings in standard notation. An IPv6 address
+//! that maps an IPv4 one, such as `::ffff:10.1.2.3`, is treated as the IPv4
+//! address, and addresses never match a block or range of the other
+//! family.
+//!
+//! A CIDR block is an address and a prefix length, e.g. `10.0.0.0/8` or
+//! `2001:db8::/32`. Host bits below the prefix are ignored, and an address
+//! without a prefix is a block of that single address. Literal blocks are
+//! parsed once, when an expression is compiled or the first time an
+//! `Evaluator` reaches them, and cached by their interned string.
+//! `(ip-in-range ip first last)` includes both bounds.
+
+use crate::compat::{FxHashMap, RwLock};
+use crate::eval::EvalError;
+use crate::StringId;
+use alloc::format;
+use core::net::IpAddr;
+
+/// Parse an address, unwrapping IPv4-mapped IPv6 addresses
+pub(crate) fn parse_ip(text: &str) -> Result<IpAddr, EvalError> {
+    text.parse::<IpAddr>()
+        .map(|ip| ip.to_canonical())
+        .map_err(|_| EvalError::InvalidAddress(format!("`{}` is not an IP address", text)))
+}
+
+/// An address as a number, with IPv4 addresses kept apart from IPv6 ones
+fn bits(ip: IpAddr) -> (bool, u128) {
+    match ip {
+        IpAddr::V4(v4) => (false, u32::from(v4).into()),
+        IpAddr::V6(v6) => (true, u128::from(v6)),
+    }
+}
+
+/// Check if `ip` lies between `first` and `last` inclusive
+pub(crate) fn in_range(ip: IpAddr, first: IpAddr, last: IpAddr) -> bool {
+    let (family, ip) = bits(ip);
+    let (first_family, first) = bits(first);
+    let (last_family, last) = bits(last);
+    family == first_family && family == last_family && first <= ip && ip <= last
+}
+
+/// A parsed CIDR block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    v6: bool,
+    network: u128,
+    mask: u128,
+}
+
+impl Cidr {
+    pub(crate) fn parse(text: &str) -> Result<Self, EvalError> {
+        let invalid = || EvalError::InvalidAddress(format!("`{}` is not a CIDR block", text));
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let width = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if prefix.bytes().all(|b| b.is_ascii_digit()) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= width)
+                .ok_or_else(invalid)?,
+            Some(_) => return Err(invalid()),
+            None => width,
+        };
+        // The prefix counts from the top of the address, so shift the mask
+        // into place within its width
+        let mask = u128::MAX
+            .checked_shl(128 - prefix)
+            .unwrap_or(0)
+            .checked_shr(128 - width)
+            .unwrap_or(0);
+        let (v6, network) = bits(address);
+        Ok(Cidr {
+            v6,
+            network: network & mask,
+            mask,
+        })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        let (v6, ip) = bits(ip);
+        v6 == self.v6 && ip & self.mask == self.network
+    }
+}
+
+/// Parsed literal CIDR blocks keyed by their interned string
+#[derive(Debug, Default)]
+pub(crate) struct CidrCache {
+    blocks: RwLock<FxHashMap<StringId, Cidr>>,
+}
+
+impl CidrCache {
+    /// Check if the block for `id` is cached
+    pub(crate) fn contains(&self, id: StringId) -> bool {
+        self.blocks.read().unwrap().contains_key(&id)
+    }
+
+    /// Get the parsed form of an interned block, parsing it on first use
+    pub(crate) fn get_or_parse(&self, id: StringId, text: &str) -> Result<Cidr, EvalError> {
+        if let Some(&cidr) = self.blocks.read().unwrap().get(&id) {
+            return Ok(cidr);
+        }
+        let cidr = Cidr::parse(text)?;
+        self.blocks.write().unwrap().insert(id, cidr);
+        Ok(cidr)
+    }
+}
+
+impl Clone for CidrCache {
+    fn clone(&self) -> Self {
+        Self {
+            blocks: RwLock::new(self.blocks.read().unwrap().clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        parse_ip(text).unwrap()
+    }
+
+    #[test]
+    fn cidr_blocks() {
+        let cases = [
+            (
+                "10.0.0.0/8",
+                &["10.0.0.1", "10.255.255.255", "::ffff:10.1.2.3"][..],
+                &["11.0.0.0", "::a00:1"][..],
+            ),
+            (
+                "192.168.1.77/24",
+                &["192.168.1.0", "192.168.1.255"],
+                &["192.168.2.1"],
+            ),
+            ("0.0.0.0/0", &["8.8.8.8"], &["::1"]),
+            ("203.0.113.9", &["203.0.113.9"], &["203.0.113.8"]),
+            (
+                "2001:db8::/32",
+                &["2001:db8::1", "2001:db8:ffff::"],
+                &["2001:db9::", "10.0.0.1"],
+            ),
+            ("::/0", &["::1", "fe80::1"], &["127.0.0.1"]),
+        ];
+        for (block, inside, outside) in cases {
+            let cidr = Cidr::parse(block).unwrap();
+            for address in inside {
+                assert!(
+                    cidr.contains(ip(address)),
+                    "{} should contain {}",
+                    block,
+                    address
+                );
+            }
+            for address in outside {
+                assert!(
+                    !cidr.contains(ip(address)),
+                    "{} should not contain {}",
+                    block,
+                    address
+                );
+            }
+        }
+
+        for invalid in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0.0/+8",
+            "10.0.0/8",
+            "::/129",
+            "host/8",
+        ] {
+            assert!(Cidr::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let (first, last) = (ip("10.0.0.10"), ip("10.0.1.5"));
+        assert!(in_range(ip("10.0.0.200"), first, last));
+        assert!(in_range(ip("10.0.1.5"), first, last));
+        assert!(!in_range(ip("10.0.1.6"), first, last));
+        assert!(!in_range(ip("::a00:c8"), first, last));
+        assert!(in_range(ip("fe80::5"), ip("fe80::"), ip("fe80::ffff")));
+        assert!(parse_ip("10.0.0.256").is_err());
+    }
+}
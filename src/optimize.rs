@@ -0,0 +1,650 @@
+//! Expression simplification
+//!
+//! `simplify` rewrites an expression into a cheaper equivalent:
+//!
+//! - builtin calls and list literals whose arguments are all literals are
+//!   evaluated, e.g. `(> 3 2)` becomes `true`
+//! - boolean identities are applied: `(and x true)` becomes `x` and
+//!   `(or x true)` becomes `true`
+//! - nested `and`/`or` of the same kind are flattened
+//! - double negation `(not (not x))` is removed
+//!
+//! The rewrites assume boolean operators are given boolean operands. An
+//! operand that would have failed to evaluate may be dropped, so a
+//! simplified rule can succeed where the original reported an error.
+//!
+//! `Expr::partial_eval` substitutes variables known ahead of time, such as
+//! campaign constants, and then simplifies, leaving a residual expression
+//! over the variables that are only known per request.
+//!
+//! `to_dnf` rewrites a rule into disjunctive normal form, a list of
+//! conjunctions of which at least one must hold, so an inverted index can
+//! key each conjunction on its equality predicates. Negations are pushed
+//! down to the predicates with De Morgan's laws, which also hold for null
+//! operands. Distributing `and` over `or` can multiply the number of
+//! conjunctions, so a subexpression whose expansion would exceed
+//! `MAX_CONJUNCTIONS` is kept whole as a single predicate instead.
+
+use crate::eval::{apply, foldable, make_list, EvalOptions};
+use crate::expr::ELEMENT;
+use crate::{BuiltinFunction, Environment, Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Variables known before evaluation, for `Expr::partial_eval`
+#[derive(Debug, Clone)]
+pub struct PartialEnv<'i> {
+    interner: &'i StringInterner,
+    known: Environment,
+}
+
+impl<'i> PartialEnv<'i> {
+    /// Create an environment with nothing known, for expressions built with
+    /// `interner`
+    pub fn new(interner: &'i StringInterner) -> Self {
+        Self::with_env(interner, Environment::new())
+    }
+
+    /// Create an environment knowing every binding in `known`
+    pub fn with_env(interner: &'i StringInterner, known: Environment) -> Self {
+        Self { interner, known }
+    }
+
+    /// Bind a known variable, returning the previous value if any
+    pub fn bind(&mut self, name: StringId, value: Value) -> Option<Value> {
+        self.known.insert(name, value)
+    }
+
+    /// Look up a known variable
+    pub fn get(&self, name: StringId) -> Option<&Value> {
+        self.known.get(name)
+    }
+
+    /// Get the interner the expressions were built with
+    pub fn interner(&self) -> &'i StringInterner {
+        self.interner
+    }
+}
+
+impl Expr {
+    /// Substitute the known variables of `env` and simplify the result
+    ///
+    /// Variables `env` does not know are left in place, so evaluating the
+    /// residual expression against them gives the same result as evaluating
+    /// this one against every variable, with the caveats of `simplify`.
+    pub fn partial_eval(&self, env: &PartialEnv) -> Expr {
+        let bound = substitute(self.clone(), env, &mut Vec::new());
+        simplify(bound, env.interner)
+    }
+}
+
+/// Replace the variables `env` knows with their values, leaving the locals
+/// in `locals`, which shadow them, in place
+fn substitute(expr: Expr, env: &PartialEnv, locals: &mut Vec<StringId>) -> Expr {
+    match expr {
+        Expr::Variable(name) if !locals.contains(&name) => match env.get(name) {
+            Some(value) => Expr::Literal(value.clone()),
+            None => expr,
+        },
+        // Quoted expressions are values, read only when passed to `eval`
+        Expr::Call { function, args }
+            if env.interner.builtin(function) == Some(BuiltinFunction::Quote) =>
+        {
+            Expr::Call { function, args }
+        }
+        Expr::Call { function, args } => {
+            // The second argument of `any`/`all`/`filter`/`map` reads its
+            // element as a local
+            let binds = env
+                .interner
+                .builtin(function)
+                .is_some_and(BuiltinFunction::binds_element);
+            let element = env.interner.get_id(ELEMENT).filter(|_| binds);
+            let args = args
+                .into_iter()
+                .enumerate()
+                .map(|(i, arg)| match element {
+                    Some(element) if i == 1 => {
+                        locals.push(element);
+                        let arg = substitute(arg, env, locals);
+                        locals.pop();
+                        arg
+                    }
+                    _ => substitute(arg, env, locals),
+                })
+                .collect();
+            Expr::Call { function, args }
+        }
+        Expr::List(items) => Expr::List(
+            items
+                .into_iter()
+                .map(|item| substitute(item, env, locals))
+                .collect(),
+        ),
+        Expr::Annotated { metadata, expr } => Expr::Annotated {
+            metadata,
+            expr: Box::new(substitute(*expr, env, locals)),
+        },
+        Expr::Let { bindings, body } => {
+            let scope = locals.len();
+            let bindings = bindings
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = substitute(value, env, locals);
+                    locals.push(name);
+                    (name, value)
+                })
+                .collect();
+            let body = substitute(*body, env, locals);
+            locals.truncate(scope);
+            Expr::Let {
+                bindings,
+                body: Box::new(body),
+            }
+        }
+        other => other,
+    }
+}
+
+/// Simplify an expression built with `interner`
+pub fn simplify(expr: Expr, interner: &StringInterner) -> Expr {
+    match expr {
+        Expr::List(items) => {
+            let items: Vec<Expr> = items
+                .into_iter()
+                .map(|item| simplify(item, interner))
+                .collect();
+            match literals(&items) {
+                Some(values) => Expr::Literal(make_list(&values)),
+                None => Expr::List(items),
+            }
+        }
+        // Quoted expressions are left exactly as written
+        Expr::Call { function, args }
+            if interner.builtin(function) == Some(BuiltinFunction::Quote) =>
+        {
+            Expr::Call { function, args }
+        }
+        Expr::Call { function, args } => {
+            let args: Vec<Expr> = args
+                .into_iter()
+                .map(|arg| simplify(arg, interner))
+                .collect();
+            match interner.builtin(function) {
+                // Leave unknown functions and bad arity for the evaluator to report
+                Some(builtin) if builtin.arity().accepts(args.len()) => {
+                    simplify_call(builtin, function, args, interner)
+                }
+                _ => Expr::Call { function, args },
+            }
+        }
+        Expr::Annotated { metadata, expr } => Expr::Annotated {
+            metadata,
+            expr: Box::new(simplify(*expr, interner)),
+        },
+        Expr::Let { bindings, body } => Expr::Let {
+            bindings: bindings
+                .into_iter()
+                .map(|(name, value)| (name, simplify(value, interner)))
+                .collect(),
+            body: Box::new(simplify(*body, interner)),
+        },
+        other => other,
+    }
+}
+
+fn simplify_call(
+    builtin: BuiltinFunction,
+    function: StringId,
+    mut args: Vec<Expr>,
+    interner: &StringInterner,
+) -> Expr {
+    match builtin {
+        BuiltinFunction::And => simplify_junction(function, args, false),
+        BuiltinFunction::Or => simplify_junction(function, args, true),
+        BuiltinFunction::If => simplify_if(function, args),
+        BuiltinFunction::Not if is_call_to(&args[0], BuiltinFunction::Not, interner) => {
+            match args.pop() {
+                Some(Expr::Call { mut args, .. }) => args.pop().expect("not has one argument"),
+                _ => unreachable!(),
+            }
+        }
+        // Overflow fails here and is left for the runtime overflow policy,
+        // as are distances for the runtime distance formula
+        _ if !foldable(builtin) => Expr::Call { function, args },
+        _ => match literals(&args)
+            .and_then(|values| apply(builtin, &values, interner, &EvalOptions::default()).ok())
+        {
+            // Null results depend on the missing-variable policy at runtime
+            Some(value) if !value.is_null() => Expr::Literal(value),
+            _ => Expr::Call { function, args },
+        },
+    }
+}
+
+/// Simplify `and` (`stop == false`) or `or` (`stop == true`)
+fn simplify_junction(function: StringId, args: Vec<Expr>, stop: bool) -> Expr {
+    let mut kept = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Expr::Literal(Value::Bool(b)) if b == stop => return Expr::Literal(Value::Bool(stop)),
+            // The identity element never changes the result
+            Expr::Literal(Value::Bool(_)) => {}
+            // Operands are already simplified, so nested calls are flat
+            Expr::Call {
+                function: inner,
+                args,
+            } if inner == function => kept.extend(args),
+            other => kept.push(other),
+        }
+    }
+
+    match kept.len() {
+        0 => Expr::Literal(Value::Bool(!stop)),
+        1 => kept.pop().expect("one operand"),
+        _ => Expr::Call {
+            function,
+            args: kept,
+        },
+    }
+}
+
+/// Simplify `if` by dropping clauses whose test is a literal that is not
+/// true, and everything after a literal true test
+fn simplify_if(function: StringId, args: Vec<Expr>) -> Expr {
+    let mut kept = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(first) = args.next() {
+        let Some(value) = args.next() else {
+            kept.push(first);
+            break;
+        };
+        match first {
+            Expr::Literal(Value::Bool(true)) => {
+                kept.push(value);
+                break;
+            }
+            Expr::Literal(Value::Bool(false) | Value::Null) => {}
+            test => kept.extend([test, value]),
+        }
+    }
+
+    match kept.len() {
+        0 => Expr::Literal(Value::Null),
+        1 => kept.pop().expect("one value"),
+        _ => Expr::Call {
+            function,
+            args: kept,
+        },
+    }
+}
+
+fn is_call_to(expr: &Expr, builtin: BuiltinFunction, interner: &StringInterner) -> bool {
+    match expr {
+        Expr::Call { function, .. } => interner.builtin(*function) == Some(builtin),
+        _ => false,
+    }
+}
+
+/// Borrow the values of `exprs` if they are all literals
+fn literals(exprs: &[Expr]) -> Option<Vec<&Value>> {
+    exprs
+        .iter()
+        .map(|expr| match expr {
+            Expr::Literal(value) => Some(value),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Largest number of conjunctions `to_dnf` expands a subexpression into
+pub const MAX_CONJUNCTIONS: usize = 64;
+
+/// A condition in a `Conjunction`, holding when `expr` is true or, if
+/// `negated`, when it is false
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub expr: Expr,
+    pub negated: bool,
+}
+
+impl Predicate {
+    /// Get the variable and the values it must equal one of, if this is a
+    /// `(= var literal)` or `(in var [literals])` predicate that is not
+    /// negated
+    pub fn equality(&self, interner: &StringInterner) -> Option<(StringId, Vec<Value>)> {
+        let Expr::Call { function, args } = &self.expr else {
+            return None;
+        };
+        if self.negated || args.len() != 2 {
+            return None;
+        }
+        match (interner.builtin(*function)?, &args[0], &args[1]) {
+            (BuiltinFunction::Equal, Expr::Variable(name), Expr::Literal(value))
+            | (BuiltinFunction::Equal, Expr::Literal(value), Expr::Variable(name)) => {
+                Some((*name, vec![value.clone()]))
+            }
+            (BuiltinFunction::In, Expr::Variable(name), list) => {
+                let values = match list {
+                    Expr::Literal(Value::StringList(ids)) => {
+                        ids.iter().map(|&id| Value::String(id)).collect()
+                    }
+                    Expr::Literal(Value::IntegerList(ns)) => {
+                        ns.iter().map(|&n| Value::Integer(n)).collect()
+                    }
+                    Expr::Literal(Value::List(items)) => items.clone(),
+                    Expr::List(items) => literals(items)?.into_iter().cloned().collect(),
+                    _ => return None,
+                };
+                Some((*name, values))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Predicates that must all hold
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conjunction {
+    pub predicates: Vec<Predicate>,
+}
+
+impl Conjunction {
+    /// Rebuild this conjunction as an expression
+    pub fn to_expr(&self, interner: &mut StringInterner) -> Expr {
+        let and = interner.intern(BuiltinFunction::And.as_str());
+        let not = interner.intern(BuiltinFunction::Not.as_str());
+        let args = self
+            .predicates
+            .iter()
+            .map(|predicate| {
+                if predicate.negated {
+                    Expr::Call {
+                        function: not,
+                        args: vec![predicate.expr.clone()],
+                    }
+                } else {
+                    predicate.expr.clone()
+                }
+            })
+            .collect();
+        Expr::Call {
+            function: and,
+            args,
+        }
+    }
+}
+
+/// Rewrite an expression built with `interner` into disjunctive normal
+/// form
+///
+/// The expression holds exactly when one of the conjunctions does. An
+/// expression that is always false has no conjunctions, and one that is
+/// always true has a single empty conjunction.
+pub fn to_dnf(expr: &Expr, interner: &StringInterner) -> Vec<Conjunction> {
+    dnf(expr, false, interner)
+}
+
+fn dnf(expr: &Expr, negated: bool, interner: &StringInterner) -> Vec<Conjunction> {
+    let whole = || {
+        vec![Conjunction {
+            predicates: vec![Predicate {
+                expr: expr.clone(),
+                negated,
+            }],
+        }]
+    };
+    let (function, args) = match expr {
+        Expr::Literal(Value::Bool(b)) if *b != negated => return vec![Conjunction::default()],
+        Expr::Literal(Value::Bool(_)) => return Vec::new(),
+        Expr::Call { function, args } => (function, args),
+        Expr::Annotated { expr, .. } => return dnf(expr, negated, interner),
+        _ => return whole(),
+    };
+    let builtin = interner
+        .builtin(*function)
+        .filter(|builtin| builtin.arity().accepts(args.len()));
+    // A negated `and` is an `or` of negations and vice versa
+    let disjunction = match builtin {
+        Some(BuiltinFunction::Not) => return dnf(&args[0], !negated, interner),
+        Some(BuiltinFunction::Or) => !negated,
+        Some(BuiltinFunction::And) => negated,
+        _ => return whole(),
+    };
+
+    let mut result = if disjunction {
+        Vec::new()
+    } else {
+        vec![Conjunction::default()]
+    };
+    for arg in args {
+        let operand = dnf(arg, negated, interner);
+        if disjunction {
+            result.extend(operand);
+        } else {
+            // Distribute: each result conjunction joins each operand one
+            if result.len() * operand.len() > MAX_CONJUNCTIONS {
+                return whole();
+            }
+            result = result
+                .iter()
+                .flat_map(|left| {
+                    operand.iter().map(move |right| Conjunction {
+                        predicates: [&left.predicates[..], &right.predicates[..]].concat(),
+                    })
+                })
+                .collect();
+        }
+        if result.len() > MAX_CONJUNCTIONS {
+            return whole();
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Environment, Evaluator};
+
+    fn simplified(source: &str, interner: &mut StringInterner) -> Expr {
+        let expr = parse(source, interner).unwrap();
+        simplify(expr, interner)
+    }
+
+    #[test]
+    fn rewrites() {
+        let mut interner = StringInterner::new();
+        let cases = [
+            ("(> 3 2)", "true"),
+            ("(and x true)", "x"),
+            ("(or x true)", "true"),
+            ("(and x false y)", "false"),
+            ("(or false x (or y z))", "(or x y z)"),
+            ("(and (> 3 2) (not (not x)))", "x"),
+            ("(not (= 1 2))", "true"),
+            ("(in 2 [1 2 3])", "true"),
+            ("(in x [1 2 3])", "(in x [1 2 3])"),
+            ("(and)", "true"),
+            ("(= null 1)", "(= null 1)"),
+            ("(if (> 3 2) x y)", "x"),
+            ("(if false x null y z w v)", "(if z w v)"),
+            ("(if x 1 true 2 3)", "(if x 1 2)"),
+            ("(if false x)", "null"),
+        ];
+
+        for (source, expected) in cases {
+            let expected = parse(expected, &mut interner).unwrap();
+            let expected = match expected {
+                // List literals parse as Expr::List but fold to a literal value
+                Expr::Call { function, args } => Expr::Call {
+                    function,
+                    args: args
+                        .into_iter()
+                        .map(|arg| simplify(arg, &interner))
+                        .collect(),
+                },
+                other => other,
+            };
+            assert_eq!(simplified(source, &mut interner), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn leaves_errors_for_the_evaluator() {
+        let mut interner = StringInterner::new();
+        let expr = simplified(r#"(frob (< "a" 1))"#, &mut interner);
+        assert!(matches!(&expr, Expr::Call { args, .. } if args[0].is_call()));
+    }
+
+    #[test]
+    fn preserves_results() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(40));
+        env.insert(interner.intern("vip"), Value::Bool(false));
+
+        let sources = [
+            "(and (>= age 18) (or vip (> 2 1)))",
+            "(or (and vip true) (not (not (< age 50))))",
+            "(and (in age [30 40]) (not (= 1 1)))",
+        ];
+        for source in sources {
+            let expr = parse(source, &mut interner).unwrap();
+            let evaluator = Evaluator::new(&interner);
+            let expected = evaluator.eval(&expr, &env);
+            let simplified = simplify(expr, &interner);
+            assert_eq!(evaluator.eval(&simplified, &env), expected, "{}", source);
+        }
+    }
+    #[test]
+    fn partial_evaluation() {
+        let mut interner = StringInterner::new();
+        let source =
+            r#"(and (= campaign "spring") (> budget 50) (or (>= age 18) (in region regions)))"#;
+        let expr = parse(source, &mut interner).unwrap();
+
+        let mut known = Environment::new();
+        known.insert(
+            interner.intern("campaign"),
+            Value::String(interner.intern("spring")),
+        );
+        known.insert(interner.intern("budget"), Value::Integer(100));
+        known.insert(
+            interner.intern("regions"),
+            Value::StringList(vec![interner.intern("eu"), interner.intern("us")].into()),
+        );
+        let residual = expr.partial_eval(&PartialEnv::with_env(&interner, known.clone()));
+        let expected = simplified(r#"(or (>= age 18) (in region ["eu" "us"]))"#, &mut interner);
+        assert_eq!(residual, expected);
+
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(16));
+        env.insert(
+            interner.intern("region"),
+            Value::Symbol(interner.intern("eu")),
+        );
+        let mut full = known;
+        let evaluator = Evaluator::new(&interner);
+        full.extend(env.iter().map(|(name, value)| (name, value.clone())));
+        assert_eq!(
+            evaluator.eval(&residual, &env),
+            evaluator.eval(&expr, &full)
+        );
+
+        // A known value that decides the rule leaves only a literal
+        let mut partial = PartialEnv::new(&interner);
+        partial.bind(interner.get_id("budget").unwrap(), Value::Integer(10));
+        assert_eq!(
+            expr.partial_eval(&partial),
+            Expr::Literal(Value::Bool(false))
+        );
+        assert_eq!(
+            expr.partial_eval(&PartialEnv::new(&interner)),
+            simplify(expr.clone(), &interner)
+        );
+
+        // A local shadows a known value of the same name
+        let expr = parse(
+            "(let ((budget (* budget 2))) (> budget 150))",
+            &mut interner,
+        )
+        .unwrap();
+        let expected = simplified("(let ((budget 200)) (> budget 150))", &mut interner);
+        let mut partial = PartialEnv::new(&interner);
+        partial.bind(interner.get_id("budget").unwrap(), Value::Integer(100));
+        assert_eq!(expr.partial_eval(&partial), expected);
+    }
+    #[test]
+    fn disjunctive_normal_form() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (or (= a 1) (= 2 a)) (in b ["x" "y"]) (not (or c (not d))))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let dnf = to_dnf(&expr, &interner);
+        assert_eq!(dnf.len(), 2);
+        let predicates = &dnf[1].predicates;
+        assert_eq!(predicates.len(), 4);
+        let (a, b) = (interner.get_id("a").unwrap(), interner.get_id("b").unwrap());
+        assert_eq!(
+            predicates[0].equality(&interner),
+            Some((a, vec![Value::Integer(2)]))
+        );
+        let (name, values) = predicates[1].equality(&interner).unwrap();
+        assert_eq!((name, values.len()), (b, 2));
+        assert!(predicates[2].negated && predicates[2].equality(&interner).is_none());
+        assert!(!predicates[3].negated);
+
+        assert_eq!(
+            to_dnf(&parse("(or)", &mut interner).unwrap(), &interner),
+            vec![]
+        );
+        assert_eq!(
+            to_dnf(&parse("(not false)", &mut interner).unwrap(), &interner),
+            vec![Conjunction::default()]
+        );
+    }
+
+    #[test]
+    fn dnf_preserves_results() {
+        let mut interner = StringInterner::new();
+        let names = ["a", "b", "c", "d", "e", "f", "g"];
+        let sources = [
+            "(and (or a b) (or c d) (not (and e (or f g))))",
+            "(not (or a (and b c) (not (or d e))))",
+            // Expands to 128 conjunctions, so part is kept whole
+            "(and (or a b) (or b c) (or c d) (or d e) (or e f) (or f g) (or g a))",
+        ];
+        let or = interner.intern("or");
+        for source in sources {
+            let expr = parse(source, &mut interner).unwrap();
+            let dnf = to_dnf(&expr, &interner);
+            assert!(dnf.len() <= MAX_CONJUNCTIONS, "{}", source);
+            let rebuilt = Expr::Call {
+                function: or,
+                args: dnf.iter().map(|c| c.to_expr(&mut interner)).collect(),
+            };
+            let evaluator = Evaluator::new(&interner);
+            for bits in 0..1u32 << names.len() {
+                let env: Environment = names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| {
+                        let id = interner.get_id(name).unwrap();
+                        (id, Value::Bool(bits & (1 << i) != 0))
+                    })
+                    .collect();
+                assert_eq!(
+                    evaluator.eval(&rebuilt, &env),
+                    evaluator.eval(&expr, &env),
+                    "{} with {:b}",
+                    source,
+                    bits
+                );
+            }
+        }
+    }
+}
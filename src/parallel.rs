@@ -0,0 +1,90 @@
+//! Parallel evaluation with rayon
+//!
+//! Available with the `rayon` feature. `Evaluator::eval_batch_parallel`
+//! spreads one rule over many environments and `RuleSet::matches_parallel`
+//! spreads many rules over one environment, both on rayon's global thread
+//! pool. Evaluators, interners, rule sets and compiled expressions are
+//! `Sync`, so every thread reads the same compiled code and interner;
+//! custom functions already have to be `Send + Sync` to be registered.
+//!
+//! Results are in the same order as the sequential versions. Splitting
+//! work has a cost, so small batches are usually faster sequentially.
+
+use crate::{Environment, EvalError, Evaluator, Expr, RuleId, RuleSet, Value};
+use rayon::prelude::*;
+
+impl Evaluator<'_> {
+    /// Evaluate one expression against many environments in parallel
+    ///
+    /// Like `eval_batch`, the expression is compiled once and the cancel
+    /// token is checked before each environment.
+    pub fn eval_batch_parallel(
+        &self,
+        expr: &Expr,
+        envs: &[Environment],
+    ) -> Vec<Result<Value, EvalError>> {
+        let compiled = self.compile_batch(expr);
+        envs.par_iter()
+            .map(|env| self.eval_compiled(&compiled, env))
+            .collect()
+    }
+}
+
+impl RuleSet {
+    /// Return the IDs of all rules that evaluate to `true`, evaluating
+    /// candidate rules in parallel
+    ///
+    /// Matches `matches`, including the insertion order of the result.
+    pub fn matches_parallel(&self, env: &Environment) -> Vec<RuleId> {
+        self.candidates(env)
+            .into_par_iter()
+            .filter_map(|slot| self.check(slot, env))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, CompiledExpr, StringInterner};
+
+    #[test]
+    fn shareable() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<StringInterner>();
+        assert_sync::<Evaluator<'static>>();
+        assert_sync::<CompiledExpr>();
+        assert_sync::<RuleSet>();
+    }
+
+    #[test]
+    fn matches_sequential() {
+        let mut interner = StringInterner::new();
+        let age = interner.intern("age");
+        let expr = parse("(and (>= age 18) (< age 65))", &mut interner).unwrap();
+        let envs: Vec<Environment> = (0..1000)
+            .map(|n| [(age, Value::Integer(n % 100))].into_iter().collect())
+            .collect();
+        let evaluator = Evaluator::new(&interner);
+        assert_eq!(
+            evaluator.eval_batch_parallel(&expr, &envs),
+            evaluator.eval_batch(&expr, &envs)
+        );
+
+        let mut rules = RuleSet::new();
+        for id in 0..200 {
+            let source = format!(r#"(or (= tier "gold") (> score {}))"#, id);
+            let expr = parse(&source, rules.interner_mut()).unwrap();
+            rules.add_rule(id, expr);
+        }
+        let mut env = Environment::new();
+        env.insert(rules.interner_mut().intern("score"), Value::Integer(150));
+        env.insert(
+            rules.interner_mut().intern("tier"),
+            Value::String(rules.interner_mut().intern("silver")),
+        );
+        let expected: Vec<RuleId> = (0..150).collect();
+        assert_eq!(rules.matches(&env), expected);
+        assert_eq!(rules.matches_parallel(&env), expected);
+    }
+}
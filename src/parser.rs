@@ -0,0 +1,1207 @@
+//! S-expression parser
+//!
+//! Grammar:
+//!
+//! ```text
+//! expr    = call | list | literal | variable | annotated | let | cond
+//! call    = "(" name expr* ")"
+//! annotated = "(" "meta" ("(" key literal ")")* expr ")"
+//! let     = "(" "let" "(" ("(" name expr ")")* ")" expr ")"
+//! cond    = "(" "cond" ("(" (expr | "else") expr ")")+ ")"
+//! list    = "[" expr* "]"
+//! literal = string | integer | float | decimal | "true" | "false" | "null" | "'" symbol
+//! ```
+//!
+//! A decimal is a number with a `d` suffix, such as `19.99d`, and
+//! evaluates exactly as a `Value::Decimal`.
+//!
+//! A `;` starts a comment that runs to the end of the line. Comments count
+//! as whitespace, so they may appear between any two tokens.
+//!
+//! `(meta (id 42) (author "ann") expr)` attaches metadata to `expr` as an
+//! `Expr::Annotated`, which evaluates exactly like `expr`. Every element
+//! but the last is a `(key value)` entry with a literal value, so `meta`
+//! cannot be used as a function name.
+//!
+//! `(let ((total (* price quantity)) (over (> total 100))) (and over vip))`
+//! binds local variables as an `Expr::Let`, so a subexpression is computed
+//! once and read by name. Each binding may read those before it, and a
+//! local shadows an attribute of the same name. Local names are plain
+//! names rather than dotted paths, and `let` cannot be used as a function
+//! name either.
+//!
+//! `(cond (test1 value1) (test2 value2) (else value3))` is read as the
+//! builtin call `(if test1 value1 test2 value2 value3)`, which prints back
+//! as the `if`. An `else` clause must come last, and without one the
+//! result is null when no test is true.
+//!
+//! `any`, `all`, `filter` and `map` read a first argument `(x list)` as
+//! `list`, naming its element `x`: `(filter (p purchases) (> p 0))` is
+//! read as `(filter purchases (let ((p _)) (> p 0)))` and prints back that
+//! way. A call to a builtin in that position is still a call, but
+//! a call to a custom function must be bound with `let` first.
+//!
+//! Any other bare atom is a variable reference, which may be a dotted
+//! attribute path like `user.device.os` with no empty segments. Calls to
+//! builtins are checked for arity while parsing, and nesting and string
+//! literals are checked against `EvalLimits`.
+//!
+//! `parse_many` reads a file of rules, one expression after another, as an
+//! iterator instead of requiring one string per rule.
+//!
+//! `parse_spanned` also records the source span of every node, for tools
+//! such as linters that report on a node rather than a whole rule.
+
+use crate::context::PATH_SEPARATOR;
+use crate::error::{IronwoodError, Span};
+use crate::eval::make_list;
+use crate::expr::ELEMENT;
+use crate::limits::{EvalLimits, Limit};
+use crate::telemetry;
+use crate::{BuiltinFunction, Decimal, Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Name of the annotation form
+pub const META: &str = "meta";
+
+/// Name of the local binding form
+pub const LET: &str = "let";
+
+/// Name of the multi-way conditional form, read as an `if` call
+pub const COND: &str = "cond";
+
+/// Test of the `cond` clause taken when no other is
+pub const ELSE: &str = "else";
+
+/// Parse a single expression, interning all names and strings
+///
+/// Uses the default `EvalLimits`.
+pub fn parse(source: &str, interner: &mut StringInterner) -> Result<Expr, IronwoodError> {
+    parse_with_limits(source, interner, &EvalLimits::default())
+}
+
+/// Parse a single expression, rejecting rules nested deeper than
+/// `limits.max_depth` or with string literals longer than
+/// `limits.max_string_len`
+pub fn parse_with_limits(
+    source: &str,
+    interner: &mut StringInterner,
+    limits: &EvalLimits,
+) -> Result<Expr, IronwoodError> {
+    let span = telemetry::parse(source.len());
+    let mut parser = Parser::new(source, interner, limits);
+    let result = parser.parse_all();
+    span.result(&result);
+    result
+}
+
+/// Parse a single expression like `parse`, recording the span of every
+/// node
+pub fn parse_spanned(
+    source: &str,
+    interner: &mut StringInterner,
+) -> Result<SpannedExpr, IronwoodError> {
+    parse_spanned_with_limits(source, interner, &EvalLimits::default())
+}
+
+/// Parse a single expression like `parse_with_limits`, recording the span
+/// of every node
+pub fn parse_spanned_with_limits(
+    source: &str,
+    interner: &mut StringInterner,
+    limits: &EvalLimits,
+) -> Result<SpannedExpr, IronwoodError> {
+    let span = telemetry::parse(source.len());
+    let mut parser = Parser::new(source, interner, limits);
+    parser.ranges = Some(Vec::new());
+    let expr = parser.parse_all();
+    span.result(&expr);
+    let expr = expr?;
+    let ranges = parser.ranges.unwrap_or_default();
+    Ok(SpannedExpr {
+        expr,
+        spans: spans_of(source, &ranges),
+    })
+}
+
+/// An expression together with the source span of each of its nodes,
+/// returned by `parse_spanned`
+///
+/// Nodes are the ones `Expr::walk` visits. The expression cannot be
+/// modified in place, since spans are matched to nodes by position in the
+/// tree; take it with `into_expr` to rewrite it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedExpr {
+    expr: Expr,
+    /// Spans of the nodes in `Expr::walk` order
+    spans: Vec<Span>,
+}
+
+impl SpannedExpr {
+    /// Get the expression
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    /// Take the expression, dropping the spans
+    pub fn into_expr(self) -> Expr {
+        self.expr
+    }
+
+    /// Get the span of the whole expression
+    pub fn span(&self) -> Span {
+        self.spans[0]
+    }
+
+    /// Get the span of `node`, which must be a reference into `expr()`
+    /// rather than an equal expression
+    pub fn span_of(&self, node: &Expr) -> Option<Span> {
+        self.iter()
+            .find(|(candidate, _)| core::ptr::eq(*candidate, node))
+            .map(|(_, span)| span)
+    }
+
+    /// Iterate over every node and its span in `Expr::walk` order
+    pub fn iter(&self) -> impl Iterator<Item = (&Expr, Span)> {
+        let mut stack = vec![&self.expr];
+        let nodes = core::iter::from_fn(move || {
+            let node = stack.pop()?;
+            match node {
+                Expr::Call { args: children, .. } | Expr::List(children) => {
+                    stack.extend(children.iter().rev())
+                }
+                Expr::Annotated { expr, .. } => stack.push(expr),
+                Expr::Let { bindings, body } => {
+                    stack.push(body);
+                    stack.extend(bindings.iter().rev().map(|(_, value)| value));
+                }
+                _ => {}
+            }
+            Some(node)
+        });
+        nodes.zip(self.spans.iter().copied())
+    }
+}
+
+/// Parse every expression in `source` in turn, interning all names and
+/// strings
+///
+/// Uses the default `EvalLimits`. See `ParseMany`.
+pub fn parse_many<'s, 'i>(source: &'s str, interner: &'i mut StringInterner) -> ParseMany<'s, 'i> {
+    parse_many_with_limits(source, interner, &EvalLimits::default())
+}
+
+/// Parse every expression in `source` in turn, checking each against
+/// `limits` like `parse_with_limits`
+pub fn parse_many_with_limits<'s, 'i>(
+    source: &'s str,
+    interner: &'i mut StringInterner,
+    limits: &EvalLimits,
+) -> ParseMany<'s, 'i> {
+    ParseMany {
+        parser: Parser::new(source, interner, limits),
+        failed: false,
+    }
+}
+
+/// Iterator over the expressions of a source holding many, returned by
+/// `parse_many`
+///
+/// Expressions are parsed lazily, one per call to `next`. Error spans are
+/// positions in the whole source. After an error the rest of the source
+/// cannot be split into expressions reliably, so iteration stops.
+pub struct ParseMany<'s, 'i> {
+    parser: Parser<'s, 'i>,
+    failed: bool,
+}
+
+impl Iterator for ParseMany<'_, '_> {
+    type Item = Result<Expr, IronwoodError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        self.parser.skip_whitespace();
+        self.parser.peek()?;
+        let expr = self.parser.parse_expr();
+        self.failed = expr.is_err();
+        Some(expr)
+    }
+}
+
+impl core::iter::FusedIterator for ParseMany<'_, '_> {}
+
+struct Parser<'s, 'i> {
+    source: &'s str,
+    pos: usize,
+    interner: &'i mut StringInterner,
+    limits: EvalLimits,
+    /// Nesting of the expression being parsed
+    depth: usize,
+    /// Byte ranges of the nodes parsed so far in prefix order, when
+    /// recording spans
+    ranges: Option<Vec<(usize, usize)>>,
+}
+
+impl<'s, 'i> Parser<'s, 'i> {
+    fn new(source: &'s str, interner: &'i mut StringInterner, limits: &EvalLimits) -> Self {
+        Parser {
+            source,
+            pos: 0,
+            interner,
+            limits: *limits,
+            depth: 0,
+            ranges: None,
+        }
+    }
+
+    /// Parse the whole source as one expression
+    fn parse_all(&mut self) -> Result<Expr, IronwoodError> {
+        let expr = self.parse_expr()?;
+        self.skip_whitespace();
+        if self.pos < self.source.len() {
+            return Err(self.error("unexpected trailing input", self.pos, self.source.len()));
+        }
+        Ok(expr)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, IronwoodError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.depth == self.limits.max_depth {
+            return Err(IronwoodError::LimitExceeded {
+                limit: Limit::Depth,
+                span: Span::from_source(self.source, start, start),
+            });
+        }
+        self.depth += 1;
+        // Reserve the node's range before its children record theirs
+        let node = self.ranges.as_mut().map(|ranges| {
+            ranges.push((start, start));
+            ranges.len() - 1
+        });
+        let expr = self.parse_node(start);
+        if let (Some(ranges), Some(node)) = (&mut self.ranges, node) {
+            ranges[node].1 = self.pos;
+        }
+        self.depth -= 1;
+        expr
+    }
+
+    fn parse_node(&mut self, start: usize) -> Result<Expr, IronwoodError> {
+        match self.peek() {
+            None => Err(self.error("unexpected end of input", start, start)),
+            Some('(') => self.parse_call(),
+            Some('[') => {
+                self.pos += 1;
+                let items = self.parse_until(']', start)?;
+                Ok(Expr::List(items))
+            }
+            Some('"') => self.parse_string(),
+            Some(')') | Some(']') => {
+                Err(self.error("unexpected closing delimiter", start, start + 1))
+            }
+            Some('\'') => {
+                self.pos += 1;
+                let name = self.atom();
+                if name.is_empty() {
+                    return Err(self.error("expected symbol after `'`", start, self.pos));
+                }
+                Ok(Expr::Literal(Value::Symbol(self.interner.intern(name))))
+            }
+            Some(_) => {
+                let atom = self.atom();
+                let expr = self.parse_atom(atom);
+                if expr.is_variable() && atom.split(PATH_SEPARATOR).any(str::is_empty) {
+                    return Err(self.error("empty segment in attribute path", start, self.pos));
+                }
+                Ok(expr)
+            }
+        }
+    }
+
+    fn parse_call(&mut self) -> Result<Expr, IronwoodError> {
+        let start = self.pos;
+        self.pos += 1;
+        self.skip_whitespace();
+
+        let name_start = self.pos;
+        let name = self.atom();
+        if name.is_empty() {
+            return Err(self.error("expected function name", name_start, name_start));
+        }
+        if name == META {
+            return self.parse_meta(start);
+        }
+        if name == LET {
+            return self.parse_let(start);
+        }
+        if name == COND {
+            return self.parse_cond(start);
+        }
+        let function = self.interner.intern(name);
+        let builtin = BuiltinFunction::from_str(name);
+        let args = match builtin {
+            Some(builtin) if builtin.binds_element() => self.parse_element_args(start)?,
+            _ => self.parse_until(')', start)?,
+        };
+
+        if let Some(builtin) = builtin {
+            let expected = builtin.arity();
+            if !expected.accepts(args.len()) {
+                return Err(IronwoodError::Arity {
+                    function: builtin,
+                    expected,
+                    found: args.len(),
+                    span: Span::from_source(self.source, start, self.pos),
+                });
+            }
+        }
+        Ok(Expr::Call { function, args })
+    }
+
+    /// Parse the arguments of `any`, `all`, `filter` or `map`, reading a
+    /// first argument `(x list)` as `list` with the element named `x`
+    ///
+    /// The rest is read as if it were `(let ((x _)) ...)`, spanning the
+    /// binding in place of the `let` and `x` in place of `_`.
+    fn parse_element_args(&mut self, open: usize) -> Result<Vec<Expr>, IronwoodError> {
+        self.skip_whitespace();
+        let binding = self.pos;
+        let Some((name, name_start)) = self.element_name() else {
+            self.pos = binding;
+            return self.parse_until(')', open);
+        };
+        let name_end = self.pos;
+        let list = self.parse_expr()?;
+        self.skip_whitespace();
+        match self.peek() {
+            Some(')') => self.pos += 1,
+            Some(_) => {
+                return Err(self.error(
+                    "expected `)` after element binding",
+                    self.pos,
+                    self.pos + 1,
+                ))
+            }
+            None => return Err(self.error("unclosed delimiter", binding, binding + 1)),
+        }
+        let element = self.interner.intern(ELEMENT);
+        if name == element {
+            let mut args = vec![list];
+            args.extend(self.parse_until(')', open)?);
+            return Ok(args);
+        }
+
+        let node = self.ranges.as_mut().map(|ranges| {
+            ranges.push((binding, self.pos));
+            ranges.push((name_start, name_end));
+            ranges.len() - 2
+        });
+        let mut rest = self.parse_until(')', open)?.into_iter();
+        let mut args = vec![list];
+        if let Some(body) = rest.next() {
+            if let (Some(ranges), Some(node)) = (&mut self.ranges, node) {
+                ranges[node].1 = ranges[node + 2].1;
+            }
+            args.push(Expr::Let {
+                bindings: vec![(name, Expr::Variable(element))],
+                body: Box::new(body),
+            });
+        }
+        args.extend(rest);
+        Ok(args)
+    }
+
+    /// Try to read `(x` opening an element binding, returning the name and
+    /// where it starts, and leaving the position anywhere if it is not one
+    ///
+    /// A call to a builtin or a special form is an argument, not a binding.
+    fn element_name(&mut self) -> Option<(StringId, usize)> {
+        if self.peek() != Some('(') {
+            return None;
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+        let start = self.pos;
+        let name = self.atom();
+        let special =
+            [META, LET, COND].contains(&name) || BuiltinFunction::from_str(name).is_some();
+        if special || name.contains(PATH_SEPARATOR) {
+            return None;
+        }
+        match self.parse_atom(name) {
+            Expr::Variable(local) if self.pos > start => Some((local, start)),
+            _ => None,
+        }
+    }
+
+    /// Parse the entries and expression of a `meta` form after its name
+    fn parse_meta(&mut self, open: usize) -> Result<Expr, IronwoodError> {
+        let mut entries = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let mark = self.pos;
+            match self.metadata_entry() {
+                Some((key, value)) => entries.push((mark, key, value)),
+                None => {
+                    self.pos = mark;
+                    break;
+                }
+            }
+        }
+        // The last element is the expression even if it looks like an entry
+        if self.peek() == Some(')') {
+            if let Some((mark, _, _)) = entries.pop() {
+                self.pos = mark;
+            }
+        }
+
+        let expr = self.parse_expr()?;
+        self.skip_whitespace();
+        match self.peek() {
+            Some(')') => self.pos += 1,
+            Some(_) => {
+                return Err(self.error(
+                    "expected `)` after annotated expression",
+                    self.pos,
+                    self.pos + 1,
+                ))
+            }
+            None => return Err(self.error("unclosed delimiter", open, open + 1)),
+        }
+        let metadata = entries
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect();
+        Ok(Expr::Annotated {
+            metadata,
+            expr: Box::new(expr),
+        })
+    }
+
+    /// Parse the bindings and body of a `let` form after its name
+    fn parse_let(&mut self, open: usize) -> Result<Expr, IronwoodError> {
+        self.skip_whitespace();
+        let list = self.pos;
+        if self.peek() != Some('(') {
+            return Err(self.error("expected `(` before let bindings", list, list + 1));
+        }
+        self.pos += 1;
+        let mut bindings = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let binding = self.pos;
+            match self.peek() {
+                None => return Err(self.error("unclosed delimiter", list, list + 1)),
+                Some(')') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('(') => self.pos += 1,
+                Some(_) => {
+                    return Err(self.error("expected `(name expr)` binding", binding, binding + 1))
+                }
+            }
+            self.skip_whitespace();
+            let name_start = self.pos;
+            let name = self.atom();
+            let local = match self.parse_atom(name) {
+                Expr::Variable(local) if !name.is_empty() && !name.contains(PATH_SEPARATOR) => {
+                    local
+                }
+                _ => return Err(self.error("expected local name", name_start, self.pos)),
+            };
+            let value = self.parse_expr()?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(')') => self.pos += 1,
+                Some(_) => {
+                    return Err(self.error(
+                        "expected `)` after let binding",
+                        self.pos,
+                        self.pos + 1,
+                    ))
+                }
+                None => return Err(self.error("unclosed delimiter", binding, binding + 1)),
+            }
+            bindings.push((local, value));
+        }
+
+        let body = self.parse_expr()?;
+        self.skip_whitespace();
+        match self.peek() {
+            Some(')') => self.pos += 1,
+            Some(_) => {
+                return Err(self.error("expected `)` after let body", self.pos, self.pos + 1))
+            }
+            None => return Err(self.error("unclosed delimiter", open, open + 1)),
+        }
+        Ok(Expr::Let {
+            bindings,
+            body: Box::new(body),
+        })
+    }
+
+    /// Parse the clauses of a `cond` form after its name into the
+    /// equivalent `if` call
+    fn parse_cond(&mut self, open: usize) -> Result<Expr, IronwoodError> {
+        let mut args = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let clause = self.pos;
+            match self.peek() {
+                None => return Err(self.error("unclosed delimiter", open, open + 1)),
+                Some(')') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('(') => self.pos += 1,
+                Some(_) => {
+                    return Err(self.error("expected `(test value)` clause", clause, clause + 1))
+                }
+            }
+            self.skip_whitespace();
+            let test = self.pos;
+            let otherwise = self.atom() == ELSE;
+            if !otherwise {
+                self.pos = test;
+                args.push(self.parse_expr()?);
+            } else if args.is_empty() {
+                // A lone `else` clause is `(if true value)`, spanning the
+                // `else` in place of the test
+                if let Some(ranges) = &mut self.ranges {
+                    ranges.push((test, self.pos));
+                }
+                args.push(Expr::Literal(Value::Bool(true)));
+            }
+            args.push(self.parse_expr()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(')') => self.pos += 1,
+                Some(_) => {
+                    return Err(self.error(
+                        "expected `)` after cond clause",
+                        self.pos,
+                        self.pos + 1,
+                    ))
+                }
+                None => return Err(self.error("unclosed delimiter", clause, clause + 1)),
+            }
+            if otherwise {
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(')') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(_) => {
+                        return Err(self.error(
+                            "expected `else` clause to be last",
+                            self.pos,
+                            self.pos + 1,
+                        ))
+                    }
+                    None => return Err(self.error("unclosed delimiter", open, open + 1)),
+                }
+            }
+        }
+        if args.is_empty() {
+            return Err(self.error("expected at least one cond clause", open, self.pos));
+        }
+        Ok(Expr::Call {
+            function: self.interner.intern(BuiltinFunction::If.as_str()),
+            args,
+        })
+    }
+
+    /// Try to parse a `(key value)` metadata entry, leaving the position
+    /// anywhere if it is not one
+    fn metadata_entry(&mut self) -> Option<(StringId, Value)> {
+        if self.peek() != Some('(') {
+            return None;
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+        let key = self.atom();
+        if key.is_empty() {
+            return None;
+        }
+        let key = self.interner.intern(key);
+        // Metadata values are not nodes of the expression
+        let nodes = self.ranges.as_ref().map(Vec::len);
+        let value = self.parse_expr();
+        if let (Some(ranges), Some(nodes)) = (&mut self.ranges, nodes) {
+            ranges.truncate(nodes);
+        }
+        let value = match value.ok()? {
+            Expr::Literal(value) => value,
+            Expr::List(items) => {
+                let values = items
+                    .iter()
+                    .map(|item| match item {
+                        Expr::Literal(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                make_list(&values)
+            }
+            _ => return None,
+        };
+        self.skip_whitespace();
+        if self.peek() != Some(')') {
+            return None;
+        }
+        self.pos += 1;
+        Some((key, value))
+    }
+
+    /// Parse expressions up to and including the `close` delimiter
+    fn parse_until(&mut self, close: char, open: usize) -> Result<Vec<Expr>, IronwoodError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Err(self.error("unclosed delimiter", open, open + 1)),
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                Some(_) => items.push(self.parse_expr()?),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<Expr, IronwoodError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string", start, self.pos));
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => break,
+                '\\' => {
+                    let escape_start = self.pos - 1;
+                    let escaped = match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        _ => {
+                            let end = self.pos + self.peek().map_or(0, char::len_utf8);
+                            return Err(self.error("invalid escape sequence", escape_start, end));
+                        }
+                    };
+                    self.pos += 1;
+                    text.push(escaped);
+                }
+                c => text.push(c),
+            }
+        }
+        if text.len() > self.limits.max_string_len {
+            return Err(IronwoodError::LimitExceeded {
+                limit: Limit::StringLength,
+                span: Span::from_source(self.source, start, self.pos),
+            });
+        }
+        Ok(Expr::Literal(Value::String(self.interner.intern(&text))))
+    }
+
+    fn parse_atom(&mut self, atom: &str) -> Expr {
+        match atom {
+            "true" => return Expr::Literal(Value::Bool(true)),
+            "false" => return Expr::Literal(Value::Bool(false)),
+            "null" => return Expr::Literal(Value::Null),
+            _ => {}
+        }
+        if looks_numeric(atom) {
+            if let Ok(n) = atom.parse::<i64>() {
+                return Expr::Literal(Value::Integer(n));
+            }
+            if let Ok(f) = atom.parse::<f64>() {
+                return Expr::Literal(Value::Float(f));
+            }
+            if let Some(Ok(d)) = atom.strip_suffix('d').map(str::parse::<Decimal>) {
+                return Expr::Literal(Value::Decimal(d));
+            }
+        }
+        Expr::Variable(self.interner.intern(atom))
+    }
+
+    /// Consume a run of non-delimiter characters
+    fn atom(&mut self) -> &'s str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | '"' | '\'' | ';') {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        &self.source[start..self.pos]
+    }
+
+    /// Skip whitespace and comments
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                let rest = &self.source[self.pos..];
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else if c.is_whitespace() {
+                self.pos += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn error(&self, message: &str, start: usize, end: usize) -> IronwoodError {
+        IronwoodError::Parse {
+            message: message.to_string(),
+            span: Span::from_source(self.source, start, end),
+        }
+    }
+}
+
+/// Build spans for byte ranges, in one pass over `source` while their
+/// starts never decrease
+///
+/// Only the nodes an element binding is read as start before the node
+/// ahead of them, which restarts the count from the beginning.
+fn spans_of(source: &str, ranges: &[(usize, usize)]) -> Vec<Span> {
+    let mut line = 1;
+    let mut col = 1;
+    let mut pos = 0;
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start < pos {
+                (line, col, pos) = (1, 1, 0);
+            }
+            for c in source[pos..start].chars() {
+                if c == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
+            }
+            pos = start;
+            Span {
+                start,
+                end,
+                line,
+                col,
+            }
+        })
+        .collect()
+}
+
+/// Check if an atom should be read as a number rather than a variable
+fn looks_numeric(atom: &str) -> bool {
+    let digits = atom.strip_prefix(['-', '+']).unwrap_or(atom);
+    digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && digits.len() > usize::from(digits.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Arity;
+
+    #[test]
+    fn parse_calls_and_literals() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (= status "active") (>= age 18) (in x [1 2 3]) (< score -0.5) (= kind 'admin))"#,
+            &mut interner,
+        )
+        .unwrap();
+
+        let Expr::Call { function, args } = expr else {
+            panic!("expected call");
+        };
+        assert_eq!(interner.resolve(function), Some("and"));
+        assert_eq!(args.len(), 5);
+
+        let status = interner.get_id("status").unwrap();
+        let active = interner.get_id("active").unwrap();
+        assert_eq!(
+            args[0],
+            Expr::Call {
+                function: interner.get_id("=").unwrap(),
+                args: vec![Expr::Variable(status), Expr::Literal(Value::String(active))],
+            }
+        );
+        assert!(
+            matches!(&args[2], Expr::Call { args, .. } if args[1] == Expr::List(vec![
+                Expr::Literal(Value::Integer(1)),
+                Expr::Literal(Value::Integer(2)),
+                Expr::Literal(Value::Integer(3)),
+            ]))
+        );
+        assert!(
+            matches!(&args[3], Expr::Call { args, .. } if args[1] == Expr::Literal(Value::Float(-0.5)))
+        );
+        let admin = interner.get_id("admin").unwrap();
+        assert!(
+            matches!(&args[4], Expr::Call { args, .. } if args[1] == Expr::Literal(Value::Symbol(admin)))
+        );
+    }
+
+    #[test]
+    fn parse_atoms() {
+        let mut interner = StringInterner::new();
+        assert_eq!(
+            parse("true", &mut interner),
+            Ok(Expr::Literal(Value::Bool(true)))
+        );
+        assert_eq!(
+            parse(" 42 ", &mut interner),
+            Ok(Expr::Literal(Value::Integer(42)))
+        );
+        assert_eq!(
+            parse("1e3", &mut interner),
+            Ok(Expr::Literal(Value::Float(1000.0)))
+        );
+        assert_eq!(
+            parse(r#""a \"b\"""#, &mut interner),
+            Ok(Expr::Literal(Value::String(interner.intern("a \"b\""))))
+        );
+        assert!(parse("null?", &mut interner).unwrap().is_variable());
+        assert!(parse("-", &mut interner).unwrap().is_variable());
+        assert!(parse("user.device.os", &mut interner)
+            .unwrap()
+            .is_variable());
+        for path in ["user..os", "user.", ".user"] {
+            assert!(parse(path, &mut interner).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn parse_errors_carry_spans() {
+        let mut interner = StringInterner::new();
+
+        let err = parse("(and\n  (= x 1)\n  (> y", &mut interner).unwrap_err();
+        assert_eq!(err.span().line, 3);
+        assert_eq!(err.span().col, 3);
+
+        let err = parse("(= x 1) extra", &mut interner).unwrap_err();
+        assert_eq!(err.span().start, 8);
+
+        let source = "(or (not a b) c)";
+        let err = parse(source, &mut interner).unwrap_err();
+        assert_eq!(
+            err,
+            IronwoodError::Arity {
+                function: BuiltinFunction::Not,
+                expected: Arity::Exact(1),
+                found: 2,
+                span: Span::from_source(source, 4, 13),
+            }
+        );
+
+        assert!(matches!(
+            parse(r#""abc"#, &mut interner),
+            Err(IronwoodError::Parse { .. })
+        ));
+        assert!(matches!(
+            parse("()", &mut interner),
+            Err(IronwoodError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_let() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(let ((x 1) (y (+ x 1))) (> y x))", &mut interner).unwrap();
+        assert!(expr.is_let());
+
+        let cases = [
+            ("(let x 1)", "expected `(` before let bindings", 5),
+            ("(let (x 1) x)", "expected `(name expr)` binding", 6),
+            ("(let ((a.b 1)) a)", "expected local name", 7),
+            ("(let ((\"x\" 1)) x)", "expected local name", 7),
+            ("(let ((x 1 2)) x)", "expected `)` after let binding", 11),
+            ("(let ((x 1)) x y)", "expected `)` after let body", 15),
+            ("(let ((x 1)) x", "unclosed delimiter", 0),
+        ];
+        for (source, expected, start) in cases {
+            match parse(source, &mut interner) {
+                Err(IronwoodError::Parse { message, span }) => {
+                    assert_eq!(message, expected, "{}", source);
+                    assert_eq!(span.start, start, "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_cond() {
+        let mut interner = StringInterner::new();
+        let cases = [
+            (
+                r#"(cond ((> score 90) "gold") ((> score 50) "silver") (else "bronze"))"#,
+                r#"(if (> score 90) "gold" (> score 50) "silver" "bronze")"#,
+            ),
+            ("(cond (vip 1.5))", "(if vip 1.5)"),
+            ("(cond (else 1.0))", "(if true 1.0)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                parse(source, &mut interner),
+                parse(expected, &mut interner),
+                "{}",
+                source
+            );
+        }
+
+        let source = "(cond (vip 2) (else 1))";
+        let spanned = parse_spanned(source, &mut interner).unwrap();
+        let spans: Vec<_> = spanned.iter().map(|(_, span)| span.start).collect();
+        assert_eq!(spans, [0, 7, 11, 20]);
+        let spanned = parse_spanned("(cond (else 1))", &mut interner).unwrap();
+        let spans: Vec<_> = spanned.iter().map(|(_, span)| span.start).collect();
+        assert_eq!(spans, [0, 7, 12]);
+
+        let cases = [
+            ("(cond)", "expected at least one cond clause", 0),
+            ("(cond x)", "expected `(test value)` clause", 6),
+            ("(cond (x 1 2))", "expected `)` after cond clause", 11),
+            (
+                "(cond (else 1) (x 2))",
+                "expected `else` clause to be last",
+                15,
+            ),
+            ("(cond (x 1)", "unclosed delimiter", 0),
+        ];
+        for (source, expected, start) in cases {
+            match parse(source, &mut interner) {
+                Err(IronwoodError::Parse { message, span }) => {
+                    assert_eq!(message, expected, "{}", source);
+                    assert_eq!(span.start, start, "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_element_binding() {
+        let mut interner = StringInterner::new();
+        let cases = [
+            (
+                r#"(filter (p purchases) (> (get p "amount") 100))"#,
+                r#"(filter purchases (let ((p _)) (> (get p "amount") 100)))"#,
+            ),
+            ("(all (_ xs) (> _ 1))", "(all xs (> _ 1))"),
+            // Calls to builtins are arguments rather than bindings
+            ("(any (get m \"k\") _)", "(any (get m \"k\") _)"),
+            (
+                "(count (map (x [1 2]) x))",
+                "(count (map [1 2] (let ((x _)) x)))",
+            ),
+        ];
+        for (source, expected) in cases {
+            let parsed = parse(source, &mut interner);
+            assert!(parsed.is_ok(), "{}: {:?}", source, parsed);
+            assert_eq!(parsed, parse(expected, &mut interner), "{}", source);
+        }
+
+        let source = "(filter (p xs) (> p 1))";
+        let spanned = parse_spanned(source, &mut interner).unwrap();
+        let spans: Vec<_> = spanned
+            .iter()
+            .map(|(_, span)| (span.start, span.end))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (0, 23),
+                (11, 13),
+                (8, 22),
+                (9, 10),
+                (15, 22),
+                (18, 19),
+                (20, 21)
+            ]
+        );
+
+        let cases = [
+            (
+                "(map (x xs ys) x)",
+                "expected `)` after element binding",
+                11,
+            ),
+            ("(map (x xs", "unclosed delimiter", 5),
+        ];
+        for (source, expected, start) in cases {
+            match parse(source, &mut interner) {
+                Err(IronwoodError::Parse { message, span }) => {
+                    assert_eq!(message, expected, "{}", source);
+                    assert_eq!(span.start, start, "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+        assert!(matches!(
+            parse("(map (x xs))", &mut interner),
+            Err(IronwoodError::Arity { found: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_limits() {
+        let mut interner = StringInterner::new();
+        let limits = EvalLimits {
+            max_depth: 3,
+            max_string_len: 3,
+            ..EvalLimits::default()
+        };
+
+        let source = "(not [(not x)])";
+        let err = parse_with_limits(source, &mut interner, &limits).unwrap_err();
+        assert_eq!(
+            err,
+            IronwoodError::LimitExceeded {
+                limit: Limit::Depth,
+                span: Span::from_source(source, 11, 11),
+            }
+        );
+        assert!(parse_with_limits("(not [x])", &mut interner, &limits).is_ok());
+
+        let err = parse_with_limits(r#"(= x "long")"#, &mut interner, &limits).unwrap_err();
+        assert_eq!(err.span().start, 5);
+        assert!(parse_with_limits(r#"(= x "abc")"#, &mut interner, &limits).is_ok());
+
+        // The default limits stop runaway nesting before the stack does
+        let deep = "(not ".repeat(100_000) + "true" + &")".repeat(100_000);
+        assert!(matches!(
+            parse(&deep, &mut interner),
+            Err(IronwoodError::LimitExceeded {
+                limit: Limit::Depth,
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn parse_many_rules() {
+        let mut interner = StringInterner::new();
+        let source = "; targeting rules\n\
+                      (= country \"US\") ; the home market\n\
+                      \n\
+                      (and (>= age 18) ; adults\n\
+                           (in tier [gold silver]))\n\
+                      flag;trailing\n";
+        let exprs: Vec<Expr> = parse_many(source, &mut interner)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(exprs.len(), 3);
+        assert_eq!(
+            exprs[1],
+            parse("(and (>= age 18) (in tier [gold silver]))", &mut interner).unwrap()
+        );
+        assert_eq!(exprs[2], Expr::Variable(interner.intern("flag")));
+
+        assert_eq!(parse_many(" ; nothing here", &mut interner).count(), 0);
+        assert_eq!(
+            parse("(not ; negated\n x)", &mut interner).unwrap(),
+            parse("(not x)", &mut interner).unwrap()
+        );
+
+        // Iteration stops at the first error, which points into the source
+        let mut many = parse_many("(= a 1)\n(= b\n(= c 3)", &mut interner);
+        assert!(many.next().unwrap().is_ok());
+        assert_eq!(many.next().unwrap().unwrap_err().span().line, 2);
+        assert!(many.next().is_none());
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let mut interner = StringInterner::new();
+        let source = r#"(meta (id 42) (author "ann") (tags [1 2]) (and (= x 1) (not true)))"#;
+        let annotated = parse(source, &mut interner).unwrap();
+        let plain = parse("(and (= x 1) (not true))", &mut interner).unwrap();
+        assert_eq!(annotated.unannotated(), &plain);
+        assert_eq!(annotated.metadata().len(), 3);
+        let author = interner.intern("author");
+        let ann = interner.intern("ann");
+        assert_eq!(annotated.metadata_value(author), Some(&Value::String(ann)));
+        assert_eq!(annotated.to_sexpr(&interner), source);
+
+        // The last element is the expression even when it looks like an entry
+        let expr = parse("(meta (id 1) (not true))", &mut interner).unwrap();
+        assert_eq!(
+            expr.unannotated(),
+            &parse("(not true)", &mut interner).unwrap()
+        );
+        assert_eq!(expr.metadata().len(), 1);
+
+        let mut env = crate::Environment::new();
+        env.insert(interner.intern("x"), Value::Integer(1));
+        assert!(parse("(meta (id 1))", &mut interner).is_ok());
+        assert!(parse("(meta (id 1) x", &mut interner).is_err());
+        assert!(parse("(meta (id 1) x y)", &mut interner).is_err());
+        assert!(parse("(meta)", &mut interner).is_err());
+
+        let evaluator = crate::Evaluator::new(&interner);
+        assert_eq!(
+            evaluator.eval(&annotated, &env),
+            evaluator.eval(&plain, &env)
+        );
+    }
+
+    #[test]
+    fn parse_spans() {
+        let mut interner = StringInterner::new();
+        let source = "(and (= country \"US\") ; home\n     (meta (id 7) (in tier [gold 'silver])))";
+        let spanned = parse_spanned(source, &mut interner).unwrap();
+        assert_eq!(spanned.expr(), &parse(source, &mut interner).unwrap());
+
+        let snippets: Vec<&str> = spanned
+            .iter()
+            .map(|(_, span)| span.snippet(source))
+            .collect();
+        assert_eq!(
+            snippets,
+            [
+                source,
+                "(= country \"US\")",
+                "country",
+                "\"US\"",
+                "(meta (id 7) (in tier [gold 'silver]))",
+                "(in tier [gold 'silver])",
+                "tier",
+                "[gold 'silver]",
+                "gold",
+                "'silver",
+            ]
+        );
+        for (_, span) in spanned.iter() {
+            assert_eq!(span, Span::from_source(source, span.start, span.end));
+        }
+
+        let mut count = 0;
+        spanned.expr().walk(|_| count += 1);
+        assert_eq!(count, snippets.len());
+
+        let Expr::Call { args, .. } = spanned.expr() else {
+            panic!("expected call");
+        };
+        let span = spanned.span_of(args[1].unannotated()).unwrap();
+        assert_eq!((span.line, span.col), (2, 19));
+        assert_eq!(spanned.span_of(&args[1].clone()), None);
+        assert_eq!(spanned.span().len(), source.len());
+    }
+}
@@ -0,0 +1,122 @@
+//! Regular expressions for `matches-regex`
+//!
+//! Patterns use the syntax of the `regex` crate and match anywhere in the
+//! text, so anchor them with `^` and `$` to match the whole string. Literal
+//! patterns are compiled once, when an expression is compiled or the first
+//! time an `Evaluator` reaches them, and cached by their interned string.
+//! Patterns computed at runtime are compiled on every call.
+//!
+//! Without the `std` feature there is no `regex` crate, and every pattern
+//! fails with `EvalError::InvalidRegex`.
+
+use crate::compat::{FxHashMap, RwLock};
+use crate::eval::EvalError;
+use crate::{Expr, StringId, Value};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+pub(crate) use regex::Regex;
+
+/// Stand-in for `regex::Regex` without `std`. It cannot be constructed, so
+/// every pattern fails to compile
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone)]
+pub(crate) enum Regex {}
+
+#[cfg(not(feature = "std"))]
+impl Regex {
+    pub(crate) fn new(_pattern: &str) -> Result<Self, &'static str> {
+        Err("regular expressions require the `std` feature")
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        match *self {}
+    }
+
+    pub(crate) fn is_match(&self, _text: &str) -> bool {
+        match *self {}
+    }
+}
+
+/// Compile a pattern, reporting syntax errors as evaluation errors
+pub(crate) fn compile_regex(pattern: &str) -> Result<Regex, EvalError> {
+    Regex::new(pattern).map_err(|error| EvalError::InvalidRegex(error.to_string()))
+}
+
+/// Get the interned string of a literal argument, such as a
+/// `matches-regex` pattern or a `semver-*` range
+pub(crate) fn literal_pattern(arg: &Expr) -> Option<StringId> {
+    match arg {
+        Expr::Literal(Value::String(id) | Value::Symbol(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+/// A compiled pattern that compares equal to patterns with the same source
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern(pub(crate) Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Compiled literal patterns keyed by their interned string
+#[derive(Debug, Default)]
+pub(crate) struct RegexCache {
+    patterns: RwLock<FxHashMap<StringId, Arc<Regex>>>,
+}
+
+impl RegexCache {
+    /// Check if the regex for `id` is cached
+    pub(crate) fn contains(&self, id: StringId) -> bool {
+        self.patterns.read().unwrap().contains_key(&id)
+    }
+
+    /// Get the compiled form of an interned pattern, compiling it on first use
+    pub(crate) fn get_or_compile(
+        &self,
+        id: StringId,
+        pattern: &str,
+    ) -> Result<Arc<Regex>, EvalError> {
+        if let Some(regex) = self.patterns.read().unwrap().get(&id) {
+            return Ok(Arc::clone(regex));
+        }
+        let regex = Arc::new(compile_regex(pattern)?);
+        self.patterns
+            .write()
+            .unwrap()
+            .insert(id, Arc::clone(&regex));
+        Ok(regex)
+    }
+}
+
+impl Clone for RegexCache {
+    fn clone(&self) -> Self {
+        Self {
+            patterns: RwLock::new(self.patterns.read().unwrap().clone()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_by_id() {
+        let cache = RegexCache::default();
+        let id = StringId::new(0);
+        let first = cache.get_or_compile(id, "^a+$").unwrap();
+        // The cached regex is returned without looking at the pattern again
+        let second = cache.get_or_compile(id, "ignored").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(second.is_match("aaa"));
+
+        assert!(matches!(
+            cache.get_or_compile(StringId::new(1), "(unclosed"),
+            Err(EvalError::InvalidRegex(_))
+        ));
+    }
+}
@@ -0,0 +1,534 @@
+//! Rendering expressions back to S-expression text
+//!
+//! Printed text parses back to an equivalent expression. Literal lists,
+//! such as those produced by `optimize::simplify`, print with list syntax
+//! and so parse back as `Expr::List`. Floats that are not finite, maps
+//! and symbols containing delimiters have no source syntax and do not
+//! round trip; maps print as `{"key" value}` with their keys sorted.
+//!
+//! `Value::display` and `Expr::display` adapt values and expressions to
+//! `Display` for logs and debugging. Values render as `"US"`,
+//! `sym:country`, `[1, 2, 3]` or `{"x-tenant": "acme"}`; expressions
+//! render as compact S-expressions.
+
+use crate::compat::FxHashMap;
+use crate::parser::{LET, META};
+use crate::{BuiltinFunction, Expr, Interner, StringId, Value};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Layout used when printing an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Format {
+    /// Everything on one line
+    #[default]
+    Compact,
+    /// Calls and lists that do not fit in `width` columns are broken onto
+    /// multiple lines, one argument per line indented by `indent` spaces
+    Indented { indent: usize, width: usize },
+}
+
+impl Format {
+    /// Indented layout with two-space indentation and 80 columns
+    pub fn indented() -> Self {
+        Format::Indented {
+            indent: 2,
+            width: 80,
+        }
+    }
+}
+
+impl Expr {
+    /// Render this expression as single-line S-expression text
+    pub fn to_sexpr(&self, interner: &dyn Interner) -> String {
+        self.to_sexpr_with(interner, Format::Compact)
+    }
+
+    /// Render this expression as S-expression text with the given layout
+    pub fn to_sexpr_with(&self, interner: &dyn Interner, format: Format) -> String {
+        let mut out = String::new();
+        match format {
+            Format::Compact => write_compact(&mut out, self, interner),
+            Format::Indented { indent, width } => {
+                let printer = Printer {
+                    interner,
+                    indent,
+                    width,
+                };
+                printer.write(&mut out, self, 0);
+            }
+        }
+        out
+    }
+}
+
+/// `Display` adapter for a value, returned by `Value::display`
+#[derive(Debug, Clone, Copy)]
+pub struct ValueDisplay<'a> {
+    value: &'a Value,
+    interner: &'a dyn Interner,
+}
+
+/// `Display` adapter for an expression, returned by `Expr::display`
+#[derive(Debug, Clone, Copy)]
+pub struct ExprDisplay<'a> {
+    expr: &'a Expr,
+    interner: &'a dyn Interner,
+}
+
+impl Value {
+    /// Render this value for humans, resolving strings through `interner`
+    pub fn display<'a>(&'a self, interner: &'a dyn Interner) -> ValueDisplay<'a> {
+        ValueDisplay {
+            value: self,
+            interner,
+        }
+    }
+}
+
+impl Expr {
+    /// Render this expression as compact S-expression text through `Display`
+    pub fn display<'a>(&'a self, interner: &'a dyn Interner) -> ExprDisplay<'a> {
+        ExprDisplay {
+            expr: self,
+            interner,
+        }
+    }
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = |id: StringId| match self.interner.resolve(id) {
+            Some(text) => format!("{:?}", text),
+            None => format!("#{}", id.raw()),
+        };
+        match self.value {
+            Value::Symbol(id) => match self.interner.resolve(*id) {
+                Some(name) => write!(f, "sym:{}", name),
+                None => write!(f, "sym:#{}", id.raw()),
+            },
+            Value::String(id) => f.write_str(&text(*id)),
+            Value::Text(s) => write!(f, "{:?}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Decimal(d) => write!(f, "{}d", d),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Null => f.write_str("null"),
+            Value::StringList(ids) => display_list(f, ids.iter().map(|&id| text(id))),
+            Value::IntegerList(ns) => display_list(f, ns.iter()),
+            Value::List(items) => {
+                display_list(f, items.iter().map(|item| item.display(self.interner)))
+            }
+            Value::Map(map) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in sorted_entries(map, self.interner).enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", text(key), value.display(self.interner))?;
+                }
+                f.write_char('}')
+            }
+            Value::Quoted(expr) => write!(
+                f,
+                "({} {})",
+                BuiltinFunction::Quote.as_str(),
+                expr.display(self.interner)
+            ),
+            Value::IntRange(start, end) => write!(
+                f,
+                "({} {} {})",
+                BuiltinFunction::IntRange.as_str(),
+                start,
+                end
+            ),
+            Value::BloomSet(set) => write!(f, "#<bloom-set {} items>", set.len()),
+        }
+    }
+}
+
+fn display_list<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    items: impl Iterator<Item = T>,
+) -> fmt::Result {
+    f.write_char('[')?;
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    f.write_char(']')
+}
+
+impl fmt::Display for ExprDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expr.to_sexpr(self.interner))
+    }
+}
+
+struct Printer<'i> {
+    interner: &'i dyn Interner,
+    indent: usize,
+    width: usize,
+}
+
+impl Printer<'_> {
+    /// Write `expr` starting at column `column`
+    fn write(&self, out: &mut String, expr: &Expr, column: usize) {
+        let flat = expr.to_sexpr(self.interner);
+        if let Expr::Annotated { metadata, expr } = expr {
+            if column + flat.len() > self.width {
+                // Entries stay on the first line, the expression goes below
+                write_metadata(out, metadata, self.interner);
+                out.push('\n');
+                out.extend(core::iter::repeat_n(' ', column + self.indent));
+                self.write(out, expr, column + self.indent);
+                out.push(')');
+                return;
+            }
+        }
+        if let Expr::Let { bindings, body } = expr {
+            if column + flat.len() > self.width {
+                // One binding per line, aligned, and the body below
+                let binding_column = column + LET.len() + 3;
+                out.push('(');
+                out.push_str(LET);
+                out.push_str(" (");
+                for (i, (local, value)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        out.push('\n');
+                        out.extend(core::iter::repeat_n(' ', binding_column));
+                    }
+                    let local = name(*local, self.interner);
+                    out.push('(');
+                    out.push_str(local);
+                    out.push(' ');
+                    self.write(out, value, binding_column + local.len() + 2);
+                    out.push(')');
+                }
+                out.push_str(")\n");
+                out.extend(core::iter::repeat_n(' ', column + self.indent));
+                self.write(out, body, column + self.indent);
+                out.push(')');
+                return;
+            }
+        }
+        let (open, close, head, items) = match expr {
+            Expr::Call { function, args } if column + flat.len() > self.width => {
+                ('(', ')', Some(*function), args.as_slice())
+            }
+            Expr::List(items) if column + flat.len() > self.width && !items.is_empty() => {
+                ('[', ']', None, items.as_slice())
+            }
+            _ => {
+                out.push_str(&flat);
+                return;
+            }
+        };
+
+        let inner = column + self.indent;
+        out.push(open);
+        if let Some(function) = head {
+            out.push_str(name(function, self.interner));
+        }
+        for (i, item) in items.iter().enumerate() {
+            // List elements start right after the bracket on the first line
+            if i > 0 || head.is_some() {
+                out.push('\n');
+                out.extend(core::iter::repeat_n(' ', inner));
+            }
+            self.write(out, item, inner);
+        }
+        out.push(close);
+    }
+}
+
+fn write_compact(out: &mut String, expr: &Expr, interner: &dyn Interner) {
+    match expr {
+        Expr::Literal(value) => write_value(out, value, interner),
+        Expr::Variable(id) => out.push_str(name(*id, interner)),
+        Expr::Call { function, args } => {
+            out.push('(');
+            out.push_str(name(*function, interner));
+            for arg in args {
+                out.push(' ');
+                write_compact(out, arg, interner);
+            }
+            out.push(')');
+        }
+        Expr::List(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_compact(out, item, interner);
+            }
+            out.push(']');
+        }
+        Expr::Annotated { metadata, expr } => {
+            write_metadata(out, metadata, interner);
+            out.push(' ');
+            write_compact(out, expr, interner);
+            out.push(')');
+        }
+        Expr::Let { bindings, body } => {
+            out.push('(');
+            out.push_str(LET);
+            out.push_str(" (");
+            for (i, (local, value)) in bindings.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                out.push('(');
+                out.push_str(name(*local, interner));
+                out.push(' ');
+                write_compact(out, value, interner);
+                out.push(')');
+            }
+            out.push_str(") ");
+            write_compact(out, body, interner);
+            out.push(')');
+        }
+    }
+}
+
+/// Write the opening of a `meta` form up to its expression
+fn write_metadata(out: &mut String, metadata: &[(StringId, Value)], interner: &dyn Interner) {
+    out.push('(');
+    out.push_str(META);
+    for (key, value) in metadata {
+        out.push_str(" (");
+        out.push_str(name(*key, interner));
+        out.push(' ');
+        write_value(out, value, interner);
+        out.push(')');
+    }
+}
+
+fn write_value(out: &mut String, value: &Value, interner: &dyn Interner) {
+    match value {
+        Value::Symbol(id) => {
+            out.push('\'');
+            out.push_str(name(*id, interner));
+        }
+        Value::String(id) => write_string(out, interner.resolve(*id).unwrap_or_default()),
+        Value::Text(text) => write_string(out, text),
+        Value::Integer(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        // Debug formatting always includes a decimal point or exponent, so
+        // the number parses back as a float
+        Value::Float(f) => {
+            let _ = write!(out, "{:?}", f);
+        }
+        Value::Decimal(d) => {
+            let _ = write!(out, "{}d", d);
+        }
+        Value::Bool(b) => {
+            let _ = write!(out, "{}", b);
+        }
+        Value::Null => out.push_str("null"),
+        Value::StringList(ids) => {
+            let items = ids.iter().map(|&id| Value::String(id));
+            write_list(out, items, interner);
+        }
+        Value::IntegerList(ns) => write_list(out, ns.iter().map(|&n| Value::Integer(n)), interner),
+        Value::List(items) => write_list(out, items.iter().cloned(), interner),
+        Value::Map(map) => {
+            out.push('{');
+            for (i, (key, value)) in sorted_entries(map, interner).enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_string(out, interner.resolve(key).unwrap_or_default());
+                out.push(' ');
+                write_value(out, value, interner);
+            }
+            out.push('}');
+        }
+        // Parses back as a call evaluating to the same quoted expression
+        Value::Quoted(expr) => {
+            out.push('(');
+            out.push_str(BuiltinFunction::Quote.as_str());
+            out.push(' ');
+            write_compact(out, expr, interner);
+            out.push(')');
+        }
+        Value::IntRange(start, end) => {
+            let _ = write!(
+                out,
+                "({} {} {})",
+                BuiltinFunction::IntRange.as_str(),
+                start,
+                end
+            );
+        }
+        // Too large to write out, and so has no form that parses back
+        Value::BloomSet(set) => {
+            let _ = write!(out, "#<bloom-set {} items>", set.len());
+        }
+    }
+}
+
+/// Entries of a map ordered by key text so output is deterministic
+fn sorted_entries<'m>(
+    map: &'m FxHashMap<StringId, Value>,
+    interner: &dyn Interner,
+) -> impl Iterator<Item = (StringId, &'m Value)> {
+    let mut entries: Vec<_> = map.iter().map(|(&key, value)| (key, value)).collect();
+    entries.sort_by_key(|&(key, _)| (interner.resolve(key), key));
+    entries.into_iter()
+}
+
+fn write_list(out: &mut String, items: impl Iterator<Item = Value>, interner: &dyn Interner) {
+    out.push('[');
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        write_value(out, &item, interner);
+    }
+    out.push(']');
+}
+
+fn write_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Resolve a name, falling back to a placeholder for foreign IDs
+fn name(id: StringId, interner: &dyn Interner) -> &str {
+    interner.resolve(id).unwrap_or("<unknown>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::simplify;
+    use crate::{parse, StringInterner};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn round_trip() {
+        let mut interner = StringInterner::new();
+        let sources = [
+            r#"(and (= status "active") (>= age 18.5) (not (in 'tier [1 2 3])))"#,
+            r#"(or (= note "say \"hi\"\n\tand \\ bye") (is-null x) (= y null))"#,
+            "(and)",
+            "[]",
+            "(geo_within_bbox lat lng -1e-7 2.0 1e21 -3.25)",
+            "(let ((total (* price qty)) (limit 100)) (> total limit))",
+            "(let () true)",
+        ];
+        for source in sources {
+            let expr = parse(source, &mut interner).unwrap();
+            let printed = expr.to_sexpr(&interner);
+            assert_eq!(printed, source);
+            assert_eq!(parse(&printed, &mut interner).unwrap(), expr);
+
+            let indented = expr.to_sexpr_with(&interner, Format::indented());
+            assert_eq!(parse(&indented, &mut interner).unwrap(), expr);
+        }
+    }
+
+    #[test]
+    fn literal_values() {
+        let mut interner = StringInterner::new();
+        let expr = parse(r#"(in x [(> 2 1) "a" 'b 3])"#, &mut interner).unwrap();
+        let simplified = simplify(expr, &interner);
+        assert_eq!(simplified.to_sexpr(&interner), r#"(in x [true "a" 'b 3])"#);
+
+        let text = Expr::Literal(Value::Text("new".into()));
+        assert_eq!(text.to_sexpr(&interner), r#""new""#);
+    }
+
+    #[test]
+    fn display_adapters() {
+        let mut interner = StringInterner::new();
+        let us = interner.intern("US");
+        let country = interner.intern("country");
+        let cases = [
+            (Value::String(us), r#""US""#),
+            (Value::Symbol(country), "sym:country"),
+            (Value::IntegerList(vec![1, 2, 3].into()), "[1, 2, 3]"),
+            (
+                Value::StringList(vec![us, country].into()),
+                r#"["US", "country"]"#,
+            ),
+            (
+                Value::List(vec![Value::Float(1.0), Value::Null, Value::Symbol(us)]),
+                "[1.0, null, sym:US]",
+            ),
+            (Value::Text("say \"hi\"".into()), r#""say \"hi\"""#),
+            (
+                Value::Map(
+                    [(us, Value::Integer(1)), (country, Value::Null)]
+                        .into_iter()
+                        .collect(),
+                ),
+                r#"{"US": 1, "country": null}"#,
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(value.display(&interner).to_string(), expected);
+        }
+
+        let expr = parse(r#"(= country "US")"#, &mut interner).unwrap();
+        assert_eq!(
+            format!("rule {}", expr.display(&interner)),
+            r#"rule (= country "US")"#
+        );
+    }
+
+    #[test]
+    fn indented_layout() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (= country "US") (or (>= age 21) (in segment ["vip" "staff"])) flag)"#,
+            &mut interner,
+        )
+        .unwrap();
+        let format = Format::Indented {
+            indent: 2,
+            width: 40,
+        };
+
+        let expected = r#"(and
+  (= country "US")
+  (or
+    (>= age 21)
+    (in segment ["vip" "staff"]))
+  flag)"#;
+        assert_eq!(expr.to_sexpr_with(&interner, format), expected);
+
+        // Expressions that fit stay on one line
+        assert_eq!(
+            expr.to_sexpr_with(&interner, Format::indented()),
+            expr.to_sexpr(&interner)
+        );
+
+        let expr = parse(
+            "(let ((total (* price quantity)) (over (> total 100))) (and over vip))",
+            &mut interner,
+        )
+        .unwrap();
+        let expected = "(let ((total (* price quantity))
+      (over (> total 100)))
+  (and over vip))";
+        assert_eq!(expr.to_sexpr_with(&interner, format), expected);
+    }
+}
@@ -0,0 +1,39 @@
+//! Lazily provided variables
+//!
+//! Some inputs are expensive to fetch, such as features from a feature
+//! store or a remote cache, and most rules read only a few of them. An
+//! evaluator given a `VariableProvider` with `Evaluator::with_provider`
+//! asks it for a variable only when a rule reads the variable and the
+//! environment has no value for it, so nothing is fetched for variables a
+//! rule never touches, including those in branches it skips.
+//!
+//! A variable the provider has no value for is missing, and handled by
+//! `EvalOptions::missing` as usual. The provider is asked on every read,
+//! so one backed by a slow store caches what it fetches.
+
+use crate::{StringId, Value};
+use core::fmt;
+
+/// Source of values for variables missing from the environment
+///
+/// Providers are shared by every evaluation of an evaluator, including
+/// those of a parallel batch, so they cache through atomics or locks.
+pub trait VariableProvider: Send + Sync {
+    /// Get the value of a variable, `None` if it has none
+    fn get(&self, name: StringId) -> Option<Value>;
+}
+
+impl<F> VariableProvider for F
+where
+    F: Fn(StringId) -> Option<Value> + Send + Sync,
+{
+    fn get(&self, name: StringId) -> Option<Value> {
+        self(name)
+    }
+}
+
+impl fmt::Debug for dyn VariableProvider + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VariableProvider")
+    }
+}
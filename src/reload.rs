@@ -0,0 +1,214 @@
+//! Hot reloading of rule sets
+//!
+//! A `RuleSetHandle` holds the active `RuleSet` behind an `Arc`. Workers
+//! `load` it once per request and evaluate against the `Arc` they got, so
+//! replacing the rule set never disturbs evaluations already running: they
+//! finish on the old version, which is dropped when the last of them does.
+//! Readers only hold a lock long enough to clone the `Arc`.
+//!
+//! Environments are built with IDs from the active rule set's interner, and
+//! a request that started before a reload may still use them afterwards.
+//! `RuleSetHandle::replace` therefore only accepts a rule set whose
+//! interner gives every string of the active one the same ID, as one
+//! extended from a clone of the active interner does. `RuleSetHandle::rebase`
+//! accepts any rule set, moving its rules onto a copy of the active
+//! interner first.
+
+use crate::compat::{Mutex, RwLock};
+use crate::{RuleId, RuleSet, StringId, StringInterner};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reasons a rule set cannot replace the active one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadError {
+    /// ID of the active interner that the new interner does not give the
+    /// same string
+    IncompatibleInterner(StringId),
+    /// Rule contains IDs missing from the interner it was built with
+    UnknownId(RuleId),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::IncompatibleInterner(id) => write!(
+                f,
+                "new interner does not give #{} the same string",
+                id.raw()
+            ),
+            ReloadError::UnknownId(rule) => {
+                write!(f, "rule {} contains IDs missing from its interner", rule)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReloadError {}
+
+/// Source of the IDs that tell handles apart
+static NEXT_HANDLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Shared handle to the active rule set, replaceable while evaluations run
+#[derive(Debug)]
+pub struct RuleSetHandle {
+    /// Identity of this handle, unique in the process
+    id: usize,
+    active: RwLock<Active>,
+    /// Held by writers, so a rebase is not based on a version another
+    /// writer is replacing
+    writer: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct Active {
+    rules: Arc<RuleSet>,
+    generation: u64,
+}
+
+impl RuleSetHandle {
+    /// Create a handle with `rules` active, as generation 0
+    pub fn new(rules: RuleSet) -> Self {
+        Self {
+            id: NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed),
+            active: RwLock::new(Active {
+                rules: Arc::new(rules),
+                generation: 0,
+            }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Get the active rule set, which stays valid however long it is held
+    pub fn load(&self) -> Arc<RuleSet> {
+        Arc::clone(&self.active.read().unwrap().rules)
+    }
+
+    /// Get the number of times the rule set has been replaced
+    pub fn generation(&self) -> u64 {
+        self.active.read().unwrap().generation
+    }
+
+    /// Get the identity of this handle, which no other handle in the
+    /// process shares
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Get the active rule set together with its generation
+    pub(crate) fn load_with_generation(&self) -> (Arc<RuleSet>, u64) {
+        let active = self.active.read().unwrap();
+        (Arc::clone(&active.rules), active.generation)
+    }
+
+    /// Make `rules` active, returning the rule set it replaces
+    ///
+    /// Fails if `rules`' interner does not give every string of the active
+    /// interner the same ID, leaving the active rule set in place.
+    pub fn replace(&self, rules: RuleSet) -> Result<Arc<RuleSet>, ReloadError> {
+        let _writer = self.writer.lock().unwrap();
+        compatible(self.load().interner(), rules.interner())?;
+        Ok(self.swap(rules))
+    }
+
+    /// Make the rules of `rules` active, rebuilt onto a copy of the active
+    /// interner extended with the strings they use, returning the rule set
+    /// they replace
+    ///
+    /// Rules are compiled again, so this is slower than `replace` but
+    /// accepts rule sets built with any interner.
+    pub fn rebase(&self, rules: &RuleSet) -> Result<Arc<RuleSet>, ReloadError> {
+        let _writer = self.writer.lock().unwrap();
+        let mut interner = self.load().interner().clone();
+        let remap = interner.merge(rules.interner());
+        let exprs = rules
+            .rules()
+            .map(|(id, expr)| {
+                Ok((
+                    id,
+                    remap.remap_expr(expr).ok_or(ReloadError::UnknownId(id))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut rebased = RuleSet::with_interner(interner);
+        for (id, expr) in exprs {
+            rebased.add_rule(id, expr);
+        }
+        Ok(self.swap(rebased))
+    }
+
+    fn swap(&self, rules: RuleSet) -> Arc<RuleSet> {
+        let mut active = self.active.write().unwrap();
+        active.generation += 1;
+        core::mem::replace(&mut active.rules, Arc::new(rules))
+    }
+}
+
+/// Check that `new` gives every string of `old` the same ID
+fn compatible(old: &StringInterner, new: &StringInterner) -> Result<(), ReloadError> {
+    for raw in 0..old.id_bound() {
+        let id = StringId::new(raw);
+        if let Some(s) = old.resolve(id) {
+            if new.resolve(id) != Some(s) {
+                return Err(ReloadError::IncompatibleInterner(id));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{parse, Environment, Value};
+
+    /// Build a rule set on `interner` from rule sources
+    pub(crate) fn rule_set(interner: StringInterner, rules: &[(RuleId, &str)]) -> RuleSet {
+        let mut rule_set = RuleSet::with_interner(interner);
+        for &(id, source) in rules {
+            let expr = parse(source, rule_set.interner_mut()).unwrap();
+            rule_set.add_rule(id, expr);
+        }
+        rule_set
+    }
+
+    #[test]
+    fn replaces_while_held() {
+        let handle = RuleSetHandle::new(rule_set(StringInterner::new(), &[(1, "(> age 18)")]));
+        let held = handle.load();
+        let age = held.interner().get_id("age").unwrap();
+        let mut env = Environment::new();
+        env.insert(age, Value::Integer(30));
+
+        let next = rule_set(
+            held.interner().clone(),
+            &[(1, "(> age 40)"), (2, "(< age 65)")],
+        );
+        let old = handle.replace(next).unwrap();
+        assert!(Arc::ptr_eq(&old, &held));
+        assert_eq!(handle.generation(), 1);
+        // The held version still evaluates, and IDs built with it still
+        // work on the new one
+        assert_eq!(held.matches(&env), [1]);
+        assert_eq!(handle.load().matches(&env), [2]);
+
+        let mut foreign = StringInterner::new();
+        foreign.intern("tier");
+        let foreign = rule_set(foreign, &[(3, "(> age 50)")]);
+        assert_eq!(
+            handle.replace(RuleSet::new()).unwrap_err(),
+            ReloadError::IncompatibleInterner(held.interner().get_id(">").unwrap())
+        );
+        assert_eq!(handle.generation(), 1);
+
+        handle.rebase(&foreign).unwrap();
+        assert_eq!(handle.generation(), 2);
+        let active = handle.load();
+        assert_eq!(active.interner().get_id("age"), Some(age));
+        env.insert(age, Value::Integer(60));
+        assert_eq!(active.matches(&env), [3]);
+    }
+}
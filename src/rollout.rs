@@ -0,0 +1,114 @@
+//! Stable bucketing for percentage rollouts
+//!
+//! `(percent-of key salt percent)` is true for a deterministic `percent`
+//! of keys: the key is hashed together with the salt into one of
+//! `BUCKETS` buckets, and the call is true when the bucket falls below
+//! `percent * 100`. Raising the percentage only ever adds keys, and a new
+//! salt reshuffles them, so each experiment gets an independent split.
+//!
+//! The hash is part of the public contract so that other services, and
+//! later releases, put every key in the same bucket:
+//!
+//! 1. Build the bytes `salt`, `:`, `key`, with both strings as UTF-8.
+//!    Integer keys are written in decimal, so `42` and `"42"` agree.
+//! 2. Hash them with 64-bit FNV-1a: start from `0xcbf29ce484222325` and
+//!    for each byte XOR it in, then multiply by `0x100000001b3` wrapping.
+//! 3. The bucket is the hash modulo `BUCKETS`.
+//!
+//! For example, key `user-1` with salt `experiment-42` lands in bucket
+//! 994, so it is in a 10% rollout but not a 9.9% one.
+//!
+//! Under an interner made by `StringInterner::with_case_folding`, the key
+//! and salt are folded to lowercase before hashing, so `"User-1"` and
+//! `"user-1"` land in the same bucket whichever was interned first.
+//!
+//! `(experiment-bucket key salt variants weights)` splits the buckets
+//! between variants in proportion to their weights, in order: with weights
+//! `[w0 w1 ...]` summing to `total`, the key gets the first variant `i`
+//! whose running sum `w0 + ... + wi` exceeds `bucket * total / BUCKETS`.
+//! A variant of weight 0 is never chosen. Experiments in one layer are
+//! mutually exclusive when a first call salted with the layer's name
+//! splits keys between them, and each experiment then splits the keys it
+//! got between its variants with a second call salted with its own name.
+
+/// Number of buckets keys are spread over, giving 0.01% granularity
+pub const BUCKETS: u64 = 10_000;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Get the bucket of `key` under `salt`, in `0..BUCKETS`
+pub fn bucket(key: &str, salt: &str) -> u64 {
+    fnv1a(salt.bytes().chain([b':']).chain(key.bytes())) % BUCKETS
+}
+
+/// 64-bit FNV-1a hash, which is the same on every platform and release
+pub(crate) fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Check if a key in `bucket` is within a rollout to `percent` of keys
+pub fn in_rollout(bucket: u64, percent: f64) -> bool {
+    (bucket as f64) < percent * (BUCKETS / 100) as f64
+}
+
+/// Get the index of the weight whose share of the buckets `bucket` falls
+/// in, `None` if the weights sum to 0
+pub fn allocate(bucket: u64, weights: &[f64]) -> Option<usize> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let position = bucket as f64 * total / BUCKETS as f64;
+    let mut sum = 0.0;
+    weights.iter().position(|weight| {
+        sum += weight;
+        position < sum
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn stable_buckets() {
+        // Pinned values: changing them breaks agreement with other services
+        assert_eq!(bucket("user-1", "experiment-42"), 994);
+        assert_eq!(bucket("user-1", "checkout-v2"), 899);
+        assert_eq!(bucket("", ""), 8189);
+        assert!(in_rollout(994, 10.0));
+        assert!(!in_rollout(994, 9.9));
+        assert!(!in_rollout(0, 0.0));
+        assert!(in_rollout(BUCKETS - 1, 100.0));
+
+        // Roughly `percent` of keys fall in the rollout
+        let included = (0..10_000)
+            .filter(|n| in_rollout(bucket(&n.to_string(), "experiment-42"), 25.0))
+            .count();
+        assert!((2300..2700).contains(&included), "{}", included);
+    }
+
+    #[test]
+    fn allocates_by_weight() {
+        assert_eq!(allocate(0, &[1.0, 1.0]), Some(0));
+        assert_eq!(allocate(4999, &[1.0, 1.0]), Some(0));
+        assert_eq!(allocate(5000, &[1.0, 1.0]), Some(1));
+        assert_eq!(allocate(BUCKETS - 1, &[1.0, 1.0]), Some(1));
+        assert_eq!(allocate(994, &[10.0, 0.0, 90.0]), Some(0));
+        assert_eq!(allocate(1000, &[10.0, 0.0, 90.0]), Some(2));
+        assert_eq!(allocate(0, &[0.0, 0.0]), None);
+
+        let mut counts = [0u32; 3];
+        for n in 0..10_000 {
+            let bucket = bucket(&n.to_string(), "layer-1");
+            counts[allocate(bucket, &[20.0, 30.0, 50.0]).unwrap()] += 1;
+        }
+        for (count, expected) in counts.into_iter().zip([2000, 3000, 5000]) {
+            assert!(count.abs_diff(expected) < 300, "{:?}", counts);
+        }
+    }
+}
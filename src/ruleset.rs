@@ -0,0 +1,371 @@
+//! Matching many rules against a single environment
+//!
+//! A `RuleSet` owns the interner its rules are built with and compiles each
+//! rule on insertion. Rules that require a variable to equal one of a set of
+//! literals (a top-level `(= var literal)` or `(in var [...])`, possibly as a
+//! conjunct of a top-level `and`) are indexed by that variable and literal,
+//! so `matches` only evaluates rules whose indexed predicate can hold plus
+//! the rules that have no indexable predicate.
+
+use crate::compat::{self, FxHashMap};
+use crate::compile::{compile, CompiledExpr};
+use crate::member::EXACT_FLOAT_LIMIT;
+use crate::telemetry;
+use crate::{BuiltinFunction, Environment, Expr, StringId, StringInterner, Value};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Caller-assigned rule identifier
+pub type RuleId = u64;
+
+/// Normalized literal used as an index key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum IndexKey {
+    Text(StringId),
+    Int(i64),
+    Bool(bool),
+}
+
+impl IndexKey {
+    /// Get the key a value is indexed under, if it has one. Integral floats
+    /// and decimals share keys with integers and computed text shares keys
+    /// with interned strings because `=` treats them as equal. Floats from
+    /// `EXACT_FLOAT_LIMIT` on have no key, since one may equal several
+    /// integers
+    pub(crate) fn of(value: &Value, interner: &StringInterner) -> Option<Self> {
+        match value {
+            Value::Symbol(id) | Value::String(id) => Some(IndexKey::Text(*id)),
+            Value::Text(s) => interner.get_id(s).map(IndexKey::Text),
+            Value::Integer(n) => Some(IndexKey::Int(*n)),
+            Value::Float(f) if compat::fract(*f) == 0.0 && f.abs() < EXACT_FLOAT_LIMIT => {
+                Some(IndexKey::Int(*f as i64))
+            }
+            Value::Decimal(d) => d.to_i64().map(IndexKey::Int),
+            Value::Bool(b) => Some(IndexKey::Bool(*b)),
+            _ => None,
+        }
+    }
+
+    /// Check if `=` holds between this key and a float too large to have
+    /// a key of its own, which equals every integer that rounds to it
+    pub(crate) fn rounds_to(self, value: &Value) -> bool {
+        match (self, value) {
+            (IndexKey::Int(n), Value::Float(f)) => f.abs() >= EXACT_FLOAT_LIMIT && n as f64 == *f,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    id: RuleId,
+    expr: Expr,
+    compiled: CompiledExpr,
+    /// Variable and keys this rule is indexed under, `None` if unindexed
+    indexed: Option<(StringId, Vec<IndexKey>)>,
+}
+
+/// A collection of rules evaluated together against one environment
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    interner: StringInterner,
+    /// Rule slots, `None` for removed rules
+    rules: Vec<Option<Rule>>,
+    slots: FxHashMap<RuleId, usize>,
+    /// Variable -> literal -> slots of rules requiring that equality
+    index: FxHashMap<StringId, FxHashMap<IndexKey, Vec<usize>>>,
+    /// Slots of rules that must always be evaluated
+    unindexed: Vec<usize>,
+}
+
+impl RuleSet {
+    /// Create an empty rule set with its own interner
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty rule set sharing an existing interner
+    pub fn with_interner(interner: StringInterner) -> Self {
+        Self {
+            interner,
+            ..Self::default()
+        }
+    }
+
+    /// Get the interner rules are built with
+    pub fn interner(&self) -> &StringInterner {
+        &self.interner
+    }
+
+    /// Get the interner mutably, e.g. to parse new rules
+    pub fn interner_mut(&mut self) -> &mut StringInterner {
+        &mut self.interner
+    }
+
+    /// Add a rule built with this set's interner, replacing any rule with
+    /// the same ID
+    pub fn add_rule(&mut self, id: RuleId, expr: Expr) {
+        self.remove_rule(id);
+
+        let slot = self.rules.len();
+        let indexed = index_predicate(&expr, &self.interner);
+        match &indexed {
+            Some((name, keys)) => {
+                let by_key = self.index.entry(*name).or_default();
+                for key in keys {
+                    by_key.entry(*key).or_default().push(slot);
+                }
+            }
+            None => self.unindexed.push(slot),
+        }
+
+        let compiled = compile(&expr, &self.interner);
+        self.rules.push(Some(Rule {
+            id,
+            expr,
+            compiled,
+            indexed,
+        }));
+        self.slots.insert(id, slot);
+    }
+
+    /// Remove a rule, returning its expression if it existed
+    pub fn remove_rule(&mut self, id: RuleId) -> Option<Expr> {
+        let slot = self.slots.remove(&id)?;
+        let rule = self.rules[slot].take()?;
+        match &rule.indexed {
+            Some((name, keys)) => {
+                if let Some(by_key) = self.index.get_mut(name) {
+                    for key in keys {
+                        if let Some(slots) = by_key.get_mut(key) {
+                            slots.retain(|&s| s != slot);
+                        }
+                    }
+                }
+            }
+            None => self.unindexed.retain(|&s| s != slot),
+        }
+        Some(rule.expr)
+    }
+
+    /// Get the expression of a rule
+    pub fn rule(&self, id: RuleId) -> Option<&Expr> {
+        let slot = *self.slots.get(&id)?;
+        self.rules[slot].as_ref().map(|rule| &rule.expr)
+    }
+
+    /// Iterate over the rules in insertion order
+    pub(crate) fn rules(&self) -> impl Iterator<Item = (RuleId, &Expr)> {
+        self.rules
+            .iter()
+            .flatten()
+            .map(|rule| (rule.id, &rule.expr))
+    }
+
+    /// Get the compiled form of a rule
+    pub(crate) fn compiled(&self, id: RuleId) -> Option<&CompiledExpr> {
+        let slot = *self.slots.get(&id)?;
+        self.rules[slot].as_ref().map(|rule| &rule.compiled)
+    }
+
+    /// Get the ID and compiled form of the rule in `slot`
+    pub(crate) fn compiled_in(&self, slot: usize) -> Option<(RuleId, &CompiledExpr)> {
+        let rule = self.rules[slot].as_ref()?;
+        Some((rule.id, &rule.compiled))
+    }
+
+    /// Get the ID and expression of the rule in `slot`
+    #[cfg(feature = "std")]
+    pub(crate) fn rule_in(&self, slot: usize) -> Option<(RuleId, &Expr)> {
+        let rule = self.rules[slot].as_ref()?;
+        Some((rule.id, &rule.expr))
+    }
+
+    /// Get the number of rules
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Check if the rule set is empty
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Return the IDs of all rules that evaluate to `true`, in insertion
+    /// order. Rules that fail to evaluate do not match
+    pub fn matches(&self, env: &Environment) -> Vec<RuleId> {
+        self.candidates(env)
+            .into_iter()
+            .filter_map(|slot| self.check(slot, env))
+            .collect()
+    }
+
+    /// Slots of the rules that may match `env`, in insertion order
+    pub(crate) fn candidates(&self, env: &Environment) -> Vec<usize> {
+        let mut candidates = self.unindexed.clone();
+        for (name, by_key) in &self.index {
+            let Some(value) = env.get(*name) else {
+                continue;
+            };
+            match IndexKey::of(value, &self.interner) {
+                Some(key) => candidates.extend(by_key.get(&key).into_iter().flatten()),
+                None => {
+                    for (key, slots) in by_key {
+                        if key.rounds_to(value) {
+                            candidates.extend_from_slice(slots);
+                        }
+                    }
+                }
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Get the ID of the rule in `slot` if it evaluates to `true`
+    pub(crate) fn check(&self, slot: usize, env: &Environment) -> Option<RuleId> {
+        let rule = self.rules[slot].as_ref()?;
+        let span = telemetry::rule(rule.id);
+        let result = rule.compiled.eval(env, &self.interner);
+        let matched = result == Ok(Value::Bool(true));
+        match &result {
+            Err(_) => span.result(&result),
+            Ok(_) if matched => span.outcome("match"),
+            Ok(_) => span.outcome("no-match"),
+        }
+        matched.then_some(rule.id)
+    }
+}
+
+/// Find an equality predicate that must hold for `expr` to be true
+fn index_predicate(expr: &Expr, interner: &StringInterner) -> Option<(StringId, Vec<IndexKey>)> {
+    let Expr::Call { function, args } = expr.unannotated() else {
+        return None;
+    };
+    match interner.builtin(*function)? {
+        BuiltinFunction::And => args.iter().find_map(|arg| index_predicate(arg, interner)),
+        BuiltinFunction::Equal => match args.as_slice() {
+            [Expr::Variable(name), Expr::Literal(value)]
+            | [Expr::Literal(value), Expr::Variable(name)] => {
+                Some((*name, vec![IndexKey::of(value, interner)?]))
+            }
+            _ => None,
+        },
+        BuiltinFunction::In => match args.as_slice() {
+            [Expr::Variable(name), list] => Some((*name, literal_keys(list, interner)?)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Get the index keys of every element of a literal list
+fn literal_keys(list: &Expr, interner: &StringInterner) -> Option<Vec<IndexKey>> {
+    match list {
+        Expr::List(items) => items
+            .iter()
+            .map(|item| match item {
+                Expr::Literal(value) => IndexKey::of(value, interner),
+                _ => None,
+            })
+            .collect(),
+        Expr::Literal(Value::StringList(ids)) => {
+            Some(ids.iter().map(|&id| IndexKey::Text(id)).collect())
+        }
+        Expr::Literal(Value::IntegerList(ns)) => {
+            Some(ns.iter().map(|&n| IndexKey::Int(n)).collect())
+        }
+        Expr::Literal(Value::List(values)) => values
+            .iter()
+            .map(|value| IndexKey::of(value, interner))
+            .collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn rule_set(rules: &[(RuleId, &str)]) -> RuleSet {
+        let mut set = RuleSet::new();
+        for (id, source) in rules {
+            let expr = parse(source, set.interner_mut()).unwrap();
+            set.add_rule(*id, expr);
+        }
+        set
+    }
+
+    fn env(set: &mut RuleSet, bindings: &[(&str, Value)]) -> Environment {
+        bindings
+            .iter()
+            .map(|(name, value)| (set.interner_mut().intern(name), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn matches_indexed_and_unindexed_rules() {
+        let mut set = rule_set(&[
+            (1, r#"(and (= country "US") (>= age 21))"#),
+            (2, r#"(in country ["CA" "MX"])"#),
+            (3, "(>= age 65)"),
+            (4, r#"(= "US" country)"#),
+            (5, "(= age 30.0)"),
+        ]);
+        assert_eq!(set.unindexed, vec![2usize]);
+
+        let us = Value::String(set.interner_mut().intern("US"));
+        let env_us = env(&mut set, &[("country", us), ("age", Value::Integer(30))]);
+        assert_eq!(set.matches(&env_us), vec![1, 4, 5]);
+
+        let ca = Value::Symbol(set.interner_mut().intern("CA"));
+        let env_ca = env(&mut set, &[("country", ca), ("age", Value::Integer(70))]);
+        assert_eq!(set.matches(&env_ca), vec![2, 3]);
+    }
+
+    #[test]
+    fn large_floats_are_unindexed() {
+        // Above 2^53 one float equals several integers under `=`
+        let mut set = rule_set(&[
+            (1, "(= x 9007199254740992.0)"),
+            (2, "(= x 9007199254740993)"),
+            (3, "(in x [9007199254740994 1])"),
+        ]);
+        assert_eq!(set.unindexed, vec![0usize]);
+
+        let int = env(&mut set, &[("x", Value::Integer(9007199254740993))]);
+        let expected: Vec<RuleId> = [1, 2, 3]
+            .into_iter()
+            .filter(|&id| {
+                let rule = set.compiled(id).unwrap();
+                rule.eval(&int, &set.interner) == Ok(Value::Bool(true))
+            })
+            .collect();
+        assert_eq!(expected, vec![1, 2]);
+        assert_eq!(set.matches(&int), expected);
+
+        let float = env(&mut set, &[("x", Value::Float(9007199254740992.0))]);
+        assert_eq!(set.matches(&float), vec![1, 2]);
+        let float = env(&mut set, &[("x", Value::Float(9007199254740994.0))]);
+        assert_eq!(set.matches(&float), vec![3]);
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut set = rule_set(&[(1, "(= tier 1)"), (2, "(= tier 2)")]);
+        let env = env(&mut set, &[("tier", Value::Integer(2))]);
+        assert_eq!(set.matches(&env), vec![2]);
+
+        let expr = parse("(= tier 2)", set.interner_mut()).unwrap();
+        set.add_rule(1, expr);
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.matches(&env), vec![2, 1]);
+
+        assert!(set.remove_rule(2).is_some());
+        assert!(set.remove_rule(2).is_none());
+        assert_eq!(set.matches(&env), vec![1]);
+        assert_eq!(set.len(), 1);
+    }
+}
@@ -0,0 +1,776 @@
+//! Static type checking against declared variable types
+//!
+//! A `Schema` declares the type of every variable rules may read.
+//! `typecheck` infers the type of an expression from it without
+//! evaluating anything, so malformed rules can be rejected when they are
+//! saved: unknown variables and functions, wrong arity, and operands of the
+//! wrong kind such as `(= age "21")` or `(> name 3)`. Unknown names come
+//! with the closest declared variable or builtin as a suggestion, so
+//! `contry` can be reported as a misspelling of `country`.
+//!
+//! Types are checked by kind: numbers (`Integer`, `Float`), text (`String`,
+//! `Symbol`, `Text`), lists and booleans. `null` is accepted anywhere, as
+//! it is by the evaluator.
+
+use crate::compat::FxHashMap;
+use crate::expr::{Arity, ELEMENT};
+use crate::suggest;
+use crate::{BuiltinFunction, Expr, StringId, StringInterner, Value, ValueType};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Declared types of the variables rules may read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    variables: FxHashMap<StringId, ValueType>,
+    /// Values enumerated for variables that take few, for coverage
+    domains: FxHashMap<StringId, Vec<Value>>,
+}
+
+impl Schema {
+    /// Create an empty schema
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the type of a variable, returning the previous type if any
+    pub fn declare(&mut self, name: StringId, ty: ValueType) -> Option<ValueType> {
+        self.variables.insert(name, ty)
+    }
+
+    /// Get the declared type of a variable
+    pub fn get(&self, name: StringId) -> Option<ValueType> {
+        self.variables.get(&name).copied()
+    }
+
+    /// Enumerate the values a variable can take, returning the previous
+    /// domain if any
+    ///
+    /// Domains are used by `RuleSet::coverage` and do not affect type
+    /// checking; the variable's type is declared with `declare`.
+    pub fn declare_domain(&mut self, name: StringId, values: Vec<Value>) -> Option<Vec<Value>> {
+        self.domains.insert(name, values)
+    }
+
+    /// Get the values enumerated for a variable
+    pub fn domain(&self, name: StringId) -> Option<&[Value]> {
+        self.domains.get(&name).map(Vec::as_slice)
+    }
+
+    /// Get the number of declared variables
+    pub fn len(&self) -> usize {
+        self.variables.len()
+    }
+
+    /// Check if no variables are declared
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    /// Iterate over all declarations in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (StringId, ValueType)> + '_ {
+        self.variables.iter().map(|(&name, &ty)| (name, ty))
+    }
+
+    /// Get the declared variable whose name is closest to a misspelled
+    /// `name`, if any is close enough to suggest
+    pub fn suggest(&self, name: StringId, interner: &StringInterner) -> Option<StringId> {
+        let name = interner.resolve(name)?;
+        let closest = suggest::closest(
+            name,
+            self.variables.keys().filter_map(|&id| interner.resolve(id)),
+        )?;
+        interner.get_id(closest)
+    }
+}
+
+impl FromIterator<(StringId, ValueType)> for Schema {
+    fn from_iter<I: IntoIterator<Item = (StringId, ValueType)>>(iter: I) -> Self {
+        Self {
+            variables: iter.into_iter().collect(),
+            domains: FxHashMap::default(),
+        }
+    }
+}
+
+/// Reasons an expression does not typecheck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    /// Variable is not declared in the schema
+    UndeclaredVariable {
+        name: StringId,
+        /// Declared variable with the closest name
+        suggestion: Option<StringId>,
+    },
+    /// Function name does not match any builtin
+    UnknownFunction {
+        name: StringId,
+        /// Builtin with the closest name
+        suggestion: Option<BuiltinFunction>,
+    },
+    /// Builtin called with the wrong number of arguments
+    Arity {
+        function: BuiltinFunction,
+        expected: Arity,
+        found: usize,
+    },
+    /// Argument has a type the builtin cannot operate on
+    Mismatch {
+        function: BuiltinFunction,
+        expected: &'static str,
+        found: ValueType,
+    },
+    /// Operands of a comparison or membership test can never be equal
+    Incomparable {
+        function: BuiltinFunction,
+        left: ValueType,
+        right: ValueType,
+    },
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::UndeclaredVariable { name, suggestion } => {
+                write!(f, "undeclared variable #{}", name.raw())?;
+                match suggestion {
+                    Some(id) => write!(f, ", did you mean #{}?", id.raw()),
+                    None => Ok(()),
+                }
+            }
+            TypeError::UnknownFunction { name, suggestion } => {
+                write!(f, "unknown function #{}", name.raw())?;
+                match suggestion {
+                    Some(builtin) => write!(f, ", did you mean `{}`?", builtin.as_str()),
+                    None => Ok(()),
+                }
+            }
+            TypeError::Arity {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` expects {} argument(s), found {}",
+                function.as_str(),
+                expected,
+                found
+            ),
+            TypeError::Mismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` expects {}, found {:?}",
+                function.as_str(),
+                expected,
+                found
+            ),
+            TypeError::Incomparable {
+                function,
+                left,
+                right,
+            } => write!(
+                f,
+                "`{}` cannot compare {:?} with {:?}",
+                function.as_str(),
+                left,
+                right
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TypeError {}
+
+/// Infer the type of an expression built with `interner`
+///
+/// Builtin calls always produce their result type, although at runtime they
+/// may also produce null.
+pub fn typecheck(
+    expr: &Expr,
+    schema: &Schema,
+    interner: &StringInterner,
+) -> Result<ValueType, TypeError> {
+    match expr {
+        Expr::Literal(value) => Ok(value.value_type()),
+        Expr::Variable(name) => schema
+            .get(*name)
+            .ok_or_else(|| TypeError::UndeclaredVariable {
+                name: *name,
+                suggestion: schema.suggest(*name, interner),
+            }),
+        Expr::List(items) => {
+            let types = items
+                .iter()
+                .map(|item| typecheck(item, schema, interner))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(list_type(&types))
+        }
+        Expr::Call { function, args } => {
+            let builtin = interner.builtin(*function).ok_or_else(|| {
+                let name = interner.resolve(*function);
+                TypeError::UnknownFunction {
+                    name: *function,
+                    suggestion: name.and_then(BuiltinFunction::suggest),
+                }
+            })?;
+            let expected = builtin.arity();
+            if !expected.accepts(args.len()) {
+                return Err(TypeError::Arity {
+                    function: builtin,
+                    expected,
+                    found: args.len(),
+                });
+            }
+            let types = match builtin {
+                // The quoted expression is a value, checked only if evaluated
+                BuiltinFunction::Quote => return Ok(ValueType::Quoted),
+                builtin if builtin.binds_element() => {
+                    // The second argument reads each element as `_`
+                    let list = typecheck(&args[0], schema, interner)?;
+                    let mut scope = schema.clone();
+                    if let Some(element) = interner.get_id(ELEMENT) {
+                        scope.declare(element, element_type(list).unwrap_or(ValueType::Null));
+                    }
+                    vec![list, typecheck(&args[1], &scope, interner)?]
+                }
+                _ => args
+                    .iter()
+                    .map(|arg| typecheck(arg, schema, interner))
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            check_call(builtin, &types)
+        }
+        Expr::Annotated { expr, .. } => typecheck(expr, schema, interner),
+        Expr::Let { bindings, body } => {
+            // Locals are declared with their inferred types, shadowing
+            // attributes of the same name
+            let mut scope = schema.clone();
+            for (name, value) in bindings {
+                let ty = typecheck(value, &scope, interner)?;
+                scope.declare(*name, ty);
+            }
+            typecheck(body, &scope, interner)
+        }
+    }
+}
+
+/// Kind of value, the granularity types are checked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Number,
+    Text,
+    List,
+    Bool,
+    Map,
+    Quoted,
+    Range,
+    Set,
+    Null,
+}
+
+fn kind(ty: ValueType) -> Kind {
+    match ty {
+        ValueType::Integer | ValueType::Float | ValueType::Decimal => Kind::Number,
+        ValueType::Symbol | ValueType::String | ValueType::Text => Kind::Text,
+        ValueType::StringList | ValueType::IntegerList | ValueType::List => Kind::List,
+        ValueType::Bool => Kind::Bool,
+        ValueType::Map => Kind::Map,
+        ValueType::Quoted => Kind::Quoted,
+        ValueType::IntRange => Kind::Range,
+        ValueType::BloomSet => Kind::Set,
+        ValueType::Null => Kind::Null,
+    }
+}
+
+/// Type of a list literal, mirroring how the evaluator builds it
+fn list_type(items: &[ValueType]) -> ValueType {
+    if items
+        .iter()
+        .all(|&ty| matches!(ty, ValueType::String | ValueType::Symbol))
+    {
+        ValueType::StringList
+    } else if items.iter().all(|&ty| ty == ValueType::Integer) {
+        ValueType::IntegerList
+    } else {
+        ValueType::List
+    }
+}
+
+/// Type of the elements of a list type, `None` if they may have any type.
+/// Scalars count as a single-element list
+fn element_type(ty: ValueType) -> Option<ValueType> {
+    match ty {
+        ValueType::StringList => Some(ValueType::String),
+        ValueType::IntegerList => Some(ValueType::Integer),
+        ValueType::List | ValueType::Null => None,
+        scalar => Some(scalar),
+    }
+}
+
+fn expect(function: BuiltinFunction, ty: ValueType, wanted: Kind) -> Result<(), TypeError> {
+    let actual = kind(ty);
+    if actual == wanted || actual == Kind::Null {
+        return Ok(());
+    }
+    let expected = match wanted {
+        Kind::Number => "number",
+        Kind::Text => "string",
+        Kind::List => "list",
+        Kind::Bool => "boolean",
+        Kind::Map => "map",
+        Kind::Quoted => "quoted expression",
+        Kind::Range => "range",
+        Kind::Set => "bloom set",
+        Kind::Null => "null",
+    };
+    Err(TypeError::Mismatch {
+        function,
+        expected,
+        found: ty,
+    })
+}
+
+/// Check that values of two types may compare equal
+fn comparable(
+    function: BuiltinFunction,
+    left: ValueType,
+    right: ValueType,
+) -> Result<(), TypeError> {
+    let (a, b) = (kind(left), kind(right));
+    if a == b || a == Kind::Null || b == Kind::Null {
+        Ok(())
+    } else {
+        Err(TypeError::Incomparable {
+            function,
+            left,
+            right,
+        })
+    }
+}
+
+/// Check that elements of two list types may compare equal
+fn comparable_elements(
+    function: BuiltinFunction,
+    left: ValueType,
+    right: ValueType,
+) -> Result<(), TypeError> {
+    match (element_type(left), element_type(right)) {
+        (Some(a), Some(b)) => comparable(function, a, b),
+        _ => Ok(()),
+    }
+}
+
+fn check_call(function: BuiltinFunction, args: &[ValueType]) -> Result<ValueType, TypeError> {
+    use BuiltinFunction::*;
+
+    let each = |wanted| args.iter().try_for_each(|&ty| expect(function, ty, wanted));
+    match function {
+        And | Or | Not => each(Kind::Bool)?,
+        Exists | IsNull => {}
+        Equal | NotEqual => comparable(function, args[0], args[1])?,
+        LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual => {
+            for &ty in args {
+                if !matches!(
+                    kind(ty),
+                    Kind::Number | Kind::Text | Kind::List | Kind::Null
+                ) {
+                    return Err(TypeError::Mismatch {
+                        function,
+                        expected: "number, string or list",
+                        found: ty,
+                    });
+                }
+            }
+            comparable(function, args[0], args[1])?;
+            comparable_elements(function, args[0], args[1])?;
+        }
+        In | NotIn => {
+            expect(function, args[1], Kind::List)?;
+            if let Some(element) = element_type(args[1]) {
+                comparable(function, args[0], element)?;
+            }
+        }
+        OneOf | NoneOf => {
+            expect(function, args[1], Kind::List)?;
+            comparable_elements(function, args[0], args[1])?;
+        }
+        AllOf => {
+            expect(function, args[0], Kind::List)?;
+            comparable_elements(function, args[0], args[1])?;
+        }
+        GeoWithinRadius | GeoWithinBbox => each(Kind::Number)?,
+        GeoDistance => each(Kind::List)?,
+        GeoCellIn => expect(function, args[1], Kind::List)?,
+        Geohash => each(Kind::Number)?,
+        ExperimentBucket => {
+            if args[0] != ValueType::Integer {
+                expect(function, args[0], Kind::Text)?;
+            }
+            expect(function, args[1], Kind::Text)?;
+            expect(function, args[2], Kind::List)?;
+            expect(function, args[3], Kind::List)?;
+        }
+        GeoWithinPolygon => {
+            expect(function, args[0], Kind::Number)?;
+            expect(function, args[1], Kind::Number)?;
+            expect(function, args[2], Kind::List)?;
+        }
+        StartsWith | EndsWith | Contains | Lowercase | Uppercase | Trim | Concat | StringLength
+        | MatchesRegex | SemverEq | SemverGt | SemverLt | SemverMatches | IpInCidr | IpInRange
+        | EqualFold => each(Kind::Text)?,
+        Substring => {
+            expect(function, args[0], Kind::Text)?;
+            expect(function, args[1], Kind::Number)?;
+            expect(function, args[2], Kind::Number)?;
+        }
+        Get | HasKey => {
+            expect(function, args[0], Kind::Map)?;
+            expect(function, args[1], Kind::Text)?;
+        }
+        Add | Subtract | Multiply => {
+            each(Kind::Number)?;
+            if args.contains(&ValueType::Decimal) && args.contains(&ValueType::Float) {
+                return Err(TypeError::Mismatch {
+                    function,
+                    expected: "integer or decimal",
+                    found: ValueType::Float,
+                });
+            }
+        }
+        Decimal => {
+            if kind(args[0]) != Kind::Text {
+                expect(function, args[0], Kind::Number)?;
+            }
+        }
+        PercentOf => {
+            if args[0] != ValueType::Integer {
+                expect(function, args[0], Kind::Text)?;
+            }
+            expect(function, args[1], Kind::Text)?;
+            expect(function, args[2], Kind::Number)?;
+        }
+        If => {
+            for clause in args.chunks(2) {
+                if let [test, _] = clause {
+                    expect(function, *test, Kind::Bool)?;
+                }
+            }
+        }
+        Count => expect(function, args[0], Kind::List)?,
+        Sum | Avg => {
+            expect(function, args[0], Kind::List)?;
+            if let Some(element) = element_type(args[0]) {
+                expect(function, element, Kind::Number)?;
+            }
+        }
+        MinOf | MaxOf => {
+            expect(function, args[0], Kind::List)?;
+            if let Some(element) = element_type(args[0]) {
+                if !matches!(kind(element), Kind::Number | Kind::Text | Kind::Null) {
+                    return Err(TypeError::Mismatch {
+                        function,
+                        expected: "list of numbers or strings",
+                        found: args[0],
+                    });
+                }
+            }
+        }
+        Any | All | Filter => {
+            expect(function, args[0], Kind::List)?;
+            expect(function, args[1], Kind::Bool)?;
+        }
+        Map => expect(function, args[0], Kind::List)?,
+        Quote => {}
+        Eval => expect(function, args[0], Kind::Quoted)?,
+        IntRange => {
+            for &ty in args {
+                if !matches!(ty, ValueType::Integer | ValueType::Null) {
+                    return Err(TypeError::Mismatch {
+                        function,
+                        expected: "integer",
+                        found: ty,
+                    });
+                }
+            }
+        }
+        InRange => {
+            expect(function, args[0], Kind::Number)?;
+            expect(function, args[1], Kind::Range)?;
+        }
+        RangesOverlap => each(Kind::Range)?,
+        Union | Intersection | Difference | IsSubset | IsDisjoint => each(Kind::List)?,
+        InSet => expect(function, args[1], Kind::Set)?,
+    }
+
+    Ok(match function {
+        Lowercase | Uppercase | Trim | Concat | Substring => ValueType::String,
+        StringLength => ValueType::Integer,
+        Add | Subtract | Multiply => [ValueType::Decimal, ValueType::Float]
+            .into_iter()
+            .find(|ty| args.contains(ty))
+            .unwrap_or(ValueType::Integer),
+        Decimal => ValueType::Decimal,
+        GeoDistance => ValueType::Float,
+        Geohash => ValueType::String,
+        ExperimentBucket => element_type(args[2]).unwrap_or(ValueType::Null),
+        Quote => ValueType::Quoted,
+        // The quoted expression is only known at runtime
+        Eval => ValueType::Null,
+        IntRange => ValueType::IntRange,
+        // Lists of one type keep it, and a mix may hold anything
+        Union if args.iter().all(|&ty| ty == args[0]) => args[0],
+        Union => ValueType::List,
+        // Elements of the first list, so never of a wider type
+        Intersection | Difference => args[0],
+        Count => ValueType::Integer,
+        // Sums of floats or decimals, or a mix, depend on the elements
+        Sum if args[0] == ValueType::IntegerList => ValueType::Integer,
+        Sum => ValueType::Null,
+        Avg => ValueType::Float,
+        MinOf | MaxOf => element_type(args[0]).unwrap_or(ValueType::Null),
+        Filter => args[0],
+        Map => list_type(&args[1..]),
+        // Map entries may have any type, and null checks against every type
+        Get => ValueType::Null,
+        // Branches of different types may produce either
+        If => {
+            let mut values = args
+                .chunks(2)
+                .map(|clause| clause[clause.len() - 1])
+                .filter(|&ty| ty != ValueType::Null);
+            let first = values.next().unwrap_or(ValueType::Null);
+            if values.all(|ty| ty == first) {
+                first
+            } else {
+                ValueType::Null
+            }
+        }
+        _ => ValueType::Bool,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use alloc::string::ToString;
+
+    fn schema(interner: &mut StringInterner) -> Schema {
+        [
+            ("age", ValueType::Integer),
+            ("score", ValueType::Float),
+            ("country", ValueType::String),
+            ("tags", ValueType::StringList),
+            ("vip", ValueType::Bool),
+            ("headers", ValueType::Map),
+        ]
+        .into_iter()
+        .map(|(name, ty)| (interner.intern(name), ty))
+        .collect()
+    }
+
+    fn check(source: &str, interner: &mut StringInterner) -> Result<ValueType, TypeError> {
+        let schema = schema(interner);
+        let expr = parse(source, interner).unwrap();
+        typecheck(&expr, &schema, interner)
+    }
+
+    #[test]
+    fn accepts_well_typed_rules() {
+        let mut interner = StringInterner::new();
+        let cases = [
+            (
+                r#"(and (= country "US") (>= age 21) (< score 0.5) vip)"#,
+                ValueType::Bool,
+            ),
+            (r#"(one-of tags ["news" "tech"])"#, ValueType::Bool),
+            ("(in age [18 21 null])", ValueType::Bool),
+            (r#"(= (lowercase country) 'us)"#, ValueType::Bool),
+            ("(string-length country)", ValueType::Integer),
+            ("(substring country 0 2)", ValueType::String),
+            (r#"["a" 'b]"#, ValueType::StringList),
+            ("[1 2.0]", ValueType::List),
+            ("(or (is-null age) (= age null))", ValueType::Bool),
+            (r#"(has-key headers "x-tenant")"#, ValueType::Bool),
+            (r#"(= (get headers "x-tenant") "acme")"#, ValueType::Bool),
+            (r#"(percent-of age "exp" 12.5)"#, ValueType::Bool),
+            (
+                r#"(experiment-bucket age "exp" ["a" "b"] [1 1])"#,
+                ValueType::String,
+            ),
+            (r#"(semver-matches country ">=2.3.0, <3")"#, ValueType::Bool),
+            (
+                r#"(ip-in-range country "10.0.0.1" "10.0.0.9")"#,
+                ValueType::Bool,
+            ),
+            (
+                "(let ((n (string-length country)) (adult (>= age n))) (and adult vip))",
+                ValueType::Bool,
+            ),
+            ("(let ((age 'unknown)) age)", ValueType::Symbol),
+            (
+                r#"(if vip "gold" (> age 30) "silver" null)"#,
+                ValueType::String,
+            ),
+            ("(if vip 1.5 0)", ValueType::Null),
+            (r#"(any tags (starts-with _ "sports-"))"#, ValueType::Bool),
+            ("(count tags)", ValueType::Integer),
+            ("(max-of tags)", ValueType::String),
+            ("(geo-distance [0 0] [1.5 2])", ValueType::Float),
+            (
+                r#"(geo-cell-in (geohash age age 7) ["u09" "u0d"])"#,
+                ValueType::Bool,
+            ),
+            (r#"(filter (t tags) (!= t "news"))"#, ValueType::StringList),
+            ("(map (t tags) (string-length t))", ValueType::IntegerList),
+            // Undeclared variables are fine until the quote is evaluated
+            ("(quote (> undeclared 1))", ValueType::Quoted),
+            ("(eval (quote (> age 1)))", ValueType::Null),
+            ("(in-range age (int-range 18 24))", ValueType::Bool),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(check(source, &mut interner), Ok(expected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_ill_typed_rules() {
+        let mut interner = StringInterner::new();
+        assert_eq!(
+            check(r#"(= age "21")"#, &mut interner),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::Equal,
+                left: ValueType::Integer,
+                right: ValueType::String,
+            })
+        );
+        assert_eq!(
+            check("(and vip (> country 3))", &mut interner),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::GreaterThan,
+                left: ValueType::String,
+                right: ValueType::Integer,
+            })
+        );
+        assert_eq!(
+            check("(< vip 1)", &mut interner),
+            Err(TypeError::Mismatch {
+                function: BuiltinFunction::LessThan,
+                expected: "number, string or list",
+                found: ValueType::Bool,
+            })
+        );
+        assert_eq!(
+            check("(in age tags)", &mut interner),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::In,
+                left: ValueType::Integer,
+                right: ValueType::String,
+            })
+        );
+        assert_eq!(
+            check("(one-of tags [1 2])", &mut interner),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::OneOf,
+                left: ValueType::String,
+                right: ValueType::Integer,
+            })
+        );
+        assert_eq!(
+            check(
+                r#"(let ((n (string-length country))) (= n "3"))"#,
+                &mut interner
+            ),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::Equal,
+                left: ValueType::Integer,
+                right: ValueType::String,
+            })
+        );
+        assert_eq!(
+            check(r#"(if age "adult" "minor")"#, &mut interner),
+            Err(TypeError::Mismatch {
+                function: BuiltinFunction::If,
+                expected: "boolean",
+                found: ValueType::Integer,
+            })
+        );
+        assert_eq!(
+            check("(all tags (> _ 3))", &mut interner),
+            Err(TypeError::Incomparable {
+                function: BuiltinFunction::GreaterThan,
+                left: ValueType::String,
+                right: ValueType::Integer,
+            })
+        );
+        assert_eq!(
+            check("(sum tags)", &mut interner),
+            Err(TypeError::Mismatch {
+                function: BuiltinFunction::Sum,
+                expected: "number",
+                found: ValueType::String,
+            })
+        );
+        assert_eq!(
+            check(r#"(get tags "x-tenant")"#, &mut interner),
+            Err(TypeError::Mismatch {
+                function: BuiltinFunction::Get,
+                expected: "map",
+                found: ValueType::StringList,
+            })
+        );
+        assert_eq!(
+            check("(and (= agee 1))", &mut interner),
+            Err(TypeError::UndeclaredVariable {
+                name: interner.get_id("agee").unwrap(),
+                suggestion: interner.get_id("age"),
+            })
+        );
+        assert_eq!(
+            check("(= contry \"US\")", &mut interner),
+            Err(TypeError::UndeclaredVariable {
+                name: interner.get_id("contry").unwrap(),
+                suggestion: interner.get_id("country"),
+            })
+        );
+        assert_eq!(
+            check("(= xyzzy 1)", &mut interner),
+            Err(TypeError::UndeclaredVariable {
+                name: interner.get_id("xyzzy").unwrap(),
+                suggestion: None,
+            })
+        );
+        assert_eq!(
+            check("(frob age)", &mut interner),
+            Err(TypeError::UnknownFunction {
+                name: interner.get_id("frob").unwrap(),
+                suggestion: None,
+            })
+        );
+        let error = check(r#"(one_of tags ["news"])"#, &mut interner).unwrap_err();
+        assert_eq!(
+            error,
+            TypeError::UnknownFunction {
+                name: interner.get_id("one_of").unwrap(),
+                suggestion: Some(BuiltinFunction::OneOf),
+            }
+        );
+        assert!(error.to_string().ends_with("did you mean `one-of`?"));
+
+        // Parsing already rejects bad builtin arity, so build the call by hand
+        let schema = schema(&mut interner);
+        let expr = Expr::Call {
+            function: interner.intern("not"),
+            args: vec![],
+        };
+        assert!(matches!(
+            typecheck(&expr, &schema, &interner),
+            Err(TypeError::Arity { found: 0, .. })
+        ));
+    }
+}
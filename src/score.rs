@@ -0,0 +1,167 @@
+//! Numeric scoring expressions
+//!
+//! Expressions whose top level is numeric, such as
+//! `(+ (* 0.4 recency_score) (* 0.6 ctr))`, rank candidates rather than
+//! match them. `Evaluator::eval_score` evaluates one as an `f64`, and a
+//! `ScoreSet` compiles many and returns the `top_k` highest for an
+//! environment, for ranking offers or content.
+//!
+//! Integers and decimals are converted to the nearest `f64`. A result that
+//! is not a number fails with `EvalError::NotNumeric`, including the null
+//! of a missing variable under `MissingVariable::Null`.
+
+use crate::compile::{compile, CompiledExpr};
+use crate::{Environment, EvalError, Expr, RuleId, StringInterner, Value};
+use alloc::vec::Vec;
+
+/// Convert the result of a scoring expression to an `f64`
+pub(crate) fn to_score(value: Value) -> Result<f64, EvalError> {
+    match value {
+        Value::Integer(n) => Ok(n as f64),
+        Value::Float(x) => Ok(x),
+        Value::Decimal(d) => Ok(d.to_f64()),
+        other => Err(EvalError::NotNumeric(other.value_type())),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Score {
+    id: RuleId,
+    expr: Expr,
+    compiled: CompiledExpr,
+}
+
+/// A collection of scoring expressions evaluated together against one
+/// environment
+#[derive(Debug, Default)]
+pub struct ScoreSet {
+    interner: StringInterner,
+    /// Expressions in insertion order
+    scores: Vec<Score>,
+}
+
+impl ScoreSet {
+    /// Create an empty score set with its own interner
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty score set sharing an existing interner
+    pub fn with_interner(interner: StringInterner) -> Self {
+        Self {
+            interner,
+            scores: Vec::new(),
+        }
+    }
+
+    /// Get the interner expressions are built with
+    pub fn interner(&self) -> &StringInterner {
+        &self.interner
+    }
+
+    /// Get the interner mutably, e.g. to parse new expressions
+    pub fn interner_mut(&mut self) -> &mut StringInterner {
+        &mut self.interner
+    }
+
+    /// Add a scoring expression built with this set's interner, replacing
+    /// any expression with the same ID
+    pub fn add(&mut self, id: RuleId, expr: Expr) {
+        self.remove(id);
+        let compiled = compile(&expr, &self.interner);
+        self.scores.push(Score { id, expr, compiled });
+    }
+
+    /// Remove an expression, returning it if it existed
+    pub fn remove(&mut self, id: RuleId) -> Option<Expr> {
+        let position = self.scores.iter().position(|score| score.id == id)?;
+        Some(self.scores.remove(position).expr)
+    }
+
+    /// Get the expression with an ID
+    pub fn get(&self, id: RuleId) -> Option<&Expr> {
+        self.scores
+            .iter()
+            .find(|score| score.id == id)
+            .map(|score| &score.expr)
+    }
+
+    /// Get the number of expressions
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Check if the score set is empty
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Evaluate every expression, in insertion order
+    pub fn scores(&self, env: &Environment) -> Vec<(RuleId, Result<f64, EvalError>)> {
+        self.scores
+            .iter()
+            .map(|score| {
+                let result = score.compiled.eval(env, &self.interner);
+                (score.id, result.and_then(to_score))
+            })
+            .collect()
+    }
+
+    /// Get the `k` highest scores, highest first, with ties in insertion
+    /// order. Expressions that fail to evaluate or score NaN are left out
+    pub fn top_k(&self, env: &Environment, k: usize) -> Vec<(RuleId, f64)> {
+        let mut scores: Vec<(RuleId, f64)> = self
+            .scores(env)
+            .into_iter()
+            .filter_map(|(id, score)| Some((id, score.ok().filter(|x| !x.is_nan())?)))
+            .collect();
+        // Stable, so ties keep insertion order
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scores.truncate(k);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Evaluator};
+
+    #[test]
+    fn ranks_scores() {
+        let mut set = ScoreSet::new();
+        for (id, source) in [
+            (1, "(+ (* 0.4 recency) (* 0.6 ctr))"),
+            (2, "(* 2 recency)"),
+            (3, "ctr"),
+            (4, r#"(concat "not" "a number")"#),
+            (5, "(decimal \"1.5\")"),
+            (6, "recency"),
+        ] {
+            let expr = parse(source, set.interner_mut()).unwrap();
+            set.add(id, expr);
+        }
+        let interner = set.interner_mut();
+        let (recency, ctr) = (interner.intern("recency"), interner.intern("ctr"));
+        let mut env = Environment::new();
+        env.insert(recency, Value::Integer(1));
+        env.insert(ctr, Value::Float(0.5));
+
+        assert_eq!(set.top_k(&env, 3), [(2, 2.0), (5, 1.5), (6, 1.0)]);
+        assert_eq!(set.top_k(&env, 10).len(), 5);
+        let scores = set.scores(&env);
+        assert!(matches!(scores[0], (1, Ok(x)) if (x - 0.7).abs() < 1e-12));
+        assert_eq!(
+            scores[3],
+            (4, Err(EvalError::NotNumeric(crate::ValueType::Text)))
+        );
+
+        let expr = set.remove(2).unwrap();
+        assert_eq!(set.len(), 5);
+        assert_eq!(set.top_k(&env, 1), [(5, 1.5)]);
+        assert_eq!(
+            Evaluator::new(set.interner()).eval_score(&expr, &env),
+            Ok(2.0)
+        );
+    }
+}
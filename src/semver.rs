@@ -0,0 +1,444 @@
+//! Semantic versions for the `semver-*` builtins
+//!
+//! Versions follow Semantic Versioning 2.0.0: `MAJOR.MINOR.PATCH` with an
+//! optional `-prerelease` and `+build`. Build metadata is ignored when
+//! comparing, and a prerelease sorts before its release, so
+//! `1.0.0-beta.2 < 1.0.0-beta.11 < 1.0.0`.
+//!
+//! `semver-matches` takes a range of comma-separated comparators that must
+//! all match, e.g. `>=2.3.0, <3`. Each comparator is an operator followed
+//! by a version whose minor and patch may be left out:
+//!
+//! - `=`, or no operator, matches the components given: `=2.3` is any
+//!   `2.3.x`
+//! - `>`, `>=`, `<` and `<=` compare the components given: `<3` is below
+//!   `3.0.0` and `<=2.3` includes every `2.3.x`
+//! - `~1.2.3` allows patch updates: at least `1.2.3` and below `1.3.0`
+//! - `^1.2.3` allows updates that do not change the leftmost non-zero
+//!   component: at least `1.2.3` and below `2.0.0`, while `^0.2.3` stays
+//!   below `0.3.0`
+//!
+//! Literal versions and ranges are parsed once, when an expression is
+//! compiled or the first time an `Evaluator` reaches them, and cached by
+//! their interned string like literal regex patterns.
+
+use crate::compat::{FxHashMap, RwLock};
+use crate::eval::EvalError;
+use crate::{BuiltinFunction, StringId};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// A parsed semantic version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<Identifier>,
+}
+
+/// Dot-separated part of a prerelease. Numeric identifiers sort before
+/// alphanumeric ones
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+impl Version {
+    pub(crate) fn parse(text: &str) -> Result<Self, EvalError> {
+        let invalid = || EvalError::InvalidVersion(format!("`{}` is not a semantic version", text));
+        let (version, build) = match text.split_once('+') {
+            Some((version, build)) => (version, Some(build)),
+            None => (text, None),
+        };
+        if let Some(build) = build {
+            if !build.split('.').all(is_identifier) {
+                return Err(invalid());
+            }
+        }
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, parse_prerelease(pre).ok_or_else(invalid)?),
+            None => (version, Vec::new()),
+        };
+        let mut parts = core.split('.').map(parse_number);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => Ok(Version {
+                major,
+                minor,
+                patch,
+                pre,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| compare_prerelease(&self.pre, &other.pre))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A release sorts after any of its prereleases
+fn compare_prerelease(a: &[Identifier], b: &[Identifier]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.cmp(b),
+    }
+}
+
+fn parse_prerelease(pre: &str) -> Option<Vec<Identifier>> {
+    pre.split('.')
+        .map(|part| {
+            if !is_identifier(part) {
+                None
+            } else if part.bytes().all(|b| b.is_ascii_digit()) {
+                parse_number(part).map(Identifier::Numeric)
+            } else {
+                Some(Identifier::Alphanumeric(part.to_string()))
+            }
+        })
+        .collect()
+}
+
+fn is_identifier(part: &str) -> bool {
+    !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Parse a numeric component, which may not have leading zeros
+fn parse_number(part: &str) -> Option<u64> {
+    let valid = !part.is_empty()
+        && part.bytes().all(|b| b.is_ascii_digit())
+        && (part == "0" || !part.starts_with('0'));
+    valid.then(|| part.parse().ok()).flatten()
+}
+
+/// Comma-separated comparators that must all match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Range {
+    comparators: Vec<Comparator>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+}
+
+/// An operator and a version that may leave out its minor and patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparator {
+    op: Op,
+    major: u64,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    pre: Vec<Identifier>,
+}
+
+impl Range {
+    pub(crate) fn parse(text: &str) -> Result<Self, EvalError> {
+        let invalid = || EvalError::InvalidVersion(format!("`{}` is not a version range", text));
+        let comparators = text
+            .split(',')
+            .map(|part| Comparator::parse(part.trim()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(Range { comparators })
+    }
+
+    pub(crate) fn matches(&self, version: &Version) -> bool {
+        self.comparators.iter().all(|c| c.matches(version))
+    }
+}
+
+impl Comparator {
+    fn parse(text: &str) -> Option<Self> {
+        let operators = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("~", Op::Tilde),
+            ("^", Op::Caret),
+        ];
+        let (op, version) = operators
+            .iter()
+            .find_map(|&(prefix, op)| Some((op, text.strip_prefix(prefix)?)))
+            .unwrap_or((Op::Exact, text));
+        let version = version.trim_start();
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, parse_prerelease(pre)?),
+            None => (version, Vec::new()),
+        };
+        let mut parts = core.split('.');
+        let major = parse_number(parts.next()?)?;
+        let mut component = || match parts.next() {
+            Some(part) => parse_number(part).map(Some),
+            None => Some(None),
+        };
+        let minor = component()?;
+        let patch = component()?;
+        if parts.next().is_some() || (!pre.is_empty() && patch.is_none()) {
+            return None;
+        }
+        Some(Comparator {
+            op,
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+
+    fn matches(&self, version: &Version) -> bool {
+        let ordering = self.compare(version);
+        match self.op {
+            Op::Exact => ordering == Ordering::Equal,
+            Op::Greater => ordering == Ordering::Greater,
+            Op::GreaterEq => ordering != Ordering::Less,
+            Op::Less => ordering == Ordering::Less,
+            Op::LessEq => ordering != Ordering::Greater,
+            Op::Tilde => {
+                let fixed = if self.minor.is_some() { 2 } else { 1 };
+                ordering != Ordering::Less && self.prefix_equal(version, fixed)
+            }
+            Op::Caret => {
+                let given = [Some(self.major), self.minor, self.patch];
+                let fixed = given
+                    .iter()
+                    .position(|&n| n.is_some_and(|n| n != 0))
+                    .map_or_else(|| given.iter().flatten().count(), |i| i + 1);
+                ordering != Ordering::Less && self.prefix_equal(version, fixed)
+            }
+        }
+    }
+
+    /// Compare `version` with this comparator on the components it gives
+    fn compare(&self, version: &Version) -> Ordering {
+        version
+            .major
+            .cmp(&self.major)
+            .then_with(|| {
+                self.minor
+                    .map_or(Ordering::Equal, |n| version.minor.cmp(&n))
+            })
+            .then_with(|| match self.patch {
+                Some(n) => version
+                    .patch
+                    .cmp(&n)
+                    .then_with(|| compare_prerelease(&version.pre, &self.pre)),
+                None => Ordering::Equal,
+            })
+    }
+
+    /// Check that the first `n` components of `version` match
+    fn prefix_equal(&self, version: &Version, n: usize) -> bool {
+        [Some(self.major), self.minor, self.patch]
+            .into_iter()
+            .zip([version.major, version.minor, version.patch])
+            .take(n)
+            .all(|(given, actual)| given.is_none_or(|given| given == actual))
+    }
+}
+
+/// The literal right-hand side of a `semver-*` call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Operand {
+    Version(Version),
+    Range(Range),
+}
+
+impl Operand {
+    /// Parse the second argument of `function`, a range for
+    /// `semver-matches` and a version otherwise
+    pub(crate) fn parse(function: BuiltinFunction, text: &str) -> Result<Self, EvalError> {
+        if function == BuiltinFunction::SemverMatches {
+            Range::parse(text).map(Operand::Range)
+        } else {
+            Version::parse(text).map(Operand::Version)
+        }
+    }
+
+    /// Check if `version` satisfies `function` against this operand
+    pub(crate) fn test(&self, function: BuiltinFunction, version: &Version) -> bool {
+        match self {
+            Operand::Range(range) => range.matches(version),
+            Operand::Version(other) => {
+                let ordering = version.cmp(other);
+                match function {
+                    BuiltinFunction::SemverGt => ordering == Ordering::Greater,
+                    BuiltinFunction::SemverLt => ordering == Ordering::Less,
+                    _ => ordering == Ordering::Equal,
+                }
+            }
+        }
+    }
+}
+
+/// Parsed literal operands keyed by their interned string and whether
+/// they were parsed as a range
+#[derive(Debug, Default)]
+pub(crate) struct SemverCache {
+    operands: RwLock<FxHashMap<(StringId, bool), Arc<Operand>>>,
+}
+
+impl SemverCache {
+    /// Check if the operand for `id` is cached as `function` parses it
+    pub(crate) fn contains(&self, function: BuiltinFunction, id: StringId) -> bool {
+        let key = (id, function == BuiltinFunction::SemverMatches);
+        self.operands.read().unwrap().contains_key(&key)
+    }
+
+    /// Get the parsed form of an interned operand, parsing it on first use
+    pub(crate) fn get_or_parse(
+        &self,
+        function: BuiltinFunction,
+        id: StringId,
+        text: &str,
+    ) -> Result<Arc<Operand>, EvalError> {
+        let key = (id, function == BuiltinFunction::SemverMatches);
+        if let Some(operand) = self.operands.read().unwrap().get(&key) {
+            return Ok(Arc::clone(operand));
+        }
+        let operand = Arc::new(Operand::parse(function, text)?);
+        self.operands
+            .write()
+            .unwrap()
+            .insert(key, Arc::clone(&operand));
+        Ok(operand)
+    }
+}
+
+impl Clone for SemverCache {
+    fn clone(&self) -> Self {
+        Self {
+            operands: RwLock::new(self.operands.read().unwrap().clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.10.0",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{:?}", pair);
+        }
+        assert_eq!(
+            version("1.2.3+build.5").cmp(&version("1.2.3")),
+            Ordering::Equal
+        );
+
+        for invalid in [
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.x",
+            "1.2.3-",
+            "1.2.3-a..b",
+            "v1.2.3",
+            "",
+        ] {
+            assert!(Version::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let cases = [
+            (
+                ">=2.3.0, <3",
+                &["2.3.0", "2.9.9"][..],
+                &["2.2.9", "3.0.0", "2.3.0-rc.1"][..],
+            ),
+            ("=2.3", &["2.3.0", "2.3.7"], &["2.4.0"]),
+            ("1.2.3", &["1.2.3"], &["1.2.4"]),
+            ("<=2.3", &["2.3.9", "1.0.0"], &["2.4.0"]),
+            (">2", &["3.0.0"], &["2.9.0"]),
+            ("~1.2.3", &["1.2.3", "1.2.9"], &["1.3.0", "1.2.2"]),
+            ("~1", &["1.0.0", "1.9.0"], &["2.0.0"]),
+            ("^1.2.3", &["1.2.3", "1.9.0"], &["2.0.0", "1.2.2"]),
+            ("^0.2.3", &["0.2.3", "0.2.9"], &["0.3.0"]),
+            ("^0.0.3", &["0.0.3"], &["0.0.4"]),
+            ("^0", &["0.9.9"], &["1.0.0"]),
+            (
+                ">=1.0.0-beta.2",
+                &["1.0.0-beta.11", "1.0.0"],
+                &["1.0.0-beta.1"],
+            ),
+        ];
+        for (range, matching, other) in cases {
+            let parsed = Range::parse(range).unwrap();
+            for v in matching {
+                assert!(parsed.matches(&version(v)), "{} should match {}", range, v);
+            }
+            for v in other {
+                assert!(
+                    !parsed.matches(&version(v)),
+                    "{} should not match {}",
+                    range,
+                    v
+                );
+            }
+        }
+
+        for invalid in ["", ">=", "1.2.3.4", ">=1.x", "1.2-beta", ">=1.0.0,"] {
+            assert!(Range::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn caches_by_id() {
+        let cache = SemverCache::default();
+        let id = StringId::new(0);
+        let first = cache
+            .get_or_parse(BuiltinFunction::SemverMatches, id, "^1.2")
+            .unwrap();
+        let second = cache
+            .get_or_parse(BuiltinFunction::SemverMatches, id, "ignored")
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        // The same string is parsed again when used as a version
+        assert!(cache
+            .get_or_parse(BuiltinFunction::SemverGt, id, "^1.2")
+            .is_err());
+    }
+}
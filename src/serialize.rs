@@ -0,0 +1,269 @@
+//! Serde support for expressions and values
+//!
+//! `Expr` and `Value` serialize their `StringId`s as raw integers, which is
+//! compact but only meaningful alongside the interner that produced them.
+//! `SerializableExpr` and `SerializableValue` carry resolved strings instead
+//! so rules can be stored or shipped to a process with a different interner.
+
+use crate::{BloomSet, BuiltinFunction, Decimal, Expr, StringId, StringInterner, Value};
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Builtins serialize as their S-expression name, e.g. `"one-of"`
+impl Serialize for BuiltinFunction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for BuiltinFunction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        BuiltinFunction::from_str(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown builtin function `{}`", name)))
+    }
+}
+
+/// Interner-independent form of `Expr` with all strings resolved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SerializableExpr {
+    Literal(SerializableValue),
+    Variable(String),
+    Call {
+        function: String,
+        args: Vec<SerializableExpr>,
+    },
+    List(Vec<SerializableExpr>),
+    Annotated {
+        metadata: Vec<(String, SerializableValue)>,
+        expr: Box<SerializableExpr>,
+    },
+    Let {
+        bindings: Vec<(String, SerializableExpr)>,
+        body: Box<SerializableExpr>,
+    },
+}
+
+/// Interner-independent form of `Value` with all strings resolved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SerializableValue {
+    Symbol(String),
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Decimal(Decimal),
+    StringList(Vec<String>),
+    IntegerList(Vec<i64>),
+    Bool(bool),
+    List(Vec<SerializableValue>),
+    Null,
+    Map(BTreeMap<String, SerializableValue>),
+    Quoted(Box<SerializableExpr>),
+    IntRange(i64, i64),
+    BloomSet(BloomSet),
+}
+
+impl SerializableExpr {
+    /// Resolve every interned string in `expr`, returning `None` if any ID
+    /// is missing from the interner
+    pub fn from_expr(expr: &Expr, interner: &StringInterner) -> Option<Self> {
+        Some(match expr {
+            Expr::Literal(value) => {
+                SerializableExpr::Literal(SerializableValue::from_value(value, interner)?)
+            }
+            Expr::Variable(id) => SerializableExpr::Variable(resolve(*id, interner)?),
+            Expr::Call { function, args } => SerializableExpr::Call {
+                function: resolve(*function, interner)?,
+                args: args
+                    .iter()
+                    .map(|arg| Self::from_expr(arg, interner))
+                    .collect::<Option<_>>()?,
+            },
+            Expr::List(items) => SerializableExpr::List(
+                items
+                    .iter()
+                    .map(|item| Self::from_expr(item, interner))
+                    .collect::<Option<_>>()?,
+            ),
+            Expr::Annotated { metadata, expr } => SerializableExpr::Annotated {
+                metadata: metadata
+                    .iter()
+                    .map(|(key, value)| {
+                        Some((
+                            resolve(*key, interner)?,
+                            SerializableValue::from_value(value, interner)?,
+                        ))
+                    })
+                    .collect::<Option<_>>()?,
+                expr: Box::new(Self::from_expr(expr, interner)?),
+            },
+            Expr::Let { bindings, body } => SerializableExpr::Let {
+                bindings: bindings
+                    .iter()
+                    .map(|(name, value)| {
+                        Some((resolve(*name, interner)?, Self::from_expr(value, interner)?))
+                    })
+                    .collect::<Option<_>>()?,
+                body: Box::new(Self::from_expr(body, interner)?),
+            },
+        })
+    }
+
+    /// Rebuild an `Expr`, interning strings into `interner`
+    pub fn into_expr(self, interner: &mut StringInterner) -> Expr {
+        match self {
+            SerializableExpr::Literal(value) => Expr::Literal(value.into_value(interner)),
+            SerializableExpr::Variable(name) => Expr::Variable(interner.intern(&name)),
+            SerializableExpr::Call { function, args } => Expr::Call {
+                function: interner.intern(&function),
+                args: args
+                    .into_iter()
+                    .map(|arg| arg.into_expr(interner))
+                    .collect(),
+            },
+            SerializableExpr::List(items) => Expr::List(
+                items
+                    .into_iter()
+                    .map(|item| item.into_expr(interner))
+                    .collect(),
+            ),
+            SerializableExpr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata: metadata
+                    .into_iter()
+                    .map(|(key, value)| (interner.intern(&key), value.into_value(interner)))
+                    .collect(),
+                expr: Box::new(expr.into_expr(interner)),
+            },
+            SerializableExpr::Let { bindings, body } => Expr::Let {
+                bindings: bindings
+                    .into_iter()
+                    .map(|(name, value)| (interner.intern(&name), value.into_expr(interner)))
+                    .collect(),
+                body: Box::new(body.into_expr(interner)),
+            },
+        }
+    }
+}
+
+impl SerializableValue {
+    /// Resolve every interned string in `value`
+    pub fn from_value(value: &Value, interner: &StringInterner) -> Option<Self> {
+        Some(match value {
+            Value::Symbol(id) => SerializableValue::Symbol(resolve(*id, interner)?),
+            Value::String(id) => SerializableValue::String(resolve(*id, interner)?),
+            Value::Integer(n) => SerializableValue::Integer(*n),
+            Value::Float(f) => SerializableValue::Float(*f),
+            Value::Decimal(d) => SerializableValue::Decimal(*d),
+            Value::StringList(ids) => SerializableValue::StringList(
+                ids.iter()
+                    .map(|id| resolve(*id, interner))
+                    .collect::<Option<_>>()?,
+            ),
+            Value::IntegerList(ns) => SerializableValue::IntegerList(ns.to_vec()),
+            Value::Bool(b) => SerializableValue::Bool(*b),
+            Value::List(items) => SerializableValue::List(
+                items
+                    .iter()
+                    .map(|item| Self::from_value(item, interner))
+                    .collect::<Option<_>>()?,
+            ),
+            Value::Null => SerializableValue::Null,
+            Value::Text(s) => SerializableValue::String(s.to_string()),
+            Value::Map(map) => SerializableValue::Map(
+                map.iter()
+                    .map(|(key, value)| {
+                        Some((resolve(*key, interner)?, Self::from_value(value, interner)?))
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            Value::Quoted(expr) => {
+                SerializableValue::Quoted(Box::new(SerializableExpr::from_expr(expr, interner)?))
+            }
+            Value::IntRange(start, end) => SerializableValue::IntRange(*start, *end),
+            Value::BloomSet(set) => SerializableValue::BloomSet(set.clone()),
+        })
+    }
+
+    /// Rebuild a `Value`, interning strings into `interner`
+    pub fn into_value(self, interner: &mut StringInterner) -> Value {
+        match self {
+            SerializableValue::Symbol(s) => Value::Symbol(interner.intern(&s)),
+            SerializableValue::String(s) => Value::String(interner.intern(&s)),
+            SerializableValue::Integer(n) => Value::Integer(n),
+            SerializableValue::Float(f) => Value::Float(f),
+            SerializableValue::Decimal(d) => Value::Decimal(d),
+            SerializableValue::StringList(list) => {
+                Value::StringList(list.iter().map(|s| interner.intern(s)).collect())
+            }
+            SerializableValue::IntegerList(ns) => Value::IntegerList(ns.into()),
+            SerializableValue::Bool(b) => Value::Bool(b),
+            SerializableValue::List(items) => Value::List(
+                items
+                    .into_iter()
+                    .map(|item| item.into_value(interner))
+                    .collect(),
+            ),
+            SerializableValue::Null => Value::Null,
+            SerializableValue::Map(map) => Value::Map(
+                map.into_iter()
+                    .map(|(key, value)| (interner.intern(&key), value.into_value(interner)))
+                    .collect(),
+            ),
+            SerializableValue::Quoted(expr) => Value::Quoted(Box::new(expr.into_expr(interner))),
+            SerializableValue::IntRange(start, end) => Value::IntRange(start, end),
+            SerializableValue::BloomSet(set) => Value::BloomSet(set),
+        }
+    }
+}
+
+fn resolve(id: StringId, interner: &StringInterner) -> Option<String> {
+    interner.resolve(id).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn raw_roundtrip() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(and (= status "active") (in age [18 21]))"#,
+            &mut interner,
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&expr).unwrap();
+        let back: Expr = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, expr);
+
+        assert_eq!(
+            serde_json::to_string(&BuiltinFunction::OneOf).unwrap(),
+            r#""one-of""#
+        );
+        assert!(serde_json::from_str::<BuiltinFunction>(r#""nope""#).is_err());
+    }
+
+    #[test]
+    fn resolved_roundtrip_across_interners() {
+        let mut source = StringInterner::new();
+        let expr = parse(r#"(one-of tags ["a" "b"])"#, &mut source).unwrap();
+        let portable = SerializableExpr::from_expr(&expr, &source).unwrap();
+        let json = serde_json::to_string(&portable).unwrap();
+
+        let mut target = StringInterner::new();
+        target.intern("unrelated");
+        let restored = serde_json::from_str::<SerializableExpr>(&json)
+            .unwrap()
+            .into_expr(&mut target);
+
+        assert_ne!(restored, expr);
+        assert_eq!(
+            SerializableExpr::from_expr(&restored, &target),
+            Some(portable)
+        );
+    }
+}
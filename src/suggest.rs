@@ -0,0 +1,62 @@
+//! "Did you mean" suggestions for misspelled names
+//!
+//! A name is close to a candidate when their edit distance, counting
+//! inserted, deleted and substituted characters, is at most a third of the
+//! name's length (and at least one), so `contry` suggests `country` and
+//! `one_of` suggests `one-of` but `age` does not suggest `tags`.
+
+use alloc::vec::Vec;
+
+/// Find the candidate closest to `name`, if any is close enough. Ties go to
+/// the candidate that sorts first, so the result does not depend on the
+/// order of `candidates`
+pub(crate) fn closest<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between two strings, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` seen so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_close_names() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("contry", "country"), 1);
+
+        let names = ["country", "age", "tags", "count"];
+        assert_eq!(closest("contry", names), Some("country"));
+        assert_eq!(closest("agee", names), Some("age"));
+        assert_eq!(closest("tgs", names), Some("tags"));
+        assert_eq!(closest("xyz", names), None);
+        assert_eq!(closest("age", names), None);
+        assert_eq!(closest("counts", names), Some("count"));
+    }
+}
@@ -0,0 +1,244 @@
+//! Spans for the `tracing` crate
+//!
+//! With the `tracing` feature, parsing, compiling and evaluating each run
+//! inside a span with target `ironwood`, so rules show up in distributed
+//! traces alongside the request that evaluated them:
+//!
+//! - `parse`, with the `length` of the source
+//! - `compile`, with the number of `instructions` emitted
+//! - `eval`, once per evaluation by the tree walker or of a compiled
+//!   expression
+//! - `rule`, with its `rule` id, around each rule a `RuleSet` evaluates
+//!
+//! Spans that can fail end with an `outcome` field, `ok` or `error`, and
+//! the `error` itself when there is one; `rule` spans have the outcome
+//! `match`, `no-match` or `error`. Spans are at the `DEBUG` level, except
+//! `eval` and `rule`, which run per request and are at `TRACE`.
+//!
+//! Without the feature every span here is a unit and costs nothing.
+
+use crate::RuleId;
+use core::fmt::Display;
+
+/// Span entered until dropped
+#[must_use]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+}
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use super::*;
+    use tracing::field::Empty;
+
+    impl Span {
+        fn enter(span: tracing::Span) -> Self {
+            Self {
+                span: span.entered(),
+            }
+        }
+
+        /// Record the outcome of the spanned work
+        pub(crate) fn outcome(&self, outcome: &str) {
+            self.span.record("outcome", outcome);
+        }
+
+        /// Record the outcome of the spanned work, which may have failed
+        pub(crate) fn result<T, E: Display>(&self, result: &Result<T, E>) {
+            match result {
+                Ok(_) => self.outcome("ok"),
+                Err(error) => {
+                    self.outcome("error");
+                    self.span.record("error", tracing::field::display(error));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn parse(length: usize) -> Span {
+        Span::enter(tracing::debug_span!(
+            target: "ironwood",
+            "parse",
+            length,
+            outcome = Empty,
+            error = Empty
+        ))
+    }
+
+    pub(crate) fn compile() -> Span {
+        Span::enter(tracing::debug_span!(
+            target: "ironwood",
+            "compile",
+            instructions = Empty
+        ))
+    }
+
+    /// Record how many instructions a `compile` span emitted
+    pub(crate) fn compiled(span: &Span, instructions: usize) {
+        span.span.record("instructions", instructions);
+    }
+
+    pub(crate) fn eval() -> Span {
+        Span::enter(tracing::trace_span!(
+            target: "ironwood",
+            "eval",
+            outcome = Empty,
+            error = Empty
+        ))
+    }
+
+    pub(crate) fn rule(id: RuleId) -> Span {
+        Span::enter(tracing::trace_span!(
+            target: "ironwood",
+            "rule",
+            rule = id,
+            outcome = Empty,
+            error = Empty
+        ))
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use enabled::*;
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use super::*;
+
+    impl Span {
+        #[inline(always)]
+        pub(crate) fn outcome(&self, _outcome: &str) {}
+
+        #[inline(always)]
+        pub(crate) fn result<T, E: Display>(&self, _result: &Result<T, E>) {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn parse(_length: usize) -> Span {
+        Span {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn compile() -> Span {
+        Span {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn compiled(_span: &Span, _instructions: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn eval() -> Span {
+        Span {}
+    }
+
+    #[inline(always)]
+    pub(crate) fn rule(_id: RuleId) -> Span {
+        Span {}
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::*;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{parse, RuleSet, StringInterner, Value};
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Subscriber logging each span field as `span field=value`
+    #[derive(Default)]
+    struct Recorder {
+        names: Mutex<Vec<&'static str>>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Fields<'r> {
+        span: &'static str,
+        log: &'r Mutex<Vec<String>>,
+    }
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            let entry = format!("{} {}={}", self.span, field.name(), value);
+            self.log.lock().unwrap().push(entry);
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let entry = format!("{} {}={:?}", self.span, field.name(), value);
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "ironwood"
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut names = self.names.lock().unwrap();
+            names.push(span.metadata().name());
+            span.record(&mut Fields {
+                span: span.metadata().name(),
+                log: &self.log,
+            });
+            Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, id: &Id, values: &Record<'_>) {
+            let span = self.names.lock().unwrap()[id.into_u64() as usize - 1];
+            values.record(&mut Fields {
+                span,
+                log: &self.log,
+            });
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn spans_parse_compile_and_rules() {
+        let recorder = Recorder::default();
+        let log = Arc::clone(&recorder.log);
+        tracing::subscriber::with_default(recorder, || {
+            let mut rules = RuleSet::new();
+            let expr = parse("(> x 1)", rules.interner_mut()).unwrap();
+            rules.add_rule(7, expr);
+            let x = rules.interner_mut().intern("x");
+            let mut env = crate::Environment::new();
+            env.insert(x, Value::Integer(2));
+            assert_eq!(rules.matches(&env), [7]);
+
+            assert!(parse("(> x", &mut StringInterner::new()).is_err());
+        });
+
+        let log = log.lock().unwrap();
+        for entry in [
+            "parse length=7",
+            "parse outcome=ok",
+            "rule rule=7",
+            "eval outcome=ok",
+            "rule outcome=match",
+            "parse outcome=error",
+        ] {
+            assert!(
+                log.iter().any(|logged| logged == entry),
+                "{entry} in {log:?}"
+            );
+        }
+        assert!(log
+            .iter()
+            .any(|entry| entry.starts_with("compile instructions=")));
+        assert!(log.iter().any(|entry| entry.starts_with("parse error=")));
+    }
+}
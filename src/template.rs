@@ -0,0 +1,373 @@
+//! Parameterized rules
+//!
+//! A `Template` is an expression with named parameters, written as
+//! `(defmacro in-country (c) (= country c))`. `Template::instantiate`
+//! stamps out a rule with each parameter replaced by a literal value, so
+//! tooling that generates thousands of similar rules never builds rule
+//! text by concatenating strings. `Template::expand` instead replaces calls
+//! such as `(in-country "US")` inside another rule, substituting the
+//! argument expressions. Both happen before evaluation, so the result
+//! compiles and evaluates like a rule written out by hand.
+//!
+//! Parameters are replaced wherever the body reads them, except under a
+//! `let` that binds a local of the same name. An argument expression is
+//! never evaluated under the body's locals: substituting one that reads a
+//! name the body binds with `let` is an error rather than a silent change
+//! of meaning.
+
+use crate::{BuiltinFunction, Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Name of the template definition form
+pub const DEFMACRO: &str = "defmacro";
+
+/// Reasons a template cannot be defined or instantiated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// Form is not `(defmacro name (param ...) body)`, or names a builtin
+    InvalidDefinition,
+    /// Parameter is listed more than once
+    DuplicateParameter(StringId),
+    /// Number of arguments differs from the number of parameters
+    Arity { expected: usize, found: usize },
+    /// Argument reads a variable that a `let` in the body binds as a local
+    Captured { param: StringId, local: StringId },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::InvalidDefinition => {
+                f.write_str("expected `(defmacro name (param ...) body)`")
+            }
+            TemplateError::DuplicateParameter(name) => {
+                write!(f, "duplicate parameter #{}", name.raw())
+            }
+            TemplateError::Arity { expected, found } => write!(
+                f,
+                "template expects {} argument(s), found {}",
+                expected, found
+            ),
+            TemplateError::Captured { param, local } => write!(
+                f,
+                "argument for #{} reads #{}, which the template binds with `let`",
+                param.raw(),
+                local.raw()
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TemplateError {}
+
+/// An expression with named parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    name: StringId,
+    params: Vec<StringId>,
+    body: Expr,
+}
+
+impl Template {
+    /// Create a template whose body reads its parameters as variables
+    pub fn new(name: StringId, params: Vec<StringId>, body: Expr) -> Result<Self, TemplateError> {
+        for (i, param) in params.iter().enumerate() {
+            if params[..i].contains(param) {
+                return Err(TemplateError::DuplicateParameter(*param));
+            }
+        }
+        Ok(Self { name, params, body })
+    }
+
+    /// Create a template from a parsed `(defmacro name (param ...) body)`
+    /// form built with `interner`
+    ///
+    /// The name may not be a builtin's, and there must be at least one
+    /// parameter, since `()` is not an expression.
+    pub fn from_form(form: Expr, interner: &StringInterner) -> Result<Self, TemplateError> {
+        let defmacro = interner.get_id(DEFMACRO);
+        let Expr::Call { function, args } = form else {
+            return Err(TemplateError::InvalidDefinition);
+        };
+        let Ok([name, params, body]) = <[Expr; 3]>::try_from(args) else {
+            return Err(TemplateError::InvalidDefinition);
+        };
+        // `(a b c)` parses as a call to `a`, so the first parameter is its name
+        let (
+            Expr::Variable(name),
+            Expr::Call {
+                function: first,
+                args: rest,
+            },
+        ) = (name, params)
+        else {
+            return Err(TemplateError::InvalidDefinition);
+        };
+        let builtin = interner
+            .resolve(name)
+            .is_some_and(|name| BuiltinFunction::from_str(name).is_some());
+        if Some(function) != defmacro || builtin {
+            return Err(TemplateError::InvalidDefinition);
+        }
+        let mut params = Vec::with_capacity(rest.len() + 1);
+        params.push(first);
+        for param in rest {
+            match param {
+                Expr::Variable(param) => params.push(param),
+                _ => return Err(TemplateError::InvalidDefinition),
+            }
+        }
+        Self::new(name, params, body)
+    }
+
+    /// Get the name calls to the template use
+    pub fn name(&self) -> StringId {
+        self.name
+    }
+
+    /// Get the parameters in order
+    pub fn params(&self) -> &[StringId] {
+        &self.params
+    }
+
+    /// Get the body, with its parameters as variables
+    pub fn body(&self) -> &Expr {
+        &self.body
+    }
+
+    /// Stamp out the body with each parameter replaced by the value in
+    /// the same position
+    pub fn instantiate(&self, values: &[Value]) -> Result<Expr, TemplateError> {
+        let args: Vec<Expr> = values.iter().cloned().map(Expr::Literal).collect();
+        self.apply(&args)
+    }
+
+    /// Stamp out the body with each parameter replaced by the expression
+    /// in the same position
+    pub fn apply(&self, args: &[Expr]) -> Result<Expr, TemplateError> {
+        if args.len() != self.params.len() {
+            return Err(TemplateError::Arity {
+                expected: self.params.len(),
+                found: args.len(),
+            });
+        }
+        self.substitute(self.body.clone(), args, &mut Vec::new())
+    }
+
+    /// Replace every call to the template in `expr` with the body applied
+    /// to the call's arguments
+    ///
+    /// Arguments are expanded before they are substituted. Calls the body
+    /// makes to other templates are left for those templates to expand.
+    pub fn expand(&self, expr: &Expr) -> Result<Expr, TemplateError> {
+        self.expand_owned(expr.clone())
+    }
+
+    fn expand_owned(&self, expr: Expr) -> Result<Expr, TemplateError> {
+        Ok(match expr {
+            Expr::Call { function, args } => {
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.expand_owned(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if function == self.name {
+                    self.apply(&args)?
+                } else {
+                    Expr::Call { function, args }
+                }
+            }
+            Expr::List(items) => Expr::List(
+                items
+                    .into_iter()
+                    .map(|item| self.expand_owned(item))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata,
+                expr: Box::new(self.expand_owned(*expr)?),
+            },
+            Expr::Let { bindings, body } => Expr::Let {
+                bindings: bindings
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, self.expand_owned(value)?)))
+                    .collect::<Result<_, TemplateError>>()?,
+                body: Box::new(self.expand_owned(*body)?),
+            },
+            leaf => leaf,
+        })
+    }
+
+    /// Replace the parameters read in `expr`, which is under the body's
+    /// `locals`
+    fn substitute(
+        &self,
+        expr: Expr,
+        args: &[Expr],
+        locals: &mut Vec<StringId>,
+    ) -> Result<Expr, TemplateError> {
+        Ok(match expr {
+            Expr::Variable(name) if !locals.contains(&name) => {
+                let Some(i) = self.params.iter().position(|&param| param == name) else {
+                    return Ok(expr);
+                };
+                let arg = &args[i];
+                if !locals.is_empty() {
+                    let variables = arg.variables();
+                    if let Some(&local) = locals.iter().find(|&&local| variables.contains(&local)) {
+                        return Err(TemplateError::Captured { param: name, local });
+                    }
+                }
+                arg.clone()
+            }
+            Expr::Call {
+                function,
+                args: call,
+            } => Expr::Call {
+                function,
+                args: call
+                    .into_iter()
+                    .map(|arg| self.substitute(arg, args, locals))
+                    .collect::<Result<_, _>>()?,
+            },
+            Expr::List(items) => Expr::List(
+                items
+                    .into_iter()
+                    .map(|item| self.substitute(item, args, locals))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata,
+                expr: Box::new(self.substitute(*expr, args, locals)?),
+            },
+            Expr::Let { bindings, body } => {
+                let scope = locals.len();
+                let result = self.substitute_let(bindings, *body, args, locals);
+                locals.truncate(scope);
+                result?
+            }
+            leaf => leaf,
+        })
+    }
+
+    fn substitute_let(
+        &self,
+        bindings: Vec<(StringId, Expr)>,
+        body: Expr,
+        args: &[Expr],
+        locals: &mut Vec<StringId>,
+    ) -> Result<Expr, TemplateError> {
+        let mut substituted = Vec::with_capacity(bindings.len());
+        for (name, value) in bindings {
+            substituted.push((name, self.substitute(value, args, locals)?));
+            locals.push(name);
+        }
+        Ok(Expr::Let {
+            bindings: substituted,
+            body: Box::new(self.substitute(body, args, locals)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn template(source: &str, interner: &mut StringInterner) -> Result<Template, TemplateError> {
+        let form = parse(source, interner).unwrap();
+        Template::from_form(form, interner)
+    }
+
+    #[test]
+    fn instantiates() {
+        let mut interner = StringInterner::new();
+        let in_country = template(
+            "(defmacro in-country (c min-age) (and (= country c) (>= age min-age)))",
+            &mut interner,
+        )
+        .unwrap();
+        assert_eq!(in_country.params().len(), 2);
+
+        let us = Value::String(interner.intern("US"));
+        let expected = parse(r#"(and (= country "US") (>= age 21))"#, &mut interner).unwrap();
+        assert_eq!(
+            in_country.instantiate(&[us.clone(), Value::Integer(21)]),
+            Ok(expected)
+        );
+        assert_eq!(
+            in_country.instantiate(&[us]),
+            Err(TemplateError::Arity {
+                expected: 2,
+                found: 1
+            })
+        );
+
+        // A local of the same name shadows the parameter
+        let shadowed = template("(defmacro f (x) (+ x (let ((x 1)) x)))", &mut interner).unwrap();
+        let expected = parse("(+ 5 (let ((x 1)) x))", &mut interner).unwrap();
+        assert_eq!(shadowed.instantiate(&[Value::Integer(5)]), Ok(expected));
+    }
+
+    #[test]
+    fn expands_calls() {
+        let mut interner = StringInterner::new();
+        let in_country =
+            template("(defmacro in-country (c) (= country c))", &mut interner).unwrap();
+        let expr = parse(
+            r#"(or (in-country "US") (and (in-country home) vip))"#,
+            &mut interner,
+        )
+        .unwrap();
+        let expected = parse(
+            r#"(or (= country "US") (and (= country home) vip))"#,
+            &mut interner,
+        )
+        .unwrap();
+        assert_eq!(in_country.expand(&expr), Ok(expected));
+
+        let over = template(
+            "(defmacro over (n) (let ((twice (* limit 2))) (> n twice)))",
+            &mut interner,
+        )
+        .unwrap();
+        let expr = parse("(over (+ price 1))", &mut interner).unwrap();
+        assert!(over.expand(&expr).is_ok());
+        let expr = parse("(over twice)", &mut interner).unwrap();
+        assert_eq!(
+            over.expand(&expr),
+            Err(TemplateError::Captured {
+                param: interner.get_id("n").unwrap(),
+                local: interner.get_id("twice").unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_definitions() {
+        let mut interner = StringInterner::new();
+        for source in [
+            "(defmacro f x (= x 1))",
+            "(defmacro f (x))",
+            "(defmacro \"f\" (x) x)",
+            "(defmacro not (x) x)",
+            "(defmacro f (x 1) x)",
+            "(define f (x) x)",
+        ] {
+            assert_eq!(
+                template(source, &mut interner),
+                Err(TemplateError::InvalidDefinition),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            template("(defmacro f (x y x) x)", &mut interner),
+            Err(TemplateError::DuplicateParameter(
+                interner.get_id("x").unwrap()
+            ))
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Invariants and random expressions for testing
+//!
+//! `check_roundtrip` asserts that an expression survives printing and
+//! parsing, and `check_compile_consistency` that the tree walker and the
+//! compiled form of an expression agree. Both panic with the expression
+//! and the difference, so they can be called from unit tests, property
+//! tests and fuzz targets, including with an application's own
+//! `FunctionRegistry`.
+//!
+//! With the `arbitrary` feature `Expr`, `Value` and `StringId` implement
+//! `arbitrary::Arbitrary`. Their IDs are arbitrary too, so most generated
+//! calls fail at once as unknown functions. `ExprGenerator` instead builds
+//! expressions that call builtins with an accepted number of arguments and
+//! read a fixed set of variables, bounded in depth and width, so fuzz
+//! targets spend their time deep in the parser, evaluator and compiler:
+//!
+//! ```
+//! # #[cfg(feature = "arbitrary")] {
+//! use arbitrary::Unstructured;
+//! use ironwood::testing::{check_compile_consistency, check_roundtrip, ExprGenerator};
+//! use ironwood::StringInterner;
+//!
+//! let mut interner = StringInterner::new();
+//! let generator = ExprGenerator::new(&mut interner);
+//! let mut u = Unstructured::new(&[7, 1, 3, 0, 42, 9, 200, 5, 17, 64]);
+//! let expr = generator.generate(&mut u).unwrap();
+//! let env = generator.environment(&mut u).unwrap();
+//! check_compile_consistency(&expr, &env, &interner);
+//! check_roundtrip(&expr, &mut interner);
+//! # }
+//! ```
+
+use crate::compile::compile_with;
+use crate::print::Format;
+use crate::{parse, Environment, Evaluator, Expr, FunctionRegistry, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+#[cfg(feature = "arbitrary")]
+pub use generator::ExprGenerator;
+
+/// Assert that `expr` prints as text that parses back to the same
+/// expression, in both the compact and indented layouts
+///
+/// List values come back as `Expr::List` of literals and computed text as
+/// interned strings, as the printer documents, and are compared in that
+/// form. Expressions holding values with no source syntax, such as maps or
+/// floats that are not finite, fail the check.
+pub fn check_roundtrip(expr: &Expr, interner: &mut StringInterner) {
+    let expected = source_form(expr, interner);
+    for format in [Format::Compact, Format::indented()] {
+        let text = expr.to_sexpr_with(interner, format);
+        let reparsed = match parse(&text, interner) {
+            Ok(reparsed) => reparsed,
+            Err(error) => panic!("printed expression does not parse: {}\n{}", error, text),
+        };
+        assert_eq!(
+            reparsed, expected,
+            "printed expression parses differently:\n{}",
+            text
+        );
+    }
+}
+
+/// Rewrite literals the way printing and parsing them does
+fn source_form(expr: &Expr, interner: &mut StringInterner) -> Expr {
+    match expr {
+        Expr::Literal(value) => literal_source_form(value, interner),
+        Expr::Variable(_) => expr.clone(),
+        Expr::Call { function, args } => Expr::Call {
+            function: *function,
+            args: args.iter().map(|arg| source_form(arg, interner)).collect(),
+        },
+        Expr::List(items) => Expr::List(
+            items
+                .iter()
+                .map(|item| source_form(item, interner))
+                .collect(),
+        ),
+        Expr::Annotated { metadata, expr } => Expr::Annotated {
+            metadata: metadata.clone(),
+            expr: Box::new(source_form(expr, interner)),
+        },
+        Expr::Let { bindings, body } => Expr::Let {
+            bindings: bindings
+                .iter()
+                .map(|(name, value)| (*name, source_form(value, interner)))
+                .collect(),
+            body: Box::new(source_form(body, interner)),
+        },
+    }
+}
+
+fn literal_source_form(value: &Value, interner: &mut StringInterner) -> Expr {
+    let list = |items: Vec<Value>, interner: &mut StringInterner| {
+        Expr::List(
+            items
+                .iter()
+                .map(|item| literal_source_form(item, interner))
+                .collect(),
+        )
+    };
+    match value {
+        Value::Text(text) => Expr::Literal(Value::String(interner.intern(text))),
+        Value::StringList(ids) => list(ids.iter().map(|&id| Value::String(id)).collect(), interner),
+        Value::IntegerList(ns) => list(ns.iter().map(|&n| Value::Integer(n)).collect(), interner),
+        Value::List(items) => list(items.clone(), interner),
+        _ => Expr::Literal(value.clone()),
+    }
+}
+
+/// Assert that the tree walker and the compiled form of `expr` produce the
+/// same result against `env`
+pub fn check_compile_consistency(expr: &Expr, env: &Environment, interner: &StringInterner) {
+    check_compile_consistency_with(expr, env, interner, &FunctionRegistry::new());
+}
+
+/// Assert that the tree walker and the compiled form of `expr` produce the
+/// same result against `env`, calling custom functions from `functions`
+pub fn check_compile_consistency_with(
+    expr: &Expr,
+    env: &Environment,
+    interner: &StringInterner,
+    functions: &FunctionRegistry,
+) {
+    let walked = Evaluator::new(interner)
+        .with_functions(functions)
+        .eval(expr, env);
+    let compiled = compile_with(expr, interner, functions).eval(env, interner);
+    assert_eq!(
+        walked,
+        compiled,
+        "tree walker and compiled expression disagree on {}",
+        expr.display(interner)
+    );
+}
+
+#[cfg(feature = "arbitrary")]
+mod generator {
+    use crate::compat::FxHashMap;
+    use crate::context::PATH_SEPARATOR;
+    use crate::expr::Arity;
+    use crate::{BuiltinFunction, Environment, Expr, StringId, StringInterner, Value};
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use arbitrary::{Result, Unstructured};
+
+    /// Variables generated expressions read
+    const VARIABLES: &[&str] = &["a", "b", "n", "s", "tags", "user.id", "missing"];
+
+    /// Strings for literals, chosen to be meaningful to the text, version,
+    /// address and pattern builtins
+    const STRINGS: &[&str] = &[
+        "",
+        "a",
+        "US",
+        "hello world",
+        "1.2.3",
+        "^1.2",
+        "10.0.0.1",
+        "10.0.0.0/8",
+        "^h.*d$",
+        "(",
+    ];
+
+    /// Builds random but well-formed expressions from fuzzer input
+    #[derive(Debug, Clone)]
+    pub struct ExprGenerator {
+        /// Deepest nesting of calls and lists, counting the root as 1
+        pub max_depth: usize,
+        /// Most arguments of a variadic call or items of a list
+        pub max_width: usize,
+        functions: Vec<(BuiltinFunction, StringId)>,
+        variables: Vec<StringId>,
+        /// Variables that are plain names, and so may be locals
+        locals: Vec<StringId>,
+        strings: Vec<StringId>,
+    }
+
+    impl ExprGenerator {
+        /// Create a generator with depth 6 and width 4, interning the names it
+        /// uses into `interner`
+        pub fn new(interner: &mut StringInterner) -> Self {
+            Self {
+                max_depth: 6,
+                max_width: 4,
+                functions: BuiltinFunction::ALL
+                    .iter()
+                    .map(|&function| (function, interner.intern(function.as_str())))
+                    .collect(),
+                variables: VARIABLES.iter().map(|name| interner.intern(name)).collect(),
+                locals: VARIABLES
+                    .iter()
+                    .filter(|name| !name.contains(PATH_SEPARATOR))
+                    .map(|name| interner.intern(name))
+                    .collect(),
+                strings: STRINGS.iter().map(|s| interner.intern(s)).collect(),
+            }
+        }
+
+        /// Get the variables generated expressions read
+        pub fn variables(&self) -> &[StringId] {
+            &self.variables
+        }
+
+        /// Generate an expression
+        pub fn generate(&self, u: &mut Unstructured) -> Result<Expr> {
+            self.expr(u, 1)
+        }
+
+        /// Generate an environment binding some of the variables, leaving the
+        /// others unknown
+        pub fn environment(&self, u: &mut Unstructured) -> Result<Environment> {
+            let mut env = Environment::new();
+            for &name in &self.variables {
+                if u.arbitrary()? {
+                    env.insert(name, self.value(u, 2, true)?);
+                }
+            }
+            Ok(env)
+        }
+
+        fn expr(&self, u: &mut Unstructured, depth: usize) -> Result<Expr> {
+            // Leaves only once the input runs out or the depth is reached
+            let kind = if depth >= self.max_depth || u.is_empty() {
+                u.int_in_range(0..=1)?
+            } else {
+                u.int_in_range(0..=6)?
+            };
+            Ok(match kind {
+                0 => Expr::Literal(self.value(u, 1, false)?),
+                1 => Expr::Variable(*u.choose(&self.variables)?),
+                2 => {
+                    let len = u.int_in_range(0..=self.max_width)?;
+                    let items = (0..len)
+                        .map(|_| self.expr(u, depth + 1))
+                        .collect::<Result<_>>()?;
+                    Expr::List(items)
+                }
+                3 => Expr::Annotated {
+                    metadata: vec![(*u.choose(&self.variables)?, self.value(u, 0, false)?)],
+                    expr: Box::new(self.expr(u, depth + 1)?),
+                },
+                // Locals reuse the plain variable names, so they shadow them
+                4 => {
+                    let len = u.int_in_range(1..=2)?;
+                    let bindings = (0..len)
+                        .map(|_| Ok((*u.choose(&self.locals)?, self.expr(u, depth + 1)?)))
+                        .collect::<Result<_>>()?;
+                    Expr::Let {
+                        bindings,
+                        body: Box::new(self.expr(u, depth + 1)?),
+                    }
+                }
+                _ => {
+                    let &(function, name) = u.choose(&self.functions)?;
+                    let len = match function.arity() {
+                        Arity::Exact(n) => n,
+                        Arity::AtLeast(n) => u.int_in_range(n..=n.max(self.max_width))?,
+                    };
+                    let args = (0..len)
+                        .map(|_| self.expr(u, depth + 1))
+                        .collect::<Result<_>>()?;
+                    Expr::Call {
+                        function: name,
+                        args,
+                    }
+                }
+            })
+        }
+
+        /// Generate a value, nesting lists and maps up to `depth` levels.
+        /// Maps and non-finite floats have no literal syntax, so only bindings
+        /// may hold them
+        fn value(&self, u: &mut Unstructured, depth: usize, binding: bool) -> Result<Value> {
+            let floats: &[f64] = if binding {
+                &[0.0, -0.5, 1.5, 21.0, f64::INFINITY, f64::NAN]
+            } else {
+                &[0.0, -0.5, 1.5, 21.0]
+            };
+            let kinds = match (depth, binding) {
+                (0, _) => 7,
+                (_, false) => 8,
+                (_, true) => 9,
+            };
+            Ok(match u.int_in_range(0..=kinds)? {
+                0 => Value::Null,
+                1 => Value::Bool(u.arbitrary()?),
+                2 => Value::Integer(*u.choose(&[0, 1, -1, 2, 21, 100, i64::MAX, i64::MIN])?),
+                3 => Value::Integer(u.arbitrary()?),
+                4 => Value::Float(*u.choose(floats)?),
+                5 => Value::String(*u.choose(&self.strings)?),
+                6 => Value::Symbol(*u.choose(&self.variables)?),
+                7 => {
+                    let len = u.int_in_range(0..=self.max_width)?;
+                    Value::StringList(
+                        (0..len)
+                            .map(|_| u.choose(&self.strings).copied())
+                            .collect::<Result<_>>()?,
+                    )
+                }
+                8 => {
+                    let len = u.int_in_range(0..=self.max_width)?;
+                    Value::List(
+                        (0..len)
+                            .map(|_| self.value(u, depth - 1, binding))
+                            .collect::<Result<_>>()?,
+                    )
+                }
+                _ => {
+                    let len = u.int_in_range(0..=self.max_width)?;
+                    let mut map = FxHashMap::default();
+                    for _ in 0..len {
+                        map.insert(
+                            *u.choose(&self.strings)?,
+                            self.value(u, depth - 1, binding)?,
+                        );
+                    }
+                    Value::Map(map)
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_invariants() {
+        let mut interner = StringInterner::new();
+        let mut functions = FunctionRegistry::new();
+        functions.register("double", |args: &[Value]| match args {
+            [Value::Integer(n)] => Ok(Value::Integer(n * 2)),
+            _ => Ok(Value::Null),
+        });
+        let mut env = Environment::new();
+        env.insert(interner.intern("n"), Value::Integer(21));
+
+        for source in [
+            r#"(and (>= n 18) (in "US" ["US" "CA"]) (meta (id 7) (= (double n) 42)))"#,
+            "[1 [2.5 'a] null (or)]",
+            "(concat \"a\\\"b\" (substring \"hello\" 1 3))",
+        ] {
+            let expr = parse(source, &mut interner).unwrap();
+            check_roundtrip(&expr, &mut interner);
+            check_compile_consistency_with(&expr, &env, &interner, &functions);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "parses differently")]
+    fn rejects_unprintable_values() {
+        let mut interner = StringInterner::new();
+        let expr = Expr::Literal(Value::Float(f64::INFINITY));
+        check_roundtrip(&expr, &mut interner);
+    }
+
+    #[cfg(feature = "arbitrary")]
+    mod generated {
+        use super::super::*;
+        use crate::compile;
+        use arbitrary::Unstructured;
+
+        /// Deterministic fuzzer input
+        fn bytes(seed: u64, len: usize) -> Vec<u8> {
+            let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        }
+
+        #[test]
+        fn evaluators_agree() {
+            let mut interner = StringInterner::new();
+            let generator = ExprGenerator::new(&mut interner);
+            for seed in 0..500 {
+                let data = bytes(seed, 256);
+                let mut u = Unstructured::new(&data);
+                let expr = generator.generate(&mut u).unwrap();
+                let env = generator.environment(&mut u).unwrap();
+
+                check_compile_consistency(&expr, &env, &interner);
+                check_roundtrip(&expr, &mut interner);
+            }
+        }
+
+        #[test]
+        fn arbitrary_exprs_do_not_panic() {
+            let interner = StringInterner::new();
+            for seed in 0..200 {
+                let data = bytes(seed, 128);
+                let Ok(expr) = Unstructured::new(&data).arbitrary::<Expr>() else {
+                    continue;
+                };
+                let _ = Evaluator::new(&interner).eval(&expr, &Environment::new());
+                let _ = compile(&expr, &interner).eval(&Environment::new(), &interner);
+            }
+        }
+    }
+}
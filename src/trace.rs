@@ -0,0 +1,207 @@
+//! Evaluation traces
+//!
+//! `Evaluator::eval_with_trace` records every subexpression the tree
+//! walker evaluates, with its result and how long it took, to answer why an
+//! expression produced the value it did. A node's children are the
+//! operands that were actually evaluated, in order, so their results are
+//! the node's inputs. Operands skipped by short-circuiting `and`/`or` do
+//! not appear.
+//!
+//! `wasm32-unknown-unknown` has no clock, so traces recorded there report
+//! every elapsed time as zero.
+
+use crate::eval::{EvalError, Evaluator, Observer};
+use crate::{Environment, Expr, StringInterner, Value};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// One evaluated subexpression and the subexpressions it evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace<'e> {
+    expr: &'e Expr,
+    result: Result<Value, EvalError>,
+    elapsed: Duration,
+    children: Vec<Trace<'e>>,
+}
+
+impl<'e> Trace<'e> {
+    /// Get the subexpression this node evaluated
+    pub fn expr(&self) -> &'e Expr {
+        self.expr
+    }
+
+    /// Get the result of the subexpression
+    pub fn result(&self) -> &Result<Value, EvalError> {
+        &self.result
+    }
+
+    /// Get the time spent evaluating the subexpression, including its
+    /// children
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Get the traces of the operands this subexpression evaluated
+    pub fn children(&self) -> &[Trace<'e>] {
+        &self.children
+    }
+
+    /// Render the trace as an indented tree, one subexpression per line
+    ///
+    /// ```text
+    /// (and (>= age 21) (= country "US")) => false
+    ///   (>= age 21) => true
+    ///     age => 30
+    ///     21 => 21
+    ///   (= country "US") => false
+    /// ```
+    pub fn render(&self, interner: &StringInterner) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out, interner, 0);
+        out
+    }
+
+    fn render_into(&self, out: &mut String, interner: &StringInterner, depth: usize) {
+        out.extend(std::iter::repeat_n(' ', depth * 2));
+        let _ = match &self.result {
+            Ok(value) => writeln!(
+                out,
+                "{} => {}",
+                self.expr.display(interner),
+                value.display(interner)
+            ),
+            Err(error) => writeln!(out, "{} => error: {}", self.expr.display(interner), error),
+        };
+        for child in &self.children {
+            child.render_into(out, interner, depth + 1);
+        }
+    }
+}
+
+impl Evaluator<'_> {
+    /// Evaluate an expression, recording a trace of every subexpression
+    /// that was evaluated
+    ///
+    /// The result is the same as `eval`. Tracing times each node, so it is
+    /// much slower than plain evaluation and meant for explaining results
+    /// rather than serving them.
+    pub fn eval_with_trace<'e>(
+        &self,
+        expr: &'e Expr,
+        env: &Environment,
+    ) -> (Result<Value, EvalError>, Trace<'e>) {
+        let mut recorder = Recorder {
+            frames: Vec::new(),
+            root: None,
+        };
+        let result = self.eval_observed(expr, env, &mut recorder);
+        let trace = recorder.root.expect("root expression was traced");
+        (result, trace)
+    }
+}
+
+/// Builds a `Trace` from observer callbacks
+struct Recorder<'e> {
+    /// One frame per subexpression being evaluated, innermost last
+    frames: Vec<Frame<'e>>,
+    root: Option<Trace<'e>>,
+}
+
+struct Frame<'e> {
+    /// When evaluation started, `None` without a clock
+    start: Option<Instant>,
+    children: Vec<Trace<'e>>,
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn now() -> Option<Instant> {
+    Some(Instant::now())
+}
+
+/// `Instant::now` panics on targets without a clock
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn now() -> Option<Instant> {
+    None
+}
+
+impl<'e> Observer<'e> for Recorder<'e> {
+    fn enter(&mut self) {
+        self.frames.push(Frame {
+            start: now(),
+            children: Vec::new(),
+        });
+    }
+
+    fn exit(&mut self, expr: &'e Expr, result: &Result<Value, EvalError>) {
+        let frame = self.frames.pop().expect("exit matches enter");
+        let node = Trace {
+            expr,
+            result: result.clone(),
+            elapsed: frame.start.map_or(Duration::ZERO, |start| start.elapsed()),
+            children: frame.children,
+        };
+        match self.frames.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => self.root = Some(node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn explains_result() {
+        let mut interner = StringInterner::new();
+        let mut env = Environment::new();
+        env.insert(interner.intern("age"), Value::Integer(30));
+        env.insert(
+            interner.intern("country"),
+            Value::String(interner.intern("CA")),
+        );
+        let expr = parse(
+            r#"(and (>= age 21) (= country "US") (expensive age))"#,
+            &mut interner,
+        )
+        .unwrap();
+
+        let evaluator = Evaluator::new(&interner);
+        let (result, trace) = evaluator.eval_with_trace(&expr, &env);
+        assert_eq!(result, Ok(Value::Bool(false)));
+        assert_eq!(result, evaluator.eval(&expr, &env));
+        assert_eq!(trace.result(), &result);
+        assert_eq!(trace.expr(), &expr);
+        // The custom call is skipped once `=` decides the result
+        assert_eq!(trace.children().len(), 2);
+
+        let expected = r#"(and (>= age 21) (= country "US") (expensive age)) => false
+  (>= age 21) => true
+    age => 30
+    21 => 21
+  (= country "US") => false
+    country => "CA"
+    "US" => "US"
+"#;
+        assert_eq!(trace.render(&interner), expected);
+    }
+
+    #[test]
+    fn records_errors() {
+        let mut interner = StringInterner::new();
+        let expr = parse("(or (exists tier) (> tier 3))", &mut interner).unwrap();
+        let evaluator = Evaluator::new(&interner);
+        let (result, trace) = evaluator.eval_with_trace(&expr, &Environment::new());
+
+        let tier = interner.get_id("tier").unwrap();
+        assert_eq!(result, Err(EvalError::UnknownVariable(tier)));
+        let [exists, compare] = trace.children() else {
+            panic!("expected two operands");
+        };
+        assert_eq!(exists.children()[0].result(), &Ok(Value::Null));
+        assert_eq!(exists.result(), &Ok(Value::Bool(false)));
+        assert_eq!(compare.children().len(), 1);
+        assert_eq!(compare.result(), &result);
+    }
+}
@@ -1,10 +1,17 @@
 //! Value types for Ironwood S-expression engine
 
-use crate::StringId;
+use crate::{BloomSet, Expr, StringId};
+use crate::decimal::Decimal;
+use crate::compat::FxHashMap;
+use crate::list::SmallList;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 
 /// Core value types that can be stored and evaluated
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum Value {
     /// Interned symbol identifier
     Symbol(StringId),
@@ -15,9 +22,27 @@ pub enum Value {
     /// Float value
     Float(f64),
     /// List of strings
-    StringList(Vec<StringId>),
+    StringList(SmallList<StringId>),
     /// List of integers
-    IntegerList(Vec<i64>),
+    IntegerList(SmallList<i64>),
+    /// Boolean value
+    Bool(bool),
+    /// List of arbitrary values
+    List(Vec<Value>),
+    /// Absent value
+    Null,
+    /// Text computed during evaluation that is not in the interner
+    Text(Box<str>),
+    /// Map from interned keys to values, e.g. a header map
+    Map(FxHashMap<StringId, Value>),
+    /// Exact decimal number, e.g. a price
+    Decimal(Decimal),
+    /// Expression produced by `quote` instead of being evaluated
+    Quoted(Box<Expr>),
+    /// Integers from the first to the second inclusive, e.g. an age band
+    IntRange(i64, i64),
+    /// Probabilistic set of strings and integers, e.g. a huge audience
+    BloomSet(BloomSet),
 }
 
 /// Manual PartialEq implementation to handle float comparison
@@ -30,6 +55,15 @@ impl PartialEq for Value {
             (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
             (Value::StringList(a), Value::StringList(b)) => a == b,
             (Value::IntegerList(a), Value::IntegerList(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            (Value::Text(a), Value::Text(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Decimal(a), Value::Decimal(b)) => a == b,
+            (Value::Quoted(a), Value::Quoted(b)) => a == b,
+            (Value::IntRange(a, b), Value::IntRange(c, d)) => (a, b) == (c, d),
+            (Value::BloomSet(a), Value::BloomSet(b)) => a == b,
             _ => false,
         }
     }
@@ -37,8 +71,8 @@ impl PartialEq for Value {
 
 impl Eq for Value {}
 
-impl std::hash::Hash for Value {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+impl core::hash::Hash for Value {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         match self {
             Value::Symbol(id) => {
                 0u8.hash(state);
@@ -64,12 +98,50 @@ impl std::hash::Hash for Value {
                 5u8.hash(state);
                 list.hash(state);
             }
+            Value::Bool(b) => {
+                6u8.hash(state);
+                b.hash(state);
+            }
+            Value::List(list) => {
+                7u8.hash(state);
+                list.hash(state);
+            }
+            Value::Null => 8u8.hash(state),
+            Value::Text(s) => {
+                9u8.hash(state);
+                s.hash(state);
+            }
+            Value::Map(map) => {
+                10u8.hash(state);
+                // Iteration order is arbitrary, so hash the entries sorted
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_unstable_by_key(|(key, _)| **key);
+                entries.hash(state);
+            }
+            Value::Decimal(d) => {
+                11u8.hash(state);
+                d.hash(state);
+            }
+            Value::Quoted(expr) => {
+                12u8.hash(state);
+                expr.hash(state);
+            }
+            Value::IntRange(start, end) => {
+                13u8.hash(state);
+                start.hash(state);
+                end.hash(state);
+            }
+            Value::BloomSet(set) => {
+                14u8.hash(state);
+                set.hash(state);
+            }
         }
     }
 }
 
 /// Value type enumeration for type checking and domain validation
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ValueType {
     Symbol,
     String,
@@ -77,6 +149,15 @@ pub enum ValueType {
     Float,
     StringList,
     IntegerList,
+    Bool,
+    List,
+    Null,
+    Text,
+    Map,
+    Decimal,
+    Quoted,
+    IntRange,
+    BloomSet,
 }
 
 impl Value {
@@ -89,6 +170,15 @@ impl Value {
             Value::Float(_) => ValueType::Float,
             Value::StringList(_) => ValueType::StringList,
             Value::IntegerList(_) => ValueType::IntegerList,
+            Value::Bool(_) => ValueType::Bool,
+            Value::List(_) => ValueType::List,
+            Value::Null => ValueType::Null,
+            Value::Text(_) => ValueType::Text,
+            Value::Map(_) => ValueType::Map,
+            Value::Decimal(_) => ValueType::Decimal,
+            Value::Quoted(_) => ValueType::Quoted,
+            Value::IntRange(..) => ValueType::IntRange,
+            Value::BloomSet(_) => ValueType::BloomSet,
         }
     }
 
@@ -122,6 +212,51 @@ impl Value {
         matches!(self, Value::IntegerList(_))
     }
 
+    /// Check if value is a boolean
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Value::Bool(_))
+    }
+
+    /// Check if value is a general list
+    pub fn is_list(&self) -> bool {
+        matches!(self, Value::List(_))
+    }
+
+    /// Check if value is null
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Check if value is uninterned text
+    pub fn is_text(&self) -> bool {
+        matches!(self, Value::Text(_))
+    }
+
+    /// Check if value is a map
+    pub fn is_map(&self) -> bool {
+        matches!(self, Value::Map(_))
+    }
+
+    /// Check if value is a decimal
+    pub fn is_decimal(&self) -> bool {
+        matches!(self, Value::Decimal(_))
+    }
+
+    /// Check if value is a quoted expression
+    pub fn is_quoted(&self) -> bool {
+        matches!(self, Value::Quoted(_))
+    }
+
+    /// Check if value is an integer range
+    pub fn is_int_range(&self) -> bool {
+        matches!(self, Value::IntRange(..))
+    }
+
+    /// Check if value is a bloom set
+    pub fn is_bloom_set(&self) -> bool {
+        matches!(self, Value::BloomSet(_))
+    }
+
     /// Try to get symbol ID
     pub fn as_symbol(&self) -> Option<StringId> {
         match self {
@@ -155,7 +290,7 @@ impl Value {
     }
 
     /// Try to get string list
-    pub fn as_string_list(&self) -> Option<&Vec<StringId>> {
+    pub fn as_string_list(&self) -> Option<&[StringId]> {
         match self {
             Value::StringList(list) => Some(list),
             _ => None,
@@ -163,17 +298,82 @@ impl Value {
     }
 
     /// Try to get integer list
-    pub fn as_integer_list(&self) -> Option<&Vec<i64>> {
+    pub fn as_integer_list(&self) -> Option<&[i64]> {
         match self {
             Value::IntegerList(list) => Some(list),
             _ => None,
         }
     }
+
+    /// Try to get boolean value
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Try to get general list
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    /// Try to get uninterned text
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Try to get map
+    pub fn as_map(&self) -> Option<&FxHashMap<StringId, Value>> {
+        match self {
+            Value::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    /// Get decimal value if this is a decimal
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            Value::Decimal(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// Get the expression if this is a quoted expression
+    pub fn as_quoted(&self) -> Option<&Expr> {
+        match self {
+            Value::Quoted(expr) => Some(expr),
+            _ => None,
+        }
+    }
+
+    /// Get the inclusive bounds if this is an integer range
+    pub fn as_int_range(&self) -> Option<(i64, i64)> {
+        match self {
+            Value::IntRange(start, end) => Some((*start, *end)),
+            _ => None,
+        }
+    }
+
+    /// Get the set if this is a bloom set
+    pub fn as_bloom_set(&self) -> Option<&BloomSet> {
+        match self {
+            Value::BloomSet(set) => Some(set),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec;
 
     #[test]
     fn test_value_types() {
@@ -205,16 +405,65 @@ mod tests {
 
         // String list
         let sl = vec![StringId::new(0), StringId::new(1)];
-        let string_list = Value::StringList(sl.clone());
+        let string_list = Value::StringList(sl.clone().into());
         assert_eq!(string_list.value_type(), ValueType::StringList);
         assert!(string_list.is_string_list());
-        assert_eq!(string_list.as_string_list(), Some(&sl));
+        assert_eq!(string_list.as_string_list(), Some(&sl[..]));
 
         // Integer list
         let il = vec![1, 2, 3];
-        let int_list = Value::IntegerList(il.clone());
+        let int_list = Value::IntegerList(il.clone().into());
         assert_eq!(int_list.value_type(), ValueType::IntegerList);
         assert!(int_list.is_integer_list());
-        assert_eq!(int_list.as_integer_list(), Some(&il));
+        assert_eq!(int_list.as_integer_list(), Some(&il[..]));
+
+        // Bool
+        let b = Value::Bool(true);
+        assert_eq!(b.value_type(), ValueType::Bool);
+        assert!(b.is_bool());
+        assert!(!b.is_integer());
+        assert_eq!(b.as_bool(), Some(true));
+        assert_eq!(i.as_bool(), None);
+        assert_ne!(Value::Bool(true), Value::Integer(1));
+
+        // General list
+        let items = vec![Value::Integer(1), Value::Bool(false)];
+        let list = Value::List(items.clone());
+        assert_eq!(list.value_type(), ValueType::List);
+        assert!(list.is_list());
+        assert!(!list.is_integer_list());
+        assert_eq!(list.as_list(), Some(&items));
+        assert_ne!(Value::List(vec![Value::Integer(1)]), Value::IntegerList(vec![1].into()));
+
+        // Null
+        assert_eq!(Value::Null.value_type(), ValueType::Null);
+        assert!(Value::Null.is_null());
+        assert!(!b.is_null());
+        assert_eq!(Value::Null, Value::Null);
+
+        // Text
+        let text = Value::Text("computed".into());
+        assert_eq!(text.value_type(), ValueType::Text);
+        assert!(text.is_text());
+        assert!(!text.is_string());
+        assert_eq!(text.as_text(), Some("computed"));
+
+        // Map
+        let pairs = [
+            (StringId::new(0), Value::Integer(1)),
+            (StringId::new(1), Value::Null),
+        ];
+        let entries: FxHashMap<_, _> = pairs.clone().into_iter().collect();
+        let map = Value::Map(entries.clone());
+        assert_eq!(map.value_type(), ValueType::Map);
+        assert!(map.is_map());
+        assert_eq!(map.as_map(), Some(&entries));
+        let reversed = Value::Map(pairs.into_iter().rev().collect());
+        assert_eq!(map, reversed);
+        let hash = |value: &Value| {
+            use core::hash::{BuildHasher, BuildHasherDefault};
+            BuildHasherDefault::<rustc_hash::FxHasher>::default().hash_one(value)
+        };
+        assert_eq!(hash(&map), hash(&reversed));
     }
 }
\ No newline at end of file
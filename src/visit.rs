@@ -0,0 +1,333 @@
+//! Traversal of expression trees
+//!
+//! `ExprVisitor` walks an expression by reference and `ExprFolder` rebuilds
+//! one by value. Every method has a default that recurses into children, so
+//! implementations override only the nodes they care about and call
+//! `walk_expr` or `fold_expr` (or the default body) to keep descending.
+//! `Expr::walk` and `Expr::map` cover the common cases with a closure.
+
+use crate::compat::HashSet;
+use crate::{Expr, StringId, StringInterner, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Read-only traversal of an expression
+pub trait ExprVisitor {
+    /// Visit any expression, dispatching on its kind
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+
+    /// Visit a literal value
+    fn visit_literal(&mut self, _value: &Value) {}
+
+    /// Visit a variable reference
+    fn visit_variable(&mut self, _name: StringId) {}
+
+    /// Visit a function call, then its arguments
+    fn visit_call(&mut self, _function: StringId, args: &[Expr]) {
+        for arg in args {
+            self.visit_expr(arg);
+        }
+    }
+
+    /// Visit a list literal, then its items
+    fn visit_list(&mut self, items: &[Expr]) {
+        for item in items {
+            self.visit_expr(item);
+        }
+    }
+
+    /// Visit an annotated expression, then the expression
+    fn visit_annotated(&mut self, _metadata: &[(StringId, Value)], expr: &Expr) {
+        self.visit_expr(expr);
+    }
+
+    /// Visit a `let`, then each bound expression and the body
+    fn visit_let(&mut self, bindings: &[(StringId, Expr)], body: &Expr) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        self.visit_expr(body);
+    }
+}
+
+/// Dispatch `expr` to the matching `ExprVisitor` method
+pub fn walk_expr<V: ExprVisitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Literal(value) => visitor.visit_literal(value),
+        Expr::Variable(name) => visitor.visit_variable(*name),
+        Expr::Call { function, args } => visitor.visit_call(*function, args),
+        Expr::List(items) => visitor.visit_list(items),
+        Expr::Annotated { metadata, expr } => visitor.visit_annotated(metadata, expr),
+        Expr::Let { bindings, body } => visitor.visit_let(bindings, body),
+    }
+}
+
+/// Rebuilding traversal of an expression
+pub trait ExprFolder {
+    /// Fold any expression, dispatching on its kind
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr(self, expr)
+    }
+
+    /// Fold a literal value
+    fn fold_literal(&mut self, value: Value) -> Expr {
+        Expr::Literal(value)
+    }
+
+    /// Fold a variable reference
+    fn fold_variable(&mut self, name: StringId) -> Expr {
+        Expr::Variable(name)
+    }
+
+    /// Fold a function call after folding its arguments
+    fn fold_call(&mut self, function: StringId, args: Vec<Expr>) -> Expr {
+        let args = args.into_iter().map(|arg| self.fold_expr(arg)).collect();
+        Expr::Call { function, args }
+    }
+
+    /// Fold a list literal after folding its items
+    fn fold_list(&mut self, items: Vec<Expr>) -> Expr {
+        Expr::List(items.into_iter().map(|item| self.fold_expr(item)).collect())
+    }
+
+    /// Fold an annotated expression after folding the expression, keeping
+    /// its metadata
+    fn fold_annotated(&mut self, metadata: Vec<(StringId, Value)>, expr: Expr) -> Expr {
+        Expr::Annotated {
+            metadata,
+            expr: Box::new(self.fold_expr(expr)),
+        }
+    }
+
+    /// Fold a `let` after folding its bound expressions and body
+    fn fold_let(&mut self, bindings: Vec<(StringId, Expr)>, body: Expr) -> Expr {
+        Expr::Let {
+            bindings: bindings
+                .into_iter()
+                .map(|(name, value)| (name, self.fold_expr(value)))
+                .collect(),
+            body: Box::new(self.fold_expr(body)),
+        }
+    }
+}
+
+/// Dispatch `expr` to the matching `ExprFolder` method
+pub fn fold_expr<F: ExprFolder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::Literal(value) => folder.fold_literal(value),
+        Expr::Variable(name) => folder.fold_variable(name),
+        Expr::Call { function, args } => folder.fold_call(function, args),
+        Expr::List(items) => folder.fold_list(items),
+        Expr::Annotated { metadata, expr } => folder.fold_annotated(metadata, *expr),
+        Expr::Let { bindings, body } => folder.fold_let(bindings, *body),
+    }
+}
+
+impl Expr {
+    /// Call `f` on this expression and every subexpression, parents before
+    /// their children
+    pub fn walk<F: FnMut(&Expr)>(&self, mut f: F) {
+        self.walk_with(&mut f);
+    }
+
+    fn walk_with<F: FnMut(&Expr)>(&self, f: &mut F) {
+        f(self);
+        match self {
+            Expr::Call { args: children, .. } | Expr::List(children) => {
+                for child in children {
+                    child.walk_with(f);
+                }
+            }
+            Expr::Annotated { expr, .. } => expr.walk_with(f),
+            Expr::Let { bindings, body } => {
+                for (_, value) in bindings {
+                    value.walk_with(f);
+                }
+                body.walk_with(f);
+            }
+            _ => {}
+        }
+    }
+
+    /// Rebuild this expression bottom-up, replacing every node with the
+    /// result of `f` after its children have been replaced
+    pub fn map<F: FnMut(Expr) -> Expr>(self, mut f: F) -> Expr {
+        self.map_with(&mut f)
+    }
+
+    fn map_with<F: FnMut(Expr) -> Expr>(self, f: &mut F) -> Expr {
+        let expr = match self {
+            Expr::Call { function, args } => Expr::Call {
+                function,
+                args: args.into_iter().map(|arg| arg.map_with(f)).collect(),
+            },
+            Expr::List(items) => {
+                Expr::List(items.into_iter().map(|item| item.map_with(f)).collect())
+            }
+            Expr::Annotated { metadata, expr } => Expr::Annotated {
+                metadata,
+                expr: Box::new(expr.map_with(f)),
+            },
+            Expr::Let { bindings, body } => Expr::Let {
+                bindings: bindings
+                    .into_iter()
+                    .map(|(name, value)| (name, value.map_with(f)))
+                    .collect(),
+                body: Box::new(body.map_with(f)),
+            },
+            leaf => leaf,
+        };
+        f(expr)
+    }
+
+    /// Get every variable this expression reads from the environment,
+    /// leaving out locals bound by `let`
+    pub fn variables(&self) -> HashSet<StringId> {
+        let mut names = HashSet::default();
+        self.free_variables(&mut Vec::new(), &mut names);
+        names
+    }
+
+    fn free_variables(&self, bound: &mut Vec<StringId>, names: &mut HashSet<StringId>) {
+        match self {
+            Expr::Variable(name) if !bound.contains(name) => {
+                names.insert(*name);
+            }
+            Expr::Call { args: children, .. } | Expr::List(children) => {
+                for child in children {
+                    child.free_variables(bound, names);
+                }
+            }
+            Expr::Annotated { expr, .. } => expr.free_variables(bound, names),
+            Expr::Let { bindings, body } => {
+                let scope = bound.len();
+                for (name, value) in bindings {
+                    value.free_variables(bound, names);
+                    bound.push(*name);
+                }
+                body.free_variables(bound, names);
+                bound.truncate(scope);
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the names of every variable this expression reads, skipping IDs
+    /// missing from `interner`
+    pub fn variable_names<'i>(&self, interner: &'i StringInterner) -> HashSet<&'i str> {
+        self.variables()
+            .into_iter()
+            .filter_map(|name| interner.resolve(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, StringInterner};
+
+    #[test]
+    fn visitor_sees_every_node() {
+        #[derive(Default)]
+        struct Counter {
+            variables: Vec<StringId>,
+            literals: usize,
+            calls: usize,
+        }
+        impl ExprVisitor for Counter {
+            fn visit_literal(&mut self, _value: &Value) {
+                self.literals += 1;
+            }
+            fn visit_variable(&mut self, name: StringId) {
+                self.variables.push(name);
+            }
+            fn visit_call(&mut self, _function: StringId, args: &[Expr]) {
+                self.calls += 1;
+                for arg in args {
+                    self.visit_expr(arg);
+                }
+            }
+        }
+
+        let mut interner = StringInterner::new();
+        let expr = parse("(and (= a 1) (in b [c 2 3]))", &mut interner).unwrap();
+        let mut counter = Counter::default();
+        counter.visit_expr(&expr);
+
+        let names = ["a", "b", "c"].map(|name| interner.get_id(name).unwrap());
+        assert_eq!(counter.variables, names);
+        assert_eq!(counter.literals, 3);
+        assert_eq!(counter.calls, 3);
+
+        let mut nodes = 0;
+        expr.walk(|_| nodes += 1);
+        assert_eq!(nodes, 10);
+    }
+
+    #[test]
+    fn variables() {
+        let mut interner = StringInterner::new();
+        let expr = parse(
+            r#"(or (and (= country "US") (>= age 21)) (in country ['CA tier]) flag)"#,
+            &mut interner,
+        )
+        .unwrap();
+
+        let expected = ["country", "age", "tier", "flag"];
+        assert_eq!(
+            expr.variables(),
+            expected
+                .map(|name| interner.get_id(name).unwrap())
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            expr.variable_names(&interner),
+            expected.into_iter().collect()
+        );
+        assert!(parse("(> 2 1)", &mut interner)
+            .unwrap()
+            .variables()
+            .is_empty());
+
+        // Locals are not free, except in their own binding
+        let expr = parse("(let ((x (+ x 1)) (y x)) (and y z))", &mut interner).unwrap();
+        assert_eq!(
+            expr.variable_names(&interner),
+            ["x", "z"].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn folder_rebuilds() {
+        struct Rename {
+            from: StringId,
+            to: StringId,
+        }
+        impl ExprFolder for Rename {
+            fn fold_variable(&mut self, name: StringId) -> Expr {
+                Expr::Variable(if name == self.from { self.to } else { name })
+            }
+        }
+
+        let mut interner = StringInterner::new();
+        let expr = parse("(or (= old 1) [old other])", &mut interner).unwrap();
+        let expected = parse("(or (= new 1) [new other])", &mut interner).unwrap();
+        let mut rename = Rename {
+            from: interner.get_id("old").unwrap(),
+            to: interner.get_id("new").unwrap(),
+        };
+        assert_eq!(rename.fold_expr(expr.clone()), expected);
+
+        // The same rewrite as a closure
+        let (old, new) = (rename.from, rename.to);
+        let mapped = expr.map(|node| match node {
+            Expr::Variable(name) if name == old => Expr::Variable(new),
+            other => other,
+        });
+        assert_eq!(mapped, expected);
+    }
+}
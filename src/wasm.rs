@@ -0,0 +1,87 @@
+//! JavaScript bindings
+//!
+//! With the `wasm` feature, `wasm-bindgen` exports these functions so
+//! browser-based rule editors can preview rules with the same engine the
+//! backend runs. Rules are passed as S-expression source and contexts as
+//! JSON text in the form `json::context_from_json` reads; results come
+//! back as JSON text and failures are thrown as error messages.
+
+use crate::json::{context_from_json, to_json, value_to_json};
+use crate::{Context, Evaluator, StringInterner};
+use wasm_bindgen::prelude::*;
+
+/// Parse a rule and return it in the JSON rule format
+#[wasm_bindgen]
+pub fn parse(source: &str) -> Result<String, JsValue> {
+    parse_rule(source).map_err(|message| JsValue::from_str(&message))
+}
+
+/// Evaluate a rule against a JSON context and return the result as JSON
+#[wasm_bindgen]
+pub fn eval_json_context(rule: &str, context: &str) -> Result<String, JsValue> {
+    eval_rule(rule, context).map_err(|message| JsValue::from_str(&message))
+}
+
+/// Evaluate a rule against a JSON context and return its rendered trace
+#[wasm_bindgen]
+pub fn explain(rule: &str, context: &str) -> Result<String, JsValue> {
+    explain_rule(rule, context).map_err(|message| JsValue::from_str(&message))
+}
+
+fn parse_rule(source: &str) -> Result<String, String> {
+    let mut interner = StringInterner::new();
+    let expr = crate::parse(source, &mut interner).map_err(|e| e.to_string())?;
+    let json = to_json(&expr, &interner).map_err(|e| e.to_string())?;
+    Ok(json.to_string())
+}
+
+fn eval_rule(rule: &str, context: &str) -> Result<String, String> {
+    let mut context = load_context(context)?;
+    let expr = context.parse(rule).map_err(|e| e.to_string())?;
+    let value = context.eval(&expr).map_err(|e| e.to_string())?;
+    let json = value_to_json(&value, context.interner()).map_err(|e| e.to_string())?;
+    Ok(json.to_string())
+}
+
+fn explain_rule(rule: &str, context: &str) -> Result<String, String> {
+    let mut context = load_context(context)?;
+    let expr = context.parse(rule).map_err(|e| e.to_string())?;
+    let evaluator = Evaluator::new(context.interner());
+    let (_, trace) = evaluator.eval_with_trace(&expr, context.environment());
+    Ok(trace.render(context.interner()))
+}
+
+fn load_context(json: &str) -> Result<Context, String> {
+    let json: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut context = Context::new();
+    context_from_json(&json, &mut context).map_err(|e| e.to_string())?;
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_rules() {
+        assert_eq!(
+            parse_rule("(>= age 21)").unwrap(),
+            r#"{"args":[{"var":"age"},21],"op":">="}"#
+        );
+        assert!(parse_rule("(>= age").is_err());
+
+        let context = r#"{"user": {"age": 30}, "country": "CA"}"#;
+        assert_eq!(eval_rule("(>= user.age 21)", context).unwrap(), "true");
+        assert_eq!(
+            eval_rule("[country user.age]", context).unwrap(),
+            r#"["CA",30]"#
+        );
+        assert!(eval_rule("(> tier 3)", context).is_err());
+        assert!(eval_rule("true", "[]").is_err());
+
+        assert_eq!(
+            explain_rule(r#"(= country "US")"#, context).unwrap(),
+            "(= country \"US\") => false\n  country => \"CA\"\n  \"US\" => \"US\"\n"
+        );
+    }
+}